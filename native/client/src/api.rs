@@ -0,0 +1,206 @@
+//! A small API for apps, shaped for flutter_rust_bridge: one opaque handle, async methods, and
+//! only strings, bytes and plain structs crossing the boundary. Generate the Dart bindings with
+//! `flutter_rust_bridge_codegen generate --rust-input crate::api --rust-root native/client`.
+
+use crate::session::Brongnal;
+use crate::sqlite_client::SqliteClient;
+use crate::{ClientEvent, DecryptedMessage, MessageId, SenderVerification};
+use anyhow::{Context, Result};
+use futures::StreamExt;
+use proto::Identity;
+use rusqlite::Connection;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::broadcast::{self, Receiver, Sender};
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+
+/// A message another user sent us.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ReceivedMessage {
+    pub sender: String,
+    pub message: Vec<u8>,
+    /// The message as text, if it is UTF-8.
+    pub text: Option<String>,
+    /// Whether the sender's identity key has been revoked in Gossamer.
+    pub sender_revoked: bool,
+    /// Whether the sender's identity key belongs to `sender`.
+    pub sender_verification: SenderVerification,
+}
+
+impl From<DecryptedMessage> for ReceivedMessage {
+    fn from(message: DecryptedMessage) -> Self {
+        ReceivedMessage {
+            sender: message.sender_identity,
+            text: String::from_utf8(message.message.clone()).ok(),
+            message: message.message,
+            sender_revoked: message.sender_revoked,
+            sender_verification: message.sender_verification,
+        }
+    }
+}
+
+/// The peers we have exchanged messages with, most recent first.
+struct Contacts(std::sync::Mutex<Connection>);
+
+impl Contacts {
+    fn open(path: PathBuf) -> Result<Self> {
+        let connection = Connection::open(path).context("Failed to open contacts db.")?;
+        connection.execute(
+            "CREATE TABLE IF NOT EXISTS contacts (
+                identity TEXT PRIMARY KEY,
+                last_message_at INTEGER NOT NULL
+            )",
+            (),
+        )?;
+        Ok(Contacts(std::sync::Mutex::new(connection)))
+    }
+
+    fn touch(&self, identity: &str) -> Result<()> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as i64;
+        self.0.lock().unwrap().execute(
+            "INSERT INTO contacts (identity, last_message_at) VALUES (?1, ?2)
+             ON CONFLICT(identity) DO UPDATE SET last_message_at = excluded.last_message_at",
+            (identity, now),
+        )?;
+        Ok(())
+    }
+
+    fn list(&self) -> Result<Vec<String>> {
+        let connection = self.0.lock().unwrap();
+        let mut statement = connection
+            .prepare("SELECT identity FROM contacts ORDER BY last_message_at DESC, identity")?;
+        let contacts = statement
+            .query_map((), |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(contacts)
+    }
+}
+
+/// The events reported since the stream was created, in the order they happened.
+pub struct EventStream {
+    rx: Mutex<Receiver<ClientEvent>>,
+}
+
+impl EventStream {
+    /// Waits for the next event. Returns `None` once the app has been closed. A consumer that
+    /// falls too far behind misses the oldest events rather than holding up the others.
+    pub async fn next(&self) -> Option<ClientEvent> {
+        let mut rx = self.rx.lock().await;
+        loop {
+            match rx.recv().await {
+                Ok(event) => return Some(event),
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    }
+}
+
+/// The messages received since the stream was created, in the order they arrived.
+pub struct MessageStream {
+    events: EventStream,
+}
+
+impl MessageStream {
+    /// Waits for the next message. Returns `None` once the app has been closed.
+    pub async fn next(&self) -> Option<ReceivedMessage> {
+        loop {
+            if let ClientEvent::MessageReceived(message) = self.events.next().await? {
+                return Some(message.into());
+            }
+        }
+    }
+}
+
+/// A user of Brongnal on this device, keeping its keys and contacts under a data directory.
+pub struct BrongnalApp {
+    brongnal: Brongnal,
+    contacts: Arc<Contacts>,
+    events: Sender<ClientEvent>,
+    forwarder: JoinHandle<()>,
+}
+
+impl BrongnalApp {
+    /// Opens the keys and contacts in `data_dir`, creating them if needed, and connects to
+    /// `server_url`. A `unix:` prefix names a unix domain socket.
+    pub async fn new(data_dir: String, server_url: String) -> Result<BrongnalApp> {
+        let data_dir = PathBuf::from(data_dir);
+        std::fs::create_dir_all(&data_dir).context("Failed to create data_dir.")?;
+        let client = SqliteClient::new(
+            &data_dir.join("identity_key"),
+            &data_dir.join("keys.sqlite"),
+        )?;
+        let brongnal = Brongnal::connect(&server_url, client).await?;
+        let contacts = Arc::new(Contacts::open(data_dir.join("contacts.sqlite"))?);
+
+        // Senders become contacts before their messages reach the app's streams.
+        let (events, _) = broadcast::channel(100);
+        let mut brongnal_events = Box::pin(brongnal.events());
+        let forwarder = tokio::spawn({
+            let contacts = contacts.clone();
+            let events = events.clone();
+            async move {
+                while let Some(event) = brongnal_events.next().await {
+                    if let ClientEvent::MessageReceived(DecryptedMessage {
+                        sender_identity, ..
+                    }) = &event
+                    {
+                        if let Err(e) = contacts.touch(sender_identity) {
+                            eprintln!("Failed to record contact: {e}");
+                        }
+                    }
+                    let _ = events.send(event);
+                }
+            }
+        });
+        Ok(BrongnalApp {
+            brongnal,
+            contacts,
+            events,
+            forwarder,
+        })
+    }
+
+    /// Registers this device as `name`, then starts receiving its messages, rotating its signed
+    /// pre key and topping up its one time keys in the background.
+    pub async fn register(&self, name: String) -> Result<()> {
+        self.brongnal.register(&name.try_into()?).await
+    }
+
+    /// Queues `text` for each of `peer`'s devices, returning the id events report it by.
+    pub async fn send_message(&self, peer: String, text: String) -> Result<MessageId> {
+        let peer: Identity = peer.try_into()?;
+        let id = self.brongnal.send(&peer, text.as_bytes()).await?;
+        self.contacts.touch(&peer)?;
+        Ok(id)
+    }
+
+    /// The users we have sent messages to or received them from, most recent first.
+    pub async fn contacts(&self) -> Result<Vec<String>> {
+        self.contacts.list()
+    }
+
+    /// Everything that happens in the app from now on: messages, sends, connection changes and
+    /// errors. Each stream sees every event.
+    pub fn event_stream(&self) -> EventStream {
+        EventStream {
+            rx: Mutex::new(self.events.subscribe()),
+        }
+    }
+
+    /// The messages this device receives from now on. Each stream sees every message.
+    pub fn message_stream(&self) -> MessageStream {
+        MessageStream {
+            events: self.event_stream(),
+        }
+    }
+}
+
+impl Drop for BrongnalApp {
+    fn drop(&mut self) {
+        self.forwarder.abort();
+    }
+}
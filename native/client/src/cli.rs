@@ -0,0 +1,485 @@
+//! The command line client: its arguments, an optional file of defaults for them, and the
+//! commands they run.
+
+use crate::secret_store::open_secret_store;
+use crate::session::Brongnal;
+use crate::sqlite_client::SqliteClient;
+use crate::{ClientEvent, ConnectionState, DecryptedMessage, DeliveryState, X3DHClient};
+use anyhow::{bail, Context, Result};
+use clap::{Parser, Subcommand};
+use ed25519_dalek::VerifyingKey;
+use futures::StreamExt;
+use nom::character::complete::{alphanumeric1, multispace1};
+use nom::IResult;
+use proto::{Identity, DEFAULT_DEVICE_ID};
+use protocol::x3dh::CipherSuite;
+use std::collections::HashMap;
+use std::io::{stdin, BufRead, BufReader};
+use std::path::PathBuf;
+use std::thread;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+
+/// The server the client talks to unless told otherwise.
+pub const DEFAULT_SERVER: &str = "https://signal.brongan.com:443";
+
+/// Brongnal's command line client.
+#[derive(Debug, Parser)]
+#[command(name = "client")]
+pub struct Cli {
+    /// Who to act as. Required unless the config file names one.
+    #[arg(long, global = true)]
+    pub name: Option<String>,
+    /// Server to connect to. A "unix:" prefix names a unix domain socket.
+    #[arg(long, global = true)]
+    pub server: Option<String>,
+    /// Device to act as.
+    #[arg(long, global = true)]
+    pub device_id: Option<u32>,
+    /// Directory keys are kept in. Defaults to $XDG_DATA_HOME/brongnal.
+    #[arg(long, global = true)]
+    pub data_dir: Option<PathBuf>,
+    /// Invite code for registering a new identity on servers that only admit invited users.
+    #[arg(long, global = true)]
+    pub invite_code: Option<String>,
+    /// File of "key = value" lines giving defaults for name, server, device_id, data_dir and
+    /// invite_code.
+    /// Defaults to $XDG_CONFIG_HOME/brongnal/config, if there is one.
+    #[arg(long, global = true)]
+    pub config: Option<PathBuf>,
+    /// Cipher suite senders are asked to use, e.g. 2 for deployments that require AES.
+    #[arg(long, global = true, env = "CIPHER_SUITE")]
+    pub cipher_suite: Option<u32>,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, PartialEq, Subcommand)]
+pub enum Command {
+    /// Register, then send "NAME MESSAGE" lines from stdin and print messages as they arrive.
+    /// What runs without a command.
+    Chat,
+    /// Register this device's keys and exit.
+    Register,
+    /// Send one message and exit, as an identity that's already registered.
+    Send {
+        /// Who to send it to.
+        #[arg(long)]
+        to: String,
+        /// What to send.
+        #[arg(long)]
+        message: String,
+    },
+    /// Print this device's identity key fingerprint, to compare with a contact's copy of it.
+    Fingerprint,
+    /// Download everything the server holds about this identity as length-delimited protos.
+    Export {
+        /// The file to write the export to.
+        #[arg(long)]
+        output: PathBuf,
+        /// Export queued messages in full rather than only when they were queued and their size.
+        #[arg(long)]
+        include_ciphertexts: bool,
+    },
+}
+
+/// What a command acts as and connects to, from the flags or failing those the config file.
+#[derive(Debug, PartialEq)]
+pub struct Settings {
+    pub name: Identity,
+    pub server: String,
+    pub device_id: u32,
+    pub data_dir: Option<PathBuf>,
+    pub cipher_suite: CipherSuite,
+    pub invite_code: Option<String>,
+}
+
+/// Parses "key = value" lines. Blank lines and lines starting with `#` are skipped.
+pub fn parse_config(config: &str) -> Result<HashMap<String, String>> {
+    let mut values = HashMap::new();
+    for line in config.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let Some((key, value)) = line.split_once('=') else {
+            bail!("Expected \"key = value\", got \"{line}\".");
+        };
+        let key = key.trim();
+        if !["name", "server", "device_id", "data_dir", "invite_code"].contains(&key) {
+            bail!("Unknown config key {key}.");
+        }
+        values.insert(key.to_owned(), value.trim().to_owned());
+    }
+    Ok(values)
+}
+
+impl Cli {
+    pub fn settings(&self) -> Result<Settings> {
+        let path = match &self.config {
+            Some(path) => Some(path.clone()),
+            None => xdg::BaseDirectories::with_prefix("brongnal")?.find_config_file("config"),
+        };
+        let mut config = match path {
+            Some(path) => parse_config(
+                &std::fs::read_to_string(&path)
+                    .with_context(|| format!("Failed to read {}", path.display()))?,
+            )?,
+            None => HashMap::new(),
+        };
+        let name = self
+            .name
+            .clone()
+            .or(config.remove("name"))
+            .context("No --name given, and the config file doesn't name anyone.")?;
+        let name: Identity = name
+            .parse()
+            .with_context(|| format!("Invalid name {name:?}."))?;
+        let device_id = match (self.device_id, config.remove("device_id")) {
+            (Some(device_id), _) => device_id,
+            (None, Some(device_id)) => device_id
+                .parse()
+                .with_context(|| format!("Invalid device_id {device_id} in config."))?,
+            (None, None) => DEFAULT_DEVICE_ID,
+        };
+        Ok(Settings {
+            name,
+            server: self
+                .server
+                .clone()
+                .or(config.remove("server"))
+                .unwrap_or(String::from(DEFAULT_SERVER)),
+            device_id,
+            data_dir: self
+                .data_dir
+                .clone()
+                .or(config.remove("data_dir").map(PathBuf::from)),
+            cipher_suite: match self.cipher_suite {
+                Some(id) => CipherSuite::try_from(id)?,
+                None => CipherSuite::CURRENT,
+            },
+            invite_code: self.invite_code.clone().or(config.remove("invite_code")),
+        })
+    }
+}
+
+impl Settings {
+    /// Where `file` is kept, creating its directory if need be.
+    fn data_file(&self, file: &str) -> Result<PathBuf> {
+        match &self.data_dir {
+            Some(dir) => {
+                std::fs::create_dir_all(dir)?;
+                Ok(dir.join(file))
+            }
+            None => Ok(xdg::BaseDirectories::with_prefix("brongnal")?.place_data_file(file)?),
+        }
+    }
+
+    /// This device's keys.
+    pub fn open_client(&self) -> Result<SqliteClient> {
+        let name = &self.name;
+        let device_id = self.device_id;
+        // Each device has its own keys; the default device keeps the paths from before devices.
+        let (account, identity_key_path, db_path) = if device_id == DEFAULT_DEVICE_ID {
+            (
+                name.to_string(),
+                self.data_file("identity_key")?,
+                self.data_file(&format!("{name}_keys.sqlite"))?,
+            )
+        } else {
+            (
+                format!("{name}_{device_id}"),
+                self.data_file(&format!("identity_key_{device_id}"))?,
+                self.data_file(&format!("{name}_{device_id}_keys.sqlite"))?,
+            )
+        };
+        SqliteClient::with_secret_store(open_secret_store(&account, &identity_key_path)?, &db_path)
+    }
+
+    async fn connect(&self) -> Result<Brongnal> {
+        Ok(Brongnal::connect(&self.server, self.open_client()?)
+            .await?
+            .with_device_id(self.device_id)
+            .with_cipher_suite(self.cipher_suite)
+            .with_invite_code(self.invite_code.clone()))
+    }
+}
+
+/// `key` in groups of four hex digits.
+pub fn fingerprint(key: &VerifyingKey) -> String {
+    key.as_bytes()
+        .chunks(2)
+        .map(|pair| format!("{:02x}{:02x}", pair[0], pair[1]))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Runs the command `cli` names.
+pub async fn run(cli: Cli) -> Result<()> {
+    let settings = cli.settings()?;
+    match cli.command.unwrap_or(Command::Chat) {
+        Command::Chat => chat(&settings).await,
+        Command::Register => {
+            settings
+                .connect()
+                .await?
+                .register_only(&settings.name)
+                .await?;
+            println!(
+                "Registered {} device {} at {}.",
+                settings.name, settings.device_id, settings.server
+            );
+            Ok(())
+        }
+        Command::Send { to, message } => {
+            let id = settings
+                .connect()
+                .await?
+                .send_now(&settings.name, &to.parse()?, message.as_bytes())
+                .await?;
+            println!("Sent message {id} to {to}.");
+            Ok(())
+        }
+        Command::Fingerprint => {
+            let ik = settings.open_client()?.get_ik()?;
+            println!("{}", fingerprint(&ik.verifying_key()));
+            Ok(())
+        }
+        Command::Export {
+            output,
+            include_ciphertexts,
+        } => {
+            let records = settings
+                .connect()
+                .await?
+                .export_account_data(&settings.name, include_ciphertexts, &output)
+                .await?;
+            println!("Wrote {records} records to {}.", output.display());
+            Ok(())
+        }
+    }
+}
+
+/// A line typed into `chat`.
+#[derive(Debug)]
+struct ChatLine {
+    to: String,
+    msg: String,
+}
+
+fn parse_chat_line(input: &str) -> IResult<&str, ChatLine> {
+    let (input, name) = alphanumeric1(input)?;
+    let (message, _spaces) = multispace1(input)?;
+    Ok((
+        "",
+        ChatLine {
+            to: name.to_owned(),
+            msg: message.to_owned(),
+        },
+    ))
+}
+
+async fn chat(settings: &Settings) -> Result<()> {
+    let name = &settings.name;
+    eprintln!(
+        "Registering {name} device {} at {}",
+        settings.device_id, settings.server
+    );
+    let brongnal = settings.connect().await?;
+    let mut events = Box::pin(brongnal.events());
+    brongnal.register(name).await?;
+
+    println!("NAME MESSAGE");
+
+    let (cli_tx, mut cli_rx) = mpsc::unbounded_channel();
+
+    thread::spawn(move || {
+        for line in BufReader::new(stdin()).lines() {
+            let line = line.unwrap();
+            match parse_chat_line(&line).map_err(|e| e.to_owned()) {
+                Ok((_, command)) => {
+                    if cli_tx.send(command).is_err() {
+                        return;
+                    }
+                }
+                Err(e) => eprintln!("Invalid Command: {e}"),
+            }
+        }
+    });
+
+    loop {
+        tokio::select! {
+            command = cli_rx.recv() => {
+                match command {
+                    Some(command) => {
+                        let sent = match command.to.parse::<Identity>() {
+                            Ok(to) => brongnal.send(&to, command.msg.as_bytes()).await,
+                            Err(e) => Err(e.into()),
+                        };
+                        if let Err(e) = sent {
+                            eprintln!("Error: {e}");
+                        }
+                    },
+                    None => {
+                        eprintln!("Closing...");
+                        return Ok(());
+                    }
+                }
+
+            },
+            event = events.next() => {
+                match event {
+                    Some(ClientEvent::MessageReceived(DecryptedMessage { sender_identity, message, .. })) => {
+                        println!("Received message from {sender_identity}: \"{}\"", String::from_utf8(message).unwrap());
+                    },
+                    Some(ClientEvent::KeyChanged { identity }) => {
+                        eprintln!("Warning: {identity}'s identity key has been revoked.");
+                    },
+                    Some(ClientEvent::NoOneTimeKey { peer }) => {
+                        eprintln!("Warning: {peer} has run out of one time keys, so messages to them are less forward secret.");
+                    },
+                    Some(ClientEvent::KeyNotConfirmed { recipient, device_id, .. }) => {
+                        eprintln!("Warning: {recipient}'s device {device_id} hasn't confirmed the key of a message sent to it.");
+                    },
+                    Some(ClientEvent::DeliveryStateChanged { recipient, state: DeliveryState::Expired, .. }) => {
+                        eprintln!("Warning: a message to {recipient} expired before one of their devices collected it.");
+                    },
+                    Some(ClientEvent::Warning(warning)) => eprintln!("Warning: {warning}"),
+                    Some(ClientEvent::Error(error)) => eprintln!("Error: {error}"),
+                    // The session reconnects on its own.
+                    Some(ClientEvent::ConnectionState(ConnectionState::Backoff { until })) => {
+                        let wait = until.saturating_duration_since(Instant::now());
+                        eprintln!("Server terminated connection. Reconnecting in {}s...", wait.as_secs_f64().ceil());
+                    },
+                    Some(_) => {},
+                    None => return Ok(()),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::error::ErrorKind;
+    use clap::CommandFactory;
+
+    fn parse(args: &[&str]) -> Result<Cli, clap::Error> {
+        Cli::try_parse_from(std::iter::once("client").chain(args.iter().copied()))
+    }
+
+    #[test]
+    fn commands() {
+        Cli::command().debug_assert();
+        assert_eq!(parse(&["--name", "alice"]).unwrap().command, None);
+        assert_eq!(
+            parse(&[
+                "send",
+                "--to",
+                "bob",
+                "--message",
+                "Hi Bob",
+                "--name",
+                "alice"
+            ])
+            .unwrap()
+            .command,
+            Some(Command::Send {
+                to: String::from("bob"),
+                message: String::from("Hi Bob"),
+            })
+        );
+        assert_eq!(
+            parse(&[
+                "export",
+                "--output",
+                "alice.export",
+                "--include-ciphertexts"
+            ])
+            .unwrap()
+            .command,
+            Some(Command::Export {
+                output: PathBuf::from("alice.export"),
+                include_ciphertexts: true,
+            })
+        );
+        assert_eq!(
+            parse(&["send", "--to", "bob"]).unwrap_err().kind(),
+            ErrorKind::MissingRequiredArgument
+        );
+        assert_eq!(
+            parse(&["--device-id", "two", "register"])
+                .unwrap_err()
+                .kind(),
+            ErrorKind::ValueValidation
+        );
+        assert_eq!(
+            parse(&["alice"]).unwrap_err().kind(),
+            ErrorKind::InvalidSubcommand
+        );
+    }
+
+    #[test]
+    fn flags_override_config() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("brongnal-cli-{}", std::process::id()));
+        std::fs::write(
+            &path,
+            "# Defaults\nname = alice\nserver = unix:/tmp/brongnal.sock\ndevice_id = 2\ninvite_code = 0123\n",
+        )?;
+        let config = path.to_str().unwrap();
+
+        let settings = parse(&["--config", config, "register"])?.settings()?;
+        assert_eq!(settings.name.as_str(), "alice");
+        assert_eq!(settings.server, "unix:/tmp/brongnal.sock");
+        assert_eq!(settings.device_id, 2);
+        assert_eq!(settings.data_dir, None);
+        assert_eq!(settings.invite_code.as_deref(), Some("0123"));
+
+        let settings = parse(&[
+            "--config",
+            config,
+            "--name",
+            "bob",
+            "--server",
+            "http://localhost:8080",
+            "--data-dir",
+            "/tmp/bob",
+            "--invite-code",
+            "4567",
+            "register",
+            "--device-id",
+            "3",
+        ])?
+        .settings()?;
+        assert_eq!(settings.name.as_str(), "bob");
+        assert_eq!(settings.server, "http://localhost:8080");
+        assert_eq!(settings.device_id, 3);
+        assert_eq!(settings.data_dir, Some(PathBuf::from("/tmp/bob")));
+        assert_eq!(settings.invite_code.as_deref(), Some("4567"));
+
+        std::fs::write(&path, "server = http://localhost:8080\n")?;
+        let settings = parse(&["--config", config, "--name", "carol"])?.settings()?;
+        assert_eq!(settings.device_id, DEFAULT_DEVICE_ID);
+        let err = parse(&["--config", config, "register"])?
+            .settings()
+            .unwrap_err();
+        assert!(err.to_string().contains("No --name given"));
+
+        std::fs::write(&path, "nmae = alice\n")?;
+        assert!(parse(&["--config", config])?.settings().is_err());
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn fingerprints() {
+        let key = ed25519_dalek::SigningKey::from_bytes(&[7; 32]).verifying_key();
+        let fingerprint = fingerprint(&key);
+        assert_eq!(fingerprint.len(), 16 * 4 + 15);
+        assert!(fingerprint.starts_with(&format!(
+            "{:02x}{:02x} ",
+            key.as_bytes()[0],
+            key.as_bytes()[1]
+        )));
+    }
+}
@@ -0,0 +1,240 @@
+//! Sorting the errors the client's calls fail with by what the caller can do about them.
+
+use crate::{KeyRevoked, RecipientNotFound, TimedOut};
+use protocol::aead::AeadError;
+use protocol::kem::KemError;
+use protocol::x3dh::X3DHError;
+use std::time::Duration;
+use tonic::{Code, Status};
+
+/// What a failure of `message`, `register`, `listen` and the like means for whoever made the
+/// call. Those fail with `anyhow::Error`s; `ClientError::classify` sorts them.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ClientError {
+    /// The server was unreachable, slow or busy. Trying again unchanged may succeed, though no
+    /// sooner than `retry_after` if the server said how long to wait.
+    Retryable { retry_after: Option<Duration> },
+    /// Trying again unchanged fails the same way, e.g. because the request was malformed or
+    /// couldn't be authenticated.
+    Fatal,
+    /// The call can't succeed until the user does something about it.
+    NeedsUserAction(UserAction),
+}
+
+/// What the user needs to do before a call that failed with `ClientError::NeedsUserAction` can
+/// succeed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum UserAction {
+    /// Check who `identity` is now, since their identity key was revoked.
+    KeyChanged { identity: String },
+    /// Check the name, since the server doesn't know `identity`.
+    RecipientNotFound { identity: String },
+    /// Update the app, since a peer is using a cipher suite or protocol version it doesn't
+    /// support.
+    UpdateClient,
+}
+
+impl ClientError {
+    /// Classifies `e` by the first of its causes that says anything about retrying. Errors
+    /// nothing is known about are fatal.
+    pub fn classify(e: &anyhow::Error) -> ClientError {
+        e.chain()
+            .find_map(|cause| {
+                if cause.is::<TimedOut>() || cause.is::<tonic::transport::Error>() {
+                    Some(ClientError::Retryable { retry_after: None })
+                } else if let Some(KeyRevoked { peer, .. }) = cause.downcast_ref() {
+                    Some(ClientError::NeedsUserAction(UserAction::KeyChanged {
+                        identity: peer.clone(),
+                    }))
+                } else if let Some(RecipientNotFound { peer }) = cause.downcast_ref() {
+                    Some(ClientError::NeedsUserAction(
+                        UserAction::RecipientNotFound {
+                            identity: peer.clone(),
+                        },
+                    ))
+                } else if let Some(status) = cause.downcast_ref::<Status>() {
+                    Some(status.into())
+                } else if let Some(error) = cause.downcast_ref::<X3DHError>() {
+                    Some(error.into())
+                } else if cause.is::<AeadError>() || cause.is::<KemError>() {
+                    Some(ClientError::Fatal)
+                } else {
+                    None
+                }
+            })
+            .unwrap_or(ClientError::Fatal)
+    }
+
+    /// How long to wait before trying again, at least `backoff`, or `None` if trying again is
+    /// pointless.
+    pub fn retry_delay(&self, backoff: Duration) -> Option<Duration> {
+        match self {
+            ClientError::Retryable { retry_after } => {
+                Some(retry_after.map_or(backoff, |retry_after| retry_after.max(backoff)))
+            }
+            ClientError::Fatal | ClientError::NeedsUserAction(_) => None,
+        }
+    }
+}
+
+impl From<&Status> for ClientError {
+    fn from(status: &Status) -> ClientError {
+        match status.code() {
+            Code::Unavailable | Code::DeadlineExceeded => {
+                ClientError::Retryable { retry_after: None }
+            }
+            // Rate limited, or the recipient's mailbox is full until they collect their messages.
+            Code::ResourceExhausted => ClientError::Retryable {
+                retry_after: status
+                    .metadata()
+                    .get("retry-after")
+                    .and_then(|seconds| seconds.to_str().ok()?.parse().ok())
+                    .map(Duration::from_secs),
+            },
+            _ => ClientError::Fatal,
+        }
+    }
+}
+
+impl From<&X3DHError> for ClientError {
+    fn from(error: &X3DHError) -> ClientError {
+        match error {
+            X3DHError::UnsupportedSuite(_) | X3DHError::UnsupportedVersion(_) => {
+                ClientError::NeedsUserAction(UserAction::UpdateClient)
+            }
+            X3DHError::SignatureValidation | X3DHError::Aead(_) | X3DHError::Kem(_) => {
+                ClientError::Fatal
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn classify_status(status: Status) -> ClientError {
+        ClientError::classify(&anyhow::Error::from(status).context("Failed to message bob"))
+    }
+
+    #[test]
+    fn status_codes() {
+        let retryable = ClientError::Retryable { retry_after: None };
+        for (code, expected) in [
+            (Code::Ok, ClientError::Fatal),
+            (Code::Cancelled, ClientError::Fatal),
+            (Code::Unknown, ClientError::Fatal),
+            (Code::InvalidArgument, ClientError::Fatal),
+            (Code::DeadlineExceeded, retryable.clone()),
+            (Code::NotFound, ClientError::Fatal),
+            (Code::AlreadyExists, ClientError::Fatal),
+            (Code::PermissionDenied, ClientError::Fatal),
+            (Code::ResourceExhausted, retryable.clone()),
+            (Code::FailedPrecondition, ClientError::Fatal),
+            (Code::Aborted, ClientError::Fatal),
+            (Code::OutOfRange, ClientError::Fatal),
+            (Code::Unimplemented, ClientError::Fatal),
+            (Code::Internal, ClientError::Fatal),
+            (Code::Unavailable, retryable.clone()),
+            (Code::DataLoss, ClientError::Fatal),
+            (Code::Unauthenticated, ClientError::Fatal),
+        ] {
+            assert_eq!(classify_status(Status::new(code, "")), expected, "{code:?}");
+        }
+
+        let mut status = Status::resource_exhausted("too many messages");
+        status
+            .metadata_mut()
+            .insert("retry-after", "10".parse().unwrap());
+        assert_eq!(
+            classify_status(status),
+            ClientError::Retryable {
+                retry_after: Some(Duration::from_secs(10))
+            }
+        );
+    }
+
+    #[test]
+    fn client_and_protocol_errors() {
+        let classify = |e: anyhow::Error| ClientError::classify(&e);
+        assert_eq!(
+            classify(
+                TimedOut {
+                    operation: "send_message",
+                    after: Duration::from_secs(10),
+                }
+                .into()
+            ),
+            ClientError::Retryable { retry_after: None }
+        );
+        assert_eq!(
+            classify(
+                RecipientNotFound {
+                    peer: String::from("carol"),
+                }
+                .into()
+            ),
+            ClientError::NeedsUserAction(UserAction::RecipientNotFound {
+                identity: String::from("carol")
+            })
+        );
+        assert_eq!(
+            classify(
+                KeyRevoked {
+                    peer: String::from("bob"),
+                    device_id: 1,
+                }
+                .into()
+            ),
+            ClientError::NeedsUserAction(UserAction::KeyChanged {
+                identity: String::from("bob")
+            })
+        );
+        assert_eq!(
+            classify(X3DHError::UnsupportedVersion(2).into()),
+            ClientError::NeedsUserAction(UserAction::UpdateClient)
+        );
+        assert_eq!(
+            classify(X3DHError::SignatureValidation.into()),
+            ClientError::Fatal
+        );
+        assert_eq!(
+            classify(
+                anyhow::Error::from(AeadError::Truncated).context("Failed to decrypt a message")
+            ),
+            ClientError::Fatal
+        );
+        assert_eq!(
+            classify(anyhow::anyhow!("Refusing to message bob")),
+            ClientError::Fatal
+        );
+    }
+
+    #[test]
+    fn only_retryable_errors_are_retried() {
+        let backoff = Duration::from_secs(1);
+        assert_eq!(
+            ClientError::Retryable { retry_after: None }.retry_delay(backoff),
+            Some(backoff)
+        );
+        assert_eq!(
+            ClientError::Retryable {
+                retry_after: Some(Duration::from_secs(10))
+            }
+            .retry_delay(backoff),
+            Some(Duration::from_secs(10))
+        );
+        assert_eq!(
+            ClientError::Retryable {
+                retry_after: Some(Duration::ZERO)
+            }
+            .retry_delay(backoff),
+            Some(backoff)
+        );
+        assert_eq!(ClientError::Fatal.retry_delay(backoff), None);
+        assert_eq!(
+            ClientError::NeedsUserAction(UserAction::UpdateClient).retry_delay(backoff),
+            None
+        );
+    }
+}
@@ -1,168 +1,3116 @@
-use anyhow::{Context, Result};
-use chacha20poly1305::{ChaCha20Poly1305, KeyInit};
-use ed25519_dalek::SigningKey;
+use anyhow::{bail, Context, Result};
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{OsRng, Payload};
+use ed25519_dalek::{Signer, SigningKey, VerifyingKey};
+use prost::Message;
+use proto::backup::{BackupPreKey, IdentityBackup};
+use proto::gossamer::append_key::KeyPurpose;
+use proto::gossamer::gossamer_client::GossamerClient;
+use proto::gossamer::message::Action;
+use proto::gossamer::{
+    ActionRequest, AppendKey, GetLedgerRequest, RevokeKey as RevokeKeyAction, RevokeKeyRequest,
+    Tombstone,
+};
 use proto::service::brongnal_client::BrongnalClient;
 use proto::service::{
-    Message as MessageProto, RegisterPreKeyBundleRequest, RequestPreKeysRequest,
-    RetrieveMessagesRequest, SendMessageRequest,
+    ContentType, CountOneTimeKeysRequest, DecryptionFailure, DeleteAccountRequest,
+    DeleteDeviceRequest, DeliveryState as DeliveryStateProto, DeviceMessage,
+    ExportAccountDataRequest, FetchProvisioningRequest, GetAccountDeletionNonceRequest,
+    GetRegistrationChallengeRequest, KeyConfirmation, LinkingPayload, Message as MessageProto,
+    MessageStatusStreamRequest, PingRequest, PublishProvisioningRequest, PushPlatform,
+    RegisterPreKeyBundleRequest, RegisterPushTokenRequest, RequestPreKeysRequest,
+    RetrieveMessagesRequest, SendMessageRequest, UpdateSignedPreKeyRequest,
+    UploadOneTimeKeysRequest,
+};
+use proto::{
+    MessagePayload, SessionMessage, DEFAULT_DEVICE_ID, HEARTBEAT_INTERVAL, KEEPALIVE_INTERVAL,
+    KEEPALIVE_TIMEOUT, MAX_CIPHERTEXT_LEN, MAX_PING_NONCE_LEN, MESSAGE_UUID_LEN,
+    PROVISIONING_ID_LEN, PROVISIONING_TTL,
 };
+use protocol::aead::MIN_CIPHERTEXT_LEN;
+use protocol::backup::{open_backup, seal_backup, BackupError, KdfParams};
+use protocol::confirmation::{self, Transcript, MAC_LEN};
+use protocol::kem::{self, KemPublicKey, KemSecretKey, SignedKemPreKey};
+use protocol::pow;
+use protocol::provisioning::{open_identity_key, seal_identity_key};
+use protocol::ratchet::{Role, Session, SessionError};
 use protocol::x3dh;
 use std::collections::HashMap;
+use std::future::Future;
+use std::path::Path;
 use std::sync::Arc;
-use tokio::sync::mpsc::Sender;
-use tokio::sync::Mutex;
-use tonic::transport::Channel;
-use tonic::Streaming;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::net::UnixStream;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::broadcast::{Receiver, Sender};
+use tokio::sync::{watch, Mutex};
+use tokio::time::Instant;
+use tonic::transport::{Channel, Endpoint, Uri};
+use tonic::{Code, Status, Streaming};
+use tower::service_fn;
 use x25519_dalek::{PublicKey as X25519PublicKey, StaticSecret as X25519StaticSecret};
-use x3dh::{initiate_recv, initiate_send, SignedPreKey, SignedPreKeys};
+use x3dh::{
+    initiate_recv_with_context, initiate_send_with_context, CipherSuite, SignedPreKey,
+    SignedPreKeys,
+};
 
+pub mod api;
+pub mod cli;
+pub mod error;
 pub mod memory_client;
+pub mod secret_store;
+pub mod session;
 pub mod sqlite_client;
 
+/// How many signed pre keys a client keeps after replacing them, so messages that were encrypted
+/// against them before the rotation reached the sender still decrypt.
+pub const RETAINED_SPKS: usize = 4;
+
+/// How often clients replace their signed pre key.
+pub const SPK_ROTATION_PERIOD: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// When the background top-up task uploads more one time keys, and how many.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OpkTopUp {
+    /// Upload more keys once the server has fewer than this many.
+    pub low_water_mark: u32,
+    /// How many keys to upload at a time.
+    pub batch_size: u32,
+    /// How often to check the server's count, give or take a tenth so devices don't all check at
+    /// once.
+    pub check_period: Duration,
+    /// How long to wait after receiving a message before checking, so a burst of messages leads
+    /// to a single check.
+    pub settle_delay: Duration,
+    /// The least time between two uploads.
+    pub min_upload_interval: Duration,
+}
+
+impl Default for OpkTopUp {
+    fn default() -> Self {
+        OpkTopUp {
+            low_water_mark: 20,
+            batch_size: 100,
+            check_period: Duration::from_secs(60 * 60),
+            settle_delay: Duration::from_secs(5),
+            min_upload_interval: Duration::from_secs(60),
+        }
+    }
+}
+
+/// The longest message whose ciphertext the server accepts by default.
+pub const MAX_MESSAGE_LEN: usize = MAX_CIPHERTEXT_LEN - MIN_CIPHERTEXT_LEN;
+
+/// How often `finish_linking` checks whether the primary device has approved the link.
+pub const LINKING_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long the message stream may go without a message or heartbeat before `listen` gives up on
+/// the connection. Allows for a couple of heartbeats going missing.
+pub const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(3 * HEARTBEAT_INTERVAL.as_secs());
+
+/// How long the message stream may go without a message or heartbeat before `listen` pings the
+/// server to check it still answers. A little over the heartbeat interval, so healthy streams
+/// aren't probed.
+pub const PROBE_AFTER: Duration = Duration::from_secs(HEARTBEAT_INTERVAL.as_secs() * 3 / 2);
+
+/// How long a single request to the server may take.
+pub const RPC_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long the server may take to start streaming messages to `listen`.
+pub const STREAM_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The most hashes `register` spends on the server's registration challenge, about 2^26 or a few
+/// seconds' work. Servers asking for much more than that are refused.
+pub const MAX_CHALLENGE_ATTEMPTS: u64 = 1 << 26;
+
+/// How long `message` may take overall, including fetching every device's keys and checking them
+/// in Gossamer.
+pub const MESSAGE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long a sent message is kept so it can be resent to a device of the recipient that couldn't
+/// decrypt it.
+pub const SENT_MESSAGE_RETENTION: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// How many times a message is resent to a device that couldn't decrypt it before giving up.
+pub const MAX_RESEND_ATTEMPTS: u32 = 3;
+
+/// How long a recipient's device has to confirm the key it agreed on for a message before
+/// `ClientEvent::KeyNotConfirmed` is reported. Long enough for a device that is offline for a
+/// while to collect the message.
+pub const KEY_CONFIRMATION_TIMEOUT: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// How many messages in a row from a peer may fail to decrypt for want of a session with them
+/// before one is set up again.
+pub const SESSION_RESET_AFTER: u32 = 3;
+
+/// How long after resetting the session with a peer another reset is held back, so two devices
+/// that can't decrypt each other's messages don't keep resetting.
+pub const SESSION_RESET_COOLDOWN: Duration = Duration::from_secs(60 * 60);
+
+/// How long calls to the server may take before giving up with `TimedOut`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Timeouts {
+    /// Each unary request. The server is told the deadline too.
+    pub rpc: Duration,
+    /// Opening the message stream, as opposed to how long it stays open.
+    pub stream_connect: Duration,
+    /// How long the message stream may go without a message or heartbeat.
+    pub heartbeat: Duration,
+    /// How long the message stream may go without a message or heartbeat before the server is
+    /// pinged, giving up on the stream early if it doesn't answer. Probing starts again after
+    /// each answer, until `heartbeat` runs out.
+    pub probe: Duration,
+    /// Sending one message, across all the requests that takes.
+    pub message: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Timeouts {
+            rpc: RPC_TIMEOUT,
+            stream_connect: STREAM_CONNECT_TIMEOUT,
+            heartbeat: HEARTBEAT_TIMEOUT,
+            probe: PROBE_AFTER,
+            message: MESSAGE_TIMEOUT,
+        }
+    }
+}
+
+/// A call to the server didn't finish in time. The connection may be hung rather than gone, and
+/// the server may or may not have acted on the call, so it is worth retrying.
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
+#[error("{operation} timed out after {after:?}")]
+pub struct TimedOut {
+    pub operation: &'static str,
+    pub after: Duration,
+}
+
+/// `message` refused to send because the recipient's device had no one time pre key left, so the
+/// message would only be as secret as the device's signed pre key.
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
+#[error("{peer}'s device {device_id} has run out of one time keys")]
+pub struct NoOneTimeKey {
+    pub peer: String,
+    pub device_id: u32,
+}
+
+/// `message` couldn't send because the server doesn't know the recipient.
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
+#[error("{peer} isn't registered")]
+pub struct RecipientNotFound {
+    pub peer: String,
+}
+
+/// `message` refused to send because the recipient's device's identity key has been revoked in
+/// Gossamer, so it has either been replaced or compromised.
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
+#[error("Refusing to message {peer}: their device {device_id} identity key has been revoked.")]
+pub struct KeyRevoked {
+    pub peer: String,
+    pub device_id: u32,
+}
+
+/// `message` refused to send a message too long for the server to accept.
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
+#[error("the message is {len} bytes but at most {max} fit in one message, send it as an attachment or in chunks")]
+pub struct MessageTooLarge {
+    pub len: usize,
+    pub max: usize,
+}
+
+/// What `message` does when the recipient's signed pre key is older than allowed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StaleSpkAction {
+    /// Log a warning and send anyway.
+    Warn,
+    /// Refuse to send the message.
+    Refuse,
+}
+
+/// How old a recipient's signed pre key may be before `message` stops trusting it. A key that
+/// outlives several rotation periods suggests the recipient's device is gone or the server is
+/// handing out an old bundle.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SpkAgePolicy {
+    pub max_age: Duration,
+    pub action: StaleSpkAction,
+}
+
+impl Default for SpkAgePolicy {
+    fn default() -> Self {
+        SpkAgePolicy {
+            max_age: Duration::from_secs(30 * 24 * 60 * 60),
+            action: StaleSpkAction::Warn,
+        }
+    }
+}
+
+/// What `message` requires of the recipient's pre keys before it sends.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SendPolicy {
+    pub spk_age: SpkAgePolicy,
+    /// Refuse to send with `NoOneTimeKey` rather than fall back to a last-resort key, which
+    /// other senders may be using too.
+    pub require_otk: bool,
+}
+
+/// Every secret a client holds, as carried by a backup. Pre keys are paired with the ids the
+/// server assigned them, if any.
+pub struct KeyBackup {
+    pub ik: SigningKey,
+    /// Newest first, so the first is the current signed pre key.
+    pub spks: Vec<(Option<u32>, X25519StaticSecret)>,
+    pub opks: Vec<(Option<u32>, X25519StaticSecret)>,
+    /// Newest first, so the first is the current last-resort key.
+    pub last_resort_keys: Vec<(Option<u32>, X25519StaticSecret)>,
+    pub kem_opks: Vec<(Option<u32>, KemSecretKey)>,
+    /// Newest first, so the first is the current last-resort KEM key.
+    pub last_resort_kem_keys: Vec<(Option<u32>, KemSecretKey)>,
+}
+
+impl From<KeyBackup> for IdentityBackup {
+    fn from(keys: KeyBackup) -> Self {
+        let pre_keys = |keys: Vec<(Option<u32>, X25519StaticSecret)>| {
+            keys.into_iter()
+                .map(|(id, key)| BackupPreKey {
+                    private_key: Some(key.to_bytes().to_vec()),
+                    id,
+                })
+                .collect()
+        };
+        let kem_keys = |keys: Vec<(Option<u32>, KemSecretKey)>| {
+            keys.into_iter()
+                .map(|(id, key)| BackupPreKey {
+                    private_key: Some(key.as_bytes().to_vec()),
+                    id,
+                })
+                .collect()
+        };
+        IdentityBackup {
+            identity_key: Some(keys.ik.to_bytes().to_vec()),
+            signed_pre_keys: pre_keys(keys.spks),
+            one_time_keys: pre_keys(keys.opks),
+            last_resort_keys: pre_keys(keys.last_resort_keys),
+            one_time_kem_keys: kem_keys(keys.kem_opks),
+            last_resort_kem_keys: kem_keys(keys.last_resort_kem_keys),
+        }
+    }
+}
+
+impl TryFrom<IdentityBackup> for KeyBackup {
+    type Error = anyhow::Error;
+
+    fn try_from(backup: IdentityBackup) -> Result<Self> {
+        let pre_keys = |keys: Vec<BackupPreKey>| {
+            keys.into_iter()
+                .map(|key| {
+                    let secret: [u8; 32] = key
+                        .private_key()
+                        .try_into()
+                        .context("Backup has an invalid pre key.")?;
+                    Ok((key.id, X25519StaticSecret::from(secret)))
+                })
+                .collect::<Result<Vec<_>>>()
+        };
+        let kem_keys = |keys: Vec<BackupPreKey>| {
+            keys.into_iter()
+                .map(|key| {
+                    let secret = KemSecretKey::from_bytes(key.private_key())
+                        .context("Backup has an invalid KEM pre key.")?;
+                    Ok((key.id, secret))
+                })
+                .collect::<Result<Vec<_>>>()
+        };
+        let ik: [u8; 32] = backup
+            .identity_key()
+            .try_into()
+            .context("Backup has an invalid identity key.")?;
+        let spks = pre_keys(backup.signed_pre_keys)?;
+        if spks.is_empty() {
+            bail!("Backup has no signed pre key.");
+        }
+        Ok(KeyBackup {
+            ik: SigningKey::from_bytes(&ik),
+            spks,
+            opks: pre_keys(backup.one_time_keys)?,
+            last_resort_keys: pre_keys(backup.last_resort_keys)?,
+            kem_opks: kem_keys(backup.one_time_kem_keys)?,
+            last_resort_kem_keys: kem_keys(backup.last_resort_kem_keys)?,
+        })
+    }
+}
+
+/// A message kept after sending it, in case one of the recipient's devices can't decrypt it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SentMessage {
+    pub recipient: String,
+    pub message: Vec<u8>,
+    pub sent_at: SystemTime,
+    /// How many times the message has been resent so far, counted on the message as first sent.
+    pub attempts: u32,
+    /// The message this is a resent copy of, or `None` if it is the message as first sent.
+    pub resent_from: Option<[u8; MESSAGE_UUID_LEN]>,
+}
+
+/// A key agreement one of a recipient's devices is expected to confirm, after it decrypts the
+/// message that started it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PendingConfirmation {
+    pub peer: String,
+    pub device_id: u32,
+    /// The MAC the device proves it derived the same key with. See `protocol::confirmation`.
+    pub mac: [u8; MAC_LEN],
+    pub sent_at: SystemTime,
+}
+
+/// The session kept with one of a peer's devices, set up by an X3DH message one side sent the
+/// other.
+#[derive(Clone)]
+pub struct PeerSession {
+    /// The device's identity key in that X3DH message, which messages under the session are
+    /// from.
+    pub ik: VerifyingKey,
+    pub session: Session,
+}
+
+/// Whether a peer has proven it derives the same keys from X3DH as we do.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SessionState {
+    /// None of the peer's devices has confirmed a key agreement with us yet.
+    Unconfirmed,
+    /// One of the peer's devices confirmed the key agreement of a message we sent it.
+    Confirmed,
+}
+
 pub trait X3DHClient {
     fn fetch_wipe_opk(
         &mut self,
         opk: &X25519PublicKey,
     ) -> Result<X25519StaticSecret, anyhow::Error>;
+    /// Like `fetch_wipe_opk`, but finds the key by the id the server assigned to it.
+    fn fetch_wipe_opk_by_id(&mut self, id: u32) -> Result<X25519StaticSecret, anyhow::Error>;
     fn get_ik(&self) -> Result<SigningKey, anyhow::Error>;
     fn get_pre_key(&self) -> Result<X25519StaticSecret, anyhow::Error>;
+    /// Returns the secret of the signed pre key the server assigned `id` to.
+    fn get_spk_secret(&self, id: u32) -> Result<X25519StaticSecret, anyhow::Error>;
+    /// Records the id the server assigned to one of our signed or one time pre keys.
+    fn set_pre_key_id(&mut self, key: &X25519PublicKey, id: u32) -> Result<()>;
     fn get_spk(&self) -> Result<SignedPreKey, anyhow::Error>;
     fn create_opks(&mut self, num_keys: u32) -> Result<SignedPreKeys>;
+    /// Replaces the signed pre key with a new one, retaining the previous `RETAINED_SPKS`.
+    fn rotate_spk(&mut self) -> Result<SignedPreKey>;
+    /// Returns the key senders fall back to once the one time keys run out, signed like the
+    /// signed pre key.
+    fn get_last_resort_key(&self) -> Result<SignedPreKey>;
+    /// Like `get_spk_secret`, but for last-resort keys. Using one doesn't remove it.
+    fn get_last_resort_secret(&self, id: u32) -> Result<X25519StaticSecret>;
+    /// Replaces the last-resort key with a new one, retaining the previous `RETAINED_SPKS`.
+    fn rotate_last_resort_key(&mut self) -> Result<SignedPreKey>;
+    /// Creates `num_keys` one time KEM pre keys, each signed by the identity key. Fails if built
+    /// without ML-KEM support.
+    fn create_kem_opks(&mut self, num_keys: u32) -> Result<Vec<SignedKemPreKey>>;
+    /// Records the id the server assigned to one of our KEM pre keys.
+    fn set_kem_pre_key_id(&mut self, key: &KemPublicKey, id: u32) -> Result<()>;
+    /// Like `fetch_wipe_opk_by_id`, but for one time KEM pre keys.
+    fn fetch_wipe_kem_opk_by_id(&mut self, id: u32) -> Result<KemSecretKey>;
+    /// Returns the KEM key senders fall back to once the one time KEM keys run out, or `None`
+    /// if the client has none because it was built without ML-KEM support.
+    fn get_last_resort_kem_key(&self) -> Result<Option<SignedKemPreKey>>;
+    /// Like `get_last_resort_secret`, but for last-resort KEM keys.
+    fn get_last_resort_kem_secret(&self, id: u32) -> Result<KemSecretKey>;
+    /// Like `rotate_last_resort_key`, but for the last-resort KEM key. Fails if built without
+    /// ML-KEM support.
+    fn rotate_last_resort_kem_key(&mut self) -> Result<SignedKemPreKey>;
+    /// Keeps `message`, sent as `uuid`, until `forget_sent_messages` forgets it.
+    fn save_sent_message(
+        &mut self,
+        uuid: &[u8; MESSAGE_UUID_LEN],
+        message: SentMessage,
+    ) -> Result<()>;
+    /// Returns the message sent as `uuid`, or `None` if it wasn't kept or has been forgotten.
+    fn get_sent_message(&self, uuid: &[u8; MESSAGE_UUID_LEN]) -> Result<Option<SentMessage>>;
+    /// Forgets the messages sent before `before`.
+    fn forget_sent_messages(&mut self, before: SystemTime) -> Result<()>;
+    /// Returns every secret the client holds.
+    fn export_keys(&self) -> Result<KeyBackup>;
+    /// Replaces every secret the client holds, including its identity key, with `keys`.
+    fn import_keys(&mut self, keys: KeyBackup) -> Result<()>;
+    /// Returns the session kept with `peer`'s `device_id`, or `None` if there is none or it can't
+    /// be read, e.g. because a newer release stored it, in which case it has to be set up again
+    /// with X3DH.
+    fn get_session(&mut self, peer: &str, device_id: u32) -> Result<Option<PeerSession>>;
+    /// Keeps `session` as the session with `peer`'s `device_id`, replacing any already kept.
+    fn save_session(&mut self, peer: &str, device_id: u32, session: &PeerSession) -> Result<()>;
+    /// Forgets the session with `peer`'s `device_id`, if there is one.
+    fn delete_session(&mut self, peer: &str, device_id: u32) -> Result<()>;
+    /// Returns the devices of `peer` a session is kept with.
+    fn session_devices(&self, peer: &str) -> Result<Vec<u32>>;
+    /// Waits for `confirmation.peer`'s `confirmation.device_id` to confirm the key it agreed on
+    /// for the message sent as `uuid`.
+    fn expect_key_confirmation(
+        &mut self,
+        uuid: &[u8; MESSAGE_UUID_LEN],
+        confirmation: PendingConfirmation,
+    ) -> Result<()>;
+    /// Stops waiting for `device_id` to confirm the message sent as `uuid`, returning what it was
+    /// expected to confirm, or `None` if nothing was.
+    fn take_key_confirmation(
+        &mut self,
+        uuid: &[u8; MESSAGE_UUID_LEN],
+        device_id: u32,
+    ) -> Result<Option<PendingConfirmation>>;
+    /// Stops waiting for confirmations of messages sent before `before`, returning them along
+    /// with the uuids the messages were sent as.
+    fn expire_key_confirmations(
+        &mut self,
+        before: SystemTime,
+    ) -> Result<Vec<([u8; MESSAGE_UUID_LEN], PendingConfirmation)>>;
+    /// Whether `peer` has confirmed a key agreement with us.
+    fn session_state(&self, peer: &str) -> Result<SessionState>;
+    /// Records that `peer` confirmed a key agreement with us.
+    fn confirm_session(&mut self, peer: &str) -> Result<()>;
+    /// Forgets every secret the client holds, including its identity key, and the messages,
+    /// sessions and confirmations it kept, e.g. once the account they belong to is deleted. The client shouldn't be
+    /// used afterwards.
+    fn wipe(&mut self) -> Result<()>;
 }
 
-#[allow(dead_code)]
-struct SessionKeys<T> {
-    session_keys: HashMap<T, [u8; 32]>,
+/// Whether Gossamer vouches for the identity a message claims to be from. Anyone can claim any
+/// identity; only the identity key a message is from is authenticated.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SenderVerification {
+    /// The sender's identity key is published under the identity it claims.
+    Verified,
+    /// The claimed identity has published no identity keys, so there is nothing to check against.
+    Unverified,
+    /// The claimed identity has published identity keys, but not the one the message is from.
+    Mismatch,
 }
 
-#[allow(dead_code)]
-impl<Identity: Eq + std::hash::Hash> SessionKeys<Identity> {
-    fn set_session_key(&mut self, recipient_identity: Identity, secret_key: &[u8; 32]) {
-        self.session_keys.insert(recipient_identity, *secret_key);
-    }
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DecryptedMessage {
+    pub sender_identity: String,
+    pub message: Vec<u8>,
+    /// Whether the sender's identity key has been revoked in Gossamer.
+    pub sender_revoked: bool,
+    /// Whether the sender's identity key belongs to `sender_identity`.
+    pub sender_verification: SenderVerification,
+    /// Whether the sender has confirmed a key agreement with us.
+    pub session_state: SessionState,
+}
+
+/// Whether the client is receiving messages from the server.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// Opening the message stream.
+    Connecting,
+    /// Receiving messages as they arrive.
+    Connected,
+    /// Not receiving messages, and not about to try.
+    Disconnected,
+    /// Lost the connection, and waiting until `until` to reconnect.
+    Backoff { until: Instant },
+}
+
+/// How far a sent message has got towards one of its recipient's devices.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeliveryState {
+    /// The server is holding the message until the device connects.
+    Queued,
+    /// The server handed the message to the device.
+    Delivered,
+    /// The device didn't connect before the server's message ttl ran out, so the server
+    /// discarded the message.
+    Expired,
+}
+
+/// Identifies a sent message in `ClientEvent`s. Derived from the message's uuid, so retries of a
+/// send report the same id.
+pub type MessageId = u64;
 
-    fn get_encryption_key(&mut self, recipient_identity: &Identity) -> Result<ChaCha20Poly1305> {
-        let key = self
-            .session_keys
-            .get(recipient_identity)
-            .context("Session key not found.")?;
-        Ok(ChaCha20Poly1305::new_from_slice(key).unwrap())
+/// Something that happened in the client that a user interface may want to show. `listen`,
+/// `message` and `register` report these on a broadcast channel so any number of consumers can
+/// follow along; sending to a channel nobody is subscribed to is not an error.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ClientEvent {
+    /// `name`'s `device_id` finished registering its keys with the server.
+    Registered {
+        name: String,
+        device_id: u32,
+    },
+    MessageReceived(DecryptedMessage),
+    /// The server accepted message `id` for each of `recipient`'s devices. `no_one_time_key` is
+    /// set if any of them had run out of one time keys; see `NoOneTimeKey`.
+    MessageSent {
+        id: MessageId,
+        recipient: String,
+        no_one_time_key: bool,
+    },
+    /// The recipient's device read message `id`. Nothing sends receipts yet.
+    DeliveryReceipt {
+        id: MessageId,
+    },
+    /// `recipient`'s `device_id` couldn't decrypt message `id`, so it was encrypted to a fresh
+    /// prekey bundle and resent to that device as `resent_as`.
+    MessageResent {
+        id: MessageId,
+        resent_as: MessageId,
+        recipient: String,
+        device_id: u32,
+    },
+    /// Message `id`'s copy for `recipient`'s `device_id` reached `state`, as reported by
+    /// `watch_message_status`.
+    DeliveryStateChanged {
+        id: MessageId,
+        recipient: String,
+        device_id: u32,
+        state: DeliveryState,
+    },
+    ConnectionState(ConnectionState),
+    /// The server answered a ping in `rtt`.
+    RoundTripTime(Duration),
+    /// One of `peer`'s devices had run out of one time keys, so a message to it was sent with
+    /// reduced forward secrecy.
+    NoOneTimeKey {
+        peer: String,
+    },
+    /// The server reported that this device has only `remaining` one time keys left. The top-up
+    /// task uploads more.
+    OneTimeKeysLow {
+        remaining: u32,
+    },
+    /// `identity`'s identity key was revoked in Gossamer, so it has either been replaced or
+    /// compromised.
+    KeyChanged {
+        identity: String,
+    },
+    /// `peer`'s `device_id` proved it derived the same key as us for a message we sent it, so
+    /// the session with `peer` is confirmed.
+    SessionConfirmed {
+        peer: String,
+        device_id: u32,
+    },
+    /// A warning that `recipient`'s `device_id` didn't confirm the key it agreed on for message
+    /// `id` within `KEY_CONFIRMATION_TIMEOUT`: it may not have received the message, or may have
+    /// derived another key from it.
+    KeyNotConfirmed {
+        id: MessageId,
+        recipient: String,
+        device_id: u32,
+    },
+    /// A new session with `peer` was set up with X3DH, either because their messages kept failing
+    /// to decrypt for want of one or because they reset it with an authentic key agreement.
+    SessionReset {
+        peer: String,
+    },
+    /// A message from `peer` repeated one already received under the session with them, e.g.
+    /// because the server replayed it, so it was dropped.
+    ReplayDetected {
+        peer: String,
+    },
+    /// Something worth telling the user about that didn't stop the operation.
+    Warning(String),
+    /// An operation failed. Failures to handle one received message don't stop the others.
+    Error(String),
+}
+
+/// Reports `event` to whoever is subscribed to `events`.
+fn emit(events: &Sender<ClientEvent>, event: ClientEvent) {
+    // Only fails when nobody is subscribed, in which case nobody is interested.
+    let _ = events.send(event);
+}
+
+/// Moves the connection to `new_state`, telling both `events` and `state`'s receivers.
+fn set_connection_state(
+    events: &Sender<ClientEvent>,
+    state: &watch::Sender<ConnectionState>,
+    new_state: ConnectionState,
+) {
+    state.send_replace(new_state);
+    emit(events, ClientEvent::ConnectionState(new_state));
+}
+
+/// Wraps `message` in a request the server abandons after `timeout`.
+fn request<T>(message: T, timeout: Duration) -> tonic::Request<T> {
+    let mut request = tonic::Request::new(message);
+    request.set_timeout(timeout);
+    request
+}
+
+/// Waits up to `timeout` for the response to `call`, failing with `TimedOut` if it doesn't arrive,
+/// or if the server gave up on it first.
+async fn deadline<T>(
+    operation: &'static str,
+    timeout: Duration,
+    call: impl Future<Output = Result<tonic::Response<T>, Status>>,
+) -> Result<T> {
+    let timed_out = TimedOut {
+        operation,
+        after: timeout,
+    };
+    match tokio::time::timeout(timeout, call).await {
+        Ok(Ok(response)) => Ok(response.into_inner()),
+        // Tonic servers cancel requests whose deadline passed rather than reporting it exceeded.
+        Ok(Err(status))
+            if status.code() == Code::DeadlineExceeded
+                || (status.code() == Code::Cancelled && status.message() == "Timeout expired") =>
+        {
+            Err(timed_out.into())
+        }
+        Ok(Err(status)) => Err(status.into()),
+        Err(_) => Err(timed_out.into()),
     }
+}
+
+/// Pings the server over HTTP/2 while connected, even when idle, so a connection that
+/// intermediaries have silently dropped fails rather than hanging.
+pub fn with_keepalive(endpoint: Endpoint) -> Endpoint {
+    endpoint
+        .http2_keep_alive_interval(KEEPALIVE_INTERVAL)
+        .keep_alive_timeout(KEEPALIVE_TIMEOUT)
+        .keep_alive_while_idle(true)
+}
+
+/// Connects to a server listening on a unix domain socket at `path`. The channel serves both
+/// `BrongnalClient` and `GossamerClient`.
+pub async fn connect_uds(path: impl AsRef<Path>) -> Result<Channel> {
+    let path = path.as_ref().to_owned();
+    // The endpoint requires a URI, but the connector ignores it.
+    Ok(with_keepalive(Endpoint::try_from("http://[::]:50051")?)
+        .connect_with_connector(service_fn(move |_: Uri| UnixStream::connect(path.clone())))
+        .await?)
+}
 
-    fn destroy_session_key(&mut self, peer: &Identity) {
-        self.session_keys.remove(peer);
+/// Whether `provider` has revoked `key` according to the Gossamer ledger.
+pub async fn is_revoked(
+    gossamer: &mut GossamerClient<Channel>,
+    provider: &str,
+    key: &VerifyingKey,
+    timeouts: Timeouts,
+) -> Result<bool> {
+    Ok(key_status(gossamer, provider, key, timeouts).await?.revoked)
+}
+
+/// What `provider`'s Gossamer ledger says about `key`.
+struct KeyStatus {
+    verification: SenderVerification,
+    revoked: bool,
+}
+
+async fn key_status(
+    gossamer: &mut GossamerClient<Channel>,
+    provider: &str,
+    key: &VerifyingKey,
+    timeouts: Timeouts,
+) -> Result<KeyStatus> {
+    let ledger = deadline(
+        "get_ledger",
+        timeouts.rpc,
+        gossamer.get_ledger(request(
+            GetLedgerRequest {
+                provider: Some(provider.to_owned()),
+            },
+            timeouts.rpc,
+        )),
+    )
+    .await?;
+    let mut status = KeyStatus {
+        verification: SenderVerification::Unverified,
+        revoked: false,
+    };
+    for message in ledger.messages {
+        let message: proto::SignedMessage = message.try_into()?;
+        match message.message.action {
+            Some(Action::AppendKey(append))
+                if append.provider() == provider
+                    && append.key_purpose() == KeyPurpose::IdentityKey =>
+            {
+                if append.public_key() == key.as_bytes() {
+                    status.verification = SenderVerification::Verified;
+                } else if status.verification == SenderVerification::Unverified {
+                    status.verification = SenderVerification::Mismatch;
+                }
+            }
+            Some(Action::RevokeKey(revoke))
+                if revoke.provider() == provider && revoke.public_key() == key.as_bytes() =>
+            {
+                status.revoked = true;
+            }
+            Some(Action::Tombstone(tombstone)) if tombstone.provider() == provider => {
+                status.revoked = true;
+            }
+            _ => {}
+        }
     }
+    Ok(status)
 }
 
-pub struct DecryptedMessage {
-    pub sender_identity: String,
-    pub message: Vec<u8>,
+/// Appends our identity key to the Gossamer ledger so it can later be revoked.
+pub async fn publish_identity_key(
+    gossamer: &mut GossamerClient<Channel>,
+    x3dh_client: Arc<Mutex<dyn X3DHClient + Send>>,
+    name: String,
+    timeouts: Timeouts,
+) -> Result<()> {
+    let ik = x3dh_client.lock().await.get_ik()?;
+    let mut action = AppendKey {
+        provider: Some(name.clone()),
+        public_key: Some(ik.verifying_key().to_bytes().to_vec()),
+        ..Default::default()
+    };
+    action.set_key_purpose(KeyPurpose::IdentityKey);
+    deadline(
+        "perform",
+        timeouts.rpc,
+        gossamer.perform(request(
+            ActionRequest {
+                message: Some(proto::sign_action(&ik, name, Action::AppendKey(action))),
+            },
+            timeouts.rpc,
+        )),
+    )
+    .await?;
+    Ok(())
 }
 
+/// Revokes our identity key in the Gossamer ledger so peers stop encrypting to it.
+pub async fn revoke_identity_key(
+    gossamer: &mut GossamerClient<Channel>,
+    x3dh_client: Arc<Mutex<dyn X3DHClient + Send>>,
+    name: String,
+    timeouts: Timeouts,
+) -> Result<()> {
+    let ik = x3dh_client.lock().await.get_ik()?;
+    let action = RevokeKeyAction {
+        provider: Some(name.clone()),
+        public_key: Some(ik.verifying_key().to_bytes().to_vec()),
+    };
+    deadline(
+        "revoke_key",
+        timeouts.rpc,
+        gossamer.revoke_key(request(
+            RevokeKeyRequest {
+                message: Some(proto::sign_action(&ik, name, Action::RevokeKey(action))),
+            },
+            timeouts.rpc,
+        )),
+    )
+    .await?;
+    Ok(())
+}
+
+/// Receives `name`'s `device_id`'s messages until the server ends the stream, reporting them and
+/// the state of the connection on `events`. Fails if the stream doesn't open within
+/// `STREAM_CONNECT_TIMEOUT`, or once it goes quiet for longer than `HEARTBEAT_TIMEOUT`.
 pub async fn listen(
+    stub: BrongnalClient<Channel>,
+    gossamer: GossamerClient<Channel>,
+    x3dh_client: Arc<Mutex<dyn X3DHClient + Send>>,
+    name: String,
+    device_id: u32,
+    events: Sender<ClientEvent>,
+) -> Result<()> {
+    listen_with_timeouts(
+        stub,
+        gossamer,
+        x3dh_client,
+        name,
+        device_id,
+        events,
+        Timeouts::default(),
+    )
+    .await
+}
+
+/// Like `listen`, but gives up on opening the stream, the stream going quiet and the requests
+/// made while handling messages after `timeouts` rather than the defaults.
+pub async fn listen_with_timeouts(
+    stub: BrongnalClient<Channel>,
+    gossamer: GossamerClient<Channel>,
+    x3dh_client: Arc<Mutex<dyn X3DHClient + Send>>,
+    name: String,
+    device_id: u32,
+    events: Sender<ClientEvent>,
+    timeouts: Timeouts,
+) -> Result<()> {
+    let (state, _) = watch::channel(ConnectionState::Disconnected);
+    let result = listen_with_state(
+        stub,
+        gossamer,
+        x3dh_client,
+        name,
+        device_id,
+        events.clone(),
+        &state,
+        timeouts,
+    )
+    .await;
+    set_connection_state(&events, &state, ConnectionState::Disconnected);
+    result
+}
+
+/// Like `listen_with_timeouts`, but also keeps `state` up to date, for consumers that poll the
+/// state of the connection rather than follow `events`. Once the stream ends, the caller says
+/// what state the connection is in, e.g. whether it is about to reconnect.
+#[allow(clippy::too_many_arguments)]
+pub async fn listen_with_state(
     mut stub: BrongnalClient<Channel>,
+    gossamer: GossamerClient<Channel>,
     x3dh_client: Arc<Mutex<dyn X3DHClient + Send>>,
     name: String,
-    tx: Sender<DecryptedMessage>,
+    device_id: u32,
+    events: Sender<ClientEvent>,
+    state: &watch::Sender<ConnectionState>,
+    timeouts: Timeouts,
 ) -> Result<()> {
-    let stream = stub
-        .retrieve_messages(RetrieveMessagesRequest {
-            identity: Some(name),
-        })
-        .await;
-    if let Err(e) = &stream {
-        eprintln!("Failed to retrieve messages: {e}");
-    }
-    if let Err(e) = get_messages(stream?.into_inner(), x3dh_client, tx).await {
-        eprintln!("get_messages terminated with: {e}");
-        return Err(e);
+    set_connection_state(&events, state, ConnectionState::Connecting);
+    // No deadline for the server: it would end the stream once the deadline passed.
+    let stream = deadline(
+        "retrieve_messages",
+        timeouts.stream_connect,
+        stub.retrieve_messages(RetrieveMessagesRequest {
+            identity: Some(name.clone()),
+            device_id: Some(device_id),
+        }),
+    );
+    let result = match stream.await {
+        Ok(stream) => {
+            set_connection_state(&events, state, ConnectionState::Connected);
+            get_messages(
+                stream,
+                stub,
+                gossamer,
+                x3dh_client,
+                &name,
+                device_id,
+                &events,
+                timeouts,
+            )
+            .await
+        }
+        Err(e) => Err(e.context("Failed to retrieve messages")),
+    };
+    if let Err(e) = &result {
+        emit(&events, ClientEvent::Error(format!("{e:#}")));
     }
-    Ok(())
+    result
 }
 
 pub async fn register(
     stub: &mut BrongnalClient<Channel>,
     x3dh_client: Arc<Mutex<dyn X3DHClient + Send>>,
     name: String,
+    device_id: u32,
+    events: &Sender<ClientEvent>,
 ) -> Result<()> {
-    eprintln!("Registering {name}!");
-    let request = {
+    register_with_suite(
+        stub,
+        x3dh_client,
+        name,
+        device_id,
+        CipherSuite::CURRENT,
+        Timeouts::default(),
+        None,
+        events,
+    )
+    .await
+}
+
+/// Like `register`, but asks senders to use `cipher_suite` rather than the default, gives up
+/// after `timeouts.rpc` rather than `RPC_TIMEOUT`, and presents `invite_code` to servers that
+/// only admit invited identities.
+#[allow(clippy::too_many_arguments)]
+pub async fn register_with_suite(
+    stub: &mut BrongnalClient<Channel>,
+    x3dh_client: Arc<Mutex<dyn X3DHClient + Send>>,
+    name: String,
+    device_id: u32,
+    cipher_suite: CipherSuite,
+    timeouts: Timeouts,
+    invite_code: Option<String>,
+    events: &Sender<ClientEvent>,
+) -> Result<()> {
+    eprintln!("Registering {name} device {device_id}!");
+    let (challenge, challenge_solution) =
+        solve_registration_challenge(stub, &name, timeouts).await?;
+    let (request, spk, opks, last_resort_key, kem_opks, last_resort_kem_key) = {
         let mut x3dh_client = x3dh_client.lock().await;
         let ik = x3dh_client.get_ik()?.verifying_key().as_bytes().to_vec();
-        tonic::Request::new(RegisterPreKeyBundleRequest {
-            identity_key: Some(ik),
-            identity: Some(name.clone()),
-            signed_pre_key: Some(x3dh_client.get_spk()?.into()),
-            one_time_key_bundle: Some(x3dh_client.create_opks(100)?.into()),
-        })
+        let spk = x3dh_client.get_spk()?;
+        let opks = x3dh_client.create_opks(100)?;
+        let last_resort_key = x3dh_client.get_last_resort_key()?;
+        // Without KEM keys, peers message us with classic X3DH.
+        let (kem_opks, last_resort_kem_key) = if kem::SUPPORTED {
+            (
+                x3dh_client.create_kem_opks(100)?,
+                x3dh_client.get_last_resort_kem_key()?,
+            )
+        } else {
+            (Vec::new(), None)
+        };
+        let request = request(
+            RegisterPreKeyBundleRequest {
+                identity_key: Some(ik),
+                identity: Some(name.clone()),
+                signed_pre_key: Some(spk.clone().into()),
+                one_time_key_bundle: Some(opks.clone().into()),
+                device_id: Some(device_id),
+                last_resort_key: Some(last_resort_key.clone().into()),
+                last_resort_kem_key: last_resort_kem_key.clone().map(Into::into),
+                one_time_kem_keys: kem_opks.iter().cloned().map(Into::into).collect(),
+                cipher_suite: Some(cipher_suite.id()),
+                challenge,
+                challenge_solution,
+                invite_code,
+            },
+            timeouts.rpc,
+        );
+        (
+            request,
+            spk.pre_key,
+            opks.pre_keys,
+            last_resort_key.pre_key,
+            kem_opks,
+            last_resort_kem_key,
+        )
     };
-    stub.register_pre_key_bundle(request).await?;
+    let response = deadline(
+        "register_pre_key_bundle",
+        timeouts.rpc,
+        stub.register_pre_key_bundle(request),
+    )
+    .await?;
+    // Servers that predate prekey ids don't assign any.
+    let mut x3dh_client = x3dh_client.lock().await;
+    if let Some(id) = response.signed_pre_key_id {
+        x3dh_client.set_pre_key_id(&spk, id)?;
+    }
+    for (opk, id) in opks.iter().zip(&response.one_time_key_ids) {
+        x3dh_client.set_pre_key_id(opk, *id)?;
+    }
+    forget_rejected_opks(&mut *x3dh_client, &opks, response.one_time_keys_accepted)?;
+    if let Some(id) = response.last_resort_key_id {
+        x3dh_client.set_pre_key_id(&last_resort_key, id)?;
+    }
+    for (kem_opk, id) in kem_opks.iter().zip(response.one_time_kem_key_ids) {
+        x3dh_client.set_kem_pre_key_id(&kem_opk.pre_key, id)?;
+    }
+    if let (Some(key), Some(id)) = (last_resort_kem_key, response.last_resort_kem_key_id) {
+        x3dh_client.set_kem_pre_key_id(&key.pre_key, id)?;
+    }
     eprintln!("Registered: {}!", name);
+    emit(events, ClientEvent::Registered { name, device_id });
+    Ok(())
+}
+
+/// Fetches and solves the server's registration challenge for `name`, if it requires one.
+/// Servers that predate challenges require none.
+async fn solve_registration_challenge(
+    stub: &mut BrongnalClient<Channel>,
+    name: &str,
+    timeouts: Timeouts,
+) -> Result<(Option<Vec<u8>>, Option<Vec<u8>>)> {
+    let response = match deadline(
+        "get_registration_challenge",
+        timeouts.rpc,
+        stub.get_registration_challenge(request(GetRegistrationChallengeRequest {}, timeouts.rpc)),
+    )
+    .await
+    {
+        Ok(response) => response,
+        Err(e)
+            if e.downcast_ref::<Status>()
+                .is_some_and(|status| status.code() == Code::Unimplemented) =>
+        {
+            return Ok((None, None));
+        }
+        Err(e) => return Err(e),
+    };
+    let difficulty = response.difficulty();
+    let Some(challenge) = response.challenge.filter(|_| difficulty > 0) else {
+        return Ok((None, None));
+    };
+    let solution = {
+        let (challenge, name) = (challenge.clone(), name.to_owned());
+        tokio::task::spawn_blocking(move || {
+            pow::solve(&challenge, &name, difficulty, MAX_CHALLENGE_ATTEMPTS)
+        })
+        .await?
+    };
+    let Some(solution) = solution else {
+        bail!("couldn't solve the server's registration challenge of difficulty {difficulty}");
+    };
+    Ok((Some(challenge), Some(solution.to_vec())))
+}
+
+/// Wipes the secrets of the uploaded `opks` past the first `accepted`, which the server dropped
+/// for want of room. Servers that predate `accepted` store every key.
+fn forget_rejected_opks(
+    x3dh_client: &mut (dyn X3DHClient + Send),
+    opks: &[X25519PublicKey],
+    accepted: Option<u32>,
+) -> Result<()> {
+    let Some(accepted) = accepted else {
+        return Ok(());
+    };
+    let rejected = opks.get(accepted as usize..).unwrap_or_default();
+    if !rejected.is_empty() {
+        eprintln!(
+            "The server stored {accepted} of {} one time keys.",
+            opks.len()
+        );
+    }
+    for opk in rejected {
+        x3dh_client.fetch_wipe_opk(opk)?;
+    }
+    Ok(())
+}
+
+/// Removes one of `name`'s devices, e.g. one that was lost. Any of the identity's devices may
+/// remove another, so `x3dh_client` need not belong to `device_id`.
+pub async fn delete_device(
+    stub: &mut BrongnalClient<Channel>,
+    x3dh_client: Arc<Mutex<dyn X3DHClient + Send>>,
+    name: String,
+    device_id: u32,
+    timeouts: Timeouts,
+) -> Result<()> {
+    let ik = x3dh_client.lock().await.get_ik()?;
+    let signature = ik.sign(&proto::delete_device_payload(&name, device_id));
+    deadline(
+        "delete_device",
+        timeouts.rpc,
+        stub.delete_device(request(
+            DeleteDeviceRequest {
+                identity: Some(name),
+                device_id: Some(device_id),
+                signature: Some(signature.to_bytes().to_vec()),
+            },
+            timeouts.rpc,
+        )),
+    )
+    .await?;
+    Ok(())
+}
+
+/// Deletes `name` and every one of its devices from the server, tombstoning it in the Gossamer
+/// ledger if its identity key is there so peers learn it's gone. Once the server confirms, this
+/// device's keys are wiped.
+pub async fn delete_account(
+    stub: &mut BrongnalClient<Channel>,
+    gossamer: &mut GossamerClient<Channel>,
+    x3dh_client: Arc<Mutex<dyn X3DHClient + Send>>,
+    name: String,
+    timeouts: Timeouts,
+) -> Result<()> {
+    let nonce = deadline(
+        "get_account_deletion_nonce",
+        timeouts.rpc,
+        stub.get_account_deletion_nonce(request(
+            GetAccountDeletionNonceRequest {
+                identity: Some(name.clone()),
+            },
+            timeouts.rpc,
+        )),
+    )
+    .await?
+    .nonce
+    .context("Server sent no account deletion nonce.")?;
+    let ik = x3dh_client.lock().await.get_ik()?;
+    let status = key_status(gossamer, &name, &ik.verifying_key(), timeouts).await?;
+    let tombstone =
+        (status.verification == SenderVerification::Verified && !status.revoked).then(|| {
+            proto::sign_action(
+                &ik,
+                name.clone(),
+                Action::Tombstone(Tombstone {
+                    provider: Some(name.clone()),
+                }),
+            )
+        });
+    let signature = ik.sign(&proto::delete_account_payload(&name, &nonce));
+    deadline(
+        "delete_account",
+        timeouts.rpc,
+        stub.delete_account(request(
+            DeleteAccountRequest {
+                identity: Some(name),
+                nonce: Some(nonce),
+                signature: Some(signature.to_bytes().to_vec()),
+                tombstone,
+            },
+            timeouts.rpc,
+        )),
+    )
+    .await?;
+    x3dh_client.lock().await.wipe()
+}
+
+/// Downloads everything the server holds about `name` into a file at `path`, as length-delimited
+/// `AccountDataRecord`s, returning how many records there were. Queued messages are only exported
+/// in full with `include_ciphertexts`; otherwise just when they were queued and their size.
+pub async fn export_account_data(
+    stub: &mut BrongnalClient<Channel>,
+    x3dh_client: Arc<Mutex<dyn X3DHClient + Send>>,
+    name: String,
+    include_ciphertexts: bool,
+    path: &Path,
+    timeouts: Timeouts,
+) -> Result<usize> {
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let signature = x3dh_client
+        .lock()
+        .await
+        .get_ik()?
+        .sign(&proto::export_account_data_payload(
+            &name,
+            timestamp,
+            include_ciphertexts,
+        ));
+    let mut stream = deadline(
+        "export_account_data",
+        timeouts.stream_connect,
+        stub.export_account_data(ExportAccountDataRequest {
+            identity: Some(name),
+            timestamp: Some(timestamp),
+            include_ciphertexts: Some(include_ciphertexts),
+            signature: Some(signature.to_bytes().to_vec()),
+        }),
+    )
+    .await?;
+    let mut export = Vec::new();
+    let mut records = 0;
+    while let Some(record) = stream.message().await? {
+        record.encode_length_delimited(&mut export)?;
+        records += 1;
+    }
+    tokio::fs::write(path, export)
+        .await
+        .with_context(|| format!("Failed to write {}", path.display()))?;
+    Ok(records)
+}
+
+/// Reports on `events` how the messages `name` sends while watching are getting on, until the
+/// server ends the stream. Fails if the stream doesn't open within `timeouts.stream_connect`.
+pub async fn watch_message_status(
+    stub: &mut BrongnalClient<Channel>,
+    x3dh_client: Arc<Mutex<dyn X3DHClient + Send>>,
+    name: String,
+    device_id: u32,
+    events: &Sender<ClientEvent>,
+    timeouts: Timeouts,
+) -> Result<()> {
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let signature = x3dh_client
+        .lock()
+        .await
+        .get_ik()?
+        .sign(&proto::message_status_stream_payload(
+            &name, device_id, timestamp,
+        ));
+    let mut stream = deadline(
+        "message_status_stream",
+        timeouts.stream_connect,
+        stub.message_status_stream(MessageStatusStreamRequest {
+            identity: Some(name),
+            device_id: Some(device_id),
+            timestamp: Some(timestamp),
+            signature: Some(signature.to_bytes().to_vec()),
+        }),
+    )
+    .await?;
+    while let Some(status) = stream.message().await? {
+        let Ok(uuid) = <[u8; MESSAGE_UUID_LEN]>::try_from(status.message_uuid()) else {
+            continue;
+        };
+        let state = match status.state() {
+            DeliveryStateProto::Queued => DeliveryState::Queued,
+            DeliveryStateProto::Delivered => DeliveryState::Delivered,
+            DeliveryStateProto::Expired => DeliveryState::Expired,
+            DeliveryStateProto::Unknown => continue,
+        };
+        emit(
+            events,
+            ClientEvent::DeliveryStateChanged {
+                id: message_id(&uuid),
+                recipient: status.recipient_identity().to_owned(),
+                device_id: status.device_id(),
+                state,
+            },
+        );
+    }
+    Ok(())
+}
+
+/// Tells the server to wake `name`'s `device_id` through `platform` with `device_token` while it
+/// has no open message stream, e.g. after the OS hands the app a new token.
+pub async fn register_push_token(
+    stub: &mut BrongnalClient<Channel>,
+    x3dh_client: Arc<Mutex<dyn X3DHClient + Send>>,
+    name: String,
+    device_id: u32,
+    device_token: String,
+    platform: PushPlatform,
+    timeouts: Timeouts,
+) -> Result<()> {
+    let app_version = env!("CARGO_PKG_VERSION");
+    let ik = x3dh_client.lock().await.get_ik()?;
+    let signature = ik.sign(&proto::register_push_token_payload(
+        &name,
+        device_id,
+        &device_token,
+        platform,
+        app_version,
+    ));
+    deadline(
+        "register_push_token",
+        timeouts.rpc,
+        stub.register_push_token(request(
+            RegisterPushTokenRequest {
+                identity: Some(name),
+                device_id: Some(device_id),
+                device_token: Some(device_token),
+                platform: Some(platform as i32),
+                app_version: Some(app_version.to_owned()),
+                signature: Some(signature.to_bytes().to_vec()),
+            },
+            timeouts.rpc,
+        )),
+    )
+    .await?;
+    Ok(())
+}
+
+/// Encrypts every secret `x3dh_client` holds under `passphrase`, so the identity survives losing
+/// the device.
+pub async fn export_backup(
+    x3dh_client: Arc<Mutex<dyn X3DHClient + Send>>,
+    passphrase: &str,
+    params: KdfParams,
+) -> Result<Vec<u8>> {
+    let keys = x3dh_client.lock().await.export_keys()?;
+    Ok(seal_backup(
+        &IdentityBackup::from(keys).encode_to_vec(),
+        passphrase,
+        params,
+    )?)
+}
+
+/// Restores the secrets in `backup` into `x3dh_client`. Fails with `BackupError::WrongPassphrase`
+/// if `passphrase` doesn't match, and with `BackupError::DifferentIdentity` if the client is
+/// already registered under another identity, unless `replace_identity` confirms replacing it.
+pub async fn import_backup(
+    x3dh_client: Arc<Mutex<dyn X3DHClient + Send>>,
+    backup: &[u8],
+    passphrase: &str,
+    replace_identity: bool,
+) -> Result<()> {
+    let plaintext = open_backup(backup, passphrase)?;
+    let keys: KeyBackup = IdentityBackup::decode(plaintext.as_slice())
+        .context("Backup is malformed.")?
+        .try_into()?;
+    let mut x3dh_client = x3dh_client.lock().await;
+    let current = x3dh_client.export_keys()?;
+    // Only keys the server assigned ids to have been uploaded, so a client without any hasn't
+    // registered its identity and has nothing to lose.
+    let registered = current
+        .spks
+        .iter()
+        .chain(&current.opks)
+        .any(|(id, _)| id.is_some());
+    if registered && current.ik != keys.ik && !replace_identity {
+        return Err(BackupError::DifferentIdentity.into());
+    }
+    x3dh_client.import_keys(keys)
+}
+
+/// Encrypts `payload` for `peer`'s `device_id` under the session kept with it. The advanced
+/// session is kept before the ciphertext is returned, so no message key is used twice, even
+/// across restarts.
+pub fn session_encrypt(
+    x3dh_client: &mut (impl X3DHClient + ?Sized),
+    peer: &str,
+    device_id: u32,
+    payload: Payload,
+) -> Result<Vec<u8>> {
+    let mut session = x3dh_client
+        .get_session(peer, device_id)?
+        .ok_or(SessionError::NoSession)?;
+    let ciphertext = session.session.encrypt(payload)?;
+    x3dh_client.save_session(peer, device_id, &session)?;
+    Ok(ciphertext)
+}
+
+/// Decrypts a `message` from `peer`'s `device_id` under the session kept with it, keeping the
+/// advanced session. Returns the plaintext along with the identity key the session is with.
+pub fn session_decrypt(
+    x3dh_client: &mut (impl X3DHClient + ?Sized),
+    peer: &str,
+    device_id: u32,
+    message: &[u8],
+    aad: &[u8],
+) -> Result<(Vec<u8>, VerifyingKey)> {
+    let mut session = x3dh_client
+        .get_session(peer, device_id)?
+        .ok_or(SessionError::NoSession)?;
+    let plaintext = session.session.decrypt(message, aad)?;
+    x3dh_client.save_session(peer, device_id, &session)?;
+    Ok((plaintext, session.ik))
+}
+
+/// A new device's request to be linked to an identity registered on another device.
+pub struct PendingLink {
+    provisioning_id: Vec<u8>,
+    secret: X25519StaticSecret,
+    started: Instant,
+}
+
+impl PendingLink {
+    /// What the new device shows the primary device, e.g. as a QR code.
+    pub fn payload(&self) -> Vec<u8> {
+        LinkingPayload {
+            provisioning_id: Some(self.provisioning_id.clone()),
+            public_key: Some(X25519PublicKey::from(&self.secret).to_bytes().to_vec()),
+        }
+        .encode_to_vec()
+    }
+}
+
+/// Starts linking a new device. Show `payload()` to the primary device, which passes it to
+/// `approve_link`, then wait for its identity key with `finish_linking`.
+pub fn start_linking() -> PendingLink {
+    let mut provisioning_id = vec![0; PROVISIONING_ID_LEN];
+    OsRng.fill_bytes(&mut provisioning_id);
+    PendingLink {
+        provisioning_id,
+        secret: X25519StaticSecret::random_from_rng(OsRng),
+        started: Instant::now(),
+    }
+}
+
+/// Sends our identity key to the new device that showed `payload`. Only approve payloads read
+/// directly off a device you control: whoever holds the payload's key receives the identity.
+pub async fn approve_link(
+    stub: &mut BrongnalClient<Channel>,
+    x3dh_client: Arc<Mutex<dyn X3DHClient + Send>>,
+    payload: &[u8],
+    timeouts: Timeouts,
+) -> Result<()> {
+    let payload = LinkingPayload::decode(payload).context("Invalid linking payload.")?;
+    let recipient = proto::parse_x25519_public_key("public_key", payload.public_key())?;
+    let ik = x3dh_client.lock().await.get_ik()?;
+    let envelope = seal_identity_key(&recipient, &ik)?;
+    deadline(
+        "publish_provisioning",
+        timeouts.rpc,
+        stub.publish_provisioning(request(
+            PublishProvisioningRequest {
+                provisioning_id: payload.provisioning_id,
+                envelope: Some(envelope),
+            },
+            timeouts.rpc,
+        )),
+    )
+    .await?;
+    Ok(())
+}
+
+/// Waits for the primary device to approve `link` and returns the identity key it sent. Gives up
+/// once the server would have expired the envelope.
+pub async fn finish_linking(
+    stub: &mut BrongnalClient<Channel>,
+    link: PendingLink,
+    timeouts: Timeouts,
+) -> Result<SigningKey> {
+    loop {
+        let response = deadline(
+            "fetch_provisioning",
+            timeouts.rpc,
+            stub.fetch_provisioning(request(
+                FetchProvisioningRequest {
+                    provisioning_id: Some(link.provisioning_id.clone()),
+                },
+                timeouts.rpc,
+            )),
+        )
+        .await;
+        match response {
+            Ok(response) => return Ok(open_identity_key(&link.secret, response.envelope())?),
+            Err(e)
+                if matches!(e.downcast_ref::<Status>(), Some(status) if status.code() == Code::NotFound)
+                    && link.started.elapsed() < PROVISIONING_TTL =>
+            {
+                tokio::time::sleep(LINKING_POLL_INTERVAL).await;
+            }
+            Err(e) => return Err(e.context("Linking failed")),
+        }
+    }
+}
+
+/// Replaces the signed pre key and last-resort keys and uploads the new ones for `name`'s
+/// `device_id`.
+pub async fn rotate_spk(
+    stub: &mut BrongnalClient<Channel>,
+    x3dh_client: Arc<Mutex<dyn X3DHClient + Send>>,
+    name: String,
+    device_id: u32,
+    timeouts: Timeouts,
+) -> Result<()> {
+    let mut x3dh_client = x3dh_client.lock().await;
+    let spk = x3dh_client.rotate_spk()?;
+    let last_resort_key = x3dh_client.rotate_last_resort_key()?;
+    let last_resort_kem_key = if kem::SUPPORTED {
+        Some(x3dh_client.rotate_last_resort_kem_key()?)
+    } else {
+        None
+    };
+    let response = deadline(
+        "update_signed_pre_key",
+        timeouts.rpc,
+        stub.update_signed_pre_key(request(
+            UpdateSignedPreKeyRequest {
+                identity: Some(name),
+                signed_pre_key: Some(spk.clone().into()),
+                device_id: Some(device_id),
+                last_resort_key: Some(last_resort_key.clone().into()),
+                last_resort_kem_key: last_resort_kem_key.clone().map(Into::into),
+            },
+            timeouts.rpc,
+        )),
+    )
+    .await?;
+    if let Some(id) = response.signed_pre_key_id {
+        x3dh_client.set_pre_key_id(&spk.pre_key, id)?;
+    }
+    if let Some(id) = response.last_resort_key_id {
+        x3dh_client.set_pre_key_id(&last_resort_key.pre_key, id)?;
+    }
+    if let (Some(key), Some(id)) = (last_resort_kem_key, response.last_resort_kem_key_id) {
+        x3dh_client.set_kem_pre_key_id(&key.pre_key, id)?;
+    }
     Ok(())
 }
 
+/// Creates `count` one time keys and uploads them for `name`'s `device_id`, leaving its other
+/// keys as they are. Returns how many one time keys the device now has on the server.
+pub async fn upload_one_time_keys(
+    stub: &mut BrongnalClient<Channel>,
+    x3dh_client: Arc<Mutex<dyn X3DHClient + Send>>,
+    name: String,
+    device_id: u32,
+    count: u32,
+    timeouts: Timeouts,
+) -> Result<u32> {
+    let mut x3dh_client = x3dh_client.lock().await;
+    // The secrets are stored before the public keys are uploaded, so no sender gets a key we
+    // can't decrypt with.
+    let opks = x3dh_client.create_opks(count)?;
+    let response = deadline(
+        "upload_one_time_keys",
+        timeouts.rpc,
+        stub.upload_one_time_keys(request(
+            UploadOneTimeKeysRequest {
+                identity: Some(name),
+                device_id: Some(device_id),
+                one_time_key_bundle: Some(opks.clone().into()),
+            },
+            timeouts.rpc,
+        )),
+    )
+    .await?;
+    for (opk, id) in opks.pre_keys.iter().zip(&response.one_time_key_ids) {
+        x3dh_client.set_pre_key_id(opk, *id)?;
+    }
+    forget_rejected_opks(
+        &mut *x3dh_client,
+        &opks.pre_keys,
+        response.one_time_keys_accepted,
+    )?;
+    Ok(response.one_time_key_count())
+}
+
+/// Rotates the signed pre key for `name`'s `device_id` every `period`, giving up on each attempt
+/// after `timeouts`.
+pub async fn rotate_spk_periodically(
+    mut stub: BrongnalClient<Channel>,
+    x3dh_client: Arc<Mutex<dyn X3DHClient + Send>>,
+    name: String,
+    device_id: u32,
+    period: Duration,
+    timeouts: Timeouts,
+) {
+    let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+    loop {
+        interval.tick().await;
+        let result = rotate_spk(
+            &mut stub,
+            x3dh_client.clone(),
+            name.clone(),
+            device_id,
+            timeouts,
+        )
+        .await;
+        if let Err(e) = result {
+            eprintln!("Failed to rotate signed pre key: {e}");
+        }
+    }
+}
+
+/// Returns how many one time keys `name`'s `device_id` has left on the server.
+pub async fn count_one_time_keys(
+    stub: &mut BrongnalClient<Channel>,
+    name: String,
+    device_id: u32,
+    timeouts: Timeouts,
+) -> Result<u32> {
+    let response = deadline(
+        "count_one_time_keys",
+        timeouts.rpc,
+        stub.count_one_time_keys(request(
+            CountOneTimeKeysRequest {
+                identity: Some(name),
+                device_id: Some(device_id),
+            },
+            timeouts.rpc,
+        )),
+    )
+    .await?;
+    Ok(response.one_time_key_count())
+}
+
+/// Uploads a batch of one time keys for `name`'s `device_id` if the server has fewer than the
+/// low-water mark. Returns whether it did.
+pub async fn top_up_opks(
+    stub: &mut BrongnalClient<Channel>,
+    x3dh_client: Arc<Mutex<dyn X3DHClient + Send>>,
+    name: String,
+    device_id: u32,
+    top_up: OpkTopUp,
+    timeouts: Timeouts,
+) -> Result<bool> {
+    let count = count_one_time_keys(stub, name.clone(), device_id, timeouts).await?;
+    if count >= top_up.low_water_mark {
+        return Ok(false);
+    }
+    upload_one_time_keys(
+        stub,
+        x3dh_client,
+        name,
+        device_id,
+        top_up.batch_size,
+        timeouts,
+    )
+    .await?;
+    Ok(true)
+}
+
+/// `duration`, give or take a tenth.
+fn jittered(duration: Duration) -> Duration {
+    duration.mul_f64(0.9 + 0.2 * (OsRng.next_u64() as f64 / u64::MAX as f64))
+}
+
+/// Keeps `name`'s `device_id` stocked with one time keys, checking the server's count every
+/// `top_up.check_period`, after each burst of messages reported on `received` and whenever the
+/// server reports them running low. Returns once `received` closes.
+pub async fn top_up_opks_periodically(
+    mut stub: BrongnalClient<Channel>,
+    x3dh_client: Arc<Mutex<dyn X3DHClient + Send>>,
+    name: String,
+    device_id: u32,
+    top_up: OpkTopUp,
+    timeouts: Timeouts,
+    mut received: Receiver<ClientEvent>,
+) {
+    let mut next_check = Instant::now() + jittered(top_up.check_period);
+    let mut last_upload: Option<Instant> = None;
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep_until(next_check) => {}
+            event = received.recv() => match event {
+                // Each message we receive may have used up one of our keys.
+                Ok(ClientEvent::MessageReceived(_)) | Err(RecvError::Lagged(_)) => {
+                    tokio::time::sleep(jittered(top_up.settle_delay)).await;
+                    received = received.resubscribe();
+                }
+                Ok(ClientEvent::OneTimeKeysLow { .. }) => {}
+                Ok(_) => continue,
+                Err(RecvError::Closed) => return,
+            },
+        }
+        next_check = Instant::now() + jittered(top_up.check_period);
+        if let Some(last_upload) = last_upload {
+            if last_upload.elapsed() < top_up.min_upload_interval {
+                next_check = next_check.min(last_upload + top_up.min_upload_interval);
+                continue;
+            }
+        }
+        let result = top_up_opks(
+            &mut stub,
+            x3dh_client.clone(),
+            name.clone(),
+            device_id,
+            top_up,
+            timeouts,
+        )
+        .await;
+        match result {
+            Ok(true) => last_upload = Some(Instant::now()),
+            Ok(false) => {}
+            Err(e) => eprintln!("Failed to top up one time keys: {e}"),
+        }
+    }
+}
+
+/// Encrypts `message` from `sender_identity`'s `sender_device_id` separately to each of the
+/// recipient's devices, under the session kept with it or with X3DH if there is none, and sends
+/// the envelopes together. Returns the id `events` reports the message by. Fails with `TimedOut` if sending
+/// takes longer than `MESSAGE_TIMEOUT`, with `RecipientNotFound` if the server doesn't know the
+/// recipient and with `KeyRevoked` if one of their devices' identity keys has been revoked;
+/// `ClientError::classify` says which failures are worth retrying.
+#[allow(clippy::too_many_arguments)]
 pub async fn message(
     stub: &mut BrongnalClient<Channel>,
+    gossamer: &mut GossamerClient<Channel>,
     x3dh_client: Arc<Mutex<dyn X3DHClient + Send>>,
     sender_identity: String,
+    sender_device_id: u32,
     recipient_identity: &str,
-    message: &str,
-) -> Result<()> {
-    let message = message.as_bytes();
-    let request = tonic::Request::new(RequestPreKeysRequest {
-        identity: Some(recipient_identity.to_owned()),
-    });
-    let response = stub.request_pre_keys(request).await?;
-    let (_sk, message) = initiate_send(
-        response.into_inner().try_into()?,
+    message: &[u8],
+    policy: SendPolicy,
+    events: &Sender<ClientEvent>,
+) -> Result<MessageId> {
+    message_with_uuid(
+        stub,
+        gossamer,
+        x3dh_client,
         sender_identity,
-        &x3dh_client.lock().await.get_ik()?,
+        sender_device_id,
+        recipient_identity,
         message,
-    )?;
-    let request = tonic::Request::new(SendMessageRequest {
-        recipient_identity: Some(recipient_identity.to_owned()),
-        message: Some(message.into()),
+        policy,
+        new_message_uuid(),
+        Timeouts::default(),
+        events,
+    )
+    .await
+}
+
+/// Picks the uuid a new message is sent as. Retries of the message must reuse it.
+pub fn new_message_uuid() -> [u8; MESSAGE_UUID_LEN] {
+    let mut uuid = [0; MESSAGE_UUID_LEN];
+    OsRng.fill_bytes(&mut uuid);
+    uuid
+}
+
+/// The id `events` report the message sent as `uuid` by.
+pub fn message_id(uuid: &[u8; MESSAGE_UUID_LEN]) -> MessageId {
+    let mut id = [0; 8];
+    id.copy_from_slice(&uuid[..8]);
+    MessageId::from_be_bytes(id)
+}
+
+/// Like `message`, but sends the message as `uuid` and gives up after `timeouts` rather than the
+/// defaults. Retrying a send that failed, e.g. with `TimedOut`, with the same `uuid` delivers the
+/// message at most once, and reports it by the same id.
+#[allow(clippy::too_many_arguments)]
+pub async fn message_with_uuid(
+    stub: &mut BrongnalClient<Channel>,
+    gossamer: &mut GossamerClient<Channel>,
+    x3dh_client: Arc<Mutex<dyn X3DHClient + Send>>,
+    sender_identity: String,
+    sender_device_id: u32,
+    recipient_identity: &str,
+    message: &[u8],
+    policy: SendPolicy,
+    uuid: [u8; MESSAGE_UUID_LEN],
+    timeouts: Timeouts,
+    events: &Sender<ClientEvent>,
+) -> Result<MessageId> {
+    let send = send_to_devices(
+        stub,
+        gossamer,
+        x3dh_client.clone(),
+        sender_identity,
+        sender_device_id,
+        recipient_identity,
+        message,
+        ContentType::Text,
+        Devices::All,
+        policy,
+        uuid,
+        timeouts,
+        events,
+    );
+    let result = match tokio::time::timeout(timeouts.message, send).await {
+        Ok(result) => result,
+        Err(_) => Err(TimedOut {
+            operation: "message",
+            after: timeouts.message,
+        }
+        .into()),
+    };
+    match result {
+        Ok(no_one_time_key) => {
+            keep_sent_message(
+                &x3dh_client,
+                &uuid,
+                SentMessage {
+                    recipient: recipient_identity.to_owned(),
+                    message: message.to_vec(),
+                    sent_at: SystemTime::now(),
+                    attempts: 0,
+                    resent_from: None,
+                },
+                events,
+            )
+            .await;
+            let id = message_id(&uuid);
+            emit(
+                events,
+                ClientEvent::MessageSent {
+                    id,
+                    recipient: recipient_identity.to_owned(),
+                    no_one_time_key,
+                },
+            );
+            Ok(id)
+        }
+        Err(e) => {
+            emit(
+                events,
+                ClientEvent::Error(format!("Failed to message {recipient_identity}: {e}")),
+            );
+            Err(e)
+        }
+    }
+}
+
+/// Keeps `sent` in case the recipient can't decrypt it, and forgets the messages kept for longer
+/// than `SENT_MESSAGE_RETENTION`. Failing to is only worth a warning, since the message was sent.
+async fn keep_sent_message(
+    x3dh_client: &Mutex<dyn X3DHClient + Send>,
+    uuid: &[u8; MESSAGE_UUID_LEN],
+    sent: SentMessage,
+    events: &Sender<ClientEvent>,
+) {
+    let mut x3dh_client = x3dh_client.lock().await;
+    let result = x3dh_client.save_sent_message(uuid, sent).and_then(|()| {
+        x3dh_client.forget_sent_messages(SystemTime::now() - SENT_MESSAGE_RETENTION)
     });
-    stub.send_message(request).await?;
+    if let Err(e) = result {
+        emit(
+            events,
+            ClientEvent::Warning(format!(
+                "Failed to keep message {} in case it needs resending: {e}",
+                message_id(uuid)
+            )),
+        );
+    }
+}
+
+/// What binds `content_type` to a ciphertext as associated data, so the server can't change it
+/// without the message failing to decrypt. Empty for text, which is all senders that predate
+/// content types send, so their messages still decrypt.
+pub fn content_type_ad(content_type: ContentType) -> Vec<u8> {
+    match content_type {
+        ContentType::Text => Vec::new(),
+        content_type => (content_type as i32).to_be_bytes().to_vec(),
+    }
+}
+
+/// What binds a message's `content_type` and the `sender_device_id` it is from to its ciphertext
+/// as associated data. Messages that don't say which device they are from, which senders that
+/// predate sessions only send from device 1, bind the content type alone.
+pub fn message_ad(content_type: ContentType, sender_device_id: Option<u32>) -> Vec<u8> {
+    match sender_device_id {
+        Some(device_id) => [(content_type as i32).to_be_bytes(), device_id.to_be_bytes()].concat(),
+        None => content_type_ad(content_type),
+    }
+}
+
+/// The associated data of a message `sender_identity`'s `device_id` sends as `content_type` under
+/// the session it keeps with the recipient.
+pub fn session_ad(sender_identity: &str, device_id: u32, content_type: ContentType) -> Vec<u8> {
+    [
+        sender_identity.as_bytes(),
+        &message_ad(content_type, Some(device_id)),
+    ]
+    .concat()
+}
+
+/// Which of the recipient's devices `send_to_devices` sends to, and how.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Devices {
+    /// Every device, under the session kept with it if there is one and with X3DH otherwise.
+    All,
+    /// Only this device, with X3DH even if a session is kept with it, which the new one replaces.
+    /// For messages about ones that failed to decrypt, which the session may be why.
+    Fresh(u32),
+    /// Only this device, under the session kept with it, such as to confirm the key agreement
+    /// that set the session up. Fails if there is none.
+    Session(u32),
+}
+
+/// Encrypts `message` from `sender_device_id` to `devices` of the recipient and sends the
+/// envelopes as `uuid`. Each device that X3DH is used for keeps a session with this one once it
+/// decrypts the message, as this one does with it once the message is sent, so later messages
+/// go under the session. Returns whether any of the devices had run out of one time keys.
+#[allow(clippy::too_many_arguments)]
+async fn send_to_devices(
+    stub: &mut BrongnalClient<Channel>,
+    gossamer: &mut GossamerClient<Channel>,
+    x3dh_client: Arc<Mutex<dyn X3DHClient + Send>>,
+    sender_identity: String,
+    sender_device_id: u32,
+    recipient_identity: &str,
+    message: &[u8],
+    content_type: ContentType,
+    devices: Devices,
+    policy: SendPolicy,
+    uuid: [u8; MESSAGE_UUID_LEN],
+    timeouts: Timeouts,
+    events: &Sender<ClientEvent>,
+) -> Result<bool> {
+    // Checked before taking any of the recipient's one time keys.
+    if message.len() > MAX_MESSAGE_LEN {
+        return Err(MessageTooLarge {
+            len: message.len(),
+            max: MAX_MESSAGE_LEN,
+        }
+        .into());
+    }
+    if let Devices::Session(device_id) = devices {
+        let device_message = session_message(
+            &mut *x3dh_client.lock().await,
+            &sender_identity,
+            sender_device_id,
+            recipient_identity,
+            device_id,
+            message,
+            content_type,
+        )?;
+        send_envelopes(
+            stub,
+            recipient_identity,
+            vec![device_message],
+            true,
+            uuid,
+            message.len(),
+            timeouts,
+        )
+        .await?;
+        return Ok(false);
+    }
+    // Sessions with every device spare fetching the recipient's prekeys. The server refuses the
+    // envelopes if the recipient has devices without one.
+    if devices == Devices::All {
+        if let Some(device_messages) = session_messages(
+            gossamer,
+            &x3dh_client,
+            &sender_identity,
+            sender_device_id,
+            recipient_identity,
+            message,
+            content_type,
+            timeouts,
+        )
+        .await?
+        {
+            match send_envelopes(
+                stub,
+                recipient_identity,
+                device_messages,
+                false,
+                uuid,
+                message.len(),
+                timeouts,
+            )
+            .await
+            {
+                Ok(()) => return Ok(false),
+                Err(e)
+                    if e.downcast_ref::<Status>()
+                        .is_some_and(|status| status.code() == Code::FailedPrecondition) => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+    let mut bundles = deadline(
+        "request_pre_keys",
+        timeouts.rpc,
+        stub.request_pre_keys(request(
+            RequestPreKeysRequest {
+                identity: Some(recipient_identity.to_owned()),
+            },
+            timeouts.rpc,
+        )),
+    )
+    .await
+    .map_err(|e| match e.downcast_ref::<Status>() {
+        Some(status) if status.code() == Code::NotFound => RecipientNotFound {
+            peer: recipient_identity.to_owned(),
+        }
+        .into(),
+        _ => e,
+    })?
+    .bundles;
+    match devices {
+        Devices::All => {
+            // Sessions with devices the recipient no longer has are of no more use.
+            let mut x3dh_client = x3dh_client.lock().await;
+            for device_id in x3dh_client.session_devices(recipient_identity)? {
+                if !bundles.iter().any(|bundle| bundle.device_id() == device_id) {
+                    x3dh_client.delete_session(recipient_identity, device_id)?;
+                }
+            }
+        }
+        Devices::Fresh(device_id) => {
+            bundles.retain(|bundle| bundle.device_id() == device_id);
+            if bundles.is_empty() {
+                bail!("{recipient_identity} has no device {device_id}.");
+            }
+        }
+        Devices::Session(_) => unreachable!("sent under the session above"),
+    }
+    let ik = x3dh_client.lock().await.get_ik()?;
+    let mut device_messages = Vec::with_capacity(bundles.len());
+    let mut sessions = Vec::new();
+    let mut confirmations = Vec::new();
+    let mut no_one_time_key = false;
+    for bundle in bundles {
+        let device_id = bundle.device_id();
+        let uploaded_at = bundle.signed_pre_key_uploaded_at;
+        let bundle: x3dh::PreKeyBundle = bundle.try_into()?;
+        // A device that still has the identity key the session kept with it was set up with
+        // carries on under the session.
+        let has_session = devices == Devices::All
+            && x3dh_client
+                .lock()
+                .await
+                .get_session(recipient_identity, device_id)?
+                .is_some_and(|session| session.ik == bundle.ik);
+        // Servers that predate upload times don't report them, so there is nothing to check.
+        if let (Some(uploaded_at), false) = (uploaded_at, has_session) {
+            let age = SystemTime::now()
+                .duration_since(UNIX_EPOCH + Duration::from_secs(uploaded_at))
+                .unwrap_or_default();
+            if age > policy.spk_age.max_age {
+                let days = age.as_secs() / (24 * 60 * 60);
+                match policy.spk_age.action {
+                    StaleSpkAction::Warn => emit(events, ClientEvent::Warning(format!(
+                        "{recipient_identity}'s device {device_id} signed pre key is {days} days old."
+                    ))),
+                    StaleSpkAction::Refuse => bail!(
+                        "Refusing to message {recipient_identity}: their device {device_id} signed pre key is {days} days old."
+                    ),
+                }
+            }
+        }
+        if !has_session && (bundle.opk.is_none() || bundle.last_resort) {
+            if policy.require_otk {
+                return Err(NoOneTimeKey {
+                    peer: recipient_identity.to_owned(),
+                    device_id,
+                }
+                .into());
+            }
+            no_one_time_key = true;
+            emit(
+                events,
+                ClientEvent::NoOneTimeKey {
+                    peer: recipient_identity.to_owned(),
+                },
+            );
+        }
+        if is_revoked(gossamer, recipient_identity, &bundle.ik, timeouts).await? {
+            emit(
+                events,
+                ClientEvent::KeyChanged {
+                    identity: recipient_identity.to_owned(),
+                },
+            );
+            return Err(KeyRevoked {
+                peer: recipient_identity.to_owned(),
+                device_id,
+            }
+            .into());
+        }
+        if has_session {
+            device_messages.push(session_message(
+                &mut *x3dh_client.lock().await,
+                &sender_identity,
+                sender_device_id,
+                recipient_identity,
+                device_id,
+                message,
+                content_type,
+            )?);
+            continue;
+        }
+        let responder_ik = bundle.ik;
+        let (sk, message) = initiate_send_with_context(
+            bundle,
+            sender_identity.clone(),
+            &ik,
+            message,
+            &message_ad(content_type, Some(sender_device_id)),
+        )?;
+        // Only text is confirmed, lest two devices trade confirmations forever.
+        if content_type == ContentType::Text {
+            let transcript = Transcript {
+                initiator_ik: ik.verifying_key(),
+                responder_ik,
+                ek: message.ek,
+                opk_id: message.opk_id,
+            };
+            confirmations.push(PendingConfirmation {
+                peer: recipient_identity.to_owned(),
+                device_id,
+                mac: transcript.confirmation(&sk),
+                sent_at: SystemTime::now(),
+            });
+        }
+        sessions.push((
+            device_id,
+            PeerSession {
+                ik: responder_ik,
+                session: Session::new(&sk, Role::Initiator),
+            },
+        ));
+        let mut message: MessageProto = message.into();
+        message.set_content_type(content_type);
+        message.set_sender_device_id(sender_device_id);
+        device_messages.push(DeviceMessage {
+            device_id: Some(device_id),
+            message: Some(message),
+        });
+    }
+    send_envelopes(
+        stub,
+        recipient_identity,
+        device_messages,
+        matches!(devices, Devices::Fresh(_)),
+        uuid,
+        message.len(),
+        timeouts,
+    )
+    .await?;
+    keep_sessions(&x3dh_client, recipient_identity, sessions, events).await;
+    expect_key_confirmations(&x3dh_client, &uuid, confirmations, events).await;
+    Ok(no_one_time_key)
+}
+
+/// Encrypts `message` to each of the recipient's devices a session is kept with, or returns
+/// `None` if there are none, or any of them can't be read or is with an identity key that has
+/// since been revoked, in which case the recipient's prekeys say what to do instead.
+#[allow(clippy::too_many_arguments)]
+async fn session_messages(
+    gossamer: &mut GossamerClient<Channel>,
+    x3dh_client: &Mutex<dyn X3DHClient + Send>,
+    sender_identity: &str,
+    sender_device_id: u32,
+    recipient_identity: &str,
+    message: &[u8],
+    content_type: ContentType,
+    timeouts: Timeouts,
+) -> Result<Option<Vec<DeviceMessage>>> {
+    let mut sessions = Vec::new();
+    {
+        let mut x3dh_client = x3dh_client.lock().await;
+        for device_id in x3dh_client.session_devices(recipient_identity)? {
+            let Some(session) = x3dh_client.get_session(recipient_identity, device_id)? else {
+                return Ok(None);
+            };
+            sessions.push((device_id, session.ik));
+        }
+    }
+    if sessions.is_empty() {
+        return Ok(None);
+    }
+    for (_, ik) in &sessions {
+        if is_revoked(gossamer, recipient_identity, ik, timeouts).await? {
+            return Ok(None);
+        }
+    }
+    let mut x3dh_client = x3dh_client.lock().await;
+    let device_messages = sessions
+        .into_iter()
+        .map(|(device_id, _)| {
+            session_message(
+                &mut *x3dh_client,
+                sender_identity,
+                sender_device_id,
+                recipient_identity,
+                device_id,
+                message,
+                content_type,
+            )
+        })
+        .collect::<Result<_>>()?;
+    Ok(Some(device_messages))
+}
+
+/// Encrypts `message` for `recipient_identity`'s `device_id` under the session kept with it.
+fn session_message(
+    x3dh_client: &mut (dyn X3DHClient + Send),
+    sender_identity: &str,
+    sender_device_id: u32,
+    recipient_identity: &str,
+    device_id: u32,
+    message: &[u8],
+    content_type: ContentType,
+) -> Result<DeviceMessage> {
+    let ciphertext = session_encrypt(
+        x3dh_client,
+        recipient_identity,
+        device_id,
+        Payload {
+            msg: message,
+            aad: &session_ad(sender_identity, sender_device_id, content_type),
+        },
+    )?;
+    let mut message: MessageProto = SessionMessage {
+        sender_identity: sender_identity.to_owned(),
+        ciphertext,
+    }
+    .into();
+    message.set_content_type(content_type);
+    message.set_sender_device_id(sender_device_id);
+    Ok(DeviceMessage {
+        device_id: Some(device_id),
+        message: Some(message),
+    })
+}
+
+/// Sends `device_messages` to `recipient_identity` as `uuid`. Only `partial` envelopes may leave
+/// out some of the recipient's devices. `len` is the plaintext's, which `MessageTooLarge` reports
+/// should the server find the envelopes too large.
+async fn send_envelopes(
+    stub: &mut BrongnalClient<Channel>,
+    recipient_identity: &str,
+    device_messages: Vec<DeviceMessage>,
+    partial: bool,
+    uuid: [u8; MESSAGE_UUID_LEN],
+    len: usize,
+    timeouts: Timeouts,
+) -> Result<()> {
+    deadline(
+        "send_message",
+        timeouts.rpc,
+        stub.send_message(request(
+            SendMessageRequest {
+                recipient_identity: Some(recipient_identity.to_owned()),
+                message: None,
+                device_messages,
+                message_uuid: Some(uuid.to_vec()),
+                partial: partial.then_some(true),
+            },
+            timeouts.rpc,
+        )),
+    )
+    .await
+    .map_err(|e| {
+        // Servers may be configured with a lower limit than the default.
+        let max_ciphertext_len = e
+            .downcast_ref::<Status>()
+            .and_then(|status| status.metadata().get("max-ciphertext-len"))
+            .and_then(|max| max.to_str().ok()?.parse::<usize>().ok());
+        match max_ciphertext_len {
+            Some(max) => MessageTooLarge {
+                len,
+                max: max.saturating_sub(MIN_CIPHERTEXT_LEN),
+            }
+            .into(),
+            None => e,
+        }
+    })?;
+    Ok(())
+}
+
+/// Keeps `sessions`, set up with X3DH messages to `peer`'s devices that were just sent. Failing to
+/// is only worth a warning, since the next message sets up another.
+async fn keep_sessions(
+    x3dh_client: &Mutex<dyn X3DHClient + Send>,
+    peer: &str,
+    sessions: Vec<(u32, PeerSession)>,
+    events: &Sender<ClientEvent>,
+) {
+    let mut x3dh_client = x3dh_client.lock().await;
+    for (device_id, session) in sessions {
+        if let Err(e) = x3dh_client.save_session(peer, device_id, &session) {
+            emit(
+                events,
+                ClientEvent::Warning(format!(
+                    "Failed to keep the session with {peer}'s device {device_id}: {e}"
+                )),
+            );
+        }
+    }
+}
+
+/// Waits for each of `confirmations` of the message sent as `uuid`. Failing to is only worth a
+/// warning, since the message was sent.
+async fn expect_key_confirmations(
+    x3dh_client: &Mutex<dyn X3DHClient + Send>,
+    uuid: &[u8; MESSAGE_UUID_LEN],
+    confirmations: Vec<PendingConfirmation>,
+    events: &Sender<ClientEvent>,
+) {
+    let mut x3dh_client = x3dh_client.lock().await;
+    for confirmation in confirmations {
+        if let Err(e) = x3dh_client.expect_key_confirmation(uuid, confirmation) {
+            emit(
+                events,
+                ClientEvent::Warning(format!(
+                    "Failed to wait for confirmation of message {}: {e}",
+                    message_id(uuid)
+                )),
+            );
+        }
+    }
+}
+
+/// Reports the key agreements of messages sent more than `timeout` ago that the recipient's
+/// device hasn't confirmed as `ClientEvent::KeyNotConfirmed`, and stops waiting for them. The
+/// sessions they set up are dropped, so the next message to the device agrees on a new key
+/// rather than carry on under one the device never confirmed.
+pub async fn check_key_confirmations(
+    x3dh_client: Arc<Mutex<dyn X3DHClient + Send>>,
+    timeout: Duration,
+    events: &Sender<ClientEvent>,
+) -> Result<()> {
+    let mut x3dh_client = x3dh_client.lock().await;
+    let expired = x3dh_client.expire_key_confirmations(SystemTime::now() - timeout)?;
+    for (uuid, confirmation) in expired {
+        x3dh_client.delete_session(&confirmation.peer, confirmation.device_id)?;
+        emit(
+            events,
+            ClientEvent::KeyNotConfirmed {
+                id: message_id(&uuid),
+                recipient: confirmation.peer,
+                device_id: confirmation.device_id,
+            },
+        );
+    }
     Ok(())
 }
 
+/// Runs `check_key_confirmations` every tenth of `timeout`, so confirmations are reported missing
+/// soon after they are due.
+pub async fn check_key_confirmations_periodically(
+    x3dh_client: Arc<Mutex<dyn X3DHClient + Send>>,
+    timeout: Duration,
+    events: Sender<ClientEvent>,
+) {
+    let mut interval = tokio::time::interval(timeout / 10);
+    loop {
+        interval.tick().await;
+        if let Err(e) = check_key_confirmations(x3dh_client.clone(), timeout, &events).await {
+            eprintln!("Failed to check key confirmations: {e}");
+        }
+    }
+}
+
+/// Measures how long the server takes to answer a ping. Fails with `TimedOut` if it takes longer
+/// than `timeouts.rpc`.
+pub async fn ping(stub: &mut BrongnalClient<Channel>, timeouts: Timeouts) -> Result<Duration> {
+    let mut nonce = [0; MAX_PING_NONCE_LEN];
+    OsRng.fill_bytes(&mut nonce);
+    let start = Instant::now();
+    let response = deadline(
+        "ping",
+        timeouts.rpc,
+        stub.ping(request(
+            PingRequest {
+                nonce: Some(nonce.to_vec()),
+            },
+            timeouts.rpc,
+        )),
+    )
+    .await?;
+    if response.nonce() != nonce {
+        bail!("The server answered a different ping.");
+    }
+    Ok(start.elapsed())
+}
+
 // TODO(https://github.com/brongan/brongnal/issues/23) - Replace with stream of decrypted messages.
 // TODO(https://github.com/brongan/brongnal/issues/24) - Avoid blocking sqlite calls from async.
+/// Decrypts each message on `stream`, sent to `name`'s `device_id`, and reports it on `events`,
+/// along with the server's reports of our one time keys running low. A message that can't be
+/// handled is reported as an error and skipped, and its sender is told so they can resend it;
+/// only losing the stream, it carrying neither messages nor heartbeats for `timeouts.heartbeat`,
+/// or the server not answering a ping once it has been quiet for `timeouts.probe`, ends the loop.
+#[allow(clippy::too_many_arguments)]
 pub async fn get_messages(
     mut stream: Streaming<MessageProto>,
+    mut stub: BrongnalClient<Channel>,
+    mut gossamer: GossamerClient<Channel>,
     x3dh_client: Arc<Mutex<dyn X3DHClient + Send>>,
-    tx: Sender<DecryptedMessage>,
+    name: &str,
+    device_id: u32,
+    events: &Sender<ClientEvent>,
+    timeouts: Timeouts,
 ) -> Result<()> {
-    while let Some(message) = stream.message().await? {
-        let x3dh::Message {
-            sender_identity,
-            sender_ik,
-            ek,
-            opk,
-            ciphertext,
-        } = message.try_into()?;
+    let mut last_heard = Instant::now();
+    let mut next_probe = last_heard + timeouts.probe;
+    let mut session_failures = SessionFailures::default();
+    loop {
+        let give_up = last_heard + timeouts.heartbeat;
+        let message = match tokio::time::timeout_at(next_probe.min(give_up), stream.message()).await
+        {
+            Ok(message) => message?,
+            Err(_) if next_probe < give_up => {
+                let rtt = ping(&mut stub, timeouts)
+                    .await
+                    .context("The server stopped answering pings")?;
+                emit(events, ClientEvent::RoundTripTime(rtt));
+                next_probe = Instant::now() + timeouts.probe;
+                continue;
+            }
+            Err(_) => bail!("No heartbeat from the server in {:?}", timeouts.heartbeat),
+        };
+        let Some(message) = message else {
+            break;
+        };
+        last_heard = Instant::now();
+        next_probe = last_heard + timeouts.probe;
+        let sender = message.sender().unwrap_or_default().to_owned();
+        // Bound to the ciphertext, so it can only be trusted once the message decrypts with it.
+        let Ok(content_type) = ContentType::try_from(message.content_type().unwrap_or_default())
+        else {
+            emit(
+                events,
+                ClientEvent::Warning(format!(
+                    "Ignoring a message from {sender} this client doesn't know how to show."
+                )),
+            );
+            continue;
+        };
+        // Like the content type. Senders that predate sessions only send from the default device.
+        let sender_device_id = message.sender_device_id();
+        let sender_device = sender_device_id.unwrap_or(DEFAULT_DEVICE_ID);
+        let uuid = <[u8; MESSAGE_UUID_LEN]>::try_from(message.message_uuid()).ok();
+        let payload = MessagePayload::try_from(message);
+        let under_session = matches!(payload, Ok(MessagePayload::SessionCiphertext(_)));
+        // Only messages that set up a session have a key agreement to confirm.
+        let decrypted = match payload {
+            Ok(MessagePayload::X3DHInitial(message)) => {
+                decrypt_message(
+                    *message,
+                    content_type,
+                    sender_device_id,
+                    &mut gossamer,
+                    &x3dh_client,
+                    timeouts,
+                )
+                .await
+            }
+            Ok(MessagePayload::SessionCiphertext(message)) => decrypt_session_message(
+                message,
+                sender_device,
+                content_type,
+                &mut gossamer,
+                &x3dh_client,
+                timeouts,
+            )
+            .await
+            .map(|message| (message, None)),
+            // Heartbeats only keep the stream alive.
+            Ok(MessagePayload::ServerControl(control)) => {
+                if let Some(status) = control.pre_key_status {
+                    emit(
+                        events,
+                        ClientEvent::OneTimeKeysLow {
+                            remaining: status.remaining(),
+                        },
+                    );
+                }
+                continue;
+            }
+            Ok(MessagePayload::Unknown) => {
+                emit(
+                    events,
+                    ClientEvent::Warning(String::from(
+                        "Ignoring a message in a form this client doesn't know how to read.",
+                    )),
+                );
+                continue;
+            }
+            Err(e) => Err(e.into()),
+        };
+        match decrypted {
+            Ok((message, mac)) => {
+                if under_session || mac.is_some() {
+                    session_failures.succeeded(&sender);
+                }
+                if message.sender_revoked {
+                    emit(
+                        events,
+                        ClientEvent::KeyChanged {
+                            identity: message.sender_identity.clone(),
+                        },
+                    );
+                }
+                if message.sender_verification == SenderVerification::Mismatch {
+                    emit(
+                        events,
+                        ClientEvent::Warning(format!(
+                            "A message claiming to be from {} is from an identity key they haven't published.",
+                            message.sender_identity
+                        )),
+                    );
+                }
+                // It decrypted, so `content_type` is the one the sender sealed it as.
+                match content_type {
+                    ContentType::Text => {
+                        emit(events, ClientEvent::MessageReceived(message));
+                        // Messages without a uuid can't be identified to the sender.
+                        if let (Some(uuid), Some(mac)) = (uuid, mac) {
+                            let (stub, gossamer, x3dh_client, name, events) = (
+                                stub.clone(),
+                                gossamer.clone(),
+                                x3dh_client.clone(),
+                                name.to_owned(),
+                                events.clone(),
+                            );
+                            tokio::spawn(async move {
+                                if let Err(e) = send_key_confirmation(
+                                    stub,
+                                    gossamer,
+                                    x3dh_client,
+                                    name,
+                                    device_id,
+                                    &sender,
+                                    sender_device,
+                                    uuid,
+                                    mac,
+                                    timeouts,
+                                    &events,
+                                )
+                                .await
+                                {
+                                    emit(
+                                        &events,
+                                        ClientEvent::Error(format!(
+                                            "Failed to confirm a message's key to {sender}: {e}"
+                                        )),
+                                    );
+                                }
+                            });
+                        }
+                    }
+                    ContentType::DecryptionFailure => {
+                        let (stub, gossamer, x3dh_client, name, events) = (
+                            stub.clone(),
+                            gossamer.clone(),
+                            x3dh_client.clone(),
+                            name.to_owned(),
+                            events.clone(),
+                        );
+                        tokio::spawn(async move {
+                            if let Err(e) = resend(
+                                stub,
+                                gossamer,
+                                x3dh_client,
+                                name,
+                                device_id,
+                                &sender,
+                                &message.message,
+                                timeouts,
+                                &events,
+                            )
+                            .await
+                            {
+                                emit(
+                                    &events,
+                                    ClientEvent::Error(format!(
+                                        "Failed to resend a message to {sender}: {e}"
+                                    )),
+                                );
+                            }
+                        });
+                    }
+                    ContentType::KeyConfirmation => {
+                        if let Err(e) =
+                            accept_key_confirmation(&x3dh_client, &sender, &message.message, events)
+                                .await
+                        {
+                            emit(
+                                events,
+                                ClientEvent::Error(format!(
+                                    "Rejected a key confirmation from {sender}: {e}"
+                                )),
+                            );
+                        }
+                    }
+                    // The X3DH message the reset came in already replaced the session with the
+                    // sender's device, if its key agreement was authentic enough to.
+                    ContentType::SessionReset => {
+                        if mac.is_some() {
+                            emit(events, ClientEvent::SessionReset { peer: sender });
+                        }
+                    }
+                }
+            }
+            // Not the sender's doing, so it neither counts against the session nor asks them to
+            // send the message again.
+            Err(e) if matches!(e.downcast_ref(), Some(SessionError::ReplayDetected(_))) => {
+                emit(events, ClientEvent::ReplayDetected { peer: sender });
+            }
+            // Most likely confirms a key agreement since replaced by a newer one, under whose
+            // session the sender hadn't yet sent it, so it is of no more use.
+            Err(e) if under_session && content_type == ContentType::KeyConfirmation => {
+                emit(
+                    events,
+                    ClientEvent::Warning(format!(
+                        "Ignoring a key confirmation from {sender} that doesn't decrypt: {e}"
+                    )),
+                );
+            }
+            Err(e) => {
+                emit(
+                    events,
+                    ClientEvent::Error(format!("Failed to decrypt a message from {sender:?}: {e}")),
+                );
+                // Anyone can claim to be the sender of a message that doesn't decrypt, so only the
+                // failures a reset can't make worse count towards one: those where no session is
+                // kept with them to lose.
+                if matches!(e.downcast_ref(), Some(SessionError::NoSession))
+                    && session_failures.failed(&sender)
+                {
+                    let (stub, gossamer, x3dh_client, name, events, sender) = (
+                        stub.clone(),
+                        gossamer.clone(),
+                        x3dh_client.clone(),
+                        name.to_owned(),
+                        events.clone(),
+                        sender.clone(),
+                    );
+                    tokio::spawn(async move {
+                        if let Err(e) = reset_session(
+                            stub,
+                            gossamer,
+                            x3dh_client,
+                            name,
+                            device_id,
+                            &sender,
+                            sender_device,
+                            timeouts,
+                            &events,
+                        )
+                        .await
+                        {
+                            emit(
+                                &events,
+                                ClientEvent::Error(format!(
+                                    "Failed to reset the session with {sender}: {e}"
+                                )),
+                            );
+                        }
+                    });
+                }
+                // Never about a notice, lest two devices that can't decrypt each other's messages
+                // trade notices forever. Messages without a uuid can't be identified to the sender.
+                if let (ContentType::Text, Some(uuid)) = (content_type, uuid) {
+                    let (stub, gossamer, x3dh_client, name, events) = (
+                        stub.clone(),
+                        gossamer.clone(),
+                        x3dh_client.clone(),
+                        name.to_owned(),
+                        events.clone(),
+                    );
+                    tokio::spawn(async move {
+                        if let Err(e) = report_decryption_failure(
+                            stub,
+                            gossamer,
+                            x3dh_client,
+                            name,
+                            device_id,
+                            &sender,
+                            sender_device,
+                            uuid,
+                            timeouts,
+                            &events,
+                        )
+                        .await
+                        {
+                            emit(
+                                &events,
+                                ClientEvent::Error(format!(
+                                    "Failed to tell {sender} a message didn't decrypt: {e}"
+                                )),
+                            );
+                        }
+                    });
+                }
+            }
+        }
+    }
+    eprintln!("Server terminated message stream.");
+    Ok(())
+}
+
+/// Counts the messages from each peer that failed to decrypt for want of a session with them, to
+/// tell when the session needs resetting.
+#[derive(Default)]
+struct SessionFailures {
+    /// Failures in a row, by peer.
+    failures: HashMap<String, u32>,
+    /// When the session with each peer was last reset.
+    last_reset: HashMap<String, Instant>,
+}
+
+impl SessionFailures {
+    /// Records that a message from `peer` decrypted under a session with them, or set one up.
+    fn succeeded(&mut self, peer: &str) {
+        self.failures.remove(peer);
+    }
+
+    /// Records that a message from `peer` failed to decrypt for want of a session, returning whether
+    /// the session should now be reset: after `SESSION_RESET_AFTER` failures in a row, unless it
+    /// was reset less than `SESSION_RESET_COOLDOWN` ago.
+    fn failed(&mut self, peer: &str) -> bool {
+        let failures = self.failures.entry(peer.to_owned()).or_default();
+        *failures += 1;
+        if *failures < SESSION_RESET_AFTER
+            || self
+                .last_reset
+                .get(peer)
+                .is_some_and(|last| last.elapsed() < SESSION_RESET_COOLDOWN)
+        {
+            return false;
+        }
+        self.failures.remove(peer);
+        self.last_reset.insert(peer.to_owned(), Instant::now());
+        true
+    }
+}
+
+/// Sets up a new session between `name`'s `device_id` and `peer`'s `peer_device_id` with X3DH,
+/// which replaces the one they keep once they have authenticated it.
+#[allow(clippy::too_many_arguments)]
+async fn reset_session(
+    mut stub: BrongnalClient<Channel>,
+    mut gossamer: GossamerClient<Channel>,
+    x3dh_client: Arc<Mutex<dyn X3DHClient + Send>>,
+    name: String,
+    device_id: u32,
+    peer: &str,
+    peer_device_id: u32,
+    timeouts: Timeouts,
+    events: &Sender<ClientEvent>,
+) -> Result<()> {
+    emit(
+        events,
+        ClientEvent::SessionReset {
+            peer: peer.to_owned(),
+        },
+    );
+    let send = send_to_devices(
+        &mut stub,
+        &mut gossamer,
+        x3dh_client,
+        name,
+        device_id,
+        peer,
+        &[],
+        ContentType::SessionReset,
+        Devices::Fresh(peer_device_id),
+        SendPolicy::default(),
+        new_message_uuid(),
+        timeouts,
+        events,
+    );
+    tokio::time::timeout(timeouts.message, send)
+        .await
+        .map_err(|_| TimedOut {
+            operation: "reset_session",
+            after: timeouts.message,
+        })??;
+    Ok(())
+}
+
+/// Tells `sender`'s `sender_device_id` that `name`'s `device_id` couldn't decrypt the message it
+/// sent as `uuid`.
+#[allow(clippy::too_many_arguments)]
+async fn report_decryption_failure(
+    mut stub: BrongnalClient<Channel>,
+    mut gossamer: GossamerClient<Channel>,
+    x3dh_client: Arc<Mutex<dyn X3DHClient + Send>>,
+    name: String,
+    device_id: u32,
+    sender: &str,
+    sender_device_id: u32,
+    uuid: [u8; MESSAGE_UUID_LEN],
+    timeouts: Timeouts,
+    events: &Sender<ClientEvent>,
+) -> Result<()> {
+    let failure = DecryptionFailure {
+        message_uuid: Some(uuid.to_vec()),
+        device_id: Some(device_id),
+    }
+    .encode_to_vec();
+    let send = send_to_devices(
+        &mut stub,
+        &mut gossamer,
+        x3dh_client,
+        name,
+        device_id,
+        sender,
+        &failure,
+        ContentType::DecryptionFailure,
+        Devices::Fresh(sender_device_id),
+        SendPolicy::default(),
+        new_message_uuid(),
+        timeouts,
+        events,
+    );
+    tokio::time::timeout(timeouts.message, send)
+        .await
+        .map_err(|_| TimedOut {
+            operation: "report_decryption_failure",
+            after: timeouts.message,
+        })??;
+    Ok(())
+}
+
+/// Proves to `sender`'s `sender_device_id` that `name`'s `device_id` derived the same key as it
+/// did for the message it sent as `uuid`, with the `mac` over its key agreement. Sent under the
+/// session the key agreement set up, so it takes none of the sender's one time keys and reaches
+/// none of their other devices.
+#[allow(clippy::too_many_arguments)]
+async fn send_key_confirmation(
+    mut stub: BrongnalClient<Channel>,
+    mut gossamer: GossamerClient<Channel>,
+    x3dh_client: Arc<Mutex<dyn X3DHClient + Send>>,
+    name: String,
+    device_id: u32,
+    sender: &str,
+    sender_device_id: u32,
+    uuid: [u8; MESSAGE_UUID_LEN],
+    mac: [u8; MAC_LEN],
+    timeouts: Timeouts,
+    events: &Sender<ClientEvent>,
+) -> Result<()> {
+    let confirmation = KeyConfirmation {
+        message_uuid: Some(uuid.to_vec()),
+        device_id: Some(device_id),
+        mac: Some(mac.to_vec()),
+    }
+    .encode_to_vec();
+    let send = send_to_devices(
+        &mut stub,
+        &mut gossamer,
+        x3dh_client,
+        name,
+        device_id,
+        sender,
+        &confirmation,
+        ContentType::KeyConfirmation,
+        Devices::Session(sender_device_id),
+        SendPolicy::default(),
+        new_message_uuid(),
+        timeouts,
+        events,
+    );
+    tokio::time::timeout(timeouts.message, send)
+        .await
+        .map_err(|_| TimedOut {
+            operation: "send_key_confirmation",
+            after: timeouts.message,
+        })??;
+    Ok(())
+}
+
+/// Checks the `confirmation` `peer` sent of a message this device sent them, marking the session
+/// with them confirmed if it matches. Confirmations of messages this device didn't send, or
+/// stopped waiting for, are ignored.
+async fn accept_key_confirmation(
+    x3dh_client: &Mutex<dyn X3DHClient + Send>,
+    peer: &str,
+    confirmation: &[u8],
+    events: &Sender<ClientEvent>,
+) -> Result<()> {
+    let confirmation =
+        KeyConfirmation::decode(confirmation).context("Malformed key confirmation")?;
+    let uuid = <[u8; MESSAGE_UUID_LEN]>::try_from(confirmation.message_uuid())
+        .context("Key confirmation has an invalid message uuid")?;
+    let device_id = confirmation.device_id();
+    let mut x3dh_client = x3dh_client.lock().await;
+    let Some(pending) = x3dh_client.take_key_confirmation(&uuid, device_id)? else {
+        return Ok(());
+    };
+    let id = message_id(&uuid);
+    // Anyone can claim to be `peer`, so keep waiting for the real confirmation.
+    if pending.peer != peer {
+        let recipient = pending.peer.clone();
+        x3dh_client.expect_key_confirmation(&uuid, pending)?;
+        bail!("{peer} claims to confirm message {id}, which was sent to {recipient}.");
+    }
+    if confirmation::verify(&pending.mac, confirmation.mac()).is_err() {
+        x3dh_client.expect_key_confirmation(&uuid, pending)?;
+        bail!("{peer}'s device {device_id} confirmed message {id} with a key other than ours.");
+    }
+    x3dh_client.confirm_session(peer)?;
+    emit(
+        events,
+        ClientEvent::SessionConfirmed {
+            peer: peer.to_owned(),
+            device_id,
+        },
+    );
+    Ok(())
+}
+
+/// Resends the message `failure` is about from `name`'s `own_device_id` to the device of `peer`
+/// that couldn't decrypt it, encrypted to a fresh prekey bundle, unless it has already been resent
+/// `MAX_RESEND_ATTEMPTS` times. Resent copies count against the message as first sent, so notices
+/// about them and it alike are refused after that. Notices about messages this device didn't send,
+/// or no longer keeps, are ignored.
+#[allow(clippy::too_many_arguments)]
+async fn resend(
+    mut stub: BrongnalClient<Channel>,
+    mut gossamer: GossamerClient<Channel>,
+    x3dh_client: Arc<Mutex<dyn X3DHClient + Send>>,
+    name: String,
+    own_device_id: u32,
+    peer: &str,
+    failure: &[u8],
+    timeouts: Timeouts,
+    events: &Sender<ClientEvent>,
+) -> Result<()> {
+    let failure =
+        DecryptionFailure::decode(failure).context("Malformed decryption failure notice")?;
+    let uuid = <[u8; MESSAGE_UUID_LEN]>::try_from(failure.message_uuid())
+        .context("Decryption failure notice has an invalid message uuid")?;
+    let device_id = failure.device_id();
+    let sent = {
         let mut x3dh_client = x3dh_client.lock().await;
-        let opk = if let Some(opk) = opk {
-            // TODO(#28) - Handle a missing one-time prekey.
-            Some(x3dh_client.fetch_wipe_opk(&opk)?)
-        } else {
-            None
+        // Messages past keeping aren't resent, however long since a send last forgot them.
+        x3dh_client.forget_sent_messages(SystemTime::now() - SENT_MESSAGE_RETENTION)?;
+        x3dh_client.get_sent_message(&uuid)?
+    };
+    let Some(sent) = sent else {
+        return Ok(());
+    };
+    // Anyone can claim to be `peer`, but the message only ever goes back to its recipient.
+    if sent.recipient != peer {
+        bail!(
+            "{peer} claims to have been sent a message that was sent to {}.",
+            sent.recipient
+        );
+    }
+    let id = message_id(&uuid);
+    let original = sent.resent_from.unwrap_or(uuid);
+    let counted = match sent.resent_from {
+        Some(original) => match x3dh_client.lock().await.get_sent_message(&original)? {
+            Some(counted) => counted,
+            None => return Ok(()),
+        },
+        None => sent.clone(),
+    };
+    if counted.attempts >= MAX_RESEND_ATTEMPTS {
+        bail!("{peer}'s device {device_id} still can't decrypt message {id} after {MAX_RESEND_ATTEMPTS} attempts.");
+    }
+    // Counted before resending, so failing to keep the count can't lift the limit.
+    let attempts = counted.attempts + 1;
+    x3dh_client.lock().await.save_sent_message(
+        &original,
+        SentMessage {
+            attempts,
+            ..counted
+        },
+    )?;
+    let resent_as = new_message_uuid();
+    let send = send_to_devices(
+        &mut stub,
+        &mut gossamer,
+        x3dh_client.clone(),
+        name,
+        own_device_id,
+        peer,
+        &sent.message,
+        ContentType::Text,
+        Devices::Fresh(device_id),
+        SendPolicy::default(),
+        resent_as,
+        timeouts,
+        events,
+    );
+    tokio::time::timeout(timeouts.message, send)
+        .await
+        .map_err(|_| TimedOut {
+            operation: "resend",
+            after: timeouts.message,
+        })??;
+    keep_sent_message(
+        &x3dh_client,
+        &resent_as,
+        SentMessage {
+            sent_at: SystemTime::now(),
+            attempts,
+            resent_from: Some(original),
+            ..sent
+        },
+        events,
+    )
+    .await;
+    emit(
+        events,
+        ClientEvent::MessageResent {
+            id,
+            resent_as: message_id(&resent_as),
+            recipient: peer.to_owned(),
+            device_id,
+        },
+    );
+    Ok(())
+}
+
+/// Decrypts `message`, which only succeeds if it was sent as `content_type` from
+/// `sender_device_id`. The session the key agreement sets up replaces any kept with the sender's
+/// device, unless the message is from a key the sender hasn't published or has revoked, or from
+/// one nothing vouches for in place of the session's. Only if it does is the message returned
+/// with the MAC that confirms its key agreement to the sender, under the session.
+async fn decrypt_message(
+    message: x3dh::Message,
+    content_type: ContentType,
+    sender_device_id: Option<u32>,
+    gossamer: &mut GossamerClient<Channel>,
+    x3dh_client: &Mutex<dyn X3DHClient + Send>,
+    timeouts: Timeouts,
+) -> Result<(DecryptedMessage, Option<[u8; MAC_LEN]>)> {
+    let x3dh::Message {
+        sender_identity,
+        sender_ik,
+        ek,
+        spk_id,
+        opk_id,
+        opk,
+        last_resort,
+        kem_pre_key_id,
+        kem_last_resort,
+        kem_ciphertext,
+        suite,
+        version,
+        ciphertext,
+    } = message;
+    let KeyStatus {
+        verification: sender_verification,
+        revoked: sender_revoked,
+    } = key_status(gossamer, &sender_identity, &sender_ik, timeouts).await?;
+    let mut x3dh_client = x3dh_client.lock().await;
+    // TODO(#28) - Handle a missing one-time prekey.
+    let opk = match (opk_id, opk) {
+        (Some(id), _) if last_resort => Some(x3dh_client.get_last_resort_secret(id)?),
+        (Some(id), _) => Some(x3dh_client.fetch_wipe_opk_by_id(id)?),
+        (None, Some(opk)) => Some(x3dh_client.fetch_wipe_opk(&opk)?),
+        (None, None) => None,
+    };
+    let spk = match spk_id {
+        Some(id) => x3dh_client.get_spk_secret(id)?,
+        None => x3dh_client.get_pre_key()?,
+    };
+    // Messages without a KEM ciphertext are classic X3DH. If one was stripped in transit the
+    // key agreement doesn't match the sender's and decryption fails.
+    let kem = match (kem_pre_key_id, &kem_ciphertext) {
+        (Some(id), Some(kem_ciphertext)) if kem_last_resort => Some((
+            x3dh_client.get_last_resort_kem_secret(id)?,
+            &kem_ciphertext[..],
+        )),
+        (Some(id), Some(kem_ciphertext)) => Some((
+            x3dh_client.fetch_wipe_kem_opk_by_id(id)?,
+            &kem_ciphertext[..],
+        )),
+        (None, None) => None,
+        _ => bail!("Message from {sender_identity} has an incomplete KEM key agreement."),
+    };
+    let ik = x3dh_client.get_ik()?;
+    let (sk, message) = initiate_recv_with_context(
+        &ik,
+        &spk,
+        &sender_ik,
+        ek,
+        opk,
+        kem,
+        suite,
+        version,
+        &ciphertext,
+        &message_ad(content_type, sender_device_id),
+    )?;
+    // The session with the sender's device is replaced, but not by a key that isn't theirs or is
+    // revoked, nor one nothing vouches for in place of the key the session was set up with.
+    let sender_device = sender_device_id.unwrap_or(DEFAULT_DEVICE_ID);
+    let replaces = !sender_revoked
+        && sender_verification != SenderVerification::Mismatch
+        && match x3dh_client.get_session(&sender_identity, sender_device)? {
+            Some(session) => {
+                session.ik == sender_ik || sender_verification == SenderVerification::Verified
+            }
+            None => true,
         };
-        let (_sk, message) = initiate_recv(
-            &x3dh_client.get_ik()?,
-            &x3dh_client.get_pre_key()?,
-            &sender_ik,
-            ek,
-            opk,
-            &ciphertext,
+    if replaces {
+        x3dh_client.save_session(
+            &sender_identity,
+            sender_device,
+            &PeerSession {
+                ik: sender_ik,
+                session: Session::new(&sk, Role::Responder),
+            },
         )?;
-        tx.send(DecryptedMessage {
+    }
+    let transcript = Transcript {
+        initiator_ik: sender_ik,
+        responder_ik: ik.verifying_key(),
+        ek,
+        opk_id,
+    };
+    let session_state = x3dh_client.session_state(&sender_identity)?;
+    Ok((
+        DecryptedMessage {
             sender_identity,
             message,
-        })
-        .await?;
-    }
-    eprintln!("Server terminated message stream.");
-    Ok(())
+            sender_revoked,
+            sender_verification,
+            session_state,
+        },
+        replaces.then(|| transcript.confirmation(&sk)),
+    ))
+}
+
+/// Decrypts `message` under the session kept with its sender's `sender_device_id`, which only
+/// succeeds if it was sent as `content_type`. The sender's key status is that of the identity key
+/// the session was set up with.
+async fn decrypt_session_message(
+    message: SessionMessage,
+    sender_device_id: u32,
+    content_type: ContentType,
+    gossamer: &mut GossamerClient<Channel>,
+    x3dh_client: &Mutex<dyn X3DHClient + Send>,
+    timeouts: Timeouts,
+) -> Result<DecryptedMessage> {
+    let SessionMessage {
+        sender_identity,
+        ciphertext,
+    } = message;
+    let (message, sender_ik) = session_decrypt(
+        &mut *x3dh_client.lock().await,
+        &sender_identity,
+        sender_device_id,
+        &ciphertext,
+        &session_ad(&sender_identity, sender_device_id, content_type),
+    )?;
+    let KeyStatus {
+        verification: sender_verification,
+        revoked: sender_revoked,
+    } = key_status(gossamer, &sender_identity, &sender_ik, timeouts).await?;
+    let session_state = x3dh_client.lock().await.session_state(&sender_identity)?;
+    Ok(DecryptedMessage {
+        sender_identity,
+        message,
+        sender_revoked,
+        sender_verification,
+        session_state,
+    })
 }
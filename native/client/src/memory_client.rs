@@ -1,17 +1,37 @@
-use crate::X3DHClient;
-use anyhow::{Context, Result};
+use crate::{
+    KeyBackup, PeerSession, PendingConfirmation, SentMessage, SessionState, X3DHClient,
+    RETAINED_SPKS,
+};
+use anyhow::{bail, Context, Result};
 use chacha20poly1305::aead::OsRng;
 use ed25519_dalek::SigningKey;
+use proto::MESSAGE_UUID_LEN;
 use protocol::bundle::{create_prekey_bundle, sign_bundle};
+use protocol::kem::{self, sign_kem_pre_key, KemPublicKey, KemSecretKey, SignedKemPreKey};
 use protocol::x3dh;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::SystemTime;
 use x25519_dalek::{PublicKey as X25519PublicKey, StaticSecret as X25519StaticSecret};
 use x3dh::{SignedPreKey, SignedPreKeys};
 
 pub struct MemoryClient {
     ik: SigningKey,
     pre_key: X25519StaticSecret,
+    spk_id: Option<u32>,
+    /// Signed pre keys replaced by `rotate_spk` and their ids, newest first.
+    old_spks: VecDeque<(Option<u32>, X25519StaticSecret)>,
     opks: HashMap<X25519PublicKey, X25519StaticSecret>,
+    opk_ids: HashMap<u32, X25519PublicKey>,
+    /// Last-resort keys and their ids, newest first. The first is the current one.
+    last_resort_keys: VecDeque<(Option<u32>, X25519StaticSecret)>,
+    kem_opks: HashMap<KemPublicKey, KemSecretKey>,
+    kem_opk_ids: HashMap<u32, KemPublicKey>,
+    /// Like `last_resort_keys`. Empty if built without ML-KEM support.
+    last_resort_kem_keys: VecDeque<(Option<u32>, KemSecretKey)>,
+    sent_messages: HashMap<[u8; MESSAGE_UUID_LEN], SentMessage>,
+    sessions: HashMap<(String, u32), PeerSession>,
+    key_confirmations: HashMap<([u8; MESSAGE_UUID_LEN], u32), PendingConfirmation>,
+    confirmed_sessions: HashSet<String>,
 }
 
 impl Default for MemoryClient {
@@ -22,24 +42,56 @@ impl Default for MemoryClient {
 
 impl MemoryClient {
     pub fn new() -> Self {
+        Self::with_ik(SigningKey::generate(&mut OsRng))
+    }
+
+    /// Creates a client for a device linked to the identity `ik` belongs to.
+    pub fn with_ik(ik: SigningKey) -> Self {
         Self {
-            ik: SigningKey::generate(&mut OsRng),
+            ik,
             pre_key: X25519StaticSecret::random_from_rng(OsRng),
+            spk_id: None,
+            old_spks: VecDeque::new(),
             opks: HashMap::new(),
+            opk_ids: HashMap::new(),
+            last_resort_keys: VecDeque::from([(None, X25519StaticSecret::random_from_rng(OsRng))]),
+            kem_opks: HashMap::new(),
+            kem_opk_ids: HashMap::new(),
+            // Generating fails only when built without ML-KEM support.
+            last_resort_kem_keys: kem::generate().into_iter().map(|key| (None, key)).collect(),
+            sent_messages: HashMap::new(),
+            sessions: HashMap::new(),
+            key_confirmations: HashMap::new(),
+            confirmed_sessions: HashSet::new(),
+        }
+    }
+
+    fn sign(&self, pre_key: &X25519StaticSecret) -> SignedPreKey {
+        SignedPreKey {
+            pre_key: X25519PublicKey::from(pre_key),
+            signature: sign_bundle(
+                &self.ik,
+                &[(pre_key.clone(), X25519PublicKey::from(pre_key))],
+            ),
         }
     }
 }
 
 impl X3DHClient for MemoryClient {
-    fn fetch_wipe_opk(
-        &mut self,
-        opk: &X25519PublicKey,
-    ) -> Result<X25519StaticSecret> {
+    fn fetch_wipe_opk(&mut self, opk: &X25519PublicKey) -> Result<X25519StaticSecret> {
         self.opks
             .remove(opk)
             .context("Client failed to find pre key.")
     }
 
+    fn fetch_wipe_opk_by_id(&mut self, id: u32) -> Result<X25519StaticSecret> {
+        let opk = self
+            .opk_ids
+            .remove(&id)
+            .context("Client failed to find pre key id.")?;
+        self.fetch_wipe_opk(&opk)
+    }
+
     fn get_ik(&self) -> Result<SigningKey> {
         Ok(self.ik.clone())
     }
@@ -48,14 +100,36 @@ impl X3DHClient for MemoryClient {
         Ok(self.pre_key.clone())
     }
 
+    fn get_spk_secret(&self, id: u32) -> Result<X25519StaticSecret> {
+        if self.spk_id == Some(id) {
+            return self.get_pre_key();
+        }
+        self.old_spks
+            .iter()
+            .find(|(spk_id, _)| *spk_id == Some(id))
+            .map(|(_, spk)| spk.clone())
+            .context(format!("Client has no signed pre key with id {id}."))
+    }
+
+    fn set_pre_key_id(&mut self, key: &X25519PublicKey, id: u32) -> Result<()> {
+        if *key == X25519PublicKey::from(&self.pre_key) {
+            self.spk_id = Some(id);
+        } else if self.opks.contains_key(key) {
+            self.opk_ids.insert(id, *key);
+        } else if let Some(last_resort_key) = self
+            .last_resort_keys
+            .iter_mut()
+            .find(|(_, secret)| X25519PublicKey::from(secret) == *key)
+        {
+            last_resort_key.0 = Some(id);
+        } else {
+            bail!("Client failed to find pre key.");
+        }
+        Ok(())
+    }
+
     fn get_spk(&self) -> Result<SignedPreKey> {
-        Ok(SignedPreKey {
-            pre_key: X25519PublicKey::from(&self.pre_key),
-            signature: sign_bundle(
-                &self.ik,
-                &[(self.pre_key.clone(), X25519PublicKey::from(&self.pre_key))],
-            ),
-        })
+        Ok(self.sign(&self.pre_key))
     }
 
     fn create_opks(&mut self, num_keys: u32) -> Result<SignedPreKeys> {
@@ -69,4 +143,259 @@ impl X3DHClient for MemoryClient {
             signature: opks.signature,
         })
     }
+
+    fn rotate_spk(&mut self) -> Result<SignedPreKey> {
+        let old_spk = std::mem::replace(
+            &mut self.pre_key,
+            X25519StaticSecret::random_from_rng(OsRng),
+        );
+        self.old_spks.push_front((self.spk_id.take(), old_spk));
+        self.old_spks.truncate(RETAINED_SPKS);
+        self.get_spk()
+    }
+
+    fn get_last_resort_key(&self) -> Result<SignedPreKey> {
+        let (_, secret) = self
+            .last_resort_keys
+            .front()
+            .context("Client has no last resort key.")?;
+        Ok(self.sign(secret))
+    }
+
+    fn get_last_resort_secret(&self, id: u32) -> Result<X25519StaticSecret> {
+        self.last_resort_keys
+            .iter()
+            .find(|(key_id, _)| *key_id == Some(id))
+            .map(|(_, secret)| secret.clone())
+            .context(format!("Client has no last resort key with id {id}."))
+    }
+
+    fn rotate_last_resort_key(&mut self) -> Result<SignedPreKey> {
+        self.last_resort_keys
+            .push_front((None, X25519StaticSecret::random_from_rng(OsRng)));
+        self.last_resort_keys.truncate(RETAINED_SPKS + 1);
+        self.get_last_resort_key()
+    }
+
+    fn create_kem_opks(&mut self, num_keys: u32) -> Result<Vec<SignedKemPreKey>> {
+        (0..num_keys)
+            .map(|_| {
+                let secret = kem::generate()?;
+                let signed = sign_kem_pre_key(&self.ik, &secret.public_key());
+                self.kem_opks.insert(signed.pre_key.clone(), secret);
+                Ok(signed)
+            })
+            .collect()
+    }
+
+    fn set_kem_pre_key_id(&mut self, key: &KemPublicKey, id: u32) -> Result<()> {
+        if self.kem_opks.contains_key(key) {
+            self.kem_opk_ids.insert(id, key.clone());
+        } else if let Some(last_resort_kem_key) = self
+            .last_resort_kem_keys
+            .iter_mut()
+            .find(|(_, secret)| secret.public_key() == *key)
+        {
+            last_resort_kem_key.0 = Some(id);
+        } else {
+            bail!("Client failed to find kem pre key.");
+        }
+        Ok(())
+    }
+
+    fn fetch_wipe_kem_opk_by_id(&mut self, id: u32) -> Result<KemSecretKey> {
+        let key = self
+            .kem_opk_ids
+            .remove(&id)
+            .context("Client failed to find kem pre key id.")?;
+        self.kem_opks
+            .remove(&key)
+            .context("Client failed to find kem pre key.")
+    }
+
+    fn get_last_resort_kem_key(&self) -> Result<Option<SignedKemPreKey>> {
+        Ok(self
+            .last_resort_kem_keys
+            .front()
+            .map(|(_, secret)| sign_kem_pre_key(&self.ik, &secret.public_key())))
+    }
+
+    fn get_last_resort_kem_secret(&self, id: u32) -> Result<KemSecretKey> {
+        self.last_resort_kem_keys
+            .iter()
+            .find(|(key_id, _)| *key_id == Some(id))
+            .map(|(_, secret)| secret.clone())
+            .context(format!("Client has no last resort kem key with id {id}."))
+    }
+
+    fn rotate_last_resort_kem_key(&mut self) -> Result<SignedKemPreKey> {
+        let secret = kem::generate()?;
+        let signed = sign_kem_pre_key(&self.ik, &secret.public_key());
+        self.last_resort_kem_keys.push_front((None, secret));
+        self.last_resort_kem_keys.truncate(RETAINED_SPKS + 1);
+        Ok(signed)
+    }
+
+    fn export_keys(&self) -> Result<KeyBackup> {
+        let opk_ids: HashMap<&X25519PublicKey, u32> =
+            self.opk_ids.iter().map(|(id, opk)| (opk, *id)).collect();
+        let kem_opk_ids: HashMap<&KemPublicKey, u32> = self
+            .kem_opk_ids
+            .iter()
+            .map(|(id, key)| (key, *id))
+            .collect();
+        Ok(KeyBackup {
+            ik: self.ik.clone(),
+            spks: std::iter::once((self.spk_id, self.pre_key.clone()))
+                .chain(self.old_spks.iter().cloned())
+                .collect(),
+            opks: self
+                .opks
+                .iter()
+                .map(|(opk, secret)| (opk_ids.get(opk).copied(), secret.clone()))
+                .collect(),
+            last_resort_keys: self.last_resort_keys.iter().cloned().collect(),
+            kem_opks: self
+                .kem_opks
+                .iter()
+                .map(|(key, secret)| (kem_opk_ids.get(key).copied(), secret.clone()))
+                .collect(),
+            last_resort_kem_keys: self.last_resort_kem_keys.iter().cloned().collect(),
+        })
+    }
+
+    fn import_keys(&mut self, keys: KeyBackup) -> Result<()> {
+        let mut spks = VecDeque::from(keys.spks);
+        let (spk_id, pre_key) = spks.pop_front().context("Backup has no signed pre key.")?;
+        self.ik = keys.ik;
+        self.pre_key = pre_key;
+        self.spk_id = spk_id;
+        self.old_spks = spks;
+        self.opks.clear();
+        self.opk_ids.clear();
+        for (id, secret) in keys.opks {
+            let opk = X25519PublicKey::from(&secret);
+            if let Some(id) = id {
+                self.opk_ids.insert(id, opk);
+            }
+            self.opks.insert(opk, secret);
+        }
+        self.last_resort_keys = VecDeque::from(keys.last_resort_keys);
+        // Backups from before last-resort keys don't carry one.
+        if self.last_resort_keys.is_empty() {
+            self.rotate_last_resort_key()?;
+        }
+        self.kem_opks.clear();
+        self.kem_opk_ids.clear();
+        for (id, secret) in keys.kem_opks {
+            let key = secret.public_key();
+            if let Some(id) = id {
+                self.kem_opk_ids.insert(id, key.clone());
+            }
+            self.kem_opks.insert(key, secret);
+        }
+        self.last_resort_kem_keys = VecDeque::from(keys.last_resort_kem_keys);
+        // Nor do backups from before KEM keys or from clients without ML-KEM support.
+        if self.last_resort_kem_keys.is_empty() && kem::SUPPORTED {
+            self.rotate_last_resort_kem_key()?;
+        }
+        Ok(())
+    }
+
+    fn save_sent_message(
+        &mut self,
+        uuid: &[u8; MESSAGE_UUID_LEN],
+        message: SentMessage,
+    ) -> Result<()> {
+        self.sent_messages.insert(*uuid, message);
+        Ok(())
+    }
+
+    fn get_sent_message(&self, uuid: &[u8; MESSAGE_UUID_LEN]) -> Result<Option<SentMessage>> {
+        Ok(self.sent_messages.get(uuid).cloned())
+    }
+
+    fn forget_sent_messages(&mut self, before: SystemTime) -> Result<()> {
+        self.sent_messages
+            .retain(|_, message| message.sent_at >= before);
+        Ok(())
+    }
+
+    fn get_session(&mut self, peer: &str, device_id: u32) -> Result<Option<PeerSession>> {
+        Ok(self.sessions.get(&(peer.to_owned(), device_id)).cloned())
+    }
+
+    fn save_session(&mut self, peer: &str, device_id: u32, session: &PeerSession) -> Result<()> {
+        self.sessions
+            .insert((peer.to_owned(), device_id), session.clone());
+        Ok(())
+    }
+
+    fn delete_session(&mut self, peer: &str, device_id: u32) -> Result<()> {
+        self.sessions.remove(&(peer.to_owned(), device_id));
+        Ok(())
+    }
+
+    fn session_devices(&self, peer: &str) -> Result<Vec<u32>> {
+        let mut devices: Vec<u32> = self
+            .sessions
+            .keys()
+            .filter(|(session_peer, _)| session_peer == peer)
+            .map(|(_, device_id)| *device_id)
+            .collect();
+        devices.sort();
+        Ok(devices)
+    }
+
+    fn expect_key_confirmation(
+        &mut self,
+        uuid: &[u8; MESSAGE_UUID_LEN],
+        confirmation: PendingConfirmation,
+    ) -> Result<()> {
+        self.key_confirmations
+            .insert((*uuid, confirmation.device_id), confirmation);
+        Ok(())
+    }
+
+    fn take_key_confirmation(
+        &mut self,
+        uuid: &[u8; MESSAGE_UUID_LEN],
+        device_id: u32,
+    ) -> Result<Option<PendingConfirmation>> {
+        Ok(self.key_confirmations.remove(&(*uuid, device_id)))
+    }
+
+    fn expire_key_confirmations(
+        &mut self,
+        before: SystemTime,
+    ) -> Result<Vec<([u8; MESSAGE_UUID_LEN], PendingConfirmation)>> {
+        let mut expired = Vec::new();
+        self.key_confirmations.retain(|(uuid, _), confirmation| {
+            if confirmation.sent_at >= before {
+                return true;
+            }
+            expired.push((*uuid, confirmation.clone()));
+            false
+        });
+        Ok(expired)
+    }
+
+    fn session_state(&self, peer: &str) -> Result<SessionState> {
+        Ok(if self.confirmed_sessions.contains(peer) {
+            SessionState::Confirmed
+        } else {
+            SessionState::Unconfirmed
+        })
+    }
+
+    fn confirm_session(&mut self, peer: &str) -> Result<()> {
+        self.confirmed_sessions.insert(peer.to_owned());
+        Ok(())
+    }
+
+    fn wipe(&mut self) -> Result<()> {
+        // Nothing outlives a memory client but the keys, messages and sessions it holds.
+        *self = MemoryClient::new();
+        Ok(())
+    }
 }
@@ -0,0 +1,170 @@
+use anyhow::{anyhow, Context, Result};
+use ed25519_dalek::SigningKey;
+use std::fs::OpenOptions;
+use std::io::{ErrorKind, Read};
+use std::path::{Path, PathBuf};
+use zeroize::Zeroizing;
+
+#[cfg(feature = "keychain")]
+const KEYCHAIN_SERVICE: &str = "brongnal";
+
+/// Somewhere to keep the long-term identity key.
+pub trait SecretStore: Send {
+    /// Returns the stored identity key, or `None` if none has been stored yet.
+    fn load(&self) -> Result<Option<SigningKey>>;
+    /// Stores `key`, replacing any identity key already stored.
+    fn store(&self, key: &SigningKey) -> Result<()>;
+    /// Removes the stored identity key, if there is one.
+    fn clear(&self) -> Result<()>;
+}
+
+fn decode(bytes: &[u8]) -> Result<SigningKey> {
+    SigningKey::from_keypair_bytes(
+        bytes
+            .try_into()
+            .map_err(|_| anyhow!("invalidly sized key"))?,
+    )
+    .map_err(|_| anyhow!("invalid key"))
+}
+
+/// Overwrites the file at `path` with zeros before removing it, so what it held, e.g. an identity
+/// key or a backup from `export_backup`, isn't left in its blocks for the taking. Succeeds if there
+/// is no such file.
+pub fn remove_securely(path: &Path) -> std::io::Result<()> {
+    let mut file = match OpenOptions::new().write(true).open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    let len = file.metadata()?.len();
+    std::io::copy(&mut std::io::repeat(0).take(len), &mut file)?;
+    file.sync_all()?;
+    drop(file);
+    std::fs::remove_file(path)
+}
+
+/// Keeps the identity key in a plain file.
+pub struct FileStore {
+    path: PathBuf,
+}
+
+impl FileStore {
+    pub fn new(path: &Path) -> Self {
+        FileStore {
+            path: path.to_path_buf(),
+        }
+    }
+}
+
+impl SecretStore for FileStore {
+    fn load(&self) -> Result<Option<SigningKey>> {
+        match std::fs::read(&self.path) {
+            Ok(bytes) => decode(&Zeroizing::new(bytes)).map(Some),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).context("Failed to read identity key from disk."),
+        }
+    }
+
+    fn store(&self, key: &SigningKey) -> Result<()> {
+        let bytes = Zeroizing::new(key.to_keypair_bytes());
+        std::fs::write(&self.path, bytes.as_slice())
+            .context("Failed to write identity key to disk.")
+    }
+
+    fn clear(&self) -> Result<()> {
+        remove_securely(&self.path).context("Failed to remove identity key from disk.")
+    }
+}
+
+/// Keeps the identity key in the platform keystore: the macOS Keychain, the Windows Credential
+/// Manager or the Secret Service on Linux.
+#[cfg(feature = "keychain")]
+pub struct KeychainStore {
+    entry: keyring::Entry,
+}
+
+#[cfg(feature = "keychain")]
+impl KeychainStore {
+    /// `account` tells apart identities kept on the same machine.
+    pub fn new(account: &str) -> Result<Self> {
+        Ok(KeychainStore {
+            entry: keyring::Entry::new(KEYCHAIN_SERVICE, account)?,
+        })
+    }
+
+    /// Whether the platform keystore can be reached at all.
+    fn available(&self) -> bool {
+        matches!(
+            self.entry.get_secret(),
+            Ok(_) | Err(keyring::Error::NoEntry)
+        )
+    }
+}
+
+#[cfg(feature = "keychain")]
+impl SecretStore for KeychainStore {
+    fn load(&self) -> Result<Option<SigningKey>> {
+        match self.entry.get_secret() {
+            Ok(bytes) => decode(&Zeroizing::new(bytes)).map(Some),
+            Err(keyring::Error::NoEntry) => Ok(None),
+            Err(e) => Err(e).context("Failed to read identity key from keychain."),
+        }
+    }
+
+    fn store(&self, key: &SigningKey) -> Result<()> {
+        let bytes = Zeroizing::new(key.to_keypair_bytes());
+        self.entry
+            .set_secret(bytes.as_slice())
+            .context("Failed to write identity key to keychain.")
+    }
+
+    fn clear(&self) -> Result<()> {
+        match self.entry.delete_password() {
+            Ok(()) | Err(keyring::Error::NoEntry) => Ok(()),
+            Err(e) => Err(e).context("Failed to remove identity key from keychain."),
+        }
+    }
+}
+
+/// Returns the keychain store for `account` if the platform keystore is reachable, moving an
+/// identity key already in the file at `fallback` into it. Otherwise returns a file store at
+/// `fallback`.
+#[cfg_attr(not(feature = "keychain"), allow(unused_variables))]
+pub fn open_secret_store(account: &str, fallback: &Path) -> Result<Box<dyn SecretStore>> {
+    let file = FileStore::new(fallback);
+    #[cfg(feature = "keychain")]
+    if let Ok(keychain) = KeychainStore::new(account) {
+        if keychain.available() {
+            if keychain.load()?.is_none() {
+                if let Some(key) = file.load()? {
+                    keychain.store(&key)?;
+                    remove_securely(fallback)
+                        .context("Failed to remove identity key from disk.")?;
+                }
+            }
+            return Ok(Box::new(keychain));
+        }
+    }
+    Ok(Box::new(file))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn remove_securely_overwrites() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("brongnal-backup-{}", std::process::id()));
+        std::fs::write(&path, [0xab; 100])?;
+        let mut file = std::fs::File::open(&path)?;
+        remove_securely(&path)?;
+        assert!(!path.exists());
+
+        // The open handle still reads the removed file, now zeroed.
+        let mut contents = Vec::new();
+        file.read_to_end(&mut contents)?;
+        assert_eq!(contents, [0; 100]);
+        assert!(remove_securely(&path).is_ok());
+        Ok(())
+    }
+}
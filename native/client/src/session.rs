@@ -0,0 +1,492 @@
+//! One handle for everything a device does: registering, sending through an outbox that survives
+//! a flaky connection, and receiving in the background. Apps and the CLI build on this rather
+//! than wiring up the stub, listener and spk rotation themselves.
+
+use crate::error::ClientError;
+use crate::{
+    check_key_confirmations_periodically, connect_uds, delete_account, emit, export_account_data,
+    listen_with_state, message_id, message_with_uuid, new_message_uuid, ping, register_with_suite,
+    rotate_spk_periodically, set_connection_state, top_up_opks_periodically, watch_message_status,
+    with_keepalive, ClientEvent, ConnectionState, MessageId, OpkTopUp, SendPolicy, SessionState,
+    Timeouts, X3DHClient, KEY_CONFIRMATION_TIMEOUT, SPK_ROTATION_PERIOD,
+};
+use anyhow::{bail, Context, Result};
+use futures::Stream;
+use proto::gossamer::gossamer_client::GossamerClient;
+use proto::service::brongnal_client::BrongnalClient;
+use proto::{Identity, DEFAULT_DEVICE_ID, MESSAGE_UUID_LEN};
+use protocol::x3dh::CipherSuite;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::broadcast::{self, Sender};
+use tokio::sync::{mpsc, watch, Mutex};
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+use tonic::transport::{Channel, Endpoint};
+
+/// How long the message listener waits before reconnecting after the server drops it, and the
+/// outbox before retrying a send that failed for want of a connection. Each failed attempt in a
+/// row doubles the wait, up to `MAX_RECONNECT_DELAY`.
+pub const MIN_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+/// The longest the listener and outbox wait between attempts.
+pub const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+
+/// A message waiting in the outbox.
+struct Outgoing {
+    uuid: [u8; MESSAGE_UUID_LEN],
+    peer: String,
+    message: Vec<u8>,
+}
+
+/// Who this device registered as, and where its messages queue to be sent.
+struct Registered {
+    name: String,
+    outbox: mpsc::UnboundedSender<Outgoing>,
+}
+
+/// A device's connection to a Brongnal server. Dropping it stops everything it runs in the
+/// background; messages still in the outbox are not sent.
+pub struct Brongnal {
+    stub: BrongnalClient<Channel>,
+    gossamer: GossamerClient<Channel>,
+    x3dh_client: Arc<Mutex<dyn X3DHClient + Send>>,
+    device_id: u32,
+    cipher_suite: CipherSuite,
+    policy: SendPolicy,
+    opk_top_up: OpkTopUp,
+    timeouts: Timeouts,
+    invite_code: Option<String>,
+    events: Sender<ClientEvent>,
+    connection: Arc<watch::Sender<ConnectionState>>,
+    registered: Mutex<Option<Registered>>,
+    tasks: std::sync::Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl Brongnal {
+    /// Connects to `url` as the device whose keys `identity_store` holds. A `unix:` prefix names a
+    /// unix domain socket.
+    pub async fn connect(
+        url: &str,
+        identity_store: impl X3DHClient + Send + 'static,
+    ) -> Result<Brongnal> {
+        let channel = match url.strip_prefix("unix:") {
+            Some(path) => connect_uds(path).await?,
+            None => {
+                with_keepalive(Endpoint::from_shared(url.to_owned())?)
+                    .connect()
+                    .await?
+            }
+        };
+        let (events, _) = broadcast::channel(100);
+        Ok(Brongnal {
+            stub: BrongnalClient::new(channel.clone()),
+            gossamer: GossamerClient::new(channel),
+            x3dh_client: Arc::new(Mutex::new(identity_store)),
+            device_id: DEFAULT_DEVICE_ID,
+            cipher_suite: CipherSuite::CURRENT,
+            policy: SendPolicy::default(),
+            opk_top_up: OpkTopUp::default(),
+            timeouts: Timeouts::default(),
+            invite_code: None,
+            events,
+            connection: Arc::new(watch::channel(ConnectionState::Disconnected).0),
+            registered: Mutex::new(None),
+            tasks: std::sync::Mutex::new(Vec::new()),
+        })
+    }
+
+    /// Registers as `device_id` rather than `DEFAULT_DEVICE_ID`.
+    pub fn with_device_id(mut self, device_id: u32) -> Self {
+        self.device_id = device_id;
+        self
+    }
+
+    /// Asks senders to use `cipher_suite` rather than `CipherSuite::CURRENT`.
+    pub fn with_cipher_suite(mut self, cipher_suite: CipherSuite) -> Self {
+        self.cipher_suite = cipher_suite;
+        self
+    }
+
+    /// Holds the recipients of sent messages to `policy` rather than the default.
+    pub fn with_send_policy(mut self, policy: SendPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Keeps the server stocked with one time keys according to `top_up` rather than the
+    /// default.
+    pub fn with_opk_top_up(mut self, top_up: OpkTopUp) -> Self {
+        self.opk_top_up = top_up;
+        self
+    }
+
+    /// Gives up on requests after `timeouts` rather than the defaults.
+    pub fn with_timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Registers with `code` on servers that only admit invited identities. Servers that don't, or
+    /// that already know the identity, ignore it.
+    pub fn with_invite_code(mut self, code: Option<String>) -> Self {
+        self.invite_code = code;
+        self
+    }
+
+    /// Registers this device as `name`, then starts receiving its messages, sending the outbox,
+    /// following the delivery of what it sends, rotating its signed pre key, topping up its one
+    /// time keys and reporting key agreements recipients don't confirm in the background.
+    pub async fn register(&self, name: &Identity) -> Result<()> {
+        let mut registered = self.registered.lock().await;
+        if let Some(registered) = registered.as_ref() {
+            bail!("Already registered as {}.", registered.name);
+        }
+        self.register_only(name).await?;
+
+        let (outbox, outbox_rx) = mpsc::unbounded_channel();
+        let listener = tokio::spawn(listen_forever(
+            self.stub.clone(),
+            self.gossamer.clone(),
+            self.x3dh_client.clone(),
+            name.to_string(),
+            self.device_id,
+            self.timeouts,
+            self.events.clone(),
+            self.connection.clone(),
+        ));
+        let status = tokio::spawn(watch_message_status_forever(
+            self.stub.clone(),
+            self.x3dh_client.clone(),
+            name.to_string(),
+            self.device_id,
+            self.timeouts,
+            self.events.clone(),
+        ));
+        let sender = tokio::spawn(send_outbox(
+            self.stub.clone(),
+            self.gossamer.clone(),
+            self.x3dh_client.clone(),
+            name.to_string(),
+            self.device_id,
+            self.policy,
+            self.timeouts,
+            self.events.clone(),
+            self.connection.subscribe(),
+            outbox_rx,
+        ));
+        let rotation = tokio::spawn(rotate_spk_periodically(
+            self.stub.clone(),
+            self.x3dh_client.clone(),
+            name.to_string(),
+            self.device_id,
+            SPK_ROTATION_PERIOD,
+            self.timeouts,
+        ));
+        let top_up = tokio::spawn(top_up_opks_periodically(
+            self.stub.clone(),
+            self.x3dh_client.clone(),
+            name.to_string(),
+            self.device_id,
+            self.opk_top_up,
+            self.timeouts,
+            self.events.subscribe(),
+        ));
+        let confirmations = tokio::spawn(check_key_confirmations_periodically(
+            self.x3dh_client.clone(),
+            KEY_CONFIRMATION_TIMEOUT,
+            self.events.clone(),
+        ));
+        self.tasks.lock().unwrap().extend([
+            listener,
+            status,
+            sender,
+            rotation,
+            top_up,
+            confirmations,
+        ]);
+        *registered = Some(Registered {
+            name: name.to_string(),
+            outbox,
+        });
+        Ok(())
+    }
+
+    /// Registers this device as `name` without starting anything in the background, for
+    /// one-shot commands that exit right after.
+    pub async fn register_only(&self, name: &Identity) -> Result<()> {
+        register_with_suite(
+            &mut self.stub.clone(),
+            self.x3dh_client.clone(),
+            name.to_string(),
+            self.device_id,
+            self.cipher_suite,
+            self.timeouts,
+            self.invite_code.clone(),
+            &self.events,
+        )
+        .await
+    }
+
+    /// Deletes `name` and all of its devices from the server, stops everything running in the
+    /// background and wipes this device's keys. Messages still in the outbox are not sent.
+    pub async fn delete_account(&self, name: &Identity) -> Result<()> {
+        delete_account(
+            &mut self.stub.clone(),
+            &mut self.gossamer.clone(),
+            self.x3dh_client.clone(),
+            name.to_string(),
+            self.timeouts,
+        )
+        .await?;
+        for task in self.tasks.lock().unwrap().drain(..) {
+            task.abort();
+        }
+        set_connection_state(
+            &self.events,
+            &self.connection,
+            ConnectionState::Disconnected,
+        );
+        *self.registered.lock().await = None;
+        Ok(())
+    }
+
+    /// Writes everything the server holds about `name` to `path`, returning how many records
+    /// that was. Queued messages are only exported in full with `include_ciphertexts`.
+    pub async fn export_account_data(
+        &self,
+        name: &Identity,
+        include_ciphertexts: bool,
+        path: &Path,
+    ) -> Result<usize> {
+        export_account_data(
+            &mut self.stub.clone(),
+            self.x3dh_client.clone(),
+            name.to_string(),
+            include_ciphertexts,
+            path,
+            self.timeouts,
+        )
+        .await
+    }
+
+    /// Sends `message` to each of `peer`'s devices as `name` right away rather than through the
+    /// outbox, for one-shot commands that exit once it's sent. Nothing retries a failed send.
+    pub async fn send_now(
+        &self,
+        name: &Identity,
+        peer: &Identity,
+        message: &[u8],
+    ) -> Result<MessageId> {
+        message_with_uuid(
+            &mut self.stub.clone(),
+            &mut self.gossamer.clone(),
+            self.x3dh_client.clone(),
+            name.to_string(),
+            self.device_id,
+            peer,
+            message,
+            self.policy,
+            new_message_uuid(),
+            self.timeouts,
+            &self.events,
+        )
+        .await
+    }
+
+    /// Queues `message` for each of `peer`'s devices, returning the id events report it by.
+    /// Messages are sent in the order they were queued, while the message stream is connected;
+    /// one that fails for want of a connection is retried until it is sent, and one that can't
+    /// be sent is reported as an error.
+    pub async fn send(&self, peer: &Identity, message: &[u8]) -> Result<MessageId> {
+        let registered = self.registered.lock().await;
+        let registered = registered
+            .as_ref()
+            .context("Register before sending messages.")?;
+        let uuid = new_message_uuid();
+        registered
+            .outbox
+            .send(Outgoing {
+                uuid,
+                peer: peer.to_string(),
+                message: message.to_vec(),
+            })
+            .ok()
+            .context("The outbox has stopped.")?;
+        Ok(message_id(&uuid))
+    }
+
+    /// Measures how long the server takes to answer a ping, also reporting it as a
+    /// `ClientEvent::RoundTripTime`.
+    pub async fn ping(&self) -> Result<Duration> {
+        let rtt = ping(&mut self.stub.clone(), self.timeouts).await?;
+        emit(&self.events, ClientEvent::RoundTripTime(rtt));
+        Ok(rtt)
+    }
+
+    /// Whether `peer` has confirmed a key agreement with this device. Changes are reported as
+    /// `ClientEvent::SessionConfirmed`.
+    pub async fn session_state(&self, peer: &Identity) -> Result<SessionState> {
+        self.x3dh_client.lock().await.session_state(peer)
+    }
+
+    /// The state of the connection, starting with the current one. The same changes are reported
+    /// as `ClientEvent::ConnectionState`s.
+    pub fn connection_state(&self) -> watch::Receiver<ConnectionState> {
+        self.connection.subscribe()
+    }
+
+    /// Everything that happens from now on: messages, sends, connection changes and errors. Each
+    /// stream sees every event; one that falls too far behind misses the oldest events rather
+    /// than holding up the others. Ends once the `Brongnal` is dropped.
+    pub fn events(&self) -> impl Stream<Item = ClientEvent> {
+        futures::stream::unfold(self.events.subscribe(), |mut rx| async move {
+            loop {
+                match rx.recv().await {
+                    Ok(event) => return Some((event, rx)),
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        })
+    }
+}
+
+impl Drop for Brongnal {
+    fn drop(&mut self) {
+        for task in self.tasks.lock().unwrap().drain(..) {
+            task.abort();
+        }
+    }
+}
+
+/// Listens for `name`'s messages, reconnecting with capped exponential backoff whenever the
+/// server drops the stream.
+#[allow(clippy::too_many_arguments)]
+async fn listen_forever(
+    stub: BrongnalClient<Channel>,
+    gossamer: GossamerClient<Channel>,
+    x3dh_client: Arc<Mutex<dyn X3DHClient + Send>>,
+    name: String,
+    device_id: u32,
+    timeouts: Timeouts,
+    events: Sender<ClientEvent>,
+    state: Arc<watch::Sender<ConnectionState>>,
+) {
+    let mut delay = MIN_RECONNECT_DELAY;
+    loop {
+        let result = listen_with_state(
+            stub.clone(),
+            gossamer.clone(),
+            x3dh_client.clone(),
+            name.clone(),
+            device_id,
+            events.clone(),
+            &state,
+            timeouts,
+        )
+        .await;
+        if result.is_ok() {
+            delay = MIN_RECONNECT_DELAY;
+        }
+        set_connection_state(
+            &events,
+            &state,
+            ConnectionState::Backoff {
+                until: Instant::now() + delay,
+            },
+        );
+        tokio::time::sleep(delay).await;
+        if result.is_err() {
+            delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+        }
+    }
+}
+
+/// Follows the delivery of `name`'s messages, reconnecting with capped exponential backoff
+/// whenever the server drops the stream.
+async fn watch_message_status_forever(
+    mut stub: BrongnalClient<Channel>,
+    x3dh_client: Arc<Mutex<dyn X3DHClient + Send>>,
+    name: String,
+    device_id: u32,
+    timeouts: Timeouts,
+    events: Sender<ClientEvent>,
+) {
+    let mut delay = MIN_RECONNECT_DELAY;
+    loop {
+        let result = watch_message_status(
+            &mut stub,
+            x3dh_client.clone(),
+            name.clone(),
+            device_id,
+            &events,
+            timeouts,
+        )
+        .await;
+        if result.is_ok() {
+            delay = MIN_RECONNECT_DELAY;
+        }
+        tokio::time::sleep(delay).await;
+        if result.is_err() {
+            delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+        }
+    }
+}
+
+/// Sends each message queued on `outbox` as `name`'s `device_id`, one at a time, whenever `connection` says
+/// the message stream is connected. A message whose send fails
+/// with a `ClientError::Retryable` error is resent with the same uuid, so the server delivers it
+/// at most once, waiting at least as long as the server asked.
+#[allow(clippy::too_many_arguments)]
+async fn send_outbox(
+    mut stub: BrongnalClient<Channel>,
+    mut gossamer: GossamerClient<Channel>,
+    x3dh_client: Arc<Mutex<dyn X3DHClient + Send>>,
+    name: String,
+    device_id: u32,
+    policy: SendPolicy,
+    timeouts: Timeouts,
+    events: Sender<ClientEvent>,
+    mut connection: watch::Receiver<ConnectionState>,
+    mut outbox: mpsc::UnboundedReceiver<Outgoing>,
+) {
+    while let Some(outgoing) = outbox.recv().await {
+        let mut delay = MIN_RECONNECT_DELAY;
+        loop {
+            // Sending while the message stream is down would only wait out a doomed request.
+            if connection
+                .wait_for(|state| *state == ConnectionState::Connected)
+                .await
+                .is_err()
+            {
+                return;
+            }
+            // Failures are reported on `events`.
+            let result = message_with_uuid(
+                &mut stub,
+                &mut gossamer,
+                x3dh_client.clone(),
+                name.clone(),
+                device_id,
+                &outgoing.peer,
+                &outgoing.message,
+                policy,
+                outgoing.uuid,
+                timeouts,
+                &events,
+            )
+            .await;
+            let retry_delay = match &result {
+                Ok(_) => None,
+                Err(e) => ClientError::classify(e).retry_delay(delay),
+            };
+            let Some(retry_delay) = retry_delay else {
+                break;
+            };
+            tokio::time::sleep(retry_delay).await;
+            delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+        }
+    }
+}
@@ -1,62 +1,75 @@
-use crate::X3DHClient;
-use anyhow::{anyhow, Context, Result};
-use chacha20poly1305::aead::OsRng;
-use ed25519_dalek::SigningKey;
+use crate::secret_store::{FileStore, SecretStore};
+use crate::{
+    KeyBackup, PeerSession, PendingConfirmation, SentMessage, SessionState, X3DHClient,
+    RETAINED_SPKS, SENT_MESSAGE_RETENTION,
+};
+use anyhow::{anyhow, bail, Context, Result};
+use chacha20poly1305::aead::{OsRng, Payload};
+use ed25519_dalek::{SigningKey, VerifyingKey};
+use proto::MESSAGE_UUID_LEN;
 use protocol::bundle::{create_prekey_bundle, sign_bundle};
+use protocol::kem::{self, sign_kem_pre_key, KemPublicKey, KemSecretKey, SignedKemPreKey};
+use protocol::ratchet::{open_data, seal_data, Session};
 use protocol::x3dh;
-use rusqlite::{params, Connection};
+use rusqlite::types::FromSql;
+use rusqlite::{params, Connection, OptionalExtension, Params};
+use std::cell::OnceCell;
+use std::collections::HashMap;
 use std::path::Path;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use x25519_dalek::{PublicKey as X25519PublicKey, StaticSecret as X25519StaticSecret};
 use x3dh::{SignedPreKey, SignedPreKeys};
+use zeroize::Zeroizing;
 
 #[derive(Clone, Copy, strum_macros::Display)]
 #[repr(u32)]
 enum KeyType {
     PreKey = 1,
     OneTimeKey = 2,
+    LastResort = 3,
+    OneTimeKemKey = 4,
+    LastResortKem = 5,
 }
 
 struct PreKey {
     pub_key: X25519PublicKey,
     priv_key: X25519StaticSecret,
     key_type: KeyType,
+    id: Option<u32>,
 }
 
-pub struct SqliteClient {
-    identity_key: SigningKey,
-    connection: Connection,
-}
-
-fn read_ik(path: &Path) -> Result<SigningKey> {
-    let key_bytes = std::fs::read(path)?;
-    let key_bytes: &[u8] = &key_bytes;
-    SigningKey::from_keypair_bytes(
-        key_bytes
-            .try_into()
-            .map_err(|_| anyhow!("invalidly sized key"))?,
-    )
-    .map_err(|_| anyhow!("invalid key"))
+struct KemPreKey {
+    priv_key: KemSecretKey,
+    key_type: KeyType,
+    id: Option<u32>,
 }
 
-fn init_ik(path: &Path) -> Result<SigningKey> {
-    let key = SigningKey::generate(&mut OsRng);
-    std::fs::write(path, key.to_keypair_bytes())
-        .context("Failed to write identity key to disk.")?;
-    Ok(key)
+pub struct SqliteClient {
+    secret_store: Box<dyn SecretStore>,
+    /// Loaded from `secret_store` on first use. `SigningKey` zeroizes itself when dropped.
+    identity_key: OnceCell<SigningKey>,
+    connection: Connection,
+    /// Sessions read from or written to `connection` so far, by peer and device.
+    sessions: HashMap<(String, u32), PeerSession>,
 }
 
 impl SqliteClient {
     pub fn new(identity_key_path: &Path, db_path: &Path) -> Result<SqliteClient> {
-        let identity_key = match read_ik(&identity_key_path) {
-            Ok(key) => key,
-            Err(_) => init_ik(&identity_key_path).context("Failed to create new identity key.")?,
-        };
+        SqliteClient::with_secret_store(Box::new(FileStore::new(identity_key_path)), db_path)
+    }
 
+    /// Opens a client whose identity key is kept in `secret_store`, creating one there if it has
+    /// none.
+    pub fn with_secret_store(
+        secret_store: Box<dyn SecretStore>,
+        db_path: &Path,
+    ) -> Result<SqliteClient> {
         let connection = Connection::open(db_path).context("Failed to open db_path.")?;
         connection.pragma_update(None, "journal_mode", "WAL")?;
         connection.pragma_update(None, "synchronous", "normal")?;
         connection.pragma_update(None, "foreign_keys", "on")?;
+        // Zeroes deleted rows instead of leaving them in free pages.
+        connection.pragma_update(None, "secure_delete", "on")?;
 
         connection
             .execute(
@@ -69,34 +82,340 @@ impl SqliteClient {
                 (),
             )
             .context("Creating initial table failed.")?;
+        // Recently sent messages, in case a recipient can't decrypt them, each sealed under the
+        // identity key like sessions.
+        connection
+            .execute(
+                "create table if not exists sent_messages (
+             uuid blob primary key,
+             recipient text not null,
+             message blob not null,
+             sent_at integer not null,
+             attempts integer not null
+         )",
+                (),
+            )
+            .context("Creating sent messages table failed.")?;
+        let version: u32 = connection.pragma_query_value(None, "user_version", |row| row.get(0))?;
+        // Sessions were kept by peer alone before version 3, without the identity key that set
+        // them up, so they are dropped and set up again with X3DH.
+        if version < 3 {
+            connection
+                .execute("drop table if exists sessions", ())
+                .context("Dropping sessions table failed.")?;
+        }
+        // Sessions with each device of a peer, each sealed under the identity key. See
+        // `protocol::ratchet`.
+        connection
+            .execute(
+                "create table if not exists sessions (
+             peer text not null,
+             device_id integer not null,
+             identity_key blob not null,
+             state blob not null,
+             last_used integer not null,
+             primary key (peer, device_id)
+         )",
+                (),
+            )
+            .context("Creating sessions table failed.")?;
+        // Key agreements of sent messages waiting for the recipient's device to confirm them,
+        // and the peers that have. See `protocol::confirmation`.
+        connection
+            .execute(
+                "create table if not exists key_confirmations (
+             uuid blob not null,
+             device_id integer not null,
+             peer text not null,
+             mac blob not null,
+             sent_at integer not null,
+             primary key (uuid, device_id)
+         )",
+                (),
+            )
+            .context("Creating key confirmations table failed.")?;
+        connection
+            .execute(
+                "create table if not exists confirmed_sessions (
+             peer text primary key,
+             confirmed_at integer not null
+         )",
+                (),
+            )
+            .context("Creating confirmed sessions table failed.")?;
+        // Keys are identified by the id the server assigns once they are uploaded.
+        if version < 1 {
+            connection
+                .execute("alter table keys add column id integer", ())
+                .context("Adding key id column failed.")?;
+            connection.pragma_update(None, "user_version", 1)?;
+        }
+        // Lets `scrub` hand free pages back to the filesystem, which databases made before it
+        // must be rebuilt for.
+        if version < 2 {
+            connection.pragma_update(None, "auto_vacuum", "incremental")?;
+            connection
+                .execute("VACUUM", ())
+                .context("Rebuilding database for incremental vacuum failed.")?;
+            connection.pragma_update(None, "user_version", 2)?;
+        }
+        if version < 3 {
+            connection.pragma_update(None, "user_version", 3)?;
+        }
+        // Resent copies of sent messages point at the message as first sent, whose attempts
+        // count theirs.
+        if version < 4 {
+            connection
+                .execute("alter table sent_messages add column resent_from blob", ())
+                .context("Adding resent from column failed.")?;
+            connection.pragma_update(None, "user_version", 4)?;
+        }
+        // Sent messages were kept unsealed before version 5, so they are dropped rather than
+        // left readable.
+        if version < 5 {
+            connection
+                .execute("DELETE FROM sent_messages", ())
+                .context("Dropping unsealed sent messages failed.")?;
+            connection.pragma_update(None, "user_version", 5)?;
+        }
+        // Nor are any kept past the time they could be resent in, however long since a message
+        // was last sent.
+        connection
+            .execute(
+                "DELETE FROM sent_messages WHERE sent_at < ?1",
+                [(SystemTime::now() - SENT_MESSAGE_RETENTION)
+                    .duration_since(UNIX_EPOCH)?
+                    .as_secs()],
+            )
+            .context("Forgetting old sent messages failed.")?;
 
         let pre_key = X25519StaticSecret::random_from_rng(OsRng);
         let sqlite_client = SqliteClient {
-            identity_key,
+            secret_store,
+            identity_key: OnceCell::new(),
             connection,
+            sessions: HashMap::new(),
         };
         sqlite_client.insert(&[PreKey {
             pub_key: X25519PublicKey::from(&pre_key),
             priv_key: pre_key,
             key_type: KeyType::PreKey,
+            id: None,
         }])?;
+        if sqlite_client.newest_key(KeyType::LastResort)?.is_none() {
+            sqlite_client.rotate(KeyType::LastResort)?;
+        }
+        if kem::SUPPORTED
+            && sqlite_client
+                .newest_kem_key(KeyType::LastResortKem)?
+                .is_none()
+        {
+            sqlite_client.rotate_kem(KeyType::LastResortKem)?;
+        }
         Ok(sqlite_client)
     }
 
+    /// Opens a client for a device linked to the identity `identity_key` belongs to, replacing
+    /// any identity key already stored at `identity_key_path`.
+    pub fn with_identity_key(
+        identity_key: &SigningKey,
+        identity_key_path: &Path,
+        db_path: &Path,
+    ) -> Result<SqliteClient> {
+        FileStore::new(identity_key_path).store(identity_key)?;
+        SqliteClient::new(identity_key_path, db_path)
+    }
+
+    fn identity_key(&self) -> Result<&SigningKey> {
+        if let Some(key) = self.identity_key.get() {
+            return Ok(key);
+        }
+        let key = match self.secret_store.load()? {
+            Some(key) => key,
+            None => {
+                let key = SigningKey::generate(&mut OsRng);
+                self.secret_store
+                    .store(&key)
+                    .context("Failed to create new identity key.")?;
+                key
+            }
+        };
+        Ok(self.identity_key.get_or_init(|| key))
+    }
+
     fn insert(&self, keys: &[PreKey]) -> Result<()> {
-        let mut stmt = self.connection.prepare(
-            "INSERT INTO keys (public_key, private_key, key_type, creation_time) VALUES (?1, ?2, ?3, ?4)")?;
-        for key in keys {
-            stmt.execute((
-                key.pub_key.to_bytes(),
-                key.priv_key.to_bytes(),
-                key.key_type as u32,
-                SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
-            ))
-            .context("Failed to insert key.")?;
+        insert_keys(&self.connection, keys)
+    }
+
+    fn newest_key(&self, key_type: KeyType) -> Result<Option<X25519StaticSecret>> {
+        let key: Option<[u8; 32]> = self
+            .connection
+            .query_row(
+                "SELECT private_key FROM keys WHERE key_type = ?1 ORDER BY creation_time DESC, rowid DESC LIMIT 1",
+                params![key_type as u32],
+                |row| row.get(0),
+            )
+            .optional()
+            .with_context(|| format!("failed to find {key_type}"))?;
+        Ok(key.map(X25519StaticSecret::from))
+    }
+
+    fn newest_kem_key(&self, key_type: KeyType) -> Result<Option<KemSecretKey>> {
+        let key: Option<Vec<u8>> = self
+            .connection
+            .query_row(
+                "SELECT private_key FROM keys WHERE key_type = ?1 ORDER BY creation_time DESC, rowid DESC LIMIT 1",
+                params![key_type as u32],
+                |row| row.get(0),
+            )
+            .optional()
+            .with_context(|| format!("failed to find {key_type}"))?;
+        Ok(key.map(|key| KemSecretKey::from_bytes(&key)).transpose()?)
+    }
+
+    /// Adds a new key of `key_type`, keeping only the newest `RETAINED_SPKS` before it.
+    fn rotate(&self, key_type: KeyType) -> Result<()> {
+        let pre_key = X25519StaticSecret::random_from_rng(OsRng);
+        self.insert(&[PreKey {
+            pub_key: X25519PublicKey::from(&pre_key),
+            priv_key: pre_key,
+            key_type,
+            id: None,
+        }])?;
+        self.retain_newest(key_type)
+    }
+
+    /// Like `rotate`, but for KEM keys.
+    fn rotate_kem(&self, key_type: KeyType) -> Result<()> {
+        insert_kem_keys(
+            &self.connection,
+            &[KemPreKey {
+                priv_key: kem::generate()?,
+                key_type,
+                id: None,
+            }],
+        )?;
+        self.retain_newest(key_type)
+    }
+
+    fn retain_newest(&self, key_type: KeyType) -> Result<()> {
+        self.connection
+            .execute(
+                "DELETE FROM keys WHERE key_type = ?1 AND rowid NOT IN (
+                 SELECT rowid FROM keys WHERE key_type = ?1 ORDER BY creation_time DESC, rowid DESC LIMIT ?2)",
+                params![key_type as u32, RETAINED_SPKS + 1],
+            )
+            .with_context(|| format!("failed to delete old {key_type}s"))?;
+        self.scrub()
+    }
+
+    /// Deletes the key matching `condition`, returning its private key. The private key is
+    /// overwritten before the row is deleted, and the database scrubbed after, so no copy of it
+    /// is left in the database files.
+    fn take_key<T: FromSql, P: Params + Copy>(&self, condition: &str, params: P) -> Result<T> {
+        let tx = self.connection.unchecked_transaction()?;
+        let key = tx.query_row(
+            &format!("SELECT private_key FROM keys WHERE {condition}"),
+            params,
+            |row| row.get(0),
+        )?;
+        tx.execute(
+            &format!(
+                "UPDATE keys SET private_key = zeroblob(length(private_key)) WHERE {condition}"
+            ),
+            params,
+        )?;
+        tx.execute(&format!("DELETE FROM keys WHERE {condition}"), params)?;
+        tx.commit()?;
+        self.scrub()?;
+        Ok(key)
+    }
+
+    /// Returns free pages to the filesystem and empties the write-ahead log, which would
+    /// otherwise keep old copies of deleted rows.
+    fn scrub(&self) -> Result<()> {
+        self.connection
+            .execute_batch("PRAGMA incremental_vacuum")
+            .context("failed to vacuum database")?;
+        self.connection
+            .query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |_| Ok(()))
+            .context("failed to checkpoint database")?;
+        Ok(())
+    }
+
+    /// Records the id the server assigned to the key with public key `public_key`.
+    fn set_id(&self, public_key: &[u8], id: u32) -> Result<()> {
+        let updated = self
+            .connection
+            .execute(
+                "UPDATE keys SET id = ?2 WHERE public_key = ?1",
+                params![public_key, id],
+            )
+            .context("failed to set pre key id")?;
+        if updated == 0 {
+            bail!("failed to find pre key");
         }
         Ok(())
     }
+
+    /// The secret sessions are sealed under.
+    fn session_key(&self) -> Result<Zeroizing<[u8; 32]>> {
+        Ok(Zeroizing::new(self.identity_key()?.to_bytes()))
+    }
+
+    fn sign(&self, pre_key: &X25519StaticSecret) -> Result<SignedPreKey> {
+        Ok(SignedPreKey {
+            pre_key: X25519PublicKey::from(pre_key),
+            signature: sign_bundle(
+                self.identity_key()?,
+                &[(pre_key.clone(), X25519PublicKey::from(pre_key))],
+            ),
+        })
+    }
+}
+
+/// What the sealed state of the session with `peer`'s `device_id` is bound to, along with the
+/// identity key `ik` it was set up with, so a row can't be passed off as another's.
+fn session_ad(peer: &str, device_id: u32, ik: &VerifyingKey) -> Vec<u8> {
+    [peer.as_bytes(), &device_id.to_be_bytes(), ik.as_bytes()].concat()
+}
+
+/// What the sealed plaintext of the message sent to `recipient` as `uuid` is bound to, so a row
+/// can't be passed off as another's.
+fn sent_message_ad(uuid: &[u8; MESSAGE_UUID_LEN], recipient: &str) -> Vec<u8> {
+    [uuid.as_slice(), recipient.as_bytes()].concat()
+}
+
+fn insert_keys(connection: &Connection, keys: &[PreKey]) -> Result<()> {
+    let mut stmt = connection.prepare(
+            "INSERT INTO keys (public_key, private_key, key_type, creation_time, id) VALUES (?1, ?2, ?3, ?4, ?5)")?;
+    for key in keys {
+        stmt.execute((
+            key.pub_key.to_bytes(),
+            key.priv_key.to_bytes(),
+            key.key_type as u32,
+            SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+            key.id,
+        ))
+        .context("Failed to insert key.")?;
+    }
+    Ok(())
+}
+
+fn insert_kem_keys(connection: &Connection, keys: &[KemPreKey]) -> Result<()> {
+    let mut stmt = connection.prepare(
+            "INSERT INTO keys (public_key, private_key, key_type, creation_time, id) VALUES (?1, ?2, ?3, ?4, ?5)")?;
+    for key in keys {
+        stmt.execute((
+            key.priv_key.public_key().as_bytes(),
+            key.priv_key.as_bytes(),
+            key.key_type as u32,
+            SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+            key.id,
+        ))
+        .context("Failed to insert kem key.")?;
+    }
+    Ok(())
 }
 
 impl X3DHClient for SqliteClient {
@@ -104,21 +423,23 @@ impl X3DHClient for SqliteClient {
         &mut self,
         one_time_prekey: &X25519PublicKey,
     ) -> Result<X25519StaticSecret, anyhow::Error> {
-        let key: [u8; 32] = self.connection.query_row(
-            "DELETE from keys WHERE public_key=?1 RETURNING private_key",
-            params![one_time_prekey.to_bytes()],
-            |row| Ok(row.get(0)?),
-        )?;
+        let key: [u8; 32] =
+            self.take_key("public_key = ?1", params![one_time_prekey.to_bytes()])?;
+        Ok(X25519StaticSecret::from(key))
+    }
+
+    fn fetch_wipe_opk_by_id(&mut self, id: u32) -> Result<X25519StaticSecret, anyhow::Error> {
+        let key: [u8; 32] = self.take_key("key_type = 2 AND id = ?1", params![id])?;
         Ok(X25519StaticSecret::from(key))
     }
 
     fn get_ik(&self) -> Result<SigningKey, anyhow::Error> {
-        Ok(self.identity_key.clone())
+        Ok(self.identity_key()?.clone())
     }
 
     fn get_pre_key(&self) -> Result<X25519StaticSecret, anyhow::Error> {
         let mut stmt = self.connection.prepare(
-            "SELECT private_key FROM keys WHERE key_type = 1 ORDER BY creation_time DESC LIMIT 1",
+            "SELECT private_key FROM keys WHERE key_type = 1 ORDER BY creation_time DESC, rowid DESC LIMIT 1",
         ).context("failed to prepare get_pre_key statement")?;
         let key = stmt
             .query_row([], |row| {
@@ -130,21 +451,31 @@ impl X3DHClient for SqliteClient {
         Ok(X25519StaticSecret::from(key))
     }
 
+    fn get_spk_secret(&self, id: u32) -> Result<X25519StaticSecret, anyhow::Error> {
+        let key: [u8; 32] = self
+            .connection
+            .query_row(
+                "SELECT private_key FROM keys WHERE key_type = 1 AND id = ?1 ORDER BY creation_time DESC, rowid DESC LIMIT 1",
+                params![id],
+                |row| row.get(0),
+            )
+            .with_context(|| format!("failed to find signed pre key {id}"))?;
+        Ok(X25519StaticSecret::from(key))
+    }
+
+    fn set_pre_key_id(&mut self, key: &X25519PublicKey, id: u32) -> Result<()> {
+        self.set_id(key.as_bytes(), id)
+    }
+
     fn get_spk(&self) -> Result<SignedPreKey, anyhow::Error> {
         let pre_key = self
             .get_pre_key()
             .context("failed to get pre_key for spk")?;
-        Ok(SignedPreKey {
-            pre_key: X25519PublicKey::from(&pre_key),
-            signature: sign_bundle(
-                &self.identity_key,
-                &[(pre_key.clone(), X25519PublicKey::from(&pre_key))],
-            ),
-        })
+        self.sign(&pre_key)
     }
 
     fn create_opks(&mut self, num_keys: u32) -> Result<SignedPreKeys> {
-        let opks = create_prekey_bundle(&self.identity_key, num_keys);
+        let opks = create_prekey_bundle(self.identity_key()?, num_keys);
         let pre_keys = opks.bundle.iter().map(|(_, _pub)| *_pub).collect();
         let persisted_pre_keys: Vec<PreKey> = opks
             .bundle
@@ -153,6 +484,7 @@ impl X3DHClient for SqliteClient {
                 priv_key: opk.0,
                 pub_key: opk.1,
                 key_type: KeyType::OneTimeKey,
+                id: None,
             })
             .collect();
         self.insert(&persisted_pre_keys)
@@ -162,4 +494,819 @@ impl X3DHClient for SqliteClient {
             signature: opks.signature,
         })
     }
+
+    fn rotate_spk(&mut self) -> Result<SignedPreKey> {
+        self.rotate(KeyType::PreKey)?;
+        self.get_spk()
+    }
+
+    fn get_last_resort_key(&self) -> Result<SignedPreKey> {
+        let key = self
+            .newest_key(KeyType::LastResort)?
+            .context("failed to find last resort key")?;
+        self.sign(&key)
+    }
+
+    fn get_last_resort_secret(&self, id: u32) -> Result<X25519StaticSecret> {
+        let key: [u8; 32] = self
+            .connection
+            .query_row(
+                "SELECT private_key FROM keys WHERE key_type = 3 AND id = ?1 ORDER BY creation_time DESC, rowid DESC LIMIT 1",
+                params![id],
+                |row| row.get(0),
+            )
+            .with_context(|| format!("failed to find last resort key {id}"))?;
+        Ok(X25519StaticSecret::from(key))
+    }
+
+    fn rotate_last_resort_key(&mut self) -> Result<SignedPreKey> {
+        self.rotate(KeyType::LastResort)?;
+        self.get_last_resort_key()
+    }
+
+    fn create_kem_opks(&mut self, num_keys: u32) -> Result<Vec<SignedKemPreKey>> {
+        let kem_opks = (0..num_keys)
+            .map(|_| {
+                Ok(KemPreKey {
+                    priv_key: kem::generate()?,
+                    key_type: KeyType::OneTimeKemKey,
+                    id: None,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        insert_kem_keys(&self.connection, &kem_opks).context("failed to add one time kem keys")?;
+        let ik = self.identity_key()?;
+        Ok(kem_opks
+            .iter()
+            .map(|key| sign_kem_pre_key(ik, &key.priv_key.public_key()))
+            .collect())
+    }
+
+    fn set_kem_pre_key_id(&mut self, key: &KemPublicKey, id: u32) -> Result<()> {
+        self.set_id(key.as_bytes(), id)
+    }
+
+    fn fetch_wipe_kem_opk_by_id(&mut self, id: u32) -> Result<KemSecretKey> {
+        let key: Vec<u8> = self
+            .take_key("key_type = 4 AND id = ?1", params![id])
+            .with_context(|| format!("failed to find one time kem key {id}"))?;
+        Ok(KemSecretKey::from_bytes(&key)?)
+    }
+
+    fn get_last_resort_kem_key(&self) -> Result<Option<SignedKemPreKey>> {
+        let Some(key) = self.newest_kem_key(KeyType::LastResortKem)? else {
+            return Ok(None);
+        };
+        Ok(Some(sign_kem_pre_key(
+            self.identity_key()?,
+            &key.public_key(),
+        )))
+    }
+
+    fn get_last_resort_kem_secret(&self, id: u32) -> Result<KemSecretKey> {
+        let key: Vec<u8> = self
+            .connection
+            .query_row(
+                "SELECT private_key FROM keys WHERE key_type = 5 AND id = ?1 ORDER BY creation_time DESC, rowid DESC LIMIT 1",
+                params![id],
+                |row| row.get(0),
+            )
+            .with_context(|| format!("failed to find last resort kem key {id}"))?;
+        Ok(KemSecretKey::from_bytes(&key)?)
+    }
+
+    fn rotate_last_resort_kem_key(&mut self) -> Result<SignedKemPreKey> {
+        self.rotate_kem(KeyType::LastResortKem)?;
+        self.get_last_resort_kem_key()?
+            .context("failed to find last resort kem key")
+    }
+
+    fn export_keys(&self) -> Result<KeyBackup> {
+        let keys = |key_type: KeyType| -> Result<Vec<(Option<u32>, X25519StaticSecret)>> {
+            let mut stmt = self.connection.prepare(
+                "SELECT id, private_key FROM keys WHERE key_type = ?1 ORDER BY creation_time DESC, rowid DESC",
+            )?;
+            let keys = stmt
+                .query_map(params![key_type as u32], |row| {
+                    let key: [u8; 32] = row.get(1)?;
+                    Ok((row.get(0)?, X25519StaticSecret::from(key)))
+                })?
+                .collect::<Result<_, _>>()
+                .context("failed to read keys")?;
+            Ok(keys)
+        };
+        let kem_keys = |key_type: KeyType| -> Result<Vec<(Option<u32>, KemSecretKey)>> {
+            let mut stmt = self.connection.prepare(
+                "SELECT id, private_key FROM keys WHERE key_type = ?1 ORDER BY creation_time DESC, rowid DESC",
+            )?;
+            let keys = stmt
+                .query_map(params![key_type as u32], |row| {
+                    Ok((row.get(0)?, row.get::<_, Vec<u8>>(1)?))
+                })?
+                .collect::<Result<Vec<_>, _>>()
+                .context("failed to read kem keys")?;
+            keys.into_iter()
+                .map(|(id, key)| Ok((id, KemSecretKey::from_bytes(&key)?)))
+                .collect()
+        };
+        Ok(KeyBackup {
+            ik: self.identity_key()?.clone(),
+            spks: keys(KeyType::PreKey)?,
+            opks: keys(KeyType::OneTimeKey)?,
+            last_resort_keys: keys(KeyType::LastResort)?,
+            kem_opks: kem_keys(KeyType::OneTimeKemKey)?,
+            last_resort_kem_keys: kem_keys(KeyType::LastResortKem)?,
+        })
+    }
+
+    fn import_keys(&mut self, keys: KeyBackup) -> Result<()> {
+        let pre_keys = |keys: Vec<(Option<u32>, X25519StaticSecret)>, key_type| {
+            keys.into_iter().map(move |(id, priv_key)| PreKey {
+                pub_key: X25519PublicKey::from(&priv_key),
+                priv_key,
+                key_type,
+                id,
+            })
+        };
+        // Inserted oldest first so the newest signed pre key and last-resort key stay current.
+        let pre_keys: Vec<PreKey> =
+            pre_keys(keys.spks.into_iter().rev().collect(), KeyType::PreKey)
+                .chain(pre_keys(keys.opks, KeyType::OneTimeKey))
+                .chain(pre_keys(
+                    keys.last_resort_keys.into_iter().rev().collect(),
+                    KeyType::LastResort,
+                ))
+                .collect();
+        let kem_keys = |keys: Vec<(Option<u32>, KemSecretKey)>, key_type| {
+            keys.into_iter().map(move |(id, priv_key)| KemPreKey {
+                priv_key,
+                key_type,
+                id,
+            })
+        };
+        let kem_pre_keys: Vec<KemPreKey> = kem_keys(keys.kem_opks, KeyType::OneTimeKemKey)
+            .chain(kem_keys(
+                keys.last_resort_kem_keys.into_iter().rev().collect(),
+                KeyType::LastResortKem,
+            ))
+            .collect();
+        self.secret_store.store(&keys.ik)?;
+        self.identity_key = OnceCell::from(keys.ik);
+        let tx = self.connection.transaction()?;
+        tx.execute("DELETE FROM keys", ())?;
+        // Sessions and sent messages are sealed under the identity key being replaced.
+        tx.execute("DELETE FROM sessions", ())?;
+        tx.execute("DELETE FROM sent_messages", ())?;
+        insert_keys(&tx, &pre_keys).context("failed to import keys")?;
+        insert_kem_keys(&tx, &kem_pre_keys).context("failed to import kem keys")?;
+        tx.commit()?;
+        self.sessions.clear();
+        self.scrub()?;
+        // Backups from before last-resort keys don't carry one.
+        if self.newest_key(KeyType::LastResort)?.is_none() {
+            self.rotate(KeyType::LastResort)?;
+        }
+        // Nor do backups from before KEM keys or from clients without ML-KEM support.
+        if kem::SUPPORTED && self.newest_kem_key(KeyType::LastResortKem)?.is_none() {
+            self.rotate_kem(KeyType::LastResortKem)?;
+        }
+        Ok(())
+    }
+
+    fn save_sent_message(
+        &mut self,
+        uuid: &[u8; MESSAGE_UUID_LEN],
+        message: SentMessage,
+    ) -> Result<()> {
+        let sealed = seal_data(
+            &*self.session_key()?,
+            Payload {
+                msg: &message.message,
+                aad: &sent_message_ad(uuid, &message.recipient),
+            },
+        )?;
+        self.connection
+            .execute(
+                "INSERT OR REPLACE INTO sent_messages (uuid, recipient, message, sent_at, attempts, resent_from) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![
+                    uuid,
+                    message.recipient,
+                    sealed,
+                    message.sent_at.duration_since(UNIX_EPOCH)?.as_secs(),
+                    message.attempts,
+                    message.resent_from
+                ],
+            )
+            .context("Failed to save sent message.")?;
+        Ok(())
+    }
+
+    fn get_sent_message(&self, uuid: &[u8; MESSAGE_UUID_LEN]) -> Result<Option<SentMessage>> {
+        let sent = self
+            .connection
+            .query_row(
+                "SELECT recipient, message, sent_at, attempts, resent_from FROM sent_messages WHERE uuid = ?1",
+                [uuid],
+                |row| {
+                    Ok(SentMessage {
+                        recipient: row.get(0)?,
+                        message: row.get(1)?,
+                        sent_at: UNIX_EPOCH + Duration::from_secs(row.get(2)?),
+                        attempts: row.get(3)?,
+                        resent_from: row.get(4)?,
+                    })
+                },
+            )
+            .optional()
+            .context("Failed to get sent message.")?;
+        let Some(sent) = sent else {
+            return Ok(None);
+        };
+        // A message that doesn't open is as good as forgotten.
+        let Ok(message) = open_data(
+            &sent.message,
+            &*self.session_key()?,
+            &sent_message_ad(uuid, &sent.recipient),
+        ) else {
+            return Ok(None);
+        };
+        Ok(Some(SentMessage {
+            message: message.to_vec(),
+            ..sent
+        }))
+    }
+
+    fn forget_sent_messages(&mut self, before: SystemTime) -> Result<()> {
+        self.connection
+            .execute(
+                "DELETE FROM sent_messages WHERE sent_at < ?1",
+                [before.duration_since(UNIX_EPOCH)?.as_secs()],
+            )
+            .context("Failed to forget sent messages.")?;
+        self.scrub()
+    }
+
+    fn get_session(&mut self, peer: &str, device_id: u32) -> Result<Option<PeerSession>> {
+        let key = (peer.to_owned(), device_id);
+        if let Some(session) = self.sessions.get(&key) {
+            return Ok(Some(session.clone()));
+        }
+        let row: Option<(Vec<u8>, Vec<u8>)> = self
+            .connection
+            .query_row(
+                "SELECT identity_key, state FROM sessions WHERE peer = ?1 AND device_id = ?2",
+                params![peer, device_id],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()
+            .context("Failed to get session.")?;
+        let Some((ik, sealed)) = row else {
+            return Ok(None);
+        };
+        let Some(ik) = <[u8; 32]>::try_from(ik)
+            .ok()
+            .and_then(|ik| VerifyingKey::from_bytes(&ik).ok())
+        else {
+            return Ok(None);
+        };
+        let local_key = self.session_key()?;
+        let Ok(session) = Session::open(&sealed, &local_key, &session_ad(peer, device_id, &ik))
+        else {
+            return Ok(None);
+        };
+        let session = PeerSession { ik, session };
+        self.sessions.insert(key, session.clone());
+        Ok(Some(session))
+    }
+
+    fn save_session(&mut self, peer: &str, device_id: u32, session: &PeerSession) -> Result<()> {
+        let sealed = session.session.seal(
+            &*self.session_key()?,
+            &session_ad(peer, device_id, &session.ik),
+        )?;
+        self.connection
+            .execute(
+                "INSERT OR REPLACE INTO sessions (peer, device_id, identity_key, state, last_used)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![
+                    peer,
+                    device_id,
+                    session.ik.as_bytes(),
+                    sealed,
+                    SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs()
+                ],
+            )
+            .context("Failed to save session.")?;
+        self.sessions
+            .insert((peer.to_owned(), device_id), session.clone());
+        Ok(())
+    }
+
+    fn delete_session(&mut self, peer: &str, device_id: u32) -> Result<()> {
+        self.sessions.remove(&(peer.to_owned(), device_id));
+        self.connection
+            .execute(
+                "DELETE FROM sessions WHERE peer = ?1 AND device_id = ?2",
+                params![peer, device_id],
+            )
+            .context("Failed to delete session.")?;
+        self.scrub()
+    }
+
+    fn session_devices(&self, peer: &str) -> Result<Vec<u32>> {
+        let mut statement = self
+            .connection
+            .prepare("SELECT device_id FROM sessions WHERE peer = ?1 ORDER BY device_id")?;
+        let devices = statement
+            .query_map([peer], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()
+            .context("Failed to get session devices.")?;
+        Ok(devices)
+    }
+
+    fn expect_key_confirmation(
+        &mut self,
+        uuid: &[u8; MESSAGE_UUID_LEN],
+        confirmation: PendingConfirmation,
+    ) -> Result<()> {
+        self.connection
+            .execute(
+                "INSERT OR REPLACE INTO key_confirmations (uuid, device_id, peer, mac, sent_at) VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![
+                    uuid,
+                    confirmation.device_id,
+                    confirmation.peer,
+                    confirmation.mac,
+                    confirmation.sent_at.duration_since(UNIX_EPOCH)?.as_secs()
+                ],
+            )
+            .context("Failed to save key confirmation.")?;
+        Ok(())
+    }
+
+    fn take_key_confirmation(
+        &mut self,
+        uuid: &[u8; MESSAGE_UUID_LEN],
+        device_id: u32,
+    ) -> Result<Option<PendingConfirmation>> {
+        self.connection
+            .query_row(
+                "DELETE FROM key_confirmations WHERE uuid = ?1 AND device_id = ?2 RETURNING peer, mac, sent_at",
+                params![uuid, device_id],
+                |row| {
+                    Ok(PendingConfirmation {
+                        peer: row.get(0)?,
+                        device_id,
+                        mac: row.get(1)?,
+                        sent_at: UNIX_EPOCH + Duration::from_secs(row.get(2)?),
+                    })
+                },
+            )
+            .optional()
+            .context("Failed to take key confirmation.")
+    }
+
+    fn expire_key_confirmations(
+        &mut self,
+        before: SystemTime,
+    ) -> Result<Vec<([u8; MESSAGE_UUID_LEN], PendingConfirmation)>> {
+        let mut stmt = self.connection.prepare(
+            "DELETE FROM key_confirmations WHERE sent_at < ?1 RETURNING uuid, device_id, peer, mac, sent_at",
+        )?;
+        let expired = stmt
+            .query_map([before.duration_since(UNIX_EPOCH)?.as_secs()], |row| {
+                Ok((
+                    row.get(0)?,
+                    PendingConfirmation {
+                        device_id: row.get(1)?,
+                        peer: row.get(2)?,
+                        mac: row.get(3)?,
+                        sent_at: UNIX_EPOCH + Duration::from_secs(row.get(4)?),
+                    },
+                ))
+            })?
+            .collect::<rusqlite::Result<_>>()
+            .context("Failed to expire key confirmations.")?;
+        Ok(expired)
+    }
+
+    fn session_state(&self, peer: &str) -> Result<SessionState> {
+        let confirmed = self
+            .connection
+            .query_row(
+                "SELECT 1 FROM confirmed_sessions WHERE peer = ?1",
+                [peer],
+                |_| Ok(()),
+            )
+            .optional()
+            .context("Failed to get session state.")?;
+        Ok(match confirmed {
+            Some(()) => SessionState::Confirmed,
+            None => SessionState::Unconfirmed,
+        })
+    }
+
+    fn confirm_session(&mut self, peer: &str) -> Result<()> {
+        self.connection
+            .execute(
+                "INSERT OR REPLACE INTO confirmed_sessions (peer, confirmed_at) VALUES (?1, ?2)",
+                params![
+                    peer,
+                    SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs()
+                ],
+            )
+            .context("Failed to confirm session.")?;
+        Ok(())
+    }
+
+    fn wipe(&mut self) -> Result<()> {
+        self.secret_store.clear()?;
+        self.identity_key = OnceCell::new();
+        self.sessions.clear();
+        self.connection
+            .execute("DELETE FROM keys", ())
+            .context("failed to delete keys")?;
+        self.connection
+            .execute("DELETE FROM sent_messages", ())
+            .context("failed to delete sent messages")?;
+        self.connection
+            .execute("DELETE FROM sessions", ())
+            .context("failed to delete sessions")?;
+        self.connection
+            .execute("DELETE FROM key_confirmations", ())
+            .context("failed to delete key confirmations")?;
+        self.connection
+            .execute("DELETE FROM confirmed_sessions", ())
+            .context("failed to delete confirmed sessions")?;
+        self.scrub()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::secret_store::SecretStore;
+    use crate::sqlite_client::*;
+    use std::sync::{Arc, Mutex};
+
+    /// Counts how often the client reaches for its identity key.
+    #[derive(Clone, Default)]
+    struct MockStore {
+        key: Arc<Mutex<Option<SigningKey>>>,
+        loads: Arc<Mutex<u32>>,
+    }
+
+    impl SecretStore for MockStore {
+        fn load(&self) -> Result<Option<SigningKey>> {
+            *self.loads.lock().unwrap() += 1;
+            Ok(self.key.lock().unwrap().clone())
+        }
+
+        fn store(&self, key: &SigningKey) -> Result<()> {
+            *self.key.lock().unwrap() = Some(key.clone());
+            Ok(())
+        }
+
+        fn clear(&self) -> Result<()> {
+            *self.key.lock().unwrap() = None;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn identity_key_loaded_lazily() -> Result<()> {
+        let ik = SigningKey::generate(&mut OsRng);
+        let store = MockStore::default();
+        store.store(&ik)?;
+        let client =
+            SqliteClient::with_secret_store(Box::new(store.clone()), Path::new(":memory:"))?;
+        assert_eq!(*store.loads.lock().unwrap(), 0);
+
+        assert_eq!(client.get_ik()?, ik);
+        client.get_spk()?;
+        assert_eq!(client.get_ik()?, ik);
+        assert_eq!(*store.loads.lock().unwrap(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn identity_key_created_in_empty_store() -> Result<()> {
+        let store = MockStore::default();
+        let mut client =
+            SqliteClient::with_secret_store(Box::new(store.clone()), Path::new(":memory:"))?;
+        let ik = client.get_ik()?;
+        assert_eq!(store.key.lock().unwrap().clone(), Some(ik));
+
+        let mut keys = client.export_keys()?;
+        keys.ik = SigningKey::generate(&mut OsRng);
+        let imported = keys.ik.clone();
+        client.import_keys(keys)?;
+        assert_eq!(client.get_ik()?, imported);
+        assert_eq!(store.key.lock().unwrap().clone(), Some(imported));
+        Ok(())
+    }
+
+    #[test]
+    fn sent_messages() -> Result<()> {
+        let mut client =
+            SqliteClient::with_secret_store(Box::new(MockStore::default()), Path::new(":memory:"))?;
+        let sent_at = UNIX_EPOCH + Duration::from_secs(1_000);
+        let message = SentMessage {
+            recipient: String::from("bob"),
+            message: b"Hello Bob!".to_vec(),
+            sent_at,
+            attempts: 0,
+            resent_from: None,
+        };
+        client.save_sent_message(&[1; MESSAGE_UUID_LEN], message.clone())?;
+        assert_eq!(
+            client.get_sent_message(&[1; MESSAGE_UUID_LEN])?,
+            Some(message.clone())
+        );
+        assert_eq!(client.get_sent_message(&[2; MESSAGE_UUID_LEN])?, None);
+        // The plaintext is sealed.
+        let stored: Vec<u8> =
+            client
+                .connection
+                .query_row("SELECT message FROM sent_messages", (), |row| row.get(0))?;
+        assert!(!contains(&stored, b"Hello Bob!"));
+
+        let resent = SentMessage {
+            sent_at: sent_at + Duration::from_secs(60),
+            attempts: 1,
+            resent_from: Some([1; MESSAGE_UUID_LEN]),
+            ..message.clone()
+        };
+        client.save_sent_message(&[2; MESSAGE_UUID_LEN], resent.clone())?;
+        client.forget_sent_messages(sent_at + Duration::from_secs(1))?;
+        assert_eq!(client.get_sent_message(&[1; MESSAGE_UUID_LEN])?, None);
+        assert_eq!(
+            client.get_sent_message(&[2; MESSAGE_UUID_LEN])?,
+            Some(resent)
+        );
+        // And bound to the row it is in.
+        client
+            .connection
+            .execute("UPDATE sent_messages SET recipient = 'carol'", ())?;
+        assert_eq!(client.get_sent_message(&[2; MESSAGE_UUID_LEN])?, None);
+
+        client.wipe()?;
+        assert_eq!(client.get_sent_message(&[2; MESSAGE_UUID_LEN])?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn key_confirmations() -> Result<()> {
+        let mut client =
+            SqliteClient::with_secret_store(Box::new(MockStore::default()), Path::new(":memory:"))?;
+        let sent_at = UNIX_EPOCH + Duration::from_secs(1_000);
+        let laptop = PendingConfirmation {
+            peer: String::from("bob"),
+            device_id: 1,
+            mac: [1; 32],
+            sent_at,
+        };
+        let phone = PendingConfirmation {
+            device_id: 2,
+            mac: [2; 32],
+            ..laptop.clone()
+        };
+        let later = PendingConfirmation {
+            sent_at: sent_at + Duration::from_secs(60),
+            ..laptop.clone()
+        };
+        client.expect_key_confirmation(&[1; MESSAGE_UUID_LEN], laptop.clone())?;
+        client.expect_key_confirmation(&[1; MESSAGE_UUID_LEN], phone.clone())?;
+        client.expect_key_confirmation(&[2; MESSAGE_UUID_LEN], later.clone())?;
+
+        assert_eq!(
+            client.take_key_confirmation(&[1; MESSAGE_UUID_LEN], 2)?,
+            Some(phone)
+        );
+        assert_eq!(
+            client.take_key_confirmation(&[1; MESSAGE_UUID_LEN], 2)?,
+            None
+        );
+        assert_eq!(
+            client.expire_key_confirmations(sent_at + Duration::from_secs(1))?,
+            vec![([1; MESSAGE_UUID_LEN], laptop)]
+        );
+        assert_eq!(
+            client.take_key_confirmation(&[1; MESSAGE_UUID_LEN], 1)?,
+            None
+        );
+
+        assert_eq!(client.session_state("bob")?, SessionState::Unconfirmed);
+        client.confirm_session("bob")?;
+        assert_eq!(client.session_state("bob")?, SessionState::Confirmed);
+        assert_eq!(client.session_state("carol")?, SessionState::Unconfirmed);
+
+        client.wipe()?;
+        assert_eq!(
+            client.take_key_confirmation(&[2; MESSAGE_UUID_LEN], 1)?,
+            None
+        );
+        assert_eq!(client.session_state("bob")?, SessionState::Unconfirmed);
+        Ok(())
+    }
+
+    #[test]
+    fn wipe() -> Result<()> {
+        let store = MockStore::default();
+        let mut client =
+            SqliteClient::with_secret_store(Box::new(store.clone()), Path::new(":memory:"))?;
+        let ik = client.get_ik()?;
+        client.create_opks(2)?;
+        client.wipe()?;
+        assert_eq!(store.key.lock().unwrap().clone(), None);
+        assert!(client.get_spk().is_err());
+        assert_ne!(client.get_ik()?, ik);
+        Ok(())
+    }
+
+    /// Everything on disk for the database at `path`.
+    fn database_files(path: &Path) -> Vec<u8> {
+        ["", "-wal", "-shm"]
+            .iter()
+            .filter_map(|suffix| std::fs::read(format!("{}{suffix}", path.display())).ok())
+            .flatten()
+            .collect()
+    }
+
+    #[test]
+    fn wiped_keys_leave_no_trace() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("brongnal-client-{}.db3", std::process::id()));
+        let mut client = SqliteClient::with_secret_store(Box::new(MockStore::default()), &path)?;
+        let opks = client.create_opks(3)?;
+        client.set_pre_key_id(&opks.pre_keys[1], 7)?;
+        let kem_opk = if kem::SUPPORTED {
+            client.create_kem_opks(1)?.pop()
+        } else {
+            None
+        };
+        let backup = client.export_keys()?;
+        let secrets: Vec<[u8; 32]> = opks
+            .pre_keys
+            .iter()
+            .map(|pre_key| {
+                let (_, secret) = backup
+                    .opks
+                    .iter()
+                    .find(|(_, secret)| X25519PublicKey::from(secret) == *pre_key)
+                    .unwrap();
+                secret.to_bytes()
+            })
+            .collect();
+        let files = database_files(&path);
+        assert!(secrets.iter().all(|secret| contains(&files, secret)));
+
+        client.fetch_wipe_opk(&opks.pre_keys[0])?;
+        client.fetch_wipe_opk_by_id(7)?;
+        assert!(client.fetch_wipe_opk_by_id(7).is_err());
+        if let Some(kem_opk) = kem_opk {
+            client.set_kem_pre_key_id(&kem_opk.pre_key, 8)?;
+            client.fetch_wipe_kem_opk_by_id(8)?;
+        }
+        let files = database_files(&path);
+        drop(client);
+        assert!(!contains(&files, &secrets[0]));
+        assert!(!contains(&files, &secrets[1]));
+        assert!(contains(&files, &secrets[2]));
+
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{suffix}", path.display()));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn sessions_survive_restarts() -> Result<()> {
+        use crate::memory_client::MemoryClient;
+        use crate::{session_decrypt, session_encrypt};
+        use chacha20poly1305::aead::Payload;
+        use proto::DEFAULT_DEVICE_ID;
+        use protocol::ratchet::{Role, SessionError};
+        use protocol::x3dh::{initiate_recv, initiate_send, PreKeyBundle};
+
+        let path =
+            std::env::temp_dir().join(format!("brongnal-sessions-{}.db3", std::process::id()));
+        let store = MockStore::default();
+        let payload = |msg| Payload { msg, aad: b"ad" };
+        let decrypt = |client: &mut dyn X3DHClient, peer, message| -> Result<Vec<u8>> {
+            Ok(session_decrypt(client, peer, DEFAULT_DEVICE_ID, message, b"ad")?.0)
+        };
+        let mut alice = MemoryClient::new();
+        let mut bob = SqliteClient::with_secret_store(Box::new(store.clone()), &path)?;
+        // Alice sets up the session with X3DH, as her first message to Bob does.
+        let alice_ik = alice.get_ik()?;
+        let bob_ik = bob.get_ik()?;
+        let bundle = PreKeyBundle {
+            ik: bob_ik.verifying_key(),
+            opk: None,
+            spk: bob.get_spk()?,
+            spk_id: None,
+            opk_id: None,
+            last_resort: false,
+            kem_pre_key: None,
+            kem_pre_key_id: None,
+            kem_last_resort: false,
+            suite: None,
+        };
+        let (sk, initial) = initiate_send(bundle, String::from("alice"), &alice_ik, b"hi")?;
+        alice.save_session(
+            "bob",
+            DEFAULT_DEVICE_ID,
+            &PeerSession {
+                ik: bob_ik.verifying_key(),
+                session: Session::new(&sk, Role::Initiator),
+            },
+        )?;
+        let (sk, _) = initiate_recv(
+            &bob_ik,
+            &bob.get_pre_key()?,
+            &initial.sender_ik,
+            initial.ek,
+            None,
+            None,
+            initial.suite,
+            initial.version,
+            &initial.ciphertext,
+        )?;
+        bob.save_session(
+            "alice",
+            DEFAULT_DEVICE_ID,
+            &PeerSession {
+                ik: initial.sender_ik,
+                session: Session::new(&sk, Role::Responder),
+            },
+        )?;
+
+        let ping = session_encrypt(&mut alice, "bob", DEFAULT_DEVICE_ID, payload(b"ping"))?;
+        assert_eq!(decrypt(&mut bob, "alice", &ping)?, b"ping");
+        let pong = session_encrypt(&mut bob, "alice", DEFAULT_DEVICE_ID, payload(b"pong"))?;
+        assert_eq!(decrypt(&mut alice, "bob", &pong)?, b"pong");
+        // Bob restarts with one message still in flight, after a later one arrived.
+        let late = session_encrypt(&mut alice, "bob", DEFAULT_DEVICE_ID, payload(b"late"))?;
+        let early = session_encrypt(&mut alice, "bob", DEFAULT_DEVICE_ID, payload(b"early"))?;
+        assert_eq!(decrypt(&mut bob, "alice", &early)?, b"early");
+        drop(bob);
+
+        let mut bob = SqliteClient::with_secret_store(Box::new(store.clone()), &path)?;
+        assert_eq!(bob.session_devices("alice")?, [DEFAULT_DEVICE_ID]);
+        let (late_plaintext, ik) =
+            session_decrypt(&mut bob, "alice", DEFAULT_DEVICE_ID, &late, b"ad")?;
+        assert_eq!(late_plaintext, b"late");
+        assert_eq!(ik, alice_ik.verifying_key());
+        // Nor does restarting forget what was received, so replays are still caught.
+        for replayed in [&early, &late] {
+            assert!(matches!(
+                decrypt(&mut bob, "alice", replayed)
+                    .unwrap_err()
+                    .downcast_ref(),
+                Some(SessionError::ReplayDetected(_))
+            ));
+        }
+        let pong = session_encrypt(&mut bob, "alice", DEFAULT_DEVICE_ID, payload(b"pong"))?;
+        assert_eq!(decrypt(&mut alice, "bob", &pong)?, b"pong");
+        let ping = session_encrypt(&mut alice, "bob", DEFAULT_DEVICE_ID, payload(b"ping"))?;
+        assert_eq!(decrypt(&mut bob, "alice", &ping)?, b"ping");
+        // Sessions are kept with each device, and with no one else.
+        assert!(session_encrypt(&mut bob, "alice", 2, payload(b"hi")).is_err());
+        assert!(session_encrypt(&mut bob, "carol", DEFAULT_DEVICE_ID, payload(b"hi")).is_err());
+
+        // States that can't be read, e.g. from a newer release, are dropped for a new session.
+        bob.connection
+            .execute("UPDATE sessions SET state = x'00'", ())?;
+        drop(bob);
+        let mut bob = SqliteClient::with_secret_store(Box::new(store.clone()), &path)?;
+        assert!(bob.get_session("alice", DEFAULT_DEVICE_ID)?.is_none());
+        let session = PeerSession {
+            ik: alice_ik.verifying_key(),
+            session: Session::new(&sk, Role::Responder),
+        };
+        bob.save_session("alice", DEFAULT_DEVICE_ID, &session)?;
+        // Each row is bound to the device and identity key it is with.
+        bob.connection.execute(
+            "UPDATE sessions SET identity_key = ?1",
+            [bob_ik.verifying_key().as_bytes()],
+        )?;
+        drop(bob);
+        let mut bob = SqliteClient::with_secret_store(Box::new(store.clone()), &path)?;
+        assert!(bob.get_session("alice", DEFAULT_DEVICE_ID)?.is_none());
+        bob.save_session("alice", DEFAULT_DEVICE_ID, &session)?;
+        bob.delete_session("alice", DEFAULT_DEVICE_ID)?;
+        assert!(bob.get_session("alice", DEFAULT_DEVICE_ID)?.is_none());
+        assert!(bob.session_devices("alice")?.is_empty());
+        drop(bob);
+
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{suffix}", path.display()));
+        }
+        Ok(())
+    }
+
+    fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+        haystack
+            .windows(needle.len())
+            .any(|window| window == needle)
+    }
 }
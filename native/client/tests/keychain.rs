@@ -0,0 +1,22 @@
+//! Exercises the real platform keystore, so it only builds with `--features keychain` and needs a
+//! reachable keystore to pass.
+#![cfg(feature = "keychain")]
+
+use anyhow::Result;
+use chacha20poly1305::aead::OsRng;
+use client::secret_store::{KeychainStore, SecretStore};
+use ed25519_dalek::SigningKey;
+
+#[test]
+fn keychain_round_trip() -> Result<()> {
+    let store = KeychainStore::new(&format!("test-{}", std::process::id()))?;
+    assert_eq!(store.load()?, None);
+
+    let ik = SigningKey::generate(&mut OsRng);
+    store.store(&ik)?;
+    let loaded = store.load();
+    store.clear()?;
+    assert_eq!(loaded?, Some(ik));
+    assert_eq!(store.load()?, None);
+    Ok(())
+}
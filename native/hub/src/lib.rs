@@ -1,31 +1,29 @@
 use crate::messages::brongnal::{RegisterUserResponse, SendMessage};
-use client::{listen, message, register, sqlite_client::SqliteClient, DecryptedMessage};
+use client::{session::Brongnal, sqlite_client::SqliteClient, ClientEvent, DecryptedMessage};
+use futures::StreamExt;
 use messages::brongnal::{ReceivedMessage, RegisterUserRequest};
-use proto::service::brongnal_client::BrongnalClient;
+use proto::Identity;
 use rinf::debug_print;
 use std::path::PathBuf;
 use std::sync::Arc;
 use tokio;
-use tokio::sync::mpsc::{self, Sender};
-use tokio::sync::Mutex;
-use tonic::transport::Channel;
 
 mod messages;
 
 rinf::write_interface!();
 
-async fn handle_register_user(
-    mut stub: BrongnalClient<Channel>,
-    client: Arc<Mutex<SqliteClient>>,
-    tx: Sender<DecryptedMessage>,
-) {
+async fn handle_register_user(brongnal: Arc<Brongnal>) {
     let mut receiver = RegisterUserRequest::get_dart_signal_receiver().unwrap();
     while let Some(dart_signal) = receiver.recv().await {
         let message: RegisterUserRequest = dart_signal.message;
         match message.username {
             Some(name) => {
                 debug_print!("Received request to register {name}");
-                match register(&mut stub, client.clone(), name.clone()).await {
+                let registered = match name.parse::<Identity>() {
+                    Ok(identity) => brongnal.register(&identity).await,
+                    Err(e) => Err(e.into()),
+                };
+                match registered {
                     Ok(_) => {
                         debug_print!("Registered {name}");
                     }
@@ -33,11 +31,6 @@ async fn handle_register_user(
                         debug_print!("Failed to register {name} with error: {e}");
                     }
                 }
-                let client = client.clone();
-                let stub = stub.clone();
-                let listen_name = name.clone();
-                let tx = tx.clone();
-                tokio::spawn(listen(stub, client, listen_name, tx));
                 RegisterUserResponse {
                     username: Some(name),
                 }
@@ -50,19 +43,16 @@ async fn handle_register_user(
     }
 }
 
-async fn handle_send_message(mut stub: BrongnalClient<Channel>, client: Arc<Mutex<SqliteClient>>) {
+async fn handle_send_message(brongnal: Arc<Brongnal>) {
     let mut receiver = SendMessage::get_dart_signal_receiver().unwrap();
     while let Some(dart_signal) = receiver.recv().await {
+        // Messages are sent as whoever this device registered as.
         let req: SendMessage = dart_signal.message;
-        match message(
-            &mut stub,
-            client.clone(),
-            req.sender().to_owned(),
-            req.receiver(),
-            req.message(),
-        )
-        .await
-        {
+        let sent = match req.receiver().parse::<Identity>() {
+            Ok(receiver) => brongnal.send(&receiver, req.message().as_bytes()).await,
+            Err(e) => Err(e.into()),
+        };
+        match sent {
             Ok(_) => {}
             Err(e) => {
                 debug_print!("Failed to message: {e}");
@@ -72,21 +62,48 @@ async fn handle_send_message(mut stub: BrongnalClient<Channel>, client: Arc<Mute
 }
 
 async fn main() {
-    let stub = BrongnalClient::connect("https://signal.brongan.com:443")
-        .await
-        .unwrap();
-
     let identity_key_path = PathBuf::from("identity_key");
     let db_path = PathBuf::from("keys.sqlite");
-    let client = Arc::new(Mutex::new(
-        SqliteClient::new(&identity_key_path, &db_path).unwrap(),
-    ));
+    let client = SqliteClient::new(&identity_key_path, &db_path).unwrap();
+    let brongnal = Arc::new(
+        Brongnal::connect("https://signal.brongan.com:443", client)
+            .await
+            .unwrap(),
+    );
 
-    let (tx, mut rx) = mpsc::channel(100);
-    tokio::spawn(handle_register_user(stub.clone(), client.clone(), tx));
-    tokio::spawn(handle_send_message(stub.clone(), client.clone()));
+    let mut events = Box::pin(brongnal.events());
+    tokio::spawn(handle_register_user(brongnal.clone()));
+    tokio::spawn(handle_send_message(brongnal));
 
-    while let Some(decrypted) = rx.recv().await {
+    while let Some(event) = events.next().await {
+        let decrypted: DecryptedMessage = match event {
+            ClientEvent::MessageReceived(decrypted) => decrypted,
+            ClientEvent::KeyChanged { identity } => {
+                debug_print!("[Revoked Key] {identity}'s identity key has been revoked.");
+                continue;
+            }
+            ClientEvent::NoOneTimeKey { peer } => {
+                debug_print!("[Warning] {peer} has run out of one time keys.");
+                continue;
+            }
+            ClientEvent::KeyNotConfirmed {
+                recipient,
+                device_id,
+                ..
+            } => {
+                debug_print!("[Warning] {recipient}'s device {device_id} hasn't confirmed a key.");
+                continue;
+            }
+            ClientEvent::Warning(warning) => {
+                debug_print!("[Warning] {warning}");
+                continue;
+            }
+            ClientEvent::Error(error) => {
+                debug_print!("[Error] {error}");
+                continue;
+            }
+            _ => continue,
+        };
         let message = String::from_utf8(decrypted.message).ok();
         if let Some(message) = &message {
             debug_print!(
@@ -4,7 +4,15 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
     tonic_build::configure()
         .file_descriptor_set_path(out_dir.join("service_descriptor.bin"))
-        .compile(&["service.proto", &"gossamer.proto"], &["proto"])
+        .compile(
+            &[
+                "service.proto",
+                "gossamer.proto",
+                "backup.proto",
+                "admin.proto",
+            ],
+            &["proto"],
+        )
         .unwrap();
     Ok(())
 }
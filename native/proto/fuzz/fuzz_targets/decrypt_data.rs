@@ -0,0 +1,20 @@
+//! Feeds arbitrary ciphertexts and associated data to both AEADs under a fixed key. The first
+//! byte is the length of the associated data, which the rest of the input starts with.
+#![no_main]
+
+use aes_gcm::Aes256Gcm;
+use chacha20poly1305::{ChaCha20Poly1305, KeyInit};
+use libfuzzer_sys::fuzz_target;
+use protocol::aead::decrypt_data;
+
+/// The key the seed corpus was sealed under.
+const KEY: [u8; 32] = [0x42; 32];
+
+fuzz_target!(|data: &[u8]| {
+    let Some((&aad_len, rest)) = data.split_first() else {
+        return;
+    };
+    let (aad, ciphertext) = rest.split_at(usize::from(aad_len).min(rest.len()));
+    let _ = decrypt_data(ciphertext, aad, &ChaCha20Poly1305::new(&KEY.into()));
+    let _ = decrypt_data(ciphertext, aad, &Aes256Gcm::new(&KEY.into()));
+});
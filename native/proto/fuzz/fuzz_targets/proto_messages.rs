@@ -0,0 +1,26 @@
+//! Feeds arbitrary bytes through the proto decode and conversion of every message a peer or the
+//! server hands us. Decoding and conversion may fail, but only with an error.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use prost::Message;
+use proto::service::{
+    Message as MessageProto, PreKeyBundle as PreKeyBundleProto, SignedPreKey as SignedPreKeyProto,
+};
+use proto::{MessagePayload, ParseError};
+use protocol::kem::SignedKemPreKey;
+use protocol::x3dh::{Message as X3DHMessage, PreKeyBundle, SignedPreKey};
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(message) = MessageProto::decode(data) {
+        let _ = MessagePayload::try_from(message.clone());
+        let _ = X3DHMessage::try_from(message);
+    }
+    if let Ok(bundle) = PreKeyBundleProto::decode(data) {
+        let _: Result<PreKeyBundle, ParseError> = bundle.try_into();
+    }
+    if let Ok(key) = SignedPreKeyProto::decode(data) {
+        let _ = SignedPreKey::try_from(key.clone());
+        let _ = SignedKemPreKey::try_from(key);
+    }
+});
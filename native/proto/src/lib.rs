@@ -1,173 +1,846 @@
-use ed25519_dalek::{Signature, VerifyingKey};
+use curve25519_dalek::edwards::CompressedEdwardsY;
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
 use prost::Message;
+use std::cmp::Ordering;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::ops::Deref;
+use std::str::FromStr;
+use std::time::Duration;
 use thiserror::Error;
 use tonic::Status;
+use unicode_normalization::UnicodeNormalization;
+use unicode_security::GeneralSecurityProfile;
 use x25519_dalek::PublicKey as X25519PublicKey;
 
-#[derive(Error, Debug)]
-pub enum ClientError {
-    #[error("Key was not a valid ED25519 point.")]
-    InvalidEd25519Key,
-    #[error("Key was not a valid X25519 point.")]
-    InvalidX25519Key,
+/// The largest message ciphertext accepted, well above any text message but far below what
+/// would let a single send fill a mailbox.
+pub const MAX_CIPHERTEXT_LEN: usize = 1 << 20;
+
+/// Why a field failed to parse.
+#[derive(Error, Debug, PartialEq)]
+pub enum ParseErrorReason {
+    #[error("missing")]
+    Missing,
+    #[error("expected {expected} bytes, got {actual}")]
+    Length { expected: usize, actual: usize },
+    #[error("{actual} bytes exceeds the limit of {max}")]
+    TooLong { max: usize, actual: usize },
+    #[error("{actual} entries exceeds the limit of {max}")]
+    TooMany { max: usize, actual: usize },
+    #[error("not a canonical encoding")]
+    NonCanonical,
+    #[error("not a point on the curve")]
+    InvalidPoint,
+    #[error("a small order point")]
+    WeakKey,
+    #[error("empty")]
+    Empty,
+    #[error("starts or ends with whitespace")]
+    SurroundingWhitespace,
+    #[error("contains the disallowed character {0:?}")]
+    DisallowedCharacter(char),
+    #[error("none this release recognizes")]
+    Unrecognized,
+}
+
+/// A proto field that failed to parse. Converts into an `InvalidArgument` status naming the field.
+#[derive(Error, Debug, PartialEq)]
+#[error("Invalid {field}: {reason}")]
+pub struct ParseError {
+    pub field: String,
+    pub reason: ParseErrorReason,
+}
+
+impl ParseError {
+    pub fn new(field: &str, reason: ParseErrorReason) -> Self {
+        ParseError {
+            field: field.to_owned(),
+            reason,
+        }
+    }
+
+    pub fn missing(field: &str) -> Self {
+        ParseError::new(field, ParseErrorReason::Missing)
+    }
+
+    /// Qualifies the field with the name of the message field containing it.
+    pub fn within(mut self, parent: &str) -> Self {
+        self.field = format!("{parent}.{}", self.field);
+        self
+    }
+}
+
+impl From<ParseError> for Status {
+    fn from(e: ParseError) -> Self {
+        Status::invalid_argument(e.to_string())
+    }
+}
+
+fn fixed_len<const N: usize>(field: &str, bytes: &[u8]) -> Result<[u8; N], ParseError> {
+    bytes.try_into().map_err(|_| {
+        ParseError::new(
+            field,
+            ParseErrorReason::Length {
+                expected: N,
+                actual: bytes.len(),
+            },
+        )
+    })
+}
+
+/// Parses an Ed25519 public key, rejecting non-canonical encodings and small order points.
+pub fn parse_verifying_key(field: &str, key: &[u8]) -> Result<VerifyingKey, ParseError> {
+    let bytes = fixed_len::<32>(field, key)?;
+    let point = CompressedEdwardsY(bytes)
+        .decompress()
+        .ok_or_else(|| ParseError::new(field, ParseErrorReason::InvalidPoint))?;
+    if point.compress().to_bytes() != bytes {
+        return Err(ParseError::new(field, ParseErrorReason::NonCanonical));
+    }
+    let key = VerifyingKey::from_bytes(&bytes)
+        .map_err(|_| ParseError::new(field, ParseErrorReason::InvalidPoint))?;
+    if key.is_weak() {
+        return Err(ParseError::new(field, ParseErrorReason::WeakKey));
+    }
+    Ok(key)
+}
+
+/// Parses an X25519 public key, rejecting u-coordinates that aren't reduced mod 2^255 - 19.
+pub fn parse_x25519_public_key(field: &str, key: &[u8]) -> Result<X25519PublicKey, ParseError> {
+    let bytes = fixed_len::<32>(field, key)?;
+    let top_is_max = bytes[31] == 0x7F && bytes[1..31].iter().all(|&b| b == 0xFF);
+    if bytes[31] & 0x80 != 0 || (top_is_max && bytes[0] >= 0xED) {
+        return Err(ParseError::new(field, ParseErrorReason::NonCanonical));
+    }
+    Ok(X25519PublicKey::from(bytes))
+}
+
+pub fn parse_signature(field: &str, signature: &[u8]) -> Result<Signature, ParseError> {
+    Ok(Signature::from_bytes(&fixed_len::<64>(field, signature)?))
+}
+
+/// The longest identity accepted, in bytes, including the domain of a federated one.
+pub const MAX_IDENTITY_LEN: usize = 64;
+
+/// The name of an account, as registered with the server and given to senders. Identities are
+/// compared in their canonical form, NFKC normalized and lowercased, so "Alice", "alice" and
+/// "ａｌｉｃｅ" all name the same account. The canonical form holds 1 to `MAX_IDENTITY_LEN` bytes
+/// of the letters, digits and marks Unicode allows in identifiers and any of `._-+@`, the last
+/// for the domain of a federated identity, so identities are safe to store, log and show. The
+/// form the identity was given in is kept for display.
+#[derive(Clone, Debug)]
+pub struct Identity {
+    canonical: String,
+    display: String,
+}
+
+impl Identity {
+    /// Checks that `identity`, read from `field`, is a valid identity.
+    pub fn parse(field: &str, identity: impl Into<String>) -> Result<Identity, ParseError> {
+        let display = identity.into();
+        if display.is_empty() {
+            return Err(ParseError::new(field, ParseErrorReason::Empty));
+        }
+        let too_long = |actual| {
+            ParseError::new(
+                field,
+                ParseErrorReason::TooLong {
+                    max: MAX_IDENTITY_LEN,
+                    actual,
+                },
+            )
+        };
+        if display.len() > MAX_IDENTITY_LEN {
+            return Err(too_long(display.len()));
+        }
+        if display.trim() != display {
+            return Err(ParseError::new(
+                field,
+                ParseErrorReason::SurroundingWhitespace,
+            ));
+        }
+        // Lowercasing can leave text that isn't normalized, e.g. by splitting off an accent.
+        let canonical: String = display.nfkc().flat_map(char::to_lowercase).nfkc().collect();
+        if canonical.len() > MAX_IDENTITY_LEN {
+            return Err(too_long(canonical.len()));
+        }
+        if let Some(c) = canonical
+            .chars()
+            .find(|&c| !c.identifier_allowed() && !"._-+@".contains(c))
+        {
+            return Err(ParseError::new(
+                field,
+                ParseErrorReason::DisallowedCharacter(c),
+            ));
+        }
+        Ok(Identity { canonical, display })
+    }
+
+    /// The canonical form, which storage is keyed by.
+    pub fn as_str(&self) -> &str {
+        &self.canonical
+    }
+
+    /// The identity as it was given, e.g. "Alice" rather than "alice".
+    pub fn display_name(&self) -> &str {
+        &self.display
+    }
+
+    /// What the identity looks like, per Unicode's confusable detection: identities with the
+    /// same skeleton, like "paypal" and "раураl" in Cyrillic, are easily mistaken for each
+    /// other.
+    pub fn skeleton(&self) -> String {
+        unicode_security::skeleton(&self.canonical).collect()
+    }
+}
+
+impl PartialEq for Identity {
+    fn eq(&self, other: &Identity) -> bool {
+        self.canonical == other.canonical
+    }
 }
 
-pub fn parse_verifying_key(key: &[u8]) -> Result<VerifyingKey, ClientError> {
-    VerifyingKey::from_bytes(&key.try_into().map_err(|_| ClientError::InvalidEd25519Key)?)
-        .map_err(|_| ClientError::InvalidEd25519Key)
+impl Eq for Identity {}
+
+impl Hash for Identity {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.canonical.hash(state);
+    }
 }
 
-pub fn parse_x25519_public_key(key: &[u8]) -> Result<X25519PublicKey, ClientError> {
-    let key: [u8; 32] = key.try_into().map_err(|_| ClientError::InvalidX25519Key)?;
-    Ok(X25519PublicKey::from(key))
+impl PartialOrd for Identity {
+    fn partial_cmp(&self, other: &Identity) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Identity {
+    fn cmp(&self, other: &Identity) -> Ordering {
+        self.canonical.cmp(&other.canonical)
+    }
+}
+
+impl Deref for Identity {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.canonical
+    }
+}
+
+impl AsRef<str> for Identity {
+    fn as_ref(&self) -> &str {
+        &self.canonical
+    }
+}
+
+impl fmt::Display for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.canonical)
+    }
+}
+
+impl FromStr for Identity {
+    type Err = ParseError;
+
+    fn from_str(identity: &str) -> Result<Identity, ParseError> {
+        Identity::parse("identity", identity)
+    }
+}
+
+impl TryFrom<String> for Identity {
+    type Error = ParseError;
+
+    fn try_from(identity: String) -> Result<Identity, ParseError> {
+        Identity::parse("identity", identity)
+    }
+}
+
+impl From<Identity> for String {
+    fn from(identity: Identity) -> String {
+        identity.canonical
+    }
+}
+
+/// The bytes an identity key signs to authorize deleting `identity` and all of its data.
+pub fn delete_user_payload(identity: &str) -> Vec<u8> {
+    [b"brongnal delete user:".as_slice(), identity.as_bytes()].concat()
+}
+
+/// The bytes an identity key signs to authorize deleting `identity` with a `nonce` from
+/// GetAccountDeletionNonce. The nonce is length prefixed so no two requests share a payload.
+pub fn delete_account_payload(identity: &str, nonce: &[u8]) -> Vec<u8> {
+    let mut payload = b"brongnal delete account:".to_vec();
+    payload.extend_from_slice(&(nonce.len() as u32).to_be_bytes());
+    payload.extend_from_slice(nonce);
+    payload.extend_from_slice(identity.as_bytes());
+    payload
+}
+
+/// The bytes an identity key signs to authorize exporting everything the server holds about
+/// `identity`, `timestamp` seconds after the unix epoch.
+pub fn export_account_data_payload(
+    identity: &str,
+    timestamp: u64,
+    include_ciphertexts: bool,
+) -> Vec<u8> {
+    [
+        b"brongnal export account data:".as_slice(),
+        &timestamp.to_be_bytes(),
+        &[include_ciphertexts as u8],
+        identity.as_bytes(),
+    ]
+    .concat()
+}
+
+/// The bytes a device's identity key signs to follow what becomes of the messages `identity`
+/// sends, `timestamp` seconds after the unix epoch.
+pub fn message_status_stream_payload(identity: &str, device_id: u32, timestamp: u64) -> Vec<u8> {
+    [
+        b"brongnal message status stream:".as_slice(),
+        &device_id.to_be_bytes(),
+        &timestamp.to_be_bytes(),
+        identity.as_bytes(),
+    ]
+    .concat()
+}
+
+/// The bytes an identity key signs to authorize removing one of `identity`'s devices.
+pub fn delete_device_payload(identity: &str, device_id: u32) -> Vec<u8> {
+    [
+        b"brongnal delete device:".as_slice(),
+        &device_id.to_be_bytes(),
+        identity.as_bytes(),
+    ]
+    .concat()
+}
+
+/// The bytes a device's identity key signs to authorize waking it with `device_token`. Each
+/// string is length prefixed so no two requests share a payload.
+pub fn register_push_token_payload(
+    identity: &str,
+    device_id: u32,
+    device_token: &str,
+    platform: service::PushPlatform,
+    app_version: &str,
+) -> Vec<u8> {
+    let mut payload = b"brongnal register push token:".to_vec();
+    payload.extend_from_slice(&device_id.to_be_bytes());
+    payload.extend_from_slice(&(platform as i32).to_be_bytes());
+    for field in [identity, device_token, app_version] {
+        payload.extend_from_slice(&(field.len() as u32).to_be_bytes());
+        payload.extend_from_slice(field.as_bytes());
+    }
+    payload
+}
+
+/// The device clients that predate devices register as.
+pub const DEFAULT_DEVICE_ID: u32 = 1;
+
+/// How often the server sends a heartbeat down each open message stream.
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How often each end of a connection pings the other over HTTP/2, so that connections
+/// intermediaries have silently dropped are noticed even without an open message stream.
+pub const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(20);
+
+/// How long a keepalive ping may go unanswered before the connection is considered dead.
+pub const KEEPALIVE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Length of the random ids new devices pick for their provisioning envelopes.
+pub const PROVISIONING_ID_LEN: usize = 16;
+
+/// Length of the random ids senders pick for each message so retries aren't delivered twice.
+pub const MESSAGE_UUID_LEN: usize = 16;
+
+/// The longest nonce the server echoes back from a ping.
+pub const MAX_PING_NONCE_LEN: usize = 32;
+
+/// How long a provisioning envelope waits on the server for the new device to fetch it.
+pub const PROVISIONING_TTL: Duration = Duration::from_secs(5 * 60);
+
+pub mod admin {
+    tonic::include_proto!("admin");
+}
+pub mod backup {
+    tonic::include_proto!("backup");
 }
 pub mod gossamer {
     tonic::include_proto!("gossamer");
 }
+// Generated oneofs hold their messages inline, however unevenly sized.
+#[allow(clippy::large_enum_variant)]
 pub mod service {
     tonic::include_proto!("service");
 }
 pub const FILE_DESCRIPTOR_SET: &[u8] = tonic::include_file_descriptor_set!("service_descriptor");
 
+use protocol::kem::{self, KemPublicKey, SignedKemPreKey};
 use protocol::x3dh::Message as X3DHMessage;
 use protocol::x3dh::PreKeyBundle;
 use protocol::x3dh::SignedPreKey;
 use protocol::x3dh::SignedPreKeys;
+use service::message::Payload as PayloadProto;
+use service::ContentType;
 use service::Message as MessageProto;
 use service::PreKeyBundle as PreKeyBundleProto;
+use service::ServerControl;
+use service::SessionCiphertext as SessionCiphertextProto;
 use service::SignedPreKey as SignedPreKeyProto;
 use service::SignedPreKeys as SignedPreKeysProto;
+use service::X3dhInitial;
 
-impl Into<SignedPreKeyProto> for SignedPreKey {
-    fn into(self) -> SignedPreKeyProto {
+impl From<SignedPreKey> for SignedPreKeyProto {
+    fn from(value: SignedPreKey) -> Self {
         SignedPreKeyProto {
-            pre_key: Some(self.pre_key.to_bytes().to_vec()),
-            signature: Some(self.signature.to_vec()),
+            pre_key: Some(value.pre_key.to_bytes().to_vec()),
+            signature: Some(value.signature.to_vec()),
         }
     }
 }
 
-impl Into<SignedPreKeysProto> for SignedPreKeys {
-    fn into(self) -> SignedPreKeysProto {
+impl From<SignedPreKeys> for SignedPreKeysProto {
+    fn from(value: SignedPreKeys) -> Self {
         SignedPreKeysProto {
-            pre_keys: self
+            pre_keys: value
                 .pre_keys
                 .into_iter()
                 .map(|key| key.to_bytes().to_vec())
                 .collect(),
-            signature: Some(self.signature.to_vec()),
+            signature: Some(value.signature.to_vec()),
         }
     }
 }
 
 impl TryFrom<SignedPreKeyProto> for SignedPreKey {
-    type Error = tonic::Status;
+    type Error = ParseError;
 
     fn try_from(value: SignedPreKeyProto) -> Result<Self, Self::Error> {
-        let signature = value.signature();
-
-        let pre_key = parse_x25519_public_key(value.pre_key())
-            .map_err(|e| Status::invalid_argument(format!("Invalid SignedPreKey: {e}")))?;
-        let signature = Signature::from_slice(&signature)
-            .map_err(|_| Status::invalid_argument("Pre Key has an invalid X25519 Signature"))?;
+        let pre_key = value
+            .pre_key
+            .ok_or_else(|| ParseError::missing("pre_key"))?;
+        let pre_key = parse_x25519_public_key("pre_key", &pre_key)?;
+        let signature = value
+            .signature
+            .ok_or_else(|| ParseError::missing("signature"))?;
+        let signature = parse_signature("signature", &signature)?;
         Ok(SignedPreKey { pre_key, signature })
     }
 }
 
-impl TryFrom<MessageProto> for X3DHMessage {
-    type Error = tonic::Status;
-
-    fn try_from(value: MessageProto) -> Result<Self, Self::Error> {
-        let sender_identity = value.sender_identity().to_owned();
-        let sender_ik = parse_verifying_key(value.sender_identity_key())
-            .map_err(|e| Status::invalid_argument(format!("Invalid sender_identity_key: {e}")))?;
+impl From<SignedKemPreKey> for SignedPreKeyProto {
+    fn from(value: SignedKemPreKey) -> Self {
+        SignedPreKeyProto {
+            pre_key: Some(value.pre_key.as_bytes().to_vec()),
+            signature: Some(value.signature.to_vec()),
+        }
+    }
+}
 
-        let ek = parse_x25519_public_key(&value.ephemeral_key())
-            .map_err(|e| Status::invalid_argument(format!("Invalid ephemeral_key: {e}")))?;
+impl TryFrom<SignedPreKeyProto> for SignedKemPreKey {
+    type Error = ParseError;
 
-        let opk = if let Some(opk) = value.one_time_key {
-            Some(
-                parse_x25519_public_key(&opk)
-                    .map_err(|e| Status::invalid_argument(format!("Invalid one_time_key: {e}")))?,
+    fn try_from(value: SignedPreKeyProto) -> Result<Self, Self::Error> {
+        let pre_key = value
+            .pre_key
+            .ok_or_else(|| ParseError::missing("pre_key"))?;
+        let pre_key = KemPublicKey::from_bytes(&pre_key).map_err(|_| {
+            ParseError::new(
+                "pre_key",
+                ParseErrorReason::Length {
+                    expected: kem::PUBLIC_KEY_LEN,
+                    actual: pre_key.len(),
+                },
             )
-        } else {
-            None
-        };
+        })?;
+        let signature = value
+            .signature
+            .ok_or_else(|| ParseError::missing("signature"))?;
+        let signature = parse_signature("signature", &signature)?;
+        Ok(SignedKemPreKey { pre_key, signature })
+    }
+}
+
+impl TryFrom<X3dhInitial> for X3DHMessage {
+    type Error = ParseError;
+
+    fn try_from(value: X3dhInitial) -> Result<Self, Self::Error> {
+        let sender_identity = value
+            .sender_identity
+            .ok_or_else(|| ParseError::missing("sender_identity"))?;
+        Identity::parse("sender_identity", sender_identity.as_str())?;
+        let sender_ik = value
+            .sender_identity_key
+            .ok_or_else(|| ParseError::missing("sender_identity_key"))?;
+        let sender_ik = parse_verifying_key("sender_identity_key", &sender_ik)?;
+        let ek = value
+            .ephemeral_key
+            .ok_or_else(|| ParseError::missing("ephemeral_key"))?;
+        let ek = parse_x25519_public_key("ephemeral_key", &ek)?;
+        let opk = value
+            .one_time_key
+            .map(|opk| parse_x25519_public_key("one_time_key", &opk))
+            .transpose()?;
+        if let Some(kem_ciphertext) = &value.kem_ciphertext {
+            if kem_ciphertext.len() != kem::CIPHERTEXT_LEN {
+                return Err(ParseError::new(
+                    "kem_ciphertext",
+                    ParseErrorReason::Length {
+                        expected: kem::CIPHERTEXT_LEN,
+                        actual: kem_ciphertext.len(),
+                    },
+                ));
+            }
+        }
+        let ciphertext = value
+            .ciphertext
+            .ok_or_else(|| ParseError::missing("ciphertext"))?;
+        if ciphertext.len() > MAX_CIPHERTEXT_LEN {
+            return Err(ParseError::new(
+                "ciphertext",
+                ParseErrorReason::TooLong {
+                    max: MAX_CIPHERTEXT_LEN,
+                    actual: ciphertext.len(),
+                },
+            ));
+        }
         Ok(X3DHMessage {
             sender_identity,
             sender_ik,
             ek,
+            spk_id: value.signed_pre_key_id,
+            opk_id: value.one_time_key_id,
             opk,
-            ciphertext: value
-                .ciphertext
-                .ok_or(Status::invalid_argument("request missing ciphertext"))?
-                .to_vec(),
+            last_resort: value.last_resort.unwrap_or_default(),
+            kem_pre_key_id: value.kem_pre_key_id,
+            kem_last_resort: value.kem_last_resort.unwrap_or_default(),
+            kem_ciphertext: value.kem_ciphertext,
+            suite: value.cipher_suite,
+            version: value.protocol_version,
+            ciphertext,
         })
     }
 }
 
-impl Into<MessageProto> for X3DHMessage {
-    fn into(self) -> MessageProto {
+/// Parses a message that must be an `x3dh_initial`, in either form.
+impl TryFrom<MessageProto> for X3DHMessage {
+    type Error = ParseError;
+
+    fn try_from(value: MessageProto) -> Result<Self, Self::Error> {
+        match MessagePayload::try_from(value)? {
+            MessagePayload::X3DHInitial(message) => Ok(*message),
+            _ => Err(ParseError::missing("x3dh_initial")),
+        }
+    }
+}
+
+impl From<X3DHMessage> for X3dhInitial {
+    fn from(value: X3DHMessage) -> Self {
+        X3dhInitial {
+            sender_identity: Some(value.sender_identity),
+            sender_identity_key: Some(value.sender_ik.to_bytes().to_vec()),
+            ephemeral_key: Some(value.ek.to_bytes().to_vec()),
+            one_time_key: value.opk.map(|opk| opk.to_bytes().to_vec()),
+            ciphertext: Some(value.ciphertext),
+            signed_pre_key_id: value.spk_id,
+            one_time_key_id: value.opk_id,
+            last_resort: value.last_resort.then_some(true),
+            kem_ciphertext: value.kem_ciphertext,
+            kem_pre_key_id: value.kem_pre_key_id,
+            kem_last_resort: value.kem_last_resort.then_some(true),
+            cipher_suite: value.suite,
+            protocol_version: value.version,
+            content_type: None,
+            sender_device_id: None,
+        }
+    }
+}
+
+impl From<X3DHMessage> for MessageProto {
+    fn from(value: X3DHMessage) -> Self {
         MessageProto {
-            sender_identity: Some(self.sender_identity),
-            sender_identity_key: Some(self.sender_ik.to_bytes().to_vec()),
-            ephemeral_key: Some(self.ek.to_bytes().to_vec()),
-            one_time_key: self.opk.map(|opk| opk.to_bytes().to_vec()),
-            ciphertext: Some(self.ciphertext),
+            payload: Some(PayloadProto::X3dhInitial(value.into())),
+            ..Default::default()
         }
     }
 }
 
-impl TryInto<PreKeyBundle> for PreKeyBundleProto {
-    type Error = tonic::Status;
+/// A message under a session set up by an earlier X3DH message.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SessionMessage {
+    pub sender_identity: String,
+    pub ciphertext: Vec<u8>,
+}
 
-    fn try_into(self) -> Result<PreKeyBundle, Self::Error> {
-        let ik = parse_verifying_key(self.identity_key())
-            .map_err(|_| Status::invalid_argument("PreKeyBundle invalid identity_key"))?;
+impl TryFrom<SessionCiphertextProto> for SessionMessage {
+    type Error = ParseError;
 
-        let opk = if let Some(opk) = self.one_time_key {
-            Some(
-                parse_x25519_public_key(&opk)
-                    .map_err(|e| Status::invalid_argument(format!("Invalid one_time_key: {e}")))?,
-            )
-        } else {
-            None
+    fn try_from(value: SessionCiphertextProto) -> Result<Self, Self::Error> {
+        let sender_identity = value
+            .sender_identity
+            .ok_or_else(|| ParseError::missing("sender_identity"))?;
+        Identity::parse("sender_identity", sender_identity.as_str())?;
+        let ciphertext = value
+            .ciphertext
+            .ok_or_else(|| ParseError::missing("ciphertext"))?;
+        if ciphertext.len() > MAX_CIPHERTEXT_LEN {
+            return Err(ParseError::new(
+                "ciphertext",
+                ParseErrorReason::TooLong {
+                    max: MAX_CIPHERTEXT_LEN,
+                    actual: ciphertext.len(),
+                },
+            ));
+        }
+        Ok(SessionMessage {
+            sender_identity,
+            ciphertext,
+        })
+    }
+}
+
+impl From<SessionMessage> for MessageProto {
+    fn from(value: SessionMessage) -> Self {
+        MessageProto {
+            payload: Some(PayloadProto::SessionCiphertext(SessionCiphertextProto {
+                sender_identity: Some(value.sender_identity),
+                ciphertext: Some(value.ciphertext),
+                content_type: None,
+                sender_device_id: None,
+            })),
+            ..Default::default()
+        }
+    }
+}
+
+/// What a message carries, parsed.
+#[derive(Clone, Debug, PartialEq)]
+pub enum MessagePayload {
+    X3DHInitial(Box<X3DHMessage>),
+    SessionCiphertext(SessionMessage),
+    ServerControl(ServerControl),
+    /// A payload this release doesn't know, such as one a later release added. Callers say so
+    /// rather than dropping the message.
+    Unknown,
+}
+
+/// Parses either form of message. Errors name fields within the payload only for messages that
+/// have one, since the flat form has no such field.
+impl TryFrom<MessageProto> for MessagePayload {
+    type Error = ParseError;
+
+    fn try_from(value: MessageProto) -> Result<Self, Self::Error> {
+        let flat = value.payload.is_none();
+        let within = |e: ParseError, payload: &str| if flat { e } else { e.within(payload) };
+        Ok(match value.upgrade().payload {
+            Some(PayloadProto::X3dhInitial(message)) => MessagePayload::X3DHInitial(Box::new(
+                message.try_into().map_err(|e| within(e, "x3dh_initial"))?,
+            )),
+            Some(PayloadProto::SessionCiphertext(message)) => MessagePayload::SessionCiphertext(
+                message
+                    .try_into()
+                    .map_err(|e| within(e, "session_ciphertext"))?,
+            ),
+            Some(PayloadProto::ServerControl(control)) => MessagePayload::ServerControl(control),
+            None => MessagePayload::Unknown,
+        })
+    }
+}
+
+impl MessageProto {
+    /// Moves the fields of the flat form that predates `payload` into the payload they describe.
+    /// Messages that already have a payload, or set none of those fields, are left as they are.
+    #[allow(deprecated)]
+    pub fn upgrade(mut self) -> MessageProto {
+        if self.payload.is_some() {
+            return self;
+        }
+        if self.heartbeat.is_some() || self.pre_key_status.is_some() {
+            self.payload = Some(PayloadProto::ServerControl(ServerControl {
+                heartbeat: self.heartbeat.take(),
+                pre_key_status: self.pre_key_status.take(),
+            }));
+            return self;
+        }
+        let initial = X3dhInitial {
+            sender_identity: self.sender_identity.take(),
+            sender_identity_key: self.sender_identity_key.take(),
+            ephemeral_key: self.ephemeral_key.take(),
+            one_time_key: self.one_time_key.take(),
+            ciphertext: self.ciphertext.take(),
+            signed_pre_key_id: self.signed_pre_key_id.take(),
+            one_time_key_id: self.one_time_key_id.take(),
+            last_resort: self.last_resort.take(),
+            kem_ciphertext: self.kem_ciphertext.take(),
+            kem_pre_key_id: self.kem_pre_key_id.take(),
+            kem_last_resort: self.kem_last_resort.take(),
+            cipher_suite: self.cipher_suite.take(),
+            protocol_version: self.protocol_version.take(),
+            // The flat form predates content types and sessions.
+            content_type: None,
+            sender_device_id: None,
         };
+        if initial != X3dhInitial::default() {
+            self.payload = Some(PayloadProto::X3dhInitial(initial));
+        }
+        self
+    }
 
+    /// The identity that sent the message, in either form. Server control isn't from one.
+    #[allow(deprecated)]
+    pub fn sender(&self) -> Option<&str> {
+        match &self.payload {
+            Some(PayloadProto::X3dhInitial(message)) => message.sender_identity.as_deref(),
+            Some(PayloadProto::SessionCiphertext(message)) => message.sender_identity.as_deref(),
+            Some(PayloadProto::ServerControl(_)) => None,
+            None => self.sender_identity.as_deref(),
+        }
+    }
+
+    /// Replaces the identity that sent the message, in either form. Server control is left as
+    /// it is.
+    #[allow(deprecated)]
+    pub fn set_sender(&mut self, sender: String) {
+        match &mut self.payload {
+            Some(PayloadProto::X3dhInitial(message)) => message.sender_identity = Some(sender),
+            Some(PayloadProto::SessionCiphertext(message)) => {
+                message.sender_identity = Some(sender)
+            }
+            Some(PayloadProto::ServerControl(_)) => {}
+            None => self.sender_identity = Some(sender),
+        }
+    }
+
+    /// The content type the sender sealed the message as, which is only known to be theirs once
+    /// it decrypts. Kept as sent, so a type a later release added isn't mistaken for text, which
+    /// messages without one are.
+    pub fn content_type(&self) -> Option<i32> {
+        match &self.payload {
+            Some(PayloadProto::X3dhInitial(message)) => message.content_type,
+            Some(PayloadProto::SessionCiphertext(message)) => message.content_type,
+            Some(PayloadProto::ServerControl(_)) | None => None,
+        }
+    }
+
+    /// Labels the message's payload with `content_type`, left unset for text, which is all
+    /// receivers that predate content types expect. Server control is left as it is.
+    pub fn set_content_type(&mut self, content_type: ContentType) {
+        let content_type = (content_type != ContentType::Text).then_some(content_type.into());
+        match &mut self.payload {
+            Some(PayloadProto::X3dhInitial(message)) => message.content_type = content_type,
+            Some(PayloadProto::SessionCiphertext(message)) => message.content_type = content_type,
+            Some(PayloadProto::ServerControl(_)) | None => {}
+        }
+    }
+
+    /// The device of the sender the message is from, which is only known to be theirs once it
+    /// decrypts. Absent from senders that predate sessions, which only send from device 1.
+    pub fn sender_device_id(&self) -> Option<u32> {
+        match &self.payload {
+            Some(PayloadProto::X3dhInitial(message)) => message.sender_device_id,
+            Some(PayloadProto::SessionCiphertext(message)) => message.sender_device_id,
+            Some(PayloadProto::ServerControl(_)) | None => None,
+        }
+    }
+
+    /// Records `device_id` as the sender's device. Server control is left as it is.
+    pub fn set_sender_device_id(&mut self, device_id: u32) {
+        match &mut self.payload {
+            Some(PayloadProto::X3dhInitial(message)) => message.sender_device_id = Some(device_id),
+            Some(PayloadProto::SessionCiphertext(message)) => {
+                message.sender_device_id = Some(device_id)
+            }
+            Some(PayloadProto::ServerControl(_)) | None => {}
+        }
+    }
+}
+
+impl From<ServerControl> for MessageProto {
+    fn from(value: ServerControl) -> Self {
+        MessageProto {
+            payload: Some(PayloadProto::ServerControl(value)),
+            ..Default::default()
+        }
+    }
+}
+
+impl TryInto<PreKeyBundle> for PreKeyBundleProto {
+    type Error = ParseError;
+
+    fn try_into(self) -> Result<PreKeyBundle, Self::Error> {
+        let ik = self
+            .identity_key
+            .ok_or_else(|| ParseError::missing("identity_key"))?;
+        let ik = parse_verifying_key("identity_key", &ik)?;
+        let opk = self
+            .one_time_key
+            .map(|opk| parse_x25519_public_key("one_time_key", &opk))
+            .transpose()?;
         let spk = self
             .signed_pre_key
-            .ok_or(Status::invalid_argument("PreKeyBundle missing spk."))?
-            .try_into()?;
+            .ok_or_else(|| ParseError::missing("signed_pre_key"))?
+            .try_into()
+            .map_err(|e: ParseError| e.within("signed_pre_key"))?;
+        let kem_pre_key = self
+            .kem_pre_key
+            .map(SignedKemPreKey::try_from)
+            .transpose()
+            .map_err(|e| e.within("kem_pre_key"))?;
 
-        Ok(PreKeyBundle { ik, opk, spk })
+        Ok(PreKeyBundle {
+            ik,
+            opk,
+            spk,
+            spk_id: self.signed_pre_key_id,
+            opk_id: self.one_time_key_id,
+            last_resort: self.last_resort.unwrap_or_default(),
+            kem_pre_key,
+            kem_pre_key_id: self.kem_pre_key_id,
+            kem_last_resort: self.kem_last_resort.unwrap_or_default(),
+            suite: self.cipher_suite,
+        })
     }
 }
 
-#[allow(dead_code)]
-struct SignedMessage {
-    message: gossamer::Message,
-    signature: Signature,
+/// A Gossamer action whose signature has been checked against `public_key`.
+#[derive(Debug)]
+pub struct SignedMessage {
+    pub message: gossamer::Message,
+    pub signature: Signature,
+    pub provider: String,
+    pub public_key: VerifyingKey,
+}
+
+/// Signs a Gossamer action on behalf of `provider` with `key`.
+pub fn sign_action(
+    key: &SigningKey,
     provider: String,
-    public_key: VerifyingKey,
+    action: gossamer::message::Action,
+) -> gossamer::SignedMessage {
+    let contents = gossamer::Message {
+        action: Some(action),
+    }
+    .encode_to_vec();
+    gossamer::SignedMessage {
+        signature: Some(key.sign(&contents).to_vec()),
+        contents: Some(contents),
+        provider: Some(provider),
+        public_key: Some(key.verifying_key().to_bytes().to_vec()),
+    }
 }
 
 impl TryInto<SignedMessage> for gossamer::SignedMessage {
     type Error = tonic::Status;
     fn try_into(self) -> Result<SignedMessage, Self::Error> {
-        let signature = Signature::from_slice(self.signature()).map_err(|_| {
-            Status::invalid_argument("SignedMessage has an invalid X25519 Signature")
-        })?;
-        let public_key = parse_verifying_key(&self.public_key()).map_err(|e| {
-            Status::invalid_argument(format!(
-                "SignedMessage has invalid sender_identity_key: {e}"
-            ))
-        })?;
+        let signature = parse_signature("signature", self.signature())?;
+        let public_key = parse_verifying_key("public_key", self.public_key())?;
         let contents = self.contents();
         public_key
-            .verify_strict(&contents, &signature)
+            .verify_strict(contents, &signature)
             .map_err(|_| Status::unauthenticated("SignedMessage signature invalid."))?;
 
-        let message = gossamer::Message::decode(&*contents)
+        let message = gossamer::Message::decode(contents)
             .map_err(|_| Status::invalid_argument("contents are not serialized message."))?;
 
         Ok(SignedMessage {
@@ -180,3 +853,470 @@ impl TryInto<SignedMessage> for gossamer::SignedMessage {
         })
     }
 }
+
+// Most messages here are in the deprecated flat form, which must still parse.
+#[cfg(test)]
+#[allow(deprecated)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::SigningKey;
+    use x25519_dalek::StaticSecret as X25519StaticSecret;
+
+    fn reason<T: std::fmt::Debug>(result: Result<T, ParseError>) -> (String, ParseErrorReason) {
+        let e = result.unwrap_err();
+        (e.field, e.reason)
+    }
+
+    fn length(expected: usize, actual: usize) -> ParseErrorReason {
+        ParseErrorReason::Length { expected, actual }
+    }
+
+    #[test]
+    fn identities() {
+        for identity in [
+            "alice",
+            "bob@b.example",
+            "carol.smith-2",
+            "dave_+tag",
+            "x",
+            "alíce",
+        ] {
+            assert_eq!(identity.parse::<Identity>().unwrap().as_str(), identity);
+        }
+        let longest = "a".repeat(MAX_IDENTITY_LEN);
+        assert!(longest.parse::<Identity>().is_ok());
+
+        let rejected = |identity: &str| reason(Identity::parse("recipient_identity", identity));
+        let invalid = |reason| (String::from("recipient_identity"), reason);
+        assert_eq!(rejected(""), invalid(ParseErrorReason::Empty));
+        assert_eq!(
+            rejected(&format!("{longest}a")),
+            invalid(ParseErrorReason::TooLong {
+                max: MAX_IDENTITY_LEN,
+                actual: MAX_IDENTITY_LEN + 1
+            })
+        );
+        assert_eq!(
+            rejected(&"a".repeat(10_000)),
+            invalid(ParseErrorReason::TooLong {
+                max: MAX_IDENTITY_LEN,
+                actual: 10_000
+            })
+        );
+        for identity in [" alice", "alice ", "\talice", "alice\n"] {
+            assert_eq!(
+                rejected(identity),
+                invalid(ParseErrorReason::SurroundingWhitespace),
+                "{identity:?}"
+            );
+        }
+        for (identity, c) in [
+            ("al ice", ' '),
+            ("al\nice", '\n'),
+            ("al\0ice", '\0'),
+            ("al\u{1b}ice", '\u{1b}'),
+            ("alice/..", '/'),
+            ("al\"ice", '"'),
+            ("al\u{a0}ice", ' '),
+            ("alice!", '!'),
+            ("alice\u{200b}", '\u{200b}'),
+        ] {
+            assert_eq!(
+                rejected(identity),
+                invalid(ParseErrorReason::DisallowedCharacter(c)),
+                "{identity:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn canonical_identities() {
+        let canonical = |identity: &str| identity.parse::<Identity>().unwrap();
+        for (identity, expected) in [
+            ("Alice", "alice"),
+            ("ALICE", "alice"),
+            ("ａｌｉｃｅ", "alice"),
+            ("Bob@B.Example", "bob@b.example"),
+            // An accent given as a combining mark composes with its letter.
+            ("Ali\u{301}ce", "alíce"),
+            ("ALÍCE", "alíce"),
+            ("ﬁona", "fiona"),
+            ("Straße", "straße"),
+            ("Σοφία", "σοφία"),
+        ] {
+            let parsed = canonical(identity);
+            assert_eq!(parsed.as_str(), expected, "{identity:?}");
+            assert_eq!(parsed.display_name(), identity);
+            assert_eq!(parsed, canonical(expected));
+            assert_eq!(canonical(parsed.as_str()).as_str(), expected);
+        }
+        assert_ne!(canonical("alice"), canonical("alíce"));
+
+        // Normalizing can lengthen an identity past the limit.
+        assert_eq!(
+            reason(Identity::parse("identity", "ﷺ".repeat(2))),
+            (
+                String::from("identity"),
+                ParseErrorReason::TooLong {
+                    max: MAX_IDENTITY_LEN,
+                    actual: 66
+                }
+            )
+        );
+    }
+
+    #[test]
+    fn confusable_identities() {
+        let skeleton = |identity: &str| identity.parse::<Identity>().unwrap().skeleton();
+        // Cyrillic letters that look like Latin ones, in part or all of the identity.
+        assert_eq!(skeleton("аlice"), skeleton("alice"));
+        assert_eq!(skeleton("раураl"), skeleton("paypal"));
+        assert_eq!(skeleton("Paypal"), skeleton("paypal"));
+        assert_ne!(skeleton("bob"), skeleton("alice"));
+        assert_ne!(
+            "раураl".parse::<Identity>().unwrap(),
+            "paypal".parse().unwrap()
+        );
+    }
+
+    /// A message in the flat form that predates payloads.
+    fn message() -> MessageProto {
+        let ik = SigningKey::from_bytes(&[1; 32]);
+        let ek = X25519PublicKey::from(&X25519StaticSecret::from([2; 32]));
+        MessageProto {
+            sender_identity: Some("alice".to_owned()),
+            sender_identity_key: Some(ik.verifying_key().to_bytes().to_vec()),
+            ephemeral_key: Some(ek.to_bytes().to_vec()),
+            one_time_key: None,
+            ciphertext: Some(vec![0; 64]),
+            signed_pre_key_id: Some(1),
+            one_time_key_id: None,
+            last_resort: None,
+            kem_ciphertext: None,
+            kem_pre_key_id: None,
+            kem_last_resort: None,
+            cipher_suite: None,
+            protocol_version: Some(1),
+            heartbeat: None,
+            pre_key_status: None,
+            message_uuid: None,
+            payload: None,
+        }
+    }
+
+    fn spk() -> SignedPreKeyProto {
+        SignedPreKeyProto {
+            pre_key: Some(vec![9; 32]),
+            signature: Some(vec![0; 64]),
+        }
+    }
+
+    #[test]
+    fn parse_key_lengths() {
+        for len in [0, 1, 31, 33, 64] {
+            let key = vec![9; len];
+            assert_eq!(
+                reason(parse_verifying_key("ik", &key)),
+                ("ik".to_owned(), length(32, len))
+            );
+            assert_eq!(
+                reason(parse_x25519_public_key("ek", &key)),
+                ("ek".to_owned(), length(32, len))
+            );
+        }
+    }
+
+    #[test]
+    fn parse_non_canonical_keys() {
+        // p = 2^255 - 19 and the high bit both encode u-coordinates that aren't reduced.
+        let mut p = [0xFF; 32];
+        p[0] = 0xED;
+        p[31] = 0x7F;
+        let mut high_bit = [9; 32];
+        high_bit[31] |= 0x80;
+        for key in [p, high_bit] {
+            assert_eq!(
+                parse_x25519_public_key("ek", &key).unwrap_err().reason,
+                ParseErrorReason::NonCanonical
+            );
+        }
+        let mut below_p = p;
+        below_p[0] = 0xEC;
+        assert!(parse_x25519_public_key("ek", &below_p).is_ok());
+
+        // y = p + 1 decompresses to the same point as y = 1.
+        let mut y = p;
+        y[0] = 0xEE;
+        assert_eq!(
+            parse_verifying_key("ik", &y).unwrap_err().reason,
+            ParseErrorReason::NonCanonical
+        );
+        // y = 2 has no x on the curve.
+        let mut y = [0; 32];
+        y[0] = 2;
+        assert_eq!(
+            parse_verifying_key("ik", &y).unwrap_err().reason,
+            ParseErrorReason::InvalidPoint
+        );
+        // The identity point has small order.
+        let mut y = [0; 32];
+        y[0] = 1;
+        assert_eq!(
+            parse_verifying_key("ik", &y).unwrap_err().reason,
+            ParseErrorReason::WeakKey
+        );
+    }
+
+    #[test]
+    fn parse_message() {
+        assert!(X3DHMessage::try_from(message()).is_ok());
+
+        type Clear = fn(&mut MessageProto);
+        let missing: [(&str, Clear); 4] = [
+            ("sender_identity", |m| m.sender_identity = None),
+            ("sender_identity_key", |m| m.sender_identity_key = None),
+            ("ephemeral_key", |m| m.ephemeral_key = None),
+            ("ciphertext", |m| m.ciphertext = None),
+        ];
+        for (field, clear) in missing {
+            let mut proto = message();
+            clear(&mut proto);
+            assert_eq!(
+                reason(X3DHMessage::try_from(proto)),
+                (field.to_owned(), ParseErrorReason::Missing)
+            );
+        }
+
+        let mut proto = message();
+        proto.one_time_key = Some(vec![]);
+        assert_eq!(
+            reason(X3DHMessage::try_from(proto)),
+            ("one_time_key".to_owned(), length(32, 0))
+        );
+        let mut proto = message();
+        proto.kem_ciphertext = Some(vec![0; kem::CIPHERTEXT_LEN - 1]);
+        assert_eq!(
+            reason(X3DHMessage::try_from(proto)),
+            (
+                "kem_ciphertext".to_owned(),
+                length(kem::CIPHERTEXT_LEN, kem::CIPHERTEXT_LEN - 1)
+            )
+        );
+        let mut proto = message();
+        proto.ciphertext = Some(vec![0; MAX_CIPHERTEXT_LEN + 1]);
+        assert_eq!(
+            reason(X3DHMessage::try_from(proto)),
+            (
+                "ciphertext".to_owned(),
+                ParseErrorReason::TooLong {
+                    max: MAX_CIPHERTEXT_LEN,
+                    actual: MAX_CIPHERTEXT_LEN + 1
+                }
+            )
+        );
+    }
+
+    #[test]
+    fn message_payloads() -> Result<(), Box<dyn std::error::Error>> {
+        let round_trip = |message: MessageProto| -> Result<MessagePayload, ParseError> {
+            MessagePayload::try_from(MessageProto::decode(&*message.encode_to_vec()).unwrap())
+        };
+        let initial = X3DHMessage::try_from(message())?;
+        assert_eq!(
+            round_trip(initial.clone().into())?,
+            MessagePayload::X3DHInitial(Box::new(initial.clone()))
+        );
+        let session = SessionMessage {
+            sender_identity: "alice".to_owned(),
+            ciphertext: vec![1; 48],
+        };
+        assert_eq!(
+            round_trip(session.clone().into())?,
+            MessagePayload::SessionCiphertext(session.clone())
+        );
+        for control in [
+            ServerControl {
+                heartbeat: Some(true),
+                pre_key_status: None,
+            },
+            ServerControl {
+                heartbeat: None,
+                pre_key_status: Some(service::PreKeyStatus { remaining: Some(3) }),
+            },
+        ] {
+            assert_eq!(
+                round_trip(control.clone().into())?,
+                MessagePayload::ServerControl(control)
+            );
+        }
+
+        // The flat form reads as the payload it describes.
+        assert_eq!(
+            round_trip(message())?,
+            MessagePayload::X3DHInitial(Box::new(initial.clone()))
+        );
+        let heartbeat = MessageProto {
+            heartbeat: Some(true),
+            ..Default::default()
+        };
+        assert_eq!(
+            round_trip(heartbeat)?,
+            MessagePayload::ServerControl(ServerControl {
+                heartbeat: Some(true),
+                pre_key_status: None,
+            })
+        );
+        assert_eq!(message().sender(), Some("alice"));
+        assert_eq!(message().content_type(), None);
+
+        // The content type is carried within the payload, and left unset for text.
+        let mut proto: MessageProto = initial.clone().into();
+        proto.set_content_type(ContentType::KeyConfirmation);
+        let proto = MessageProto::decode(&*proto.encode_to_vec())?;
+        assert_eq!(
+            proto.content_type(),
+            Some(ContentType::KeyConfirmation.into())
+        );
+        let mut proto: MessageProto = session.clone().into();
+        proto.set_content_type(ContentType::DecryptionFailure);
+        assert_eq!(
+            proto.content_type(),
+            Some(ContentType::DecryptionFailure.into())
+        );
+        proto.set_content_type(ContentType::Text);
+        assert_eq!(proto.content_type(), None);
+        assert_eq!(proto.sender_device_id(), None);
+        proto.set_sender_device_id(2);
+        let proto = MessageProto::decode(&*proto.encode_to_vec())?;
+        assert_eq!(proto.sender_device_id(), Some(2));
+
+        // Only errors in a payload are named within it.
+        let mut proto: MessageProto = X3DHMessage::try_from(message())?.into();
+        let Some(PayloadProto::X3dhInitial(initial)) = &mut proto.payload else {
+            unreachable!();
+        };
+        initial.ephemeral_key = None;
+        assert_eq!(
+            reason(MessagePayload::try_from(proto)),
+            (
+                "x3dh_initial.ephemeral_key".to_owned(),
+                ParseErrorReason::Missing
+            )
+        );
+        let proto = MessageProto {
+            payload: Some(PayloadProto::SessionCiphertext(SessionCiphertextProto {
+                sender_identity: Some("alice".to_owned()),
+                ciphertext: Some(vec![0; MAX_CIPHERTEXT_LEN + 1]),
+                content_type: None,
+                sender_device_id: None,
+            })),
+            ..Default::default()
+        };
+        assert_eq!(
+            reason(MessagePayload::try_from(proto)),
+            (
+                "session_ciphertext.ciphertext".to_owned(),
+                ParseErrorReason::TooLong {
+                    max: MAX_CIPHERTEXT_LEN,
+                    actual: MAX_CIPHERTEXT_LEN + 1
+                }
+            )
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn unknown_payload() {
+        // A payload a later release added, as field 21, alongside fields this release knows.
+        let mut encoded = MessageProto {
+            message_uuid: Some(vec![7; 16]),
+            ..Default::default()
+        }
+        .encode_to_vec();
+        encoded.extend_from_slice(&[0xAA, 0x01, 0x02, 0x08, 0x01]);
+        let proto = MessageProto::decode(&*encoded).unwrap();
+        assert_eq!(proto.message_uuid(), [7; 16]);
+        assert_eq!(proto.sender(), None);
+        assert_eq!(
+            MessagePayload::try_from(proto.clone()),
+            Ok(MessagePayload::Unknown)
+        );
+        assert_eq!(
+            reason(X3DHMessage::try_from(proto)),
+            ("x3dh_initial".to_owned(), ParseErrorReason::Missing)
+        );
+        assert_eq!(
+            MessagePayload::try_from(MessageProto::default()),
+            Ok(MessagePayload::Unknown)
+        );
+    }
+
+    #[test]
+    fn parse_pre_key_bundle() {
+        let ik = SigningKey::from_bytes(&[1; 32]);
+        let bundle = || PreKeyBundleProto {
+            identity_key: Some(ik.verifying_key().to_bytes().to_vec()),
+            signed_pre_key: Some(spk()),
+            ..Default::default()
+        };
+        let parsed: Result<PreKeyBundle, _> = bundle().try_into();
+        assert!(parsed.is_ok());
+
+        let mut proto = bundle();
+        proto.signed_pre_key = None;
+        let parsed: Result<PreKeyBundle, _> = proto.try_into();
+        assert_eq!(
+            reason(parsed),
+            ("signed_pre_key".to_owned(), ParseErrorReason::Missing)
+        );
+
+        let mut proto = bundle();
+        proto.signed_pre_key.as_mut().unwrap().signature = Some(vec![0; 63]);
+        let parsed: Result<PreKeyBundle, _> = proto.try_into();
+        assert_eq!(
+            reason(parsed),
+            ("signed_pre_key.signature".to_owned(), length(64, 63))
+        );
+
+        let mut proto = bundle();
+        proto.kem_pre_key = Some(SignedPreKeyProto {
+            pre_key: Some(vec![0; kem::PUBLIC_KEY_LEN + 1]),
+            signature: Some(vec![0; 64]),
+        });
+        let parsed: Result<PreKeyBundle, _> = proto.try_into();
+        assert_eq!(
+            reason(parsed),
+            (
+                "kem_pre_key.pre_key".to_owned(),
+                length(kem::PUBLIC_KEY_LEN, kem::PUBLIC_KEY_LEN + 1)
+            )
+        );
+    }
+
+    #[test]
+    fn parse_error_status() {
+        let status: Status = ParseError::missing("signed_pre_key.pre_key").into();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+        assert_eq!(status.message(), "Invalid signed_pre_key.pre_key: missing");
+    }
+
+    #[test]
+    fn parse_truncated_prefixes() {
+        // Every prefix of every field is rejected cleanly rather than panicking.
+        let full = message();
+        for len in 0..32 {
+            let mut proto = full.clone();
+            proto.sender_identity_key.as_mut().unwrap().truncate(len);
+            assert!(X3DHMessage::try_from(proto).is_err());
+            let mut proto = full.clone();
+            proto.ephemeral_key.as_mut().unwrap().truncate(len);
+            assert!(X3DHMessage::try_from(proto).is_err());
+        }
+        for len in 0..64 {
+            let mut proto = spk();
+            proto.signature.as_mut().unwrap().truncate(len);
+            assert!(SignedPreKey::try_from(proto.clone()).is_err());
+            assert!(SignedKemPreKey::try_from(proto).is_err());
+        }
+    }
+}
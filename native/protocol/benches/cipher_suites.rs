@@ -0,0 +1,80 @@
+//! Compares the AEADs of the cipher suites on large initial messages.
+//! Run with `cargo bench -p protocol`.
+
+use chacha20poly1305::aead::OsRng;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use ed25519_dalek::SigningKey;
+use protocol::bundle::create_prekey_bundle;
+use protocol::x3dh::{initiate_recv, initiate_send, CipherSuite, PreKeyBundle, SignedPreKey};
+
+fn cipher_suites(c: &mut Criterion) {
+    let bob_ik = SigningKey::generate(&mut OsRng);
+    let alice_ik = SigningKey::generate(&mut OsRng);
+    let bob_spk = create_prekey_bundle(&bob_ik, 1);
+    let bob_spk_secret = bob_spk.bundle[0].clone().0;
+    let bundle = |suite: CipherSuite| PreKeyBundle {
+        ik: bob_ik.verifying_key(),
+        opk: None,
+        spk: SignedPreKey {
+            pre_key: bob_spk.bundle[0].1,
+            signature: bob_spk.signature,
+        },
+        spk_id: Some(1),
+        opk_id: None,
+        last_resort: false,
+        kem_pre_key: None,
+        kem_pre_key_id: None,
+        kem_last_resort: false,
+        suite: Some(suite.id()),
+    };
+
+    for size in [64 * 1024, 1024 * 1024] {
+        let plaintext = vec![0x42; size];
+        let mut send = c.benchmark_group(format!("initiate_send/{size}"));
+        send.throughput(Throughput::Bytes(size as u64));
+        for suite in [CipherSuite::V1, CipherSuite::V2] {
+            send.bench_with_input(
+                BenchmarkId::from_parameter(format!("{suite:?}")),
+                &suite,
+                |b, &suite| {
+                    b.iter(|| {
+                        initiate_send(bundle(suite), "alice".to_owned(), &alice_ik, &plaintext)
+                            .unwrap()
+                    })
+                },
+            );
+        }
+        send.finish();
+
+        let mut recv = c.benchmark_group(format!("initiate_recv/{size}"));
+        recv.throughput(Throughput::Bytes(size as u64));
+        for suite in [CipherSuite::V1, CipherSuite::V2] {
+            let (_, message) =
+                initiate_send(bundle(suite), "alice".to_owned(), &alice_ik, &plaintext).unwrap();
+            recv.bench_with_input(
+                BenchmarkId::from_parameter(format!("{suite:?}")),
+                &message,
+                |b, message| {
+                    b.iter(|| {
+                        initiate_recv(
+                            &bob_ik,
+                            &bob_spk_secret,
+                            &message.sender_ik,
+                            message.ek,
+                            None,
+                            None,
+                            message.suite,
+                            message.version,
+                            &message.ciphertext,
+                        )
+                        .unwrap()
+                    })
+                },
+            );
+        }
+        recv.finish();
+    }
+}
+
+criterion_group!(benches, cipher_suites);
+criterion_main!(benches);
@@ -0,0 +1,117 @@
+//! Times the handshake, prekey bundle signatures and AEADs on their own.
+//! Run with `cargo bench -p protocol --bench core`.
+
+use aes_gcm::Aes256Gcm;
+use chacha20poly1305::aead::{OsRng, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, KeyInit};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use ed25519_dalek::SigningKey;
+use protocol::aead::{decrypt_data, encrypt_data, Cipher};
+use protocol::bundle::{create_prekey_bundle, sign_bundle, verify_bundle};
+use protocol::x3dh::{initiate_recv, initiate_send, PreKeyBundle, SignedPreKey};
+use x25519_dalek::PublicKey as X25519PublicKey;
+
+fn handshake(c: &mut Criterion) {
+    let bob_ik = SigningKey::generate(&mut OsRng);
+    let alice_ik = SigningKey::generate(&mut OsRng);
+    let keys = create_prekey_bundle(&bob_ik, 2);
+    let (bob_spk_secret, bob_spk) = keys.bundle[0].clone();
+    let (bob_opk_secret, bob_opk) = keys.bundle[1].clone();
+    let spk_signature = sign_bundle(&bob_ik, &keys.bundle[..1]);
+    let bundle = |opk: Option<X25519PublicKey>| PreKeyBundle {
+        ik: bob_ik.verifying_key(),
+        opk,
+        spk: SignedPreKey {
+            pre_key: bob_spk,
+            signature: spk_signature,
+        },
+        spk_id: Some(1),
+        opk_id: opk.map(|_| 1),
+        last_resort: false,
+        kem_pre_key: None,
+        kem_pre_key_id: None,
+        kem_last_resort: false,
+        suite: None,
+    };
+
+    let mut group = c.benchmark_group("handshake");
+    for (name, opk) in [("spk", None), ("spk+opk", Some(bob_opk))] {
+        group.bench_function(BenchmarkId::new("initiate_send", name), |b| {
+            b.iter(|| initiate_send(bundle(opk), "alice".to_owned(), &alice_ik, b"Hello").unwrap())
+        });
+        let (_, message) =
+            initiate_send(bundle(opk), "alice".to_owned(), &alice_ik, b"Hello").unwrap();
+        group.bench_function(BenchmarkId::new("initiate_recv", name), |b| {
+            b.iter(|| {
+                initiate_recv(
+                    &bob_ik,
+                    &bob_spk_secret,
+                    &message.sender_ik,
+                    message.ek,
+                    opk.map(|_| bob_opk_secret.clone()),
+                    None,
+                    message.suite,
+                    message.version,
+                    &message.ciphertext,
+                )
+                .unwrap()
+            })
+        });
+    }
+    group.finish();
+}
+
+fn bundles(c: &mut Criterion) {
+    let ik = SigningKey::generate(&mut OsRng);
+    let mut group = c.benchmark_group("bundle");
+    for num_keys in [1, 100, 1000] {
+        let keys = create_prekey_bundle(&ik, num_keys);
+        let public_keys: Vec<X25519PublicKey> = keys.bundle.iter().map(|(_, key)| *key).collect();
+        group.throughput(Throughput::Elements(num_keys.into()));
+        group.bench_with_input(BenchmarkId::new("sign", num_keys), &keys, |b, keys| {
+            b.iter(|| sign_bundle(&ik, &keys.bundle))
+        });
+        group.bench_with_input(
+            BenchmarkId::new("verify", num_keys),
+            &public_keys,
+            |b, public_keys| {
+                b.iter(|| verify_bundle(&ik.verifying_key(), public_keys, &keys.signature).unwrap())
+            },
+        );
+    }
+    group.finish();
+}
+
+fn aead_group<C: Cipher + KeyInit>(c: &mut Criterion, name: &str) {
+    let cipher = C::new(&C::generate_key(&mut OsRng));
+    let mut group = c.benchmark_group(format!("aead/{name}"));
+    for size in [1024, 64 * 1024, 1024 * 1024] {
+        let plaintext = vec![0x42; size];
+        let ciphertext = encrypt_data(
+            Payload {
+                msg: &plaintext,
+                aad: b"aad",
+            },
+            &cipher,
+        )
+        .unwrap();
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(BenchmarkId::new("encrypt", size), &plaintext, |b, msg| {
+            b.iter(|| encrypt_data(Payload { msg, aad: b"aad" }, &cipher).unwrap())
+        });
+        group.bench_with_input(
+            BenchmarkId::new("decrypt", size),
+            &ciphertext,
+            |b, ciphertext| b.iter(|| decrypt_data(ciphertext, b"aad", &cipher).unwrap()),
+        );
+    }
+    group.finish();
+}
+
+fn aead(c: &mut Criterion) {
+    aead_group::<ChaCha20Poly1305>(c, "chacha20poly1305");
+    aead_group::<Aes256Gcm>(c, "aes256gcm");
+}
+
+criterion_group!(benches, handshake, bundles, aead);
+criterion_main!(benches);
@@ -1,42 +1,98 @@
+use aes_gcm::Aes256Gcm;
+#[cfg(feature = "getrandom")]
+use chacha20poly1305::aead::OsRng;
 use chacha20poly1305::{
-    aead::{Aead, AeadCore, OsRng, Payload},
-    ChaCha20Poly1305, Nonce,
+    aead::{
+        rand_core::{CryptoRng, RngCore},
+        Aead, AeadCore, Nonce, Payload,
+    },
+    consts::U12,
+    ChaCha20Poly1305,
 };
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
+/*
+    Ciphertext = TAG || NONCE || AEAD(KEY, NONCE, MSG, AAD)
+    TAG - One byte naming the AEAD, see `Cipher`.
+    NONCE - A 96-bit nonce drawn from the OS RNG for every encryption. Reusing a nonce under the
+    same key reveals the XOR of the plaintexts and, for both AEADs, lets an attacker forge
+    messages, so callers never supply one. Random nonces stay safe for far more messages per key
+    than any caller encrypts: every key so far seals a single message.
+*/
+
 const NONCE_LEN: usize = 12;
-const VERSION_TAG: u8 = 1;
+/// Length of the authenticator both AEADs append to the encrypted message.
+const AUTH_TAG_LEN: usize = 16;
+/// Length of the shortest well-formed ciphertext, one sealing an empty message. Every ciphertext
+/// is this much longer than the message it seals.
+pub const MIN_CIPHERTEXT_LEN: usize = 1 + NONCE_LEN + AUTH_TAG_LEN;
+
+/// An AEAD that `encrypt_data` and `decrypt_data` can use. Its tag leads every ciphertext it
+/// produces, so a ciphertext is never handed to the wrong AEAD.
+pub trait Cipher: Aead + AeadCore<NonceSize = U12> {
+    const TAG: u8;
+}
+
+impl Cipher for ChaCha20Poly1305 {
+    const TAG: u8 = 1;
+}
+
+impl Cipher for Aes256Gcm {
+    const TAG: u8 = 2;
+}
 
-#[derive(Error, Debug, Serialize, Deserialize)]
+#[derive(Error, Debug, PartialEq, Serialize, Deserialize)]
 pub enum AeadError {
     #[error("Encryption Failed.")]
     Encrypt,
     #[error("Unexpected tag: `{0}`")]
     Tag(u8),
+    #[error("Ciphertext is truncated.")]
+    Truncated,
 }
 
-pub fn encrypt_data(payload: Payload, cipher: &ChaCha20Poly1305) -> Result<Vec<u8>, AeadError> {
-    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+/// Encrypts `payload` under a fresh random nonce, which is prepended to the result.
+#[cfg(feature = "getrandom")]
+pub fn encrypt_data<C: Cipher>(payload: Payload, cipher: &C) -> Result<Vec<u8>, AeadError> {
+    encrypt_data_with_rng(payload, cipher, &mut OsRng)
+}
+
+/// Like `encrypt_data`, but draws the nonce from `rng`.
+pub fn encrypt_data_with_rng<C: Cipher, R: RngCore + CryptoRng>(
+    payload: Payload,
+    cipher: &C,
+    rng: &mut R,
+) -> Result<Vec<u8>, AeadError> {
+    let nonce = C::generate_nonce(rng);
     let ciphertext = cipher
         .encrypt(&nonce, payload)
         .map_err(|_| AeadError::Encrypt)?;
 
-    return Ok([vec![VERSION_TAG], nonce.to_vec(), ciphertext].concat());
+    Ok([vec![C::TAG], nonce.to_vec(), ciphertext].concat())
 }
 
-pub fn decrypt_data(
+/// Decrypts a ciphertext produced by `encrypt_data` with the same key and `aad`.
+pub fn decrypt_data<C: Cipher>(
     ciphertext: &[u8],
     aad: &[u8],
-    cipher: &ChaCha20Poly1305,
+    cipher: &C,
 ) -> Result<Vec<u8>, AeadError> {
-    if ciphertext[0] != VERSION_TAG {
-        return Err(AeadError::Tag(ciphertext[0]));
+    match ciphertext.first() {
+        None => return Err(AeadError::Truncated),
+        Some(&tag) if tag != C::TAG => return Err(AeadError::Tag(tag)),
+        Some(_) => {}
+    }
+    if ciphertext.len() < MIN_CIPHERTEXT_LEN {
+        return Err(AeadError::Truncated);
     }
     let nonce_bytes = &ciphertext[1..(NONCE_LEN + 1)];
     let msg = &ciphertext[(NONCE_LEN + 1)..];
     cipher
-        .decrypt(Nonce::from_slice(&nonce_bytes), Payload { msg: &msg, aad })
+        .decrypt(
+            Nonce::<C>::from_slice(nonce_bytes),
+            Payload { msg, aad },
+        )
         .map_err(|_| AeadError::Encrypt)
 }
 
@@ -44,7 +100,98 @@ pub fn decrypt_data(
 mod tests {
     use crate::aead::*;
     use anyhow::{Context, Result};
+    use chacha20poly1305::aead::OsRng;
     use chacha20poly1305::KeyInit;
+    use std::collections::HashSet;
+
+    fn round_trip<C: Cipher + KeyInit>() -> Result<()> {
+        let cipher = C::new(&C::generate_key(&mut OsRng));
+        let text = "Hello I am a string.";
+        let ciphertext = encrypt_data(
+            Payload {
+                msg: text.as_bytes(),
+                aad: b"aad",
+            },
+            &cipher,
+        )
+        .unwrap();
+        assert_eq!(ciphertext[0], C::TAG);
+        let decrypted_data =
+            decrypt_data(&ciphertext, b"aad", &cipher).context("decryption failed.")?;
+        assert_eq!(text, String::from_utf8(decrypted_data)?);
+        assert!(decrypt_data(&ciphertext, b"other aad", &cipher).is_err());
+        Ok(())
+    }
+
+    fn seal<C: Cipher>(cipher: &C, msg: &[u8], aad: &[u8]) -> Vec<u8> {
+        encrypt_data(Payload { msg, aad }, cipher).unwrap()
+    }
+
+    fn truncated<C: Cipher + KeyInit>() {
+        let cipher = C::new(&C::generate_key(&mut OsRng));
+        let ciphertext = seal(&cipher, b"", b"aad");
+        assert_eq!(ciphertext.len(), MIN_CIPHERTEXT_LEN);
+        assert!(decrypt_data(&ciphertext, b"aad", &cipher).is_ok());
+        for len in 0..MIN_CIPHERTEXT_LEN {
+            assert!(
+                matches!(
+                    decrypt_data(&ciphertext[..len], b"aad", &cipher),
+                    Err(AeadError::Truncated)
+                ),
+                "{len}"
+            );
+        }
+        // Longer inputs are truncated too when they've lost part of the message.
+        let ciphertext = seal(&cipher, b"Hello", b"aad");
+        assert!(matches!(
+            decrypt_data(&ciphertext[..ciphertext.len() - 1], b"aad", &cipher),
+            Err(AeadError::Encrypt)
+        ));
+    }
+
+    fn bit_flips<C: Cipher + KeyInit>() {
+        let cipher = C::new(&C::generate_key(&mut OsRng));
+        let ciphertext = seal(&cipher, b"Hello", b"aad");
+        // Every bit of the nonce, encrypted message and authenticator is covered.
+        for i in 1..ciphertext.len() {
+            for bit in 0..8 {
+                let mut flipped = ciphertext.clone();
+                flipped[i] ^= 1 << bit;
+                assert!(
+                    matches!(
+                        decrypt_data(&flipped, b"aad", &cipher),
+                        Err(AeadError::Encrypt)
+                    ),
+                    "byte {i} bit {bit}"
+                );
+            }
+        }
+        for i in 0..3 {
+            let mut aad = *b"aad";
+            aad[i] ^= 1;
+            assert!(matches!(
+                decrypt_data(&ciphertext, &aad, &cipher),
+                Err(AeadError::Encrypt)
+            ));
+        }
+    }
+
+    fn fresh_nonces<C: Cipher + KeyInit>() {
+        let cipher = C::new(&C::generate_key(&mut OsRng));
+        let ciphertexts: Vec<Vec<u8>> = (0..1000).map(|_| seal(&cipher, b"Hello", b"")).collect();
+        let nonces: HashSet<&[u8]> = ciphertexts.iter().map(|c| &c[1..NONCE_LEN + 1]).collect();
+        assert_eq!(nonces.len(), ciphertexts.len());
+        let unique: HashSet<&Vec<u8>> = ciphertexts.iter().collect();
+        assert_eq!(unique.len(), ciphertexts.len());
+        // Each nonce bit should be set about half the time; a fixed or counting nonce is not.
+        for bit in 0..NONCE_LEN * 8 {
+            let set = nonces
+                .iter()
+                .filter(|nonce| nonce[bit / 8] & (1 << (bit % 8)) != 0)
+                .count();
+            assert!((350..=650).contains(&set), "bit {bit} set {set} times");
+        }
+    }
 
     #[test]
     fn aead() -> Result<()> {
@@ -64,4 +211,60 @@ mod tests {
         assert_eq!(text, String::from_utf8(decrypted_data)?);
         Ok(())
     }
+
+    #[test]
+    fn aead_round_trip() -> Result<()> {
+        round_trip::<ChaCha20Poly1305>()?;
+        round_trip::<Aes256Gcm>()
+    }
+
+    #[test]
+    fn aead_cross_cipher() -> Result<()> {
+        // Both AEADs take 32 byte keys, so the same key material works for either.
+        let key = ChaCha20Poly1305::generate_key(&mut OsRng);
+        let chacha = ChaCha20Poly1305::new(&key);
+        let aes = Aes256Gcm::new(&key);
+        let payload = || Payload {
+            msg: b"Hello",
+            aad: &[],
+        };
+
+        let ciphertext = encrypt_data(payload(), &chacha).unwrap();
+        assert!(matches!(
+            decrypt_data(&ciphertext, &[], &aes),
+            Err(AeadError::Tag(1))
+        ));
+        // Even with the tag rewritten, the other AEAD can't authenticate it.
+        let mut retagged = ciphertext.clone();
+        retagged[0] = Aes256Gcm::TAG;
+        assert!(matches!(
+            decrypt_data(&retagged, &[], &aes),
+            Err(AeadError::Encrypt)
+        ));
+
+        let ciphertext = encrypt_data(payload(), &aes).unwrap();
+        assert!(matches!(
+            decrypt_data(&ciphertext, &[], &chacha),
+            Err(AeadError::Tag(2))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn aead_truncated() {
+        truncated::<ChaCha20Poly1305>();
+        truncated::<Aes256Gcm>();
+    }
+
+    #[test]
+    fn aead_bit_flips() {
+        bit_flips::<ChaCha20Poly1305>();
+        bit_flips::<Aes256Gcm>();
+    }
+
+    #[test]
+    fn aead_fresh_nonces() {
+        fresh_nonces::<ChaCha20Poly1305>();
+        fresh_nonces::<Aes256Gcm>();
+    }
 }
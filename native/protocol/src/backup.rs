@@ -0,0 +1,170 @@
+use crate::aead::{decrypt_data, encrypt_data_with_rng};
+use crate::kdf::passphrase::{self, Header, KdfError};
+use chacha20poly1305::aead::rand_core::{CryptoRng, RngCore};
+#[cfg(feature = "getrandom")]
+use chacha20poly1305::aead::OsRng;
+use chacha20poly1305::{
+    aead::{KeyInit, Payload},
+    ChaCha20Poly1305,
+};
+use thiserror::Error;
+
+pub use crate::kdf::passphrase::KdfParams;
+
+/*
+    Passphrase-encrypted backup container.
+    Header = MAGIC || passphrase::Header
+    Container = Header || AEAD(passphrase key, plaintext, Header)
+    The backup format is versioned by the passphrase header's version.
+*/
+
+const MAGIC: &[u8; 4] = b"BNBK";
+const FORMAT_VERSION: u8 = passphrase::VERSION;
+const HEADER_LEN: usize = MAGIC.len() + passphrase::HEADER_LEN;
+
+#[derive(Error, Debug, PartialEq)]
+pub enum BackupError {
+    #[error("Backup is malformed.")]
+    Malformed,
+    #[error("Unsupported backup version: `{0}`")]
+    UnsupportedVersion(u8),
+    #[error("Wrong passphrase.")]
+    WrongPassphrase,
+    #[error("Invalid key derivation parameters.")]
+    InvalidParams,
+    #[error("Encryption Failed.")]
+    Encrypt,
+    #[error("Backup is for a different identity than the one already on this device.")]
+    DifferentIdentity,
+}
+
+impl From<KdfError> for BackupError {
+    fn from(e: KdfError) -> Self {
+        match e {
+            KdfError::Malformed => BackupError::Malformed,
+            KdfError::UnsupportedVersion(version) => BackupError::UnsupportedVersion(version),
+            KdfError::InvalidParams => BackupError::InvalidParams,
+            KdfError::WrongPassphrase => BackupError::WrongPassphrase,
+        }
+    }
+}
+
+fn derive_key(passphrase: &str, header: &Header) -> Result<ChaCha20Poly1305, BackupError> {
+    let key = header.derive_key(passphrase)?;
+    Ok(ChaCha20Poly1305::new_from_slice(key.as_slice()).unwrap())
+}
+
+/// Encrypts `plaintext` with a key derived from `passphrase`.
+#[cfg(feature = "getrandom")]
+pub fn seal_backup(
+    plaintext: &[u8],
+    passphrase: &str,
+    params: KdfParams,
+) -> Result<Vec<u8>, BackupError> {
+    seal_backup_with_rng(plaintext, passphrase, params, &mut OsRng)
+}
+
+/// Like `seal_backup`, but draws the salt and nonce from `rng`.
+pub fn seal_backup_with_rng<R: RngCore + CryptoRng>(
+    plaintext: &[u8],
+    passphrase: &str,
+    params: KdfParams,
+    rng: &mut R,
+) -> Result<Vec<u8>, BackupError> {
+    let kdf_header = Header::new_with_rng(params, rng);
+    let header = [MAGIC.as_slice(), &kdf_header.encode()].concat();
+    let cipher = derive_key(passphrase, &kdf_header)?;
+    let ciphertext = encrypt_data_with_rng(
+        Payload {
+            msg: plaintext,
+            aad: &header,
+        },
+        &cipher,
+        rng,
+    )
+    .map_err(|_| BackupError::Encrypt)?;
+    Ok([header, ciphertext].concat())
+}
+
+/// Decrypts a backup made by `seal_backup`.
+pub fn open_backup(backup: &[u8], passphrase: &str) -> Result<Vec<u8>, BackupError> {
+    // The header followed by at least the aead version tag and nonce.
+    if backup.len() <= HEADER_LEN + 13 || !backup.starts_with(MAGIC) {
+        return Err(BackupError::Malformed);
+    }
+    let (kdf_header, ciphertext) = Header::decode(&backup[MAGIC.len()..])?;
+    let cipher = derive_key(passphrase, &kdf_header)?;
+    // The header is authenticated, so a wrong passphrase and a tampered backup look the same.
+    decrypt_data(ciphertext, &backup[..HEADER_LEN], &cipher)
+        .map_err(|_| BackupError::WrongPassphrase)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backup::*;
+    use anyhow::Result;
+
+    /// Cheap costs so the tests stay fast.
+    const TEST_PARAMS: KdfParams = KdfParams {
+        m_cost: 8,
+        t_cost: 1,
+        p_cost: 1,
+    };
+
+    #[test]
+    fn round_trip() -> Result<()> {
+        let backup = seal_backup(b"Hello I am a backup.", "hunter2", TEST_PARAMS)?;
+        assert_eq!(open_backup(&backup, "hunter2")?, b"Hello I am a backup.");
+        assert_eq!(
+            open_backup(&backup, "hunter3"),
+            Err(BackupError::WrongPassphrase)
+        );
+        assert_eq!(
+            open_backup(&backup[..HEADER_LEN], "hunter2"),
+            Err(BackupError::Malformed)
+        );
+
+        let mut newer = backup.clone();
+        newer[MAGIC.len()] = FORMAT_VERSION + 1;
+        assert_eq!(
+            open_backup(&newer, "hunter2"),
+            Err(BackupError::UnsupportedVersion(FORMAT_VERSION + 1))
+        );
+        let mut expensive = backup;
+        expensive[MAGIC.len() + 1..MAGIC.len() + 5].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert_eq!(
+            open_backup(&expensive, "hunter2"),
+            Err(BackupError::InvalidParams)
+        );
+        Ok(())
+    }
+
+    /// A version 1 backup of "brongnal" under the passphrase "passphrase". Changing how version 1
+    /// is read must keep this opening.
+    const V1_VECTOR: &[u8] = &[
+        // Magic and version.
+        0x42, 0x4e, 0x42, 0x4b, 0x01, //
+        // m_cost = 8, t_cost = 1, p_cost = 1.
+        0x00, 0x00, 0x00, 0x08, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x01, //
+        // Salt.
+        0xf6, 0xda, 0x96, 0x2e, 0x1b, 0xa6, 0x7a, 0x52, //
+        0x55, 0x95, 0x59, 0x63, 0xc5, 0x1e, 0xeb, 0x75, //
+        // Aead version tag and nonce.
+        0x01, 0x39, 0x28, 0xd5, 0x22, 0x0d, 0xc0, //
+        0xd4, 0x3b, 0x58, 0xa0, 0xe2, 0xb2, //
+        // Ciphertext and Poly1305 tag.
+        0xe9, 0xf0, 0x96, 0xbc, 0xa9, 0xc4, 0xf6, 0x66, //
+        0x2e, 0x7a, 0x11, 0x5e, 0xa8, 0x1d, 0x67, 0x19, //
+        0x7e, 0x30, 0x2b, 0x7d, 0x41, 0x4f, 0xb2, 0x7a, //
+    ];
+
+    #[test]
+    fn v1_test_vector() -> Result<()> {
+        assert_eq!(open_backup(V1_VECTOR, "passphrase")?, b"brongnal");
+        assert_eq!(
+            open_backup(V1_VECTOR, "Passphrase"),
+            Err(BackupError::WrongPassphrase)
+        );
+        Ok(())
+    }
+}
@@ -1,30 +1,65 @@
+use crate::xeddsa::{self, to_x25519, to_x25519_pub};
 use blake2::{Blake2b512, Digest};
-use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use chacha20poly1305::aead::rand_core::{CryptoRng, RngCore};
+#[cfg(feature = "getrandom")]
+use chacha20poly1305::aead::OsRng;
+use ed25519_dalek::{Signature, SignatureError, SigningKey, VerifyingKey};
 use x25519_dalek::{PublicKey as X25519PublicKey, StaticSecret as X25519StaticSecret};
 
+/// Whether `verify_bundle` still accepts the Ed25519 signatures prekeys carried before they were
+/// signed with XEdDSA. Keys uploaded before then keep their signatures until they're used up or
+/// rotated, so this can only be turned off once every client has replaced them.
+pub const ED25519_COMPAT: bool = true;
+
+fn bundle_digest(public_keys: impl ExactSizeIterator<Item = X25519PublicKey>) -> Vec<u8> {
+    let mut hasher = Blake2b512::new();
+    hasher.update(public_keys.len().to_be_bytes());
+    for public_key in public_keys {
+        hasher.update(public_key.as_bytes());
+    }
+    hasher.finalize().to_vec()
+}
+
+/// Signs the public halves of `key_pairs` with XEdDSA under the X25519 form of `signing_key`.
+#[cfg(feature = "getrandom")]
 pub fn sign_bundle(
     signing_key: &SigningKey,
     key_pairs: &[(X25519StaticSecret, X25519PublicKey)],
 ) -> Signature {
-    let mut hasher = Blake2b512::new();
-    hasher.update(key_pairs.len().to_be_bytes());
-    for key_pair in key_pairs {
-        hasher.update(key_pair.1.as_bytes());
-    }
-    signing_key.sign(&hasher.finalize())
+    sign_bundle_with_rng(signing_key, key_pairs, &mut OsRng)
+}
+
+/// Like `sign_bundle`, but draws the signature's randomness from `rng`.
+pub fn sign_bundle_with_rng<R: RngCore + CryptoRng>(
+    signing_key: &SigningKey,
+    key_pairs: &[(X25519StaticSecret, X25519PublicKey)],
+    rng: &mut R,
+) -> Signature {
+    let digest = bundle_digest(key_pairs.iter().map(|(_, public_key)| *public_key));
+    xeddsa::sign_with_rng(&to_x25519(signing_key), &digest, rng)
 }
 
 pub fn verify_bundle(
     verifying_key: &VerifyingKey,
     public_keys: &[X25519PublicKey],
     signature: &Signature,
-) -> Result<(), ed25519_dalek::ed25519::Error> {
-    let mut hasher = Blake2b512::new();
-    hasher.update(public_keys.len().to_be_bytes());
-    for public_key in public_keys {
-        hasher.update(public_key.as_bytes());
+) -> Result<(), SignatureError> {
+    verify_bundle_with(verifying_key, public_keys, signature, ED25519_COMPAT)
+}
+
+/// Like `verify_bundle`, but `ed25519_compat` says whether to fall back to checking `signature`
+/// as an Ed25519 signature by `verifying_key`.
+pub fn verify_bundle_with(
+    verifying_key: &VerifyingKey,
+    public_keys: &[X25519PublicKey],
+    signature: &Signature,
+    ed25519_compat: bool,
+) -> Result<(), SignatureError> {
+    let digest = bundle_digest(public_keys.iter().copied());
+    match xeddsa::verify(&to_x25519_pub(verifying_key), &digest, signature) {
+        Err(_) if ed25519_compat => verifying_key.verify_strict(&digest, signature),
+        result => result,
     }
-    verifying_key.verify_strict(&hasher.finalize(), signature)
 }
 
 pub struct X3DHPreKeyBundle {
@@ -32,15 +67,25 @@ pub struct X3DHPreKeyBundle {
     pub signature: Signature,
 }
 
+#[cfg(feature = "getrandom")]
 pub fn create_prekey_bundle(signing_key: &SigningKey, num_keys: u32) -> X3DHPreKeyBundle {
+    create_prekey_bundle_with_rng(signing_key, num_keys, &mut OsRng)
+}
+
+/// Like `create_prekey_bundle`, but draws the keys and signature from `rng`.
+pub fn create_prekey_bundle_with_rng<R: RngCore + CryptoRng>(
+    signing_key: &SigningKey,
+    num_keys: u32,
+    rng: &mut R,
+) -> X3DHPreKeyBundle {
     let bundle: Vec<_> = (0..num_keys)
         .map(|_| {
-            let pkey = X25519StaticSecret::random();
+            let pkey = X25519StaticSecret::random_from_rng(&mut *rng);
             let pubkey = X25519PublicKey::from(&pkey);
             (pkey, pubkey)
         })
         .collect();
-    let signature = sign_bundle(signing_key, &bundle);
+    let signature = sign_bundle_with_rng(signing_key, &bundle, rng);
     X3DHPreKeyBundle { signature, bundle }
 }
 
@@ -79,4 +124,44 @@ mod tests {
         }
         Ok(())
     }
+
+    #[test]
+    fn verify_bundle_ed25519_compat() -> Result<()> {
+        use ed25519_dalek::Signer;
+
+        // A key whose Edwards point has sign bit 0 is its own XEdDSA key, so its Ed25519
+        // signatures are valid XEdDSA signatures too. Only the other half tell the modes apart.
+        let key = std::iter::repeat_with(|| SigningKey::generate(&mut OsRng))
+            .find(|key| key.verifying_key().as_bytes()[31] & 0x80 != 0)
+            .unwrap();
+        let signed_bundle = create_prekey_bundle(&key, 2);
+        let bundle_keys: Vec<X25519PublicKey> =
+            signed_bundle.bundle.iter().map(|pair| pair.1).collect();
+        let xeddsa_signature = signed_bundle.signature;
+        // How bundles were signed before XEdDSA.
+        let ed25519_signature = key.sign(&bundle_digest(bundle_keys.iter().copied()));
+
+        let verify = |signature, ed25519_compat| {
+            verify_bundle_with(
+                &key.verifying_key(),
+                &bundle_keys,
+                signature,
+                ed25519_compat,
+            )
+        };
+        verify(&xeddsa_signature, false)?;
+        verify(&xeddsa_signature, true)?;
+        verify(&ed25519_signature, true)?;
+        assert!(verify(&ed25519_signature, false).is_err());
+
+        let other_key = SigningKey::generate(&mut OsRng);
+        assert!(verify_bundle_with(
+            &other_key.verifying_key(),
+            &bundle_keys,
+            &ed25519_signature,
+            true,
+        )
+        .is_err());
+        Ok(())
+    }
 }
@@ -0,0 +1,166 @@
+//! Key confirmation after X3DH, so the initiator learns the responder derived the same secret key
+//! without waiting for a reply to arrive on its own.
+
+use ed25519_dalek::VerifyingKey;
+use hkdf::hmac::{Hmac, Mac};
+use hkdf::Hkdf;
+use sha2::Sha256;
+use subtle::ConstantTimeEq;
+use thiserror::Error;
+use x25519_dalek::PublicKey as X25519PublicKey;
+use zeroize::Zeroizing;
+
+/*
+    Transcript = Encode(IK_initiator) || Encode(IK_responder) || Encode(EK) || OPK_ID
+    MAC = HMAC-SHA256(HKDF(SK, "Brongnal-KeyConfirmation"), Transcript)
+    OPK_ID is a byte saying whether a one time prekey was used, followed by its id as a big
+    endian u32. The responder sends the MAC back once it has processed the initial message; only
+    a party holding SK can compute it, and the transcript binds it to that key agreement.
+*/
+
+const CONFIRMATION_INFO: &[u8] = b"Brongnal-KeyConfirmation";
+pub const MAC_LEN: usize = 32;
+
+#[derive(Error, Debug, PartialEq)]
+pub enum ConfirmationError {
+    #[error("Key confirmation doesn't match the key agreement.")]
+    Mismatch,
+}
+
+/// What both sides of an X3DH key agreement saw of it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Transcript {
+    pub initiator_ik: VerifyingKey,
+    pub responder_ik: VerifyingKey,
+    pub ek: X25519PublicKey,
+    /// The id of the responder's one time prekey, if the initiator used one the server
+    /// identified.
+    pub opk_id: Option<u32>,
+}
+
+impl Transcript {
+    fn encode(&self) -> Vec<u8> {
+        let mut transcript = Vec::with_capacity(3 * 32 + 5);
+        transcript.extend_from_slice(self.initiator_ik.as_bytes());
+        transcript.extend_from_slice(self.responder_ik.as_bytes());
+        transcript.extend_from_slice(self.ek.as_bytes());
+        match self.opk_id {
+            Some(id) => {
+                transcript.push(1);
+                transcript.extend_from_slice(&id.to_be_bytes());
+            }
+            None => transcript.push(0),
+        }
+        transcript
+    }
+
+    /// The MAC proving knowledge of `sk`, the secret key this key agreement produced.
+    pub fn confirmation(&self, sk: &[u8; 32]) -> [u8; MAC_LEN] {
+        let hk = Hkdf::<Sha256>::new(None, sk);
+        let mut key = Zeroizing::new([0u8; 32]);
+        hk.expand(CONFIRMATION_INFO, key.as_mut_slice()).unwrap();
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key.as_slice()).unwrap();
+        mac.update(&self.encode());
+        mac.finalize().into_bytes().into()
+    }
+}
+
+/// Checks a `mac` the responder sent against the one `Transcript::confirmation` expected, in
+/// constant time.
+pub fn verify(expected: &[u8; MAC_LEN], mac: &[u8]) -> Result<(), ConfirmationError> {
+    if bool::from(expected.as_slice().ct_eq(mac)) {
+        Ok(())
+    } else {
+        Err(ConfirmationError::Mismatch)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bundle::create_prekey_bundle;
+    use crate::confirmation::*;
+    use crate::x3dh::{initiate_recv, initiate_send, PreKeyBundle, SignedPreKey};
+    use anyhow::Result;
+    use chacha20poly1305::aead::OsRng;
+    use ed25519_dalek::SigningKey;
+    use x25519_dalek::StaticSecret as X25519StaticSecret;
+
+    #[test]
+    fn confirm_key_agreement() -> Result<()> {
+        let alice_ik = SigningKey::generate(&mut OsRng);
+        let bob_ik = SigningKey::generate(&mut OsRng);
+        let bob_spk = create_prekey_bundle(&bob_ik, 1);
+        let bob_spk_secret = bob_spk.bundle[0].clone().0;
+        let bob_opk = X25519StaticSecret::random_from_rng(OsRng);
+        let bundle = PreKeyBundle {
+            ik: bob_ik.verifying_key(),
+            opk: Some(X25519PublicKey::from(&bob_opk)),
+            spk: SignedPreKey {
+                pre_key: bob_spk.bundle[0].1,
+                signature: bob_spk.signature,
+            },
+            spk_id: None,
+            opk_id: Some(7),
+            last_resort: false,
+            kem_pre_key: None,
+            kem_pre_key_id: None,
+            kem_last_resort: false,
+            suite: None,
+        };
+        let (alice_sk, message) = initiate_send(bundle, String::from("alice"), &alice_ik, b"Hi")?;
+        let (bob_sk, _) = initiate_recv(
+            &bob_ik,
+            &bob_spk_secret,
+            &message.sender_ik,
+            message.ek,
+            Some(bob_opk),
+            None,
+            message.suite,
+            message.version,
+            &message.ciphertext,
+        )?;
+        let transcript = Transcript {
+            initiator_ik: alice_ik.verifying_key(),
+            responder_ik: bob_ik.verifying_key(),
+            ek: message.ek,
+            opk_id: message.opk_id,
+        };
+        let expected = transcript.confirmation(&alice_sk);
+        assert_eq!(verify(&expected, &transcript.confirmation(&bob_sk)), Ok(()));
+
+        // Neither another key nor another transcript confirms it.
+        assert_eq!(
+            verify(&expected, &transcript.confirmation(&[0; 32])),
+            Err(ConfirmationError::Mismatch)
+        );
+        let other_opk = Transcript {
+            opk_id: Some(8),
+            ..transcript.clone()
+        };
+        assert_eq!(
+            verify(&expected, &other_opk.confirmation(&bob_sk)),
+            Err(ConfirmationError::Mismatch)
+        );
+        let swapped = Transcript {
+            initiator_ik: transcript.responder_ik,
+            responder_ik: transcript.initiator_ik,
+            ..transcript.clone()
+        };
+        assert_eq!(
+            verify(&expected, &swapped.confirmation(&bob_sk)),
+            Err(ConfirmationError::Mismatch)
+        );
+
+        let mut tampered = transcript.confirmation(&bob_sk);
+        tampered[0] ^= 1;
+        assert_eq!(
+            verify(&expected, &tampered),
+            Err(ConfirmationError::Mismatch)
+        );
+        assert_eq!(
+            verify(&expected, &expected[..MAC_LEN - 1]),
+            Err(ConfirmationError::Mismatch)
+        );
+        Ok(())
+    }
+}
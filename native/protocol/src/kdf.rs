@@ -0,0 +1,3 @@
+//! Key derivation shared by everything that turns a secret into keys.
+
+pub mod passphrase;
@@ -0,0 +1,270 @@
+//! Derives keys from passphrases with Argon2id, for backups and anything else a user protects
+//! with one.
+
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::aead::rand_core::{CryptoRng, RngCore};
+#[cfg(feature = "getrandom")]
+use chacha20poly1305::aead::OsRng;
+use subtle::ConstantTimeEq;
+use thiserror::Error;
+use zeroize::Zeroizing;
+
+/*
+    Header = VERSION || M_COST || T_COST || P_COST || SALT
+    Key = Argon2id(passphrase, SALT, M_COST, T_COST, P_COST)
+    The header travels with whatever the key protects so the key can be derived again. The costs
+    are big endian u32s. Storing them in the header lets later versions raise them without
+    breaking old headers.
+*/
+
+/// The header version written: Argon2id v0x13 with the costs and salt above.
+pub const VERSION: u8 = 1;
+pub const SALT_LEN: usize = 16;
+pub const HEADER_LEN: usize = 1 + 3 * 4 + SALT_LEN;
+pub const KEY_LEN: usize = 32;
+/// Caps on the costs a header may ask for, so a crafted one can't exhaust memory or time.
+const MAX_M_COST: u32 = 1 << 20;
+const MAX_T_COST: u32 = 16;
+const MAX_P_COST: u32 = 8;
+
+#[derive(Error, Debug, PartialEq)]
+pub enum KdfError {
+    #[error("Header is malformed.")]
+    Malformed,
+    #[error("Unsupported header version: `{0}`")]
+    UnsupportedVersion(u8),
+    #[error("Invalid key derivation parameters.")]
+    InvalidParams,
+    #[error("Wrong passphrase.")]
+    WrongPassphrase,
+}
+
+/// Argon2id costs: memory in KiB, iterations, and parallelism.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct KdfParams {
+    pub m_cost: u32,
+    pub t_cost: u32,
+    pub p_cost: u32,
+}
+
+impl Default for KdfParams {
+    fn default() -> Self {
+        KdfParams {
+            m_cost: Params::DEFAULT_M_COST,
+            t_cost: Params::DEFAULT_T_COST,
+            p_cost: Params::DEFAULT_P_COST,
+        }
+    }
+}
+
+/// What it takes to derive a key from a passphrase again: the costs and salt it was derived with.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Header {
+    params: KdfParams,
+    salt: [u8; SALT_LEN],
+}
+
+impl Header {
+    /// A header for deriving keys with `params` under a fresh random salt.
+    #[cfg(feature = "getrandom")]
+    pub fn new(params: KdfParams) -> Self {
+        Header::new_with_rng(params, &mut OsRng)
+    }
+
+    /// Like `new`, but draws the salt from `rng`.
+    pub fn new_with_rng<R: RngCore + CryptoRng>(params: KdfParams, rng: &mut R) -> Self {
+        let mut salt = [0u8; SALT_LEN];
+        rng.fill_bytes(&mut salt);
+        Header { params, salt }
+    }
+
+    pub fn params(&self) -> KdfParams {
+        self.params
+    }
+
+    pub fn encode(&self) -> [u8; HEADER_LEN] {
+        let mut header = [0u8; HEADER_LEN];
+        header[0] = VERSION;
+        header[1..5].copy_from_slice(&self.params.m_cost.to_be_bytes());
+        header[5..9].copy_from_slice(&self.params.t_cost.to_be_bytes());
+        header[9..13].copy_from_slice(&self.params.p_cost.to_be_bytes());
+        header[13..].copy_from_slice(&self.salt);
+        header
+    }
+
+    /// Reads the header `bytes` start with, returning it and the bytes after it.
+    pub fn decode(bytes: &[u8]) -> Result<(Header, &[u8]), KdfError> {
+        if bytes.len() < HEADER_LEN {
+            return Err(KdfError::Malformed);
+        }
+        if bytes[0] != VERSION {
+            return Err(KdfError::UnsupportedVersion(bytes[0]));
+        }
+        let (header, rest) = bytes.split_at(HEADER_LEN);
+        let cost = |i: usize| u32::from_be_bytes(header[1 + 4 * i..5 + 4 * i].try_into().unwrap());
+        let header = Header {
+            params: KdfParams {
+                m_cost: cost(0),
+                t_cost: cost(1),
+                p_cost: cost(2),
+            },
+            salt: header[13..].try_into().unwrap(),
+        };
+        Ok((header, rest))
+    }
+
+    /// Derives the key `passphrase` gives under this header.
+    pub fn derive_key(&self, passphrase: &str) -> Result<Zeroizing<[u8; KEY_LEN]>, KdfError> {
+        let KdfParams {
+            m_cost,
+            t_cost,
+            p_cost,
+        } = self.params;
+        if m_cost > MAX_M_COST || t_cost > MAX_T_COST || p_cost > MAX_P_COST {
+            return Err(KdfError::InvalidParams);
+        }
+        let params = Params::new(m_cost, t_cost, p_cost, Some(KEY_LEN))
+            .map_err(|_| KdfError::InvalidParams)?;
+        let mut key = Zeroizing::new([0u8; KEY_LEN]);
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password_into(passphrase.as_bytes(), &self.salt, key.as_mut_slice())
+            .map_err(|_| KdfError::InvalidParams)?;
+        Ok(key)
+    }
+
+    /// Whether this header asks for less work than `params`, so what it protects should be
+    /// protected again under a new header once the passphrase is at hand. It stays usable until
+    /// then.
+    pub fn needs_upgrade(&self, params: KdfParams) -> bool {
+        self.params.m_cost < params.m_cost
+            || self.params.t_cost < params.t_cost
+            || self.params.p_cost < params.p_cost
+    }
+}
+
+/// Hashes `passphrase` into a header followed by the key derived under it, for `verify` to check
+/// passphrases against later.
+#[cfg(feature = "getrandom")]
+pub fn hash(passphrase: &str, params: KdfParams) -> Result<Vec<u8>, KdfError> {
+    hash_with_rng(passphrase, params, &mut OsRng)
+}
+
+/// Like `hash`, but draws the salt from `rng`.
+pub fn hash_with_rng<R: RngCore + CryptoRng>(
+    passphrase: &str,
+    params: KdfParams,
+    rng: &mut R,
+) -> Result<Vec<u8>, KdfError> {
+    let header = Header::new_with_rng(params, rng);
+    let key = header.derive_key(passphrase)?;
+    Ok([header.encode().as_slice(), key.as_slice()].concat())
+}
+
+/// Checks `passphrase` against one `hash`ed, comparing keys in constant time.
+pub fn verify(hash: &[u8], passphrase: &str) -> Result<(), KdfError> {
+    let (header, key) = Header::decode(hash)?;
+    if key.len() != KEY_LEN {
+        return Err(KdfError::Malformed);
+    }
+    if bool::from(header.derive_key(passphrase)?.ct_eq(key)) {
+        Ok(())
+    } else {
+        Err(KdfError::WrongPassphrase)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::kdf::passphrase::*;
+    use anyhow::Result;
+
+    /// Cheap costs so the tests stay fast.
+    const TEST_PARAMS: KdfParams = KdfParams {
+        m_cost: 8,
+        t_cost: 1,
+        p_cost: 1,
+    };
+
+    #[test]
+    fn round_trip() -> Result<()> {
+        let header = Header::new(TEST_PARAMS);
+        let encoded = header.encode();
+        let (decoded, rest) = Header::decode(&encoded)?;
+        assert_eq!(decoded, header);
+        assert!(rest.is_empty());
+        assert_eq!(
+            *decoded.derive_key("hunter2")?,
+            *header.derive_key("hunter2")?
+        );
+        assert_ne!(
+            *header.derive_key("hunter2")?,
+            *header.derive_key("hunter3")?
+        );
+        assert_ne!(
+            *Header::new(TEST_PARAMS).derive_key("hunter2")?,
+            *header.derive_key("hunter2")?
+        );
+
+        let hash = hash("hunter2", TEST_PARAMS)?;
+        assert_eq!(verify(&hash, "hunter2"), Ok(()));
+        assert_eq!(verify(&hash, "hunter3"), Err(KdfError::WrongPassphrase));
+        Ok(())
+    }
+
+    #[test]
+    fn upgrade() -> Result<()> {
+        let stronger = KdfParams {
+            m_cost: 16,
+            t_cost: 2,
+            ..TEST_PARAMS
+        };
+        let old = hash("hunter2", TEST_PARAMS)?;
+        let (header, _) = Header::decode(&old)?;
+        assert!(header.needs_upgrade(stronger));
+        assert!(!header.needs_upgrade(TEST_PARAMS));
+        // Headers made before the costs were raised still verify.
+        assert_eq!(verify(&old, "hunter2"), Ok(()));
+
+        let new = hash("hunter2", stronger)?;
+        let (header, _) = Header::decode(&new)?;
+        assert_eq!(header.params(), stronger);
+        assert!(!header.needs_upgrade(stronger));
+        assert_eq!(verify(&new, "hunter2"), Ok(()));
+        Ok(())
+    }
+
+    #[test]
+    fn tampered_headers() -> Result<()> {
+        let hash = hash("hunter2", TEST_PARAMS)?;
+
+        let mut salted = hash.clone();
+        salted[HEADER_LEN - 1] ^= 1;
+        assert_eq!(verify(&salted, "hunter2"), Err(KdfError::WrongPassphrase));
+        let mut cheaper = hash.clone();
+        cheaper[5..9].copy_from_slice(&2u32.to_be_bytes());
+        assert_eq!(verify(&cheaper, "hunter2"), Err(KdfError::WrongPassphrase));
+
+        let mut newer = hash.clone();
+        newer[0] = VERSION + 1;
+        assert_eq!(
+            verify(&newer, "hunter2"),
+            Err(KdfError::UnsupportedVersion(VERSION + 1))
+        );
+        let mut expensive = hash.clone();
+        expensive[1..5].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert_eq!(verify(&expensive, "hunter2"), Err(KdfError::InvalidParams));
+        let mut free = hash.clone();
+        free[1..5].copy_from_slice(&0u32.to_be_bytes());
+        assert_eq!(verify(&free, "hunter2"), Err(KdfError::InvalidParams));
+
+        assert_eq!(
+            verify(&hash[..HEADER_LEN - 1], "hunter2"),
+            Err(KdfError::Malformed)
+        );
+        assert_eq!(
+            verify(&hash[..hash.len() - 1], "hunter2"),
+            Err(KdfError::Malformed)
+        );
+        Ok(())
+    }
+}
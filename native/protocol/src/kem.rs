@@ -0,0 +1,225 @@
+use chacha20poly1305::aead::rand_core::{CryptoRng, RngCore};
+#[cfg(feature = "getrandom")]
+use chacha20poly1305::aead::OsRng;
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use zeroize::Zeroizing;
+
+/*
+    The post-quantum half of PQXDH - See https://signal.org/docs/specifications/pqxdh/
+    PQPK - Post-Quantum Pre Key. An ML-KEM-768 encapsulation key signed by the recipient's IK,
+    either one-time or last-resort like the X25519 prekeys.
+    CT - The KEM ciphertext Alice sends so Bob can recover the shared secret SS.
+    SS - The KEM shared secret, mixed into the KDF input after the DH outputs.
+*/
+
+/// Whether this build can encapsulate to and decapsulate ML-KEM-768 keys. Clients built without
+/// it neither upload KEM prekeys nor use the ones they're handed, and speak classic X3DH.
+pub const SUPPORTED: bool = cfg!(feature = "pqxdh");
+
+/// Length of an encoded ML-KEM-768 encapsulation key.
+pub const PUBLIC_KEY_LEN: usize = 1184;
+/// Length of an encoded ML-KEM-768 decapsulation key, which embeds the encapsulation key.
+pub const SECRET_KEY_LEN: usize = 2400;
+/// Length of an ML-KEM-768 ciphertext.
+pub const CIPHERTEXT_LEN: usize = 1088;
+
+/// Where the encapsulation key starts within an encoded decapsulation key (FIPS 203, 7.1).
+const PUBLIC_KEY_OFFSET: usize = 1152;
+
+#[derive(Error, Debug, Serialize, Deserialize, PartialEq)]
+pub enum KemError {
+    #[error("Built without ML-KEM support.")]
+    Unsupported,
+    #[error("KEM key is malformed.")]
+    InvalidKey,
+    #[error("KEM ciphertext is malformed.")]
+    InvalidCiphertext,
+}
+
+/// An ML-KEM-768 encapsulation key.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct KemPublicKey(Vec<u8>);
+
+impl KemPublicKey {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, KemError> {
+        if bytes.len() != PUBLIC_KEY_LEN {
+            return Err(KemError::InvalidKey);
+        }
+        Ok(KemPublicKey(bytes.to_vec()))
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+/// An ML-KEM-768 decapsulation key. Zeroized when dropped.
+#[derive(Clone)]
+pub struct KemSecretKey(Zeroizing<Vec<u8>>);
+
+impl KemSecretKey {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, KemError> {
+        if bytes.len() != SECRET_KEY_LEN {
+            return Err(KemError::InvalidKey);
+        }
+        Ok(KemSecretKey(Zeroizing::new(bytes.to_vec())))
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn public_key(&self) -> KemPublicKey {
+        KemPublicKey(self.0[PUBLIC_KEY_OFFSET..PUBLIC_KEY_OFFSET + PUBLIC_KEY_LEN].to_vec())
+    }
+}
+
+/// A KEM prekey and the signature over it using its owner's identity key.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SignedKemPreKey {
+    pub pre_key: KemPublicKey,
+    pub signature: Signature,
+}
+
+/// The bytes an identity key signs to vouch for a KEM prekey. The prefix keeps them apart from
+/// the X25519 prekey signatures.
+fn kem_pre_key_payload(key: &KemPublicKey) -> Vec<u8> {
+    [b"brongnal kem pre key:".as_slice(), key.as_bytes()].concat()
+}
+
+pub fn sign_kem_pre_key(signing_key: &SigningKey, key: &KemPublicKey) -> SignedKemPreKey {
+    SignedKemPreKey {
+        pre_key: key.clone(),
+        signature: signing_key.sign(&kem_pre_key_payload(key)),
+    }
+}
+
+pub fn verify_kem_pre_key(
+    verifying_key: &VerifyingKey,
+    key: &SignedKemPreKey,
+) -> Result<(), ed25519_dalek::ed25519::Error> {
+    verifying_key.verify_strict(&kem_pre_key_payload(&key.pre_key), &key.signature)
+}
+
+/// Creates a new decapsulation key.
+#[cfg(feature = "getrandom")]
+pub fn generate() -> Result<KemSecretKey, KemError> {
+    generate_with_rng(&mut OsRng)
+}
+
+/// Like `generate`, but draws the key from `rng`.
+pub fn generate_with_rng<R: RngCore + CryptoRng>(rng: &mut R) -> Result<KemSecretKey, KemError> {
+    Ok(KemSecretKey(Zeroizing::new(ml_kem_768::generate(rng)?)))
+}
+
+/// Returns a ciphertext for `key` and the shared secret it carries.
+#[cfg(feature = "getrandom")]
+pub fn encapsulate(key: &KemPublicKey) -> Result<(Vec<u8>, [u8; 32]), KemError> {
+    encapsulate_with_rng(key, &mut OsRng)
+}
+
+/// Like `encapsulate`, but draws the encapsulation's randomness from `rng`.
+pub fn encapsulate_with_rng<R: RngCore + CryptoRng>(
+    key: &KemPublicKey,
+    rng: &mut R,
+) -> Result<(Vec<u8>, [u8; 32]), KemError> {
+    ml_kem_768::encapsulate(key.as_bytes(), rng)
+}
+
+/// Recovers the shared secret carried by `ciphertext`. ML-KEM rejects implicitly, so a tampered
+/// ciphertext yields an unrelated secret rather than an error.
+pub fn decapsulate(key: &KemSecretKey, ciphertext: &[u8]) -> Result<[u8; 32], KemError> {
+    ml_kem_768::decapsulate(key.as_bytes(), ciphertext)
+}
+
+#[cfg(feature = "pqxdh")]
+mod ml_kem_768 {
+    use super::KemError;
+    use chacha20poly1305::aead::rand_core::{CryptoRng, RngCore};
+    use ml_kem::kem::{Decapsulate, Encapsulate};
+    use ml_kem::{Ciphertext, Encoded, EncodedSizeUser, KemCore, MlKem768};
+
+    type EncapsulationKey = <MlKem768 as KemCore>::EncapsulationKey;
+    type DecapsulationKey = <MlKem768 as KemCore>::DecapsulationKey;
+
+    pub fn generate<R: RngCore + CryptoRng>(rng: &mut R) -> Result<Vec<u8>, KemError> {
+        let (dk, _) = MlKem768::generate(rng);
+        Ok(dk.as_bytes().to_vec())
+    }
+
+    pub fn encapsulate<R: RngCore + CryptoRng>(
+        key: &[u8],
+        rng: &mut R,
+    ) -> Result<(Vec<u8>, [u8; 32]), KemError> {
+        let key = Encoded::<EncapsulationKey>::try_from(key).map_err(|_| KemError::InvalidKey)?;
+        let (ciphertext, shared_secret) = EncapsulationKey::from_bytes(&key)
+            .encapsulate(rng)
+            .map_err(|_| KemError::InvalidKey)?;
+        Ok((ciphertext.to_vec(), shared_secret.into()))
+    }
+
+    pub fn decapsulate(key: &[u8], ciphertext: &[u8]) -> Result<[u8; 32], KemError> {
+        let key = Encoded::<DecapsulationKey>::try_from(key).map_err(|_| KemError::InvalidKey)?;
+        let ciphertext = Ciphertext::<MlKem768>::try_from(ciphertext)
+            .map_err(|_| KemError::InvalidCiphertext)?;
+        let shared_secret = DecapsulationKey::from_bytes(&key)
+            .decapsulate(&ciphertext)
+            .map_err(|_| KemError::InvalidCiphertext)?;
+        Ok(shared_secret.into())
+    }
+}
+
+#[cfg(not(feature = "pqxdh"))]
+mod ml_kem_768 {
+    use super::KemError;
+    use chacha20poly1305::aead::rand_core::{CryptoRng, RngCore};
+
+    pub fn generate<R: RngCore + CryptoRng>(_rng: &mut R) -> Result<Vec<u8>, KemError> {
+        Err(KemError::Unsupported)
+    }
+
+    pub fn encapsulate<R: RngCore + CryptoRng>(
+        _key: &[u8],
+        _rng: &mut R,
+    ) -> Result<(Vec<u8>, [u8; 32]), KemError> {
+        Err(KemError::Unsupported)
+    }
+
+    pub fn decapsulate(_key: &[u8], _ciphertext: &[u8]) -> Result<[u8; 32], KemError> {
+        Err(KemError::Unsupported)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::kem::*;
+    use chacha20poly1305::aead::OsRng;
+
+    #[test]
+    fn signed_kem_pre_key() {
+        let ik = SigningKey::generate(&mut OsRng);
+        let key = KemPublicKey::from_bytes(&[7; PUBLIC_KEY_LEN]).unwrap();
+        let signed = sign_kem_pre_key(&ik, &key);
+        assert!(verify_kem_pre_key(&ik.verifying_key(), &signed).is_ok());
+
+        let other = SigningKey::generate(&mut OsRng);
+        assert!(verify_kem_pre_key(&other.verifying_key(), &signed).is_err());
+        assert_eq!(
+            KemPublicKey::from_bytes(&[7; PUBLIC_KEY_LEN - 1]),
+            Err(KemError::InvalidKey)
+        );
+    }
+
+    #[cfg(feature = "pqxdh")]
+    #[test]
+    fn encapsulate_decapsulate() -> Result<(), KemError> {
+        let secret = generate()?;
+        let (ciphertext, shared_secret) = encapsulate(&secret.public_key())?;
+        assert_eq!(ciphertext.len(), CIPHERTEXT_LEN);
+        assert_eq!(decapsulate(&secret, &ciphertext)?, shared_secret);
+        assert_ne!(decapsulate(&generate()?, &ciphertext)?, shared_secret);
+        Ok(())
+    }
+}
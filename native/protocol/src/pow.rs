@@ -0,0 +1,75 @@
+use sha2::{Digest, Sha256};
+
+/*
+    Proof of work that makes registering an identity cost the client some hashing.
+    The server hands out a random challenge and a difficulty in bits. The client searches for a
+    solution such that
+
+    SHA-256(challenge || identity || solution) starts with `difficulty` zero bits.
+
+    Binding the identity stops one solution from registering many names.
+*/
+
+/// Solutions are a little-endian counter of this many bytes.
+pub const SOLUTION_LEN: usize = 8;
+
+fn work_hash(challenge: &[u8], identity: &str, solution: &[u8]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(challenge);
+    hasher.update(identity.as_bytes());
+    hasher.update(solution);
+    hasher.finalize().into()
+}
+
+fn leading_zero_bits(hash: &[u8]) -> u32 {
+    let mut bits = 0;
+    for byte in hash {
+        bits += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+    bits
+}
+
+/// Whether `solution` solves `challenge` for `identity` at `difficulty`.
+pub fn verify(challenge: &[u8], identity: &str, solution: &[u8], difficulty: u32) -> bool {
+    leading_zero_bits(&work_hash(challenge, identity, solution)) >= difficulty
+}
+
+/// Searches for a solution to `challenge` for `identity` at `difficulty`, giving up after
+/// `max_attempts` hashes. On average a solution takes `2^difficulty` of them.
+pub fn solve(
+    challenge: &[u8],
+    identity: &str,
+    difficulty: u32,
+    max_attempts: u64,
+) -> Option<[u8; SOLUTION_LEN]> {
+    (0..max_attempts)
+        .map(u64::to_le_bytes)
+        .find(|solution| verify(challenge, identity, solution, difficulty))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_leading_zero_bits() {
+        assert_eq!(leading_zero_bits(&[0xff]), 0);
+        assert_eq!(leading_zero_bits(&[0x00, 0x10]), 11);
+        assert_eq!(leading_zero_bits(&[0x00, 0x00]), 16);
+    }
+
+    #[test]
+    fn solves() {
+        let solution = solve(b"challenge", "alice", 12, 1 << 20).unwrap();
+        assert!(verify(b"challenge", "alice", &solution, 12));
+        assert!(verify(b"challenge", "anyone", &[], 0));
+    }
+
+    #[test]
+    fn gives_up() {
+        assert_eq!(solve(b"challenge", "alice", 64, 100), None);
+    }
+}
@@ -0,0 +1,118 @@
+use crate::aead::{decrypt_data, encrypt_data_with_rng, AeadError};
+#[cfg(feature = "getrandom")]
+use chacha20poly1305::aead::OsRng;
+use chacha20poly1305::{
+    aead::{
+        rand_core::{CryptoRng, RngCore},
+        KeyInit, Payload,
+    },
+    ChaCha20Poly1305,
+};
+use ed25519_dalek::{SecretKey, SigningKey};
+use hkdf::Hkdf;
+use sha2::Sha256;
+use thiserror::Error;
+use x25519_dalek::{
+    PublicKey as X25519PublicKey, ReusableSecret as X25519ReusableSecret,
+    StaticSecret as X25519StaticSecret,
+};
+
+/*
+    Linking a new device to an existing identity.
+    The new device generates an ephemeral X25519 key and shows its public half to the primary
+    device out of band (a QR code). The primary encrypts its identity key to that public key with
+    a fresh ephemeral key of its own, and relays the envelope through the server. The server only
+    ever sees the ciphertext.
+
+    Envelope = Encode(EK_primary) || AEAD(KDF(DH(EK_primary, EK_new)), IK, AD)
+    AD = Encode(EK_new) || Encode(EK_primary)
+*/
+
+#[derive(Error, Debug, PartialEq)]
+pub enum ProvisioningError {
+    #[error("Provisioning envelope is malformed.")]
+    Malformed,
+    #[error("Aead routine failed.")]
+    Aead(#[from] AeadError),
+}
+
+fn provisioning_key(shared_secret: &[u8; 32]) -> ChaCha20Poly1305 {
+    let hk = Hkdf::<Sha256>::new(None, shared_secret);
+    let mut okm = [0u8; 32];
+    hk.expand(b"Brongnal Provisioning", &mut okm).unwrap();
+    ChaCha20Poly1305::new_from_slice(&okm).unwrap()
+}
+
+/// Encrypts `ik` to the new device's ephemeral `recipient` key.
+#[cfg(feature = "getrandom")]
+pub fn seal_identity_key(
+    recipient: &X25519PublicKey,
+    ik: &SigningKey,
+) -> Result<Vec<u8>, ProvisioningError> {
+    seal_identity_key_with_rng(recipient, ik, &mut OsRng)
+}
+
+/// Like `seal_identity_key`, but draws the ephemeral key and nonce from `rng`.
+pub fn seal_identity_key_with_rng<R: RngCore + CryptoRng>(
+    recipient: &X25519PublicKey,
+    ik: &SigningKey,
+    rng: &mut R,
+) -> Result<Vec<u8>, ProvisioningError> {
+    let ek = X25519ReusableSecret::random_from_rng(&mut *rng);
+    let ek_pub = X25519PublicKey::from(&ek);
+    let cipher = provisioning_key(ek.diffie_hellman(recipient).as_bytes());
+    let ad = [recipient.to_bytes(), ek_pub.to_bytes()].concat();
+    let ciphertext = encrypt_data_with_rng(
+        Payload {
+            msg: ik.as_bytes(),
+            aad: &ad,
+        },
+        &cipher,
+        rng,
+    )?;
+    Ok([ek_pub.as_bytes().as_slice(), &ciphertext].concat())
+}
+
+/// Recovers the identity key a primary device sealed to our ephemeral `secret`.
+pub fn open_identity_key(
+    secret: &X25519StaticSecret,
+    envelope: &[u8],
+) -> Result<SigningKey, ProvisioningError> {
+    // A bare ephemeral key followed by at least the version tag and nonce.
+    if envelope.len() <= 32 + 13 {
+        return Err(ProvisioningError::Malformed);
+    }
+    let (ek_pub, ciphertext) = envelope.split_at(32);
+    let ek_pub = X25519PublicKey::from(<[u8; 32]>::try_from(ek_pub).unwrap());
+    let cipher = provisioning_key(secret.diffie_hellman(&ek_pub).as_bytes());
+    let ad = [X25519PublicKey::from(secret).to_bytes(), ek_pub.to_bytes()].concat();
+    let ik: SecretKey = decrypt_data(ciphertext, &ad, &cipher)?
+        .try_into()
+        .map_err(|_| ProvisioningError::Malformed)?;
+    Ok(SigningKey::from_bytes(&ik))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::provisioning::*;
+    use anyhow::Result;
+
+    #[test]
+    fn seal_and_open() -> Result<()> {
+        let ik = SigningKey::generate(&mut OsRng);
+        let secret = X25519StaticSecret::random_from_rng(OsRng);
+        let envelope = seal_identity_key(&X25519PublicKey::from(&secret), &ik)?;
+        assert_eq!(open_identity_key(&secret, &envelope)?, ik);
+
+        let other = X25519StaticSecret::random_from_rng(OsRng);
+        assert_eq!(
+            open_identity_key(&other, &envelope),
+            Err(ProvisioningError::Aead(AeadError::Encrypt))
+        );
+        assert_eq!(
+            open_identity_key(&secret, &envelope[..32]),
+            Err(ProvisioningError::Malformed)
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,659 @@
+use crate::aead::{decrypt_data, encrypt_data_with_rng, AeadError};
+use chacha20poly1305::aead::rand_core::{CryptoRng, RngCore};
+#[cfg(feature = "getrandom")]
+use chacha20poly1305::aead::OsRng;
+use chacha20poly1305::{
+    aead::{KeyInit, Payload},
+    ChaCha20Poly1305,
+};
+use hkdf::Hkdf;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use thiserror::Error;
+use zeroize::{Zeroize, Zeroizing};
+
+/*
+    Symmetric-key ratchet for the messages that follow X3DH.
+    SK - The shared secret X3DH derived. It is split into one chain key per direction, so both
+    sides can send at once without ever encrypting under the same key:
+        CK_initiator || CK_responder = HKDF(SK, "Brongnal-Chains")
+    The initiator sends on CK_initiator and receives on CK_responder, the responder the reverse.
+    Each message sent on a chain advances it:
+        MK = HKDF-Expand(CK, "Brongnal-MessageKey")
+        CK' = HKDF-Expand(CK, "Brongnal-ChainKey")
+    MK encrypts that one message and CK is forgotten, so a later chain key doesn't reveal earlier
+    messages. Sender and receiver take the same steps, so the nth message sent on a chain is the
+    nth received on it.
+    Message = N || AEAD(MK_N, plaintext, AD || N)
+    N - The message's index on its chain, a big endian u32. Messages can arrive out of order, so a
+    receiver that gets N ahead of its chain advances to it, keeping the keys it skips until their
+    messages turn up. At most MAX_SKIP are skipped at once and MAX_SKIPPED_KEYS kept, oldest
+    forgotten first, so a message claiming a huge N can't make it derive or hold keys without end.
+    A message key is forgotten once its message decrypts, so a message behind the chain without a
+    skipped key can't be authenticated. The receiver instead keeps a digest of the last
+    MAX_RECEIVED_DIGESTS messages that decrypted,
+        H_N = SHA-256(len(AD) || AD || Message)
+    and only rejects such a message as a replay if it is one of those exactly. Any other message
+    behind the chain, whether forged or too old to tell, fails to decrypt like any forgery.
+
+    Stored sessions - Sessions outlive the process that set them up, encoded as
+        State = VERSION || CK_send || N_send || CK_recv || N_recv || COUNT || (N || MK_N)* || (N || H_N)*
+    with each N the index of the next message on its chain, followed by the COUNT skipped keys and
+    then the digests of the messages received. Version 1 states have no COUNT or digests. Stored
+    states are sealed under a key only the device holds, bound to the peer they are with:
+        Sealed = AEAD(HKDF(local key, "Brongnal-SessionState"), State, peer)
+    A release that changes the state bumps VERSION and keeps decoding every older version, so
+    stored sessions carry over. States from a newer release fail to decode, and their sessions
+    have to be set up again with X3DH. Whatever else the device keeps about its sessions, such as
+    the messages it may have to resend, is sealed the same way, bound to what it is instead.
+*/
+
+const CHAINS_INFO: &[u8] = b"Brongnal-Chains";
+const MESSAGE_KEY_INFO: &[u8] = b"Brongnal-MessageKey";
+const CHAIN_KEY_INFO: &[u8] = b"Brongnal-ChainKey";
+const STATE_INFO: &[u8] = b"Brongnal-SessionState";
+const INDEX_LEN: usize = 4;
+/// The version of the session state `Session::encode` writes.
+pub const STATE_VERSION: u8 = 2;
+const CHAIN_LEN: usize = 32 + INDEX_LEN;
+const SKIPPED_LEN: usize = INDEX_LEN + 32;
+const RECEIVED_LEN: usize = INDEX_LEN + 32;
+/// The most message keys a single message may skip past.
+pub const MAX_SKIP: u32 = 1000;
+/// The most skipped message keys a session keeps for late messages.
+pub const MAX_SKIPPED_KEYS: usize = 1000;
+/// The most digests of received messages a session keeps to recognise replays of them.
+pub const MAX_RECEIVED_DIGESTS: usize = 1000;
+
+/// Which side of X3DH a session was set up on, which decides the chain it sends on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+    /// Sent the initial message.
+    Initiator,
+    /// Received the initial message.
+    Responder,
+}
+
+#[derive(Error, Debug, PartialEq)]
+pub enum SessionError {
+    #[error("Session key not found.")]
+    NoSession,
+    #[error("Message is malformed.")]
+    Malformed,
+    #[error("Message skips {0} keys, more than the session allows.")]
+    TooManySkipped(u32),
+    #[error("Message {0} was already received.")]
+    ReplayDetected(u32),
+    #[error("Unsupported session state version: `{0}`")]
+    UnsupportedVersion(u8),
+    #[error("Aead routine failed.")]
+    Aead(#[from] AeadError),
+}
+
+type MessageKey = Zeroizing<[u8; 32]>;
+
+fn cipher(message_key: &MessageKey) -> ChaCha20Poly1305 {
+    ChaCha20Poly1305::new_from_slice(message_key.as_slice()).unwrap()
+}
+
+/// One direction of a session.
+#[derive(Clone)]
+struct ChainKey {
+    key: [u8; 32],
+    /// The index of the next message on the chain.
+    index: u32,
+}
+
+impl ChainKey {
+    fn new(key: [u8; 32]) -> Self {
+        ChainKey { key, index: 0 }
+    }
+
+    fn encode(&self) -> [u8; CHAIN_LEN] {
+        let mut encoded = [0u8; CHAIN_LEN];
+        encoded[..32].copy_from_slice(&self.key);
+        encoded[32..].copy_from_slice(&self.index.to_be_bytes());
+        encoded
+    }
+
+    fn decode(encoded: &[u8]) -> Self {
+        ChainKey {
+            key: encoded[..32].try_into().unwrap(),
+            index: u32::from_be_bytes(encoded[32..CHAIN_LEN].try_into().unwrap()),
+        }
+    }
+
+    /// Returns the key for the next message on this chain and its index, advancing past it.
+    fn next(&mut self) -> (u32, MessageKey) {
+        let hk = Hkdf::<Sha256>::from_prk(&self.key).unwrap();
+        let mut message_key = Zeroizing::new([0u8; 32]);
+        hk.expand(MESSAGE_KEY_INFO, message_key.as_mut_slice())
+            .unwrap();
+        hk.expand(CHAIN_KEY_INFO, &mut self.key).unwrap();
+        let index = self.index;
+        self.index += 1;
+        (index, message_key)
+    }
+}
+
+impl Drop for ChainKey {
+    fn drop(&mut self) {
+        self.key.zeroize();
+    }
+}
+
+/// A session with one peer: a chain to send on and one to receive on.
+#[derive(Clone)]
+pub struct Session {
+    sending: ChainKey,
+    receiving: ChainKey,
+    /// Keys the receiving chain advanced past before their messages arrived, by index.
+    skipped: BTreeMap<u32, MessageKey>,
+    /// Digests of the last messages that decrypted, by index, to tell replays of them from
+    /// forgeries.
+    received: BTreeMap<u32, [u8; 32]>,
+}
+
+impl Session {
+    /// Starts a session from the `secret_key` X3DH agreed on as `role`.
+    pub fn new(secret_key: &[u8; 32], role: Role) -> Self {
+        let hk = Hkdf::<Sha256>::new(None, secret_key);
+        let mut chains = [0u8; 64];
+        hk.expand(CHAINS_INFO, &mut chains).unwrap();
+        let initiator = ChainKey::new(chains[..32].try_into().unwrap());
+        let responder = ChainKey::new(chains[32..].try_into().unwrap());
+        chains.zeroize();
+        let (sending, receiving) = match role {
+            Role::Initiator => (initiator, responder),
+            Role::Responder => (responder, initiator),
+        };
+        Session {
+            sending,
+            receiving,
+            skipped: BTreeMap::new(),
+            received: BTreeMap::new(),
+        }
+    }
+
+    /// Encrypts `payload` under the next key of the sending chain, prefixed with its index on the
+    /// chain.
+    #[cfg(feature = "getrandom")]
+    pub fn encrypt(&mut self, payload: Payload) -> Result<Vec<u8>, SessionError> {
+        self.encrypt_with_rng(payload, &mut OsRng)
+    }
+
+    /// Like `encrypt`, but draws the nonce from `rng`.
+    pub fn encrypt_with_rng<R: RngCore + CryptoRng>(
+        &mut self,
+        payload: Payload,
+        rng: &mut R,
+    ) -> Result<Vec<u8>, SessionError> {
+        let (index, message_key) = self.sending.next();
+        let index = index.to_be_bytes();
+        let ciphertext = encrypt_data_with_rng(
+            Payload {
+                msg: payload.msg,
+                aad: &[payload.aad, &index].concat(),
+            },
+            &cipher(&message_key),
+            rng,
+        )?;
+        Ok([index.as_slice(), &ciphertext].concat())
+    }
+
+    /// Decrypts a message from the peer, in whatever order it arrives, under the key for its
+    /// index on the receiving chain. The session only changes if the message decrypts. Only a
+    /// copy of a message that already decrypted is reported as a replay.
+    pub fn decrypt(&mut self, message: &[u8], aad: &[u8]) -> Result<Vec<u8>, SessionError> {
+        if message.len() < INDEX_LEN {
+            return Err(SessionError::Malformed);
+        }
+        let digest = received_digest(message, aad);
+        let (index, ciphertext) = message.split_at(INDEX_LEN);
+        let aad = [aad, index].concat();
+        let index = u32::from_be_bytes(index.try_into().unwrap());
+
+        if index < self.receiving.index {
+            // A late message, whose key was skipped.
+            if let Some(message_key) = self.skipped.get(&index) {
+                let plaintext = decrypt_data(ciphertext, &aad, &cipher(message_key))?;
+                self.skipped.remove(&index);
+                self.record_received(index, digest);
+                return Ok(plaintext);
+            }
+            if self.received.get(&index) == Some(&digest) {
+                return Err(SessionError::ReplayDetected(index));
+            }
+            return Err(AeadError::Encrypt.into());
+        }
+
+        let skip = index - self.receiving.index;
+        if skip > MAX_SKIP {
+            return Err(SessionError::TooManySkipped(skip));
+        }
+        let mut receiving = self.receiving.clone();
+        let skipped: Vec<(u32, MessageKey)> = (0..skip).map(|_| receiving.next()).collect();
+        let (_, message_key) = receiving.next();
+        let plaintext = decrypt_data(ciphertext, &aad, &cipher(&message_key))?;
+        self.receiving = receiving;
+        self.skipped.extend(skipped);
+        while self.skipped.len() > MAX_SKIPPED_KEYS {
+            self.skipped.pop_first();
+        }
+        self.record_received(index, digest);
+        Ok(plaintext)
+    }
+
+    /// Records the `digest` of message `index`, which decrypted, forgetting the oldest digests
+    /// past `MAX_RECEIVED_DIGESTS`.
+    fn record_received(&mut self, index: u32, digest: [u8; 32]) {
+        self.received.insert(index, digest);
+        while self.received.len() > MAX_RECEIVED_DIGESTS {
+            self.received.pop_first();
+        }
+    }
+
+    /// Encodes the session's state, skipped keys and received digests and all, for `decode` to
+    /// restore.
+    pub fn encode(&self) -> Zeroizing<Vec<u8>> {
+        let mut state = Zeroizing::new(Vec::with_capacity(
+            1 + 2 * CHAIN_LEN
+                + INDEX_LEN
+                + self.skipped.len() * SKIPPED_LEN
+                + self.received.len() * RECEIVED_LEN,
+        ));
+        state.push(STATE_VERSION);
+        state.extend_from_slice(&self.sending.encode());
+        state.extend_from_slice(&self.receiving.encode());
+        state.extend_from_slice(&(self.skipped.len() as u32).to_be_bytes());
+        for (index, message_key) in &self.skipped {
+            state.extend_from_slice(&index.to_be_bytes());
+            state.extend_from_slice(message_key.as_slice());
+        }
+        for (index, digest) in &self.received {
+            state.extend_from_slice(&index.to_be_bytes());
+            state.extend_from_slice(digest);
+        }
+        state
+    }
+
+    pub fn decode(state: &[u8]) -> Result<Session, SessionError> {
+        let Some((&version, state)) = state.split_first() else {
+            return Err(SessionError::Malformed);
+        };
+        if !(1..=STATE_VERSION).contains(&version) {
+            return Err(SessionError::UnsupportedVersion(version));
+        }
+        if state.len() < 2 * CHAIN_LEN {
+            return Err(SessionError::Malformed);
+        }
+        let (chains, rest) = state.split_at(2 * CHAIN_LEN);
+        // Version 1 kept no digests, so only its skipped keys follow the chains.
+        let (skipped, received) = match version {
+            1 => (rest, &[][..]),
+            _ => {
+                if rest.len() < INDEX_LEN {
+                    return Err(SessionError::Malformed);
+                }
+                let (count, rest) = rest.split_at(INDEX_LEN);
+                let count = u32::from_be_bytes(count.try_into().unwrap()) as usize;
+                if rest.len() < count.saturating_mul(SKIPPED_LEN) {
+                    return Err(SessionError::Malformed);
+                }
+                rest.split_at(count * SKIPPED_LEN)
+            }
+        };
+        if !skipped.len().is_multiple_of(SKIPPED_LEN)
+            || !received.len().is_multiple_of(RECEIVED_LEN)
+        {
+            return Err(SessionError::Malformed);
+        }
+        Ok(Session {
+            sending: ChainKey::decode(&chains[..CHAIN_LEN]),
+            receiving: ChainKey::decode(&chains[CHAIN_LEN..]),
+            skipped: skipped
+                .chunks(SKIPPED_LEN)
+                .map(|skipped| {
+                    let (index, message_key) = skipped.split_at(INDEX_LEN);
+                    (
+                        u32::from_be_bytes(index.try_into().unwrap()),
+                        Zeroizing::new(message_key.try_into().unwrap()),
+                    )
+                })
+                .collect(),
+            received: received
+                .chunks(RECEIVED_LEN)
+                .map(|received| {
+                    let (index, digest) = received.split_at(INDEX_LEN);
+                    (
+                        u32::from_be_bytes(index.try_into().unwrap()),
+                        digest.try_into().unwrap(),
+                    )
+                })
+                .collect(),
+        })
+    }
+
+    /// Encrypts the session's state for storing, under a key derived from `local_key`, a secret
+    /// only this device holds, and bound to `peer`.
+    #[cfg(feature = "getrandom")]
+    pub fn seal(&self, local_key: &[u8; 32], peer: &[u8]) -> Result<Vec<u8>, SessionError> {
+        self.seal_with_rng(local_key, peer, &mut OsRng)
+    }
+
+    /// Like `seal`, but draws the nonce from `rng`.
+    pub fn seal_with_rng<R: RngCore + CryptoRng>(
+        &self,
+        local_key: &[u8; 32],
+        peer: &[u8],
+        rng: &mut R,
+    ) -> Result<Vec<u8>, SessionError> {
+        Ok(encrypt_data_with_rng(
+            Payload {
+                msg: &self.encode(),
+                aad: peer,
+            },
+            &state_key(local_key),
+            rng,
+        )?)
+    }
+
+    /// Restores a session `seal` stored with the same `local_key` and `peer`.
+    pub fn open(sealed: &[u8], local_key: &[u8; 32], peer: &[u8]) -> Result<Session, SessionError> {
+        let state = Zeroizing::new(decrypt_data(sealed, peer, &state_key(local_key))?);
+        Session::decode(&state)
+    }
+}
+
+/// Encrypts `payload` for storing alongside sessions, under the key `Session::seal` derives from
+/// `local_key`.
+#[cfg(feature = "getrandom")]
+pub fn seal_data(local_key: &[u8; 32], payload: Payload) -> Result<Vec<u8>, SessionError> {
+    Ok(encrypt_data_with_rng(
+        payload,
+        &state_key(local_key),
+        &mut OsRng,
+    )?)
+}
+
+/// Restores what `seal_data` stored with the same `local_key` and `aad`.
+pub fn open_data(
+    sealed: &[u8],
+    local_key: &[u8; 32],
+    aad: &[u8],
+) -> Result<Zeroizing<Vec<u8>>, SessionError> {
+    Ok(Zeroizing::new(decrypt_data(
+        sealed,
+        aad,
+        &state_key(local_key),
+    )?))
+}
+
+/// The digest a session keeps of `message`, received with `aad`, to recognise replays of it.
+fn received_digest(message: &[u8], aad: &[u8]) -> [u8; 32] {
+    Sha256::new()
+        .chain_update((aad.len() as u64).to_be_bytes())
+        .chain_update(aad)
+        .chain_update(message)
+        .finalize()
+        .into()
+}
+
+fn state_key(local_key: &[u8; 32]) -> ChaCha20Poly1305 {
+    let hk = Hkdf::<Sha256>::new(None, local_key);
+    let mut key = Zeroizing::new([0u8; 32]);
+    hk.expand(STATE_INFO, key.as_mut_slice()).unwrap();
+    cipher(&key)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ratchet::*;
+    use anyhow::Result;
+
+    fn sessions() -> (Session, Session) {
+        let sk = [7; 32];
+        (
+            Session::new(&sk, Role::Initiator),
+            Session::new(&sk, Role::Responder),
+        )
+    }
+
+    fn payload(msg: &[u8]) -> Payload<'_, '_> {
+        Payload { msg, aad: b"ad" }
+    }
+
+    #[test]
+    fn ping_pong() -> Result<()> {
+        let (mut alice, mut bob) = sessions();
+        for i in 0..20u8 {
+            let ping = alice.encrypt(payload(&[i]))?;
+            assert_eq!(bob.decrypt(&ping, b"ad")?, [i]);
+            let pong = bob.encrypt(payload(&[i, i]))?;
+            assert_eq!(alice.decrypt(&pong, b"ad")?, [i, i]);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn both_sides_send_at_once() -> Result<()> {
+        let (mut alice, mut bob) = sessions();
+        let pings = (0..3u8)
+            .map(|i| alice.encrypt(payload(&[i])))
+            .collect::<Result<Vec<_>, _>>()?;
+        let pongs = (0..3u8)
+            .map(|i| bob.encrypt(payload(&[i])))
+            .collect::<Result<Vec<_>, _>>()?;
+        for (i, (ping, pong)) in pings.iter().zip(&pongs).enumerate() {
+            assert_eq!(bob.decrypt(ping, b"ad")?, [i as u8]);
+            assert_eq!(alice.decrypt(pong, b"ad")?, [i as u8]);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn keys_are_never_reused() -> Result<()> {
+        let (mut alice, mut bob) = sessions();
+        let first = alice.encrypt(payload(b"hi"))?;
+        let second = alice.encrypt(payload(b"hi"))?;
+        // Each message needs the key for its own index, so one passed off as another doesn't
+        // decrypt.
+        let mut relabeled = second.clone();
+        relabeled[..INDEX_LEN].copy_from_slice(&0u32.to_be_bytes());
+        assert_eq!(
+            bob.decrypt(&relabeled, b"ad"),
+            Err(SessionError::Aead(AeadError::Encrypt))
+        );
+        // Nor does a reply under the sending chain's own keys.
+        let reflected = bob.encrypt(payload(b"hi"))?;
+        assert_eq!(
+            bob.decrypt(&reflected, b"ad"),
+            Err(SessionError::Aead(AeadError::Encrypt))
+        );
+        // Failures don't advance the chain, and each message decrypts once.
+        assert_eq!(bob.decrypt(&first, b"ad")?, b"hi");
+        assert_eq!(
+            bob.decrypt(&first, b"ad"),
+            Err(SessionError::ReplayDetected(0))
+        );
+        assert_eq!(bob.decrypt(&second, b"ad")?, b"hi");
+        assert_eq!(
+            bob.decrypt(&second, b"ad"),
+            Err(SessionError::ReplayDetected(1))
+        );
+        Ok(())
+    }
+
+    /// Has Alice send `order.len()` messages, numbered from 1, and Bob receive them in `order`.
+    fn deliver(order: &[u8]) -> Result<()> {
+        let (mut alice, mut bob) = sessions();
+        let messages = (1..=order.len() as u8)
+            .map(|i| alice.encrypt(payload(&[i])))
+            .collect::<Result<Vec<_>, _>>()?;
+        for &i in order {
+            assert_eq!(bob.decrypt(&messages[i as usize - 1], b"ad")?, [i]);
+        }
+        assert!(bob.skipped.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn out_of_order() -> Result<()> {
+        deliver(&[1, 3, 2])?;
+        deliver(&[1, 5, 2, 3, 4])?;
+        deliver(&[4, 3, 2, 1])?;
+        Ok(())
+    }
+
+    #[test]
+    fn skip_limit() -> Result<()> {
+        let (mut alice, mut bob) = sessions();
+        let messages = (0..=MAX_SKIP + 1)
+            .map(|_| alice.encrypt(payload(b"hi")))
+            .collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(
+            bob.decrypt(&messages[MAX_SKIP as usize + 1], b"ad"),
+            Err(SessionError::TooManySkipped(MAX_SKIP + 1))
+        );
+        assert_eq!(bob.receiving.index, 0);
+
+        // Skipping as many as allowed is fine.
+        assert_eq!(bob.decrypt(&messages[MAX_SKIP as usize], b"ad")?, b"hi");
+        assert_eq!(bob.decrypt(&messages[1], b"ad")?, b"hi");
+        assert_eq!(bob.decrypt(&messages[MAX_SKIP as usize + 1], b"ad")?, b"hi");
+        let skipped = &bob.skipped;
+        assert_eq!(skipped.len(), MAX_SKIPPED_KEYS - 1);
+        assert!(!skipped.contains_key(&1));
+
+        let more = (0..5)
+            .map(|_| alice.encrypt(payload(b"hi")))
+            .collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(bob.decrypt(&more[4], b"ad")?, b"hi");
+        assert_eq!(bob.skipped.len(), MAX_SKIPPED_KEYS);
+        // The oldest skipped keys make way for the newest, and their messages can no longer be
+        // told from forgeries.
+        assert_eq!(
+            bob.decrypt(&messages[0], b"ad"),
+            Err(SessionError::Aead(AeadError::Encrypt))
+        );
+        assert_eq!(bob.decrypt(&more[0], b"ad")?, b"hi");
+        assert_eq!(
+            bob.decrypt(&[0; INDEX_LEN - 1], b"ad"),
+            Err(SessionError::Malformed)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn replays() -> Result<()> {
+        let (mut alice, mut bob) = sessions();
+        let messages = (0..4u8)
+            .map(|i| alice.encrypt(payload(&[i])))
+            .collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(bob.decrypt(&messages[2], b"ad")?, [2]);
+        assert_eq!(bob.decrypt(&messages[0], b"ad")?, [0]);
+        for replayed in [0, 2] {
+            assert_eq!(
+                bob.decrypt(&messages[replayed], b"ad"),
+                Err(SessionError::ReplayDetected(replayed as u32))
+            );
+        }
+        // Only exact copies are replays. Anything else behind the chain is a forgery, even one
+        // claiming a received message's index.
+        let mut forged = messages[0].clone();
+        *forged.last_mut().unwrap() ^= 1;
+        assert_eq!(
+            bob.decrypt(&forged, b"ad"),
+            Err(SessionError::Aead(AeadError::Encrypt))
+        );
+        assert_eq!(
+            bob.decrypt(&messages[0], b"other ad"),
+            Err(SessionError::Aead(AeadError::Encrypt))
+        );
+
+        // What was received survives restoring the session, so replays are still caught.
+        let local_key = [9; 32];
+        let sealed = bob.seal(&local_key, b"alice")?;
+        let mut restored = Session::open(&sealed, &local_key, b"alice")?;
+        assert_eq!(
+            restored.decrypt(&messages[2], b"ad"),
+            Err(SessionError::ReplayDetected(2))
+        );
+        assert_eq!(restored.decrypt(&messages[3], b"ad")?, [3]);
+        assert_eq!(restored.decrypt(&messages[1], b"ad")?, [1]);
+        assert_eq!(
+            restored.decrypt(&messages[1], b"ad"),
+            Err(SessionError::ReplayDetected(1))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn stored_sessions() -> Result<()> {
+        let (mut alice, mut bob) = sessions();
+        let messages = (0..3u8)
+            .map(|i| alice.encrypt(payload(&[i])))
+            .collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(bob.decrypt(&messages[2], b"ad")?, [2]);
+
+        let local_key = [9; 32];
+        let sealed = bob.seal(&local_key, b"alice")?;
+        let mut restored = Session::open(&sealed, &local_key, b"alice")?;
+        assert_eq!(
+            *restored.encode(),
+            *bob.encode(),
+            "the skipped keys and received digests are kept"
+        );
+        assert_eq!(restored.decrypt(&messages[0], b"ad")?, [0]);
+        assert_eq!(restored.decrypt(&messages[1], b"ad")?, [1]);
+        let reply = restored.encrypt(payload(b"hi"))?;
+        assert_eq!(alice.decrypt(&reply, b"ad")?, b"hi");
+
+        assert_eq!(
+            Session::open(&sealed, &[8; 32], b"alice").err(),
+            Some(SessionError::Aead(AeadError::Encrypt))
+        );
+        assert_eq!(
+            Session::open(&sealed, &local_key, b"carol").err(),
+            Some(SessionError::Aead(AeadError::Encrypt))
+        );
+
+        // Other data is sealed under the same key, bound to what it is.
+        let sealed = seal_data(
+            &local_key,
+            Payload {
+                msg: b"Hello Bob!",
+                aad: b"message",
+            },
+        )?;
+        assert_eq!(*open_data(&sealed, &local_key, b"message")?, b"Hello Bob!");
+        assert!(open_data(&sealed, &local_key, b"alice").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn state_versions() {
+        let (alice, _) = sessions();
+        let state = alice.encode();
+        assert_eq!(state[0], STATE_VERSION);
+        assert!(Session::decode(&state).is_ok());
+
+        // Version 1 states, without received digests, still decode.
+        let mut older = state[..1 + 2 * CHAIN_LEN].to_vec();
+        older[0] = 1;
+        let older = Session::decode(&older).unwrap();
+        assert_eq!(*older.encode(), *state);
+
+        let mut newer = state.clone();
+        newer[0] = STATE_VERSION + 1;
+        assert_eq!(
+            Session::decode(&newer).err(),
+            Some(SessionError::UnsupportedVersion(STATE_VERSION + 1))
+        );
+        assert_eq!(
+            Session::decode(&state[..state.len() - 1]).err(),
+            Some(SessionError::Malformed)
+        );
+        assert_eq!(Session::decode(&[]).err(), Some(SessionError::Malformed));
+    }
+}
@@ -1,7 +1,15 @@
-use crate::aead::{decrypt_data, encrypt_data, AeadError};
+use crate::aead::{decrypt_data, encrypt_data_with_rng, AeadError};
 use crate::bundle::*;
+use crate::kem::{self, verify_kem_pre_key, KemError, KemSecretKey, SignedKemPreKey};
+use crate::xeddsa::{to_x25519, to_x25519_pub};
+use aes_gcm::Aes256Gcm;
+#[cfg(feature = "getrandom")]
+use chacha20poly1305::aead::OsRng;
 use chacha20poly1305::{
-    aead::{KeyInit, Payload},
+    aead::{
+        rand_core::{CryptoRng, RngCore},
+        KeyInit, Payload,
+    },
     ChaCha20Poly1305,
 };
 use ed25519_dalek::{Signature, SigningKey, VerifyingKey};
@@ -28,6 +36,7 @@ use x25519_dalek::{
     DH - Elliptic-curve Diffie–Hellman (ECDH) is a key agreement protocol that allows two parties,
     each having an elliptic-curve public–private key pair, to establish a shared secret over an insecure channel
     IKA - Alice's Identity Key
+    PQPK - Post-Quantum Pre Key. When Bob offers one, this becomes PQXDH: see `kem`.
 */
 
 /// A partipant in the X3DH protocol's prekey and the signature over it using their identity key.
@@ -58,43 +67,130 @@ pub struct X3DHSendKeyAgreement {
 /// * `sender_identity` is the claimed identity of the center. This must be authenticated.
 /// * `sender_ik` is the identity key of the sender.
 /// * `ek` is the ephemeral key generated to encrypt the message.
-/// * `pre_key` is the identifier (currently public key) of Bob's prekey that was used.
-/// * `opk` is Bob's one time prekey that (may) have been used.
+/// * `spk_id` is the identifier of Bob's signed prekey that was used.
+/// * `opk_id` is the identifier of Bob's one time prekey that (may) have been used.
+/// * `opk` is Bob's one time prekey, sent instead of `opk_id` by peers that predate prekey ids.
+/// * `last_resort` is set when `opk_id` is Bob's last-resort prekey, which he keeps after use.
+/// * `kem_pre_key_id` is the identifier of Bob's KEM prekey, if Alice encapsulated to one.
+/// * `kem_last_resort` is set when `kem_pre_key_id` is Bob's last-resort KEM prekey.
+/// * `kem_ciphertext` is the KEM ciphertext carrying the shared secret mixed into SK.
+/// * `suite` echoes the cipher suite from Bob's bundle, absent if he registered none.
+/// * `version` is the `PROTOCOL_VERSION` Alice derived SK with, absent if she predates versions.
 /// * `ciphertext` is the encrypted message.
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 pub struct Message {
     pub sender_identity: String,
     pub sender_ik: VerifyingKey,
     pub ek: X25519PublicKey,
-    pub pre_key: X25519PublicKey,
+    pub spk_id: Option<u32>,
+    pub opk_id: Option<u32>,
     pub opk: Option<X25519PublicKey>,
+    pub last_resort: bool,
+    pub kem_pre_key_id: Option<u32>,
+    pub kem_last_resort: bool,
+    pub kem_ciphertext: Option<Vec<u8>>,
+    pub suite: Option<u32>,
+    pub version: Option<u32>,
     pub ciphertext: Vec<u8>,
 }
 
-#[allow(deprecated)]
 impl std::fmt::Display for Message {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use base64::engine::general_purpose::STANDARD;
+        use base64::Engine;
         write!(
             f,
             "From: {} with key: {}\n\
             Keys: {}    {}\n\
             Payload: {}\n",
             self.sender_identity,
-            base64::encode(self.sender_ik),
-            base64::encode(self.ek),
-            self.opk
-                .map(base64::encode)
-                .unwrap_or(String::from("(None)")),
-            base64::encode(&self.ciphertext)
+            STANDARD.encode(self.sender_ik),
+            STANDARD.encode(self.ek),
+            match (self.opk_id, self.opk) {
+                (Some(id), _) => format!("#{id}"),
+                (None, Some(opk)) => STANDARD.encode(opk),
+                (None, None) => String::from("(None)"),
+            },
+            STANDARD.encode(&self.ciphertext)
         )
     }
 }
 
+/// The keys a sender needs to start a session. The ids are assigned by the server and are
+/// absent when talking to a server that predates them.
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct PreKeyBundle {
     pub ik: VerifyingKey,
     pub opk: Option<X25519PublicKey>,
     pub spk: SignedPreKey,
+    pub spk_id: Option<u32>,
+    pub opk_id: Option<u32>,
+    /// Whether `opk` is the recipient's last-resort prekey, handed out because it ran out of one
+    /// time prekeys, so other senders may be using it too.
+    pub last_resort: bool,
+    /// Absent when the recipient hasn't uploaded KEM prekeys.
+    pub kem_pre_key: Option<SignedKemPreKey>,
+    pub kem_pre_key_id: Option<u32>,
+    /// Like `last_resort`, but for `kem_pre_key`.
+    pub kem_last_resort: bool,
+    /// The id of the cipher suite the recipient registered with. Recipients that predate cipher
+    /// suites register none and are messaged with `CipherSuite::V1`, without binding it.
+    pub suite: Option<u32>,
+}
+
+/// The primitives a session is built from. Recipients pick one when they register and senders
+/// echo it back, so the construction can evolve without breaking peers that predate the change.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CipherSuite {
+    /// X25519, HKDF-SHA-256 and ChaCha20Poly1305.
+    V1,
+    /// X25519, HKDF-SHA-256 and AES-256-GCM, for hardware with AES instructions or deployments
+    /// that require AES.
+    V2,
+}
+
+impl CipherSuite {
+    /// The suite clients register with.
+    pub const CURRENT: CipherSuite = CipherSuite::V1;
+
+    /// The suite's id on the wire.
+    pub const fn id(self) -> u32 {
+        match self {
+            CipherSuite::V1 => 1,
+            CipherSuite::V2 => 2,
+        }
+    }
+
+    /// Resolves the suite a bundle or message names, where peers that name none use `V1`.
+    fn negotiate(id: Option<u32>) -> Result<Self, X3DHError> {
+        id.map_or(Ok(CipherSuite::V1), CipherSuite::try_from)
+    }
+}
+
+impl TryFrom<u32> for CipherSuite {
+    type Error = X3DHError;
+
+    fn try_from(id: u32) -> Result<Self, Self::Error> {
+        match id {
+            1 => Ok(CipherSuite::V1),
+            2 => Ok(CipherSuite::V2),
+            id => Err(X3DHError::UnsupportedSuite(id)),
+        }
+    }
+}
+
+/// The version of the key agreement this build sends. It names the layout of the KDF inputs and is
+/// mixed into the HKDF info, so a sender and receiver that disagree on it never derive the same SK.
+/// Bump it whenever the KDF inputs change.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Checks that this build can receive a message sent with `version`, where `None` is a sender
+/// that predates protocol versions.
+fn check_version(version: Option<u32>) -> Result<Option<u32>, X3DHError> {
+    match version {
+        None | Some(PROTOCOL_VERSION) => Ok(version),
+        Some(version) => Err(X3DHError::UnsupportedVersion(version)),
+    }
 }
 
 // KDF = Key Derivation Function
@@ -102,25 +198,92 @@ pub struct PreKeyBundle {
 // KDF(KM) represents 32 bytes of output from the HKDF algorithm [3] with inputs:
 //    HKDF input key material = F || KM, where KM is an input byte sequence containing secret key material, and F is a byte sequence containing 32 0xFF bytes if curve is X25519, and 57 0xFF bytes if curve is X448. F is used for cryptographic domain separation with XEdDSA [2].
 //    HKDF salt = A zero-filled byte sequence with length equal to the hash output length.
-//    HKDF info = An ASCII string identifying the application.
-fn kdf(km: &[u8]) -> [u8; 32] {
-    let salt = [0; 32];
-    let f = [0xFF; 32];
-    let ikm = [&f, km].concat();
-    let hk = Hkdf::<Sha256>::new(Some(&salt), &ikm);
-    let mut okm = [0u8; 32];
-    hk.expand(b"Brongnal", &mut okm).unwrap();
-    okm
+//    HKDF info = An ASCII string identifying the application, "Brongnal-v{PROTOCOL_VERSION}".
+// Senders that predate protocol versions use "Brongnal".
+// PQXDH appends the KEM shared secret SS to KM and names the KEM in the info string, so a session
+// that mixed in a KEM secret can never derive the same SK as one stripped of it.
+fn kdf(suite: CipherSuite, version: Option<u32>, km: &[u8], kem_ss: Option<&[u8; 32]>) -> [u8; 32] {
+    // Every suite so far derives SK the same way and only differs in the AEAD keyed with it.
+    match suite {
+        CipherSuite::V1 | CipherSuite::V2 => {
+            let salt = [0; 32];
+            let f = [0xFF; 32];
+            let mut info = match version {
+                Some(version) => format!("Brongnal-v{version}"),
+                None => String::from("Brongnal"),
+            };
+            let ikm = match kem_ss {
+                Some(ss) => {
+                    info.push_str("_X25519_SHA-256_ML-KEM-768");
+                    [&f, km, ss].concat()
+                }
+                None => [&f, km].concat(),
+            };
+            let hk = Hkdf::<Sha256>::new(Some(&salt), &ikm);
+            let mut okm = [0u8; 32];
+            hk.expand(info.as_bytes(), &mut okm).unwrap();
+            okm
+        }
+    }
 }
 
-/// Convert Ed25519 private key to its birationally equivalent X25519 key.
-fn to_x25519(key: &SigningKey) -> X25519StaticSecret {
-    X25519StaticSecret::from(key.to_scalar_bytes())
+/// Encrypts the initial message under SK with `suite`'s AEAD and a nonce drawn from `rng`.
+fn seal<R: RngCore + CryptoRng>(
+    suite: CipherSuite,
+    sk: &[u8; 32],
+    payload: Payload,
+    rng: &mut R,
+) -> Result<Vec<u8>, AeadError> {
+    match suite {
+        CipherSuite::V1 => {
+            encrypt_data_with_rng(payload, &ChaCha20Poly1305::new_from_slice(sk).unwrap(), rng)
+        }
+        CipherSuite::V2 => {
+            encrypt_data_with_rng(payload, &Aes256Gcm::new_from_slice(sk).unwrap(), rng)
+        }
+    }
 }
 
-/// Convert Ed25519 public key to its birationally equivalent X25519 key.
-fn to_x25519_pub(key: &VerifyingKey) -> X25519PublicKey {
-    X25519PublicKey::from(key.to_montgomery().to_bytes())
+/// Decrypts an initial message sealed with `seal`.
+fn open(
+    suite: CipherSuite,
+    sk: &[u8; 32],
+    ciphertext: &[u8],
+    aad: &[u8],
+) -> Result<Vec<u8>, AeadError> {
+    match suite {
+        CipherSuite::V1 => decrypt_data(
+            ciphertext,
+            aad,
+            &ChaCha20Poly1305::new_from_slice(sk).unwrap(),
+        ),
+        CipherSuite::V2 => decrypt_data(ciphertext, aad, &Aes256Gcm::new_from_slice(sk).unwrap()),
+    }
+}
+
+/// AD = Encode(IKA) || Encode(IKB), followed by the suite id when the recipient registered one so
+/// that a message can't be passed off as using another suite. Both sides encode the public
+/// identity keys; the receiver must not encode its own signing key, which is its private seed.
+fn associated_data(
+    sender_ik: &VerifyingKey,
+    receiver_ik: &VerifyingKey,
+    suite: Option<u32>,
+) -> Vec<u8> {
+    let mut ad = [sender_ik.to_bytes(), receiver_ik.to_bytes()].concat();
+    if let Some(suite) = suite {
+        ad.extend_from_slice(&suite.to_be_bytes());
+    }
+    ad
+}
+
+/// Appends `context` to `ad`, followed by its length so where it starts is unambiguous. An empty
+/// context appends nothing, leaving the AD of messages that don't bind one as it always was.
+fn bind_context(mut ad: Vec<u8>, context: &[u8]) -> Vec<u8> {
+    if !context.is_empty() {
+        ad.extend_from_slice(context);
+        ad.extend_from_slice(&(context.len() as u32).to_be_bytes());
+    }
+    ad
 }
 
 #[derive(Error, Debug, Serialize, Deserialize, PartialEq)]
@@ -129,6 +292,12 @@ pub enum X3DHError {
     SignatureValidation,
     #[error("Aead routine failed.")]
     Aead(#[from] AeadError),
+    #[error("KEM routine failed.")]
+    Kem(#[from] KemError),
+    #[error("Cipher suite {0} is not supported.")]
+    UnsupportedSuite(u32),
+    #[error("Protocol version {0} is not supported.")]
+    UnsupportedVersion(u32),
 }
 
 // DH(PK1, PK2) represents a byte sequence which is the shared secret output from an Elliptic Curve Diffie-Hellman function involving the key pairs represented by public keys PK1 and PK2. The Elliptic Curve Diffie-Hellman function will be either the X25519 or X448 function from [1], depending on the curve parameter.
@@ -140,11 +309,19 @@ pub enum X3DHError {
 //If the bundle does contain a one-time prekey, the calculation is modified to include an additional DH:
 //    DH4 = DH(EKA, OPKB)
 //    SK = KDF(DH1 || DH2 || DH3 || DH4)
-fn initiate_send_get_sk(
+// If she encapsulated to a KEM prekey of Bob's, its shared secret is appended:
+//    SK = KDF(DH1 || DH2 || DH3 || DH4 || SS)
+// EK is drawn from `rng`.
+#[allow(clippy::too_many_arguments)]
+fn initiate_send_get_sk<R: RngCore + CryptoRng>(
     recipient_ik: VerifyingKey,
     spk: &SignedPreKey,
     opk: Option<X25519PublicKey>,
     sender_ik: &SigningKey,
+    kem_ss: Option<&[u8; 32]>,
+    suite: CipherSuite,
+    version: Option<u32>,
+    rng: &mut R,
 ) -> Result<X3DHSendKeyAgreement, X3DHError> {
     // It might be tempting to observe that mutual authentication and forward secrecy are achieved by the DH calculations, and omit the prekey signature.
     // However, this would allow a "weak forward secrecy" attack:
@@ -152,7 +329,7 @@ fn initiate_send_get_sk(
     verify_bundle(&recipient_ik, &[spk.pre_key], &spk.signature)
         .map_err(|_| X3DHError::SignatureValidation)?;
 
-    let ek = X25519ReusableSecret::random();
+    let ek = X25519ReusableSecret::random_from_rng(rng);
     let dh1 = to_x25519(sender_ik).diffie_hellman(&spk.pre_key);
     let dh2 = ek.diffie_hellman(&to_x25519_pub(&recipient_ik));
     let dh3 = ek.diffie_hellman(&spk.pre_key);
@@ -160,15 +337,25 @@ fn initiate_send_get_sk(
     let sk = match opk {
         Some(one_time_prekey) => {
             let dh4 = ek.diffie_hellman(&one_time_prekey);
-            kdf(&[
-                dh1.to_bytes(),
-                dh2.to_bytes(),
-                dh3.to_bytes(),
-                dh4.to_bytes(),
-            ]
-            .concat())
+            kdf(
+                suite,
+                version,
+                &[
+                    dh1.to_bytes(),
+                    dh2.to_bytes(),
+                    dh3.to_bytes(),
+                    dh4.to_bytes(),
+                ]
+                .concat(),
+                kem_ss,
+            )
         }
-        None => kdf(&[dh1.to_bytes(), dh2.to_bytes(), dh3.to_bytes()].concat()),
+        None => kdf(
+            suite,
+            version,
+            &[dh1.to_bytes(), dh2.to_bytes(), dh3.to_bytes()].concat(),
+            kem_ss,
+        ),
     };
 
     // After a successful protocol run Alice and Bob will share a 32-byte secret key SK.
@@ -185,36 +372,93 @@ fn initiate_send_get_sk(
 //    Identifiers stating which of Bob's prekeys Alice used
 //    An initial ciphertext encrypted with some AEAD encryption scheme [4] using AD as associated data
 //    and using an encryption key which is either SK or the output from some cryptographic PRF keyed by SK.
+#[cfg(feature = "getrandom")]
 pub fn initiate_send(
     prekey_bundle: PreKeyBundle,
     sender_identity: String,
     sender_ik: &SigningKey,
     message: &[u8],
 ) -> Result<([u8; 32], Message), X3DHError> {
+    initiate_send_with_context(prekey_bundle, sender_identity, sender_ik, message, &[])
+}
+
+/// Like `initiate_send`, but also binds `context` to the ciphertext by appending it to AD, so it
+/// only decrypts when Bob passes the same `context` to `initiate_recv_with_context`.
+#[cfg(feature = "getrandom")]
+pub fn initiate_send_with_context(
+    prekey_bundle: PreKeyBundle,
+    sender_identity: String,
+    sender_ik: &SigningKey,
+    message: &[u8],
+    context: &[u8],
+) -> Result<([u8; 32], Message), X3DHError> {
+    initiate_send_with_rng(
+        prekey_bundle,
+        sender_identity,
+        sender_ik,
+        message,
+        context,
+        &mut OsRng,
+    )
+}
+
+/// Like `initiate_send_with_context`, but draws all of its randomness from `rng`: first the 32
+/// bytes of EK, then the KEM encapsulation's if Bob offered a KEM prekey, and last the AEAD
+/// nonce. Lets tests reproduce a session from fixed inputs.
+pub fn initiate_send_with_rng<R: RngCore + CryptoRng>(
+    prekey_bundle: PreKeyBundle,
+    sender_identity: String,
+    sender_ik: &SigningKey,
+    message: &[u8],
+    context: &[u8],
+    rng: &mut R,
+) -> Result<([u8; 32], Message), X3DHError> {
+    // Refuse rather than guess when Bob picked a suite this build doesn't implement.
+    let suite = CipherSuite::negotiate(prekey_bundle.suite)?;
+    // PQXDH is used whenever Bob offers a KEM prekey and Alice is able to encapsulate to it, and
+    // classic X3DH otherwise. Bob's KEM prekey must be signed by his identity key like his SPK.
+    let kem = match (&prekey_bundle.kem_pre_key, prekey_bundle.kem_pre_key_id) {
+        (Some(kem_pre_key), Some(id)) if kem::SUPPORTED => {
+            verify_kem_pre_key(&prekey_bundle.ik, kem_pre_key)
+                .map_err(|_| X3DHError::SignatureValidation)?;
+            let (kem_ciphertext, kem_ss) = kem::encapsulate_with_rng(&kem_pre_key.pre_key, rng)?;
+            Some((id, kem_ciphertext, kem_ss))
+        }
+        _ => None,
+    };
     let X3DHSendKeyAgreement { ek, sk } = initiate_send_get_sk(
         prekey_bundle.ik,
         &prekey_bundle.spk,
         prekey_bundle.opk,
         sender_ik,
+        kem.as_ref().map(|(_, _, kem_ss)| kem_ss),
+        suite,
+        Some(PROTOCOL_VERSION),
+        rng,
     )?;
     // Alice then calculates an "associated data" byte sequence AD that contains identity information for both parties:
     //   AD = Encode(IKA) || Encode(IKB)
     // Alice may optionally append additional information to AD, such as Alice and Bob's usernames, certificates, or other identifying information.
-    let associated_data = [
-        sender_ik.verifying_key().to_bytes(),
-        prekey_bundle.ik.to_bytes(),
-    ]
-    .concat();
+    let associated_data = bind_context(
+        associated_data(
+            &sender_ik.verifying_key(),
+            &prekey_bundle.ik,
+            prekey_bundle.suite,
+        ),
+        context,
+    );
 
     // The initial ciphertext is typically the first message in some post-X3DH communication protocol.
     // In other words, this ciphertext typically has two roles, serving as the first message within some post-X3DH protocol, and as part of Alice's X3DH initial message.
     // After sending this, Alice may continue using SK or keys derived from SK within the post-X3DH protocol for communication with Bob
-    let ciphertext = encrypt_data(
+    let ciphertext = seal(
+        suite,
+        &sk,
         Payload {
             msg: message,
             aad: &associated_data,
         },
-        &ChaCha20Poly1305::new_from_slice(&sk).unwrap(),
+        rng,
     )?;
 
     Ok((
@@ -223,19 +467,31 @@ pub fn initiate_send(
             sender_identity,
             sender_ik: sender_ik.verifying_key(),
             ek,
-            pre_key: prekey_bundle.spk.pre_key,
-            opk: prekey_bundle.opk,
+            spk_id: prekey_bundle.spk_id,
+            opk_id: prekey_bundle.opk_id,
+            // Only fall back to shipping the key itself when the server didn't assign it an id.
+            opk: prekey_bundle.opk.filter(|_| prekey_bundle.opk_id.is_none()),
+            last_resort: prekey_bundle.last_resort,
+            kem_pre_key_id: kem.as_ref().map(|(id, _, _)| *id),
+            kem_last_resort: kem.is_some() && prekey_bundle.kem_last_resort,
+            kem_ciphertext: kem.map(|(_, kem_ciphertext, _)| kem_ciphertext),
+            suite: prekey_bundle.suite,
+            version: Some(PROTOCOL_VERSION),
             ciphertext,
         },
     ))
 }
 
+#[allow(clippy::too_many_arguments)]
 fn initiate_recv_get_sk(
     sender_ik: &VerifyingKey,
     ek: X25519PublicKey,
     opk: Option<X25519StaticSecret>,
     receiver_ik: &SigningKey,
     spk: &X25519StaticSecret,
+    kem_ss: Option<&[u8; 32]>,
+    suite: CipherSuite,
+    version: Option<u32>,
 ) -> [u8; 32] {
     let dh1 = spk.diffie_hellman(&to_x25519_pub(sender_ik));
     let dh2 = to_x25519(receiver_ik).diffie_hellman(&ek);
@@ -243,56 +499,124 @@ fn initiate_recv_get_sk(
 
     if let Some(opk) = opk {
         let dh4 = opk.diffie_hellman(&ek);
-        kdf(&[
-            dh1.to_bytes(),
-            dh2.to_bytes(),
-            dh3.to_bytes(),
-            dh4.to_bytes(),
-        ]
-        .concat())
+        kdf(
+            suite,
+            version,
+            &[
+                dh1.to_bytes(),
+                dh2.to_bytes(),
+                dh3.to_bytes(),
+                dh4.to_bytes(),
+            ]
+            .concat(),
+            kem_ss,
+        )
     } else {
-        kdf(&[dh1.to_bytes(), dh2.to_bytes(), dh3.to_bytes()].concat())
+        kdf(
+            suite,
+            version,
+            &[dh1.to_bytes(), dh2.to_bytes(), dh3.to_bytes()].concat(),
+            kem_ss,
+        )
     }
 }
 
 /// Bob deletes any one-time prekey private key that was used, for forward secrecy.
 /// Caller must delete sk on error and the opk must be wiped.
+/// `receiver_kem` is the KEM prekey Alice encapsulated to along with her KEM ciphertext, if the
+/// message carried one. One-time KEM prekeys must be wiped like the opk.
+/// `suite` is the cipher suite the message claims to use and `version` the protocol version.
+#[allow(clippy::too_many_arguments)]
 pub fn initiate_recv(
     receiver_ik: &SigningKey,
     receiver_spk: &X25519StaticSecret,
     sender_ik: &VerifyingKey,
     ek: X25519PublicKey,
     receiver_opk: Option<X25519StaticSecret>,
+    receiver_kem: Option<(KemSecretKey, &[u8])>,
+    suite: Option<u32>,
+    version: Option<u32>,
+    ciphertext: &[u8],
+) -> Result<([u8; 32], Vec<u8>), X3DHError> {
+    initiate_recv_with_context(
+        receiver_ik,
+        receiver_spk,
+        sender_ik,
+        ek,
+        receiver_opk,
+        receiver_kem,
+        suite,
+        version,
+        ciphertext,
+        &[],
+    )
+}
+
+/// Like `initiate_recv`, for messages sent with `initiate_send_with_context`. Fails to decrypt
+/// unless `context` is the one the message was sent with.
+#[allow(clippy::too_many_arguments)]
+pub fn initiate_recv_with_context(
+    receiver_ik: &SigningKey,
+    receiver_spk: &X25519StaticSecret,
+    sender_ik: &VerifyingKey,
+    ek: X25519PublicKey,
+    receiver_opk: Option<X25519StaticSecret>,
+    receiver_kem: Option<(KemSecretKey, &[u8])>,
+    suite: Option<u32>,
+    version: Option<u32>,
     ciphertext: &[u8],
+    context: &[u8],
 ) -> Result<([u8; 32], Vec<u8>), X3DHError> {
     // Upon receiving Alice's initial message, Bob retrieves Alice's identity key and ephemeral key from the message.
     // Bob also loads his identity private key, and the private key(s) corresponding to whichever signed prekey and one-time prekey (if any) Alice used.
     // Using these keys, Bob repeats the DH and KDF calculations from the previous section to derive SK, and then deletes the DH values.
-    let sk = initiate_recv_get_sk(sender_ik, ek, receiver_opk, receiver_ik, receiver_spk);
+    let cipher_suite = CipherSuite::negotiate(suite)?;
+    let version = check_version(version)?;
+    let kem_ss = receiver_kem
+        .map(|(kem_key, kem_ciphertext)| kem::decapsulate(&kem_key, kem_ciphertext))
+        .transpose()?;
+    let sk = initiate_recv_get_sk(
+        sender_ik,
+        ek,
+        receiver_opk,
+        receiver_ik,
+        receiver_spk,
+        kem_ss.as_ref(),
+        cipher_suite,
+        version,
+    );
 
     // Bob then constructs the AD byte sequence using IKA and IKB, as described in the previous section.
     // AD = Encode(IKA) || Encode(IKB)
-    let ad = [sender_ik.to_bytes(), receiver_ik.verifying_key().to_bytes()].concat();
+    let ad = bind_context(
+        associated_data(sender_ik, &receiver_ik.verifying_key(), suite),
+        context,
+    );
 
     // Bob may then continue using SK or keys derived from SK within the post-X3DH protocol for communication with Alice.
     // Finally, Bob attempts to decrypt the initial ciphertext using SK and AD.
-    let cipher = ChaCha20Poly1305::new_from_slice(&sk).unwrap();
-    Ok((sk, decrypt_data(ciphertext, &ad, &cipher)?))
+    Ok((sk, open(cipher_suite, &sk, ciphertext, &ad)?))
 }
 
 #[cfg(test)]
 mod tests {
     use crate::aead::AeadError;
-    use crate::x3dh::X3DHError;
+    use crate::kem::{self, sign_kem_pre_key, KemPublicKey};
+    use crate::x3dh::{CipherSuite, X3DHError, PROTOCOL_VERSION};
 
     use super::PreKeyBundle;
     use super::{
-        create_prekey_bundle, initiate_recv, initiate_recv_get_sk, initiate_send,
-        initiate_send_get_sk, SignedPreKey, X3DHSendKeyAgreement,
+        associated_data, create_prekey_bundle, initiate_recv, initiate_recv_get_sk,
+        initiate_recv_with_context, initiate_send, initiate_send_get_sk,
+        initiate_send_with_context, initiate_send_with_rng, kdf, open, sign_bundle, SignedPreKey,
+        X3DHSendKeyAgreement,
     };
-    use anyhow::Result;
+    use anyhow::{Context, Result};
+    use chacha20poly1305::aead::rand_core::{self, CryptoRng, RngCore};
     use chacha20poly1305::aead::OsRng;
     use ed25519_dalek::SigningKey;
+    use serde::Deserialize;
+    use std::collections::VecDeque;
     use x25519_dalek::{PublicKey as X25519PublicKey, StaticSecret as X25519StaticSecret};
 
     // 1. Bob publishes his identity key and prekeys to a server.
@@ -315,7 +639,16 @@ mod tests {
         let X3DHSendKeyAgreement {
             ek: ephemeral_key,
             sk: secret_key,
-        } = initiate_send_get_sk(bob_ik.verifying_key(), &bob_spk, Some(opk_pub), &alice_ik)?;
+        } = initiate_send_get_sk(
+            bob_ik.verifying_key(),
+            &bob_spk,
+            Some(opk_pub),
+            &alice_ik,
+            None,
+            CipherSuite::V1,
+            Some(PROTOCOL_VERSION),
+            &mut OsRng,
+        )?;
 
         let recv_sk = initiate_recv_get_sk(
             &alice_ik.verifying_key(),
@@ -323,6 +656,9 @@ mod tests {
             Some(opk),
             &bob_ik,
             &bob_spk_secret,
+            None,
+            CipherSuite::V1,
+            Some(PROTOCOL_VERSION),
         );
         assert_eq!(secret_key, recv_sk);
         Ok(())
@@ -339,8 +675,16 @@ mod tests {
         };
         let alice_ik = SigningKey::generate(&mut OsRng);
 
-        let X3DHSendKeyAgreement { ek, sk } =
-            initiate_send_get_sk(bob_ik.verifying_key(), &bob_spk, None, &alice_ik)?;
+        let X3DHSendKeyAgreement { ek, sk } = initiate_send_get_sk(
+            bob_ik.verifying_key(),
+            &bob_spk,
+            None,
+            &alice_ik,
+            None,
+            CipherSuite::V1,
+            Some(PROTOCOL_VERSION),
+            &mut OsRng,
+        )?;
 
         let recv_sk = initiate_recv_get_sk(
             &alice_ik.verifying_key(),
@@ -348,6 +692,9 @@ mod tests {
             None,
             &bob_ik,
             &bob_spk_secret,
+            None,
+            CipherSuite::V1,
+            Some(PROTOCOL_VERSION),
         );
         assert_eq!(sk, recv_sk);
 
@@ -375,6 +722,13 @@ mod tests {
             ik: bob_ik.verifying_key(),
             opk: Some(bob_opk_pub),
             spk: bob_spk.clone(),
+            spk_id: None,
+            opk_id: None,
+            last_resort: false,
+            kem_pre_key: None,
+            kem_pre_key_id: None,
+            kem_last_resort: false,
+            suite: None,
         };
         let (send_sk, message) =
             initiate_send(bundle, "alice".to_owned(), &alice_ik, plaintext.as_bytes())?;
@@ -386,6 +740,9 @@ mod tests {
             &message.sender_ik,
             message.ek,
             Some(bob_opk_priv),
+            None,
+            message.suite,
+            message.version,
             &message.ciphertext,
         )?;
         assert_eq!(send_sk, recv_sk);
@@ -411,6 +768,13 @@ mod tests {
             ik: bob_ik.verifying_key(),
             opk: None,
             spk: bob_spk.clone(),
+            spk_id: None,
+            opk_id: None,
+            last_resort: false,
+            kem_pre_key: None,
+            kem_pre_key_id: None,
+            kem_last_resort: false,
+            suite: None,
         };
         let (send_sk, message) =
             initiate_send(bundle, "alice".to_owned(), &alice_ik, b"Hello Bob!")?;
@@ -422,6 +786,9 @@ mod tests {
             &message.sender_ik,
             message.ek,
             None,
+            None,
+            message.suite,
+            message.version,
             &message.ciphertext,
         )?;
         assert_eq!(send_sk, recv_sk);
@@ -430,6 +797,55 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn x3dh_send_identifies_prekeys_by_id() -> Result<()> {
+        let bob_ik = SigningKey::generate(&mut OsRng);
+        let bob_spk = create_prekey_bundle(&bob_ik, 1);
+        let bob_spk = SignedPreKey {
+            pre_key: bob_spk.bundle[0].1,
+            signature: bob_spk.signature,
+        };
+        let bob_opk = X25519PublicKey::from(&X25519StaticSecret::random_from_rng(OsRng));
+        let alice_ik = SigningKey::generate(&mut OsRng);
+
+        let bundle = PreKeyBundle {
+            ik: bob_ik.verifying_key(),
+            opk: Some(bob_opk),
+            spk: bob_spk.clone(),
+            spk_id: Some(3),
+            opk_id: Some(7),
+            last_resort: true,
+            kem_pre_key: None,
+            kem_pre_key_id: None,
+            kem_last_resort: false,
+            suite: None,
+        };
+        let (_, message) = initiate_send(bundle, "alice".to_owned(), &alice_ik, b"Hello Bob!")?;
+        assert_eq!(message.spk_id, Some(3));
+        assert_eq!(message.opk_id, Some(7));
+        assert_eq!(message.opk, None);
+        assert!(message.last_resort);
+
+        let legacy_bundle = PreKeyBundle {
+            ik: bob_ik.verifying_key(),
+            opk: Some(bob_opk),
+            spk: bob_spk,
+            spk_id: None,
+            opk_id: None,
+            last_resort: false,
+            kem_pre_key: None,
+            kem_pre_key_id: None,
+            kem_last_resort: false,
+            suite: None,
+        };
+        let (_, message) =
+            initiate_send(legacy_bundle, "alice".to_owned(), &alice_ik, b"Hello Bob!")?;
+        assert_eq!(message.opk_id, None);
+        assert_eq!(message.opk, Some(bob_opk));
+        assert!(!message.last_resort);
+        Ok(())
+    }
+
     #[test]
     fn x3dh_invalid_bundle_signature() -> Result<()> {
         let bob_spk = create_prekey_bundle(&SigningKey::generate(&mut OsRng), 1);
@@ -442,6 +858,13 @@ mod tests {
             ik: SigningKey::generate(&mut OsRng).verifying_key(),
             opk: None,
             spk: bob_spk.clone(),
+            spk_id: None,
+            opk_id: None,
+            last_resort: false,
+            kem_pre_key: None,
+            kem_pre_key_id: None,
+            kem_last_resort: false,
+            suite: None,
         };
         assert_eq!(
             initiate_send(
@@ -456,6 +879,100 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn x3dh_associated_data_binds_identity_keys() -> Result<()> {
+        let bob_ik = SigningKey::generate(&mut OsRng);
+        let (bundle, bob_spk_secret) = suite_bundle(&bob_ik, None);
+        let alice_ik = SigningKey::generate(&mut OsRng);
+        let (sk, message) = initiate_send(bundle, "alice".to_owned(), &alice_ik, b"Hello Bob!")?;
+        let (alice_pub, bob_pub) = (alice_ik.verifying_key(), bob_ik.verifying_key());
+
+        let ad = associated_data(&alice_pub, &bob_pub, None);
+        assert_eq!(ad, [alice_pub.to_bytes(), bob_pub.to_bytes()].concat());
+        let open_with = |ad: &[u8]| open(CipherSuite::V1, &sk, &message.ciphertext, ad);
+        assert_eq!(open_with(&ad)?, b"Hello Bob!");
+        // The receive path derives the same AD.
+        let (_, decrypted) = initiate_recv(
+            &bob_ik,
+            &bob_spk_secret,
+            &alice_pub,
+            message.ek,
+            None,
+            None,
+            message.suite,
+            message.version,
+            &message.ciphertext,
+        )?;
+        assert_eq!(decrypted, b"Hello Bob!");
+
+        // Changing either identity key, or any bit of one, fails to authenticate.
+        let mallory = SigningKey::generate(&mut OsRng).verifying_key();
+        for ad in [
+            associated_data(&mallory, &bob_pub, None),
+            associated_data(&alice_pub, &mallory, None),
+            associated_data(&bob_pub, &alice_pub, None),
+            // The receiver's private seed in place of its public key.
+            [alice_pub.to_bytes(), bob_ik.to_bytes()].concat(),
+        ] {
+            assert!(matches!(open_with(&ad), Err(AeadError::Encrypt)));
+        }
+        for i in 0..ad.len() {
+            let mut flipped = ad.clone();
+            flipped[i] ^= 1;
+            assert!(
+                matches!(open_with(&flipped), Err(AeadError::Encrypt)),
+                "{i}"
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn x3dh_context_binds_to_ciphertext() -> Result<()> {
+        let bob_ik = SigningKey::generate(&mut OsRng);
+        let (bundle, bob_spk_secret) = suite_bundle(&bob_ik, None);
+        let alice_ik = SigningKey::generate(&mut OsRng);
+        let recv = |message: &super::Message, context: &[u8]| {
+            initiate_recv_with_context(
+                &bob_ik,
+                &bob_spk_secret,
+                &alice_ik.verifying_key(),
+                message.ek,
+                None,
+                None,
+                message.suite,
+                message.version,
+                &message.ciphertext,
+                context,
+            )
+            .map(|(_, plaintext)| plaintext)
+        };
+
+        let (_, message) = initiate_send_with_context(
+            bundle.clone(),
+            "alice".to_owned(),
+            &alice_ik,
+            b"Hello Bob!",
+            b"context",
+        )?;
+        assert_eq!(recv(&message, b"context")?, b"Hello Bob!");
+        for context in [&b""[..], b"contexts", b"other"] {
+            assert_eq!(
+                recv(&message, context),
+                Err(X3DHError::Aead(AeadError::Encrypt))
+            );
+        }
+
+        // No context is the AD messages have always had.
+        let (_, message) = initiate_send(bundle, "alice".to_owned(), &alice_ik, b"Hello Bob!")?;
+        assert_eq!(recv(&message, b"")?, b"Hello Bob!");
+        assert_eq!(
+            recv(&message, b"context"),
+            Err(X3DHError::Aead(AeadError::Encrypt))
+        );
+        Ok(())
+    }
+
     #[test]
     fn x3dh_invalid_ciphertext() -> Result<()> {
         let bob_ik = SigningKey::generate(&mut OsRng);
@@ -471,6 +988,13 @@ mod tests {
             ik: bob_ik.verifying_key(),
             opk: None,
             spk: bob_spk.clone(),
+            spk_id: None,
+            opk_id: None,
+            last_resort: false,
+            kem_pre_key: None,
+            kem_pre_key_id: None,
+            kem_last_resort: false,
+            suite: None,
         };
         let (_, message) = initiate_send(bundle, "alice".to_owned(), &alice_ik, b"Hello Bob!")?;
 
@@ -481,6 +1005,9 @@ mod tests {
                 &message.sender_ik,
                 message.ek,
                 None,
+                None,
+                message.suite,
+                message.version,
                 b"invalid ciphertext",
             ),
             Err(X3DHError::Aead(AeadError::Tag(b'i')))
@@ -488,4 +1015,597 @@ mod tests {
 
         Ok(())
     }
+
+    /// Bob's prekey bundle registered with `suite`, and the secret for its signed prekey.
+    fn suite_bundle(bob_ik: &SigningKey, suite: Option<u32>) -> (PreKeyBundle, X25519StaticSecret) {
+        let bob_spk = create_prekey_bundle(bob_ik, 1);
+        let bob_spk_secret = bob_spk.bundle[0].clone().0;
+        let bundle = PreKeyBundle {
+            ik: bob_ik.verifying_key(),
+            opk: None,
+            spk: SignedPreKey {
+                pre_key: bob_spk.bundle[0].1,
+                signature: bob_spk.signature,
+            },
+            spk_id: Some(1),
+            opk_id: None,
+            last_resort: false,
+            kem_pre_key: None,
+            kem_pre_key_id: None,
+            kem_last_resort: false,
+            suite,
+        };
+        (bundle, bob_spk_secret)
+    }
+
+    #[test]
+    fn x3dh_send_recv_suite_v1() -> Result<()> {
+        let bob_ik = SigningKey::generate(&mut OsRng);
+        let (bundle, bob_spk_secret) = suite_bundle(&bob_ik, Some(CipherSuite::V1.id()));
+        let alice_ik = SigningKey::generate(&mut OsRng);
+
+        let (send_sk, message) =
+            initiate_send(bundle, "alice".to_owned(), &alice_ik, b"Hello Bob!")?;
+        assert_eq!(message.suite, Some(1));
+
+        let (recv_sk, decrypted) = initiate_recv(
+            &bob_ik,
+            &bob_spk_secret,
+            &message.sender_ik,
+            message.ek,
+            None,
+            None,
+            message.suite,
+            message.version,
+            &message.ciphertext,
+        )?;
+        assert_eq!(send_sk, recv_sk);
+        assert_eq!(b"Hello Bob!".to_vec(), decrypted);
+        Ok(())
+    }
+
+    #[test]
+    fn x3dh_send_recv_binary() -> Result<()> {
+        let bob_ik = SigningKey::generate(&mut OsRng);
+        let (bundle, bob_spk_secret) = suite_bundle(&bob_ik, Some(CipherSuite::CURRENT.id()));
+        let alice_ik = SigningKey::generate(&mut OsRng);
+        // Not UTF-8, with NULs inside and at either end.
+        let plaintext = b"\0\xff\xfe\0binary\x80\0";
+
+        let (_, message) = initiate_send(bundle, "alice".to_owned(), &alice_ik, plaintext)?;
+        let (_, decrypted) = initiate_recv(
+            &bob_ik,
+            &bob_spk_secret,
+            &message.sender_ik,
+            message.ek,
+            None,
+            None,
+            message.suite,
+            message.version,
+            &message.ciphertext,
+        )?;
+        assert_eq!(plaintext.to_vec(), decrypted);
+        Ok(())
+    }
+
+    #[test]
+    fn x3dh_send_recv_suite_v2() -> Result<()> {
+        let bob_ik = SigningKey::generate(&mut OsRng);
+        let (bundle, bob_spk_secret) = suite_bundle(&bob_ik, Some(CipherSuite::V2.id()));
+        let alice_ik = SigningKey::generate(&mut OsRng);
+
+        let (send_sk, message) =
+            initiate_send(bundle, "alice".to_owned(), &alice_ik, b"Hello Bob!")?;
+        assert_eq!(message.suite, Some(2));
+
+        let (recv_sk, decrypted) = initiate_recv(
+            &bob_ik,
+            &bob_spk_secret,
+            &message.sender_ik,
+            message.ek,
+            None,
+            None,
+            message.suite,
+            message.version,
+            &message.ciphertext,
+        )?;
+        assert_eq!(send_sk, recv_sk);
+        assert_eq!(b"Hello Bob!".to_vec(), decrypted);
+        Ok(())
+    }
+
+    #[test]
+    fn x3dh_cross_suite_fails_to_decrypt() -> Result<()> {
+        let bob_ik = SigningKey::generate(&mut OsRng);
+        let alice_ik = SigningKey::generate(&mut OsRng);
+        for (sent, claimed) in [
+            (CipherSuite::V1, CipherSuite::V2),
+            (CipherSuite::V2, CipherSuite::V1),
+        ] {
+            let (bundle, bob_spk_secret) = suite_bundle(&bob_ik, Some(sent.id()));
+            let (_, message) = initiate_send(bundle, "alice".to_owned(), &alice_ik, b"Hello Bob!")?;
+            assert!(initiate_recv(
+                &bob_ik,
+                &bob_spk_secret,
+                &message.sender_ik,
+                message.ek,
+                None,
+                None,
+                Some(claimed.id()),
+                message.version,
+                &message.ciphertext,
+            )
+            .is_err());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn x3dh_unsupported_suite() -> Result<()> {
+        let bob_ik = SigningKey::generate(&mut OsRng);
+        let alice_ik = SigningKey::generate(&mut OsRng);
+        let (bundle, _) = suite_bundle(&bob_ik, Some(99));
+        assert_eq!(
+            initiate_send(bundle, "alice".to_owned(), &alice_ik, b"Hello Bob!"),
+            Err(X3DHError::UnsupportedSuite(99))
+        );
+
+        let (bundle, bob_spk_secret) = suite_bundle(&bob_ik, Some(CipherSuite::V1.id()));
+        let (_, message) = initiate_send(bundle, "alice".to_owned(), &alice_ik, b"Hello Bob!")?;
+        assert_eq!(
+            initiate_recv(
+                &bob_ik,
+                &bob_spk_secret,
+                &message.sender_ik,
+                message.ek,
+                None,
+                None,
+                Some(99),
+                message.version,
+                &message.ciphertext,
+            ),
+            Err(X3DHError::UnsupportedSuite(99))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn x3dh_suite_mismatch_fails_to_decrypt() -> Result<()> {
+        let bob_ik = SigningKey::generate(&mut OsRng);
+        let (bundle, bob_spk_secret) = suite_bundle(&bob_ik, Some(CipherSuite::V1.id()));
+        let alice_ik = SigningKey::generate(&mut OsRng);
+        let (_, message) = initiate_send(bundle, "alice".to_owned(), &alice_ik, b"Hello Bob!")?;
+
+        // Claiming the message predates cipher suites changes the associated data it was sealed
+        // with.
+        assert_eq!(
+            initiate_recv(
+                &bob_ik,
+                &bob_spk_secret,
+                &message.sender_ik,
+                message.ek,
+                None,
+                None,
+                None,
+                message.version,
+                &message.ciphertext,
+            ),
+            Err(X3DHError::Aead(AeadError::Encrypt))
+        );
+        Ok(())
+    }
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{b:02x}")).collect()
+    }
+
+    fn unhex(hex: &str) -> Vec<u8> {
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn unhex_key(hex: &str) -> Result<[u8; 32]> {
+        unhex(hex).try_into().ok().context("keys are 32 bytes")
+    }
+
+    /// Hands out fixed bytes in order, so a test picks every random value a protocol run draws.
+    struct FixedRng(VecDeque<u8>);
+
+    impl RngCore for FixedRng {
+        fn next_u32(&mut self) -> u32 {
+            rand_core::impls::next_u32_via_fill(self)
+        }
+
+        fn next_u64(&mut self) -> u64 {
+            rand_core::impls::next_u64_via_fill(self)
+        }
+
+        fn fill_bytes(&mut self, dest: &mut [u8]) {
+            for byte in dest {
+                *byte = self.0.pop_front().expect("FixedRng ran out of bytes");
+            }
+        }
+
+        fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+            self.fill_bytes(dest);
+            Ok(())
+        }
+    }
+
+    impl CryptoRng for FixedRng {}
+
+    #[derive(Deserialize)]
+    struct Vectors {
+        vectors: Vec<Vector>,
+    }
+
+    /// See testdata/x3dh_vectors.json for what each field holds.
+    #[derive(Deserialize)]
+    struct Vector {
+        name: String,
+        alice_identity_seed: String,
+        bob_identity_seed: String,
+        bob_signed_pre_key: String,
+        bob_one_time_pre_key: Option<String>,
+        cipher_suite: Option<u32>,
+        protocol_version: u32,
+        message: String,
+        ephemeral_secret: String,
+        nonce: String,
+        ephemeral_key: String,
+        sk: String,
+        ciphertext: String,
+    }
+
+    #[test]
+    fn x3dh_vectors() -> Result<()> {
+        let Vectors { vectors } =
+            serde_json::from_str(include_str!("../testdata/x3dh_vectors.json"))?;
+        assert!(!vectors.is_empty());
+        for vector in vectors {
+            let name = &vector.name;
+            let alice_ik = SigningKey::from_bytes(&unhex_key(&vector.alice_identity_seed)?);
+            let bob_ik = SigningKey::from_bytes(&unhex_key(&vector.bob_identity_seed)?);
+            let bob_spk_secret = X25519StaticSecret::from(unhex_key(&vector.bob_signed_pre_key)?);
+            let bob_spk_pub = X25519PublicKey::from(&bob_spk_secret);
+            let bob_opk_secret = vector
+                .bob_one_time_pre_key
+                .as_deref()
+                .map(unhex_key)
+                .transpose()?
+                .map(X25519StaticSecret::from);
+            let bundle = PreKeyBundle {
+                ik: bob_ik.verifying_key(),
+                opk: bob_opk_secret.as_ref().map(X25519PublicKey::from),
+                spk: SignedPreKey {
+                    pre_key: bob_spk_pub,
+                    signature: sign_bundle(&bob_ik, &[(bob_spk_secret.clone(), bob_spk_pub)]),
+                },
+                spk_id: None,
+                opk_id: None,
+                last_resort: false,
+                kem_pre_key: None,
+                kem_pre_key_id: None,
+                kem_last_resort: false,
+                suite: vector.cipher_suite,
+            };
+            let plaintext = unhex(&vector.message);
+            let mut rng = FixedRng(
+                [unhex(&vector.ephemeral_secret), unhex(&vector.nonce)]
+                    .concat()
+                    .into(),
+            );
+
+            let (sk, message) = initiate_send_with_rng(
+                bundle,
+                "alice".to_owned(),
+                &alice_ik,
+                &plaintext,
+                &[],
+                &mut rng,
+            )?;
+            assert!(rng.0.is_empty(), "{name}");
+            assert_eq!(message.version, Some(vector.protocol_version), "{name}");
+            assert_eq!(message.suite, vector.cipher_suite, "{name}");
+            assert_eq!(hex(message.ek.as_bytes()), vector.ephemeral_key, "{name}");
+            assert_eq!(hex(&sk), vector.sk, "{name}");
+            assert_eq!(hex(&message.ciphertext), vector.ciphertext, "{name}");
+
+            let (recv_sk, decrypted) = initiate_recv(
+                &bob_ik,
+                &bob_spk_secret,
+                &alice_ik.verifying_key(),
+                X25519PublicKey::from(unhex_key(&vector.ephemeral_key)?),
+                bob_opk_secret,
+                None,
+                vector.cipher_suite,
+                Some(vector.protocol_version),
+                &unhex(&vector.ciphertext),
+            )?;
+            assert_eq!(hex(&recv_sk), vector.sk, "{name}");
+            assert_eq!(decrypted, plaintext, "{name}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn kdf_known_answers() {
+        // Changing the derivation for a version that has shipped breaks every peer on it.
+        let km = [1; 96];
+        let kem_ss = [2; 32];
+        for (version, kem_ss, sk) in [
+            (
+                None,
+                None,
+                "de638995551ec3d17ec0804a291077cd8f032f0c612c0052987fe945d738d633",
+            ),
+            (
+                None,
+                Some(&kem_ss),
+                "b676342507b64235ba09981265d36dc0f06d7d30228d97aa2b7d843da0031143",
+            ),
+            (
+                Some(1),
+                None,
+                "7cf24ad26fb93499fad5bd9597f8b7d96bbe47a0263159326de71094c4b14b90",
+            ),
+            (
+                Some(1),
+                Some(&kem_ss),
+                "95cf90524fc1028b33e09dc552a40c0c6b63f4fb601aaf81c1c626fafa60e306",
+            ),
+        ] {
+            for suite in [CipherSuite::V1, CipherSuite::V2] {
+                assert_eq!(hex(&kdf(suite, version, &km, kem_ss)), sk, "{version:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn x3dh_send_recv_version() -> Result<()> {
+        let bob_ik = SigningKey::generate(&mut OsRng);
+        let (bundle, bob_spk_secret) = suite_bundle(&bob_ik, None);
+        let alice_ik = SigningKey::generate(&mut OsRng);
+        let (send_sk, message) =
+            initiate_send(bundle, "alice".to_owned(), &alice_ik, b"Hello Bob!")?;
+        assert_eq!(message.version, Some(PROTOCOL_VERSION));
+
+        let recv = |version| {
+            initiate_recv(
+                &bob_ik,
+                &bob_spk_secret,
+                &message.sender_ik,
+                message.ek,
+                None,
+                None,
+                message.suite,
+                version,
+                &message.ciphertext,
+            )
+        };
+        let (recv_sk, decrypted) = recv(message.version)?;
+        assert_eq!(send_sk, recv_sk);
+        assert_eq!(b"Hello Bob!".to_vec(), decrypted);
+        // Claiming the message predates versions derives another SK.
+        assert_eq!(recv(None), Err(X3DHError::Aead(AeadError::Encrypt)));
+        Ok(())
+    }
+
+    #[test]
+    fn x3dh_unsupported_version() -> Result<()> {
+        let bob_ik = SigningKey::generate(&mut OsRng);
+        let (bundle, bob_spk_secret) = suite_bundle(&bob_ik, None);
+        let alice_ik = SigningKey::generate(&mut OsRng);
+        let (_, mut message) = initiate_send(bundle, "alice".to_owned(), &alice_ik, b"Hello Bob!")?;
+        // A message from a sender on the next version, which this build can't derive SK for.
+        message.version = Some(PROTOCOL_VERSION + 1);
+
+        assert_eq!(
+            initiate_recv(
+                &bob_ik,
+                &bob_spk_secret,
+                &message.sender_ik,
+                message.ek,
+                None,
+                None,
+                message.suite,
+                message.version,
+                &message.ciphertext,
+            ),
+            Err(X3DHError::UnsupportedVersion(2))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn pqxdh_key_agreement_requires_kem_secret() -> Result<()> {
+        let bob_ik = SigningKey::generate(&mut OsRng);
+        let bob_spk = create_prekey_bundle(&bob_ik, 1);
+        let bob_spk_secret = bob_spk.bundle[0].clone().0;
+        let bob_spk = SignedPreKey {
+            pre_key: bob_spk.bundle[0].1,
+            signature: bob_spk.signature,
+        };
+        let alice_ik = SigningKey::generate(&mut OsRng);
+        let kem_ss = [7; 32];
+
+        let X3DHSendKeyAgreement { ek, sk } = initiate_send_get_sk(
+            bob_ik.verifying_key(),
+            &bob_spk,
+            None,
+            &alice_ik,
+            Some(&kem_ss),
+            CipherSuite::V1,
+            Some(PROTOCOL_VERSION),
+            &mut OsRng,
+        )?;
+        let recv_sk = |kem_ss| {
+            initiate_recv_get_sk(
+                &alice_ik.verifying_key(),
+                ek,
+                None,
+                &bob_ik,
+                &bob_spk_secret,
+                kem_ss,
+                CipherSuite::V1,
+                Some(PROTOCOL_VERSION),
+            )
+        };
+        assert_eq!(sk, recv_sk(Some(&kem_ss)));
+        // Dropping the KEM secret, or swapping in another, must not yield the same key.
+        assert_ne!(sk, recv_sk(None));
+        assert_ne!(sk, recv_sk(Some(&[8; 32])));
+        Ok(())
+    }
+
+    #[cfg(not(feature = "pqxdh"))]
+    #[test]
+    fn x3dh_without_kem_support_ignores_kem_pre_key() -> Result<()> {
+        let bob_ik = SigningKey::generate(&mut OsRng);
+        let bob_spk = create_prekey_bundle(&bob_ik, 1);
+        let bob_spk_secret = bob_spk.bundle[0].clone().0;
+        let bob_spk = SignedPreKey {
+            pre_key: bob_spk.bundle[0].1,
+            signature: bob_spk.signature,
+        };
+        let alice_ik = SigningKey::generate(&mut OsRng);
+
+        let kem_pre_key = KemPublicKey::from_bytes(&[7; kem::PUBLIC_KEY_LEN])?;
+        let bundle = PreKeyBundle {
+            ik: bob_ik.verifying_key(),
+            opk: None,
+            spk: bob_spk,
+            spk_id: None,
+            opk_id: None,
+            last_resort: false,
+            kem_pre_key: Some(sign_kem_pre_key(&bob_ik, &kem_pre_key)),
+            kem_pre_key_id: Some(1),
+            kem_last_resort: false,
+            suite: None,
+        };
+        let (send_sk, message) =
+            initiate_send(bundle, "alice".to_owned(), &alice_ik, b"Hello Bob!")?;
+        assert_eq!(message.kem_pre_key_id, None);
+        assert_eq!(message.kem_ciphertext, None);
+
+        let (recv_sk, decrypted) = initiate_recv(
+            &bob_ik,
+            &bob_spk_secret,
+            &message.sender_ik,
+            message.ek,
+            None,
+            None,
+            message.suite,
+            message.version,
+            &message.ciphertext,
+        )?;
+        assert_eq!(send_sk, recv_sk);
+        assert_eq!(b"Hello Bob!".to_vec(), decrypted);
+        Ok(())
+    }
+
+    /// Bob's prekey bundle with a one time KEM prekey, and the secrets for it.
+    #[cfg(feature = "pqxdh")]
+    fn pqxdh_bundle(
+        bob_ik: &SigningKey,
+    ) -> Result<(PreKeyBundle, X25519StaticSecret, kem::KemSecretKey)> {
+        let bob_spk = create_prekey_bundle(bob_ik, 1);
+        let bob_spk_secret = bob_spk.bundle[0].clone().0;
+        let kem_secret = kem::generate()?;
+        let bundle = PreKeyBundle {
+            ik: bob_ik.verifying_key(),
+            opk: None,
+            spk: SignedPreKey {
+                pre_key: bob_spk.bundle[0].1,
+                signature: bob_spk.signature,
+            },
+            spk_id: Some(1),
+            opk_id: None,
+            last_resort: false,
+            kem_pre_key: Some(sign_kem_pre_key(bob_ik, &kem_secret.public_key())),
+            kem_pre_key_id: Some(5),
+            kem_last_resort: false,
+            suite: None,
+        };
+        Ok((bundle, bob_spk_secret, kem_secret))
+    }
+
+    #[cfg(feature = "pqxdh")]
+    #[test]
+    fn pqxdh_send_recv() -> Result<()> {
+        let bob_ik = SigningKey::generate(&mut OsRng);
+        let (bundle, bob_spk_secret, bob_kem_secret) = pqxdh_bundle(&bob_ik)?;
+        let alice_ik = SigningKey::generate(&mut OsRng);
+
+        let (send_sk, message) =
+            initiate_send(bundle, "alice".to_owned(), &alice_ik, b"Hello Bob!")?;
+        assert_eq!(message.kem_pre_key_id, Some(5));
+        assert!(!message.kem_last_resort);
+        let kem_ciphertext = message.kem_ciphertext.clone().unwrap();
+        assert_eq!(kem_ciphertext.len(), kem::CIPHERTEXT_LEN);
+
+        let (recv_sk, decrypted) = initiate_recv(
+            &bob_ik,
+            &bob_spk_secret,
+            &message.sender_ik,
+            message.ek,
+            None,
+            Some((bob_kem_secret, &kem_ciphertext)),
+            message.suite,
+            message.version,
+            &message.ciphertext,
+        )?;
+        assert_eq!(send_sk, recv_sk);
+        assert_eq!(b"Hello Bob!".to_vec(), decrypted);
+        Ok(())
+    }
+
+    #[cfg(feature = "pqxdh")]
+    #[test]
+    fn pqxdh_stripped_kem_fields_fail_to_decrypt() -> Result<()> {
+        let bob_ik = SigningKey::generate(&mut OsRng);
+        let (bundle, bob_spk_secret, _) = pqxdh_bundle(&bob_ik)?;
+        let alice_ik = SigningKey::generate(&mut OsRng);
+        let (_, mut message) = initiate_send(bundle, "alice".to_owned(), &alice_ik, b"Hello Bob!")?;
+
+        // An attacker strips the KEM fields, hoping Bob falls back to classic X3DH.
+        message.kem_pre_key_id = None;
+        message.kem_ciphertext = None;
+        assert!(matches!(
+            initiate_recv(
+                &bob_ik,
+                &bob_spk_secret,
+                &message.sender_ik,
+                message.ek,
+                None,
+                None,
+                message.suite,
+                message.version,
+                &message.ciphertext,
+            ),
+            Err(X3DHError::Aead(AeadError::Encrypt))
+        ));
+        Ok(())
+    }
+
+    #[cfg(feature = "pqxdh")]
+    #[test]
+    fn pqxdh_invalid_kem_pre_key_signature() -> Result<()> {
+        let bob_ik = SigningKey::generate(&mut OsRng);
+        let (mut bundle, _, _) = pqxdh_bundle(&bob_ik)?;
+        let forged = kem::generate()?.public_key();
+        bundle.kem_pre_key = Some(sign_kem_pre_key(&SigningKey::generate(&mut OsRng), &forged));
+        assert_eq!(
+            initiate_send(
+                bundle,
+                "alice".to_owned(),
+                &SigningKey::generate(&mut OsRng),
+                b"Hello Bob!"
+            ),
+            Err(X3DHError::SignatureValidation)
+        );
+        Ok(())
+    }
 }
@@ -0,0 +1,238 @@
+use chacha20poly1305::aead::rand_core::{CryptoRng, RngCore};
+#[cfg(feature = "getrandom")]
+use chacha20poly1305::aead::OsRng;
+use curve25519_dalek::{
+    edwards::{CompressedEdwardsY, EdwardsPoint},
+    montgomery::MontgomeryPoint,
+    scalar::{clamp_integer, Scalar},
+};
+use ed25519_dalek::{Signature, SignatureError, SigningKey, VerifyingKey};
+use sha2::{Digest, Sha512};
+use x25519_dalek::{PublicKey as X25519PublicKey, StaticSecret as X25519StaticSecret};
+
+/*
+    XEdDSA - See https://signal.org/docs/specifications/xeddsa/
+    Signs with an X25519 private key, so a single identity key serves for both DH and signatures.
+    k - An X25519 private key. The public key is its Montgomery u-coordinate.
+    A, a - The Edwards public key and private scalar k maps to. A's sign bit is always 0, which
+    flips a to -k when k's own Edwards point has its sign bit set.
+    Z - 64 random bytes mixed into the nonce so that a faulty signer can't leak a.
+    Signatures are R || s like Ed25519 ones and verify as Ed25519 signatures under A.
+*/
+
+/// Converts an Ed25519 signing key to its birationally equivalent X25519 key.
+pub fn to_x25519(key: &SigningKey) -> X25519StaticSecret {
+    X25519StaticSecret::from(key.to_scalar_bytes())
+}
+
+/// Converts an Ed25519 public key to its birationally equivalent X25519 key.
+pub fn to_x25519_pub(key: &VerifyingKey) -> X25519PublicKey {
+    X25519PublicKey::from(key.to_montgomery().to_bytes())
+}
+
+/// hash_i(X) = hash(2^b - 1 - i || X), where b is 256 bits.
+fn hash_i(i: u8, inputs: &[&[u8]]) -> Scalar {
+    let mut prefix = [0xFF; 32];
+    prefix[0] -= i;
+    let mut hasher = Sha512::new().chain_update(prefix);
+    for input in inputs {
+        hasher.update(input);
+    }
+    Scalar::from_bytes_mod_order_wide(&hasher.finalize().into())
+}
+
+/// hash(R || A || M) reduced mod q, as Ed25519 computes it.
+fn challenge(r: &CompressedEdwardsY, a: &CompressedEdwardsY, message: &[u8]) -> Scalar {
+    let hash = Sha512::new()
+        .chain_update(r.as_bytes())
+        .chain_update(a.as_bytes())
+        .chain_update(message)
+        .finalize();
+    Scalar::from_bytes_mod_order_wide(&hash.into())
+}
+
+/// Returns the Edwards public key A with sign bit 0 and the private scalar a for `k`.
+pub fn calculate_key_pair(k: &X25519StaticSecret) -> (CompressedEdwardsY, Scalar) {
+    let k = Scalar::from_bytes_mod_order(clamp_integer(k.to_bytes()));
+    let e = EdwardsPoint::mul_base(&k).compress();
+    let a = if e.as_bytes()[31] & 0x80 != 0 { -k } else { k };
+    let mut a_pub = e.to_bytes();
+    a_pub[31] &= 0x7F;
+    (CompressedEdwardsY(a_pub), a)
+}
+
+/// Signs `message` with the X25519 private key `k`.
+#[cfg(feature = "getrandom")]
+pub fn sign(k: &X25519StaticSecret, message: &[u8]) -> Signature {
+    sign_with_rng(k, message, &mut OsRng)
+}
+
+/// Like `sign`, but draws Z from `rng`.
+pub fn sign_with_rng<R: RngCore + CryptoRng>(
+    k: &X25519StaticSecret,
+    message: &[u8],
+    rng: &mut R,
+) -> Signature {
+    let mut z = [0u8; 64];
+    rng.fill_bytes(&mut z);
+    sign_with_nonce(k, message, &z)
+}
+
+fn sign_with_nonce(k: &X25519StaticSecret, message: &[u8], z: &[u8; 64]) -> Signature {
+    let (a_pub, a) = calculate_key_pair(k);
+    let r = hash_i(1, &[a.as_bytes(), message, z]);
+    let r_pub = EdwardsPoint::mul_base(&r).compress();
+    let h = challenge(&r_pub, &a_pub, message);
+    let s = r + h * a;
+    Signature::from_components(r_pub.to_bytes(), s.to_bytes())
+}
+
+/// Verifies an XEdDSA `signature` over `message` by the X25519 public key `u`.
+pub fn verify(
+    u: &X25519PublicKey,
+    message: &[u8],
+    signature: &Signature,
+) -> Result<(), SignatureError> {
+    let s = signature.s_bytes();
+    // u must be a canonical field element and s must fit in |q| = 253 bits.
+    if !is_canonical_field_element(u.as_bytes()) || s[31] & 0xE0 != 0 {
+        return Err(SignatureError::new());
+    }
+    let a_pub = MontgomeryPoint(u.to_bytes())
+        .to_edwards(0)
+        .ok_or_else(SignatureError::new)?;
+    let r_pub = CompressedEdwardsY(*signature.r_bytes());
+    let h = challenge(&r_pub, &a_pub.compress(), message);
+    let r_check = EdwardsPoint::vartime_double_scalar_mul_basepoint(
+        &h,
+        &-a_pub,
+        &Scalar::from_bytes_mod_order(*s),
+    );
+    if r_check.compress() == r_pub {
+        Ok(())
+    } else {
+        Err(SignatureError::new())
+    }
+}
+
+/// Whether `bytes` encode an integer less than p = 2^255 - 19.
+fn is_canonical_field_element(bytes: &[u8; 32]) -> bool {
+    let top_is_max = bytes[31] == 0x7F && bytes[1..31].iter().all(|&b| b == 0xFF);
+    bytes[31] & 0x80 == 0 && !(top_is_max && bytes[0] >= 0xED)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::xeddsa::*;
+    use anyhow::Result;
+    use chacha20poly1305::aead::OsRng;
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{b:02x}")).collect()
+    }
+
+    fn unhex<const N: usize>(hex: &str) -> [u8; N] {
+        let bytes: Vec<u8> = (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect();
+        bytes.try_into().unwrap()
+    }
+
+    // The XEdDSA spec publishes no test vectors. These come from a separate implementation of
+    // the spec on RFC 8032 arithmetic. The last two keys are RFC 7748's, whose Edwards points
+    // have their sign bit set, so they exercise the negated private scalar.
+    const VECTORS: [(&str, &str, &[u8], &str); 3] = [
+        (
+            "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f",
+            "8f40c5adb68f25624ae5b214ea767a6ec94d829d3d7b5e1ad1ba6f3e2138285f",
+            b"",
+            "677da9db799cf0fff09fa07e027a63741a0b36308c5ce86515a8d2a384757b09\
+             e5fc90156d6692ca509b9b7358c1e096ce574587f85ccf792b5ecb1265ed3100",
+        ),
+        (
+            "77076d0a7318a57d3c16c17251b26645df4c2f87ebc0992ab177fba51db92c2a",
+            "8520f0098930a754748b7ddcb43ef75a0dbf3a0d26381af4eba4a98eaa9b4e6a",
+            b"brongnal",
+            "4520e2d8d79837582d0086edcefea50bc1fc304583e9be42e884107777d7cb33\
+             f4b8d8a38fd3d3318826603b010950fc7ab7d952ce31a1935c9ceb6c3f74f00b",
+        ),
+        (
+            "5dab087e624a8a4b79e17f8b83800ee66f3bb1292618b6fd1c2f8b27ff88e0eb",
+            "de9edb7d7b7dc1b4d35b61c2ece435373f8343c85b78674dadfc7e146f882b4f",
+            b"brongnal",
+            "ef273ae1790bbc139180f8b1883166bead737ba5d5e1a8fe2a4016ae00c97982\
+             ed4519bc9df36c1ed4c668879f10cd657a363824797fc3c8bed432dc1010fb0a",
+        ),
+    ];
+
+    /// The nonce randomness each vector was signed with.
+    fn vector_nonce(i: usize) -> [u8; 64] {
+        match i {
+            0 => [0; 64],
+            _ => std::array::from_fn(|i| i as u8),
+        }
+    }
+
+    #[test]
+    fn xeddsa_known_answers() -> Result<()> {
+        for (i, (k, u, message, signature)) in VECTORS.into_iter().enumerate() {
+            let k = X25519StaticSecret::from(unhex::<32>(k));
+            let u = X25519PublicKey::from(unhex::<32>(u));
+            assert_eq!(X25519PublicKey::from(&k), u);
+            let signature = Signature::from_bytes(&unhex(signature));
+            assert_eq!(
+                hex(&sign_with_nonce(&k, message, &vector_nonce(i)).to_bytes()),
+                hex(&signature.to_bytes()),
+                "vector {i}"
+            );
+            assert!(verify(&u, message, &signature).is_ok(), "vector {i}");
+            assert!(verify(&u, b"other message", &signature).is_err());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn xeddsa_verifies_as_ed25519() -> Result<()> {
+        // An XEdDSA signature is an Ed25519 signature under A, whichever sign bit k's point has.
+        for _ in 0..16 {
+            let k = X25519StaticSecret::random_from_rng(OsRng);
+            let signature = sign(&k, b"Hello");
+            let (a_pub, _) = calculate_key_pair(&k);
+            VerifyingKey::from_bytes(a_pub.as_bytes())?.verify_strict(b"Hello", &signature)?;
+            assert!(verify(&X25519PublicKey::from(&k), b"Hello", &signature).is_ok());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn xeddsa_sign_verify_identity_key() {
+        let ik = SigningKey::generate(&mut OsRng);
+        let signature = sign(&to_x25519(&ik), b"Hello");
+        assert!(verify(&to_x25519_pub(&ik.verifying_key()), b"Hello", &signature).is_ok());
+
+        let other = SigningKey::generate(&mut OsRng);
+        assert!(verify(&to_x25519_pub(&other.verifying_key()), b"Hello", &signature).is_err());
+    }
+
+    #[test]
+    fn xeddsa_rejects_malformed() {
+        let k = X25519StaticSecret::random_from_rng(OsRng);
+        let u = X25519PublicKey::from(&k);
+        let signature = sign(&k, b"Hello");
+
+        // s must be less than 2^253.
+        let mut bytes = signature.to_bytes();
+        bytes[63] |= 0x20;
+        assert!(verify(&u, b"Hello", &Signature::from_bytes(&bytes)).is_err());
+
+        // u must be a canonical encoding, less than p and without the high bit set.
+        let mut p = [0xFF; 32];
+        p[0] = 0xED;
+        p[31] = 0x7F;
+        assert!(verify(&X25519PublicKey::from(p), b"Hello", &signature).is_err());
+        let mut high_bit = u.to_bytes();
+        high_bit[31] |= 0x80;
+        assert!(verify(&X25519PublicKey::from(high_bit), b"Hello", &signature).is_err());
+    }
+}
@@ -0,0 +1,49 @@
+#![cfg(target_arch = "wasm32")]
+
+use chacha20poly1305::aead::OsRng;
+use ed25519_dalek::SigningKey;
+use protocol::bundle::create_prekey_bundle;
+use protocol::x3dh::{initiate_recv, initiate_send, PreKeyBundle, SignedPreKey};
+use wasm_bindgen_test::*;
+
+wasm_bindgen_test_configure!(run_in_browser);
+
+#[wasm_bindgen_test]
+fn x3dh_send_recv() {
+    let bob_ik = SigningKey::generate(&mut OsRng);
+    let bob_spk = create_prekey_bundle(&bob_ik, 1);
+    let bob_spk_secret = bob_spk.bundle[0].clone().0;
+    let alice_ik = SigningKey::generate(&mut OsRng);
+    let bundle = PreKeyBundle {
+        ik: bob_ik.verifying_key(),
+        opk: None,
+        spk: SignedPreKey {
+            pre_key: bob_spk.bundle[0].1,
+            signature: bob_spk.signature,
+        },
+        spk_id: None,
+        opk_id: None,
+        last_resort: false,
+        kem_pre_key: None,
+        kem_pre_key_id: None,
+        kem_last_resort: false,
+        suite: None,
+    };
+
+    let (send_sk, message) =
+        initiate_send(bundle, "alice".to_owned(), &alice_ik, b"Hello Bob!").unwrap();
+    let (recv_sk, decrypted) = initiate_recv(
+        &bob_ik,
+        &bob_spk_secret,
+        &message.sender_ik,
+        message.ek,
+        None,
+        None,
+        message.suite,
+        message.version,
+        &message.ciphertext,
+    )
+    .unwrap();
+    assert_eq!(send_sk, recv_sk);
+    assert_eq!(decrypted, b"Hello Bob!");
+}
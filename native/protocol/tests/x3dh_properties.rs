@@ -0,0 +1,181 @@
+//! Property tests for the X3DH round trip over arbitrary keys, identities and plaintexts.
+#![cfg(not(target_arch = "wasm32"))]
+
+use ed25519_dalek::{SigningKey, VerifyingKey};
+use proptest::prelude::*;
+use protocol::bundle::sign_bundle;
+use protocol::x3dh::{initiate_recv, initiate_send, Message, PreKeyBundle, SignedPreKey};
+use x25519_dalek::{PublicKey as X25519PublicKey, StaticSecret as X25519StaticSecret};
+
+/// The secret keys of a conversation, from which its bundle is built.
+#[derive(Debug)]
+struct Seeds {
+    bob_ik: [u8; 32],
+    bob_spk: [u8; 32],
+    bob_opk: Option<[u8; 32]>,
+    alice_ik: [u8; 32],
+}
+
+/// Bob's keys and the bundle he publishes for them.
+struct Bob {
+    ik: SigningKey,
+    spk: X25519StaticSecret,
+    opk: Option<X25519StaticSecret>,
+    bundle: PreKeyBundle,
+}
+
+fn bob(seeds: &Seeds) -> Bob {
+    let ik = SigningKey::from_bytes(&seeds.bob_ik);
+    let spk = X25519StaticSecret::from(seeds.bob_spk);
+    let spk_pub = X25519PublicKey::from(&spk);
+    let opk = seeds.bob_opk.map(X25519StaticSecret::from);
+    let bundle = PreKeyBundle {
+        ik: ik.verifying_key(),
+        opk: opk.as_ref().map(X25519PublicKey::from),
+        spk: SignedPreKey {
+            pre_key: spk_pub,
+            signature: sign_bundle(&ik, &[(spk.clone(), spk_pub)]),
+        },
+        spk_id: None,
+        opk_id: None,
+        last_resort: false,
+        kem_pre_key: None,
+        kem_pre_key_id: None,
+        kem_last_resort: false,
+        suite: None,
+    };
+    Bob {
+        ik,
+        spk,
+        opk,
+        bundle,
+    }
+}
+
+fn recv(bob: &Bob, message: &Message) -> Result<([u8; 32], Vec<u8>), String> {
+    initiate_recv(
+        &bob.ik,
+        &bob.spk,
+        &message.sender_ik,
+        message.ek,
+        bob.opk.clone(),
+        None,
+        message.suite,
+        message.version,
+        &message.ciphertext,
+    )
+    .map_err(|e| e.to_string())
+}
+
+fn flip(bytes: &mut [u8], bit: usize) {
+    bytes[bit / 8] ^= 1 << (bit % 8);
+}
+
+prop_compose! {
+    fn seeds()(
+        bob_ik in any::<[u8; 32]>(),
+        bob_spk in any::<[u8; 32]>(),
+        bob_opk in any::<Option<[u8; 32]>>(),
+        alice_ik in any::<[u8; 32]>(),
+    ) -> Seeds {
+        Seeds { bob_ik, bob_spk, bob_opk, alice_ik }
+    }
+}
+
+prop_compose! {
+    /// Bob's keys, Alice's identity key and name, and the plaintext she sends.
+    fn conversation()(
+        seeds in seeds(),
+        alice in "[a-z0-9_]{1,32}",
+        plaintext in prop::collection::vec(any::<u8>(), 0..8192),
+    ) -> (Seeds, String, Vec<u8>) {
+        (seeds, alice, plaintext)
+    }
+}
+
+proptest! {
+    // Each case runs a full key agreement over up to 8 KiB, so keep the default suite quick.
+    #![proptest_config(ProptestConfig::with_cases(64))]
+
+    #[test]
+    fn send_recv_round_trip((seeds, alice, plaintext) in conversation()) {
+        let bob = bob(&seeds);
+        let alice_ik = SigningKey::from_bytes(&seeds.alice_ik);
+        let (send_sk, message) =
+            initiate_send(bob.bundle.clone(), alice.clone(), &alice_ik, &plaintext).unwrap();
+        prop_assert_eq!(&message.sender_identity, &alice);
+        prop_assert_eq!(message.opk, bob.bundle.opk);
+        let (recv_sk, decrypted) = recv(&bob, &message).unwrap();
+        prop_assert_eq!(send_sk, recv_sk);
+        prop_assert_eq!(decrypted, plaintext);
+    }
+
+    #[test]
+    fn corrupt_ciphertext_fails(
+        (seeds, alice, plaintext) in conversation(),
+        bit in any::<prop::sample::Index>(),
+    ) {
+        let bob = bob(&seeds);
+        let alice_ik = SigningKey::from_bytes(&seeds.alice_ik);
+        let (_, mut message) = initiate_send(bob.bundle.clone(), alice, &alice_ik, &plaintext).unwrap();
+        let bit = bit.index(message.ciphertext.len() * 8);
+        flip(&mut message.ciphertext, bit);
+        prop_assert!(recv(&bob, &message).is_err());
+    }
+
+    #[test]
+    fn corrupt_ephemeral_key_fails(
+        (seeds, alice, plaintext) in conversation(),
+        // X25519 ignores the top bit of a public key (RFC 7748 5), so it carries no information.
+        bit in 0..255usize,
+    ) {
+        let bob = bob(&seeds);
+        let alice_ik = SigningKey::from_bytes(&seeds.alice_ik);
+        let (_, mut message) = initiate_send(bob.bundle.clone(), alice, &alice_ik, &plaintext).unwrap();
+        let mut ek = message.ek.to_bytes();
+        flip(&mut ek, bit);
+        message.ek = ek.into();
+        prop_assert!(recv(&bob, &message).is_err());
+    }
+
+    #[test]
+    fn corrupt_sender_identity_key_fails(
+        (seeds, alice, plaintext) in conversation(),
+        bit in 0..256usize,
+    ) {
+        let bob = bob(&seeds);
+        let alice_ik = SigningKey::from_bytes(&seeds.alice_ik);
+        let (_, mut message) = initiate_send(bob.bundle.clone(), alice, &alice_ik, &plaintext).unwrap();
+        let mut ik = message.sender_ik.to_bytes();
+        flip(&mut ik, bit);
+        // Most flips leave the curve, which no receiver gets past parsing.
+        if let Ok(ik) = VerifyingKey::from_bytes(&ik) {
+            message.sender_ik = ik;
+            prop_assert!(recv(&bob, &message).is_err());
+        }
+    }
+
+    #[test]
+    fn corrupt_receiver_identity_key_fails(
+        (seeds, alice, plaintext) in conversation(),
+        bit in 0..256usize,
+    ) {
+        let mut bob = bob(&seeds);
+        let alice_ik = SigningKey::from_bytes(&seeds.alice_ik);
+        let (_, message) = initiate_send(bob.bundle.clone(), alice, &alice_ik, &plaintext).unwrap();
+        let mut ik = bob.ik.to_bytes();
+        flip(&mut ik, bit);
+        bob.ik = SigningKey::from_bytes(&ik);
+        prop_assert!(recv(&bob, &message).is_err());
+    }
+
+    #[test]
+    fn sends_derive_distinct_keys((seeds, alice, plaintext) in conversation()) {
+        let bob = bob(&seeds);
+        let alice_ik = SigningKey::from_bytes(&seeds.alice_ik);
+        let (first, _) =
+            initiate_send(bob.bundle.clone(), alice.clone(), &alice_ik, &plaintext).unwrap();
+        let (second, _) = initiate_send(bob.bundle.clone(), alice, &alice_ik, &plaintext).unwrap();
+        prop_assert_ne!(first, second);
+    }
+}
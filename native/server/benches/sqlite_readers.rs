@@ -0,0 +1,97 @@
+//! Compares read throughput of sqlite storage with and without read only connections while
+//! another task keeps writing. Run with `cargo bench -p server --bench sqlite_readers`.
+
+use client::memory_client::MemoryClient;
+use client::X3DHClient;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use futures::future::try_join_all;
+use server::brongnal::{AuditEntry, Storage};
+use server::sqlite_brongnal::SqliteStorage;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::runtime::Runtime;
+use tokio_rusqlite::Connection;
+
+const USERS: usize = 16;
+/// Reads made at once per iteration.
+const CONCURRENT_READS: usize = 64;
+
+async fn storage(path: &str, readers: usize) -> Arc<SqliteStorage> {
+    for suffix in ["", "-wal", "-shm"] {
+        let _ = std::fs::remove_file(format!("{path}{suffix}"));
+    }
+    let storage = SqliteStorage::new(Connection::open(path).await.unwrap())
+        .await
+        .unwrap()
+        .with_readers(readers)
+        .await
+        .unwrap();
+    for user in 0..USERS {
+        let client = MemoryClient::new();
+        storage
+            .register_user(
+                format!("user{user}").parse().unwrap(),
+                1,
+                (&client.get_ik().unwrap()).into(),
+                client.get_spk().unwrap().into(),
+            )
+            .await
+            .unwrap();
+    }
+    Arc::new(storage)
+}
+
+/// Writes to `storage` until `stop` is set.
+async fn write_load(storage: Arc<SqliteStorage>, stop: Arc<AtomicBool>) {
+    while !stop.load(Ordering::Relaxed) {
+        storage
+            .add_audit_entry(AuditEntry {
+                actor: String::from("bench"),
+                action: String::from("write"),
+                target: String::from("user0"),
+                time: SystemTime::now(),
+            })
+            .await
+            .unwrap();
+    }
+}
+
+fn reads_under_write_load(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let path = std::env::temp_dir().join(format!("brongnal-bench-{}.db3", std::process::id()));
+    let path = path.to_str().unwrap();
+
+    let mut group = c.benchmark_group("reads_under_write_load");
+    group.throughput(Throughput::Elements(CONCURRENT_READS as u64));
+    for readers in [0, 4] {
+        let storage = runtime.block_on(storage(path, readers));
+        let stop = Arc::new(AtomicBool::new(false));
+        let writer = runtime.spawn(write_load(storage.clone(), stop.clone()));
+        group.bench_with_input(
+            BenchmarkId::new("get_current_keys", readers),
+            &storage,
+            |b, storage| {
+                b.to_async(&runtime).iter(|| {
+                    try_join_all((0..CONCURRENT_READS).map(|read| {
+                        let storage = storage.clone();
+                        async move {
+                            storage
+                                .get_current_keys(&format!("user{}", read % USERS), 1)
+                                .await
+                        }
+                    }))
+                })
+            },
+        );
+        stop.store(true, Ordering::Relaxed);
+        runtime.block_on(writer).unwrap();
+    }
+    group.finish();
+    for suffix in ["", "-wal", "-shm"] {
+        let _ = std::fs::remove_file(format!("{path}{suffix}"));
+    }
+}
+
+criterion_group!(benches, reads_under_write_load);
+criterion_main!(benches);
@@ -0,0 +1,825 @@
+//! Queries operators can make about the server's users and load, and the changes they can make
+//! to them, served apart from the Brongnal service so they can be kept off the public listener.
+
+use crate::brongnal::{
+    hash_invite_code, unix_seconds, AuditEntry, BrongnalController, DeviceStats, Invite,
+};
+use crate::ip_limit::RpcClass;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::OsRng;
+use proto::admin::admin_server::Admin;
+use proto::admin::backup_database_response::Part;
+use proto::admin::{
+    BackupDatabaseRequest, BackupDatabaseResponse, BackupProgress, CreateInviteRequest,
+    CreateInviteResponse, DeleteUserRequest, DeleteUserResponse, DeviceDetail,
+    GetUserDetailRequest, GetUserDetailResponse, InviteSummary, ListInvitesRequest,
+    ListInvitesResponse, ListUsersRequest, ListUsersResponse, PurgeMessagesRequest,
+    PurgeMessagesResponse, QueueDepth, RevokeInviteRequest, RevokeInviteResponse,
+    ServerStatsRequest, ServerStatsResponse, TableRows, UserSummary,
+};
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::sync::mpsc::{self, Sender};
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::service::Interceptor;
+use tonic::{Request, Response, Result, Status};
+
+/// The header Admin callers name themselves in, for the audit log.
+pub const ACTOR_HEADER: &str = "brongnal-admin-actor";
+/// How many of the identities with the most queued messages stats name by default.
+pub const DEEPEST_QUEUES: usize = 10;
+/// How many random bytes invite codes are made from, written out in hex.
+pub const INVITE_CODE_LEN: usize = 16;
+/// How many bytes of a database backup each response carries.
+pub const BACKUP_CHUNK_LEN: usize = 64 * 1024;
+
+/// Answers the Admin service from the storage and open streams of a controller.
+pub struct AdminService {
+    controller: Arc<BrongnalController>,
+}
+
+impl AdminService {
+    pub fn new(controller: Arc<BrongnalController>) -> Self {
+        AdminService { controller }
+    }
+
+    /// Logs and records that the caller of `request` did `action` to `target`.
+    async fn audit<T>(&self, request: &Request<T>, action: &str, target: &str) -> Result<()> {
+        let actor = actor(request);
+        println!("Audit: {actor} did {action} to \"{target}\".");
+        self.controller
+            .storage()
+            .add_audit_entry(AuditEntry {
+                actor: actor.to_owned(),
+                action: action.to_owned(),
+                target: target.to_owned(),
+                time: SystemTime::now(),
+            })
+            .await
+    }
+}
+
+/// Who the caller of `request` named themselves as.
+fn actor<T>(request: &Request<T>) -> &str {
+    request
+        .metadata()
+        .get(ACTOR_HEADER)
+        .and_then(|actor| actor.to_str().ok())
+        .unwrap_or("unknown")
+}
+
+/// Admits Admin requests that carry `authorization: Bearer <token>`, or every request if there is
+/// no token to check, as when the service only listens on loopback or a unix domain socket.
+#[derive(Clone)]
+pub struct AdminAuth {
+    token: Option<String>,
+}
+
+impl AdminAuth {
+    pub fn new(token: Option<String>) -> Self {
+        AdminAuth { token }
+    }
+}
+
+impl Interceptor for AdminAuth {
+    fn call(&mut self, request: Request<()>) -> Result<Request<()>> {
+        let Some(token) = &self.token else {
+            return Ok(request);
+        };
+        let bearer = request
+            .metadata()
+            .get("authorization")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        match bearer {
+            Some(bearer) if bearer == token => Ok(request),
+            Some(_) => Err(Status::unauthenticated("wrong admin token")),
+            None => Err(Status::unauthenticated("missing admin token")),
+        }
+    }
+}
+
+/// Seconds since `time`.
+fn age(time: SystemTime) -> u64 {
+    SystemTime::now()
+        .duration_since(time)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// Sends the file at `path` down `tx` in chunks, stopping early if the caller goes away.
+fn send_file(path: &Path, tx: &Sender<Result<BackupDatabaseResponse>>) -> std::io::Result<()> {
+    let mut file = File::open(path)?;
+    let mut chunk = vec![0; BACKUP_CHUNK_LEN];
+    loop {
+        let len = file.read(&mut chunk)?;
+        if len == 0 {
+            return Ok(());
+        }
+        let response = BackupDatabaseResponse {
+            part: Some(Part::Data(chunk[..len].to_vec())),
+        };
+        if tx.blocking_send(Ok(response)).is_err() {
+            return Ok(());
+        }
+    }
+}
+
+/// Sums the stats of one identity's devices.
+fn summarize(devices: &[DeviceStats]) -> UserSummary {
+    let registered_at = devices.iter().map(|device| device.registered_at).min();
+    let spk_uploaded_at = devices.iter().map(|device| device.spk_uploaded_at).min();
+    UserSummary {
+        identity: devices.first().map(|device| device.identity.clone()),
+        display_identity: devices
+            .first()
+            .map(|device| device.display_identity.clone()),
+        device_count: Some(devices.len() as u32),
+        registered_at: registered_at.map(unix_seconds),
+        one_time_key_count: Some(devices.iter().map(|device| device.opk_count as u64).sum()),
+        queued_message_count: Some(
+            devices
+                .iter()
+                .map(|device| device.queued_messages as u64)
+                .sum(),
+        ),
+        signed_pre_key_age: spk_uploaded_at.map(age),
+    }
+}
+
+#[tonic::async_trait]
+impl Admin for AdminService {
+    async fn list_users(
+        &self,
+        _request: Request<ListUsersRequest>,
+    ) -> Result<Response<ListUsersResponse>> {
+        let devices = self.controller.storage().device_stats(None).await?;
+        // Devices come ordered by identity, so each identity's are adjacent.
+        let users = devices
+            .chunk_by(|a, b| a.identity == b.identity)
+            .map(summarize)
+            .collect();
+        Ok(Response::new(ListUsersResponse { users }))
+    }
+
+    async fn get_user_detail(
+        &self,
+        request: Request<GetUserDetailRequest>,
+    ) -> Result<Response<GetUserDetailResponse>> {
+        let identity = request
+            .into_inner()
+            .identity
+            .ok_or_else(|| Status::invalid_argument("request missing identity"))?;
+        let devices = self
+            .controller
+            .storage()
+            .device_stats(Some(&identity))
+            .await?;
+        if devices.is_empty() {
+            return Err(Status::not_found("user not found"));
+        }
+        let devices = devices
+            .into_iter()
+            .map(|device| DeviceDetail {
+                device_id: Some(device.device_id),
+                registered_at: Some(unix_seconds(device.registered_at)),
+                signed_pre_key_uploaded_at: Some(unix_seconds(device.spk_uploaded_at)),
+                one_time_key_count: Some(device.opk_count as u64),
+                queued_message_count: Some(device.queued_messages as u64),
+                has_push_token: Some(device.has_push_token),
+                connected: Some(self.controller.is_connected(&identity, device.device_id)),
+            })
+            .collect();
+        Ok(Response::new(GetUserDetailResponse {
+            identity: Some(identity),
+            devices,
+        }))
+    }
+
+    async fn server_stats(
+        &self,
+        request: Request<ServerStatsRequest>,
+    ) -> Result<Response<ServerStatsResponse>> {
+        let deepest_queue_count = request
+            .into_inner()
+            .deepest_queue_count
+            .unwrap_or(DEEPEST_QUEUES as u32);
+        let stats = self
+            .controller
+            .storage()
+            .stats(deepest_queue_count as usize)
+            .await?;
+        let limits = self.controller.ip_limits();
+        let (checkpoints, incomplete_checkpoints) = self.controller.checkpoints();
+        Ok(Response::new(ServerStatsResponse {
+            user_count: Some(stats.identities as u64),
+            device_count: Some(stats.devices as u64),
+            one_time_key_count: Some(stats.opks as u64),
+            queued_message_count: Some(stats.queued_messages as u64),
+            database_size_bytes: stats.size_bytes,
+            open_stream_count: Some(self.controller.open_streams() as u64),
+            table_rows: stats
+                .table_rows
+                .into_iter()
+                .map(|(table, rows)| TableRows {
+                    table: Some(table),
+                    rows: Some(rows as u64),
+                })
+                .collect(),
+            wal_size_bytes: stats.wal_size_bytes,
+            oldest_message_age: stats.oldest_message_at.map(age),
+            deepest_queues: stats
+                .deepest_queues
+                .into_iter()
+                .map(|(identity, depth)| QueueDepth {
+                    identity: Some(identity),
+                    queued_message_count: Some(depth as u64),
+                })
+                .collect(),
+            ping_count: Some(self.controller.pings()),
+            throttled_registration_count: Some(limits.throttled(RpcClass::Registration)),
+            throttled_pre_key_request_count: Some(limits.throttled(RpcClass::PreKeys)),
+            throttled_challenge_request_count: Some(limits.throttled(RpcClass::Challenge)),
+            wal_checkpoint_count: Some(checkpoints),
+            incomplete_wal_checkpoint_count: Some(incomplete_checkpoints),
+        }))
+    }
+
+    async fn delete_user(
+        &self,
+        request: Request<DeleteUserRequest>,
+    ) -> Result<Response<DeleteUserResponse>> {
+        let identity = request
+            .get_ref()
+            .identity
+            .clone()
+            .ok_or_else(|| Status::invalid_argument("request missing identity"))?;
+        self.controller.storage().delete_user(&identity).await?;
+        self.controller.close_streams(&identity);
+        self.audit(&request, "delete_user", &identity).await?;
+        Ok(Response::new(DeleteUserResponse {}))
+    }
+
+    async fn purge_messages(
+        &self,
+        request: Request<PurgeMessagesRequest>,
+    ) -> Result<Response<PurgeMessagesResponse>> {
+        let identity = request
+            .get_ref()
+            .identity
+            .clone()
+            .ok_or_else(|| Status::invalid_argument("request missing identity"))?;
+        let purged = self.controller.storage().purge_messages(&identity).await?;
+        self.audit(&request, "purge_messages", &identity).await?;
+        Ok(Response::new(PurgeMessagesResponse {
+            purged_count: Some(purged as u64),
+        }))
+    }
+
+    async fn create_invite(
+        &self,
+        request: Request<CreateInviteRequest>,
+    ) -> Result<Response<CreateInviteResponse>> {
+        let max_uses = request.get_ref().max_uses.unwrap_or(1);
+        if max_uses == 0 {
+            return Err(Status::invalid_argument("max_uses must be at least 1"));
+        }
+        let mut code = [0; INVITE_CODE_LEN];
+        OsRng.fill_bytes(&mut code);
+        let code = hex(&code);
+        let code_hash = hash_invite_code(&code);
+        let created_at = SystemTime::now();
+        self.controller
+            .storage()
+            .add_invite(Invite {
+                code_hash: code_hash.clone(),
+                creator: actor(&request).to_owned(),
+                max_uses,
+                uses: 0,
+                created_at,
+                expires_at: request
+                    .get_ref()
+                    .expires_in
+                    .map(|expires_in| created_at + Duration::from_secs(expires_in)),
+                revoked: false,
+            })
+            .await?;
+        self.audit(&request, "create_invite", &hex(&code_hash))
+            .await?;
+        Ok(Response::new(CreateInviteResponse {
+            code: Some(code),
+            code_hash: Some(code_hash),
+        }))
+    }
+
+    async fn revoke_invite(
+        &self,
+        request: Request<RevokeInviteRequest>,
+    ) -> Result<Response<RevokeInviteResponse>> {
+        let code_hash = request
+            .get_ref()
+            .code_hash
+            .clone()
+            .ok_or_else(|| Status::invalid_argument("request missing code_hash"))?;
+        self.controller.storage().revoke_invite(&code_hash).await?;
+        self.audit(&request, "revoke_invite", &hex(&code_hash))
+            .await?;
+        Ok(Response::new(RevokeInviteResponse {}))
+    }
+
+    async fn list_invites(
+        &self,
+        _request: Request<ListInvitesRequest>,
+    ) -> Result<Response<ListInvitesResponse>> {
+        let invites = self
+            .controller
+            .storage()
+            .invites()
+            .await?
+            .into_iter()
+            .map(|invite| InviteSummary {
+                code_hash: Some(invite.code_hash),
+                creator: Some(invite.creator),
+                max_uses: Some(invite.max_uses),
+                uses: Some(invite.uses),
+                created_at: Some(unix_seconds(invite.created_at)),
+                expires_at: invite.expires_at.map(unix_seconds),
+                revoked: Some(invite.revoked),
+            })
+            .collect();
+        Ok(Response::new(ListInvitesResponse { invites }))
+    }
+
+    type BackupDatabaseStream = ReceiverStream<Result<BackupDatabaseResponse>>;
+
+    async fn backup_database(
+        &self,
+        request: Request<BackupDatabaseRequest>,
+    ) -> Result<Response<Self::BackupDatabaseStream>> {
+        self.audit(&request, "backup_database", "database").await?;
+        let mut suffix = [0; 8];
+        OsRng.fill_bytes(&mut suffix);
+        let path = std::env::temp_dir().join(format!("brongnal-backup-{}.db3", hex(&suffix)));
+        let controller = self.controller.clone();
+        let (tx, rx) = mpsc::channel(100);
+        tokio::spawn(async move {
+            let progress_tx = tx.clone();
+            // Progress the caller is too slow to read is skipped rather than holding up the copy.
+            let progress = Box::new(move |copied_pages, total_pages| {
+                let _ = progress_tx.try_send(Ok(BackupDatabaseResponse {
+                    part: Some(Part::Progress(BackupProgress {
+                        copied_pages: Some(copied_pages),
+                        total_pages: Some(total_pages),
+                    })),
+                }));
+            });
+            if let Err(e) = controller.storage().backup(&path, progress).await {
+                eprintln!("Failed to back up the database: {e}");
+                let _ = std::fs::remove_file(&path);
+                let _ = tx.send(Err(e)).await;
+                return;
+            }
+            tokio::task::spawn_blocking(move || {
+                if let Err(e) = send_file(&path, &tx) {
+                    eprintln!("Failed to send the database backup: {e}");
+                    let _ = tx.blocking_send(Err(Status::internal(format!(
+                        "failed to read the backup: {e}"
+                    ))));
+                }
+                let _ = std::fs::remove_file(&path);
+            });
+        });
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::brongnal::{OpkQuota, Storage};
+    use crate::memory_brongnal::MemoryStorage;
+    use crate::sqlite_brongnal::SqliteStorage;
+    use anyhow::Result;
+    use client::memory_client::MemoryClient;
+    use client::X3DHClient;
+    use proto::service::Message as MessageProto;
+    use proto::SessionMessage;
+    use tokio_rusqlite::Connection;
+    use tokio_stream::StreamExt;
+    use tonic::metadata::MetadataValue;
+
+    async fn register(
+        controller: &BrongnalController,
+        identity: &str,
+        device_id: u32,
+    ) -> Result<()> {
+        let client = MemoryClient::new();
+        controller
+            .storage()
+            .register_user(
+                identity.parse()?,
+                device_id,
+                (&client.get_ik()?).into(),
+                client.get_spk()?.into(),
+            )
+            .await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn queries() -> Result<()> {
+        let controller = Arc::new(BrongnalController::new(Box::new(MemoryStorage::default())));
+        let admin = AdminService::new(controller.clone());
+        register(&controller, "bob", 1).await?;
+        register(&controller, "bob", 2).await?;
+        register(&controller, "carol", 1).await?;
+        let mut bob = MemoryClient::new();
+        controller
+            .storage()
+            .add_opks("bob", 2, bob.create_opks(4)?.pre_keys, OpkQuota::default())
+            .await?;
+        controller
+            .storage()
+            .add_message("bob", 1, MessageProto::default())
+            .await?;
+
+        let users = admin
+            .list_users(Request::new(ListUsersRequest {}))
+            .await?
+            .into_inner()
+            .users;
+        assert_eq!(users.len(), 2);
+        assert_eq!(users[0].identity.as_deref(), Some("bob"));
+        assert_eq!(users[0].device_count, Some(2));
+        assert_eq!(users[0].one_time_key_count, Some(4));
+        assert_eq!(users[0].queued_message_count, Some(1));
+        assert!(users[0].signed_pre_key_age < Some(60));
+        assert_eq!(users[1].identity.as_deref(), Some("carol"));
+        assert_eq!(users[1].device_count, Some(1));
+
+        let detail = admin
+            .get_user_detail(Request::new(GetUserDetailRequest {
+                identity: Some(String::from("bob")),
+            }))
+            .await?
+            .into_inner();
+        assert_eq!(
+            detail
+                .devices
+                .iter()
+                .map(|device| (
+                    device.device_id,
+                    device.one_time_key_count,
+                    device.connected
+                ))
+                .collect::<Vec<_>>(),
+            vec![
+                (Some(1), Some(0), Some(false)),
+                (Some(2), Some(4), Some(false))
+            ]
+        );
+        assert_eq!(
+            admin
+                .get_user_detail(Request::new(GetUserDetailRequest {
+                    identity: Some(String::from("dave")),
+                }))
+                .await
+                .unwrap_err()
+                .code(),
+            tonic::Code::NotFound
+        );
+
+        let stats = admin
+            .server_stats(Request::new(ServerStatsRequest::default()))
+            .await?
+            .into_inner();
+        assert_eq!(
+            stats,
+            ServerStatsResponse {
+                user_count: Some(2),
+                device_count: Some(3),
+                one_time_key_count: Some(4),
+                queued_message_count: Some(1),
+                database_size_bytes: None,
+                open_stream_count: Some(0),
+                table_rows: stats.table_rows.clone(),
+                wal_size_bytes: None,
+                oldest_message_age: Some(0),
+                deepest_queues: vec![QueueDepth {
+                    identity: Some(String::from("bob")),
+                    queued_message_count: Some(1),
+                }],
+                ping_count: Some(0),
+                throttled_registration_count: Some(0),
+                throttled_pre_key_request_count: Some(0),
+                throttled_challenge_request_count: Some(0),
+                wal_checkpoint_count: Some(0),
+                incomplete_wal_checkpoint_count: Some(0),
+            }
+        );
+        assert!(stats.table_rows.contains(&TableRows {
+            table: Some(String::from("pre_key")),
+            rows: Some(4),
+        }));
+        let none = admin
+            .server_stats(Request::new(ServerStatsRequest {
+                deepest_queue_count: Some(0),
+            }))
+            .await?
+            .into_inner();
+        assert_eq!(none.deepest_queues, Vec::new());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn delete_and_purge() -> Result<()> {
+        let controller = Arc::new(BrongnalController::new(Box::new(MemoryStorage::default())));
+        let admin = AdminService::new(controller.clone());
+        let storage = controller.storage();
+        register(&controller, "bob", 1).await?;
+        register(&controller, "bob", 2).await?;
+        register(&controller, "carol", 1).await?;
+        let mut bob = MemoryClient::new();
+        storage
+            .add_opks("bob", 2, bob.create_opks(4)?.pre_keys, OpkQuota::default())
+            .await?;
+        let message = |ciphertext: u8| -> MessageProto {
+            SessionMessage {
+                sender_identity: String::from("alice"),
+                ciphertext: vec![ciphertext],
+            }
+            .into()
+        };
+        storage.add_message("bob", 1, message(0)).await?;
+        storage.add_message("bob", 2, message(1)).await?;
+        storage.add_message("carol", 1, message(2)).await?;
+        let by_oncall = |identity: &str| {
+            let mut request = Request::new(identity.to_owned());
+            request
+                .metadata_mut()
+                .insert(ACTOR_HEADER, MetadataValue::from_static("oncall"));
+            request
+        };
+
+        let purge = by_oncall("bob").map(|identity| PurgeMessagesRequest {
+            identity: Some(identity),
+        });
+        let purged = admin.purge_messages(purge).await?.into_inner();
+        assert_eq!(purged.purged_count, Some(2));
+        // Bob keeps his account, but only gets messages sent after the purge.
+        assert_eq!(storage.get_devices("bob").await?, vec![1, 2]);
+        storage.add_message("bob", 2, message(3)).await?;
+        assert_eq!(storage.get_messages("bob", 1).await?, Vec::new());
+        assert_eq!(storage.get_messages("bob", 2).await?, vec![message(3)]);
+
+        let delete = |identity: &str| {
+            Request::new(DeleteUserRequest {
+                identity: Some(identity.to_owned()),
+            })
+        };
+        storage.add_message("bob", 1, message(4)).await?;
+        admin.delete_user(delete("bob")).await?;
+        assert!(!storage.user_exists("bob").await?);
+        assert_eq!(
+            admin
+                .get_user_detail(Request::new(GetUserDetailRequest {
+                    identity: Some(String::from("bob")),
+                }))
+                .await
+                .unwrap_err()
+                .code(),
+            tonic::Code::NotFound
+        );
+        // Bob's keys and messages went with him; carol's are untouched.
+        let stats = storage.stats(DEEPEST_QUEUES).await?;
+        assert_eq!(
+            (
+                stats.identities,
+                stats.devices,
+                stats.opks,
+                stats.queued_messages
+            ),
+            (1, 1, 0, 1)
+        );
+        assert_eq!(
+            admin.delete_user(delete("bob")).await.unwrap_err().code(),
+            tonic::Code::NotFound
+        );
+
+        // Only changes that were made are recorded.
+        let audit_log = storage.audit_log().await?;
+        assert_eq!(
+            audit_log
+                .iter()
+                .map(|entry| (&entry.actor[..], &entry.action[..], &entry.target[..]))
+                .collect::<Vec<_>>(),
+            vec![
+                ("oncall", "purge_messages", "bob"),
+                ("unknown", "delete_user", "bob")
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn invites() -> Result<()> {
+        let controller = Arc::new(BrongnalController::new(Box::new(MemoryStorage::default())));
+        let admin = AdminService::new(controller.clone());
+        let storage = controller.storage();
+        let mut create = Request::new(CreateInviteRequest {
+            max_uses: Some(2),
+            expires_in: Some(60),
+        });
+        create
+            .metadata_mut()
+            .insert(ACTOR_HEADER, MetadataValue::from_static("oncall"));
+        let created = admin.create_invite(create).await?.into_inner();
+        let code = created.code.unwrap();
+        let code_hash = created.code_hash.unwrap();
+        assert_eq!(code.len(), 2 * INVITE_CODE_LEN);
+        assert_eq!(hash_invite_code(&code), code_hash);
+        let single_use = admin
+            .create_invite(Request::new(CreateInviteRequest::default()))
+            .await?
+            .into_inner();
+        assert_ne!(single_use.code, Some(code));
+        assert_eq!(
+            admin
+                .create_invite(Request::new(CreateInviteRequest {
+                    max_uses: Some(0),
+                    expires_in: None,
+                }))
+                .await
+                .unwrap_err()
+                .code(),
+            tonic::Code::InvalidArgument
+        );
+
+        admin
+            .revoke_invite(Request::new(RevokeInviteRequest {
+                code_hash: Some(code_hash.clone()),
+            }))
+            .await?;
+        assert_eq!(
+            admin
+                .revoke_invite(Request::new(RevokeInviteRequest {
+                    code_hash: Some(vec![0; 32]),
+                }))
+                .await
+                .unwrap_err()
+                .code(),
+            tonic::Code::NotFound
+        );
+
+        let invites = admin
+            .list_invites(Request::new(ListInvitesRequest {}))
+            .await?
+            .into_inner()
+            .invites;
+        assert_eq!(
+            invites
+                .iter()
+                .map(|invite| (
+                    invite.creator.as_deref(),
+                    invite.max_uses,
+                    invite.expires_at.zip(invite.created_at).map(|(e, c)| e - c),
+                    invite.revoked
+                ))
+                .collect::<Vec<_>>(),
+            vec![
+                (Some("oncall"), Some(2), Some(60), Some(true)),
+                (Some("unknown"), Some(1), None, Some(false))
+            ]
+        );
+        assert_eq!(
+            storage
+                .audit_log()
+                .await?
+                .iter()
+                .map(|entry| (&entry.action[..], entry.target == hex(&code_hash)))
+                .collect::<Vec<_>>(),
+            vec![
+                ("create_invite", true),
+                ("create_invite", false),
+                ("revoke_invite", true)
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn auth() {
+        let with_header = |value: &'static str| {
+            let mut request = Request::new(());
+            request
+                .metadata_mut()
+                .insert("authorization", MetadataValue::from_static(value));
+            request
+        };
+        let mut auth = AdminAuth::new(Some(String::from("s3cret")));
+        assert!(auth.call(with_header("Bearer s3cret")).is_ok());
+        for request in [
+            Request::new(()),
+            with_header("Bearer guess"),
+            with_header("s3cret"),
+        ] {
+            assert_eq!(
+                auth.call(request).unwrap_err().code(),
+                tonic::Code::Unauthenticated
+            );
+        }
+        assert!(AdminAuth::new(None).call(Request::new(())).is_ok());
+    }
+
+    fn remove_database(path: &Path) {
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{suffix}", path.display()));
+        }
+    }
+
+    #[tokio::test]
+    async fn backup_database() -> Result<()> {
+        let in_memory = AdminService::new(Arc::new(BrongnalController::new(Box::new(
+            MemoryStorage::default(),
+        ))));
+        let mut backup = in_memory
+            .backup_database(Request::new(BackupDatabaseRequest {}))
+            .await?
+            .into_inner();
+        assert_eq!(
+            backup.next().await.unwrap().unwrap_err().code(),
+            tonic::Code::Unimplemented
+        );
+
+        let path = std::env::temp_dir().join(format!("brongnal-live-{}.db3", std::process::id()));
+        let restored_path =
+            std::env::temp_dir().join(format!("brongnal-restored-{}.db3", std::process::id()));
+        remove_database(&path);
+        remove_database(&restored_path);
+        let storage = SqliteStorage::new(Connection::open(&path).await?)
+            .await?
+            .with_readers(1)
+            .await?;
+        let controller = Arc::new(BrongnalController::new(Box::new(storage)));
+        let admin = AdminService::new(controller.clone());
+        register(&controller, "bob", 1).await?;
+        register(&controller, "carol", 1).await?;
+        let mut bob = MemoryClient::new();
+        controller
+            .storage()
+            .add_opks(
+                "bob",
+                1,
+                bob.create_opks(100)?.pre_keys,
+                OpkQuota::default(),
+            )
+            .await?;
+        for _ in 0..50 {
+            controller
+                .storage()
+                .add_message("bob", 1, MessageProto::default())
+                .await?;
+        }
+
+        let mut backup = admin
+            .backup_database(Request::new(BackupDatabaseRequest {}))
+            .await?
+            .into_inner();
+        let mut progress = None;
+        let mut data = Vec::new();
+        while let Some(response) = backup.next().await {
+            match response?.part {
+                Some(Part::Progress(BackupProgress {
+                    copied_pages,
+                    total_pages,
+                })) => progress = Some((copied_pages, total_pages)),
+                Some(Part::Data(chunk)) => data.extend(chunk),
+                None => {}
+            }
+        }
+        let (copied_pages, total_pages) = progress.unwrap();
+        assert_eq!(copied_pages, total_pages);
+
+        std::fs::write(&restored_path, data)?;
+        let restored = SqliteStorage::new(Connection::open(&restored_path).await?).await?;
+        assert_eq!(
+            restored.stats(0).await?.table_rows,
+            controller.storage().stats(0).await?.table_rows
+        );
+        assert_eq!(restored.get_opks("bob", 1).await?.len(), 100);
+        assert_eq!(restored.audit_log().await?[0].action, "backup_database");
+        drop(restored);
+        drop(admin);
+        drop(controller);
+        remove_database(&path);
+        remove_database(&restored_path);
+        Ok(())
+    }
+}
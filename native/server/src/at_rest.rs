@@ -0,0 +1,122 @@
+//! Encrypts what the server keeps on disk under a key only the server holds, for deployments that
+//! require their database to be unreadable without it.
+
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use std::path::Path;
+
+/// Bytes in an at-rest key, written out in hex in key files.
+pub const AT_REST_KEY_LEN: usize = 32;
+/// Bytes of random nonce each sealed value starts with.
+const NONCE_LEN: usize = 24;
+
+/// A key sealing values the server stores, so they are kept secret and can't be swapped for one
+/// another without it.
+#[derive(Clone)]
+pub struct AtRestKey {
+    cipher: XChaCha20Poly1305,
+}
+
+impl std::fmt::Debug for AtRestKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("AtRestKey(..)")
+    }
+}
+
+impl AtRestKey {
+    pub fn new(key: [u8; AT_REST_KEY_LEN]) -> Self {
+        AtRestKey {
+            cipher: XChaCha20Poly1305::new(&key.into()),
+        }
+    }
+
+    /// Reads a key written as hex, as by `openssl rand -hex 32`, from the file at `path`.
+    pub fn from_file(path: &Path) -> Result<Self, String> {
+        let hex = std::fs::read_to_string(path)
+            .map_err(|e| format!("failed to read {}: {e}", path.display()))?;
+        let hex = hex.trim();
+        if hex.len() != AT_REST_KEY_LEN * 2 {
+            return Err(format!(
+                "{} must hold {} hex digits",
+                path.display(),
+                AT_REST_KEY_LEN * 2
+            ));
+        }
+        let mut key = [0; AT_REST_KEY_LEN];
+        for (byte, digits) in key.iter_mut().zip(hex.as_bytes().chunks(2)) {
+            *byte = std::str::from_utf8(digits)
+                .ok()
+                .and_then(|digits| u8::from_str_radix(digits, 16).ok())
+                .ok_or_else(|| format!("{} must hold only hex digits", path.display()))?;
+        }
+        Ok(AtRestKey::new(key))
+    }
+
+    /// Encrypts `plaintext`, binding it to `context`, e.g. the row it is stored in.
+    pub fn seal(&self, plaintext: &[u8], context: &[u8]) -> Vec<u8> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: plaintext,
+                    aad: context,
+                },
+            )
+            .expect("plaintext fits in a single message");
+        [nonce.as_slice(), &ciphertext].concat()
+    }
+
+    /// Decrypts what `seal` made of a value with the same `context`, or None if it was made with
+    /// another key or context or has been tampered with.
+    pub fn open(&self, sealed: &[u8], context: &[u8]) -> Option<Vec<u8>> {
+        if sealed.len() < NONCE_LEN {
+            return None;
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        self.cipher
+            .decrypt(
+                XNonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: context,
+                },
+            )
+            .ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seal_and_open() {
+        let key = AtRestKey::new([1; AT_REST_KEY_LEN]);
+        let sealed = key.seal(b"hello", b"bob");
+        assert_ne!(key.seal(b"hello", b"bob"), sealed);
+        assert_eq!(key.open(&sealed, b"bob"), Some(b"hello".to_vec()));
+        assert_eq!(key.open(&sealed, b"alice"), None);
+        assert_eq!(
+            AtRestKey::new([2; AT_REST_KEY_LEN]).open(&sealed, b"bob"),
+            None
+        );
+        assert_eq!(key.open(&sealed[..NONCE_LEN - 1], b"bob"), None);
+    }
+
+    #[test]
+    fn key_files() {
+        let path = std::env::temp_dir().join(format!("brongnal-key-{}", std::process::id()));
+        std::fs::write(&path, format!("{}\n", "0f".repeat(AT_REST_KEY_LEN))).unwrap();
+        let key = AtRestKey::from_file(&path).unwrap();
+        let sealed = AtRestKey::new([0x0f; AT_REST_KEY_LEN]).seal(b"hello", b"");
+        assert_eq!(key.open(&sealed, b""), Some(b"hello".to_vec()));
+
+        std::fs::write(&path, "0f0f").unwrap();
+        assert!(AtRestKey::from_file(&path).is_err());
+        std::fs::write(&path, "zz".repeat(AT_REST_KEY_LEN)).unwrap();
+        assert!(AtRestKey::from_file(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,247 @@
+//! Measures how many operations per second each `Storage` backend sustains, and how that changes
+//! with the number of users stored.
+//!
+//! cargo r --release -p server --bin storage_stress -- --users 100,10000 > /dev/null
+//!
+//! The storage logs every call to stdout, so results go to stderr.
+
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::OsRng;
+use ed25519_dalek::SigningKey;
+use proto::service::{Message as MessageProto, SignedPreKey as SignedPreKeyProto};
+use proto::SessionMessage;
+use server::brongnal::{OpkQuota, QuotaPolicy, Storage, OPK_RESERVATION_TTL};
+use server::memory_brongnal::MemoryStorage;
+use server::sqlite_brongnal::SqliteStorage;
+use std::future::Future;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use tokio_rusqlite::Connection;
+use x25519_dalek::PublicKey as X25519PublicKey;
+
+type DynStorage = Arc<dyn Storage + Send + Sync>;
+
+/// Returns the value following `flag` on the command line, if the flag is present.
+fn flag_value(args: &[String], flag: &str) -> Result<Option<String>, String> {
+    args.iter()
+        .position(|arg| arg == flag)
+        .map(|i| {
+            args.get(i + 1)
+                .cloned()
+                .ok_or(format!("{flag} requires a value"))
+        })
+        .transpose()
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Config {
+    users: usize,
+    opks: usize,
+    messages: usize,
+    ops: usize,
+    tasks: usize,
+}
+
+fn identity(user: usize) -> String {
+    format!("user{user}")
+}
+
+fn random_user(config: &Config) -> String {
+    identity(OsRng.next_u32() as usize % config.users)
+}
+
+fn opk() -> X25519PublicKey {
+    let mut key = [0; 32];
+    OsRng.fill_bytes(&mut key);
+    X25519PublicKey::from(key)
+}
+
+fn message() -> MessageProto {
+    SessionMessage {
+        sender_identity: "stress".to_owned(),
+        ciphertext: vec![0x42; 256],
+    }
+    .into()
+}
+
+/// No storage quota should get in the way of measuring the storage itself.
+const NO_OPK_QUOTA: OpkQuota = OpkQuota {
+    max_keys: usize::MAX,
+    policy: QuotaPolicy::Reject,
+};
+
+/// Registers every user with `config.opks` one time pre keys and `config.messages` queued
+/// messages.
+async fn populate(storage: &DynStorage, config: &Config) -> anyhow::Result<()> {
+    let spk = SignedPreKeyProto {
+        pre_key: Some(vec![9; 32]),
+        signature: Some(vec![0; 64]),
+    };
+    for user in 0..config.users {
+        let ik = SigningKey::from_bytes(&[(user % 251) as u8 + 1; 32]).verifying_key();
+        storage
+            .register_user(identity(user).parse()?, 1, ik, spk.clone())
+            .await?;
+        let opks = (0..config.opks).map(|_| opk()).collect();
+        storage
+            .add_opks(&identity(user), 1, opks, NO_OPK_QUOTA)
+            .await?;
+        for _ in 0..config.messages {
+            storage.add_message(&identity(user), 1, message()).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Runs `op` `ops` times and reports the rate it ran at.
+async fn measure<F, Fut>(name: &str, ops: usize, mut op: F) -> anyhow::Result<()>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = tonic::Result<()>>,
+{
+    let start = Instant::now();
+    for _ in 0..ops {
+        op().await?;
+    }
+    report(name, ops, start.elapsed());
+    Ok(())
+}
+
+fn report(name: &str, ops: usize, elapsed: Duration) {
+    eprintln!(
+        "  {name:<24} {:>10.0} ops/s {:>10.1} us/op",
+        ops as f64 / elapsed.as_secs_f64(),
+        elapsed.as_secs_f64() * 1e6 / ops as f64
+    );
+}
+
+async fn run(storage: DynStorage, config: Config) -> anyhow::Result<()> {
+    let start = Instant::now();
+    populate(&storage, &config).await?;
+    report("populate (per user)", config.users, start.elapsed());
+
+    let s = &storage;
+    let c = &config;
+    measure("get_current_keys", c.ops, || async {
+        s.get_current_keys(&random_user(c), 1).await.map(|_| ())
+    })
+    .await?;
+    measure("get_devices", c.ops, || async {
+        s.get_devices(&random_user(c)).await.map(|_| ())
+    })
+    .await?;
+    measure("count_opks", c.ops, || async {
+        s.count_opks(&random_user(c), 1).await.map(|_| ())
+    })
+    .await?;
+    measure("add_opks", c.ops, || async {
+        s.add_opks(&random_user(c), 1, vec![opk()], NO_OPK_QUOTA)
+            .await
+            .map(|_| ())
+    })
+    .await?;
+    measure("reserve_opk", c.ops, || async {
+        s.reserve_opk(&random_user(c), 1, SystemTime::now() + OPK_RESERVATION_TTL)
+            .await
+            .map(|_| ())
+    })
+    .await?;
+    measure("add_message", c.ops, || async {
+        s.add_message(&random_user(c), 1, message()).await
+    })
+    .await?;
+    // Draining a mailbox empties it, so pair each retrieval with a delivery.
+    measure("add_message+get_messages", c.ops, || async {
+        let user = random_user(c);
+        s.add_message(&user, 1, message()).await?;
+        s.get_messages(&user, 1).await.map(|_| ())
+    })
+    .await?;
+
+    // Many clients at once: each task sends, reserves a prekey and drains a mailbox in turn.
+    let start = Instant::now();
+    let tasks: Vec<_> = (0..c.tasks)
+        .map(|_| {
+            let storage = storage.clone();
+            tokio::spawn(async move {
+                for _ in 0..config.ops / config.tasks {
+                    storage
+                        .add_message(&random_user(&config), 1, message())
+                        .await?;
+                    storage
+                        .reserve_opk(
+                            &random_user(&config),
+                            1,
+                            SystemTime::now() + OPK_RESERVATION_TTL,
+                        )
+                        .await?;
+                    storage.get_messages(&random_user(&config), 1).await?;
+                }
+                Ok::<(), tonic::Status>(())
+            })
+        })
+        .collect();
+    for task in tasks {
+        task.await??;
+    }
+    report(
+        &format!("concurrent x{}", c.tasks),
+        c.ops / c.tasks * c.tasks * 3,
+        start.elapsed(),
+    );
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let args: Vec<String> = std::env::args().collect();
+    let flag = |name: &str, default: usize| -> anyhow::Result<usize> {
+        Ok(flag_value(&args, name)
+            .map_err(anyhow::Error::msg)?
+            .map(|value| value.parse())
+            .transpose()?
+            .unwrap_or(default))
+    };
+    let user_counts: Vec<usize> = flag_value(&args, "--users")
+        .map_err(anyhow::Error::msg)?
+        .unwrap_or(String::from("100,1000"))
+        .split(',')
+        .map(|users| users.parse())
+        .collect::<Result<_, _>>()?;
+    let backends = flag_value(&args, "--backend")
+        .map_err(anyhow::Error::msg)?
+        .unwrap_or(String::from("sqlite,memory"));
+
+    for users in user_counts {
+        let config = Config {
+            users,
+            opks: flag("--opks", 50)?,
+            messages: flag("--messages", 10)?,
+            ops: flag("--ops", 2000)?,
+            tasks: flag("--tasks", 16)?.max(1),
+        };
+        for backend in backends.split(',') {
+            eprintln!("{backend}: {config:?}");
+            match backend {
+                "memory" => run(Arc::new(MemoryStorage::default()), config).await?,
+                "sqlite" => {
+                    // A file rather than an in-memory database, so writes pay for the WAL as
+                    // they do in production.
+                    let path: PathBuf = std::env::temp_dir().join(format!(
+                        "brongnal-stress-{}-{users}.db3",
+                        std::process::id()
+                    ));
+                    let storage = SqliteStorage::new(Connection::open(&path).await?).await?;
+                    let result = run(Arc::new(storage), config).await;
+                    for suffix in ["", "-wal", "-shm"] {
+                        let _ = std::fs::remove_file(format!("{}{suffix}", path.display()));
+                    }
+                    result?;
+                }
+                backend => anyhow::bail!("unknown backend {backend}"),
+            }
+        }
+    }
+    Ok(())
+}
@@ -1,190 +1,4699 @@
+use crate::federation::{Federation, Route};
+use crate::gossamer::InMemoryGossamer;
+use crate::ip_limit::{IpRateLimits, RpcClass};
+use crate::push::{PushDispatcher, PushError, PushPlatform, WakeupPayload};
+use crate::rate_limit::{throttled, RateLimit, RateLimiter};
+use crate::request_log::authenticated;
+use blake2::{Blake2s256, Digest};
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::OsRng;
 use ed25519_dalek::{Signature, VerifyingKey};
+use proto::gossamer::gossamer_server::Gossamer;
+use proto::gossamer::message::Action;
+use proto::gossamer::ActionRequest;
+use proto::service::account_data_record::Record;
 use proto::service::brongnal_server::Brongnal;
+use proto::service::message::Payload;
 use proto::service::Message as MessageProto;
 use proto::service::PreKeyBundle as PreKeyBundleProto;
+use proto::service::PreKeyStatus;
+use proto::service::ServerControl;
 use proto::service::SignedPreKey as SignedPreKeyProto;
+use proto::service::SignedPreKeys as SignedPreKeysProto;
 use proto::service::{
-    RegisterPreKeyBundleRequest, RegisterPreKeyBundleResponse, RequestPreKeysRequest,
-    RetrieveMessagesRequest, SendMessageRequest, SendMessageResponse,
+    AccountDataRecord, CountOneTimeKeysRequest, CountOneTimeKeysResponse, DeleteAccountRequest,
+    DeleteAccountResponse, DeleteDeviceRequest, DeleteDeviceResponse, DeleteUserRequest,
+    DeleteUserResponse, DeliveryState, DeviceMessage, DeviceRegistration, ExportAccountDataRequest,
+    ExportedPreKey, ExportedPushToken, FetchProvisioningRequest, FetchProvisioningResponse,
+    GetAccountDeletionNonceRequest, GetAccountDeletionNonceResponse,
+    GetRegistrationChallengeRequest, GetRegistrationChallengeResponse, MessageStatus,
+    MessageStatusStreamRequest, PingRequest, PingResponse, PreKeyKind, PublishProvisioningRequest,
+    PublishProvisioningResponse, QueuedMessageMetadata, RegisterPreKeyBundleRequest,
+    RegisterPreKeyBundleResponse, RegisterPushTokenRequest, RegisterPushTokenResponse,
+    RequestPreKeysRequest, RequestPreKeysResponse, RetrieveMessagesRequest, SendMessageRequest,
+    SendMessageResponse, UpdateSignedPreKeyRequest, UpdateSignedPreKeyResponse,
+    UploadOneTimeKeysRequest, UploadOneTimeKeysResponse,
+};
+use proto::{
+    delete_account_payload, delete_device_payload, delete_user_payload,
+    export_account_data_payload, message_status_stream_payload, parse_signature,
+    parse_verifying_key, parse_x25519_public_key, register_push_token_payload, Identity,
+    MessagePayload, ParseError, ParseErrorReason, DEFAULT_DEVICE_ID, HEARTBEAT_INTERVAL,
+    MAX_CIPHERTEXT_LEN, MAX_PING_NONCE_LEN, MESSAGE_UUID_LEN, PROVISIONING_ID_LEN,
+    PROVISIONING_TTL,
 };
-use proto::{parse_verifying_key, parse_x25519_public_key};
 use protocol::bundle::verify_bundle;
-use std::collections::HashMap;
+use protocol::kem::{verify_kem_pre_key, SignedKemPreKey};
+use protocol::pow;
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
 use tokio::sync::mpsc::Sender;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
 use tokio_stream::wrappers::ReceiverStream;
-use tonic::{Request, Response, Result, Status};
+use tonic::{Code, Request, Response, Result, Status};
 use x25519_dalek::PublicKey as X25519PublicKey;
 
+/// The most one time pre keys accepted in a single upload, bounding the signature verification
+/// and inserts a single request can cause.
+pub const MAX_OPKS_PER_REQUEST: usize = 200;
+
+/// How long a one time pre key handed out in a bundle stays reserved for the sender. A message
+/// that uses it consumes it; otherwise it returns to the pool once the reservation expires.
+pub const OPK_RESERVATION_TTL: Duration = Duration::from_secs(5 * 60);
+
+/// The numbers of one time keys left at which a device with a message stream open is told its
+/// keys are running low.
+pub const OPK_LOW_THRESHOLDS: [usize; 2] = [10, 0];
+
+/// How many times the ciphertext limit a request may be, so a send carries a full-size copy of
+/// the message for several devices with room to spare for keys and framing.
+pub const REQUEST_LEN_FACTOR: usize = 4;
+
+/// The largest provisioning envelope accepted. Envelopes only carry an identity key.
+pub const MAX_PROVISIONING_ENVELOPE_BYTES: usize = 1024;
+
+/// The longest push token accepted. FCM and APNs tokens are a few hundred bytes at most.
+pub const MAX_PUSH_TOKEN_BYTES: usize = 4096;
+
+/// How long a registration challenge may be used for.
+pub const REGISTRATION_CHALLENGE_TTL: Duration = Duration::from_secs(5 * 60);
+
+/// The length of registration challenges, enough that they can't be guessed.
+pub const REGISTRATION_CHALLENGE_LEN: usize = 16;
+
+/// How long an account deletion nonce may be used for.
+pub const ACCOUNT_DELETION_NONCE_TTL: Duration = Duration::from_secs(5 * 60);
+
+/// The length of account deletion nonces, enough that they never repeat.
+pub const ACCOUNT_DELETION_NONCE_LEN: usize = 16;
+
+/// How far from the server's clock the timestamp a request was signed at may be, for requests
+/// that are signed over one rather than a nonce.
+pub const SIGNED_REQUEST_MAX_SKEW: Duration = Duration::from_secs(5 * 60);
+
+/// What to do with a new message when the recipient's mailbox is full.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum QuotaPolicy {
+    /// Refuse the new message with `ResourceExhausted`.
+    Reject,
+    /// Drop the recipient's oldest messages to make room.
+    EvictOldest,
+}
+
+/// Limits the number of messages queued for a single recipient.
+#[derive(Clone, Copy, Debug)]
+pub struct MailboxQuota {
+    pub max_messages: usize,
+    pub policy: QuotaPolicy,
+}
+
+/// Limits the number of unused one time pre keys stored for a single user.
+#[derive(Clone, Copy, Debug)]
+pub struct OpkQuota {
+    pub max_keys: usize,
+    pub policy: QuotaPolicy,
+}
+
+impl Default for OpkQuota {
+    fn default() -> Self {
+        OpkQuota {
+            max_keys: 500,
+            policy: QuotaPolicy::Reject,
+        }
+    }
+}
+
+/// The keys a sender needs to start a session with a registered identity.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CurrentKeys {
+    pub ik: VerifyingKey,
+    pub spk: SignedPreKeyProto,
+    pub spk_id: u32,
+    /// When `spk` was first uploaded, so senders can tell a peer has stopped rotating it.
+    pub spk_uploaded_at: SystemTime,
+    /// The cipher suite senders must use, absent for devices that registered without one.
+    pub cipher_suite: Option<u32>,
+}
+
+/// What operators are told about a registered device.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DeviceStats {
+    pub identity: String,
+    /// The identity in the form the device registered it in.
+    pub display_identity: String,
+    pub device_id: u32,
+    /// When the device last registered.
+    pub registered_at: SystemTime,
+    pub spk_uploaded_at: SystemTime,
+    /// One time pre keys available to hand out; reserved keys don't count.
+    pub opk_count: usize,
+    pub queued_messages: usize,
+    pub has_push_token: bool,
+}
+
+/// Totals across every registered identity, and how big the storage has grown.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct StorageStats {
+    pub identities: usize,
+    pub devices: usize,
+    /// One time pre keys available to hand out; reserved keys don't count.
+    pub opks: usize,
+    pub queued_messages: usize,
+    /// Rows in each table, by table name.
+    pub table_rows: BTreeMap<String, usize>,
+    /// How many bytes the storage takes up, absent unless it's kept in a file.
+    pub size_bytes: Option<u64>,
+    /// How many bytes of the write-ahead log are waiting to be checkpointed into the file.
+    pub wal_size_bytes: Option<u64>,
+    /// When the oldest undelivered message was queued.
+    pub oldest_message_at: Option<SystemTime>,
+    /// The identities with the most queued messages and how many each has, deepest first.
+    pub deepest_queues: Vec<(String, usize)>,
+}
+
+/// What checkpointing the write-ahead log into the database file did.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Checkpoint {
+    /// How many bytes the log took up beforehand.
+    pub wal_size_bytes: u64,
+    /// Pages in the log, and how many of them were copied into the database file.
+    pub log_pages: u64,
+    pub checkpointed_pages: u64,
+    /// Whether the log was emptied; it isn't while another connection is still reading from it.
+    pub truncated: bool,
+}
+
+/// Called as a backup goes with how many pages it has copied and how many there are in all.
+pub type BackupProgress = Box<dyn FnMut(u64, u64) + Send>;
+
+/// A record of an operator changing a user's data through the Admin service.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AuditEntry {
+    /// Who made the change, as they named themselves to the Admin service.
+    pub actor: String,
+    pub action: String,
+    /// The identity or invite the change was made to.
+    pub target: String,
+    pub time: SystemTime,
+}
+
+pub(crate) fn unix_seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// The hash invites are stored and known by, so the codes themselves are never kept.
+pub fn hash_invite_code(code: &str) -> Vec<u8> {
+    Blake2s256::digest(code.as_bytes()).to_vec()
+}
+
+/// A code operators hand out that lets its holder register a new identity on servers that
+/// require one. Only a hash of the code is kept.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Invite {
+    pub code_hash: Vec<u8>,
+    /// Who created the invite, as they named themselves to the Admin service.
+    pub creator: String,
+    /// How many identities may register with the code.
+    pub max_uses: u32,
+    /// How many have.
+    pub uses: u32,
+    pub created_at: SystemTime,
+    /// When the code stops working, if ever.
+    pub expires_at: Option<SystemTime>,
+    pub revoked: bool,
+}
+
+/// Why an invite code can't be used.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InviteRejection {
+    Unknown,
+    Revoked,
+    Expired,
+    UsedUp,
+}
+
+impl InviteRejection {
+    /// The reason as sent to clients in the `invite-rejected` metadata.
+    pub fn reason(self) -> &'static str {
+        match self {
+            InviteRejection::Unknown => "unknown",
+            InviteRejection::Revoked => "revoked",
+            InviteRejection::Expired => "expired",
+            InviteRejection::UsedUp => "used-up",
+        }
+    }
+}
+
+/// A message waiting for a device, along with when it was enqueued.
+#[derive(Clone, Debug, PartialEq)]
+pub struct QueuedMessage {
+    pub queued_at: SystemTime,
+    pub message: MessageProto,
+}
+
+/// A message purged from a device's mailbox before the device collected it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExpiredMessage {
+    pub recipient: String,
+    pub device_id: u32,
+    pub message: MessageProto,
+}
+
+/// The token a device is woken with while it has no open message stream.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PushToken {
+    pub platform: PushPlatform,
+    pub token: String,
+    /// The version of the app the token was issued to.
+    pub app_version: Option<String>,
+}
+
+/// The outcome of claiming a message's uuid, carrying the id the server knows the message by.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MessageClaim {
+    /// Nothing claimed the uuid before, so the message should be delivered.
+    New(u64),
+    /// A send with the same uuid already claimed it, so the message was already delivered.
+    Duplicate(u64),
+}
+
+/// A send's claim on its message uuid, released if the send fails, or is abandoned part way, e.g.
+/// because it timed out, so a retry is delivered.
+struct UuidClaim {
+    storage: Arc<dyn Storage + Send + Sync>,
+    recipient: String,
+    uuid: Option<Vec<u8>>,
+}
+
+impl UuidClaim {
+    /// Releases the claim once the send has failed.
+    async fn release(mut self) {
+        if let Some(uuid) = self.uuid.take() {
+            release_message_uuid(&*self.storage, &self.recipient, &uuid).await;
+        }
+    }
+
+    /// Keeps the claim once the message is delivered.
+    fn keep(mut self) {
+        self.uuid = None;
+    }
+}
+
+impl Drop for UuidClaim {
+    fn drop(&mut self) {
+        let Some(uuid) = self.uuid.take() else {
+            return;
+        };
+        // The send was abandoned, so nothing is left to await the release.
+        let storage = self.storage.clone();
+        let recipient = std::mem::take(&mut self.recipient);
+        tokio::spawn(async move { release_message_uuid(&*storage, &recipient, &uuid).await });
+    }
+}
+
+async fn release_message_uuid(storage: &(dyn Storage + Send + Sync), recipient: &str, uuid: &[u8]) {
+    if let Err(e) = storage.release_message_uuid(recipient, uuid).await {
+        eprintln!("Failed to release message uuid: {e}");
+    }
+}
+
+/// Keys and messages are stored per device; an identity exists while any of its devices does.
+#[tonic::async_trait]
 pub trait Storage: std::fmt::Debug {
-    /// Add a new identity to the storage.
-    /// For now, repeated calls should not return an error.
-    // TODO(#25) - Return error when attempting to overwrite registration.
-    fn register_user(
+    /// Add a new device to the storage, returning the id of its signed pre key.
+    /// Registering an existing device replaces its identity key and signed pre key.
+    /// Devices are stored under the identity's canonical form. Registering fails with
+    /// `AlreadyExists` if the identity is registered in another form, e.g. "alice" when "Alice"
+    /// is, or another identity with the same skeleton is.
+    // TODO(#25) - Require proof of the old identity key before overwriting a registration.
+    async fn register_user(
         &self,
-        identity: String,
+        identity: Identity,
+        device_id: u32,
         ik: VerifyingKey,
         spk: SignedPreKeyProto,
-    ) -> Result<()>;
+    ) -> Result<u32>;
+
+    /// Replaces the signed pre key for a given device, returning its id.
+    /// Signed pre key ids only change when the key does.
+    async fn update_spk(
+        &self,
+        identity: &str,
+        device_id: u32,
+        pre_key: SignedPreKeyProto,
+    ) -> Result<u32>;
 
-    /// Replaces the signed pre key for a given identity.
-    // TODO(#27) -  Implement signed pre key rotation.
-    #[allow(dead_code)]
-    fn update_spk(&self, identity: &str, pre_key: SignedPreKeyProto) -> Result<()>;
+    /// Appends new unburnt one time pre keys for others to message a given device, returning
+    /// the id assigned to each key.
+    /// Uploads that would exceed `quota` are rejected or displace the oldest keys. No key may be
+    /// stored twice, by any device: uploads repeating one fail with `AlreadyExists`.
+    async fn add_opks(
+        &self,
+        identity: &str,
+        device_id: u32,
+        pre_keys: Vec<X25519PublicKey>,
+        quota: OpkQuota,
+    ) -> Result<Vec<u32>>;
+
+    /// Atomically discards a device's one time pre keys and stores `pre_keys` in their place.
+    /// Fails like `add_opks` if another device holds one of `pre_keys`.
+    async fn replace_opks(
+        &self,
+        identity: &str,
+        device_id: u32,
+        pre_keys: Vec<X25519PublicKey>,
+        quota: OpkQuota,
+    ) -> Result<Vec<u32>>;
+
+    /// Returns how many one time pre keys a device has available to hand out. Reserved keys
+    /// don't count.
+    async fn count_opks(&self, identity: &str, device_id: u32) -> Result<usize>;
 
-    /// Appends new unburnt one time pre keys for others to message a given identity.
-    fn add_opks(&self, identity: &str, pre_keys: Vec<X25519PublicKey>) -> Result<()>;
+    /// The one time pre keys a device has available to hand out, with their ids, oldest first.
+    /// Reserved keys aren't included.
+    async fn get_opks(&self, identity: &str, device_id: u32)
+        -> Result<Vec<(u32, X25519PublicKey)>>;
 
-    /// Retrieves the identity key and signed pre key for a given identity.
+    /// Returns whether an identity has registered any device.
+    async fn user_exists(&self, identity: &str) -> Result<bool>;
+
+    /// Returns the ids of an identity's registered devices in ascending order.
+    async fn get_devices(&self, identity: &str) -> Result<Vec<u32>>;
+
+    /// Retrieves the identity key and signed pre key for a given device.
     /// A client must first invoke this before messaging a peer.
-    fn get_current_keys(&self, identity: &str) -> Result<(VerifyingKey, SignedPreKeyProto)>;
+    async fn get_current_keys(&self, identity: &str, device_id: u32) -> Result<CurrentKeys>;
+
+    /// Sets or, given `None`, clears the cipher suite a device registered with. The server only
+    /// relays it, so suites it doesn't know are stored all the same.
+    async fn set_cipher_suite(
+        &self,
+        identity: &str,
+        device_id: u32,
+        cipher_suite: Option<u32>,
+    ) -> Result<()>;
+
+    /// Reserves the oldest available one time pre key for a device until `until`, returning it
+    /// along with its id. Each key is reserved by at most one caller at a time, even concurrently.
+    async fn reserve_opk(
+        &self,
+        identity: &str,
+        device_id: u32,
+        until: SystemTime,
+    ) -> Result<Option<(u32, X25519PublicKey)>>;
+
+    /// Deletes reserved one time pre key `id` once a message uses it. Returns whether it was
+    /// reserved; a key that was never handed out or that a message already used is not.
+    async fn consume_opk(&self, identity: &str, device_id: u32, id: u32) -> Result<bool>;
+
+    /// Returns one time pre keys whose reservation expired before `before` to their device's
+    /// pool, returning how many were released.
+    async fn release_expired_opk_reservations(&self, before: SystemTime) -> Result<usize>;
+
+    /// Sets or, given `None`, clears the key handed out in place of a one time pre key once a
+    /// device runs out of them, returning its id. Ids only change when the key does.
+    async fn set_last_resort_key(
+        &self,
+        identity: &str,
+        device_id: u32,
+        key: Option<X25519PublicKey>,
+    ) -> Result<u32>;
+
+    /// Retrieves a device's last-resort key along with its id. Unlike one time pre keys it is
+    /// handed out any number of times.
+    async fn get_last_resort_key(
+        &self,
+        identity: &str,
+        device_id: u32,
+    ) -> Result<Option<(u32, X25519PublicKey)>>;
+
+    /// Atomically discards a device's one time KEM pre keys and stores `pre_keys` in their place,
+    /// returning the id assigned to each key. Every registration replaces them, so unlike X25519
+    /// one time pre keys they never outlive one.
+    async fn replace_kem_opks(
+        &self,
+        identity: &str,
+        device_id: u32,
+        pre_keys: Vec<SignedPreKeyProto>,
+    ) -> Result<Vec<u32>>;
+
+    /// Retrieve and remove the oldest one time KEM pre key for a device along with its id.
+    /// Each key is handed out at most once, even to concurrent callers.
+    async fn pop_kem_opk(
+        &self,
+        identity: &str,
+        device_id: u32,
+    ) -> Result<Option<(u32, SignedPreKeyProto)>>;
+
+    /// Like `set_last_resort_key`, but for the KEM pre key handed out once a device runs out of
+    /// one time KEM pre keys.
+    async fn set_last_resort_kem_key(
+        &self,
+        identity: &str,
+        device_id: u32,
+        key: Option<SignedPreKeyProto>,
+    ) -> Result<u32>;
+
+    /// Like `get_last_resort_key`, but for the last-resort KEM pre key.
+    async fn get_last_resort_kem_key(
+        &self,
+        identity: &str,
+        device_id: u32,
+    ) -> Result<Option<(u32, SignedPreKeyProto)>>;
+
+    /// Like `get_opks`, but for one time KEM pre keys.
+    async fn get_kem_opks(
+        &self,
+        identity: &str,
+        device_id: u32,
+    ) -> Result<Vec<(u32, SignedPreKeyProto)>>;
+
+    /// Enqueue a message for a given recipient device, subject to the storage's `MailboxQuota`.
+    async fn add_message(
+        &self,
+        recipient: &str,
+        device_id: u32,
+        message: MessageProto,
+    ) -> Result<()>;
+
+    /// Retrieve enqueued messages for a given device.
+    async fn get_messages(&self, identity: &str, device_id: u32) -> Result<Vec<MessageProto>>;
+
+    /// Like `get_messages`, but leaves the messages queued.
+    async fn peek_messages(&self, identity: &str, device_id: u32) -> Result<Vec<QueuedMessage>>;
+
+    /// Claims `uuid` for a message to `recipient`, assigning the message an id. Retries of the same
+    /// send claim the same uuid and get the first claim's id back as a duplicate.
+    async fn claim_message_uuid(&self, recipient: &str, uuid: &[u8]) -> Result<MessageClaim>;
+
+    /// Releases a claim on `uuid` whose message couldn't be delivered, so a retry delivers it.
+    async fn release_message_uuid(&self, recipient: &str, uuid: &[u8]) -> Result<()>;
+
+    /// Sets the token a device is woken with, replacing any previous one.
+    async fn set_push_token(&self, identity: &str, device_id: u32, token: PushToken) -> Result<()>;
+
+    /// Returns the token a device is woken with, if it registered one.
+    async fn get_push_token(&self, identity: &str, device_id: u32) -> Result<Option<PushToken>>;
+
+    /// Forgets a device's push token if it is still `token`, e.g. once the push service rejects
+    /// it. A device that registered a new token in the meantime keeps that one.
+    async fn delete_push_token(&self, identity: &str, device_id: u32, token: &str) -> Result<()>;
+
+    /// Removes a device along with its one time pre keys and queued messages.
+    async fn delete_device(&self, identity: &str, device_id: u32) -> Result<()>;
+
+    /// Removes every device of an identity along with their one time pre keys and queued
+    /// messages.
+    async fn delete_user(&self, identity: &str) -> Result<()>;
+
+    /// Deletes every message queued for the devices of `identity`, keeping the account, and
+    /// returns how many were removed.
+    async fn purge_messages(&self, identity: &str) -> Result<usize>;
 
-    /// Retrieve a one time pre key for an identity.
-    fn pop_opk(&self, identity: &str) -> Result<Option<X25519PublicKey>>;
+    /// Deletes undelivered messages enqueued before `before`, returning the ones removed.
+    /// Claims on message uuids made before `before` are released too.
+    async fn purge_expired_messages(&self, before: SystemTime) -> Result<Vec<ExpiredMessage>>;
+
+    /// Deletes one time pre keys created before `before` that also predate their owner's
+    /// current registration, returning how many were removed.
+    async fn purge_expired_opks(&self, before: SystemTime) -> Result<usize>;
+
+    /// Returns how many devices' signed pre keys were uploaded before `before`.
+    async fn count_stale_spks(&self, before: SystemTime) -> Result<usize>;
+
+    /// Describes every device of `identity`, or of every identity given `None`, ordered by
+    /// identity and then device id.
+    async fn device_stats(&self, identity: Option<&str>) -> Result<Vec<DeviceStats>>;
+
+    /// Totals across every registered identity, naming the `deepest_queues` identities with the
+    /// most queued messages.
+    async fn stats(&self, deepest_queues: usize) -> Result<StorageStats>;
+
+    /// Records a change an operator made.
+    async fn add_audit_entry(&self, entry: AuditEntry) -> Result<()>;
+
+    /// Every change operators have made, oldest first.
+    async fn audit_log(&self) -> Result<Vec<AuditEntry>>;
+
+    /// Stores a new invite, failing with AlreadyExists if its code hash is taken.
+    async fn add_invite(&self, invite: Invite) -> Result<()>;
+
+    /// Uses up one of the uses of the invite whose code hashes to `code_hash`, unless it was
+    /// revoked, expired before `now` or has been used as often as it may be.
+    async fn redeem_invite(
+        &self,
+        code_hash: &[u8],
+        now: SystemTime,
+    ) -> Result<Result<(), InviteRejection>>;
+
+    /// Stops the invite whose code hashes to `code_hash` from being used, failing with NotFound
+    /// if there is no such invite.
+    async fn revoke_invite(&self, code_hash: &[u8]) -> Result<()>;
+
+    /// Every invite, revoked or not, oldest first.
+    async fn invites(&self) -> Result<Vec<Invite>>;
+
+    /// Copies the write-ahead log into the database file and empties it, if it has grown to at
+    /// least `min_wal_bytes`. Returns None if it hasn't, or the storage keeps no log.
+    async fn checkpoint(&self, min_wal_bytes: u64) -> Result<Option<Checkpoint>>;
+
+    /// Refreshes whatever the storage uses to plan its queries.
+    async fn optimize(&self) -> Result<()>;
+
+    /// Copies the write-ahead log into the database file, if the storage keeps one, and closes
+    /// the storage, e.g. as the server shuts down. It shouldn't be used afterwards.
+    async fn close(&self) -> Result<()>;
+
+    /// Copies a consistent snapshot of the storage into a new database at `destination` without
+    /// holding up other requests, calling `progress` with how many of its pages have been copied
+    /// and how many there are. Fails with Unimplemented unless the storage is kept in a file.
+    async fn backup(&self, destination: &Path, progress: BackupProgress) -> Result<()>;
+}
 
-    /// Enqueue a message for a given recipient.
-    fn add_message(&self, recipient: &str, message: MessageProto) -> Result<()>;
+/// How long undelivered messages and unused one time pre keys are kept, and how old a signed
+/// pre key may get before its owner is reported as no longer rotating it.
+#[derive(Clone, Copy, Debug)]
+pub struct RetentionPolicy {
+    pub message_ttl: Duration,
+    pub opk_ttl: Duration,
+    pub max_spk_age: Duration,
+}
 
-    /// Retrieve enqueued messages for a given identity.
-    fn get_messages(&self, identity: &str) -> Result<Vec<MessageProto>>;
+/// How often storage is looked after, for storage that needs it.
+#[derive(Clone, Copy, Debug)]
+pub struct MaintenancePolicy {
+    /// How often to check the size of the write-ahead log.
+    pub checkpoint_interval: Duration,
+    /// How big the log may grow before it is checkpointed.
+    pub max_wal_bytes: u64,
+    /// How often to refresh query planner statistics, if at all.
+    pub optimize_interval: Option<Duration>,
 }
 
+/// Open message streams by identity and device id.
+type Receivers = HashMap<(String, u32), Sender<Result<MessageProto>>>;
+
+/// Provisioning envelopes waiting for a new device, by provisioning id, with when they expire.
+/// They are short-lived, so they are kept in memory rather than in storage.
+type Provisioning = HashMap<Vec<u8>, (Instant, Vec<u8>)>;
+
+/// The MessageStatusStreams each sender has open.
+type StatusSubscribers = HashMap<String, Vec<Sender<Result<MessageStatus>>>>;
+
 #[derive(Debug)]
 pub struct BrongnalController {
-    storage: Box<dyn Storage + Send + Sync>,
-    receivers: Arc<Mutex<HashMap<String, Sender<Result<MessageProto>>>>>,
+    storage: Arc<dyn Storage + Send + Sync>,
+    receivers: Arc<Mutex<Receivers>>,
+    draining: AtomicBool,
+    pings: AtomicU64,
+    checkpoints: AtomicU64,
+    incomplete_checkpoints: AtomicU64,
+    send_limiter: RateLimiter,
+    ip_limits: IpRateLimits,
+    opk_quota: OpkQuota,
+    opk_reservation_ttl: Duration,
+    opk_low_thresholds: Vec<usize>,
+    max_ciphertext_len: usize,
+    federation: Option<Federation>,
+    provisioning: Mutex<Provisioning>,
+    provisioning_ttl: Duration,
+    registration_difficulty: u32,
+    registration_challenges: Mutex<HashMap<Vec<u8>, Instant>>,
+    registration_challenge_ttl: Duration,
+    invites_required: bool,
+    account_deletion_nonces: Mutex<HashMap<Vec<u8>, (String, Instant)>>,
+    status_subscribers: Mutex<StatusSubscribers>,
+    gossamer: Option<InMemoryGossamer>,
+    heartbeat_interval: Duration,
+    push: HashMap<PushPlatform, Arc<dyn PushDispatcher + Send + Sync>>,
 }
 
 impl BrongnalController {
     pub fn new(storage: Box<dyn Storage + Send + Sync>) -> BrongnalController {
         BrongnalController {
-            storage,
+            storage: storage.into(),
             receivers: Arc::new(Mutex::new(HashMap::new())),
+            draining: AtomicBool::new(false),
+            pings: AtomicU64::new(0),
+            checkpoints: AtomicU64::new(0),
+            incomplete_checkpoints: AtomicU64::new(0),
+            send_limiter: RateLimiter::unlimited(),
+            ip_limits: IpRateLimits::unlimited(),
+            opk_quota: OpkQuota::default(),
+            opk_reservation_ttl: OPK_RESERVATION_TTL,
+            opk_low_thresholds: OPK_LOW_THRESHOLDS.to_vec(),
+            max_ciphertext_len: MAX_CIPHERTEXT_LEN,
+            federation: None,
+            provisioning: Mutex::new(HashMap::new()),
+            provisioning_ttl: PROVISIONING_TTL,
+            registration_difficulty: 0,
+            registration_challenges: Mutex::new(HashMap::new()),
+            registration_challenge_ttl: REGISTRATION_CHALLENGE_TTL,
+            invites_required: false,
+            account_deletion_nonces: Mutex::new(HashMap::new()),
+            status_subscribers: Mutex::new(HashMap::new()),
+            gossamer: None,
+            heartbeat_interval: HEARTBEAT_INTERVAL,
+            push: HashMap::new(),
         }
     }
-}
 
-#[tonic::async_trait]
-impl Brongnal for BrongnalController {
-    async fn register_pre_key_bundle(
-        &self,
-        request: Request<RegisterPreKeyBundleRequest>,
-    ) -> Result<Response<RegisterPreKeyBundleResponse>> {
-        let request = request.into_inner();
-        println!("Registering PreKeyBundle for \"{}\".", request.identity());
+    /// Wakes devices with `platform` tokens through `dispatcher` when a message is queued for
+    /// them. Devices on platforms without a dispatcher aren't woken.
+    pub fn with_push_dispatcher(
+        mut self,
+        platform: PushPlatform,
+        dispatcher: Arc<dyn PushDispatcher + Send + Sync>,
+    ) -> BrongnalController {
+        self.push.insert(platform, dispatcher);
+        self
+    }
 
-        let identity: String = request
-            .identity
-            .clone()
-            .ok_or(Status::invalid_argument("request missing identity"))?;
-        let ik = parse_verifying_key(&request.identity_key())
-            .map_err(|_| Status::invalid_argument("request has invalid identity_key"))?;
-        let spk_proto = request
-            .signed_pre_key
-            .ok_or(Status::invalid_argument("request is missing signed prekey"))?;
-        let spk = protocol::x3dh::SignedPreKey::try_from(spk_proto.clone())?;
-        verify_bundle(&ik, &[spk.pre_key], &spk.signature)
-            .map_err(|_| Status::unauthenticated("failed to validate signed prekey signature"))?;
+    /// Sets how long provisioning envelopes wait for the new device to fetch them.
+    pub fn with_provisioning_ttl(mut self, ttl: Duration) -> BrongnalController {
+        self.provisioning_ttl = ttl;
+        self
+    }
 
-        let opks = request.one_time_key_bundle.ok_or(Status::invalid_argument(
-            "request missing one_time_prekey_bundle",
-        ))?;
-        let pre_keys: Vec<X25519PublicKey> = opks
-            .pre_keys
-            .iter()
-            .map(|key| parse_x25519_public_key(&key))
-            .collect::<Result<Vec<_>, _>>()
-            .map_err(|_| Status::invalid_argument("invalid prekey bundle"))?;
-        let signature = Signature::from_slice(opks.signature()).map_err(|_e| {
-            Status::invalid_argument("one time prekey bundle signature is invalid")
-        })?;
-        verify_bundle(&ik, &pre_keys, &signature).map_err(|_| {
-            Status::unauthenticated("failed to validate one time prekey bundle signature")
-        })?;
+    /// Requires registrations to solve a proof of work challenge with `difficulty` leading zero
+    /// bits, which takes about `2^difficulty` hashes. Zero, the default, requires none.
+    pub fn with_registration_difficulty(mut self, difficulty: u32) -> BrongnalController {
+        self.registration_difficulty = difficulty;
+        self
+    }
 
-        self.storage
-            .register_user(identity.clone(), ik, spk_proto)?;
-        self.storage.add_opks(&identity, pre_keys)?;
+    /// Sets how long registration challenges may be used for.
+    pub fn with_registration_challenge_ttl(mut self, ttl: Duration) -> BrongnalController {
+        self.registration_challenge_ttl = ttl;
+        self
+    }
 
-        Ok(Response::new(RegisterPreKeyBundleResponse {}))
+    /// Requires an invite code from the Admin service to register a new identity. Identities that
+    /// already exist may register more devices and new keys without one.
+    pub fn with_invites_required(mut self, required: bool) -> BrongnalController {
+        self.invites_required = required;
+        self
     }
 
-    async fn request_pre_keys(
-        &self,
-        request: Request<RequestPreKeysRequest>,
-    ) -> Result<Response<PreKeyBundleProto>> {
-        let request = request.into_inner();
-        println!("Retrieving PreKeyBundle for \"{}\".", request.identity());
+    /// Appends the tombstones of deleted accounts to `gossamer`'s ledger.
+    pub fn with_gossamer(mut self, gossamer: InMemoryGossamer) -> BrongnalController {
+        self.gossamer = Some(gossamer);
+        self
+    }
 
-        let (ik, spk) = self.storage.get_current_keys(request.identity())?;
-        // TODO(#26) - Prevent one time key pop abuse.
-        let opk = self.storage.pop_opk(request.identity())?;
+    /// Sets how often a heartbeat is sent down each open message stream.
+    pub fn with_heartbeat_interval(mut self, interval: Duration) -> BrongnalController {
+        self.heartbeat_interval = interval;
+        self
+    }
 
-        let reply = PreKeyBundleProto {
-            identity_key: Some(ik.as_bytes().into()),
-            one_time_key: opk.map(|opk| opk.as_bytes().into()),
-            signed_pre_key: Some(spk.into()),
-        };
-        Ok(Response::new(reply))
+    /// Limits how many one time pre keys each user may have stored. Unless the quota evicts old
+    /// keys, uploads keep only the keys that fit.
+    pub fn with_opk_quota(mut self, quota: OpkQuota) -> BrongnalController {
+        self.opk_quota = quota;
+        self
     }
 
-    async fn send_message(
-        &self,
-        request: Request<SendMessageRequest>,
-    ) -> Result<Response<SendMessageResponse>> {
-        let request = request.into_inner();
-        println!(
-            "Received request to send message to: \"{}\".",
-            request.recipient_identity()
-        );
+    /// Sets how long a one time pre key handed out in a bundle waits for a message to use it.
+    pub fn with_opk_reservation_ttl(mut self, ttl: Duration) -> BrongnalController {
+        self.opk_reservation_ttl = ttl;
+        self
+    }
 
-        let recipient_identity = request.recipient_identity.ok_or(Status::invalid_argument(
-            "request missing recipient_identity",
-        ))?;
-        let message_proto: MessageProto = request
-            .message
-            .ok_or(Status::invalid_argument("request missing message"))?
-            .into();
-        let _ = protocol::x3dh::Message::try_from(message_proto.clone())?;
+    /// Tells devices their one time keys are running low when they have `thresholds` left,
+    /// rather than `OPK_LOW_THRESHOLDS`.
+    pub fn with_opk_low_thresholds(mut self, thresholds: Vec<usize>) -> BrongnalController {
+        self.opk_low_thresholds = thresholds;
+        self
+    }
 
-        let tx = self
-            .receivers
+    /// Refuses messages whose ciphertext is longer than `max_len`, rather than
+    /// `MAX_CIPHERTEXT_LEN`. Longer limits have no effect.
+    pub fn with_max_ciphertext_len(mut self, max_len: usize) -> BrongnalController {
+        self.max_ciphertext_len = max_len.min(MAX_CIPHERTEXT_LEN);
+        self
+    }
+
+    /// Relays requests for identities in other domains to `federation`'s peers, and accepts
+    /// requests they relay.
+    pub fn with_federation(mut self, federation: Federation) -> BrongnalController {
+        self.federation = Some(federation);
+        self
+    }
+
+    /// The largest request worth decoding, for the transport's limit.
+    pub fn max_request_len(&self) -> usize {
+        self.max_ciphertext_len * REQUEST_LEN_FACTOR
+    }
+
+    /// Limits how often each client address may call SendMessage.
+    pub fn with_send_limit(mut self, limit: RateLimit) -> BrongnalController {
+        self.send_limiter = RateLimiter::new(limit);
+        self
+    }
+
+    /// Limits how often each client address may register, fetch pre keys and ask for
+    /// challenges, once the server is wrapped in an `IpRateLimitLayer`.
+    pub fn with_ip_limits(mut self, limits: IpRateLimits) -> BrongnalController {
+        self.ip_limits = limits;
+        self
+    }
+
+    /// Purges expired messages and one time pre keys, and releases expired one time pre key
+    /// reservations, every `interval`.
+    pub fn spawn_retention_task(
+        self: Arc<Self>,
+        policy: RetentionPolicy,
+        interval: Duration,
+    ) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+            loop {
+                interval.tick().await;
+                let now = SystemTime::now();
+                match self
+                    .storage
+                    .purge_expired_messages(now - policy.message_ttl)
+                    .await
+                {
+                    Ok(purged) => {
+                        println!("Purged {} expired messages.", purged.len());
+                        for expired in purged {
+                            self.publish_status(
+                                &expired.recipient,
+                                expired.device_id,
+                                &expired.message,
+                                DeliveryState::Expired,
+                            );
+                        }
+                    }
+                    Err(e) => eprintln!("Failed to purge expired messages: {e}"),
+                }
+                match self.storage.purge_expired_opks(now - policy.opk_ttl).await {
+                    Ok(purged) => println!("Purged {purged} expired one time keys."),
+                    Err(e) => eprintln!("Failed to purge expired one time keys: {e}"),
+                }
+                match self.storage.release_expired_opk_reservations(now).await {
+                    Ok(released) => {
+                        println!("Released {released} unused one time key reservations.")
+                    }
+                    Err(e) => eprintln!("Failed to release one time key reservations: {e}"),
+                }
+                match self
+                    .storage
+                    .count_stale_spks(now - policy.max_spk_age)
+                    .await
+                {
+                    Ok(stale) => println!(
+                        "{stale} devices have signed pre keys older than {:?}.",
+                        policy.max_spk_age
+                    ),
+                    Err(e) => eprintln!("Failed to count stale signed pre keys: {e}"),
+                }
+            }
+        })
+    }
+
+    /// Checkpoints storage's write-ahead log whenever it has grown past `policy.max_wal_bytes`,
+    /// and refreshes its query planner statistics every `policy.optimize_interval`.
+    pub fn spawn_maintenance_task(self: Arc<Self>, policy: MaintenancePolicy) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut checkpoints = tokio::time::interval(policy.checkpoint_interval);
+            // Nothing needs optimizing as soon as the server starts.
+            let mut optimizations = policy
+                .optimize_interval
+                .map(|interval| tokio::time::interval_at(Instant::now() + interval, interval));
+            loop {
+                tokio::select! {
+                    _ = checkpoints.tick() => self.checkpoint(policy.max_wal_bytes).await,
+                    _ = async { optimizations.as_mut().unwrap().tick().await },
+                        if optimizations.is_some() =>
+                    {
+                        match self.storage.optimize().await {
+                            Ok(()) => println!("Optimized the database."),
+                            Err(e) => eprintln!("Failed to optimize the database: {e}"),
+                        }
+                    }
+                }
+            }
+        })
+    }
+
+    async fn checkpoint(&self, max_wal_bytes: u64) {
+        match self.storage.checkpoint(max_wal_bytes).await {
+            Ok(Some(checkpoint)) => {
+                self.checkpoints.fetch_add(1, Ordering::Relaxed);
+                if !checkpoint.truncated {
+                    self.incomplete_checkpoints.fetch_add(1, Ordering::Relaxed);
+                }
+                println!(
+                    "Checkpointed {} of {} pages from a {} byte write-ahead log{}.",
+                    checkpoint.checkpointed_pages,
+                    checkpoint.log_pages,
+                    checkpoint.wal_size_bytes,
+                    if checkpoint.truncated {
+                        ""
+                    } else {
+                        ", which readers kept from being emptied"
+                    }
+                );
+            }
+            Ok(None) => {}
+            Err(e) => eprintln!("Failed to checkpoint the write-ahead log: {e}"),
+        }
+    }
+
+    /// Logs how big storage has grown every `interval`, for capacity planning.
+    pub fn spawn_stats_task(self: Arc<Self>, interval: Duration) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+            loop {
+                interval.tick().await;
+                let stats = match self.storage.stats(1).await {
+                    Ok(stats) => stats,
+                    Err(e) => {
+                        eprintln!("Failed to gather storage stats: {e}");
+                        continue;
+                    }
+                };
+                println!(
+                    "{} users with {} devices, {} one time keys, {} queued messages and {} open \
+                     message streams.",
+                    stats.identities,
+                    stats.devices,
+                    stats.opks,
+                    stats.queued_messages,
+                    self.open_streams()
+                );
+                println!("Answered {} pings.", self.pings());
+                println!(
+                    "Turned away {} registrations, {} pre key requests and {} challenge requests \
+                     from busy addresses.",
+                    self.ip_limits.throttled(RpcClass::Registration),
+                    self.ip_limits.throttled(RpcClass::PreKeys),
+                    self.ip_limits.throttled(RpcClass::Challenge)
+                );
+                let rows: Vec<String> = stats
+                    .table_rows
+                    .iter()
+                    .map(|(table, rows)| format!("{table}={rows}"))
+                    .collect();
+                println!("Table rows: {}.", rows.join(", "));
+                if let (Some(size), Some(wal_size)) = (stats.size_bytes, stats.wal_size_bytes) {
+                    println!("Database is {size} bytes with {wal_size} bytes of write-ahead log.");
+                }
+                if let Some(oldest) = stats.oldest_message_at {
+                    let age = SystemTime::now().duration_since(oldest).unwrap_or_default();
+                    println!("Oldest undelivered message was queued {age:?} ago.");
+                }
+                if let Some((identity, depth)) = stats.deepest_queues.first() {
+                    println!("Deepest queue is \"{identity}\" with {depth} messages.");
+                }
+            }
+        })
+    }
+
+    /// Releases rate limiter state for senders and addresses that have been idle.
+    pub fn evict_idle_rate_limits(&self) {
+        self.send_limiter.evict_idle();
+        self.ip_limits.evict_idle();
+    }
+
+    /// Closes storage, leaving the database file complete on its own. Tasks using the controller
+    /// should be stopped first.
+    pub async fn close(&self) -> Result<()> {
+        self.storage.close().await
+    }
+
+    /// Stops accepting new message streams and closes the open ones.
+    /// Messages already queued on a stream are delivered before it ends.
+    pub fn drain(&self) {
+        let mut receivers = self.receivers.lock().unwrap();
+        self.draining.store(true, Ordering::SeqCst);
+        println!("Closing {} open message streams.", receivers.len());
+        receivers.clear();
+        self.status_subscribers.lock().unwrap().clear();
+    }
+
+    pub(crate) fn storage(&self) -> &(dyn Storage + Send + Sync) {
+        self.storage.as_ref()
+    }
+
+    /// How many devices have a message stream open.
+    pub(crate) fn open_streams(&self) -> usize {
+        self.receivers.lock().unwrap().len()
+    }
+
+    /// How many pings the server has answered since it started.
+    pub(crate) fn pings(&self) -> u64 {
+        self.pings.load(Ordering::Relaxed)
+    }
+
+    /// How many write-ahead log checkpoints the maintenance task has run since the server
+    /// started, and how many of them couldn't empty the log.
+    pub(crate) fn checkpoints(&self) -> (u64, u64) {
+        (
+            self.checkpoints.load(Ordering::Relaxed),
+            self.incomplete_checkpoints.load(Ordering::Relaxed),
+        )
+    }
+
+    pub(crate) fn ip_limits(&self) -> &IpRateLimits {
+        &self.ip_limits
+    }
+
+    pub(crate) fn federation(&self) -> Option<&Federation> {
+        self.federation.as_ref()
+    }
+
+    /// Ends the message streams `identity`'s devices have open, e.g. once it is deleted.
+    pub(crate) fn close_streams(&self, identity: &str) {
+        // Dropping the senders ends the streams.
+        self.receivers
             .lock()
             .unwrap()
-            .get(&recipient_identity)
-            .map(|tx| tx.clone());
-        if let Some(tx) = tx {
-            if let Ok(()) = tx.send(Ok(message_proto.clone())).await {
-                return Ok(Response::new(SendMessageResponse {}));
+            .retain(|(receiver, _), _| receiver != identity);
+        self.status_subscribers.lock().unwrap().remove(identity);
+    }
+
+    pub(crate) fn is_connected(&self, identity: &str, device_id: u32) -> bool {
+        self.receivers
+            .lock()
+            .unwrap()
+            .contains_key(&(identity.to_owned(), device_id))
+    }
+
+    /// Checks `signature` over `payload` against the identity key of each of `identity`'s
+    /// devices, so any device can act for the identity.
+    async fn verify_any_device(
+        &self,
+        identity: &str,
+        payload: &[u8],
+        signature: &Signature,
+    ) -> Result<()> {
+        let devices = self.storage.get_devices(identity).await?;
+        if devices.is_empty() {
+            return Err(Status::not_found("user not found"));
+        }
+        for device_id in devices {
+            let ik = self.storage.get_current_keys(identity, device_id).await?.ik;
+            if ik.verify_strict(payload, signature).is_ok() {
+                authenticated(identity);
+                return Ok(());
             }
         }
-
-        self.storage
-            .add_message(&recipient_identity, message_proto)?;
-        Ok(Response::new(SendMessageResponse {}))
+        Err(Status::unauthenticated(
+            "signature does not match any device",
+        ))
     }
 
-    type RetrieveMessagesStream = ReceiverStream<Result<MessageProto>>;
-    async fn retrieve_messages(
+    /// Checks that a registration for `identity` solved a challenge this server handed out, if it
+    /// requires one. Each challenge can only be used once, whether or not it was solved.
+    fn check_registration_challenge(
         &self,
-        request: Request<RetrieveMessagesRequest>,
-    ) -> Result<Response<Self::RetrieveMessagesStream>> {
-        let request = request.into_inner();
-        println!("Retrieving \"{}\"'s messages.", request.identity());
+        identity: &str,
+        challenge: Option<&[u8]>,
+        solution: Option<&[u8]>,
+    ) -> Result<()> {
+        if self.registration_difficulty == 0 {
+            return Ok(());
+        }
+        let (Some(challenge), Some(solution)) = (challenge, solution) else {
+            return Err(Status::failed_precondition(
+                "registration requires solving a challenge from GetRegistrationChallenge",
+            ));
+        };
+        let expiry = self
+            .registration_challenges
+            .lock()
+            .unwrap()
+            .remove(challenge);
+        if expiry.filter(|expiry| *expiry > Instant::now()).is_none() {
+            return Err(Status::failed_precondition(
+                "unknown, expired or already used registration challenge",
+            ));
+        }
+        if !pow::verify(challenge, identity, solution, self.registration_difficulty) {
+            return Err(Status::permission_denied(
+                "registration challenge solution doesn't meet the difficulty",
+            ));
+        }
+        Ok(())
+    }
 
-        let identity = request
-            .identity
-            .ok_or(Status::invalid_argument("request missing identity"))?;
-        let (tx, rx) = mpsc::channel(100);
+    /// Uses up one use of `code` if registering `identity` requires an invite, refusing with
+    /// PermissionDenied and the reason in the `invite-rejected` metadata otherwise.
+    async fn redeem_invite(&self, identity: &str, code: Option<&str>) -> Result<()> {
+        if !self.invites_required || self.storage.user_exists(identity).await? {
+            return Ok(());
+        }
+        let reason = match code {
+            Some(code) => match self
+                .storage
+                .redeem_invite(&hash_invite_code(code), SystemTime::now())
+                .await?
+            {
+                Ok(()) => return Ok(()),
+                Err(rejection) => rejection.reason(),
+            },
+            None => "missing",
+        };
+        let mut status = Status::permission_denied(format!(
+            "registering a new identity requires a valid invite code ({reason})"
+        ));
+        status
+            .metadata_mut()
+            .insert("invite-rejected", reason.parse().unwrap());
+        Err(status)
+    }
 
-        // TODO(#14) - RetrieveMessages requires proof of possession
-        for message in self.storage.get_messages(&identity)? {
-            // TODO handle result.
-            let _ = tx.send(Ok(message.into())).await;
+    /// Everything stored for one of an identity's devices, in the order `ExportAccountData`
+    /// streams it.
+    async fn export_device(
+        &self,
+        device: DeviceStats,
+        include_ciphertexts: bool,
+    ) -> Result<Vec<AccountDataRecord>> {
+        let (identity, device_id) = (device.identity.as_str(), device.device_id);
+        let keys = self.storage.get_current_keys(identity, device_id).await?;
+        let mut spk = exported_pre_key(
+            PreKeyKind::Signed,
+            keys.spk_id,
+            keys.spk.pre_key.unwrap_or_default(),
+            keys.spk.signature,
+        );
+        spk.uploaded_at = Some(unix_seconds(keys.spk_uploaded_at));
+        let mut records = vec![
+            Record::Registration(DeviceRegistration {
+                identity_key: Some(keys.ik.as_bytes().into()),
+                registered_at: Some(unix_seconds(device.registered_at)),
+                cipher_suite: keys.cipher_suite,
+            }),
+            Record::PreKey(spk),
+        ];
+        for (id, key) in self.storage.get_opks(identity, device_id).await? {
+            records.push(Record::PreKey(exported_pre_key(
+                PreKeyKind::OneTime,
+                id,
+                key.as_bytes().into(),
+                None,
+            )));
+        }
+        if let Some((id, key)) = self
+            .storage
+            .get_last_resort_key(identity, device_id)
+            .await?
+        {
+            records.push(Record::PreKey(exported_pre_key(
+                PreKeyKind::LastResort,
+                id,
+                key.as_bytes().into(),
+                None,
+            )));
+        }
+        for (id, key) in self.storage.get_kem_opks(identity, device_id).await? {
+            records.push(Record::PreKey(exported_pre_key(
+                PreKeyKind::OneTimeKem,
+                id,
+                key.pre_key.unwrap_or_default(),
+                key.signature,
+            )));
+        }
+        if let Some((id, key)) = self
+            .storage
+            .get_last_resort_kem_key(identity, device_id)
+            .await?
+        {
+            records.push(Record::PreKey(exported_pre_key(
+                PreKeyKind::LastResortKem,
+                id,
+                key.pre_key.unwrap_or_default(),
+                key.signature,
+            )));
         }
-        self.receivers.lock().unwrap().insert(identity, tx);
+        for queued in self.storage.peek_messages(identity, device_id).await? {
+            records.push(Record::QueuedMessage(QueuedMessageMetadata {
+                queued_at: Some(unix_seconds(queued.queued_at)),
+                size: Some(prost::Message::encoded_len(&queued.message) as u64),
+                message: include_ciphertexts.then_some(queued.message),
+            }));
+        }
+        if let Some(token) = self.storage.get_push_token(identity, device_id).await? {
+            let mut exported = ExportedPushToken {
+                platform: None,
+                device_token: Some(token.token),
+                app_version: token.app_version,
+            };
+            exported.set_platform(token.platform.into());
+            records.push(Record::PushToken(exported));
+        }
+        Ok(records
+            .into_iter()
+            .map(|record| AccountDataRecord {
+                device_id: Some(device_id),
+                record: Some(record),
+            })
+            .collect())
+    }
 
-        Ok(Response::new(ReceiverStream::new(rx)))
+    /// Drops the keys at the end of `pre_keys` that don't fit under the device's quota, unless
+    /// the quota evicts old keys to make room. `replacing` means `pre_keys` take the place of the
+    /// device's stored keys rather than join them.
+    async fn opks_that_fit(
+        &self,
+        identity: &str,
+        device_id: u32,
+        mut pre_keys: Vec<X25519PublicKey>,
+        replacing: bool,
+    ) -> Result<Vec<X25519PublicKey>> {
+        let room = if replacing || self.opk_quota.policy == QuotaPolicy::EvictOldest {
+            self.opk_quota.max_keys
+        } else {
+            let stored = self.storage.count_opks(identity, device_id).await?;
+            self.opk_quota.max_keys.saturating_sub(stored)
+        };
+        pre_keys.truncate(room);
+        Ok(pre_keys)
+    }
+
+    /// Stores the keys in `pre_keys` that fit under the device's quota, skipping those the device
+    /// already has or that repeat an earlier key. Returns the ids of the accepted keys, a prefix of
+    /// `pre_keys` with skipped keys under the id they were first assigned, and how many keys were
+    /// skipped. `replacing` is as for `opks_that_fit`.
+    async fn store_opks(
+        &self,
+        identity: &str,
+        device_id: u32,
+        pre_keys: Vec<X25519PublicKey>,
+        replacing: bool,
+    ) -> Result<(Vec<u32>, usize)> {
+        /// Where the id of one of `pre_keys` comes from.
+        #[derive(Clone, Copy)]
+        enum Slot {
+            Stored(u32),
+            New(usize),
+        }
+        let mut slots: HashMap<X25519PublicKey, Slot> = if replacing {
+            HashMap::new()
+        } else {
+            self.storage
+                .get_opks(identity, device_id)
+                .await?
+                .into_iter()
+                .map(|(id, key)| (key, Slot::Stored(id)))
+                .collect()
+        };
+        let mut order = Vec::with_capacity(pre_keys.len());
+        let mut new_keys = Vec::new();
+        let mut skipped = 0;
+        for key in pre_keys {
+            match slots.entry(key) {
+                Entry::Occupied(slot) => {
+                    skipped += 1;
+                    order.push(*slot.get());
+                }
+                Entry::Vacant(slot) => {
+                    order.push(*slot.insert(Slot::New(new_keys.len())));
+                    new_keys.push(key);
+                }
+            }
+        }
+        let new_keys = self
+            .opks_that_fit(identity, device_id, new_keys, replacing)
+            .await?;
+        let new_ids = if replacing {
+            self.storage
+                .replace_opks(identity, device_id, new_keys, self.opk_quota)
+                .await?
+        } else {
+            self.storage
+                .add_opks(identity, device_id, new_keys, self.opk_quota)
+                .await?
+        };
+        let ids = order
+            .into_iter()
+            .map_while(|slot| match slot {
+                Slot::Stored(id) => Some(id),
+                Slot::New(i) => new_ids.get(i).copied(),
+            })
+            .collect();
+        Ok((ids, skipped))
+    }
+
+    /// Tells the device, if it has a message stream open, that it has `remaining` one time keys
+    /// left. Devices without one find out when they next check their count.
+    fn notify_pre_key_status(&self, identity: &str, device_id: u32, remaining: usize) {
+        let receivers = self.receivers.lock().unwrap();
+        let Some(tx) = receivers.get(&(identity.to_owned(), device_id)) else {
+            return;
+        };
+        let status = ServerControl {
+            heartbeat: None,
+            pre_key_status: Some(PreKeyStatus {
+                remaining: Some(remaining as u32),
+            }),
+        }
+        .into();
+        // Like heartbeats, a full stream isn't worth waiting on.
+        let _ = tx.try_send(Ok(status));
+    }
+
+    /// Tells the sender of `message`, if it was sent with a uuid and they have a
+    /// MessageStatusStream open, that its copy for the device reached `state`.
+    fn publish_status(
+        &self,
+        recipient: &str,
+        device_id: u32,
+        message: &MessageProto,
+        state: DeliveryState,
+    ) {
+        let (Some(sender), Some(uuid)) = (message.sender(), &message.message_uuid) else {
+            return;
+        };
+        let mut subscribers = self.status_subscribers.lock().unwrap();
+        let Some(streams) = subscribers.get_mut(sender) else {
+            return;
+        };
+        let mut status = MessageStatus {
+            message_uuid: Some(uuid.clone()),
+            recipient_identity: Some(recipient.to_owned()),
+            device_id: Some(device_id),
+            state: None,
+        };
+        status.set_state(state);
+        // A subscriber too slow to keep up misses updates rather than holding up delivery.
+        streams.retain(|tx| {
+            !matches!(
+                tx.try_send(Ok(status.clone())),
+                Err(TrySendError::Closed(_))
+            )
+        });
+        if streams.is_empty() {
+            subscribers.remove(sender);
+        }
+    }
+
+    /// Delivers `message` to an open stream for the device, or queues it until one opens and
+    /// wakes the device to open one.
+    async fn deliver(&self, recipient: &str, device_id: u32, message: MessageProto) -> Result<()> {
+        let tx = self
+            .receivers
+            .lock()
+            .unwrap()
+            .get(&(recipient.to_owned(), device_id))
+            .map(|tx| tx.clone());
+        if let Some(tx) = tx {
+            if let Ok(()) = tx.send(Ok(message.clone())).await {
+                self.publish_status(recipient, device_id, &message, DeliveryState::Delivered);
+                return Ok(());
+            }
+        }
+        self.storage
+            .add_message(recipient, device_id, message.clone())
+            .await?;
+        self.publish_status(recipient, device_id, &message, DeliveryState::Queued);
+        self.wake(recipient, device_id).await;
+        Ok(())
+    }
+
+    /// Pushes a wakeup to the device if it has a push token. The push is sent in the background
+    /// so senders don't wait on the push service. Tokens the push service rejects are forgotten.
+    async fn wake(&self, identity: &str, device_id: u32) {
+        if self.push.is_empty() {
+            return;
+        }
+        // The message is already queued, so failing to wake the device doesn't fail the send.
+        let token = match self.storage.get_push_token(identity, device_id).await {
+            Ok(Some(token)) => token,
+            Ok(None) => return,
+            Err(e) => {
+                eprintln!("Failed to look up push token: {e}");
+                return;
+            }
+        };
+        let Some(push) = self.push.get(&token.platform).cloned() else {
+            return;
+        };
+        let storage = self.storage.clone();
+        let identity = identity.to_owned();
+        tokio::spawn(async move {
+            match push
+                .notify(&identity, &token.token, WakeupPayload { device_id })
+                .await
+            {
+                Ok(()) => {}
+                Err(PushError::InvalidToken) => {
+                    println!("Pruning rejected push token of \"{identity}\" device {device_id}.");
+                    if let Err(e) = storage
+                        .delete_push_token(&identity, device_id, &token.token)
+                        .await
+                    {
+                        eprintln!("Failed to prune push token: {e}");
+                    }
+                }
+                Err(e) => eprintln!("Failed to wake \"{identity}\" device {device_id}: {e}"),
+            }
+        });
+    }
+}
+
+/// Sends a heartbeat down the stream `tx` feeds every `interval` until the stream ends. Only a
+/// weak reference to `tx` is kept, so heartbeats don't keep the stream open.
+/// Refuses requests signed at a `timestamp` too far from now for the signature to be trusted
+/// as fresh.
+fn check_request_timestamp(timestamp: u64) -> Result<()> {
+    if unix_seconds(SystemTime::now()).abs_diff(timestamp) > SIGNED_REQUEST_MAX_SKEW.as_secs() {
+        return Err(Status::failed_precondition(
+            "request timestamp is too far from the server's clock",
+        ));
+    }
+    Ok(())
+}
+
+fn exported_pre_key(
+    kind: PreKeyKind,
+    id: u32,
+    pre_key: Vec<u8>,
+    signature: Option<Vec<u8>>,
+) -> ExportedPreKey {
+    let mut exported = ExportedPreKey {
+        id: Some(id),
+        pre_key: Some(pre_key),
+        signature,
+        ..Default::default()
+    };
+    exported.set_kind(kind);
+    exported
+}
+
+fn spawn_heartbeat(tx: &Sender<Result<MessageProto>>, interval: Duration) {
+    let tx = tx.downgrade();
+    tokio::spawn(async move {
+        let mut ticks = tokio::time::interval_at(Instant::now() + interval, interval);
+        loop {
+            ticks.tick().await;
+            let Some(tx) = tx.upgrade() else {
+                return;
+            };
+            let heartbeat = ServerControl {
+                heartbeat: Some(true),
+                pre_key_status: None,
+            }
+            .into();
+            // A full stream isn't idle, so there is no need to wait for room.
+            if let Err(TrySendError::Closed(_)) = tx.try_send(Ok(heartbeat)) {
+                return;
+            }
+        }
+    });
+}
+
+/// Parses the identity in `field` of a request, so malformed identities never reach storage.
+fn parse_identity(field: &str, identity: Option<String>) -> Result<Identity> {
+    let identity =
+        identity.ok_or_else(|| Status::invalid_argument(format!("request missing {field}")))?;
+    Ok(Identity::parse(field, identity)?)
+}
+
+/// Fails with `InvalidArgument` if the repeated `field` has more than `max` entries.
+fn check_count(field: &str, count: usize, max: usize) -> Result<()> {
+    if count > max {
+        return Err(
+            ParseError::new(field, ParseErrorReason::TooMany { max, actual: count }).into(),
+        );
+    }
+    Ok(())
+}
+
+/// Checks that the one time prekey bundle `opks` was signed by `ik`, returning its keys.
+fn verify_opks(
+    ik: &VerifyingKey,
+    opks: Option<SignedPreKeysProto>,
+) -> Result<Vec<X25519PublicKey>> {
+    let opks = opks.ok_or_else(|| ParseError::missing("one_time_key_bundle"))?;
+    check_count(
+        "one_time_key_bundle.pre_keys",
+        opks.pre_keys.len(),
+        MAX_OPKS_PER_REQUEST,
+    )?;
+    let pre_keys: Vec<X25519PublicKey> = opks
+        .pre_keys
+        .iter()
+        .map(|key| parse_x25519_public_key("one_time_key_bundle.pre_keys", key))
+        .collect::<Result<Vec<_>, _>>()?;
+    let signature = opks
+        .signature
+        .ok_or_else(|| ParseError::missing("one_time_key_bundle.signature"))?;
+    let signature = parse_signature("one_time_key_bundle.signature", &signature)?;
+    verify_bundle(ik, &pre_keys, &signature).map_err(|_| {
+        Status::unauthenticated("failed to validate one time prekey bundle signature")
+    })?;
+    Ok(pre_keys)
+}
+
+/// Checks that the last-resort key in `field` was signed by `ik`, returning the key.
+fn verify_last_resort_key(
+    ik: &VerifyingKey,
+    field: &str,
+    key: SignedPreKeyProto,
+) -> Result<X25519PublicKey> {
+    let key = protocol::x3dh::SignedPreKey::try_from(key).map_err(|e| e.within(field))?;
+    verify_bundle(ik, &[key.pre_key], &key.signature)
+        .map_err(|_| Status::unauthenticated("failed to validate last resort key signature"))?;
+    Ok(key.pre_key)
+}
+
+/// Checks that the KEM pre key in `field` is well formed and was signed by `ik`.
+fn verify_kem_key(
+    ik: &VerifyingKey,
+    field: &str,
+    key: SignedPreKeyProto,
+) -> Result<SignedPreKeyProto> {
+    let kem_key = SignedKemPreKey::try_from(key.clone()).map_err(|e| e.within(field))?;
+    verify_kem_pre_key(ik, &kem_key)
+        .map_err(|_| Status::unauthenticated("failed to validate kem pre key signature"))?;
+    Ok(key)
+}
+
+#[tonic::async_trait]
+impl Brongnal for BrongnalController {
+    async fn register_pre_key_bundle(
+        &self,
+        request: Request<RegisterPreKeyBundleRequest>,
+    ) -> Result<Response<RegisterPreKeyBundleResponse>> {
+        let request = request.into_inner();
+        println!("Registering PreKeyBundle for \"{}\".", request.identity());
+
+        let identity = parse_identity("identity", request.identity.clone())?;
+        // Domains are how federated servers tell their identities apart.
+        if self.federation.is_some() && identity.contains('@') {
+            return Err(Status::invalid_argument("identity may not contain '@'"));
+        }
+        self.check_registration_challenge(
+            &identity,
+            request.challenge.as_deref(),
+            request.challenge_solution.as_deref(),
+        )?;
+        let device_id = request.device_id();
+        let ik = request
+            .identity_key
+            .as_deref()
+            .ok_or_else(|| ParseError::missing("identity_key"))?;
+        let ik = parse_verifying_key("identity_key", ik)?;
+        let spk_proto = request
+            .signed_pre_key
+            .ok_or_else(|| ParseError::missing("signed_pre_key"))?;
+        let spk = protocol::x3dh::SignedPreKey::try_from(spk_proto.clone())
+            .map_err(|e| e.within("signed_pre_key"))?;
+        verify_bundle(&ik, &[spk.pre_key], &spk.signature)
+            .map_err(|_| Status::unauthenticated("failed to validate signed prekey signature"))?;
+        authenticated(&identity);
+
+        let pre_keys = verify_opks(&ik, request.one_time_key_bundle)?;
+        let last_resort_key = request
+            .last_resort_key
+            .map(|key| verify_last_resort_key(&ik, "last_resort_key", key))
+            .transpose()?;
+        check_count(
+            "one_time_kem_keys",
+            request.one_time_kem_keys.len(),
+            MAX_OPKS_PER_REQUEST,
+        )?;
+        let kem_opks = request
+            .one_time_kem_keys
+            .into_iter()
+            .map(|key| verify_kem_key(&ik, "one_time_kem_keys", key))
+            .collect::<Result<Vec<_>>>()?;
+        let last_resort_kem_key = request
+            .last_resort_kem_key
+            .map(|key| verify_kem_key(&ik, "last_resort_kem_key", key))
+            .transpose()?;
+        // Only once the request is otherwise valid, so a malformed one doesn't use up the code.
+        self.redeem_invite(&identity, request.invite_code.as_deref())
+            .await?;
+
+        // A new identity or signed pre key means the client no longer holds the secrets for any
+        // one time keys uploaded by a previous installation.
+        let replaces_keys = match self.storage.get_current_keys(&identity, device_id).await {
+            Ok(keys) => keys.ik != ik || keys.spk != spk_proto,
+            Err(status) if status.code() == Code::NotFound => false,
+            Err(status) => return Err(status),
+        };
+        let spk_id = self
+            .storage
+            .register_user(identity.clone(), device_id, ik, spk_proto)
+            .await?;
+        self.storage
+            .set_cipher_suite(&identity, device_id, request.cipher_suite)
+            .await?;
+        let (opk_ids, opks_skipped) = self
+            .store_opks(&identity, device_id, pre_keys, replaces_keys)
+            .await?;
+        let opk_count = self.storage.count_opks(&identity, device_id).await?;
+        // A previous installation's last-resort key goes the same way as its one time keys.
+        let last_resort_key_id = if last_resort_key.is_some() || replaces_keys {
+            let id = self
+                .storage
+                .set_last_resort_key(&identity, device_id, last_resort_key)
+                .await?;
+            last_resort_key.map(|_| id)
+        } else {
+            None
+        };
+        let kem_opk_ids = self
+            .storage
+            .replace_kem_opks(&identity, device_id, kem_opks)
+            .await?;
+        let last_resort_kem_key_id = if last_resort_kem_key.is_some() || replaces_keys {
+            let uploaded = last_resort_kem_key.is_some();
+            let id = self
+                .storage
+                .set_last_resort_kem_key(&identity, device_id, last_resort_kem_key)
+                .await?;
+            uploaded.then_some(id)
+        } else {
+            None
+        };
+
+        Ok(Response::new(RegisterPreKeyBundleResponse {
+            signed_pre_key_id: Some(spk_id),
+            one_time_keys_accepted: Some(opk_ids.len() as u32),
+            one_time_key_ids: opk_ids,
+            last_resort_key_id,
+            last_resort_kem_key_id,
+            one_time_kem_key_ids: kem_opk_ids,
+            one_time_key_count: Some(opk_count as u32),
+            one_time_keys_skipped: Some(opks_skipped as u32),
+        }))
+    }
+
+    async fn update_signed_pre_key(
+        &self,
+        request: Request<UpdateSignedPreKeyRequest>,
+    ) -> Result<Response<UpdateSignedPreKeyResponse>> {
+        let request = request.into_inner();
+        println!("Updating signed pre key for \"{}\".", request.identity());
+
+        let device_id = request.device_id();
+        let identity = parse_identity("identity", request.identity)?;
+        let spk_proto = request
+            .signed_pre_key
+            .ok_or_else(|| ParseError::missing("signed_pre_key"))?;
+        let spk = protocol::x3dh::SignedPreKey::try_from(spk_proto.clone())
+            .map_err(|e| e.within("signed_pre_key"))?;
+        // Only the holder of the registered identity key can sign a new pre key for it.
+        let ik = self
+            .storage
+            .get_current_keys(&identity, device_id)
+            .await?
+            .ik;
+        verify_bundle(&ik, &[spk.pre_key], &spk.signature)
+            .map_err(|_| Status::unauthenticated("failed to validate signed prekey signature"))?;
+        authenticated(&identity);
+        let last_resort_key = request
+            .last_resort_key
+            .map(|key| verify_last_resort_key(&ik, "last_resort_key", key))
+            .transpose()?;
+        let last_resort_kem_key = request
+            .last_resort_kem_key
+            .map(|key| verify_kem_key(&ik, "last_resort_kem_key", key))
+            .transpose()?;
+
+        let spk_id = self
+            .storage
+            .update_spk(&identity, device_id, spk_proto)
+            .await?;
+        let last_resort_key_id = match last_resort_key {
+            Some(key) => Some(
+                self.storage
+                    .set_last_resort_key(&identity, device_id, Some(key))
+                    .await?,
+            ),
+            None => None,
+        };
+        let last_resort_kem_key_id = match last_resort_kem_key {
+            Some(key) => Some(
+                self.storage
+                    .set_last_resort_kem_key(&identity, device_id, Some(key))
+                    .await?,
+            ),
+            None => None,
+        };
+        Ok(Response::new(UpdateSignedPreKeyResponse {
+            signed_pre_key_id: Some(spk_id),
+            last_resort_key_id,
+            last_resort_kem_key_id,
+        }))
+    }
+
+    async fn upload_one_time_keys(
+        &self,
+        request: Request<UploadOneTimeKeysRequest>,
+    ) -> Result<Response<UploadOneTimeKeysResponse>> {
+        let request = request.into_inner();
+        println!("Uploading one time keys for \"{}\".", request.identity());
+
+        let device_id = request.device_id();
+        let identity = parse_identity("identity", request.identity)?;
+        // Only the holder of the registered identity key can add keys for it.
+        let ik = self
+            .storage
+            .get_current_keys(&identity, device_id)
+            .await?
+            .ik;
+        let pre_keys = verify_opks(&ik, request.one_time_key_bundle)?;
+        authenticated(&identity);
+        let (one_time_key_ids, skipped) = self
+            .store_opks(&identity, device_id, pre_keys, false)
+            .await?;
+        let count = self.storage.count_opks(&identity, device_id).await?;
+        Ok(Response::new(UploadOneTimeKeysResponse {
+            one_time_keys_accepted: Some(one_time_key_ids.len() as u32),
+            one_time_key_ids,
+            one_time_key_count: Some(count as u32),
+            one_time_keys_skipped: Some(skipped as u32),
+        }))
+    }
+
+    async fn count_one_time_keys(
+        &self,
+        request: Request<CountOneTimeKeysRequest>,
+    ) -> Result<Response<CountOneTimeKeysResponse>> {
+        let request = request.into_inner();
+        let device_id = request.device_id();
+        let identity = parse_identity("identity", request.identity)?;
+        if !self
+            .storage
+            .get_devices(&identity)
+            .await?
+            .contains(&device_id)
+        {
+            return Err(Status::not_found("user not found"));
+        }
+        let count = self.storage.count_opks(&identity, device_id).await?;
+        Ok(Response::new(CountOneTimeKeysResponse {
+            one_time_key_count: Some(count as u32),
+        }))
+    }
+
+    async fn request_pre_keys(
+        &self,
+        request: Request<RequestPreKeysRequest>,
+    ) -> Result<Response<RequestPreKeysResponse>> {
+        let relayed_from = match &self.federation {
+            Some(federation) => federation.relayed_from(&request)?.map(str::to_owned),
+            None => None,
+        };
+        let request = request.into_inner();
+        println!("Retrieving PreKeyBundles for \"{}\".", request.identity());
+
+        let mut identity = parse_identity("identity", request.identity.clone())?;
+        if let Some(federation) = &self.federation {
+            match federation.route(&identity, relayed_from.as_deref())? {
+                Route::Local(name) => identity = Identity::parse("identity", name)?,
+                Route::Remote(peer) => {
+                    let response = peer.request_pre_keys(federation.domain(), request).await?;
+                    return Ok(Response::new(response));
+                }
+            }
+        }
+
+        let devices = self.storage.get_devices(&identity).await?;
+        if devices.is_empty() {
+            return Err(Status::not_found("user not found"));
+        }
+        let mut bundles = Vec::new();
+        for device_id in devices {
+            let keys = self.storage.get_current_keys(&identity, device_id).await?;
+            // Reserved rather than removed, so a sender that never sends doesn't burn the key.
+            let mut opk = self
+                .storage
+                .reserve_opk(
+                    &identity,
+                    device_id,
+                    SystemTime::now() + self.opk_reservation_ttl,
+                )
+                .await?;
+            // Each reservation takes one key, so the pool crosses a threshold when it lands on it.
+            if opk.is_some() {
+                let remaining = self.storage.count_opks(&identity, device_id).await?;
+                if self.opk_low_thresholds.contains(&remaining) {
+                    self.notify_pre_key_status(&identity, device_id, remaining);
+                }
+            }
+            // Rather than silently dropping the one time key from the agreement, fall back to the
+            // last-resort key and tell the sender so.
+            let last_resort = opk.is_none();
+            if last_resort {
+                opk = self
+                    .storage
+                    .get_last_resort_key(&identity, device_id)
+                    .await?;
+            }
+            // Likewise for KEM keys. Devices that never uploaded any get neither, and senders
+            // fall back to classic X3DH.
+            let mut kem_pre_key = self.storage.pop_kem_opk(&identity, device_id).await?;
+            let kem_last_resort = kem_pre_key.is_none();
+            if kem_last_resort {
+                kem_pre_key = self
+                    .storage
+                    .get_last_resort_kem_key(&identity, device_id)
+                    .await?;
+            }
+            bundles.push(PreKeyBundleProto {
+                identity_key: Some(keys.ik.as_bytes().into()),
+                one_time_key: opk.map(|(_, opk)| opk.as_bytes().into()),
+                signed_pre_key: Some(keys.spk),
+                signed_pre_key_id: Some(keys.spk_id),
+                one_time_key_id: opk.map(|(id, _)| id),
+                signed_pre_key_uploaded_at: Some(
+                    keys.spk_uploaded_at
+                        .duration_since(UNIX_EPOCH)
+                        .unwrap_or_default()
+                        .as_secs(),
+                ),
+                device_id: Some(device_id),
+                last_resort: (last_resort && opk.is_some()).then_some(true),
+                kem_pre_key_id: kem_pre_key.as_ref().map(|(id, _)| *id),
+                kem_last_resort: (kem_last_resort && kem_pre_key.is_some()).then_some(true),
+                kem_pre_key: kem_pre_key.map(|(_, key)| key),
+                cipher_suite: keys.cipher_suite,
+            });
+        }
+        Ok(Response::new(RequestPreKeysResponse { bundles }))
+    }
+
+    async fn send_message(
+        &self,
+        request: Request<SendMessageRequest>,
+    ) -> Result<Response<SendMessageResponse>> {
+        let client_ip = self.ip_limits.client_ip(
+            request.remote_addr().map(|addr| addr.ip()),
+            &request.metadata().clone().into_headers(),
+        );
+        let relayed_from = match &self.federation {
+            Some(federation) => federation.relayed_from(&request)?.map(str::to_owned),
+            None => None,
+        };
+        let request = request.into_inner();
+        println!(
+            "Received request to send message to: \"{}\".",
+            request.recipient_identity()
+        );
+
+        let mut recipient_identity =
+            parse_identity("recipient_identity", request.recipient_identity)?;
+        // Senders that predate message uuids can't be told apart from retries.
+        if let Some(uuid) = &request.message_uuid {
+            if uuid.len() != MESSAGE_UUID_LEN {
+                return Err(ParseError::new(
+                    "message_uuid",
+                    ParseErrorReason::Length {
+                        expected: MESSAGE_UUID_LEN,
+                        actual: uuid.len(),
+                    },
+                )
+                .into());
+            }
+        }
+        // Senders that predate devices send one message for the default device.
+        let mut device_messages: Vec<(u32, MessageProto)> = request
+            .device_messages
+            .into_iter()
+            .map(|device_message| {
+                Ok((
+                    device_message.device_id(),
+                    device_message
+                        .message
+                        .ok_or_else(|| ParseError::missing("device_messages.message"))?,
+                ))
+            })
+            .collect::<Result<_>>()?;
+        if let Some(message) = request.message {
+            device_messages.push((DEFAULT_DEVICE_ID, message));
+        }
+
+        // Anyone can claim to be any sender, so sends are limited by the address they come from.
+        // Peers relay sends from all of their users and are trusted to limit them, and unix
+        // domain sockets have no address unless a proxy forwarded one.
+        // TODO(#14) - Limit by an authenticated sender once one exists.
+        if let (None, Some(ip)) = (&relayed_from, client_ip) {
+            if let Err(retry_after) = self.send_limiter.check(&ip.to_string()) {
+                return Err(throttled(
+                    format!("too many messages from {ip}, retry after {retry_after:?}"),
+                    retry_after,
+                ));
+            }
+        }
+
+        if device_messages.is_empty() {
+            return Err(Status::invalid_argument("request missing message"));
+        }
+        for (_, message) in &device_messages {
+            let (field, len) = match &message.payload {
+                None => ("message.ciphertext", message.ciphertext().len()),
+                Some(Payload::X3dhInitial(initial)) => (
+                    "message.x3dh_initial.ciphertext",
+                    initial.ciphertext().len(),
+                ),
+                Some(Payload::SessionCiphertext(session)) => (
+                    "message.session_ciphertext.ciphertext",
+                    session.ciphertext().len(),
+                ),
+                Some(Payload::ServerControl(_)) => continue,
+            };
+            if len > self.max_ciphertext_len {
+                let mut status: Status = ParseError::new(
+                    field,
+                    ParseErrorReason::TooLong {
+                        max: self.max_ciphertext_len,
+                        actual: len,
+                    },
+                )
+                .into();
+                status.metadata_mut().insert(
+                    "max-ciphertext-len",
+                    self.max_ciphertext_len.to_string().parse().unwrap(),
+                );
+                return Err(status);
+            }
+        }
+        // The one time pre keys each device's message uses, which must be reserved for it.
+        let mut opk_ids = Vec::new();
+        for (device_id, message) in &device_messages {
+            match MessagePayload::try_from(message.clone()).map_err(|e| e.within("message"))? {
+                MessagePayload::X3DHInitial(message) => {
+                    // Last-resort keys are shared, and senders that predate ids can't be tied to
+                    // a reservation.
+                    if let (Some(opk_id), false) = (message.opk_id, message.last_resort) {
+                        opk_ids.push((*device_id, opk_id));
+                    }
+                }
+                MessagePayload::SessionCiphertext(_) => {}
+                MessagePayload::ServerControl(_) => {
+                    return Err(Status::permission_denied(
+                        "only the server sends server_control",
+                    ))
+                }
+                // Relaying what it can't vet would leave recipients to find out.
+                MessagePayload::Unknown => {
+                    return Err(
+                        ParseError::new("message.payload", ParseErrorReason::Unrecognized).into(),
+                    )
+                }
+            }
+        }
+        // Stored and relayed in the current form, whichever the sender used.
+        let device_messages: Vec<(u32, MessageProto)> = device_messages
+            .into_iter()
+            .map(|(device_id, message)| (device_id, message.upgrade()))
+            .collect();
+        if let Some(federation) = &self.federation {
+            match federation.route(&recipient_identity, relayed_from.as_deref())? {
+                Route::Local(name) => {
+                    recipient_identity = Identity::parse("recipient_identity", name)?
+                }
+                Route::Remote(peer) => {
+                    // So the recipient can tell which server the sender is on, and reply.
+                    let device_messages = device_messages
+                        .into_iter()
+                        .map(|(device_id, mut message)| {
+                            let sender = federation.qualify(message.sender().unwrap_or_default());
+                            message.set_sender(sender);
+                            DeviceMessage {
+                                device_id: Some(device_id),
+                                message: Some(message),
+                            }
+                        })
+                        .collect();
+                    let response = peer
+                        .send_message(
+                            federation.domain(),
+                            SendMessageRequest {
+                                recipient_identity: Some(recipient_identity.into()),
+                                message: None,
+                                device_messages,
+                                message_uuid: request.message_uuid,
+                                partial: request.partial,
+                            },
+                        )
+                        .await?;
+                    return Ok(Response::new(response));
+                }
+            }
+            // A peer may only relay messages from its own identities.
+            if let Some(peer_domain) = &relayed_from {
+                let suffix = format!("@{peer_domain}");
+                if device_messages
+                    .iter()
+                    .any(|(_, message)| !message.sender().unwrap_or_default().ends_with(&suffix))
+                {
+                    return Err(Status::permission_denied(format!(
+                        "{peer_domain} relayed a message from outside its domain"
+                    )));
+                }
+            }
+        }
+        let devices = self.storage.get_devices(&recipient_identity).await?;
+        if devices.is_empty() {
+            return Err(Status::not_found("recipient not found"));
+        }
+        // Every device needs its own copy, so a sender with a stale device list must refetch the
+        // recipient's prekeys rather than have some devices silently miss the message. Partial
+        // messages still may only go to devices that exist, each once.
+        let mut addressed: Vec<u32> = device_messages.iter().map(|(id, _)| *id).collect();
+        addressed.sort();
+        let misaddressed = if request.partial.unwrap_or_default() {
+            addressed.windows(2).any(|ids| ids[0] == ids[1])
+                || addressed.iter().any(|id| !devices.contains(id))
+        } else {
+            addressed != devices
+        };
+        if misaddressed {
+            return Err(Status::failed_precondition(format!(
+                "recipient has devices {devices:?} but messages were addressed to {addressed:?}"
+            )));
+        }
+
+        let message_id = match &request.message_uuid {
+            Some(uuid) => match self
+                .storage
+                .claim_message_uuid(&recipient_identity, uuid)
+                .await?
+            {
+                MessageClaim::New(id) => Some(id),
+                MessageClaim::Duplicate(id) => {
+                    println!("Message {id} to \"{recipient_identity}\" is a retry; not delivering it again.");
+                    return Ok(Response::new(SendMessageResponse {
+                        message_id: Some(id),
+                    }));
+                }
+            },
+            None => None,
+        };
+        let claim = UuidClaim {
+            storage: self.storage.clone(),
+            recipient: recipient_identity.to_string(),
+            uuid: request.message_uuid.clone(),
+        };
+        for (device_id, opk_id) in opk_ids {
+            let consumed = match self
+                .storage
+                .consume_opk(&recipient_identity, device_id, opk_id)
+                .await
+            {
+                Ok(true) => Ok(()),
+                Ok(false) => Err(Status::failed_precondition(format!(
+                    "one time key {opk_id} of device {device_id} is not reserved, request new pre keys"
+                ))),
+                Err(status) => Err(status),
+            };
+            if let Err(status) = consumed {
+                claim.release().await;
+                return Err(status);
+            }
+        }
+        for (device_id, mut message) in device_messages {
+            message.message_uuid = request.message_uuid.clone();
+            if let Err(status) = self.deliver(&recipient_identity, device_id, message).await {
+                // Let a retry deliver the message, even if to some devices a second time.
+                claim.release().await;
+                return Err(status);
+            }
+        }
+        claim.keep();
+        Ok(Response::new(SendMessageResponse { message_id }))
+    }
+
+    type RetrieveMessagesStream = ReceiverStream<Result<MessageProto>>;
+    async fn retrieve_messages(
+        &self,
+        request: Request<RetrieveMessagesRequest>,
+    ) -> Result<Response<Self::RetrieveMessagesStream>> {
+        let request = request.into_inner();
+        println!("Retrieving \"{}\"'s messages.", request.identity());
+
+        let device_id = request.device_id();
+        let identity = parse_identity("identity", request.identity)?;
+        if self.draining.load(Ordering::SeqCst) {
+            return Err(Status::unavailable("server is shutting down"));
+        }
+        if !self
+            .storage
+            .get_devices(&identity)
+            .await?
+            .contains(&device_id)
+        {
+            return Err(Status::not_found("user not found"));
+        }
+        let (tx, rx) = mpsc::channel(100);
+
+        // TODO(#14) - RetrieveMessages requires proof of possession
+        for message in self.storage.get_messages(&identity, device_id).await? {
+            // TODO handle result.
+            if tx.send(Ok(message.clone())).await.is_ok() {
+                self.publish_status(&identity, device_id, &message, DeliveryState::Delivered);
+            }
+        }
+        spawn_heartbeat(&tx, self.heartbeat_interval);
+        let mut receivers = self.receivers.lock().unwrap();
+        // Dropping `tx` ends the stream once the stored messages are flushed.
+        if !self.draining.load(Ordering::SeqCst) {
+            receivers.insert((identity.into(), device_id), tx);
+        }
+
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    async fn delete_user(
+        &self,
+        request: Request<DeleteUserRequest>,
+    ) -> Result<Response<DeleteUserResponse>> {
+        let request = request.into_inner();
+        println!("Deleting \"{}\".", request.identity());
+
+        let signature = Signature::from_slice(request.signature())
+            .map_err(|_| Status::invalid_argument("request has invalid signature"))?;
+        let identity = parse_identity("identity", request.identity)?;
+        self.verify_any_device(&identity, &delete_user_payload(&identity), &signature)
+            .await
+            .map_err(|e| match e.code() {
+                Code::Unauthenticated => {
+                    Status::unauthenticated("failed to validate delete user signature")
+                }
+                _ => e,
+            })?;
+
+        self.storage.delete_user(&identity).await?;
+        self.close_streams(&identity);
+        Ok(Response::new(DeleteUserResponse {}))
+    }
+
+    async fn delete_device(
+        &self,
+        request: Request<DeleteDeviceRequest>,
+    ) -> Result<Response<DeleteDeviceResponse>> {
+        let request = request.into_inner();
+        println!(
+            "Deleting \"{}\" device {}.",
+            request.identity(),
+            request.device_id()
+        );
+
+        let signature = Signature::from_slice(request.signature())
+            .map_err(|_| Status::invalid_argument("request has invalid signature"))?;
+        let device_id = request.device_id();
+        let identity = parse_identity("identity", request.identity)?;
+        // Any of the identity's devices may remove another, e.g. one that was lost.
+        self.verify_any_device(
+            &identity,
+            &delete_device_payload(&identity, device_id),
+            &signature,
+        )
+        .await
+        .map_err(|e| match e.code() {
+            Code::Unauthenticated => {
+                Status::unauthenticated("failed to validate delete device signature")
+            }
+            _ => e,
+        })?;
+
+        self.storage.delete_device(&identity, device_id).await?;
+        // Dropping the sender ends the stream the deleted device still has open.
+        self.receivers
+            .lock()
+            .unwrap()
+            .remove(&(identity.into(), device_id));
+        Ok(Response::new(DeleteDeviceResponse {}))
+    }
+
+    async fn get_registration_challenge(
+        &self,
+        _request: Request<GetRegistrationChallengeRequest>,
+    ) -> Result<Response<GetRegistrationChallengeResponse>> {
+        if self.registration_difficulty == 0 {
+            return Ok(Response::new(GetRegistrationChallengeResponse {
+                challenge: None,
+                difficulty: Some(0),
+                expires_in: None,
+            }));
+        }
+        let mut challenge = vec![0; REGISTRATION_CHALLENGE_LEN];
+        OsRng.fill_bytes(&mut challenge);
+        let now = Instant::now();
+        let mut challenges = self.registration_challenges.lock().unwrap();
+        challenges.retain(|_, expiry| *expiry > now);
+        challenges.insert(challenge.clone(), now + self.registration_challenge_ttl);
+        Ok(Response::new(GetRegistrationChallengeResponse {
+            challenge: Some(challenge),
+            difficulty: Some(self.registration_difficulty),
+            expires_in: Some(self.registration_challenge_ttl.as_secs()),
+        }))
+    }
+
+    async fn ping(&self, request: Request<PingRequest>) -> Result<Response<PingResponse>> {
+        // Not logged, since clients ping whenever their message stream goes quiet.
+        let nonce = request.into_inner().nonce;
+        if nonce
+            .as_ref()
+            .is_some_and(|nonce| nonce.len() > MAX_PING_NONCE_LEN)
+        {
+            return Err(Status::invalid_argument(format!(
+                "ping nonce is longer than {MAX_PING_NONCE_LEN} bytes"
+            )));
+        }
+        self.pings.fetch_add(1, Ordering::Relaxed);
+        let server_time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        Ok(Response::new(PingResponse {
+            nonce,
+            server_time_millis: Some(server_time.as_millis() as u64),
+        }))
+    }
+
+    async fn get_account_deletion_nonce(
+        &self,
+        request: Request<GetAccountDeletionNonceRequest>,
+    ) -> Result<Response<GetAccountDeletionNonceResponse>> {
+        let identity = parse_identity("identity", request.into_inner().identity)?;
+        if !self.storage.user_exists(&identity).await? {
+            return Err(Status::not_found("user not found"));
+        }
+        let mut nonce = vec![0; ACCOUNT_DELETION_NONCE_LEN];
+        OsRng.fill_bytes(&mut nonce);
+        let now = Instant::now();
+        let mut nonces = self.account_deletion_nonces.lock().unwrap();
+        nonces.retain(|_, (_, expiry)| *expiry > now);
+        nonces.insert(
+            nonce.clone(),
+            (identity.into(), now + ACCOUNT_DELETION_NONCE_TTL),
+        );
+        Ok(Response::new(GetAccountDeletionNonceResponse {
+            nonce: Some(nonce),
+            expires_in: Some(ACCOUNT_DELETION_NONCE_TTL.as_secs()),
+        }))
+    }
+
+    async fn delete_account(
+        &self,
+        request: Request<DeleteAccountRequest>,
+    ) -> Result<Response<DeleteAccountResponse>> {
+        let request = request.into_inner();
+        println!("Deleting account \"{}\".", request.identity());
+
+        let signature = Signature::from_slice(request.signature())
+            .map_err(|_| Status::invalid_argument("request has invalid signature"))?;
+        let identity = parse_identity("identity", request.identity)?;
+        let nonce = request
+            .nonce
+            .ok_or(Status::invalid_argument("request missing nonce"))?;
+        // Each nonce can only be used once, whether or not the signature was valid.
+        let issued = self.account_deletion_nonces.lock().unwrap().remove(&nonce);
+        if !issued
+            .is_some_and(|(issued_to, expiry)| *issued_to == *identity && expiry > Instant::now())
+        {
+            return Err(Status::failed_precondition(
+                "unknown, expired or already used account deletion nonce",
+            ));
+        }
+        self.verify_any_device(
+            &identity,
+            &delete_account_payload(&identity, &nonce),
+            &signature,
+        )
+        .await
+        .map_err(|e| match e.code() {
+            Code::Unauthenticated => {
+                Status::unauthenticated("failed to validate delete account signature")
+            }
+            _ => e,
+        })?;
+
+        // The ledger checks the tombstone was signed by one of the identity's keys.
+        if let (Some(gossamer), Some(tombstone)) = (&self.gossamer, request.tombstone) {
+            let verified: proto::SignedMessage = tombstone.clone().try_into()?;
+            if !matches!(
+                verified.message.action,
+                Some(Action::Tombstone(ref tombstone)) if tombstone.provider() == identity.as_str()
+            ) {
+                return Err(Status::invalid_argument(
+                    "tombstone is not a Tombstone for the identity",
+                ));
+            }
+            gossamer
+                .perform(Request::new(ActionRequest {
+                    message: Some(tombstone),
+                }))
+                .await?;
+        }
+        self.storage.delete_user(&identity).await?;
+        self.close_streams(&identity);
+        Ok(Response::new(DeleteAccountResponse {}))
+    }
+
+    type ExportAccountDataStream =
+        tokio_stream::Iter<std::vec::IntoIter<Result<AccountDataRecord>>>;
+
+    async fn export_account_data(
+        &self,
+        request: Request<ExportAccountDataRequest>,
+    ) -> Result<Response<Self::ExportAccountDataStream>> {
+        let request = request.into_inner();
+        println!("Exporting account data for \"{}\".", request.identity());
+
+        let signature = Signature::from_slice(request.signature())
+            .map_err(|_| Status::invalid_argument("request has invalid signature"))?;
+        let include_ciphertexts = request.include_ciphertexts();
+        let timestamp = request
+            .timestamp
+            .ok_or(Status::invalid_argument("request missing timestamp"))?;
+        let identity = parse_identity("identity", request.identity)?;
+        check_request_timestamp(timestamp)?;
+        self.verify_any_device(
+            &identity,
+            &export_account_data_payload(&identity, timestamp, include_ciphertexts),
+            &signature,
+        )
+        .await
+        .map_err(|e| match e.code() {
+            Code::Unauthenticated => {
+                Status::unauthenticated("failed to validate export account data signature")
+            }
+            _ => e,
+        })?;
+
+        let mut records = Vec::new();
+        for device in self.storage.device_stats(Some(&identity)).await? {
+            let device_records = self.export_device(device, include_ciphertexts).await?;
+            records.extend(device_records.into_iter().map(Ok));
+        }
+        Ok(Response::new(tokio_stream::iter(records)))
+    }
+
+    type MessageStatusStreamStream = ReceiverStream<Result<MessageStatus>>;
+
+    async fn message_status_stream(
+        &self,
+        request: Request<MessageStatusStreamRequest>,
+    ) -> Result<Response<Self::MessageStatusStreamStream>> {
+        let request = request.into_inner();
+        println!(
+            "Following the status of \"{}\"'s messages.",
+            request.identity()
+        );
+
+        let signature = Signature::from_slice(request.signature())
+            .map_err(|_| Status::invalid_argument("request has invalid signature"))?;
+        let device_id = request.device_id();
+        let timestamp = request
+            .timestamp
+            .ok_or(Status::invalid_argument("request missing timestamp"))?;
+        let identity = parse_identity("identity", request.identity)?;
+        check_request_timestamp(timestamp)?;
+        if self.draining.load(Ordering::SeqCst) {
+            return Err(Status::unavailable("server is shutting down"));
+        }
+        let ik = self
+            .storage
+            .get_current_keys(&identity, device_id)
+            .await?
+            .ik;
+        ik.verify_strict(
+            &message_status_stream_payload(&identity, device_id, timestamp),
+            &signature,
+        )
+        .map_err(|_| Status::unauthenticated("failed to validate message status signature"))?;
+        authenticated(&identity);
+
+        let (tx, rx) = mpsc::channel(100);
+        self.status_subscribers
+            .lock()
+            .unwrap()
+            .entry(identity.into())
+            .or_default()
+            .push(tx);
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    async fn publish_provisioning(
+        &self,
+        request: Request<PublishProvisioningRequest>,
+    ) -> Result<Response<PublishProvisioningResponse>> {
+        let request = request.into_inner();
+        println!("Publishing provisioning envelope.");
+
+        let provisioning_id = request
+            .provisioning_id
+            .filter(|id| id.len() == PROVISIONING_ID_LEN)
+            .ok_or(Status::invalid_argument(
+                "request has invalid provisioning_id",
+            ))?;
+        let envelope = request
+            .envelope
+            .ok_or(Status::invalid_argument("request missing envelope"))?;
+        if envelope.len() > MAX_PROVISIONING_ENVELOPE_BYTES {
+            return Err(Status::invalid_argument(format!(
+                "envelope is larger than {MAX_PROVISIONING_ENVELOPE_BYTES} bytes"
+            )));
+        }
+
+        let now = Instant::now();
+        let mut provisioning = self.provisioning.lock().unwrap();
+        provisioning.retain(|_, (expiry, _)| *expiry > now);
+        // The first envelope wins so a later one can't replace the key the new device receives.
+        if provisioning.contains_key(&provisioning_id) {
+            return Err(Status::already_exists("provisioning id is in use"));
+        }
+        provisioning.insert(provisioning_id, (now + self.provisioning_ttl, envelope));
+        Ok(Response::new(PublishProvisioningResponse {}))
+    }
+
+    async fn fetch_provisioning(
+        &self,
+        request: Request<FetchProvisioningRequest>,
+    ) -> Result<Response<FetchProvisioningResponse>> {
+        let request = request.into_inner();
+        let provisioning_id = request
+            .provisioning_id
+            .ok_or(Status::invalid_argument("request missing provisioning_id"))?;
+
+        // Envelopes are handed out once.
+        match self.provisioning.lock().unwrap().remove(&provisioning_id) {
+            Some((expiry, envelope)) if expiry > Instant::now() => {
+                Ok(Response::new(FetchProvisioningResponse {
+                    envelope: Some(envelope),
+                }))
+            }
+            _ => Err(Status::not_found("provisioning envelope not found")),
+        }
+    }
+
+    async fn register_push_token(
+        &self,
+        request: Request<RegisterPushTokenRequest>,
+    ) -> Result<Response<RegisterPushTokenResponse>> {
+        let request = request.into_inner();
+        println!(
+            "Registering push token for \"{}\" device {}.",
+            request.identity(),
+            request.device_id()
+        );
+
+        let signature = Signature::from_slice(request.signature())
+            .map_err(|_| Status::invalid_argument("request has invalid signature"))?;
+        let device_id = request.device_id();
+        let platform = request.platform();
+        let identity = parse_identity("identity", request.identity)?;
+        let device_token = request
+            .device_token
+            .filter(|token| !token.is_empty())
+            .ok_or(Status::invalid_argument("request missing device_token"))?;
+        if device_token.len() > MAX_PUSH_TOKEN_BYTES {
+            return Err(Status::invalid_argument(format!(
+                "device_token is longer than {MAX_PUSH_TOKEN_BYTES} bytes"
+            )));
+        }
+        let payload = register_push_token_payload(
+            &identity,
+            device_id,
+            &device_token,
+            platform,
+            request.app_version.as_deref().unwrap_or_default(),
+        );
+
+        // Only the device itself may say where it is woken.
+        let ik = self
+            .storage
+            .get_current_keys(&identity, device_id)
+            .await?
+            .ik;
+        ik.verify_strict(&payload, &signature)
+            .map_err(|_| Status::unauthenticated("failed to validate push token signature"))?;
+        authenticated(&identity);
+
+        self.storage
+            .set_push_token(
+                &identity,
+                device_id,
+                PushToken {
+                    platform: platform.try_into()?,
+                    token: device_token,
+                    app_version: request.app_version,
+                },
+            )
+            .await?;
+        Ok(Response::new(RegisterPushTokenResponse {}))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::brongnal::*;
+    use crate::memory_brongnal::MemoryStorage;
+    use crate::sqlite_brongnal::SqliteStorage;
+    use crate::uds;
+    use anyhow::Result;
+    use client::memory_client::MemoryClient;
+    use client::{connect_uds, X3DHClient};
+    use ed25519_dalek::{Signer, SigningKey};
+    use proto::gossamer::append_key::KeyPurpose;
+    use proto::gossamer::{AppendKey, GetLedgerRequest, Tombstone};
+    use proto::service::brongnal_client::BrongnalClient;
+    use proto::service::brongnal_server::BrongnalServer;
+    use proto::service::DeviceMessage;
+    use proto::service::PushPlatform as PushPlatformProto;
+    use proto::sign_action;
+    use protocol::bundle::sign_bundle;
+    use protocol::kem::{self, sign_kem_pre_key, KemPublicKey};
+    use protocol::x3dh::{CipherSuite, SignedPreKeys};
+    use tokio::sync::oneshot;
+    use tonic::transport::server::TcpConnectInfo;
+    use tonic::transport::Server;
+    use tonic::Code;
+    use x25519_dalek::StaticSecret as X25519StaticSecret;
+
+    fn send_message_request(sender: &str, recipient: &MemoryClient) -> Result<SendMessageRequest> {
+        let bundle = protocol::x3dh::PreKeyBundle {
+            ik: recipient.get_ik()?.verifying_key(),
+            opk: None,
+            spk: recipient.get_spk()?,
+            spk_id: None,
+            opk_id: None,
+            last_resort: false,
+            kem_pre_key: None,
+            kem_pre_key_id: None,
+            kem_last_resort: false,
+            suite: None,
+        };
+        let (_sk, message) = protocol::x3dh::initiate_send(
+            bundle,
+            sender.to_owned(),
+            &MemoryClient::new().get_ik()?,
+            b"Hello Bob!",
+        )?;
+        Ok(SendMessageRequest {
+            recipient_identity: Some(String::from("bob")),
+            message: Some(message.into()),
+            device_messages: Vec::new(),
+            message_uuid: None,
+            partial: None,
+        })
+    }
+
+    fn register_request(
+        client: &mut MemoryClient,
+        num_opks: u32,
+    ) -> Result<RegisterPreKeyBundleRequest> {
+        Ok(RegisterPreKeyBundleRequest {
+            identity: Some(String::from("bob")),
+            identity_key: Some(client.get_ik()?.verifying_key().to_bytes().to_vec()),
+            signed_pre_key: Some(client.get_spk()?.into()),
+            one_time_key_bundle: Some(client.create_opks(num_opks)?.into()),
+            device_id: None,
+            last_resort_key: None,
+            last_resort_kem_key: None,
+            one_time_kem_keys: Vec::new(),
+            cipher_suite: None,
+            challenge: None,
+            challenge_solution: None,
+            invite_code: None,
+        })
+    }
+
+    /// Registers `bob` and returns the signed pre key he registered with.
+    async fn register_bob(
+        controller: &BrongnalController,
+        bob: &mut MemoryClient,
+    ) -> Result<SignedPreKeyProto> {
+        let request = register_request(bob, 0)?;
+        let spk = request.signed_pre_key.clone().unwrap();
+        controller
+            .register_pre_key_bundle(Request::new(request))
+            .await?;
+        Ok(spk)
+    }
+
+    #[tokio::test]
+    async fn register_malformed_keys() -> Result<()> {
+        let controller = BrongnalController::new(Box::new(MemoryStorage::default()));
+        let mut bob = MemoryClient::new();
+
+        let mut request = register_request(&mut bob, 1)?;
+        request.identity_key.as_mut().unwrap().truncate(31);
+        let status = controller
+            .register_pre_key_bundle(Request::new(request))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+        assert_eq!(
+            status.message(),
+            "Invalid identity_key: expected 32 bytes, got 31"
+        );
+
+        let mut request = register_request(&mut bob, 1)?;
+        request.signed_pre_key.as_mut().unwrap().pre_key = None;
+        let status = controller
+            .register_pre_key_bundle(Request::new(request))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+        assert_eq!(status.message(), "Invalid signed_pre_key.pre_key: missing");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn register_invalid_identity() -> Result<()> {
+        let controller = BrongnalController::new(Box::new(MemoryStorage::default()));
+        let mut bob = MemoryClient::new();
+
+        for (identity, message) in [
+            (String::new(), "Invalid identity: empty"),
+            (
+                "b".repeat(10_000),
+                "Invalid identity: 10000 bytes exceeds the limit of 64",
+            ),
+            (
+                String::from("bob\0"),
+                "Invalid identity: contains the disallowed character '\\0'",
+            ),
+        ] {
+            let mut request = register_request(&mut bob, 1)?;
+            request.identity = Some(identity.clone());
+            let status = controller
+                .register_pre_key_bundle(Request::new(request))
+                .await
+                .unwrap_err();
+            assert_eq!(status.code(), Code::InvalidArgument, "{identity:?}");
+            assert_eq!(status.message(), message);
+            assert!(!controller.storage.user_exists(&identity).await?);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn registration_challenge() -> Result<()> {
+        let controller = BrongnalController::new(Box::new(MemoryStorage::default()))
+            .with_registration_difficulty(8);
+        let mut bob = MemoryClient::new();
+        let challenge = || async {
+            let response = controller
+                .get_registration_challenge(Request::new(GetRegistrationChallengeRequest {}))
+                .await?
+                .into_inner();
+            assert_eq!(response.difficulty, Some(8));
+            assert_eq!(
+                response.expires_in,
+                Some(REGISTRATION_CHALLENGE_TTL.as_secs())
+            );
+            anyhow::Ok(response.challenge.unwrap())
+        };
+        let mut register = |challenge: &[u8], solution: &[u8]| {
+            let mut request = register_request(&mut bob, 1)?;
+            request.challenge = Some(challenge.to_vec());
+            request.challenge_solution = Some(solution.to_vec());
+            anyhow::Ok(controller.register_pre_key_bundle(Request::new(request)))
+        };
+
+        let status = controller
+            .register_pre_key_bundle(Request::new(register_request(&mut MemoryClient::new(), 1)?))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::FailedPrecondition);
+
+        let accepted = challenge().await?;
+        let solution = pow::solve(&accepted, "bob", 8, 1 << 20).unwrap();
+        register(&accepted, &solution)?.await?;
+        // Challenges are single use.
+        assert_eq!(
+            register(&accepted, &solution)?.await.unwrap_err().code(),
+            Code::FailedPrecondition
+        );
+        assert_eq!(
+            register(&[0; REGISTRATION_CHALLENGE_LEN], &solution)?
+                .await
+                .unwrap_err()
+                .code(),
+            Code::FailedPrecondition
+        );
+
+        let rejected = challenge().await?;
+        let insufficient = (0..u64::MAX)
+            .map(u64::to_le_bytes)
+            .find(|solution| !pow::verify(&rejected, "bob", solution, 8))
+            .unwrap();
+        assert_eq!(
+            register(&rejected, &insufficient)?
+                .await
+                .unwrap_err()
+                .code(),
+            Code::PermissionDenied
+        );
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn registration_challenge_expires() -> Result<()> {
+        let controller = BrongnalController::new(Box::new(MemoryStorage::default()))
+            .with_registration_difficulty(1)
+            .with_registration_challenge_ttl(Duration::from_secs(60));
+        let challenge = controller
+            .get_registration_challenge(Request::new(GetRegistrationChallengeRequest {}))
+            .await?
+            .into_inner()
+            .challenge
+            .unwrap();
+        tokio::time::advance(Duration::from_secs(61)).await;
+        let mut request = register_request(&mut MemoryClient::new(), 1)?;
+        request.challenge_solution =
+            Some(pow::solve(&challenge, "bob", 1, 1 << 20).unwrap().to_vec());
+        request.challenge = Some(challenge);
+        assert_eq!(
+            controller
+                .register_pre_key_bundle(Request::new(request))
+                .await
+                .unwrap_err()
+                .code(),
+            Code::FailedPrecondition
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn registration_challenge_disabled() -> Result<()> {
+        let controller = BrongnalController::new(Box::new(MemoryStorage::default()));
+        let response = controller
+            .get_registration_challenge(Request::new(GetRegistrationChallengeRequest {}))
+            .await?
+            .into_inner();
+        assert_eq!(response.difficulty, Some(0));
+        assert_eq!(response.challenge, None);
+        register_bob(&controller, &mut MemoryClient::new()).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn invite_codes() -> Result<()> {
+        let controller =
+            BrongnalController::new(Box::new(MemoryStorage::default())).with_invites_required(true);
+        for (code, revoked) in [("welcome", false), ("withdrawn", true)] {
+            controller
+                .storage
+                .add_invite(Invite {
+                    code_hash: hash_invite_code(code),
+                    creator: String::from("oncall"),
+                    max_uses: 1,
+                    uses: 0,
+                    created_at: SystemTime::now(),
+                    expires_at: None,
+                    revoked,
+                })
+                .await?;
+        }
+        let register = |identity: &str, code: Option<&str>| {
+            let mut request = register_request(&mut MemoryClient::new(), 1)?;
+            request.identity = Some(identity.to_owned());
+            request.invite_code = code.map(str::to_owned);
+            anyhow::Ok(controller.register_pre_key_bundle(Request::new(request)))
+        };
+        let rejection = |status: Status| {
+            assert_eq!(status.code(), Code::PermissionDenied);
+            status
+                .metadata()
+                .get("invite-rejected")
+                .map(|reason| reason.to_str().unwrap().to_owned())
+        };
+
+        assert_eq!(
+            rejection(register("bob", None)?.await.unwrap_err()).as_deref(),
+            Some("missing")
+        );
+        register("bob", Some("welcome"))?.await?;
+        // Bob exists now, so he can register again without a code.
+        register("bob", None)?.await?;
+        assert_eq!(
+            rejection(register("carol", Some("welcome"))?.await.unwrap_err()).as_deref(),
+            Some("used-up")
+        );
+        assert_eq!(
+            rejection(register("carol", Some("withdrawn"))?.await.unwrap_err()).as_deref(),
+            Some("revoked")
+        );
+        assert_eq!(
+            rejection(register("carol", Some("guess"))?.await.unwrap_err()).as_deref(),
+            Some("unknown")
+        );
+        assert!(!controller.storage.user_exists("carol").await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn invite_codes_not_required() -> Result<()> {
+        let controller = BrongnalController::new(Box::new(MemoryStorage::default()));
+        let mut request = register_request(&mut MemoryClient::new(), 1)?;
+        request.invite_code = Some(String::from("guess"));
+        controller
+            .register_pre_key_bundle(Request::new(request))
+            .await?;
+        assert!(controller.storage.user_exists("bob").await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn register_oversized_opk_bundle() -> Result<()> {
+        let controller = BrongnalController::new(Box::new(MemoryStorage::default()));
+        let mut bob = MemoryClient::new();
+        let request = register_request(&mut bob, MAX_OPKS_PER_REQUEST as u32 + 1)?;
+
+        assert_eq!(
+            controller
+                .register_pre_key_bundle(Request::new(request))
+                .await
+                .err()
+                .map(|e| e.code()),
+            Some(Code::InvalidArgument)
+        );
+        assert_eq!(
+            controller
+                .storage
+                .get_current_keys("bob", DEFAULT_DEVICE_ID)
+                .await
+                .err()
+                .map(|e| e.code()),
+            Some(Code::NotFound)
+        );
+
+        let request = register_request(&mut bob, MAX_OPKS_PER_REQUEST as u32)?;
+        controller
+            .register_pre_key_bundle(Request::new(request))
+            .await?;
+        assert_eq!(
+            controller
+                .storage
+                .count_opks("bob", DEFAULT_DEVICE_ID)
+                .await?,
+            MAX_OPKS_PER_REQUEST
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn register_invalid_opk_signature() -> Result<()> {
+        let controller = BrongnalController::new(Box::new(MemoryStorage::default()));
+        let mut bob = MemoryClient::new();
+        let mut request = register_request(&mut bob, 10)?;
+        request.one_time_key_bundle = Some(MemoryClient::new().create_opks(10)?.into());
+
+        assert_eq!(
+            controller
+                .register_pre_key_bundle(Request::new(request))
+                .await
+                .err()
+                .map(|e| e.code()),
+            Some(Code::Unauthenticated)
+        );
+        assert_eq!(
+            controller
+                .storage
+                .get_current_keys("bob", DEFAULT_DEVICE_ID)
+                .await
+                .err()
+                .map(|e| e.code()),
+            Some(Code::NotFound)
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn send_message_rate_limited() -> Result<()> {
+        let controller = BrongnalController::new(Box::new(MemoryStorage::default()))
+            .with_send_limit(RateLimit {
+                rate: 0.1,
+                burst: 2,
+            });
+        let mut bob = MemoryClient::new();
+        register_bob(&controller, &mut bob).await?;
+        let from = |sender: &str, remote: &str| -> Result<Request<SendMessageRequest>> {
+            let mut request = Request::new(send_message_request(sender, &bob)?);
+            request.extensions_mut().insert(TcpConnectInfo {
+                local_addr: None,
+                remote_addr: Some(remote.parse()?),
+            });
+            Ok(request)
+        };
+
+        for _ in 0..2 {
+            controller
+                .send_message(from("alice", "192.0.2.1:1234")?)
+                .await?;
+        }
+        // Claiming to be someone else doesn't get around the limit.
+        let status = controller
+            .send_message(from("carol", "192.0.2.1:5678")?)
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::ResourceExhausted);
+        assert_eq!(status.metadata().get("retry-after").unwrap(), "10");
+
+        // Nor does it get anyone else throttled.
+        controller
+            .send_message(from("alice", "192.0.2.2:1234")?)
+            .await?;
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retention_task_purges_expired_messages() -> Result<()> {
+        let controller = Arc::new(BrongnalController::new(Box::new(MemoryStorage::default())));
+        let mut bob = MemoryClient::new();
+        register_bob(&controller, &mut bob).await?;
+        controller
+            .send_message(Request::new(send_message_request("alice", &bob)?))
+            .await?;
+
+        let task = controller.clone().spawn_retention_task(
+            RetentionPolicy {
+                message_ttl: Duration::ZERO,
+                opk_ttl: Duration::ZERO,
+                max_spk_age: Duration::ZERO,
+            },
+            Duration::from_millis(10),
+        );
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        task.abort();
+
+        assert_eq!(
+            controller
+                .storage
+                .get_messages("bob", DEFAULT_DEVICE_ID)
+                .await?,
+            vec![]
+        );
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_send_message_sqlite() -> Result<()> {
+        let controller = Arc::new(BrongnalController::new(Box::new(
+            SqliteStorage::new(tokio_rusqlite::Connection::open_in_memory().await?).await?,
+        )));
+        let mut bob = MemoryClient::new();
+        register_bob(&controller, &mut bob).await?;
+
+        let mut sends = Vec::new();
+        for i in 0..50 {
+            let controller = controller.clone();
+            let request = send_message_request(&format!("sender-{i}"), &bob)?;
+            sends.push(tokio::spawn(async move {
+                controller.send_message(Request::new(request)).await
+            }));
+        }
+        for send in sends {
+            send.await??;
+        }
+        assert_eq!(
+            controller
+                .storage
+                .get_messages("bob", DEFAULT_DEVICE_ID)
+                .await?
+                .len(),
+            50
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn send_message_too_large() -> Result<()> {
+        let controller = BrongnalController::new(Box::new(MemoryStorage::default()))
+            .with_max_ciphertext_len(100);
+        let mut bob = MemoryClient::new();
+        register_bob(&controller, &mut bob).await?;
+        let with_ciphertext_len = |len| -> Result<_> {
+            let mut request = send_message_request("alice", &bob)?;
+            let Some(Payload::X3dhInitial(initial)) =
+                &mut request.message.as_mut().unwrap().payload
+            else {
+                unreachable!();
+            };
+            initial.ciphertext = Some(vec![0; len]);
+            Ok(Request::new(request))
+        };
+
+        controller.send_message(with_ciphertext_len(100)?).await?;
+        let status = controller
+            .send_message(with_ciphertext_len(101)?)
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+        assert_eq!(status.metadata().get("max-ciphertext-len").unwrap(), "100");
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[allow(deprecated)]
+    async fn send_message_payloads() -> Result<()> {
+        let controller = BrongnalController::new(Box::new(MemoryStorage::default()));
+        let mut bob = MemoryClient::new();
+        register_bob(&controller, &mut bob).await?;
+        let send = |message: MessageProto| {
+            controller.send_message(Request::new(SendMessageRequest {
+                recipient_identity: Some(String::from("bob")),
+                message: Some(message),
+                device_messages: Vec::new(),
+                message_uuid: None,
+                partial: None,
+            }))
+        };
+
+        // The flat form is stored as the payload it describes.
+        let Some(Payload::X3dhInitial(initial)) = send_message_request("alice", &bob)?
+            .message
+            .unwrap()
+            .payload
+        else {
+            unreachable!();
+        };
+        send(MessageProto {
+            sender_identity: initial.sender_identity.clone(),
+            sender_identity_key: initial.sender_identity_key.clone(),
+            ephemeral_key: initial.ephemeral_key.clone(),
+            ciphertext: initial.ciphertext.clone(),
+            protocol_version: initial.protocol_version,
+            ..Default::default()
+        })
+        .await?;
+        let session: MessageProto = proto::SessionMessage {
+            sender_identity: String::from("alice"),
+            ciphertext: vec![1; 48],
+        }
+        .into();
+        send(session.clone()).await?;
+        let messages = controller
+            .storage
+            .get_messages("bob", DEFAULT_DEVICE_ID)
+            .await?;
+        assert_eq!(
+            messages.iter().map(|m| &m.payload).collect::<Vec<_>>(),
+            [&Some(Payload::X3dhInitial(initial)), &session.payload]
+        );
+
+        // Only the server sends server control, and payloads it doesn't know aren't relayed.
+        let control = ServerControl {
+            heartbeat: Some(true),
+            pre_key_status: None,
+        };
+        assert_eq!(
+            send(control.into()).await.unwrap_err().code(),
+            Code::PermissionDenied
+        );
+        let status = send(MessageProto::default()).await.unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+        assert_eq!(
+            status.message(),
+            "Invalid message.payload: none this release recognizes"
+        );
+        assert!(controller
+            .storage
+            .get_messages("bob", DEFAULT_DEVICE_ID)
+            .await?
+            .is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn relayed_send_message() -> Result<()> {
+        use crate::federation::{FederationPeer, DOMAIN_HEADER, SECRET_HEADER};
+        let channel = tonic::transport::Endpoint::from_static("http://[::1]:1").connect_lazy();
+        let controller = BrongnalController::new(Box::new(MemoryStorage::default()))
+            .with_federation(
+                Federation::new("b.example")
+                    .with_peer("a.example", FederationPeer::new(channel, "s3cret")),
+            );
+        let mut bob = MemoryClient::new();
+        register_bob(&controller, &mut bob).await?;
+        let relayed = |sender: &str, recipient: &str, secret: &'static str| -> Result<_> {
+            let mut request = Request::new(SendMessageRequest {
+                recipient_identity: Some(String::from(recipient)),
+                ..send_message_request(sender, &bob)?
+            });
+            let metadata = request.metadata_mut();
+            metadata.insert(DOMAIN_HEADER, "a.example".parse().unwrap());
+            metadata.insert(SECRET_HEADER, secret.parse().unwrap());
+            Ok(request)
+        };
+
+        controller
+            .send_message(relayed("alice@a.example", "bob@b.example", "s3cret")?)
+            .await?;
+        let messages = controller
+            .storage
+            .get_messages("bob", DEFAULT_DEVICE_ID)
+            .await?;
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].sender(), Some("alice@a.example"));
+
+        for (sender, recipient, secret, code) in [
+            ("alice@a.example", "bob", "guess", Code::Unauthenticated),
+            ("mallory@c.example", "bob", "s3cret", Code::PermissionDenied),
+            ("alice", "bob", "s3cret", Code::PermissionDenied),
+            (
+                "alice@a.example",
+                "carol@c.example",
+                "s3cret",
+                Code::FailedPrecondition,
+            ),
+        ] {
+            let status = controller
+                .send_message(relayed(sender, recipient, secret)?)
+                .await
+                .unwrap_err();
+            assert_eq!(status.code(), code, "{sender} to {recipient}");
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn send_identical_message_twice() -> Result<()> {
+        let controller = BrongnalController::new(Box::new(
+            SqliteStorage::new(tokio_rusqlite::Connection::open_in_memory().await?).await?,
+        ));
+        let mut bob = MemoryClient::new();
+        register_bob(&controller, &mut bob).await?;
+
+        let request = send_message_request("alice", &bob)?;
+        controller
+            .send_message(Request::new(request.clone()))
+            .await?;
+        controller
+            .send_message(Request::new(request.clone()))
+            .await?;
+
+        let message = request.message.unwrap();
+        assert_eq!(
+            controller
+                .storage
+                .get_messages("bob", DEFAULT_DEVICE_ID)
+                .await?,
+            vec![message.clone(), message]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn retried_send_is_delivered_once() -> Result<()> {
+        let controller = BrongnalController::new(Box::new(
+            SqliteStorage::new(tokio_rusqlite::Connection::open_in_memory().await?).await?,
+        ));
+        let mut bob = MemoryClient::new();
+        register_bob(&controller, &mut bob).await?;
+
+        // The sender timed out waiting for the response to the first send, so it retries.
+        let request = SendMessageRequest {
+            message_uuid: Some(vec![1; MESSAGE_UUID_LEN]),
+            ..send_message_request("alice", &bob)?
+        };
+        let first = controller
+            .send_message(Request::new(request.clone()))
+            .await?
+            .into_inner();
+        let retry = controller
+            .send_message(Request::new(request.clone()))
+            .await?
+            .into_inner();
+        assert!(first.message_id.is_some());
+        assert_eq!(retry.message_id, first.message_id);
+        assert_eq!(
+            controller
+                .storage
+                .get_messages("bob", DEFAULT_DEVICE_ID)
+                .await?,
+            vec![MessageProto {
+                message_uuid: request.message_uuid.clone(),
+                ..request.message.clone().unwrap()
+            }]
+        );
+
+        // Even once the recipient has fetched the message.
+        controller
+            .send_message(Request::new(request.clone()))
+            .await?;
+        assert_eq!(
+            controller
+                .storage
+                .get_messages("bob", DEFAULT_DEVICE_ID)
+                .await?,
+            Vec::new()
+        );
+
+        let other = controller
+            .send_message(Request::new(SendMessageRequest {
+                message_uuid: Some(vec![2; MESSAGE_UUID_LEN]),
+                ..request.clone()
+            }))
+            .await?
+            .into_inner();
+        assert_ne!(other.message_id, first.message_id);
+
+        let status = controller
+            .send_message(Request::new(SendMessageRequest {
+                message_uuid: Some(vec![3; MESSAGE_UUID_LEN - 1]),
+                ..request
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+        Ok(())
+    }
+
+    fn message_status_request(
+        signer: &MemoryClient,
+        identity: &str,
+    ) -> Result<Request<MessageStatusStreamRequest>> {
+        let timestamp = unix_seconds(SystemTime::now());
+        let payload = message_status_stream_payload(identity, DEFAULT_DEVICE_ID, timestamp);
+        Ok(Request::new(MessageStatusStreamRequest {
+            identity: Some(identity.to_owned()),
+            device_id: None,
+            timestamp: Some(timestamp),
+            signature: Some(signer.get_ik()?.sign(&payload).to_vec()),
+        }))
+    }
+
+    fn message_status(uuid: u8, state: DeliveryState) -> MessageStatus {
+        let mut status = MessageStatus {
+            message_uuid: Some(vec![uuid; MESSAGE_UUID_LEN]),
+            recipient_identity: Some(String::from("bob")),
+            device_id: Some(DEFAULT_DEVICE_ID),
+            state: None,
+        };
+        status.set_state(state);
+        status
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn message_status_stream() -> Result<()> {
+        let controller = Arc::new(BrongnalController::new(Box::new(MemoryStorage::default())));
+        let mut alice = MemoryClient::new();
+        controller
+            .register_pre_key_bundle(Request::new(RegisterPreKeyBundleRequest {
+                identity: Some(String::from("alice")),
+                ..register_request(&mut alice, 0)?
+            }))
+            .await?;
+        let mut bob = MemoryClient::new();
+        register_bob(&controller, &mut bob).await?;
+
+        assert_eq!(
+            controller
+                .message_status_stream(message_status_request(&bob, "alice")?)
+                .await
+                .unwrap_err()
+                .code(),
+            Code::Unauthenticated
+        );
+        let statuses = controller
+            .message_status_stream(message_status_request(&alice, "alice")?)
+            .await?
+            .into_inner();
+
+        let send = |sender: &str, uuid: Option<u8>| {
+            let request = send_message_request(sender, &bob).map(|request| SendMessageRequest {
+                message_uuid: uuid.map(|uuid| vec![uuid; MESSAGE_UUID_LEN]),
+                ..request
+            });
+            let controller = controller.clone();
+            async move {
+                controller.send_message(Request::new(request?)).await?;
+                anyhow::Ok(())
+            }
+        };
+        // Neither someone else's messages nor messages without a uuid are reported.
+        send("carol", Some(1)).await?;
+        send("alice", None).await?;
+        send("alice", Some(2)).await?;
+        let messages = controller
+            .retrieve_messages(Request::new(RetrieveMessagesRequest {
+                identity: Some(String::from("bob")),
+                device_id: None,
+            }))
+            .await?
+            .into_inner();
+        send("alice", Some(3)).await?;
+        drop(messages);
+        send("alice", Some(4)).await?;
+
+        let task = controller.clone().spawn_retention_task(
+            RetentionPolicy {
+                message_ttl: Duration::ZERO,
+                opk_ttl: Duration::ZERO,
+                max_spk_age: Duration::ZERO,
+            },
+            Duration::from_millis(10),
+        );
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        task.abort();
+        controller.drain();
+
+        assert_eq!(
+            tokio_stream::StreamExt::collect::<Result<Vec<_>, Status>>(statuses).await?,
+            vec![
+                message_status(2, DeliveryState::Queued),
+                message_status(2, DeliveryState::Delivered),
+                message_status(3, DeliveryState::Delivered),
+                message_status(4, DeliveryState::Queued),
+                message_status(4, DeliveryState::Expired),
+            ]
+        );
+        Ok(())
+    }
+
+    fn delete_user_request(signer: &MemoryClient) -> Result<DeleteUserRequest> {
+        Ok(DeleteUserRequest {
+            identity: Some(String::from("bob")),
+            signature: Some(signer.get_ik()?.sign(&delete_user_payload("bob")).to_vec()),
+        })
+    }
+
+    #[tokio::test]
+    async fn delete_user() -> Result<()> {
+        let controller = BrongnalController::new(Box::new(MemoryStorage::default()));
+        let mut bob = MemoryClient::new();
+        register_bob(&controller, &mut bob).await?;
+        controller
+            .send_message(Request::new(send_message_request("alice", &bob)?))
+            .await?;
+
+        controller
+            .delete_user(Request::new(delete_user_request(&bob)?))
+            .await?;
+        assert_eq!(
+            controller
+                .request_pre_keys(Request::new(RequestPreKeysRequest {
+                    identity: Some(String::from("bob")),
+                }))
+                .await
+                .err()
+                .map(|e| e.code()),
+            Some(Code::NotFound)
+        );
+        assert_eq!(
+            controller
+                .send_message(Request::new(send_message_request("alice", &bob)?))
+                .await
+                .err()
+                .map(|e| e.code()),
+            Some(Code::NotFound)
+        );
+        assert_eq!(
+            controller
+                .storage
+                .get_messages("bob", DEFAULT_DEVICE_ID)
+                .await?,
+            vec![]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn delete_user_wrong_signer() -> Result<()> {
+        let controller = BrongnalController::new(Box::new(MemoryStorage::default()));
+        let mut bob = MemoryClient::new();
+        register_bob(&controller, &mut bob).await?;
+
+        assert_eq!(
+            controller
+                .delete_user(Request::new(delete_user_request(&MemoryClient::new())?))
+                .await
+                .err()
+                .map(|e| e.code()),
+            Some(Code::Unauthenticated)
+        );
+        assert!(controller.storage.user_exists("bob").await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn delete_account() -> Result<()> {
+        let gossamer = InMemoryGossamer::default();
+        let controller = BrongnalController::new(Box::new(MemoryStorage::default()))
+            .with_gossamer(gossamer.clone());
+        let mut bob = MemoryClient::new();
+        register_bob(&controller, &mut bob).await?;
+        let ik = bob.get_ik()?;
+        let mut append = AppendKey {
+            provider: Some(String::from("bob")),
+            public_key: Some(ik.verifying_key().to_bytes().to_vec()),
+            ..Default::default()
+        };
+        append.set_key_purpose(KeyPurpose::IdentityKey);
+        gossamer
+            .perform(Request::new(ActionRequest {
+                message: Some(sign_action(
+                    &ik,
+                    String::from("bob"),
+                    Action::AppendKey(append),
+                )),
+            }))
+            .await?;
+        let nonce = || async {
+            let nonce = controller
+                .get_account_deletion_nonce(Request::new(GetAccountDeletionNonceRequest {
+                    identity: Some(String::from("bob")),
+                }))
+                .await?
+                .into_inner()
+                .nonce
+                .unwrap();
+            anyhow::Ok(nonce)
+        };
+        let request = |signer: &SigningKey, nonce: &[u8]| DeleteAccountRequest {
+            identity: Some(String::from("bob")),
+            nonce: Some(nonce.to_vec()),
+            signature: Some(signer.sign(&delete_account_payload("bob", nonce)).to_vec()),
+            tombstone: Some(sign_action(
+                &ik,
+                String::from("bob"),
+                Action::Tombstone(Tombstone {
+                    provider: Some(String::from("bob")),
+                }),
+            )),
+        };
+
+        let wrong_key = SigningKey::generate(&mut OsRng);
+        let used = nonce().await?;
+        assert_eq!(
+            controller
+                .delete_account(Request::new(request(&wrong_key, &used)))
+                .await
+                .unwrap_err()
+                .code(),
+            Code::Unauthenticated
+        );
+        assert!(controller.storage.user_exists("bob").await?);
+        // Nonces are single use, even when the signature was wrong.
+        assert_eq!(
+            controller
+                .delete_account(Request::new(request(&ik, &used)))
+                .await
+                .unwrap_err()
+                .code(),
+            Code::FailedPrecondition
+        );
+
+        controller
+            .delete_account(Request::new(request(&ik, &nonce().await?)))
+            .await?;
+        assert_eq!(
+            controller
+                .request_pre_keys(Request::new(RequestPreKeysRequest {
+                    identity: Some(String::from("bob")),
+                }))
+                .await
+                .unwrap_err()
+                .code(),
+            Code::NotFound
+        );
+        assert_eq!(
+            nonce().await.unwrap_err().downcast::<Status>()?.code(),
+            Code::NotFound
+        );
+        let ledger = gossamer
+            .get_ledger(Request::new(GetLedgerRequest {
+                provider: Some(String::from("bob")),
+            }))
+            .await?
+            .into_inner()
+            .messages;
+        let last: proto::SignedMessage = ledger.last().unwrap().clone().try_into()?;
+        assert!(matches!(last.message.action, Some(Action::Tombstone(_))));
+        Ok(())
+    }
+
+    fn export_request(
+        signer: &MemoryClient,
+        timestamp: u64,
+        include_ciphertexts: bool,
+    ) -> Result<Request<ExportAccountDataRequest>> {
+        let payload = export_account_data_payload("bob", timestamp, include_ciphertexts);
+        Ok(Request::new(ExportAccountDataRequest {
+            identity: Some(String::from("bob")),
+            timestamp: Some(timestamp),
+            include_ciphertexts: Some(include_ciphertexts),
+            signature: Some(signer.get_ik()?.sign(&payload).to_vec()),
+        }))
+    }
+
+    #[tokio::test]
+    async fn export_account_data() -> Result<()> {
+        let controller = BrongnalController::new(Box::new(MemoryStorage::default()));
+        let mut bob = MemoryClient::new();
+        let mut request = register_request(&mut bob, 2)?;
+        request.last_resort_key = Some(bob.get_last_resort_key()?.into());
+        let spk = request.signed_pre_key.clone().unwrap();
+        let opks = request.one_time_key_bundle.clone().unwrap().pre_keys;
+        let last_resort_key = request.last_resort_key.clone().unwrap().pre_key;
+        let ids = controller
+            .register_pre_key_bundle(Request::new(request))
+            .await?
+            .into_inner();
+        controller
+            .register_push_token(Request::new(push_token_request(
+                &bob,
+                PushPlatformProto::Fcm,
+                "token",
+            )?))
+            .await?;
+        controller
+            .send_message(Request::new(send_message_request("alice", &bob)?))
+            .await?;
+        let queued = controller
+            .storage
+            .peek_messages("bob", DEFAULT_DEVICE_ID)
+            .await?
+            .remove(0);
+        let device = controller
+            .storage
+            .device_stats(Some("bob"))
+            .await?
+            .remove(0);
+        let export = |request| async {
+            let stream = controller.export_account_data(request).await?.into_inner();
+            tokio_stream::StreamExt::collect::<Result<Vec<_>, Status>>(stream).await
+        };
+
+        let record = |record| AccountDataRecord {
+            device_id: Some(DEFAULT_DEVICE_ID),
+            record: Some(record),
+        };
+        let pre_key = |kind, id, pre_key: &[u8], signature: Option<Vec<u8>>| {
+            record(Record::PreKey(exported_pre_key(
+                kind,
+                id,
+                pre_key.to_vec(),
+                signature,
+            )))
+        };
+        let mut exported_spk = exported_pre_key(
+            PreKeyKind::Signed,
+            ids.signed_pre_key_id(),
+            spk.pre_key().to_vec(),
+            spk.signature.clone(),
+        );
+        exported_spk.uploaded_at = Some(unix_seconds(device.spk_uploaded_at));
+        let mut push_token = ExportedPushToken {
+            platform: None,
+            device_token: Some(String::from("token")),
+            app_version: Some(String::from("1.0")),
+        };
+        push_token.set_platform(PushPlatformProto::Fcm);
+        let now = unix_seconds(SystemTime::now());
+        let mut expected = vec![
+            record(Record::Registration(DeviceRegistration {
+                identity_key: Some(bob.get_ik()?.verifying_key().to_bytes().to_vec()),
+                registered_at: Some(unix_seconds(device.registered_at)),
+                cipher_suite: None,
+            })),
+            record(Record::PreKey(exported_spk)),
+            pre_key(PreKeyKind::OneTime, ids.one_time_key_ids[0], &opks[0], None),
+            pre_key(PreKeyKind::OneTime, ids.one_time_key_ids[1], &opks[1], None),
+            pre_key(
+                PreKeyKind::LastResort,
+                ids.last_resort_key_id(),
+                last_resort_key.as_deref().unwrap(),
+                None,
+            ),
+            record(Record::QueuedMessage(QueuedMessageMetadata {
+                queued_at: Some(unix_seconds(queued.queued_at)),
+                size: Some(prost::Message::encoded_len(&queued.message) as u64),
+                message: None,
+            })),
+            record(Record::PushToken(push_token)),
+        ];
+        assert_eq!(export(export_request(&bob, now, false)?).await?, expected);
+        if let Some(Record::QueuedMessage(metadata)) = &mut expected[5].record {
+            metadata.message = Some(queued.message);
+        }
+        assert_eq!(export(export_request(&bob, now, true)?).await?, expected);
+        // Exporting leaves everything in place.
+        assert_eq!(
+            controller
+                .storage
+                .get_messages("bob", DEFAULT_DEVICE_ID)
+                .await?
+                .len(),
+            1
+        );
+
+        let alice = MemoryClient::new();
+        assert_eq!(
+            export(export_request(&alice, now, false)?)
+                .await
+                .unwrap_err()
+                .code(),
+            Code::Unauthenticated
+        );
+        let stale = now - SIGNED_REQUEST_MAX_SKEW.as_secs() - 1;
+        assert_eq!(
+            export(export_request(&bob, stale, false)?)
+                .await
+                .unwrap_err()
+                .code(),
+            Code::FailedPrecondition
+        );
+        Ok(())
+    }
+
+    fn delete_device_request(signer: &MemoryClient, device_id: u32) -> Result<DeleteDeviceRequest> {
+        Ok(DeleteDeviceRequest {
+            identity: Some(String::from("bob")),
+            device_id: Some(device_id),
+            signature: Some(
+                signer
+                    .get_ik()?
+                    .sign(&delete_device_payload("bob", device_id))
+                    .to_vec(),
+            ),
+        })
+    }
+
+    #[tokio::test]
+    async fn send_message_to_every_device() -> Result<()> {
+        let controller = BrongnalController::new(Box::new(MemoryStorage::default()));
+        let mut phone = MemoryClient::new();
+        let mut laptop = MemoryClient::new();
+        register_bob(&controller, &mut phone).await?;
+        controller
+            .register_pre_key_bundle(Request::new(RegisterPreKeyBundleRequest {
+                device_id: Some(2),
+                ..register_request(&mut laptop, 0)?
+            }))
+            .await?;
+
+        // A message for only the first device would leave the laptop without a copy.
+        assert_eq!(
+            controller
+                .send_message(Request::new(send_message_request("alice", &phone)?))
+                .await
+                .err()
+                .map(|e| e.code()),
+            Some(Code::FailedPrecondition)
+        );
+
+        let phone_message = send_message_request("alice", &phone)?.message;
+        let laptop_message = send_message_request("alice", &laptop)?.message;
+        controller
+            .send_message(Request::new(SendMessageRequest {
+                recipient_identity: Some(String::from("bob")),
+                message: None,
+                device_messages: vec![
+                    DeviceMessage {
+                        device_id: Some(2),
+                        message: laptop_message.clone(),
+                    },
+                    DeviceMessage {
+                        device_id: Some(DEFAULT_DEVICE_ID),
+                        message: phone_message.clone(),
+                    },
+                ],
+                message_uuid: None,
+                partial: None,
+            }))
+            .await?;
+        assert_eq!(
+            controller
+                .storage
+                .get_messages("bob", DEFAULT_DEVICE_ID)
+                .await?,
+            vec![phone_message.unwrap()]
+        );
+        assert_eq!(
+            controller.storage.get_messages("bob", 2).await?,
+            vec![laptop_message.clone().unwrap()]
+        );
+
+        // A partial message may leave devices out, but not address ones bob doesn't have or the
+        // same one twice.
+        let partial = |device_ids: &[u32]| SendMessageRequest {
+            recipient_identity: Some(String::from("bob")),
+            message: None,
+            device_messages: device_ids
+                .iter()
+                .map(|&device_id| DeviceMessage {
+                    device_id: Some(device_id),
+                    message: laptop_message.clone(),
+                })
+                .collect(),
+            message_uuid: None,
+            partial: Some(true),
+        };
+        for device_ids in [&[2, 3][..], &[2, 2]] {
+            assert_eq!(
+                controller
+                    .send_message(Request::new(partial(device_ids)))
+                    .await
+                    .err()
+                    .map(|e| e.code()),
+                Some(Code::FailedPrecondition),
+                "{device_ids:?}"
+            );
+        }
+        controller.send_message(Request::new(partial(&[2]))).await?;
+        assert_eq!(
+            controller.storage.get_messages("bob", 2).await?,
+            vec![laptop_message.unwrap()]
+        );
+        assert!(controller
+            .storage
+            .get_messages("bob", DEFAULT_DEVICE_ID)
+            .await?
+            .is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn delete_device() -> Result<()> {
+        let controller = BrongnalController::new(Box::new(MemoryStorage::default()));
+        let mut phone = MemoryClient::new();
+        let mut laptop = MemoryClient::new();
+        register_bob(&controller, &mut phone).await?;
+        controller
+            .register_pre_key_bundle(Request::new(RegisterPreKeyBundleRequest {
+                device_id: Some(2),
+                ..register_request(&mut laptop, 0)?
+            }))
+            .await?;
+
+        assert_eq!(
+            controller
+                .delete_device(Request::new(delete_device_request(
+                    &MemoryClient::new(),
+                    DEFAULT_DEVICE_ID
+                )?))
+                .await
+                .err()
+                .map(|e| e.code()),
+            Some(Code::Unauthenticated)
+        );
+        // The laptop removes the lost phone.
+        controller
+            .delete_device(Request::new(delete_device_request(
+                &laptop,
+                DEFAULT_DEVICE_ID,
+            )?))
+            .await?;
+        let bundles = controller
+            .request_pre_keys(Request::new(RequestPreKeysRequest {
+                identity: Some(String::from("bob")),
+            }))
+            .await?
+            .into_inner()
+            .bundles;
+        assert_eq!(
+            bundles
+                .iter()
+                .map(|bundle| bundle.device_id())
+                .collect::<Vec<_>>(),
+            vec![2]
+        );
+        assert_eq!(
+            controller
+                .delete_device(Request::new(delete_device_request(
+                    &laptop,
+                    DEFAULT_DEVICE_ID
+                )?))
+                .await
+                .err()
+                .map(|e| e.code()),
+            Some(Code::NotFound)
+        );
+        Ok(())
+    }
+
+    fn publish_provisioning_request(envelope: &[u8]) -> Request<PublishProvisioningRequest> {
+        Request::new(PublishProvisioningRequest {
+            provisioning_id: Some(vec![0; PROVISIONING_ID_LEN]),
+            envelope: Some(envelope.to_vec()),
+        })
+    }
+
+    async fn fetch_provisioning(controller: &BrongnalController) -> Result<Vec<u8>, Status> {
+        Ok(controller
+            .fetch_provisioning(Request::new(FetchProvisioningRequest {
+                provisioning_id: Some(vec![0; PROVISIONING_ID_LEN]),
+            }))
+            .await?
+            .into_inner()
+            .envelope()
+            .to_vec())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn provisioning_envelope_expires() -> Result<()> {
+        let controller = BrongnalController::new(Box::new(MemoryStorage::default()))
+            .with_provisioning_ttl(Duration::from_secs(60));
+        controller
+            .publish_provisioning(publish_provisioning_request(b"first"))
+            .await?;
+        assert_eq!(
+            controller
+                .publish_provisioning(publish_provisioning_request(b"second"))
+                .await
+                .err()
+                .map(|e| e.code()),
+            Some(Code::AlreadyExists)
+        );
+
+        tokio::time::advance(Duration::from_secs(61)).await;
+        assert_eq!(
+            fetch_provisioning(&controller)
+                .await
+                .err()
+                .map(|e| e.code()),
+            Some(Code::NotFound)
+        );
+
+        // Once expired the id may be reused, and each envelope is handed out once.
+        controller
+            .publish_provisioning(publish_provisioning_request(b"second"))
+            .await?;
+        assert_eq!(fetch_provisioning(&controller).await?, b"second");
+        assert_eq!(
+            fetch_provisioning(&controller)
+                .await
+                .err()
+                .map(|e| e.code()),
+            Some(Code::NotFound)
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reregistration_replaces_opks() -> Result<()> {
+        let controller = BrongnalController::new(Box::new(MemoryStorage::default()));
+        let request = register_request(&mut MemoryClient::new(), 5)?;
+        controller
+            .register_pre_key_bundle(Request::new(request))
+            .await?;
+
+        let request = register_request(&mut MemoryClient::new(), 2)?;
+        let new_keys = request.one_time_key_bundle.clone().unwrap().pre_keys;
+        controller
+            .register_pre_key_bundle(Request::new(request))
+            .await?;
+
+        assert_eq!(
+            controller
+                .storage
+                .count_opks("bob", DEFAULT_DEVICE_ID)
+                .await?,
+            2
+        );
+        let bundle = controller
+            .request_pre_keys(Request::new(RequestPreKeysRequest {
+                identity: Some(String::from("bob")),
+            }))
+            .await?
+            .into_inner()
+            .bundles
+            .remove(0);
+        assert!(new_keys.contains(&bundle.one_time_key.unwrap()));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn register_assigns_pre_key_ids() -> Result<()> {
+        let controller = BrongnalController::new(Box::new(MemoryStorage::default()));
+        let request = register_request(&mut MemoryClient::new(), 2)?;
+        let keys = request.one_time_key_bundle.clone().unwrap().pre_keys;
+        let response = controller
+            .register_pre_key_bundle(Request::new(request))
+            .await?
+            .into_inner();
+        assert!(response.signed_pre_key_id.is_some());
+        assert_eq!(response.one_time_key_ids.len(), 2);
+
+        let bundle = controller
+            .request_pre_keys(Request::new(RequestPreKeysRequest {
+                identity: Some(String::from("bob")),
+            }))
+            .await?
+            .into_inner()
+            .bundles
+            .remove(0);
+        assert_eq!(bundle.signed_pre_key_id, response.signed_pre_key_id);
+        assert_eq!(bundle.one_time_key_id, Some(response.one_time_key_ids[0]));
+        assert_eq!(bundle.one_time_key, Some(keys[0].clone()));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn update_signed_pre_key() -> Result<()> {
+        let controller = BrongnalController::new(Box::new(MemoryStorage::default()));
+        let mut bob = MemoryClient::new();
+        register_bob(&controller, &mut bob).await?;
+        let spk = bob.rotate_spk()?;
+
+        let response = controller
+            .update_signed_pre_key(Request::new(UpdateSignedPreKeyRequest {
+                identity: Some(String::from("bob")),
+                signed_pre_key: Some(spk.clone().into()),
+                device_id: None,
+                last_resort_key: None,
+                last_resort_kem_key: None,
+            }))
+            .await?
+            .into_inner();
+        assert_eq!(response.signed_pre_key_id, Some(2));
+        let bundle = controller
+            .request_pre_keys(Request::new(RequestPreKeysRequest {
+                identity: Some(String::from("bob")),
+            }))
+            .await?
+            .into_inner()
+            .bundles
+            .remove(0);
+        assert_eq!(bundle.signed_pre_key, Some(spk.into()));
+        assert_eq!(bundle.signed_pre_key_id, Some(2));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn upload_one_time_keys() -> Result<()> {
+        let controller = BrongnalController::new(Box::new(MemoryStorage::default()));
+        let mut bob = MemoryClient::new();
+        let upload_request = |bob: &mut MemoryClient, num_opks| -> Result<_> {
+            Ok(Request::new(UploadOneTimeKeysRequest {
+                identity: Some(String::from("bob")),
+                device_id: None,
+                one_time_key_bundle: Some(bob.create_opks(num_opks)?.into()),
+            }))
+        };
+
+        let status = controller
+            .upload_one_time_keys(upload_request(&mut bob, 1)?)
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::NotFound);
+
+        let registered = controller
+            .register_pre_key_bundle(Request::new(register_request(&mut bob, 2)?))
+            .await?
+            .into_inner();
+        let response = controller
+            .upload_one_time_keys(upload_request(&mut bob, 3)?)
+            .await?
+            .into_inner();
+        assert_eq!(response.one_time_key_ids.len(), 3);
+        assert!(response
+            .one_time_key_ids
+            .iter()
+            .all(|id| !registered.one_time_key_ids.contains(id)));
+        assert_eq!(response.one_time_key_count, Some(5));
+        let count = |device_id| {
+            controller.count_one_time_keys(Request::new(CountOneTimeKeysRequest {
+                identity: Some(String::from("bob")),
+                device_id: Some(device_id),
+            }))
+        };
+        assert_eq!(
+            count(DEFAULT_DEVICE_ID)
+                .await?
+                .into_inner()
+                .one_time_key_count,
+            Some(5)
+        );
+        assert_eq!(count(2).await.unwrap_err().code(), Code::NotFound);
+        // Uploading keeps the signed pre key, unlike registering.
+        assert_eq!(
+            controller
+                .storage
+                .get_current_keys("bob", DEFAULT_DEVICE_ID)
+                .await?
+                .spk_id,
+            registered.signed_pre_key_id.unwrap()
+        );
+
+        // The keys must be signed by the registered identity key, not just any key.
+        let status = controller
+            .upload_one_time_keys(upload_request(&mut MemoryClient::new(), 1)?)
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::Unauthenticated);
+        assert_eq!(
+            controller
+                .storage
+                .count_opks("bob", DEFAULT_DEVICE_ID)
+                .await?,
+            5
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn opk_quota_keeps_keys_that_fit() -> Result<()> {
+        let controller = BrongnalController::new(Box::new(MemoryStorage::default()))
+            .with_opk_quota(OpkQuota {
+                max_keys: 3,
+                policy: QuotaPolicy::Reject,
+            });
+        let mut bob = MemoryClient::new();
+        let upload = |bob: &mut MemoryClient, num_opks| -> Result<_> {
+            Ok(Request::new(UploadOneTimeKeysRequest {
+                identity: Some(String::from("bob")),
+                device_id: None,
+                one_time_key_bundle: Some(bob.create_opks(num_opks)?.into()),
+            }))
+        };
+
+        let registered = controller
+            .register_pre_key_bundle(Request::new(register_request(&mut bob, 2)?))
+            .await?
+            .into_inner();
+        assert_eq!(registered.one_time_keys_accepted, Some(2));
+        assert_eq!(registered.one_time_key_count, Some(2));
+
+        // Only the first key fits.
+        let response = controller
+            .upload_one_time_keys(upload(&mut bob, 2)?)
+            .await?
+            .into_inner();
+        assert_eq!(response.one_time_keys_accepted, Some(1));
+        assert_eq!(response.one_time_key_ids.len(), 1);
+        assert_eq!(response.one_time_key_count, Some(3));
+
+        // None do once the quota is full, but the upload still succeeds.
+        let response = controller
+            .upload_one_time_keys(upload(&mut bob, 1)?)
+            .await?
+            .into_inner();
+        assert_eq!(response.one_time_keys_accepted, Some(0));
+        assert!(response.one_time_key_ids.is_empty());
+        assert_eq!(response.one_time_key_count, Some(3));
+
+        // A new installation's keys replace the old ones, so as many fit as the quota allows.
+        let response = controller
+            .register_pre_key_bundle(Request::new(register_request(&mut MemoryClient::new(), 5)?))
+            .await?
+            .into_inner();
+        assert_eq!(response.one_time_keys_accepted, Some(3));
+        assert_eq!(response.one_time_key_ids.len(), 3);
+        assert_eq!(response.one_time_key_count, Some(3));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn duplicate_opks_skipped() -> Result<()> {
+        let controller = BrongnalController::new(Box::new(MemoryStorage::default()));
+        let mut bob = MemoryClient::new();
+        register_bob(&controller, &mut bob).await?;
+        let ik = bob.get_ik()?;
+        let secrets: Vec<X25519StaticSecret> = (0..3)
+            .map(|_| X25519StaticSecret::random_from_rng(OsRng))
+            .collect();
+        let upload = |indices: &[usize]| {
+            let key_pairs: Vec<_> = indices
+                .iter()
+                .map(|&i| (secrets[i].clone(), X25519PublicKey::from(&secrets[i])))
+                .collect();
+            let bundle = SignedPreKeys {
+                pre_keys: key_pairs.iter().map(|(_, key)| *key).collect(),
+                signature: sign_bundle(&ik, &key_pairs),
+            };
+            Request::new(UploadOneTimeKeysRequest {
+                identity: Some(String::from("bob")),
+                device_id: None,
+                one_time_key_bundle: Some(bundle.into()),
+            })
+        };
+
+        // A key repeated within a batch is stored once, and reported under the same id.
+        let first = controller
+            .upload_one_time_keys(upload(&[0, 1, 0]))
+            .await?
+            .into_inner();
+        assert_eq!(first.one_time_keys_accepted, Some(3));
+        assert_eq!(first.one_time_key_ids[2], first.one_time_key_ids[0]);
+        assert_eq!(first.one_time_keys_skipped, Some(1));
+        assert_eq!(first.one_time_key_count, Some(2));
+
+        // As is one uploaded again in a later batch.
+        let second = controller
+            .upload_one_time_keys(upload(&[1, 2]))
+            .await?
+            .into_inner();
+        assert_eq!(second.one_time_keys_accepted, Some(2));
+        assert_eq!(second.one_time_key_ids[0], first.one_time_key_ids[1]);
+        assert!(!first.one_time_key_ids.contains(&second.one_time_key_ids[1]));
+        assert_eq!(second.one_time_keys_skipped, Some(1));
+        assert_eq!(second.one_time_key_count, Some(3));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn update_signed_pre_key_wrong_signer() -> Result<()> {
+        let controller = BrongnalController::new(Box::new(MemoryStorage::default()));
+        let mut bob = MemoryClient::new();
+        let bob_spk = register_bob(&controller, &mut bob).await?;
+
+        let request = UpdateSignedPreKeyRequest {
+            identity: Some(String::from("bob")),
+            signed_pre_key: Some(MemoryClient::new().rotate_spk()?.into()),
+            device_id: None,
+            last_resort_key: None,
+            last_resort_kem_key: None,
+        };
+        assert_eq!(
+            controller
+                .update_signed_pre_key(Request::new(request.clone()))
+                .await
+                .err()
+                .map(|e| e.code()),
+            Some(Code::Unauthenticated)
+        );
+        assert_eq!(
+            controller
+                .storage
+                .get_current_keys("bob", DEFAULT_DEVICE_ID)
+                .await?
+                .spk,
+            bob_spk
+        );
+
+        let request = UpdateSignedPreKeyRequest {
+            identity: Some(String::from("alice")),
+            ..request
+        };
+        assert_eq!(
+            controller
+                .update_signed_pre_key(Request::new(request))
+                .await
+                .err()
+                .map(|e| e.code()),
+            Some(Code::NotFound)
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn last_resort_key_when_opks_run_out() -> Result<()> {
+        let controller = BrongnalController::new(Box::new(MemoryStorage::default()));
+        let mut bob = MemoryClient::new();
+        let last_resort_key = bob.get_last_resort_key()?;
+        let response = controller
+            .register_pre_key_bundle(Request::new(RegisterPreKeyBundleRequest {
+                last_resort_key: Some(last_resort_key.clone().into()),
+                ..register_request(&mut bob, 1)?
+            }))
+            .await?
+            .into_inner();
+        assert_eq!(response.last_resort_key_id, Some(1));
+        let request_bundle = || async {
+            controller
+                .request_pre_keys(Request::new(RequestPreKeysRequest {
+                    identity: Some(String::from("bob")),
+                }))
+                .await
+                .map(|response| response.into_inner().bundles.remove(0))
+        };
+
+        let bundle = request_bundle().await?;
+        assert_eq!(
+            bundle.one_time_key_id,
+            response.one_time_key_ids.first().copied()
+        );
+        assert_eq!(bundle.last_resort, None);
+        // Once the one time keys are gone every sender gets the last-resort key.
+        for _ in 0..2 {
+            let bundle = request_bundle().await?;
+            assert_eq!(
+                bundle.one_time_key,
+                Some(last_resort_key.pre_key.as_bytes().to_vec())
+            );
+            assert_eq!(bundle.one_time_key_id, Some(1));
+            assert_eq!(bundle.last_resort, Some(true));
+        }
+
+        let rotated = bob.rotate_last_resort_key()?;
+        let response = controller
+            .update_signed_pre_key(Request::new(UpdateSignedPreKeyRequest {
+                identity: Some(String::from("bob")),
+                signed_pre_key: Some(bob.get_spk()?.into()),
+                device_id: None,
+                last_resort_key: Some(rotated.clone().into()),
+                last_resort_kem_key: None,
+            }))
+            .await?
+            .into_inner();
+        assert_eq!(response.last_resort_key_id, Some(2));
+        let bundle = request_bundle().await?;
+        assert_eq!(
+            bundle.one_time_key,
+            Some(rotated.pre_key.as_bytes().to_vec())
+        );
+        assert_eq!(bundle.one_time_key_id, Some(2));
+
+        let forged = controller
+            .register_pre_key_bundle(Request::new(RegisterPreKeyBundleRequest {
+                last_resort_key: Some(MemoryClient::new().get_last_resort_key()?.into()),
+                ..register_request(&mut bob, 0)?
+            }))
+            .await;
+        assert_eq!(forged.err().map(|e| e.code()), Some(Code::Unauthenticated));
+
+        // A new installation that doesn't upload one drops the old last-resort key.
+        controller
+            .register_pre_key_bundle(Request::new(register_request(&mut MemoryClient::new(), 0)?))
+            .await?;
+        let bundle = request_bundle().await?;
+        assert_eq!(bundle.one_time_key, None);
+        assert_eq!(bundle.last_resort, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn one_time_key_used_by_one_message() -> Result<()> {
+        let controller = BrongnalController::new(Box::new(MemoryStorage::default()));
+        let mut bob = MemoryClient::new();
+        controller
+            .register_pre_key_bundle(Request::new(register_request(&mut bob, 2)?))
+            .await?;
+        let bundle = controller
+            .request_pre_keys(Request::new(RequestPreKeysRequest {
+                identity: Some(String::from("bob")),
+            }))
+            .await?
+            .into_inner()
+            .bundles
+            .remove(0);
+        // The reserved key no longer counts towards the ones left to hand out.
+        assert_eq!(
+            controller
+                .storage
+                .count_opks("bob", DEFAULT_DEVICE_ID)
+                .await?,
+            1
+        );
+        let (_sk, message) = protocol::x3dh::initiate_send(
+            bundle.try_into()?,
+            String::from("alice"),
+            &MemoryClient::new().get_ik()?,
+            b"Hello Bob!",
+        )?;
+        let request = SendMessageRequest {
+            recipient_identity: Some(String::from("bob")),
+            message: Some(message.into()),
+            device_messages: Vec::new(),
+            message_uuid: Some(vec![1; MESSAGE_UUID_LEN]),
+            partial: None,
+        };
+        controller
+            .send_message(Request::new(request.clone()))
+            .await?;
+        // A retry of the same message is still delivered once.
+        controller
+            .send_message(Request::new(request.clone()))
+            .await?;
+
+        // But another message can't reuse the key.
+        let status = controller
+            .send_message(Request::new(SendMessageRequest {
+                message_uuid: Some(vec![2; MESSAGE_UUID_LEN]),
+                ..request.clone()
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::FailedPrecondition);
+        let status = controller
+            .send_message(Request::new(SendMessageRequest {
+                message_uuid: None,
+                ..request.clone()
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::FailedPrecondition);
+        assert_eq!(
+            controller
+                .storage
+                .get_messages("bob", DEFAULT_DEVICE_ID)
+                .await?,
+            vec![MessageProto {
+                message_uuid: request.message_uuid,
+                ..request.message.unwrap()
+            }]
+        );
+        // The refused sends don't hold on to their uuids.
+        assert!(matches!(
+            controller
+                .storage
+                .claim_message_uuid("bob", &[2; MESSAGE_UUID_LEN])
+                .await?,
+            MessageClaim::New(_)
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn kem_pre_keys_in_bundle() -> Result<()> {
+        let controller = BrongnalController::new(Box::new(MemoryStorage::default()));
+        let mut bob = MemoryClient::new();
+        let kem_key = |byte: u8, ik: &SigningKey| -> Result<SignedPreKeyProto> {
+            let key = KemPublicKey::from_bytes(&[byte; kem::PUBLIC_KEY_LEN])?;
+            Ok(sign_kem_pre_key(ik, &key).into())
+        };
+        let one_time_kem_key = kem_key(1, &bob.get_ik()?)?;
+        let last_resort_kem_key = kem_key(2, &bob.get_ik()?)?;
+        let response = controller
+            .register_pre_key_bundle(Request::new(RegisterPreKeyBundleRequest {
+                one_time_kem_keys: vec![one_time_kem_key.clone()],
+                last_resort_kem_key: Some(last_resort_kem_key.clone()),
+                ..register_request(&mut bob, 0)?
+            }))
+            .await?
+            .into_inner();
+        assert_eq!(response.one_time_kem_key_ids.len(), 1);
+        assert_eq!(response.last_resort_kem_key_id, Some(1));
+        let request_bundle = || async {
+            controller
+                .request_pre_keys(Request::new(RequestPreKeysRequest {
+                    identity: Some(String::from("bob")),
+                }))
+                .await
+                .map(|response| response.into_inner().bundles.remove(0))
+        };
+
+        let bundle = request_bundle().await?;
+        assert_eq!(bundle.kem_pre_key, Some(one_time_kem_key));
+        assert_eq!(
+            bundle.kem_pre_key_id,
+            response.one_time_kem_key_ids.first().copied()
+        );
+        assert_eq!(bundle.kem_last_resort, None);
+        let bundle = request_bundle().await?;
+        assert_eq!(bundle.kem_pre_key, Some(last_resort_kem_key));
+        assert_eq!(bundle.kem_pre_key_id, Some(1));
+        assert_eq!(bundle.kem_last_resort, Some(true));
+
+        let forged = controller
+            .register_pre_key_bundle(Request::new(RegisterPreKeyBundleRequest {
+                one_time_kem_keys: vec![kem_key(3, &MemoryClient::new().get_ik()?)?],
+                ..register_request(&mut bob, 0)?
+            }))
+            .await;
+        assert_eq!(forged.err().map(|e| e.code()), Some(Code::Unauthenticated));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn cipher_suite_in_bundle() -> Result<()> {
+        let controller = BrongnalController::new(Box::new(MemoryStorage::default()));
+        let mut bob = MemoryClient::new();
+        controller
+            .register_pre_key_bundle(Request::new(RegisterPreKeyBundleRequest {
+                cipher_suite: Some(CipherSuite::CURRENT.id()),
+                ..register_request(&mut bob, 0)?
+            }))
+            .await?;
+        let request_bundle = || async {
+            controller
+                .request_pre_keys(Request::new(RequestPreKeysRequest {
+                    identity: Some(String::from("bob")),
+                }))
+                .await
+                .map(|response| response.into_inner().bundles.remove(0))
+        };
+        assert_eq!(
+            request_bundle().await?.cipher_suite,
+            Some(CipherSuite::CURRENT.id())
+        );
+
+        // Re-registering from a client that predates cipher suites drops it.
+        register_bob(&controller, &mut bob).await?;
+        assert_eq!(request_bundle().await?.cipher_suite, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn ping() -> Result<()> {
+        let controller = BrongnalController::new(Box::new(MemoryStorage::default()));
+        let before = unix_seconds(SystemTime::now()) * 1000;
+        let response = controller
+            .ping(Request::new(PingRequest {
+                nonce: Some(vec![7; MAX_PING_NONCE_LEN]),
+            }))
+            .await?
+            .into_inner();
+        assert_eq!(response.nonce, Some(vec![7; MAX_PING_NONCE_LEN]));
+        assert!(response.server_time_millis() >= before);
+        assert!(response.server_time_millis() <= before + 2000);
+        controller
+            .ping(Request::new(PingRequest { nonce: None }))
+            .await?;
+        assert_eq!(controller.pings(), 2);
+
+        let status = controller
+            .ping(Request::new(PingRequest {
+                nonce: Some(vec![7; MAX_PING_NONCE_LEN + 1]),
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+        assert_eq!(controller.pings(), 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn unknown_identity_not_found() -> Result<()> {
+        let controller = BrongnalController::new(Box::new(MemoryStorage::default()));
+        let bob = MemoryClient::new();
+
+        assert_eq!(
+            controller
+                .send_message(Request::new(send_message_request("alice", &bob)?))
+                .await
+                .err()
+                .map(|e| e.code()),
+            Some(Code::NotFound)
+        );
+        assert_eq!(
+            controller
+                .request_pre_keys(Request::new(RequestPreKeysRequest {
+                    identity: Some(String::from("bob")),
+                }))
+                .await
+                .err()
+                .map(|e| e.code()),
+            Some(Code::NotFound)
+        );
+        assert_eq!(
+            controller
+                .retrieve_messages(Request::new(RetrieveMessagesRequest {
+                    identity: Some(String::from("bob")),
+                    device_id: None,
+                }))
+                .await
+                .err()
+                .map(|e| e.code()),
+            Some(Code::NotFound)
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn drain_ends_open_streams_cleanly() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("brongnal-drain-{}.sock", std::process::id()));
+        let (incoming, _cleanup) = uds::bind(&path)?;
+        let controller = Arc::new(BrongnalController::new(Box::new(MemoryStorage::default())));
+        register_bob(&controller, &mut MemoryClient::new()).await?;
+        let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+        let server = {
+            let controller = controller.clone();
+            tokio::spawn(
+                Server::builder()
+                    .add_service(BrongnalServer::from_arc(controller.clone()))
+                    .serve_with_incoming_shutdown(incoming, async move {
+                        let _ = shutdown_rx.await;
+                        controller.drain();
+                    }),
+            )
+        };
+
+        let mut stub = BrongnalClient::new(connect_uds(&path).await?);
+        let mut stream = stub
+            .retrieve_messages(RetrieveMessagesRequest {
+                identity: Some(String::from("bob")),
+                device_id: None,
+            })
+            .await?
+            .into_inner();
+
+        shutdown_tx.send(()).unwrap();
+        assert_eq!(stream.message().await?, None);
+        assert_eq!(
+            controller
+                .retrieve_messages(Request::new(RetrieveMessagesRequest {
+                    identity: Some(String::from("bob")),
+                    device_id: None,
+                }))
+                .await
+                .err()
+                .map(|e| e.code()),
+            Some(Code::Unavailable)
+        );
+
+        drop(stream);
+        drop(stub);
+        server.await??;
+        Ok(())
+    }
+
+    /// Records the wakeups it is asked to send, rejecting tokens once they are invalidated.
+    #[derive(Debug)]
+    struct MockDispatcher {
+        pushes: mpsc::UnboundedSender<(String, String, WakeupPayload)>,
+        invalid_tokens: Mutex<Vec<String>>,
+    }
+
+    impl MockDispatcher {
+        fn new(pushes: mpsc::UnboundedSender<(String, String, WakeupPayload)>) -> Self {
+            MockDispatcher {
+                pushes,
+                invalid_tokens: Mutex::new(Vec::new()),
+            }
+        }
+    }
+
+    #[tonic::async_trait]
+    impl PushDispatcher for MockDispatcher {
+        async fn notify(
+            &self,
+            identity: &str,
+            device_token: &str,
+            payload: WakeupPayload,
+        ) -> Result<(), PushError> {
+            let _ = self
+                .pushes
+                .send((identity.to_owned(), device_token.to_owned(), payload));
+            if self
+                .invalid_tokens
+                .lock()
+                .unwrap()
+                .iter()
+                .any(|token| token == device_token)
+            {
+                return Err(PushError::InvalidToken);
+            }
+            Ok(())
+        }
+    }
+
+    fn push_token_request(
+        signer: &MemoryClient,
+        platform: PushPlatformProto,
+        token: &str,
+    ) -> Result<RegisterPushTokenRequest> {
+        let payload = register_push_token_payload("bob", DEFAULT_DEVICE_ID, token, platform, "1.0");
+        Ok(RegisterPushTokenRequest {
+            identity: Some(String::from("bob")),
+            device_id: None,
+            device_token: Some(token.to_owned()),
+            platform: Some(platform as i32),
+            app_version: Some(String::from("1.0")),
+            signature: Some(signer.get_ik()?.sign(&payload).to_vec()),
+        })
+    }
+
+    #[tokio::test]
+    async fn register_push_token_requires_device_key() -> Result<()> {
+        let controller = BrongnalController::new(Box::new(MemoryStorage::default()));
+        let mut bob = MemoryClient::new();
+        let register = |request| async {
+            controller
+                .register_push_token(Request::new(request))
+                .await
+                .err()
+                .map(|e| e.code())
+        };
+        assert_eq!(
+            register(push_token_request(&bob, PushPlatformProto::Fcm, "token")?).await,
+            Some(Code::NotFound)
+        );
+        register_bob(&controller, &mut bob).await?;
+
+        let mallory = MemoryClient::new();
+        assert_eq!(
+            register(push_token_request(
+                &mallory,
+                PushPlatformProto::Fcm,
+                "token"
+            )?)
+            .await,
+            Some(Code::Unauthenticated)
+        );
+        // Bob's signature doesn't carry over to a token he didn't sign.
+        let mut request = push_token_request(&bob, PushPlatformProto::Fcm, "token")?;
+        request.device_token = Some(String::from("other"));
+        assert_eq!(register(request).await, Some(Code::Unauthenticated));
+        assert_eq!(
+            register(push_token_request(
+                &bob,
+                PushPlatformProto::Unknown,
+                "token"
+            )?)
+            .await,
+            Some(Code::InvalidArgument)
+        );
+        assert_eq!(
+            controller
+                .storage
+                .get_push_token("bob", DEFAULT_DEVICE_ID)
+                .await?,
+            None
+        );
+
+        assert_eq!(
+            register(push_token_request(&bob, PushPlatformProto::Fcm, "token")?).await,
+            None
+        );
+        assert_eq!(
+            controller
+                .storage
+                .get_push_token("bob", DEFAULT_DEVICE_ID)
+                .await?,
+            Some(PushToken {
+                platform: PushPlatform::Fcm,
+                token: String::from("token"),
+                app_version: Some(String::from("1.0")),
+            })
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn push_only_without_open_stream() -> Result<()> {
+        let (tx, mut pushes) = mpsc::unbounded_channel();
+        let controller = BrongnalController::new(Box::new(MemoryStorage::default()))
+            .with_push_dispatcher(PushPlatform::Fcm, Arc::new(MockDispatcher::new(tx)));
+        let mut bob = MemoryClient::new();
+        register_bob(&controller, &mut bob).await?;
+
+        // Without a token there is nothing to push to.
+        controller
+            .send_message(Request::new(send_message_request("alice", &bob)?))
+            .await?;
+        controller
+            .register_push_token(Request::new(push_token_request(
+                &bob,
+                PushPlatformProto::Fcm,
+                "token",
+            )?))
+            .await?;
+        controller
+            .send_message(Request::new(send_message_request("alice", &bob)?))
+            .await?;
+        assert_eq!(
+            pushes.recv().await,
+            Some((
+                String::from("bob"),
+                String::from("token"),
+                WakeupPayload {
+                    device_id: DEFAULT_DEVICE_ID
+                }
+            ))
+        );
+
+        let mut stream = controller
+            .retrieve_messages(Request::new(RetrieveMessagesRequest {
+                identity: Some(String::from("bob")),
+                device_id: None,
+            }))
+            .await?
+            .into_inner();
+        controller
+            .send_message(Request::new(send_message_request("alice", &bob)?))
+            .await?;
+        for _ in 0..3 {
+            assert!(tokio_stream::StreamExt::next(&mut stream).await.is_some());
+        }
+        assert!(
+            tokio::time::timeout(Duration::from_millis(100), pushes.recv())
+                .await
+                .is_err()
+        );
+
+        // Once the app goes away, pushes resume.
+        drop(stream);
+        controller
+            .send_message(Request::new(send_message_request("alice", &bob)?))
+            .await?;
+        assert!(pushes.recv().await.is_some());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn push_per_platform_and_prune_invalid_tokens() -> Result<()> {
+        let (fcm_tx, mut fcm_pushes) = mpsc::unbounded_channel();
+        let (apns_tx, mut apns_pushes) = mpsc::unbounded_channel();
+        let apns = Arc::new(MockDispatcher::new(apns_tx));
+        let controller = BrongnalController::new(Box::new(MemoryStorage::default()))
+            .with_push_dispatcher(PushPlatform::Fcm, Arc::new(MockDispatcher::new(fcm_tx)))
+            .with_push_dispatcher(PushPlatform::Apns, apns.clone());
+        let mut bob = MemoryClient::new();
+        register_bob(&controller, &mut bob).await?;
+        controller
+            .register_push_token(Request::new(push_token_request(
+                &bob,
+                PushPlatformProto::Apns,
+                "ios",
+            )?))
+            .await?;
+
+        controller
+            .send_message(Request::new(send_message_request("alice", &bob)?))
+            .await?;
+        assert_eq!(
+            apns_pushes.recv().await.map(|(_, token, _)| token),
+            Some(String::from("ios"))
+        );
+        assert!(fcm_pushes.try_recv().is_err());
+
+        // Once the app is uninstalled, APNs rejects the token and it is forgotten.
+        apns.invalid_tokens
+            .lock()
+            .unwrap()
+            .push(String::from("ios"));
+        controller
+            .send_message(Request::new(send_message_request("alice", &bob)?))
+            .await?;
+        assert!(apns_pushes.recv().await.is_some());
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while controller
+                .storage
+                .get_push_token("bob", DEFAULT_DEVICE_ID)
+                .await
+                .unwrap()
+                .is_some()
+            {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await?;
+        controller
+            .send_message(Request::new(send_message_request("alice", &bob)?))
+            .await?;
+        assert!(
+            tokio::time::timeout(Duration::from_millis(100), apns_pushes.recv())
+                .await
+                .is_err()
+        );
+        Ok(())
     }
 }
@@ -0,0 +1,541 @@
+//! The server binary's configuration, from flags or, failing those, environment variables.
+
+use crate::brongnal::{MailboxQuota, MaintenancePolicy, OpkQuota, QuotaPolicy, RetentionPolicy};
+use crate::ip_limit::{IpRateLimits, RpcClass};
+use crate::rate_limit::RateLimit;
+use crate::timeout::RpcTimeouts;
+use clap::Parser;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::time::Duration;
+
+const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Brongnal's key distribution and message relay server.
+#[derive(Clone, Debug, Parser)]
+#[command(name = "server")]
+pub struct ServerConfig {
+    /// Address to listen for TCP connections on.
+    #[arg(long, env = "BRONGNAL_LISTEN_ADDR", default_value_t = IpAddr::V4(Ipv4Addr::UNSPECIFIED))]
+    pub listen_addr: IpAddr,
+    /// Port to listen for TCP connections on.
+    #[arg(long, env = "BRONGNAL_PORT", default_value_t = 8080, value_parser = parse_port)]
+    pub port: u16,
+    /// Serve over this unix domain socket instead of TCP.
+    #[arg(long, env = "BRONGNAL_UDS")]
+    pub uds: Option<PathBuf>,
+    /// Directory the database is kept in, unless --db-path names the file.
+    #[arg(long, env = "DB", default_value = "db")]
+    pub db_dir: PathBuf,
+    /// The database file. Defaults to brongnal.db3 in --db-dir.
+    #[arg(long, env = "BRONGNAL_DB_PATH")]
+    pub db_path: Option<PathBuf>,
+    /// Read only database connections queries run on alongside writes, or 0 to run them on the
+    /// one connection that writes.
+    #[arg(long, env = "BRONGNAL_READ_CONNECTIONS", default_value_t = 4)]
+    pub read_connections: usize,
+    /// File holding the hex key queued messages are encrypted under in the database. Databases
+    /// remember whether they are encrypted, and with which key.
+    #[arg(long, env = "BRONGNAL_DB_KEY_FILE")]
+    pub db_key_file: Option<PathBuf>,
+    /// Seconds to wait for in-flight requests on shutdown.
+    #[arg(long, env = "BRONGNAL_GRACE_PERIOD", default_value_t = 10)]
+    pub grace_period: u64,
+
+    /// Messages a client address may send at once.
+    #[arg(long, env = "BRONGNAL_SEND_BURST", default_value_t = 20)]
+    pub send_burst: u32,
+    /// Messages per second a client address's allowance refills at.
+    #[arg(long, env = "BRONGNAL_SEND_RATE", default_value_t = 1.0, value_parser = parse_positive)]
+    pub send_rate: f64,
+    /// Registrations an address may make at once.
+    #[arg(long, env = "BRONGNAL_REGISTRATION_BURST", default_value_t = 10)]
+    pub registration_burst: u32,
+    /// Registrations per second an address's allowance refills at.
+    #[arg(
+        long,
+        env = "BRONGNAL_REGISTRATION_RATE",
+        default_value_t = 0.1,
+        value_parser = parse_positive
+    )]
+    pub registration_rate: f64,
+    /// Pre key requests an address may make at once.
+    #[arg(long, env = "BRONGNAL_PRE_KEY_BURST", default_value_t = 50)]
+    pub pre_key_burst: u32,
+    /// Pre key requests per second an address's allowance refills at.
+    #[arg(long, env = "BRONGNAL_PRE_KEY_RATE", default_value_t = 1.0, value_parser = parse_positive)]
+    pub pre_key_rate: f64,
+    /// Registration challenges and account deletion nonces an address may ask for at once.
+    #[arg(long, env = "BRONGNAL_CHALLENGE_BURST", default_value_t = 20)]
+    pub challenge_burst: u32,
+    /// Challenges per second an address's allowance refills at.
+    #[arg(
+        long,
+        env = "BRONGNAL_CHALLENGE_RATE",
+        default_value_t = 0.5,
+        value_parser = parse_positive
+    )]
+    pub challenge_rate: f64,
+    /// Reverse proxies in front of the server, whose X-Forwarded-For entries are trusted to
+    /// name the client's address. Zero uses the connection's address.
+    #[arg(long, env = "BRONGNAL_TRUSTED_PROXIES", default_value_t = 0)]
+    pub trusted_proxies: usize,
+    /// Fraction of successful RPCs logged, between 0 and 1. Failed ones are always logged.
+    #[arg(
+        long,
+        env = "BRONGNAL_REQUEST_LOG_SAMPLE_RATE",
+        default_value_t = 1.0,
+        value_parser = parse_fraction
+    )]
+    pub request_log_sample_rate: f64,
+    /// Seconds the server may take to answer an RPC before failing it with DeadlineExceeded.
+    /// Message streams aren't limited.
+    #[arg(long, env = "BRONGNAL_RPC_TIMEOUT", default_value_t = 10)]
+    pub rpc_timeout: u64,
+    /// Comma separated per method budgets overriding --rpc-timeout, e.g.
+    /// "ExportAccountData=60,SendMessage=0", where 0 means no limit.
+    #[arg(
+        long,
+        env = "BRONGNAL_METHOD_TIMEOUTS",
+        value_delimiter = ',',
+        value_parser = parse_method_timeout
+    )]
+    pub method_timeouts: Vec<(String, u64)>,
+    /// Messages queued per recipient.
+    #[arg(long, env = "BRONGNAL_MAILBOX_QUOTA", default_value_t = 1000)]
+    pub mailbox_quota: usize,
+    /// What to do with messages to a full mailbox: "reject" them or "evict" the oldest.
+    #[arg(
+        long,
+        env = "BRONGNAL_MAILBOX_POLICY",
+        default_value = "reject",
+        value_parser = parse_policy
+    )]
+    pub mailbox_policy: QuotaPolicy,
+    /// One time keys stored per user.
+    #[arg(
+        long,
+        alias = "max-otks-per-user",
+        env = "BRONGNAL_MAX_OPKS",
+        default_value_t = OpkQuota::default().max_keys
+    )]
+    pub max_opks: usize,
+    /// What to do with uploads past --max-opks: "reject" them or "evict" the oldest keys.
+    #[arg(long, env = "BRONGNAL_OPK_POLICY", default_value = "reject", value_parser = parse_policy)]
+    pub opk_policy: QuotaPolicy,
+    /// Leading zero bits registrations must find a proof of work for, each doubling the work.
+    /// Zero lets anyone register for free.
+    #[arg(
+        long,
+        env = "BRONGNAL_REGISTRATION_DIFFICULTY",
+        default_value_t = 0,
+        value_parser = clap::value_parser!(u32).range(0..=32)
+    )]
+    pub registration_difficulty: u32,
+    /// Only admit new identities holding an invite code created through the Admin service.
+    #[arg(long, env = "BRONGNAL_INVITE_ONLY")]
+    pub invite_only: bool,
+    /// Largest message ciphertext accepted, in bytes. Defaults to, and can't exceed, 1 MiB.
+    #[arg(long, env = "BRONGNAL_MAX_CIPHERTEXT_BYTES")]
+    pub max_ciphertext_bytes: Option<usize>,
+
+    /// Days undelivered messages are kept.
+    #[arg(
+        long,
+        alias = "message-ttl",
+        env = "BRONGNAL_MESSAGE_TTL_DAYS",
+        default_value_t = 30
+    )]
+    pub message_ttl_days: u32,
+    /// Days one time keys left over from a previous registration are kept.
+    #[arg(long, env = "BRONGNAL_OPK_TTL_DAYS", default_value_t = 90)]
+    pub opk_ttl_days: u32,
+    /// Days after which users still using the same signed pre key are reported.
+    #[arg(long, env = "BRONGNAL_MAX_SPK_AGE_DAYS", default_value_t = 30)]
+    pub max_spk_age_days: u32,
+    /// Seconds between checks of the database's write-ahead log size.
+    #[arg(long, env = "BRONGNAL_WAL_CHECKPOINT_INTERVAL", default_value_t = 300)]
+    pub wal_checkpoint_interval: u64,
+    /// Bytes the write-ahead log may grow to before it is checkpointed into the database.
+    #[arg(long, env = "BRONGNAL_MAX_WAL_BYTES", default_value_t = 64 << 20)]
+    pub max_wal_bytes: u64,
+    /// Hours between refreshes of the database's query planner statistics, or 0 for never.
+    #[arg(long, env = "BRONGNAL_OPTIMIZE_INTERVAL_HOURS", default_value_t = 24)]
+    pub optimize_interval_hours: u64,
+
+    /// Comma separated origins whose pages may connect with gRPC-Web, or "*" for any.
+    #[arg(long, env = "BRONGNAL_WEB_ORIGINS", value_delimiter = ',')]
+    pub web_origins: Vec<String>,
+    /// This server's federation domain.
+    #[arg(long, env = "BRONGNAL_DOMAIN")]
+    pub domain: Option<String>,
+    /// File listing federation peers, one "domain url secret" per line.
+    #[arg(long, env = "BRONGNAL_FEDERATION_PEERS", requires = "domain")]
+    pub federation_peers: Option<PathBuf>,
+
+    /// Serve the Admin service on this address. Only loopback addresses are allowed without
+    /// --admin-token.
+    #[arg(long, env = "BRONGNAL_ADMIN_ADDR")]
+    pub admin_addr: Option<SocketAddr>,
+    /// Serve the Admin service on this unix domain socket.
+    #[arg(long, env = "BRONGNAL_ADMIN_UDS", conflicts_with = "admin_addr")]
+    pub admin_uds: Option<PathBuf>,
+    /// Bearer token Admin requests must carry.
+    #[arg(long, env = "BRONGNAL_ADMIN_TOKEN", hide_env_values = true)]
+    pub admin_token: Option<String>,
+
+    /// Firebase service account key to wake Android devices with (needs the fcm feature).
+    #[arg(long, env = "BRONGNAL_FCM_KEY")]
+    pub fcm_key: Option<PathBuf>,
+    /// APNs .p8 key to wake iOS devices with (needs the apns feature).
+    #[arg(
+        long,
+        env = "BRONGNAL_APNS_KEY",
+        requires_all = ["apns_key_id", "apns_team_id", "apns_topic"]
+    )]
+    pub apns_key: Option<PathBuf>,
+    /// Id of the --apns-key.
+    #[arg(long, env = "BRONGNAL_APNS_KEY_ID")]
+    pub apns_key_id: Option<String>,
+    /// Apple developer team the --apns-key belongs to.
+    #[arg(long, env = "BRONGNAL_APNS_TEAM_ID")]
+    pub apns_team_id: Option<String>,
+    /// The app's bundle id.
+    #[arg(long, env = "BRONGNAL_APNS_TOPIC")]
+    pub apns_topic: Option<String>,
+    /// Use the APNs sandbox, for development builds of the app.
+    #[arg(long, env = "BRONGNAL_APNS_SANDBOX")]
+    pub apns_sandbox: bool,
+}
+
+fn parse_port(port: &str) -> Result<u16, String> {
+    match port.parse() {
+        Ok(0) => Err(String::from("port 0 would listen on a random port")),
+        Ok(port) => Ok(port),
+        Err(_) => Err(format!("{port} isn't a port between 1 and 65535")),
+    }
+}
+
+fn parse_positive(value: &str) -> Result<f64, String> {
+    match value.parse() {
+        Ok(value) if value > 0.0 => Ok(value),
+        _ => Err(format!("{value} isn't a positive number")),
+    }
+}
+
+fn parse_fraction(value: &str) -> Result<f64, String> {
+    match value.parse() {
+        Ok(value) if (0.0..=1.0).contains(&value) => Ok(value),
+        _ => Err(format!("{value} isn't a number between 0 and 1")),
+    }
+}
+
+fn parse_method_timeout(value: &str) -> Result<(String, u64), String> {
+    let (method, seconds) = value
+        .split_once('=')
+        .ok_or_else(|| format!("{value} isn't METHOD=SECONDS"))?;
+    let seconds = seconds
+        .parse()
+        .map_err(|_| format!("{seconds} isn't a number of seconds"))?;
+    Ok((method.to_owned(), seconds))
+}
+
+fn parse_policy(policy: &str) -> Result<QuotaPolicy, String> {
+    match policy {
+        "reject" => Ok(QuotaPolicy::Reject),
+        "evict" => Ok(QuotaPolicy::EvictOldest),
+        _ => Err(format!("{policy} isn't \"reject\" or \"evict\"")),
+    }
+}
+
+impl ServerConfig {
+    /// The TCP address to listen on.
+    pub fn socket_addr(&self) -> SocketAddr {
+        (self.listen_addr, self.port).into()
+    }
+
+    pub fn db_path(&self) -> PathBuf {
+        self.db_path
+            .clone()
+            .unwrap_or_else(|| self.db_dir.join("brongnal.db3"))
+    }
+
+    pub fn grace_period(&self) -> Duration {
+        Duration::from_secs(self.grace_period)
+    }
+
+    pub fn send_limit(&self) -> RateLimit {
+        RateLimit {
+            rate: self.send_rate,
+            burst: self.send_burst,
+        }
+    }
+
+    pub fn ip_limits(&self) -> IpRateLimits {
+        IpRateLimits::unlimited()
+            .with_limit(
+                RpcClass::Registration,
+                RateLimit {
+                    rate: self.registration_rate,
+                    burst: self.registration_burst,
+                },
+            )
+            .with_limit(
+                RpcClass::PreKeys,
+                RateLimit {
+                    rate: self.pre_key_rate,
+                    burst: self.pre_key_burst,
+                },
+            )
+            .with_limit(
+                RpcClass::Challenge,
+                RateLimit {
+                    rate: self.challenge_rate,
+                    burst: self.challenge_burst,
+                },
+            )
+            .with_trusted_proxies(self.trusted_proxies)
+    }
+
+    pub fn rpc_timeouts(&self) -> RpcTimeouts {
+        self.method_timeouts.iter().fold(
+            RpcTimeouts::new(Duration::from_secs(self.rpc_timeout)),
+            |timeouts, (method, seconds)| {
+                let budget = (*seconds > 0).then(|| Duration::from_secs(*seconds));
+                timeouts.with_method(method, budget)
+            },
+        )
+    }
+
+    pub fn mailbox_quota(&self) -> MailboxQuota {
+        MailboxQuota {
+            max_messages: self.mailbox_quota,
+            policy: self.mailbox_policy,
+        }
+    }
+
+    pub fn opk_quota(&self) -> OpkQuota {
+        OpkQuota {
+            max_keys: self.max_opks,
+            policy: self.opk_policy,
+        }
+    }
+
+    pub fn retention_policy(&self) -> RetentionPolicy {
+        RetentionPolicy {
+            message_ttl: DAY * self.message_ttl_days,
+            opk_ttl: DAY * self.opk_ttl_days,
+            max_spk_age: DAY * self.max_spk_age_days,
+        }
+    }
+
+    pub fn maintenance_policy(&self) -> MaintenancePolicy {
+        MaintenancePolicy {
+            checkpoint_interval: Duration::from_secs(self.wal_checkpoint_interval),
+            max_wal_bytes: self.max_wal_bytes,
+            optimize_interval: (self.optimize_interval_hours > 0)
+                .then(|| Duration::from_secs(self.optimize_interval_hours * 60 * 60)),
+        }
+    }
+
+    /// Checks the Admin service isn't reachable from other machines without a token.
+    pub fn check_admin(&self) -> Result<(), String> {
+        match self.admin_addr {
+            Some(addr) if !addr.ip().is_loopback() && self.admin_token.is_none() => Err(format!(
+                "--admin-addr {addr} isn't a loopback address, so it needs --admin-token"
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::error::ErrorKind;
+    use clap::CommandFactory;
+
+    fn parse(args: &[&str]) -> Result<ServerConfig, clap::Error> {
+        ServerConfig::try_parse_from(std::iter::once("server").chain(args.iter().copied()))
+    }
+
+    #[test]
+    fn help() {
+        ServerConfig::command().debug_assert();
+        let help = ServerConfig::command().render_long_help().to_string();
+        assert!(help.contains("--listen-addr"));
+        assert!(help.contains("[env: BRONGNAL_PORT=]"));
+    }
+
+    #[test]
+    fn defaults() {
+        let config = parse(&[]).unwrap();
+        assert_eq!(config.socket_addr(), "0.0.0.0:8080".parse().unwrap());
+        assert_eq!(config.grace_period(), Duration::from_secs(10));
+        assert_eq!(config.opk_quota().max_keys, 500);
+        assert_eq!(config.opk_quota().policy, QuotaPolicy::Reject);
+        assert_eq!(config.retention_policy().message_ttl, DAY * 30);
+        assert_eq!(config.max_ciphertext_bytes, None);
+        assert_eq!(config.registration_difficulty, 0);
+        assert!(!config.invite_only);
+        assert!(config.uds.is_none());
+        assert_eq!(config.trusted_proxies, 0);
+        assert_eq!(config.read_connections, 4);
+        let maintenance = config.maintenance_policy();
+        assert_eq!(maintenance.checkpoint_interval, Duration::from_secs(300));
+        assert_eq!(maintenance.max_wal_bytes, 64 << 20);
+        assert_eq!(maintenance.optimize_interval, Some(DAY));
+        assert_eq!(config.registration_burst, 10);
+        assert_eq!(config.pre_key_rate, 1.0);
+        assert_eq!(config.request_log_sample_rate, 1.0);
+        assert_eq!(
+            config
+                .rpc_timeouts()
+                .budget("/service.Brongnal/SendMessage"),
+            Some(Duration::from_secs(10))
+        );
+    }
+
+    #[test]
+    fn flags() {
+        let config = parse(&[
+            "--listen-addr",
+            "::1",
+            "--port",
+            "9090",
+            "--db-path",
+            "/tmp/brongnal.db3",
+            "--max-otks-per-user",
+            "50",
+            "--opk-policy",
+            "evict",
+            "--message-ttl",
+            "7",
+            "--trusted-proxies",
+            "2",
+            "--pre-key-burst",
+            "5",
+            "--rpc-timeout",
+            "3",
+            "--method-timeouts",
+            "ExportAccountData=60,SendMessage=0",
+            "--read-connections",
+            "0",
+            "--optimize-interval-hours",
+            "0",
+        ])
+        .unwrap();
+        assert_eq!(config.socket_addr(), "[::1]:9090".parse().unwrap());
+        assert_eq!(config.db_path(), PathBuf::from("/tmp/brongnal.db3"));
+        assert_eq!(config.opk_quota().max_keys, 50);
+        assert_eq!(config.opk_quota().policy, QuotaPolicy::EvictOldest);
+        assert_eq!(config.retention_policy().message_ttl, DAY * 7);
+        assert_eq!(config.trusted_proxies, 2);
+        assert_eq!(config.pre_key_burst, 5);
+        assert_eq!(config.read_connections, 0);
+        assert_eq!(config.maintenance_policy().optimize_interval, None);
+        let timeouts = config.rpc_timeouts();
+        assert_eq!(
+            timeouts.budget("/service.Brongnal/Ping"),
+            Some(Duration::from_secs(3))
+        );
+        assert_eq!(
+            timeouts.budget("/service.Brongnal/ExportAccountData"),
+            Some(Duration::from_secs(60))
+        );
+        assert_eq!(timeouts.budget("/service.Brongnal/SendMessage"), None);
+    }
+
+    #[test]
+    fn flags_override_environment() {
+        // No other test reads these variables.
+        std::env::set_var("BRONGNAL_MAILBOX_QUOTA", "5");
+        std::env::set_var(
+            "BRONGNAL_WEB_ORIGINS",
+            "https://a.example,https://b.example",
+        );
+        let from_env = parse(&[]).unwrap();
+        assert_eq!(from_env.mailbox_quota().max_messages, 5);
+        assert_eq!(
+            from_env.web_origins,
+            vec!["https://a.example", "https://b.example"]
+        );
+        let from_flag = parse(&["--mailbox-quota", "6", "--web-origins", "*"]).unwrap();
+        assert_eq!(from_flag.mailbox_quota().max_messages, 6);
+        assert_eq!(from_flag.web_origins, vec!["*"]);
+        std::env::remove_var("BRONGNAL_MAILBOX_QUOTA");
+        std::env::remove_var("BRONGNAL_WEB_ORIGINS");
+    }
+
+    #[test]
+    fn rejects_invalid_values() {
+        let err = parse(&["--port", "0"]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ValueValidation);
+        assert!(err
+            .to_string()
+            .contains("port 0 would listen on a random port"));
+        for args in [
+            &["--port", "65536"][..],
+            &["--listen-addr", "localhost"],
+            &["--send-rate", "0"],
+            &["--request-log-sample-rate", "1.5"],
+            &["--method-timeouts", "SendMessage"],
+            &["--method-timeouts", "SendMessage=soon"],
+            &["--mailbox-policy", "drop"],
+            &["--registration-difficulty", "33"],
+        ] {
+            assert_eq!(
+                parse(args).unwrap_err().kind(),
+                ErrorKind::ValueValidation,
+                "{args:?}"
+            );
+        }
+        assert_eq!(
+            parse(&["--federation-peers", "peers"]).unwrap_err().kind(),
+            ErrorKind::MissingRequiredArgument
+        );
+        assert_eq!(
+            parse(&["--apns-key", "AuthKey.p8"]).unwrap_err().kind(),
+            ErrorKind::MissingRequiredArgument
+        );
+        assert_eq!(
+            parse(&[
+                "--admin-addr",
+                "127.0.0.1:8081",
+                "--admin-uds",
+                "admin.sock"
+            ])
+            .unwrap_err()
+            .kind(),
+            ErrorKind::ArgumentConflict
+        );
+    }
+
+    #[test]
+    fn admin_listener() {
+        assert!(parse(&[]).unwrap().check_admin().is_ok());
+        assert!(parse(&["--admin-addr", "127.0.0.1:8081"])
+            .unwrap()
+            .check_admin()
+            .is_ok());
+        assert!(parse(&["--admin-addr", "[::1]:8081"])
+            .unwrap()
+            .check_admin()
+            .is_ok());
+        assert!(parse(&["--admin-uds", "admin.sock"])
+            .unwrap()
+            .check_admin()
+            .is_ok());
+        assert!(parse(&["--admin-addr", "0.0.0.0:8081"])
+            .unwrap()
+            .check_admin()
+            .unwrap_err()
+            .contains("needs --admin-token"));
+        assert!(
+            parse(&["--admin-addr", "0.0.0.0:8081", "--admin-token", "s3cret"])
+                .unwrap()
+                .check_admin()
+                .is_ok()
+        );
+    }
+}
@@ -0,0 +1,294 @@
+//! Relaying between servers, so `alice@a.example` on one server can message `bob@b.example` on
+//! another. Each server knows its own domain and, for each peer it relays to, how to reach it and
+//! a secret the two share. Identities without a domain live on the server they are sent to.
+
+use proto::service::brongnal_client::BrongnalClient;
+use proto::service::{
+    RequestPreKeysRequest, RequestPreKeysResponse, SendMessageRequest, SendMessageResponse,
+};
+use std::collections::HashMap;
+use std::fmt;
+use tonic::codegen::http::HeaderMap;
+use tonic::metadata::{MetadataMap, MetadataValue};
+use tonic::transport::Channel;
+use tonic::{Extensions, Request, Result, Status};
+
+/// The header a relayed request names the server that relayed it in.
+pub const DOMAIN_HEADER: &str = "brongnal-federation-domain";
+/// The header a relayed request carries the secret its server shares with the recipient in.
+pub const SECRET_HEADER: &str = "brongnal-federation-secret";
+
+/// Another server this one relays requests to and accepts relayed requests from.
+#[derive(Clone)]
+pub struct FederationPeer {
+    stub: BrongnalClient<Channel>,
+    secret: String,
+}
+
+impl fmt::Debug for FederationPeer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Keeps the secret out of logs.
+        f.debug_struct("FederationPeer").finish_non_exhaustive()
+    }
+}
+
+impl FederationPeer {
+    /// A peer reached over `channel` that shares `secret` with this server.
+    pub fn new(channel: Channel, secret: impl Into<String>) -> Self {
+        FederationPeer {
+            stub: BrongnalClient::new(channel),
+            secret: secret.into(),
+        }
+    }
+
+    /// `message` with the headers that tell the peer `domain` relayed it.
+    fn relayed<T>(&self, domain: &str, message: T) -> Result<Request<T>> {
+        let mut request = Request::new(message);
+        let metadata = request.metadata_mut();
+        metadata.insert(
+            DOMAIN_HEADER,
+            MetadataValue::try_from(domain)
+                .map_err(|_| Status::internal("domain isn't a valid header"))?,
+        );
+        metadata.insert(
+            SECRET_HEADER,
+            MetadataValue::try_from(self.secret.as_str())
+                .map_err(|_| Status::internal("federation secret isn't a valid header"))?,
+        );
+        Ok(request)
+    }
+
+    /// Asks the peer for the pre keys of one of its identities on behalf of `domain`.
+    pub async fn request_pre_keys(
+        &self,
+        domain: &str,
+        request: RequestPreKeysRequest,
+    ) -> Result<RequestPreKeysResponse> {
+        let request = self.relayed(domain, request)?;
+        Ok(self
+            .stub
+            .clone()
+            .request_pre_keys(request)
+            .await?
+            .into_inner())
+    }
+
+    /// Sends a message to one of the peer's identities on behalf of `domain`.
+    pub async fn send_message(
+        &self,
+        domain: &str,
+        request: SendMessageRequest,
+    ) -> Result<SendMessageResponse> {
+        let request = self.relayed(domain, request)?;
+        Ok(self.stub.clone().send_message(request).await?.into_inner())
+    }
+}
+
+/// Where requests for an identity go.
+#[derive(Debug)]
+pub enum Route<'a> {
+    /// To the identity registered here by this name.
+    Local(String),
+    /// To the peer the identity's domain belongs to.
+    Remote(&'a FederationPeer),
+}
+
+/// This server's domain and the peers it relays to.
+#[derive(Debug)]
+pub struct Federation {
+    domain: String,
+    peers: HashMap<String, FederationPeer>,
+}
+
+impl Federation {
+    /// Federation as `domain`, with no peers yet.
+    pub fn new(domain: impl Into<String>) -> Self {
+        Federation {
+            domain: domain.into(),
+            peers: HashMap::new(),
+        }
+    }
+
+    /// Relays requests for identities in `domain` to `peer`, and accepts requests it relays.
+    pub fn with_peer(mut self, domain: impl Into<String>, peer: FederationPeer) -> Self {
+        self.peers.insert(domain.into(), peer);
+        self
+    }
+
+    pub fn domain(&self) -> &str {
+        &self.domain
+    }
+
+    /// Where requests for `identity` go. A request a peer relayed is never relayed again, so
+    /// misconfigured peers can't bounce it between them.
+    pub fn route(&self, identity: &str, relayed_from: Option<&str>) -> Result<Route<'_>> {
+        let Some((name, domain)) = identity.rsplit_once('@') else {
+            return Ok(Route::Local(identity.to_owned()));
+        };
+        if domain == self.domain {
+            return Ok(Route::Local(name.to_owned()));
+        }
+        if let Some(peer_domain) = relayed_from {
+            return Err(Status::failed_precondition(format!(
+                "{peer_domain} relayed a request for {domain}, which this server won't relay again"
+            )));
+        }
+        match self.peers.get(domain) {
+            Some(peer) => Ok(Route::Remote(peer)),
+            None => Err(Status::not_found(format!("unknown domain {domain}"))),
+        }
+    }
+
+    /// The domain of the peer that relayed `request`, if one did.
+    pub fn relayed_from<'a, T>(&'a self, request: &Request<T>) -> Result<Option<&'a str>> {
+        let metadata = request.metadata();
+        let Some(domain) = metadata.get(DOMAIN_HEADER) else {
+            return Ok(None);
+        };
+        let secret = metadata.get(SECRET_HEADER);
+        let peer = domain
+            .to_str()
+            .ok()
+            .and_then(|domain| self.peers.get_key_value(domain));
+        match (peer, secret) {
+            (Some((domain, peer)), Some(secret)) if secret.as_bytes() == peer.secret.as_bytes() => {
+                Ok(Some(domain))
+            }
+            _ => Err(Status::unauthenticated("unknown federation peer or secret")),
+        }
+    }
+
+    /// Whether `headers` name one of this server's peers along with the secret it shares.
+    pub fn is_peer(&self, headers: &HeaderMap) -> bool {
+        if !headers.contains_key(DOMAIN_HEADER) {
+            return false;
+        }
+        let request = Request::from_parts(
+            MetadataMap::from_headers(headers.clone()),
+            Extensions::default(),
+            (),
+        );
+        matches!(self.relayed_from(&request), Ok(Some(_)))
+    }
+
+    /// `identity` as peers know it: with this server's domain, unless it already names one.
+    pub fn qualify(&self, identity: &str) -> String {
+        if identity.contains('@') {
+            identity.to_owned()
+        } else {
+            format!("{identity}@{}", self.domain)
+        }
+    }
+}
+
+/// Parses a peer list with one `domain url secret` line per peer. Blank lines and lines starting
+/// with `#` are skipped.
+pub fn parse_peers(config: &str) -> Result<Vec<(String, String, String)>, String> {
+    config
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(
+            |line| match line.split_whitespace().collect::<Vec<_>>()[..] {
+                [domain, url, secret] => Ok((domain.to_owned(), url.to_owned(), secret.to_owned())),
+                _ => Err(format!("expected \"domain url secret\", got \"{line}\"")),
+            },
+        )
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tonic::transport::Endpoint;
+
+    fn federation() -> Federation {
+        let channel = Endpoint::from_static("http://[::1]:1").connect_lazy();
+        Federation::new("a.example").with_peer("b.example", FederationPeer::new(channel, "s3cret"))
+    }
+
+    #[tokio::test]
+    async fn routes() {
+        let federation = federation();
+        assert!(matches!(
+            federation.route("alice", None),
+            Ok(Route::Local(name)) if name == "alice"
+        ));
+        assert!(matches!(
+            federation.route("alice@a.example", None),
+            Ok(Route::Local(name)) if name == "alice"
+        ));
+        assert!(matches!(
+            federation.route("bob@b.example", None),
+            Ok(Route::Remote(_))
+        ));
+        assert_eq!(
+            federation
+                .route("carol@c.example", None)
+                .unwrap_err()
+                .code(),
+            tonic::Code::NotFound
+        );
+        assert_eq!(
+            federation
+                .route("bob@b.example", Some("b.example"))
+                .unwrap_err()
+                .code(),
+            tonic::Code::FailedPrecondition
+        );
+        assert_eq!(federation.qualify("alice"), "alice@a.example");
+        assert_eq!(federation.qualify("bob@b.example"), "bob@b.example");
+    }
+
+    #[tokio::test]
+    async fn relayed_from() {
+        let federation = federation();
+        let relayed = |domain: &'static str, secret: &'static str| {
+            let mut request = Request::new(());
+            request
+                .metadata_mut()
+                .insert(DOMAIN_HEADER, MetadataValue::from_static(domain));
+            request
+                .metadata_mut()
+                .insert(SECRET_HEADER, MetadataValue::from_static(secret));
+            request
+        };
+        assert_eq!(federation.relayed_from(&Request::new(())).unwrap(), None);
+        assert_eq!(
+            federation
+                .relayed_from(&relayed("b.example", "s3cret"))
+                .unwrap(),
+            Some("b.example")
+        );
+        for (domain, secret) in [("b.example", "guess"), ("c.example", "s3cret")] {
+            assert_eq!(
+                federation
+                    .relayed_from(&relayed(domain, secret))
+                    .unwrap_err()
+                    .code(),
+                tonic::Code::Unauthenticated
+            );
+            assert!(!federation.is_peer(&relayed(domain, secret).metadata().clone().into_headers()));
+        }
+        assert!(federation.is_peer(
+            &relayed("b.example", "s3cret")
+                .metadata()
+                .clone()
+                .into_headers()
+        ));
+        assert!(!federation.is_peer(&HeaderMap::new()));
+    }
+
+    #[test]
+    fn parse_peer_list() {
+        assert_eq!(
+            parse_peers("# Peers\n\nb.example https://b.example:8080 s3cret\n"),
+            Ok(vec![(
+                String::from("b.example"),
+                String::from("https://b.example:8080"),
+                String::from("s3cret")
+            )])
+        );
+        assert!(parse_peers("b.example https://b.example:8080").is_err());
+    }
+}
@@ -1,36 +1,414 @@
 use ed25519_dalek::VerifyingKey;
+use proto::gossamer::append_key::KeyPurpose;
 use proto::gossamer::gossamer_server::Gossamer;
-use proto::gossamer::{ActionRequest, ActionResponse, SignedMessage};
-use std::collections::HashMap;
+use proto::gossamer::message::Action;
+use proto::gossamer::{
+    ActionRequest, ActionResponse, GetLedgerRequest, GetLedgerResponse, RevokeKeyRequest,
+    RevokeKeyResponse, SignedMessage,
+};
+use std::collections::{HashMap, HashSet};
 use std::sync::{Arc, Mutex};
 use tonic::{Request, Response, Status};
 
-pub struct InMemoryGossamer {
-    _provider: Arc<Mutex<HashMap<String, VerifyingKey>>>,
-    messages: Arc<Mutex<Vec<SignedMessage>>>,
+/// The keys a provider has appended to the ledger and which of them were since revoked.
+#[derive(Debug, Default)]
+struct ProviderKeys {
+    keys: HashMap<Vec<u8>, KeyPurpose>,
+    revoked: HashSet<Vec<u8>>,
+}
+
+impl ProviderKeys {
+    fn is_current(&self, key: &VerifyingKey, purposes: &[KeyPurpose]) -> bool {
+        let key = key.as_bytes().as_slice();
+        !self.revoked.contains(key)
+            && self
+                .keys
+                .get(key)
+                .is_some_and(|purpose| purposes.contains(purpose))
+    }
+}
+
+#[derive(Debug, Default)]
+struct Ledger {
+    providers: HashMap<String, ProviderKeys>,
+    messages: Vec<SignedMessage>,
 }
 
-impl Default for InMemoryGossamer {
-    fn default() -> Self {
-        Self {
-            _provider: Arc::new(Mutex::new(HashMap::new())),
-            messages: Arc::new(Mutex::new(Vec::new())),
+impl Ledger {
+    /// Appends `message` to the ledger if its signer is allowed to perform the action.
+    fn apply(
+        &mut self,
+        message: SignedMessage,
+        verified: proto::SignedMessage,
+    ) -> Result<(), Status> {
+        let keys = self.providers.entry(verified.provider.clone()).or_default();
+        let signer = verified.public_key.as_bytes().as_slice();
+        match verified
+            .message
+            .action
+            .ok_or(Status::invalid_argument("Missing action."))?
+        {
+            Action::AppendKey(append) => {
+                if append.provider() != verified.provider {
+                    return Err(Status::invalid_argument("Action is for another provider."));
+                }
+                if append.key_purpose() == KeyPurpose::Unknown {
+                    return Err(Status::invalid_argument("Missing key purpose."));
+                }
+                let key = append.public_key().to_vec();
+                if keys.revoked.contains(&key) {
+                    return Err(Status::failed_precondition("Key was revoked."));
+                }
+                // A provider's first key proves possession by signing its own append; later keys
+                // must be vouched for by a key that is still current.
+                let authorized = if keys.keys.is_empty() {
+                    signer == key
+                } else {
+                    keys.is_current(
+                        &verified.public_key,
+                        &[KeyPurpose::IdentityKey, KeyPurpose::RecoveryKey],
+                    )
+                };
+                if !authorized {
+                    return Err(Status::permission_denied(
+                        "Signer may not append keys for this provider.",
+                    ));
+                }
+                keys.keys.insert(key, append.key_purpose());
+            }
+            Action::RevokeKey(revoke) => {
+                if revoke.provider() != verified.provider {
+                    return Err(Status::invalid_argument("Action is for another provider."));
+                }
+                let key = revoke.public_key().to_vec();
+                if !keys.keys.contains_key(&key) {
+                    return Err(Status::not_found("Key not found."));
+                }
+                if keys.revoked.contains(&key) {
+                    return Err(Status::failed_precondition("Key was already revoked."));
+                }
+                if signer != key
+                    && !keys.is_current(&verified.public_key, &[KeyPurpose::RecoveryKey])
+                {
+                    return Err(Status::permission_denied(
+                        "Keys may only be revoked by themselves or a recovery key.",
+                    ));
+                }
+                keys.revoked.insert(key);
+            }
+            Action::Tombstone(tombstone) => {
+                if tombstone.provider() != verified.provider {
+                    return Err(Status::invalid_argument("Action is for another provider."));
+                }
+                if !keys.is_current(
+                    &verified.public_key,
+                    &[KeyPurpose::IdentityKey, KeyPurpose::RecoveryKey],
+                ) {
+                    return Err(Status::permission_denied(
+                        "Signer may not delete this provider.",
+                    ));
+                }
+                // With no current keys left, nothing can vouch for new ones.
+                keys.revoked.extend(keys.keys.keys().cloned());
+            }
         }
+        self.messages.push(message);
+        Ok(())
     }
 }
 
+/// Cloning shares the ledger.
+#[derive(Clone, Debug, Default)]
+pub struct InMemoryGossamer {
+    ledger: Arc<Mutex<Ledger>>,
+}
+
 #[tonic::async_trait]
 impl Gossamer for InMemoryGossamer {
     async fn perform(
         &self,
         request: Request<ActionRequest>,
     ) -> Result<Response<ActionResponse>, Status> {
-        if let Some(message) = request.into_inner().message {
-            // TODO verify signature and parse contents.
-            self.messages.lock().unwrap().push(message);
-            Ok(Response::new(ActionResponse {}))
-        } else {
-            Err(Status::invalid_argument("Empty Gossamer Action."))
+        let message = request
+            .into_inner()
+            .message
+            .ok_or(Status::invalid_argument("Empty Gossamer Action."))?;
+        let verified = message.clone().try_into()?;
+        self.ledger.lock().unwrap().apply(message, verified)?;
+        Ok(Response::new(ActionResponse {}))
+    }
+
+    async fn revoke_key(
+        &self,
+        request: Request<RevokeKeyRequest>,
+    ) -> Result<Response<RevokeKeyResponse>, Status> {
+        let message = request
+            .into_inner()
+            .message
+            .ok_or(Status::invalid_argument("Empty Gossamer Action."))?;
+        let verified: proto::SignedMessage = message.clone().try_into()?;
+        if !matches!(verified.message.action, Some(Action::RevokeKey(_))) {
+            return Err(Status::invalid_argument("Action is not a revocation."));
+        }
+        self.ledger.lock().unwrap().apply(message, verified)?;
+        Ok(Response::new(RevokeKeyResponse {}))
+    }
+
+    async fn get_ledger(
+        &self,
+        request: Request<GetLedgerRequest>,
+    ) -> Result<Response<GetLedgerResponse>, Status> {
+        let request = request.into_inner();
+        let messages = self
+            .ledger
+            .lock()
+            .unwrap()
+            .messages
+            .iter()
+            .filter(|message| message.provider() == request.provider())
+            .cloned()
+            .collect();
+        Ok(Response::new(GetLedgerResponse { messages }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::gossamer::*;
+    use anyhow::Result;
+    use chacha20poly1305::aead::OsRng;
+    use ed25519_dalek::SigningKey;
+    use proto::gossamer::{AppendKey, RevokeKey, Tombstone};
+    use proto::sign_action;
+    use tonic::Code;
+
+    fn append(
+        signer: &SigningKey,
+        key: &SigningKey,
+        purpose: KeyPurpose,
+    ) -> Request<ActionRequest> {
+        let mut action = AppendKey {
+            provider: Some(String::from("bob")),
+            public_key: Some(key.verifying_key().to_bytes().to_vec()),
+            ..Default::default()
+        };
+        action.set_key_purpose(purpose);
+        Request::new(ActionRequest {
+            message: Some(sign_action(
+                signer,
+                String::from("bob"),
+                Action::AppendKey(action),
+            )),
+        })
+    }
+
+    fn revoke(signer: &SigningKey, key: &SigningKey) -> Request<RevokeKeyRequest> {
+        let action = RevokeKey {
+            provider: Some(String::from("bob")),
+            public_key: Some(key.verifying_key().to_bytes().to_vec()),
+        };
+        Request::new(RevokeKeyRequest {
+            message: Some(sign_action(
+                signer,
+                String::from("bob"),
+                Action::RevokeKey(action),
+            )),
+        })
+    }
+
+    fn tombstone(signer: &SigningKey) -> Request<ActionRequest> {
+        Request::new(ActionRequest {
+            message: Some(sign_action(
+                signer,
+                String::from("bob"),
+                Action::Tombstone(Tombstone {
+                    provider: Some(String::from("bob")),
+                }),
+            )),
+        })
+    }
+
+    async fn ledger_len(gossamer: &InMemoryGossamer) -> Result<usize> {
+        Ok(gossamer
+            .get_ledger(Request::new(GetLedgerRequest {
+                provider: Some(String::from("bob")),
+            }))
+            .await?
+            .into_inner()
+            .messages
+            .len())
+    }
+
+    #[tokio::test]
+    async fn first_key_must_sign_itself() -> Result<()> {
+        let gossamer = InMemoryGossamer::default();
+        let ik = SigningKey::generate(&mut OsRng);
+        let other = SigningKey::generate(&mut OsRng);
+        assert_eq!(
+            gossamer
+                .perform(append(&other, &ik, KeyPurpose::IdentityKey))
+                .await
+                .err()
+                .map(|e| e.code()),
+            Some(Code::PermissionDenied)
+        );
+        gossamer
+            .perform(append(&ik, &ik, KeyPurpose::IdentityKey))
+            .await?;
+        assert_eq!(ledger_len(&gossamer).await?, 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn revoke_after_rotation() -> Result<()> {
+        let gossamer = InMemoryGossamer::default();
+        let old_ik = SigningKey::generate(&mut OsRng);
+        let new_ik = SigningKey::generate(&mut OsRng);
+        gossamer
+            .perform(append(&old_ik, &old_ik, KeyPurpose::IdentityKey))
+            .await?;
+        gossamer
+            .perform(append(&old_ik, &new_ik, KeyPurpose::IdentityKey))
+            .await?;
+        gossamer.revoke_key(revoke(&old_ik, &old_ik)).await?;
+
+        let next_ik = SigningKey::generate(&mut OsRng);
+        assert_eq!(
+            gossamer
+                .perform(append(&old_ik, &next_ik, KeyPurpose::IdentityKey))
+                .await
+                .err()
+                .map(|e| e.code()),
+            Some(Code::PermissionDenied)
+        );
+        gossamer
+            .perform(append(&new_ik, &next_ik, KeyPurpose::IdentityKey))
+            .await?;
+        assert_eq!(ledger_len(&gossamer).await?, 4);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn revoke_before_rotation_requires_recovery_key() -> Result<()> {
+        let gossamer = InMemoryGossamer::default();
+        let old_ik = SigningKey::generate(&mut OsRng);
+        let recovery_key = SigningKey::generate(&mut OsRng);
+        gossamer
+            .perform(append(&old_ik, &old_ik, KeyPurpose::IdentityKey))
+            .await?;
+        gossamer
+            .perform(append(&old_ik, &recovery_key, KeyPurpose::RecoveryKey))
+            .await?;
+        gossamer.revoke_key(revoke(&recovery_key, &old_ik)).await?;
+
+        let new_ik = SigningKey::generate(&mut OsRng);
+        assert_eq!(
+            gossamer
+                .perform(append(&old_ik, &new_ik, KeyPurpose::IdentityKey))
+                .await
+                .err()
+                .map(|e| e.code()),
+            Some(Code::PermissionDenied)
+        );
+        gossamer
+            .perform(append(&recovery_key, &new_ik, KeyPurpose::IdentityKey))
+            .await?;
+        assert_eq!(
+            gossamer
+                .perform(append(&new_ik, &old_ik, KeyPurpose::IdentityKey))
+                .await
+                .err()
+                .map(|e| e.code()),
+            Some(Code::FailedPrecondition)
+        );
+        assert_eq!(
+            gossamer
+                .revoke_key(revoke(&recovery_key, &old_ik))
+                .await
+                .err()
+                .map(|e| e.code()),
+            Some(Code::FailedPrecondition)
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn only_key_or_recovery_key_may_revoke() -> Result<()> {
+        let gossamer = InMemoryGossamer::default();
+        let ik = SigningKey::generate(&mut OsRng);
+        let other_ik = SigningKey::generate(&mut OsRng);
+        gossamer
+            .perform(append(&ik, &ik, KeyPurpose::IdentityKey))
+            .await?;
+        gossamer
+            .perform(append(&ik, &other_ik, KeyPurpose::IdentityKey))
+            .await?;
+        assert_eq!(
+            gossamer
+                .revoke_key(revoke(&other_ik, &ik))
+                .await
+                .err()
+                .map(|e| e.code()),
+            Some(Code::PermissionDenied)
+        );
+        assert_eq!(
+            gossamer
+                .revoke_key(revoke(&ik, &SigningKey::generate(&mut OsRng)))
+                .await
+                .err()
+                .map(|e| e.code()),
+            Some(Code::NotFound)
+        );
+        assert_eq!(ledger_len(&gossamer).await?, 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tombstone_revokes_every_key() -> Result<()> {
+        let gossamer = InMemoryGossamer::default();
+        let ik = SigningKey::generate(&mut OsRng);
+        let other_ik = SigningKey::generate(&mut OsRng);
+        let stranger = SigningKey::generate(&mut OsRng);
+        assert_eq!(
+            gossamer.perform(tombstone(&ik)).await.unwrap_err().code(),
+            Code::PermissionDenied
+        );
+        gossamer
+            .perform(append(&ik, &ik, KeyPurpose::IdentityKey))
+            .await?;
+        gossamer
+            .perform(append(&ik, &other_ik, KeyPurpose::IdentityKey))
+            .await?;
+        assert_eq!(
+            gossamer
+                .perform(tombstone(&stranger))
+                .await
+                .unwrap_err()
+                .code(),
+            Code::PermissionDenied
+        );
+        gossamer.perform(tombstone(&ik)).await?;
+        assert_eq!(ledger_len(&gossamer).await?, 3);
+
+        // Neither key can act for bob any more, nor can a new one be added.
+        for signer in [&ik, &other_ik] {
+            assert_eq!(
+                gossamer
+                    .perform(append(signer, &stranger, KeyPurpose::IdentityKey))
+                    .await
+                    .unwrap_err()
+                    .code(),
+                Code::PermissionDenied
+            );
         }
+        assert_eq!(
+            gossamer
+                .perform(append(&stranger, &stranger, KeyPurpose::IdentityKey))
+                .await
+                .unwrap_err()
+                .code(),
+            Code::PermissionDenied
+        );
+        Ok(())
     }
 }
@@ -0,0 +1,485 @@
+//! Limits how often each client address may make the requests anyone can make without having
+//! registered: registering, fetching pre keys and asking for challenges. The limits apply before
+//! requests are decoded, so a flood of them is turned away cheaply.
+
+use crate::brongnal::BrongnalController;
+use crate::rate_limit::{throttled, RateLimit, RateLimiter};
+use futures::future::{self, Either, Ready};
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tonic::body::BoxBody;
+use tonic::codegen::http::{HeaderMap, Request, Response};
+use tonic::codegen::Service;
+use tonic::transport::server::TcpConnectInfo;
+use tonic::Status;
+use tower::Layer;
+
+/// The header reverse proxies append the address they received a request from to.
+pub const FORWARDED_FOR_HEADER: &str = "x-forwarded-for";
+
+/// The requests limited per address, each class with an allowance of its own.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RpcClass {
+    /// RegisterPreKeyBundle.
+    Registration,
+    /// RequestPreKeys, each of which may use up one of every device's one time keys.
+    PreKeys,
+    /// GetRegistrationChallenge and GetAccountDeletionNonce.
+    Challenge,
+}
+
+impl RpcClass {
+    /// The class of the request to gRPC `path`, if its class is limited.
+    pub fn of(path: &str) -> Option<RpcClass> {
+        match path.strip_prefix("/service.Brongnal/")? {
+            "RegisterPreKeyBundle" => Some(RpcClass::Registration),
+            "RequestPreKeys" => Some(RpcClass::PreKeys),
+            "GetRegistrationChallenge" | "GetAccountDeletionNonce" => Some(RpcClass::Challenge),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for RpcClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            RpcClass::Registration => "registration",
+            RpcClass::PreKeys => "pre key",
+            RpcClass::Challenge => "challenge",
+        })
+    }
+}
+
+/// A token bucket per client address for each `RpcClass`, and a count of the requests each has
+/// turned away.
+#[derive(Debug)]
+pub struct IpRateLimits {
+    limiters: [RateLimiter; 3],
+    throttled: [AtomicU64; 3],
+    trusted_proxies: usize,
+}
+
+impl IpRateLimits {
+    /// Limits that admit every request, taking each client's address from the connection.
+    pub fn unlimited() -> Self {
+        IpRateLimits {
+            limiters: [
+                RateLimiter::unlimited(),
+                RateLimiter::unlimited(),
+                RateLimiter::unlimited(),
+            ],
+            throttled: Default::default(),
+            trusted_proxies: 0,
+        }
+    }
+
+    /// Limits each address's requests of `class` to `limit`.
+    pub fn with_limit(mut self, class: RpcClass, limit: RateLimit) -> Self {
+        self.limiters[class as usize] = RateLimiter::new(limit);
+        self
+    }
+
+    /// Trusts the last `depth` entries of X-Forwarded-For, for a server behind that many
+    /// reverse proxies, and takes the client's address from the last of them.
+    pub fn with_trusted_proxies(mut self, depth: usize) -> Self {
+        self.trusted_proxies = depth;
+        self
+    }
+
+    /// The address of the client that made a request with `headers` over a connection from
+    /// `remote`. Behind proxies, that is the address the outermost one received the request
+    /// from; requests that skipped it, and so carry too few addresses, are held to the
+    /// connection's.
+    pub fn client_ip(&self, remote: Option<IpAddr>, headers: &HeaderMap) -> Option<IpAddr> {
+        if self.trusted_proxies == 0 {
+            return remote;
+        }
+        let forwarded: Vec<&str> = headers
+            .get_all(FORWARDED_FOR_HEADER)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .collect();
+        forwarded
+            .len()
+            .checked_sub(self.trusted_proxies)
+            .and_then(|i| forwarded[i].parse().ok())
+            .or(remote)
+    }
+
+    /// Takes one of `ip`'s tokens for `class`, failing with ResourceExhausted if it has none.
+    pub fn check(&self, class: RpcClass, ip: IpAddr) -> Result<(), Status> {
+        self.limiters[class as usize]
+            .check(&ip.to_string())
+            .map_err(|retry_after| {
+                self.throttled[class as usize].fetch_add(1, Ordering::Relaxed);
+                throttled(
+                    format!("too many {class} requests from {ip}, retry after {retry_after:?}"),
+                    retry_after,
+                )
+            })
+    }
+
+    /// How many requests of `class` have been turned away since the server started.
+    pub fn throttled(&self, class: RpcClass) -> u64 {
+        self.throttled[class as usize].load(Ordering::Relaxed)
+    }
+
+    /// Forgets addresses whose buckets have refilled.
+    pub fn evict_idle(&self) {
+        for limiter in &self.limiters {
+            limiter.evict_idle();
+        }
+    }
+}
+
+/// Wraps services in the controller's `IpRateLimits`.
+#[derive(Clone, Debug)]
+pub struct IpRateLimitLayer {
+    controller: Arc<BrongnalController>,
+}
+
+impl IpRateLimitLayer {
+    pub fn new(controller: Arc<BrongnalController>) -> Self {
+        IpRateLimitLayer { controller }
+    }
+}
+
+impl<S> Layer<S> for IpRateLimitLayer {
+    type Service = IpRateLimit<S>;
+
+    fn layer(&self, inner: S) -> IpRateLimit<S> {
+        IpRateLimit {
+            inner,
+            controller: self.controller.clone(),
+        }
+    }
+}
+
+/// Answers requests over their address's limit with ResourceExhausted, passing the rest on.
+#[derive(Clone, Debug)]
+pub struct IpRateLimit<S> {
+    inner: S,
+    controller: Arc<BrongnalController>,
+}
+
+impl<S> IpRateLimit<S> {
+    fn check<B>(&self, request: &Request<B>) -> Result<(), Status> {
+        let Some(class) = RpcClass::of(request.uri().path()) else {
+            return Ok(());
+        };
+        // Peers relay requests from all of their users, and are trusted to limit them.
+        if let Some(federation) = self.controller.federation() {
+            if federation.is_peer(request.headers()) {
+                return Ok(());
+            }
+        }
+        let limits = self.controller.ip_limits();
+        let remote = request
+            .extensions()
+            .get::<TcpConnectInfo>()
+            .and_then(TcpConnectInfo::remote_addr)
+            .map(|addr| addr.ip());
+        // Unix domain sockets have no address unless a proxy forwarded one.
+        match limits.client_ip(remote, request.headers()) {
+            Some(ip) => limits.check(class, ip),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<S, B> Service<Request<B>> for IpRateLimit<S>
+where
+    S: Service<Request<B>, Response = Response<BoxBody>>,
+{
+    type Response = Response<BoxBody>;
+    type Error = S::Error;
+    type Future = Either<Ready<Result<Response<BoxBody>, S::Error>>, S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), S::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<B>) -> Self::Future {
+        match self.check(&request) {
+            Ok(()) => Either::Right(self.inner.call(request)),
+            Err(status) => Either::Left(future::ready(Ok(status.to_http()))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::federation::{Federation, FederationPeer, DOMAIN_HEADER, SECRET_HEADER};
+    use crate::memory_brongnal::MemoryStorage;
+    use std::convert::Infallible;
+    use std::time::Duration;
+    use tonic::transport::Endpoint;
+    use tonic::Code;
+
+    const REGISTER: &str = "/service.Brongnal/RegisterPreKeyBundle";
+    const REQUEST_PRE_KEYS: &str = "/service.Brongnal/RequestPreKeys";
+    const CHALLENGE: &str = "/service.Brongnal/GetRegistrationChallenge";
+    const SEND: &str = "/service.Brongnal/SendMessage";
+    const LIMIT: RateLimit = RateLimit {
+        rate: 1.0,
+        burst: 2,
+    };
+
+    /// Answers every request with an empty response.
+    #[derive(Clone)]
+    struct Answer;
+
+    impl Service<Request<()>> for Answer {
+        type Response = Response<BoxBody>;
+        type Error = Infallible;
+        type Future = Ready<Result<Response<BoxBody>, Infallible>>;
+
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, _request: Request<()>) -> Self::Future {
+            future::ready(Ok(Response::new(tonic::body::empty_body())))
+        }
+    }
+
+    fn service(controller: BrongnalController) -> IpRateLimit<Answer> {
+        IpRateLimitLayer::new(Arc::new(controller)).layer(Answer)
+    }
+
+    /// The status of a request to `path` over a connection from `remote`, with `headers`.
+    async fn call_with(
+        service: &mut IpRateLimit<Answer>,
+        path: &str,
+        remote: Option<&str>,
+        headers: &[(&str, &str)],
+    ) -> Status {
+        let mut request = Request::builder().uri(path);
+        if let Some(remote) = remote {
+            request = request.extension(TcpConnectInfo {
+                local_addr: None,
+                remote_addr: Some(remote.parse().unwrap()),
+            });
+        }
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        let response = service.call(request.body(()).unwrap()).await.unwrap();
+        Status::from_header_map(response.headers()).unwrap_or_else(|| Status::ok(""))
+    }
+
+    async fn call(service: &mut IpRateLimit<Answer>, path: &str, remote: &str) -> Code {
+        call_with(service, path, Some(remote), &[]).await.code()
+    }
+
+    #[test]
+    fn classes() {
+        assert_eq!(RpcClass::of(REGISTER), Some(RpcClass::Registration));
+        assert_eq!(RpcClass::of(REQUEST_PRE_KEYS), Some(RpcClass::PreKeys));
+        assert_eq!(RpcClass::of(CHALLENGE), Some(RpcClass::Challenge));
+        assert_eq!(
+            RpcClass::of("/service.Brongnal/GetAccountDeletionNonce"),
+            Some(RpcClass::Challenge)
+        );
+        assert_eq!(RpcClass::of(SEND), None);
+        assert_eq!(RpcClass::of("/gossamer.Gossamer/RequestPreKeys"), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn limits_each_class_per_address() {
+        let mut service = service(
+            BrongnalController::new(Box::new(MemoryStorage::default())).with_ip_limits(
+                IpRateLimits::unlimited()
+                    .with_limit(RpcClass::Registration, LIMIT)
+                    .with_limit(RpcClass::PreKeys, LIMIT),
+            ),
+        );
+        for _ in 0..2 {
+            assert_eq!(
+                call(&mut service, REGISTER, "192.0.2.1:1000").await,
+                Code::Ok
+            );
+        }
+        // Clients are told how long to wait, whichever port they connect from.
+        let status = call_with(&mut service, REGISTER, Some("192.0.2.1:2000"), &[]).await;
+        assert_eq!(status.code(), Code::ResourceExhausted);
+        assert_eq!(
+            status.message(),
+            "too many registration requests from 192.0.2.1, retry after 1s"
+        );
+        assert_eq!(status.metadata().get("retry-after").unwrap(), "1");
+
+        // Other addresses and classes have allowances of their own, and other requests and
+        // unix domain sockets aren't limited.
+        assert_eq!(
+            call(&mut service, REGISTER, "192.0.2.2:1000").await,
+            Code::Ok
+        );
+        assert_eq!(
+            call(&mut service, REGISTER, "[2001:db8::1]:1000").await,
+            Code::Ok
+        );
+        for _ in 0..2 {
+            assert_eq!(
+                call(&mut service, REQUEST_PRE_KEYS, "192.0.2.1:1000").await,
+                Code::Ok
+            );
+        }
+        for _ in 0..10 {
+            assert_eq!(
+                call(&mut service, CHALLENGE, "192.0.2.1:1000").await,
+                Code::Ok
+            );
+            assert_eq!(call(&mut service, SEND, "192.0.2.1:1000").await, Code::Ok);
+            assert_eq!(
+                call_with(&mut service, REGISTER, None, &[]).await.code(),
+                Code::Ok
+            );
+        }
+        assert_eq!(
+            call(&mut service, REQUEST_PRE_KEYS, "192.0.2.1:1000").await,
+            Code::ResourceExhausted
+        );
+
+        let limits = service.controller.ip_limits();
+        assert_eq!(limits.throttled(RpcClass::Registration), 1);
+        assert_eq!(limits.throttled(RpcClass::PreKeys), 1);
+        assert_eq!(limits.throttled(RpcClass::Challenge), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn allowances_refill() {
+        let mut service = service(
+            BrongnalController::new(Box::new(MemoryStorage::default()))
+                .with_ip_limits(IpRateLimits::unlimited().with_limit(RpcClass::Challenge, LIMIT)),
+        );
+        for _ in 0..2 {
+            assert_eq!(
+                call(&mut service, CHALLENGE, "192.0.2.1:1000").await,
+                Code::Ok
+            );
+        }
+        assert_eq!(
+            call(&mut service, CHALLENGE, "192.0.2.1:1000").await,
+            Code::ResourceExhausted
+        );
+
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert_eq!(
+            call(&mut service, CHALLENGE, "192.0.2.1:1000").await,
+            Code::Ok
+        );
+        assert_eq!(
+            call(&mut service, CHALLENGE, "192.0.2.1:1000").await,
+            Code::ResourceExhausted
+        );
+
+        tokio::time::advance(Duration::from_secs(2)).await;
+        service.controller.evict_idle_rate_limits();
+        for _ in 0..2 {
+            assert_eq!(
+                call(&mut service, CHALLENGE, "192.0.2.1:1000").await,
+                Code::Ok
+            );
+        }
+    }
+
+    #[test]
+    fn forwarded_addresses() {
+        let headers = |values: &[&str]| {
+            let mut headers = HeaderMap::new();
+            for value in values {
+                headers.append(FORWARDED_FOR_HEADER, value.parse().unwrap());
+            }
+            headers
+        };
+        let proxy = Some("10.0.0.1".parse().unwrap());
+        let ip = |ip: &str| Some(ip.parse().unwrap());
+
+        // Without trusted proxies, the header is the client's to forge.
+        let direct = IpRateLimits::unlimited();
+        assert_eq!(direct.client_ip(proxy, &headers(&["192.0.2.1"])), proxy);
+
+        let one = IpRateLimits::unlimited().with_trusted_proxies(1);
+        assert_eq!(
+            one.client_ip(proxy, &headers(&["192.0.2.1"])),
+            ip("192.0.2.1")
+        );
+        // Addresses the client put before the proxy's are ignored.
+        assert_eq!(
+            one.client_ip(proxy, &headers(&["198.51.100.1, 192.0.2.1"])),
+            ip("192.0.2.1")
+        );
+        assert_eq!(
+            one.client_ip(proxy, &headers(&["198.51.100.1", "2001:db8::1"])),
+            ip("2001:db8::1")
+        );
+        assert_eq!(one.client_ip(proxy, &headers(&[])), proxy);
+        assert_eq!(one.client_ip(proxy, &headers(&["unknown"])), proxy);
+        assert_eq!(
+            one.client_ip(None, &headers(&["192.0.2.1"])),
+            ip("192.0.2.1")
+        );
+
+        let two = IpRateLimits::unlimited().with_trusted_proxies(2);
+        assert_eq!(
+            two.client_ip(proxy, &headers(&["198.51.100.1, 192.0.2.1, 10.0.0.2"])),
+            ip("192.0.2.1")
+        );
+        assert_eq!(two.client_ip(proxy, &headers(&["192.0.2.1"])), proxy);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn limits_forwarded_clients() {
+        let channel = Endpoint::from_static("http://[::1]:1").connect_lazy();
+        let mut service = service(
+            BrongnalController::new(Box::new(MemoryStorage::default()))
+                .with_ip_limits(
+                    IpRateLimits::unlimited()
+                        .with_limit(RpcClass::PreKeys, LIMIT)
+                        .with_trusted_proxies(1),
+                )
+                .with_federation(
+                    Federation::new("a.example")
+                        .with_peer("b.example", FederationPeer::new(channel, "s3cret")),
+                ),
+        );
+        let proxy = Some("10.0.0.1:1000");
+        let from = |client| [(FORWARDED_FOR_HEADER, client)];
+        for _ in 0..2 {
+            let status = call_with(&mut service, REQUEST_PRE_KEYS, proxy, &from("192.0.2.1")).await;
+            assert_eq!(status.code(), Code::Ok);
+        }
+        let status = call_with(&mut service, REQUEST_PRE_KEYS, proxy, &from("192.0.2.1")).await;
+        assert_eq!(status.code(), Code::ResourceExhausted);
+        // Forging an address in front of the proxy's doesn't help.
+        let forged = from("198.51.100.1, 192.0.2.1");
+        let status = call_with(&mut service, REQUEST_PRE_KEYS, proxy, &forged).await;
+        assert_eq!(status.code(), Code::ResourceExhausted);
+        // Clients behind the same proxy have allowances of their own.
+        let status = call_with(&mut service, REQUEST_PRE_KEYS, proxy, &from("192.0.2.2")).await;
+        assert_eq!(status.code(), Code::Ok);
+
+        // Peers relay requests for all of their users.
+        let peer = [
+            (FORWARDED_FOR_HEADER, "192.0.2.1"),
+            (DOMAIN_HEADER, "b.example"),
+            (SECRET_HEADER, "s3cret"),
+        ];
+        let status = call_with(&mut service, REQUEST_PRE_KEYS, proxy, &peer).await;
+        assert_eq!(status.code(), Code::Ok);
+        let impostor = [
+            (FORWARDED_FOR_HEADER, "192.0.2.1"),
+            (DOMAIN_HEADER, "b.example"),
+            (SECRET_HEADER, "guess"),
+        ];
+        let status = call_with(&mut service, REQUEST_PRE_KEYS, proxy, &impostor).await;
+        assert_eq!(status.code(), Code::ResourceExhausted);
+    }
+}
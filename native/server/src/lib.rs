@@ -0,0 +1,17 @@
+pub mod admin;
+pub mod at_rest;
+pub mod brongnal;
+pub mod config;
+pub mod federation;
+pub mod gossamer;
+pub mod ip_limit;
+pub mod memory_brongnal;
+pub mod push;
+pub mod rate_limit;
+pub mod request_log;
+pub mod sqlite_brongnal;
+#[cfg(test)]
+mod storage_tests;
+pub mod timeout;
+pub mod uds;
+pub mod web;
@@ -1,42 +1,257 @@
-use crate::gossamer::InMemoryGossamer;
-use brongnal::BrongnalController;
+use clap::Parser;
+use proto::admin::admin_server::AdminServer;
 use proto::gossamer::gossamer_server::GossamerServer;
 use proto::service::brongnal_server::BrongnalServer;
-use proto::FILE_DESCRIPTOR_SET;
-use rusqlite::Connection;
-use sqlite_brongnal::SqliteStorage;
-use std::net::{IpAddr, Ipv4Addr};
-use std::path::PathBuf;
-use tonic::transport::Server;
+use proto::{FILE_DESCRIPTOR_SET, KEEPALIVE_INTERVAL, KEEPALIVE_TIMEOUT};
+use server::admin::{AdminAuth, AdminService};
+use server::at_rest::AtRestKey;
+use server::brongnal::BrongnalController;
+use server::config::ServerConfig;
+use server::federation::{parse_peers, Federation, FederationPeer};
+use server::gossamer::InMemoryGossamer;
+use server::ip_limit::IpRateLimitLayer;
+use server::request_log::RequestLogLayer;
+use server::sqlite_brongnal::SqliteStorage;
+use server::timeout::RpcTimeoutLayer;
+use server::uds;
+use server::web;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::oneshot;
+use tokio_rusqlite::Connection;
+use tonic::transport::{Endpoint, Server};
 use tonic_reflection::server::Builder;
+use tonic_web::GrpcWebLayer;
+use tracing_subscriber::EnvFilter;
 
-mod brongnal;
-mod gossamer;
-mod memory_brongnal;
-mod sqlite_brongnal;
+/// Resolves once the process receives SIGINT or SIGTERM.
+async fn shutdown_signal() {
+    let mut terminate = signal(SignalKind::terminate()).expect("failed to install SIGTERM handler");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => println!("Received SIGINT."),
+        _ = terminate.recv() => println!("Received SIGTERM."),
+    }
+}
+
+/// Reports the Admin service stopping, which leaves the rest of the server running.
+async fn log_admin_exit(serve: impl Future<Output = Result<(), tonic::transport::Error>>) {
+    if let Err(e) = serve.await {
+        eprintln!("Admin service stopped: {e}");
+    }
+}
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // RUST_LOG=debug also logs each storage operation.
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+        )
+        .init();
     let reflection_service = Builder::configure()
         .register_encoded_file_descriptor_set(FILE_DESCRIPTOR_SET)
         .build()
         .unwrap();
-    let server_addr = (IpAddr::V4(Ipv4Addr::UNSPECIFIED), 8080).into();
+    let config = ServerConfig::parse();
+    config.check_admin()?;
+    let db_path = config.db_path();
+    println!("Database Path: {}", db_path.display());
+    let connection = Connection::open(db_path).await?;
+    let gossamer = InMemoryGossamer::default();
+    let storage = match &config.db_key_file {
+        Some(path) => SqliteStorage::new_encrypted(connection, AtRestKey::from_file(path)?).await?,
+        None => SqliteStorage::new(connection).await?,
+    };
+    let controller = BrongnalController::new(Box::new(
+        storage
+            .with_readers(config.read_connections)
+            .await?
+            .with_mailbox_quota(config.mailbox_quota()),
+    ))
+    .with_send_limit(config.send_limit())
+    .with_ip_limits(config.ip_limits())
+    .with_opk_quota(config.opk_quota())
+    .with_registration_difficulty(config.registration_difficulty)
+    .with_invites_required(config.invite_only)
+    .with_gossamer(gossamer.clone());
+    let controller = match config.max_ciphertext_bytes {
+        Some(max_len) => controller.with_max_ciphertext_len(max_len),
+        None => controller,
+    };
+    let controller = match &config.domain {
+        Some(domain) => {
+            let mut federation = Federation::new(domain);
+            if let Some(path) = &config.federation_peers {
+                for (domain, url, secret) in parse_peers(&std::fs::read_to_string(path)?)? {
+                    let channel = Endpoint::from_shared(url)?
+                        .http2_keep_alive_interval(KEEPALIVE_INTERVAL)
+                        .keep_alive_timeout(KEEPALIVE_TIMEOUT)
+                        .keep_alive_while_idle(true)
+                        .connect_lazy();
+                    println!("Federating with {domain}.");
+                    federation = federation.with_peer(domain, FederationPeer::new(channel, secret));
+                }
+            }
+            controller.with_federation(federation)
+        }
+        None => controller,
+    };
+    #[cfg(not(feature = "fcm"))]
+    if config.fcm_key.is_some() {
+        return Err("--fcm-key requires building with --features fcm".into());
+    }
+    #[cfg(feature = "fcm")]
+    let controller = match &config.fcm_key {
+        Some(path) => controller.with_push_dispatcher(
+            server::push::PushPlatform::Fcm,
+            Arc::new(server::push::FcmDispatcher::from_service_account_key(path)?),
+        ),
+        None => controller,
+    };
+    #[cfg(not(feature = "apns"))]
+    if config.apns_key.is_some() {
+        return Err("--apns-key requires building with --features apns".into());
+    }
+    #[cfg(feature = "apns")]
+    let controller = match &config.apns_key {
+        Some(key_path) => {
+            // Clap requires the others alongside --apns-key.
+            let apns_config = server::push::ApnsConfig {
+                key_id: config.apns_key_id.clone().unwrap_or_default(),
+                team_id: config.apns_team_id.clone().unwrap_or_default(),
+                key_path: key_path.clone(),
+                topic: config.apns_topic.clone().unwrap_or_default(),
+                endpoint: String::from(if config.apns_sandbox {
+                    server::push::APNS_SANDBOX
+                } else {
+                    server::push::APNS_PRODUCTION
+                }),
+            };
+            controller.with_push_dispatcher(
+                server::push::PushPlatform::Apns,
+                Arc::new(server::push::ApnsDispatcher::new(apns_config)?),
+            )
+        }
+        None => controller,
+    };
+    let controller = Arc::new(controller);
 
-    println!("Brongnal Server listening at: {server_addr}");
+    // Stopped before the database is closed.
+    let mut tasks = vec![
+        controller
+            .clone()
+            .spawn_retention_task(config.retention_policy(), Duration::from_secs(60 * 60)),
+        controller
+            .clone()
+            .spawn_stats_task(Duration::from_secs(60 * 60)),
+        controller
+            .clone()
+            .spawn_maintenance_task(config.maintenance_policy()),
+    ];
+    {
+        let controller = controller.clone();
+        tasks.push(tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(60));
+            loop {
+                interval.tick().await;
+                controller.evict_idle_rate_limits();
+            }
+        }));
+    }
 
-    let db_dir = std::env::var("DB").unwrap_or(String::from("db"));
-    let db_path: PathBuf = [&db_dir, "brongnal.db3"].iter().collect();
-    println!("Database Path: {}", db_path.display());
-    let connection = Connection::open(db_path)?;
-    let controller = BrongnalController::new(Box::new(SqliteStorage::new(connection)?));
+    let admin = Server::builder().add_service(AdminServer::with_interceptor(
+        AdminService::new(controller.clone()),
+        AdminAuth::new(config.admin_token.clone()),
+    ));
+    let _admin_cleanup = match (&config.admin_addr, &config.admin_uds) {
+        (Some(admin_addr), _) => {
+            println!("Admin service listening at: {admin_addr}");
+            tasks.push(tokio::spawn(log_admin_exit(admin.serve(*admin_addr))));
+            None
+        }
+        (None, Some(admin_path)) => {
+            let (incoming, cleanup) = uds::bind(admin_path)?;
+            println!("Admin service listening at: {}", admin_path.display());
+            tasks.push(tokio::spawn(log_admin_exit(
+                admin.serve_with_incoming(incoming),
+            )));
+            Some(cleanup)
+        }
+        (None, None) => None,
+    };
+
+    let router = Server::builder()
+        .http2_keepalive_interval(Some(KEEPALIVE_INTERVAL))
+        .http2_keepalive_timeout(Some(KEEPALIVE_TIMEOUT))
+        // Browsers speak gRPC-Web over HTTP/1.1.
+        .accept_http1(true)
+        .layer(web::cors(&config.web_origins)?)
+        .layer(GrpcWebLayer::new())
+        .layer(RequestLogLayer::new(config.request_log_sample_rate))
+        .layer(IpRateLimitLayer::new(controller.clone()))
+        .layer(RpcTimeoutLayer::new(config.rpc_timeouts()))
+        .add_service(
+            BrongnalServer::from_arc(controller.clone())
+                .max_decoding_message_size(controller.max_request_len()),
+        )
+        .add_service(GossamerServer::new(gossamer))
+        .add_service(reflection_service);
+
+    let (drain_tx, drain_rx) = oneshot::channel();
+    let shutdown = {
+        let controller = controller.clone();
+        async move {
+            shutdown_signal().await;
+            println!("Shutting down, draining connections.");
+            controller.drain();
+            let _ = drain_tx.send(());
+        }
+    };
+
+    type ServeFuture = Pin<Box<dyn Future<Output = Result<(), tonic::transport::Error>>>>;
+    let (serve, _cleanup): (ServeFuture, _) = match &config.uds {
+        Some(uds_path) => {
+            let (incoming, cleanup) = uds::bind(uds_path)?;
+            println!("Brongnal Server listening at: {}", uds_path.display());
+            (
+                Box::pin(router.serve_with_incoming_shutdown(incoming, shutdown)),
+                Some(cleanup),
+            )
+        }
+        None => {
+            let server_addr = config.socket_addr();
+            println!("Brongnal Server listening at: {server_addr}");
+            (
+                Box::pin(router.serve_with_shutdown(server_addr, shutdown)),
+                None,
+            )
+        }
+    };
 
-    Server::builder()
-        .add_service(BrongnalServer::new(controller))
-        .add_service(GossamerServer::new(InMemoryGossamer::default()))
-        .add_service(reflection_service)
-        .serve(server_addr)
-        .await?;
+    let grace_period = config.grace_period();
+    tokio::select! {
+        result = serve => result?,
+        _ = async {
+            if drain_rx.await.is_ok() {
+                println!("Waiting up to {grace_period:?} for in-flight requests.");
+                tokio::time::sleep(grace_period).await;
+            } else {
+                std::future::pending::<()>().await;
+            }
+        } => println!("Grace period elapsed with requests still in flight."),
+    }
 
+    for task in &tasks {
+        task.abort();
+    }
+    for task in tasks {
+        let _ = task.await;
+    }
+    println!("Closing database.");
+    controller.close().await?;
+    println!("Server stopped.");
     Ok(())
 }
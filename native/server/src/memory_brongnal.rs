@@ -1,119 +1,1025 @@
 use ed25519_dalek::VerifyingKey;
 use proto::service::Message as MessageProto;
 use proto::service::SignedPreKey as SignedPreKeyProto;
+use proto::Identity;
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashSet};
+use std::path::Path;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
 use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
 use std::{collections::HashMap, sync::Arc};
 use tonic::Status;
 use x25519_dalek::PublicKey as X25519PublicKey;
 
-use crate::brongnal::Storage;
+use crate::brongnal::{
+    AuditEntry, BackupProgress, Checkpoint, CurrentKeys, DeviceStats, ExpiredMessage, Invite,
+    InviteRejection, MailboxQuota, MessageClaim, OpkQuota, PushToken, QueuedMessage, QuotaPolicy,
+    Storage, StorageStats,
+};
+
+/// Queued messages for a recipient along with when they were enqueued.
+type Mailbox = Vec<(SystemTime, MessageProto)>;
+
+/// Unused one time keys for an identity along with their ids, oldest first.
+type OneTimeKeys = Vec<(u32, X25519PublicKey)>;
+
+/// One time keys handed out to senders along with their ids and when their reservations expire.
+type ReservedKeys = Vec<(u32, X25519PublicKey, SystemTime)>;
+
+/// A device's signed pre key along with its id and when it was uploaded.
+type StoredSpk = (u32, SystemTime, SignedPreKeyProto);
+
+/// A device's last-resort key, if it has one, along with the id it was last assigned.
+type LastResortKey = (u32, Option<X25519PublicKey>);
+
+/// Unused one time KEM keys for a device along with their ids, oldest first.
+type OneTimeKemKeys = Vec<(u32, SignedPreKeyProto)>;
+
+/// Like `LastResortKey`, but for the last-resort KEM key.
+type LastResortKemKey = (u32, Option<SignedPreKeyProto>);
+
+/// A recipient and the uuid a sender picked for a message to them.
+type MessageUuid = (String, Vec<u8>);
+
+/// The id of a message claimed under a uuid, along with when it was claimed.
+type ClaimedUuid = (u64, SystemTime);
+
+/// An identity and one of its device ids.
+type Device = (String, u32);
+
+fn device(identity: &str, device_id: u32) -> Device {
+    (identity.to_owned(), device_id)
+}
+
+/// The form each device registered its identity in, along with the identity's skeleton, and the
+/// devices registered under each skeleton.
+#[derive(Debug, Default)]
+struct Names {
+    display: HashMap<Device, (String, String)>,
+    by_skeleton: HashMap<String, HashSet<Device>>,
+}
+
+impl Names {
+    /// Records that `device` registered its identity as `display`, failing with `AlreadyExists`
+    /// if another device registered the identity, or one with the same `skeleton`, differently.
+    fn insert(&mut self, device: &Device, display: &str, skeleton: String) -> tonic::Result<()> {
+        let taken = self
+            .by_skeleton
+            .get(&skeleton)
+            .into_iter()
+            .flatten()
+            .filter(|registered| *registered != device)
+            .map(|registered| (registered, &self.display[registered].0))
+            .find(|(registered, registered_display)| {
+                registered.0 != device.0 || registered_display.as_str() != display
+            });
+        if let Some((_, taken)) = taken {
+            return Err(Status::already_exists(format!(
+                "{display} is too similar to the registered identity {taken}"
+            )));
+        }
+        self.remove(device);
+        self.by_skeleton
+            .entry(skeleton.clone())
+            .or_default()
+            .insert(device.clone());
+        self.display
+            .insert(device.clone(), (display.to_owned(), skeleton));
+        Ok(())
+    }
+
+    fn remove(&mut self, device: &Device) {
+        if let Some((_, skeleton)) = self.display.remove(device) {
+            if let Entry::Occupied(mut devices) = self.by_skeleton.entry(skeleton) {
+                devices.get_mut().remove(device);
+                if devices.get().is_empty() {
+                    devices.remove();
+                }
+            }
+        }
+    }
+}
 
 #[derive(Clone, Debug)]
 pub struct MemoryStorage {
-    iks: Arc<Mutex<HashMap<String, VerifyingKey>>>,
-    spks: Arc<Mutex<HashMap<String, SignedPreKeyProto>>>,
-    opks: Arc<Mutex<HashMap<String, Vec<X25519PublicKey>>>>,
-    messages: Arc<Mutex<HashMap<String, Vec<MessageProto>>>>,
+    iks: Arc<Mutex<HashMap<Device, VerifyingKey>>>,
+    registered_at: Arc<Mutex<HashMap<Device, SystemTime>>>,
+    names: Arc<Mutex<Names>>,
+    spks: Arc<Mutex<HashMap<Device, StoredSpk>>>,
+    opks: Arc<Mutex<HashMap<Device, OneTimeKeys>>>,
+    next_opk_id: Arc<AtomicU32>,
+    reserved_opks: Arc<Mutex<HashMap<Device, ReservedKeys>>>,
+    last_resort_keys: Arc<Mutex<HashMap<Device, LastResortKey>>>,
+    kem_opks: Arc<Mutex<HashMap<Device, OneTimeKemKeys>>>,
+    last_resort_kem_keys: Arc<Mutex<HashMap<Device, LastResortKemKey>>>,
+    cipher_suites: Arc<Mutex<HashMap<Device, u32>>>,
+    push_tokens: Arc<Mutex<HashMap<Device, PushToken>>>,
+    messages: Arc<Mutex<HashMap<Device, Mailbox>>>,
+    message_uuids: Arc<Mutex<HashMap<MessageUuid, ClaimedUuid>>>,
+    next_message_id: Arc<AtomicU64>,
+    audit_log: Arc<Mutex<Vec<AuditEntry>>>,
+    invites: Arc<Mutex<Vec<Invite>>>,
+    mailbox_quota: Option<MailboxQuota>,
+    latency: Duration,
 }
 
 impl Default for MemoryStorage {
     fn default() -> Self {
         MemoryStorage {
             iks: Arc::new(Mutex::new(HashMap::new())),
+            registered_at: Arc::new(Mutex::new(HashMap::new())),
+            names: Arc::new(Mutex::new(Names::default())),
             spks: Arc::new(Mutex::new(HashMap::new())),
             opks: Arc::new(Mutex::new(HashMap::new())),
+            next_opk_id: Arc::new(AtomicU32::new(1)),
+            reserved_opks: Arc::new(Mutex::new(HashMap::new())),
+            last_resort_keys: Arc::new(Mutex::new(HashMap::new())),
+            kem_opks: Arc::new(Mutex::new(HashMap::new())),
+            last_resort_kem_keys: Arc::new(Mutex::new(HashMap::new())),
+            cipher_suites: Arc::new(Mutex::new(HashMap::new())),
+            push_tokens: Arc::new(Mutex::new(HashMap::new())),
             messages: Arc::new(Mutex::new(HashMap::new())),
+            message_uuids: Arc::new(Mutex::new(HashMap::new())),
+            next_message_id: Arc::new(AtomicU64::new(1)),
+            audit_log: Arc::new(Mutex::new(Vec::new())),
+            invites: Arc::new(Mutex::new(Vec::new())),
+            mailbox_quota: None,
+            latency: Duration::ZERO,
+        }
+    }
+}
+
+impl MemoryStorage {
+    pub fn with_mailbox_quota(mut self, quota: MailboxQuota) -> Self {
+        self.mailbox_quota = Some(quota);
+        self
+    }
+
+    /// Delays every operation by `latency`, like a slow or contended database would.
+    pub fn with_latency(mut self, latency: Duration) -> Self {
+        self.latency = latency;
+        self
+    }
+
+    async fn stall(&self) {
+        if !self.latency.is_zero() {
+            tokio::time::sleep(self.latency).await;
+        }
+    }
+
+    /// Fails with `AlreadyExists` if `pre_keys` repeat a key, or one held by a device other
+    /// than `replacing`. Keys held by `replacing` are about to be discarded.
+    fn check_opks_unique(
+        &self,
+        opks: &HashMap<Device, OneTimeKeys>,
+        pre_keys: &[X25519PublicKey],
+        replacing: Option<&Device>,
+    ) -> tonic::Result<()> {
+        let reserved = self.reserved_opks.lock().unwrap();
+        let mut held: HashSet<&X25519PublicKey> = opks
+            .iter()
+            .filter(|(device, _)| Some(*device) != replacing)
+            .flat_map(|(_, keys)| keys.iter().map(|(_, key)| key))
+            .chain(
+                reserved
+                    .iter()
+                    .filter(|(device, _)| Some(*device) != replacing)
+                    .flat_map(|(_, keys)| keys.iter().map(|(_, key, _)| key)),
+            )
+            .collect();
+        if pre_keys.iter().all(|key| held.insert(key)) {
+            Ok(())
+        } else {
+            Err(Status::already_exists("one time key already uploaded"))
         }
     }
+
+    fn assign_opk_ids(&self, pre_keys: Vec<X25519PublicKey>) -> OneTimeKeys {
+        pre_keys
+            .into_iter()
+            .map(|key| (self.next_opk_id.fetch_add(1, Ordering::Relaxed), key))
+            .collect()
+    }
 }
 
+/// Stores `pre_key` as the identity's signed pre key, moving to the next id if it changed.
+fn set_spk(stored: &mut StoredSpk, pre_key: SignedPreKeyProto) -> u32 {
+    if stored.2 != pre_key {
+        *stored = (stored.0 + 1, SystemTime::now(), pre_key);
+    }
+    stored.0
+}
+
+#[tonic::async_trait]
 impl Storage for MemoryStorage {
-    fn register_user(
+    async fn register_user(
         &self,
-        identity: String,
+        identity: Identity,
+        device_id: u32,
         ik: VerifyingKey,
         spk: SignedPreKeyProto,
-    ) -> tonic::Result<()> {
-        self.iks
+    ) -> tonic::Result<u32> {
+        self.stall().await;
+        let device = device(&identity, device_id);
+        self.names
             .lock()
             .unwrap()
-            .insert(identity.clone(), ik);
-        self.spks
+            .insert(&device, identity.display_name(), identity.skeleton())?;
+        self.iks.lock().unwrap().insert(device.clone(), ik);
+        self.registered_at
             .lock()
             .unwrap()
-            .insert(identity.clone(), spk);
-        self.opks
+            .insert(device.clone(), SystemTime::now());
+        let spk_id = match self.spks.lock().unwrap().entry(device.clone()) {
+            Entry::Occupied(mut entry) => set_spk(entry.get_mut(), spk),
+            Entry::Vacant(entry) => entry.insert((1, SystemTime::now(), spk)).0,
+        };
+        self.opks.lock().unwrap().insert(device, Vec::new());
+        Ok(spk_id)
+    }
+
+    async fn update_spk(
+        &self,
+        identity: &str,
+        device_id: u32,
+        pre_key: SignedPreKeyProto,
+    ) -> tonic::Result<u32> {
+        self.stall().await;
+        Ok(set_spk(
+            self.spks
+                .lock()
+                .unwrap()
+                .get_mut(&device(identity, device_id))
+                .ok_or(Status::not_found("User not found."))?,
+            pre_key,
+        ))
+    }
+
+    async fn add_opks(
+        &self,
+        identity: &str,
+        device_id: u32,
+        pre_keys: Vec<X25519PublicKey>,
+        quota: OpkQuota,
+    ) -> tonic::Result<Vec<u32>> {
+        self.stall().await;
+        let mut opks = self.opks.lock().unwrap();
+        self.check_opks_unique(&opks, &pre_keys, None)?;
+        let stored = opks
+            .get_mut(&device(identity, device_id))
+            .ok_or(Status::not_found("User not found."))?;
+        if stored.len() + pre_keys.len() > quota.max_keys {
+            if quota.policy == QuotaPolicy::Reject || pre_keys.len() > quota.max_keys {
+                return Err(Status::resource_exhausted(format!(
+                    "{identity} may store at most {} one time keys",
+                    quota.max_keys
+                )));
+            }
+            stored.drain(..stored.len() + pre_keys.len() - quota.max_keys);
+        }
+        let pre_keys = self.assign_opk_ids(pre_keys);
+        stored.extend_from_slice(&pre_keys);
+        Ok(pre_keys.into_iter().map(|(id, _)| id).collect())
+    }
+
+    async fn replace_opks(
+        &self,
+        identity: &str,
+        device_id: u32,
+        pre_keys: Vec<X25519PublicKey>,
+        quota: OpkQuota,
+    ) -> tonic::Result<Vec<u32>> {
+        self.stall().await;
+        let mut opks = self.opks.lock().unwrap();
+        self.check_opks_unique(&opks, &pre_keys, Some(&device(identity, device_id)))?;
+        let stored = opks
+            .get_mut(&device(identity, device_id))
+            .ok_or(Status::not_found("User not found."))?;
+        if pre_keys.len() > quota.max_keys {
+            return Err(Status::resource_exhausted(format!(
+                "{identity} may store at most {} one time keys",
+                quota.max_keys
+            )));
+        }
+        *stored = self.assign_opk_ids(pre_keys);
+        self.reserved_opks
             .lock()
             .unwrap()
-            .insert(identity, Vec::new());
-        Ok(())
+            .remove(&device(identity, device_id));
+        Ok(stored.iter().map(|(id, _)| *id).collect())
     }
 
-    fn update_spk(&self, identity: &str, mut pre_key: SignedPreKeyProto) -> tonic::Result<()> {
-        self.spks
+    async fn count_opks(&self, identity: &str, device_id: u32) -> tonic::Result<usize> {
+        self.stall().await;
+        Ok(self
+            .opks
             .lock()
             .unwrap()
-            .get_mut(identity)
-            .replace(&mut pre_key);
-        Ok(())
+            .get(&device(identity, device_id))
+            .map(|opks| opks.len())
+            .unwrap_or(0))
     }
 
-    fn add_opks(
+    async fn get_opks(
         &self,
         identity: &str,
-        mut pre_keys: Vec<X25519PublicKey>,
-    ) -> tonic::Result<()> {
-        let mut opks = self.opks.lock().unwrap();
-        opks
-            .get_mut(identity)
-            .ok_or(Status::not_found("User not found."))?
-            .append(&mut pre_keys);
-        Ok(())
+        device_id: u32,
+    ) -> tonic::Result<Vec<(u32, X25519PublicKey)>> {
+        self.stall().await;
+        Ok(self
+            .opks
+            .lock()
+            .unwrap()
+            .get(&device(identity, device_id))
+            .cloned()
+            .unwrap_or_default())
     }
 
-    fn get_current_keys(&self, identity: &str) -> tonic::Result<(VerifyingKey, SignedPreKeyProto)> {
+    async fn user_exists(&self, identity: &str) -> tonic::Result<bool> {
+        self.stall().await;
+        Ok(self
+            .iks
+            .lock()
+            .unwrap()
+            .keys()
+            .any(|(registered, _)| registered == identity))
+    }
+
+    async fn get_devices(&self, identity: &str) -> tonic::Result<Vec<u32>> {
+        self.stall().await;
+        let mut devices: Vec<u32> = self
+            .iks
+            .lock()
+            .unwrap()
+            .keys()
+            .filter(|(registered, _)| registered == identity)
+            .map(|(_, device_id)| *device_id)
+            .collect();
+        devices.sort();
+        Ok(devices)
+    }
+
+    async fn get_current_keys(&self, identity: &str, device_id: u32) -> tonic::Result<CurrentKeys> {
+        self.stall().await;
+        let device = device(identity, device_id);
         let ik = *self
             .iks
             .lock()
             .unwrap()
-            .get(identity)
+            .get(&device)
             .ok_or(Status::not_found("User not found."))?;
-        let spk = self
+        let (spk_id, spk_uploaded_at, spk) = self
             .spks
             .lock()
             .unwrap()
-            .get(identity)
+            .get(&device)
             .ok_or(Status::not_found("User not found."))?
             .to_owned();
-        Ok((ik, spk))
+        Ok(CurrentKeys {
+            ik,
+            spk,
+            spk_id,
+            spk_uploaded_at,
+            cipher_suite: self.cipher_suites.lock().unwrap().get(&device).copied(),
+        })
     }
 
-    fn pop_opk(&self, identity: &str) -> tonic::Result<Option<X25519PublicKey>> {
-        let opk =
-            if let Some(opks) = self.opks.lock().unwrap().get_mut(identity) {
-                opks.pop()
-            } else {
-                None
+    async fn set_cipher_suite(
+        &self,
+        identity: &str,
+        device_id: u32,
+        cipher_suite: Option<u32>,
+    ) -> tonic::Result<()> {
+        self.stall().await;
+        let device = device(identity, device_id);
+        if !self.iks.lock().unwrap().contains_key(&device) {
+            return Err(Status::not_found("User not found."));
+        }
+        let mut cipher_suites = self.cipher_suites.lock().unwrap();
+        match cipher_suite {
+            Some(cipher_suite) => cipher_suites.insert(device, cipher_suite),
+            None => cipher_suites.remove(&device),
+        };
+        Ok(())
+    }
+
+    async fn reserve_opk(
+        &self,
+        identity: &str,
+        device_id: u32,
+        until: SystemTime,
+    ) -> tonic::Result<Option<(u32, X25519PublicKey)>> {
+        self.stall().await;
+        let device = device(identity, device_id);
+        let mut opks = self.opks.lock().unwrap();
+        let Some((id, opk)) = opks
+            .get_mut(&device)
+            .and_then(|opks| (!opks.is_empty()).then(|| opks.remove(0)))
+        else {
+            return Ok(None);
+        };
+        self.reserved_opks
+            .lock()
+            .unwrap()
+            .entry(device)
+            .or_default()
+            .push((id, opk, until));
+        Ok(Some((id, opk)))
+    }
+
+    async fn consume_opk(&self, identity: &str, device_id: u32, id: u32) -> tonic::Result<bool> {
+        self.stall().await;
+        let mut reserved_opks = self.reserved_opks.lock().unwrap();
+        let Some(reserved) = reserved_opks.get_mut(&device(identity, device_id)) else {
+            return Ok(false);
+        };
+        let before = reserved.len();
+        reserved.retain(|(reserved_id, _, _)| *reserved_id != id);
+        Ok(reserved.len() < before)
+    }
+
+    async fn release_expired_opk_reservations(&self, before: SystemTime) -> tonic::Result<usize> {
+        self.stall().await;
+        let mut opks = self.opks.lock().unwrap();
+        let mut released = 0;
+        for (device, reserved) in self.reserved_opks.lock().unwrap().iter_mut() {
+            let Some(opks) = opks.get_mut(device) else {
+                continue;
             };
-        Ok(opk)
+            let (expired, kept): (ReservedKeys, ReservedKeys) = reserved
+                .drain(..)
+                .partition(|(_, _, until)| *until < before);
+            *reserved = kept;
+            released += expired.len();
+            opks.extend(expired.into_iter().map(|(id, opk, _)| (id, opk)));
+            // Ids grow with upload order, so this keeps the oldest keys first.
+            opks.sort_by_key(|(id, _)| *id);
+        }
+        Ok(released)
     }
 
-    fn add_message(&self, recipient: &str, message: MessageProto) -> tonic::Result<()> {
+    async fn set_last_resort_key(
+        &self,
+        identity: &str,
+        device_id: u32,
+        key: Option<X25519PublicKey>,
+    ) -> tonic::Result<u32> {
+        self.stall().await;
+        let device = device(identity, device_id);
+        if !self.iks.lock().unwrap().contains_key(&device) {
+            return Err(Status::not_found("User not found."));
+        }
+        let mut last_resort_keys = self.last_resort_keys.lock().unwrap();
+        let stored = last_resort_keys.entry(device).or_insert((0, None));
+        if stored.1 != key {
+            *stored = (stored.0 + 1, key);
+        }
+        Ok(stored.0)
+    }
+
+    async fn get_last_resort_key(
+        &self,
+        identity: &str,
+        device_id: u32,
+    ) -> tonic::Result<Option<(u32, X25519PublicKey)>> {
+        self.stall().await;
+        Ok(self
+            .last_resort_keys
+            .lock()
+            .unwrap()
+            .get(&device(identity, device_id))
+            .and_then(|(id, key)| key.map(|key| (*id, key))))
+    }
+
+    async fn replace_kem_opks(
+        &self,
+        identity: &str,
+        device_id: u32,
+        pre_keys: Vec<SignedPreKeyProto>,
+    ) -> tonic::Result<Vec<u32>> {
+        self.stall().await;
+        let device = device(identity, device_id);
+        if !self.iks.lock().unwrap().contains_key(&device) {
+            return Err(Status::not_found("User not found."));
+        }
+        let pre_keys: OneTimeKemKeys = pre_keys
+            .into_iter()
+            .map(|key| (self.next_opk_id.fetch_add(1, Ordering::Relaxed), key))
+            .collect();
+        let ids = pre_keys.iter().map(|(id, _)| *id).collect();
+        self.kem_opks.lock().unwrap().insert(device, pre_keys);
+        Ok(ids)
+    }
+
+    async fn pop_kem_opk(
+        &self,
+        identity: &str,
+        device_id: u32,
+    ) -> tonic::Result<Option<(u32, SignedPreKeyProto)>> {
+        self.stall().await;
+        Ok(self
+            .kem_opks
+            .lock()
+            .unwrap()
+            .get_mut(&device(identity, device_id))
+            .and_then(|kem_opks| (!kem_opks.is_empty()).then(|| kem_opks.remove(0))))
+    }
+
+    async fn set_last_resort_kem_key(
+        &self,
+        identity: &str,
+        device_id: u32,
+        key: Option<SignedPreKeyProto>,
+    ) -> tonic::Result<u32> {
+        self.stall().await;
+        let device = device(identity, device_id);
+        if !self.iks.lock().unwrap().contains_key(&device) {
+            return Err(Status::not_found("User not found."));
+        }
+        let mut last_resort_kem_keys = self.last_resort_kem_keys.lock().unwrap();
+        let stored = last_resort_kem_keys.entry(device).or_insert((0, None));
+        if stored.1 != key {
+            *stored = (stored.0 + 1, key);
+        }
+        Ok(stored.0)
+    }
+
+    async fn get_last_resort_kem_key(
+        &self,
+        identity: &str,
+        device_id: u32,
+    ) -> tonic::Result<Option<(u32, SignedPreKeyProto)>> {
+        self.stall().await;
+        Ok(self
+            .last_resort_kem_keys
+            .lock()
+            .unwrap()
+            .get(&device(identity, device_id))
+            .and_then(|(id, key)| key.clone().map(|key| (*id, key))))
+    }
+
+    async fn get_kem_opks(
+        &self,
+        identity: &str,
+        device_id: u32,
+    ) -> tonic::Result<Vec<(u32, SignedPreKeyProto)>> {
+        self.stall().await;
+        Ok(self
+            .kem_opks
+            .lock()
+            .unwrap()
+            .get(&device(identity, device_id))
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    async fn add_message(
+        &self,
+        recipient: &str,
+        device_id: u32,
+        message: MessageProto,
+    ) -> tonic::Result<()> {
+        self.stall().await;
         let mut messages = self.messages.lock().unwrap();
-        if !messages.contains_key(recipient) {
-            messages.insert(recipient.to_owned(), Vec::new());
+        let mailbox = messages.entry(device(recipient, device_id)).or_default();
+        if let Some(quota) = &self.mailbox_quota {
+            if mailbox.len() >= quota.max_messages {
+                match quota.policy {
+                    QuotaPolicy::Reject => {
+                        return Err(Status::resource_exhausted(format!(
+                            "mailbox for {recipient} is full"
+                        )))
+                    }
+                    QuotaPolicy::EvictOldest => {
+                        let excess = mailbox.len() + 1 - quota.max_messages;
+                        mailbox.drain(..excess.min(mailbox.len()));
+                    }
+                }
+            }
         }
-        messages.get_mut(recipient).unwrap().push(message);
+        mailbox.push((SystemTime::now(), message));
         Ok(())
     }
 
-    fn get_messages(&self, identity: &str) -> tonic::Result<Vec<MessageProto>> {
+    async fn get_messages(
+        &self,
+        identity: &str,
+        device_id: u32,
+    ) -> tonic::Result<Vec<MessageProto>> {
+        self.stall().await;
         Ok(self
             .messages
             .lock()
             .unwrap()
-            .remove(identity)
-            .unwrap_or(Vec::new()))
+            .remove(&device(identity, device_id))
+            .unwrap_or(Vec::new())
+            .into_iter()
+            .map(|(_, message)| message)
+            .collect())
+    }
+
+    async fn peek_messages(
+        &self,
+        identity: &str,
+        device_id: u32,
+    ) -> tonic::Result<Vec<QueuedMessage>> {
+        self.stall().await;
+        Ok(self
+            .messages
+            .lock()
+            .unwrap()
+            .get(&device(identity, device_id))
+            .into_iter()
+            .flatten()
+            .map(|(queued_at, message)| QueuedMessage {
+                queued_at: *queued_at,
+                message: message.clone(),
+            })
+            .collect())
+    }
+
+    async fn claim_message_uuid(
+        &self,
+        recipient: &str,
+        uuid: &[u8],
+    ) -> tonic::Result<MessageClaim> {
+        self.stall().await;
+        match self
+            .message_uuids
+            .lock()
+            .unwrap()
+            .entry((recipient.to_owned(), uuid.to_vec()))
+        {
+            Entry::Occupied(claimed) => Ok(MessageClaim::Duplicate(claimed.get().0)),
+            Entry::Vacant(entry) => {
+                let id = self.next_message_id.fetch_add(1, Ordering::Relaxed);
+                entry.insert((id, SystemTime::now()));
+                Ok(MessageClaim::New(id))
+            }
+        }
+    }
+
+    async fn release_message_uuid(&self, recipient: &str, uuid: &[u8]) -> tonic::Result<()> {
+        self.stall().await;
+        self.message_uuids
+            .lock()
+            .unwrap()
+            .remove(&(recipient.to_owned(), uuid.to_vec()));
+        Ok(())
+    }
+
+    async fn set_push_token(
+        &self,
+        identity: &str,
+        device_id: u32,
+        token: PushToken,
+    ) -> tonic::Result<()> {
+        self.stall().await;
+        let device = device(identity, device_id);
+        if !self.iks.lock().unwrap().contains_key(&device) {
+            return Err(Status::not_found("User not found."));
+        }
+        self.push_tokens.lock().unwrap().insert(device, token);
+        Ok(())
+    }
+
+    async fn get_push_token(
+        &self,
+        identity: &str,
+        device_id: u32,
+    ) -> tonic::Result<Option<PushToken>> {
+        self.stall().await;
+        Ok(self
+            .push_tokens
+            .lock()
+            .unwrap()
+            .get(&device(identity, device_id))
+            .cloned())
+    }
+
+    async fn delete_push_token(
+        &self,
+        identity: &str,
+        device_id: u32,
+        token: &str,
+    ) -> tonic::Result<()> {
+        self.stall().await;
+        let device = device(identity, device_id);
+        let mut push_tokens = self.push_tokens.lock().unwrap();
+        if push_tokens
+            .get(&device)
+            .is_some_and(|stored| stored.token == token)
+        {
+            push_tokens.remove(&device);
+        }
+        Ok(())
+    }
+
+    async fn delete_device(&self, identity: &str, device_id: u32) -> tonic::Result<()> {
+        self.stall().await;
+        let device = device(identity, device_id);
+        self.iks
+            .lock()
+            .unwrap()
+            .remove(&device)
+            .ok_or(Status::not_found("User not found."))?;
+        self.registered_at.lock().unwrap().remove(&device);
+        self.names.lock().unwrap().remove(&device);
+        self.spks.lock().unwrap().remove(&device);
+        self.opks.lock().unwrap().remove(&device);
+        self.reserved_opks.lock().unwrap().remove(&device);
+        self.last_resort_keys.lock().unwrap().remove(&device);
+        self.kem_opks.lock().unwrap().remove(&device);
+        self.last_resort_kem_keys.lock().unwrap().remove(&device);
+        self.cipher_suites.lock().unwrap().remove(&device);
+        self.push_tokens.lock().unwrap().remove(&device);
+        self.messages.lock().unwrap().remove(&device);
+        Ok(())
+    }
+
+    async fn delete_user(&self, identity: &str) -> tonic::Result<()> {
+        self.stall().await;
+        let devices = self.get_devices(identity).await?;
+        if devices.is_empty() {
+            return Err(Status::not_found("User not found."));
+        }
+        for device_id in devices {
+            self.delete_device(identity, device_id).await?;
+        }
+        self.message_uuids
+            .lock()
+            .unwrap()
+            .retain(|(recipient, _), _| recipient != identity);
+        Ok(())
+    }
+
+    async fn purge_messages(&self, identity: &str) -> tonic::Result<usize> {
+        self.stall().await;
+        let devices = self.get_devices(identity).await?;
+        if devices.is_empty() {
+            return Err(Status::not_found("User not found."));
+        }
+        let mut messages = self.messages.lock().unwrap();
+        Ok(devices
+            .into_iter()
+            .filter_map(|device_id| messages.remove(&device(identity, device_id)))
+            .map(|mailbox| mailbox.len())
+            .sum())
+    }
+
+    async fn purge_expired_messages(
+        &self,
+        before: SystemTime,
+    ) -> tonic::Result<Vec<ExpiredMessage>> {
+        self.stall().await;
+        let mut purged = Vec::new();
+        for ((recipient, device_id), mailbox) in self.messages.lock().unwrap().iter_mut() {
+            let (expired, kept) = std::mem::take(mailbox)
+                .into_iter()
+                .partition(|(creation_time, _)| *creation_time < before);
+            *mailbox = kept;
+            purged.extend(
+                expired
+                    .into_iter()
+                    .map(|(_, message): (SystemTime, MessageProto)| ExpiredMessage {
+                        recipient: recipient.clone(),
+                        device_id: *device_id,
+                        message,
+                    }),
+            );
+        }
+        self.message_uuids
+            .lock()
+            .unwrap()
+            .retain(|_, (_, claimed_at)| *claimed_at >= before);
+        Ok(purged)
+    }
+
+    async fn purge_expired_opks(&self, _before: SystemTime) -> tonic::Result<usize> {
+        self.stall().await;
+        // Registering replaces the user's one time keys, so none outlive a registration.
+        Ok(0)
+    }
+
+    async fn count_stale_spks(&self, before: SystemTime) -> tonic::Result<usize> {
+        self.stall().await;
+        Ok(self
+            .spks
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|(_, uploaded_at, _)| *uploaded_at < before)
+            .count())
+    }
+
+    async fn device_stats(&self, identity: Option<&str>) -> tonic::Result<Vec<DeviceStats>> {
+        self.stall().await;
+        let mut devices: Vec<Device> = self
+            .registered_at
+            .lock()
+            .unwrap()
+            .keys()
+            .filter(|(registered, _)| identity.is_none() || identity == Some(registered))
+            .cloned()
+            .collect();
+        devices.sort();
+        let registered_at = self.registered_at.lock().unwrap();
+        let names = self.names.lock().unwrap();
+        let spks = self.spks.lock().unwrap();
+        let opks = self.opks.lock().unwrap();
+        let messages = self.messages.lock().unwrap();
+        let push_tokens = self.push_tokens.lock().unwrap();
+        Ok(devices
+            .into_iter()
+            .map(|device| DeviceStats {
+                registered_at: registered_at[&device],
+                display_identity: names.display[&device].0.clone(),
+                spk_uploaded_at: spks[&device].1,
+                opk_count: opks.get(&device).map_or(0, Vec::len),
+                queued_messages: messages.get(&device).map_or(0, Vec::len),
+                has_push_token: push_tokens.contains_key(&device),
+                device_id: device.1,
+                identity: device.0,
+            })
+            .collect())
+    }
+
+    async fn stats(&self, deepest_queues: usize) -> tonic::Result<StorageStats> {
+        self.stall().await;
+        let devices = self.device_stats(None).await?;
+        let mut identities: Vec<&str> = devices.iter().map(|device| &device.identity[..]).collect();
+        identities.dedup();
+        let messages = self.messages.lock().unwrap();
+        let mut queues: HashMap<&str, usize> = HashMap::new();
+        for ((identity, _), mailbox) in messages.iter() {
+            *queues.entry(identity).or_default() += mailbox.len();
+        }
+        let mut queues: Vec<(String, usize)> = queues
+            .into_iter()
+            .filter(|(_, depth)| *depth > 0)
+            .map(|(identity, depth)| (identity.to_owned(), depth))
+            .collect();
+        queues.sort_by(|(a, a_depth), (b, b_depth)| b_depth.cmp(a_depth).then(a.cmp(b)));
+        queues.truncate(deepest_queues);
+        // Named after the sqlite tables holding the same rows.
+        let table_rows = BTreeMap::from([
+            (
+                String::from("audit_log"),
+                self.audit_log.lock().unwrap().len(),
+            ),
+            (String::from("invite"), self.invites.lock().unwrap().len()),
+            (
+                String::from("kem_pre_key"),
+                self.kem_opks.lock().unwrap().values().map(Vec::len).sum(),
+            ),
+            (
+                String::from("message"),
+                messages.values().map(Vec::len).sum(),
+            ),
+            (
+                String::from("message_uuid"),
+                self.message_uuids.lock().unwrap().len(),
+            ),
+            (
+                String::from("pre_key"),
+                self.opks
+                    .lock()
+                    .unwrap()
+                    .values()
+                    .map(Vec::len)
+                    .sum::<usize>()
+                    + self
+                        .reserved_opks
+                        .lock()
+                        .unwrap()
+                        .values()
+                        .map(Vec::len)
+                        .sum::<usize>(),
+            ),
+            (
+                String::from("push_token"),
+                self.push_tokens.lock().unwrap().len(),
+            ),
+            (String::from("user"), devices.len()),
+        ]);
+        Ok(StorageStats {
+            identities: identities.len(),
+            devices: devices.len(),
+            opks: devices.iter().map(|device| device.opk_count).sum(),
+            queued_messages: devices.iter().map(|device| device.queued_messages).sum(),
+            table_rows,
+            size_bytes: None,
+            wal_size_bytes: None,
+            oldest_message_at: messages
+                .values()
+                .flatten()
+                .map(|(queued_at, _)| *queued_at)
+                .min(),
+            deepest_queues: queues,
+        })
+    }
+
+    async fn add_audit_entry(&self, entry: AuditEntry) -> tonic::Result<()> {
+        self.stall().await;
+        self.audit_log.lock().unwrap().push(entry);
+        Ok(())
+    }
+
+    async fn audit_log(&self) -> tonic::Result<Vec<AuditEntry>> {
+        self.stall().await;
+        Ok(self.audit_log.lock().unwrap().clone())
+    }
+
+    async fn add_invite(&self, invite: Invite) -> tonic::Result<()> {
+        self.stall().await;
+        let mut invites = self.invites.lock().unwrap();
+        if invites
+            .iter()
+            .any(|existing| existing.code_hash == invite.code_hash)
+        {
+            return Err(Status::already_exists("invite already exists"));
+        }
+        invites.push(invite);
+        Ok(())
+    }
+
+    async fn redeem_invite(
+        &self,
+        code_hash: &[u8],
+        now: SystemTime,
+    ) -> tonic::Result<Result<(), InviteRejection>> {
+        self.stall().await;
+        let mut invites = self.invites.lock().unwrap();
+        let Some(invite) = invites
+            .iter_mut()
+            .find(|invite| invite.code_hash == code_hash)
+        else {
+            return Ok(Err(InviteRejection::Unknown));
+        };
+        if invite.revoked {
+            return Ok(Err(InviteRejection::Revoked));
+        }
+        if invite
+            .expires_at
+            .is_some_and(|expires_at| expires_at <= now)
+        {
+            return Ok(Err(InviteRejection::Expired));
+        }
+        if invite.uses >= invite.max_uses {
+            return Ok(Err(InviteRejection::UsedUp));
+        }
+        invite.uses += 1;
+        Ok(Ok(()))
+    }
+
+    async fn revoke_invite(&self, code_hash: &[u8]) -> tonic::Result<()> {
+        self.stall().await;
+        match self
+            .invites
+            .lock()
+            .unwrap()
+            .iter_mut()
+            .find(|invite| invite.code_hash == code_hash)
+        {
+            Some(invite) => {
+                invite.revoked = true;
+                Ok(())
+            }
+            None => Err(Status::not_found("invite not found")),
+        }
+    }
+
+    async fn invites(&self) -> tonic::Result<Vec<Invite>> {
+        self.stall().await;
+        Ok(self.invites.lock().unwrap().clone())
+    }
+
+    /// Memory has no write-ahead log.
+    async fn checkpoint(&self, _min_wal_bytes: u64) -> tonic::Result<Option<Checkpoint>> {
+        self.stall().await;
+        Ok(None)
+    }
+
+    async fn optimize(&self) -> tonic::Result<()> {
+        self.stall().await;
+        Ok(())
+    }
+
+    async fn close(&self) -> tonic::Result<()> {
+        Ok(())
+    }
+
+    async fn backup(&self, _destination: &Path, _progress: BackupProgress) -> tonic::Result<()> {
+        self.stall().await;
+        Err(Status::unimplemented("memory storage can't be backed up"))
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use crate::memory_brongnal::*;
+    use crate::storage_tests::storage_test_suite;
+
+    storage_test_suite!(MemoryStorage::default());
+}
@@ -0,0 +1,165 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::time::Instant;
+use tonic::Status;
+
+/// Token bucket parameters: `burst` requests may be made at once, refilling at `rate` per second.
+#[derive(Clone, Copy, Debug)]
+pub struct RateLimit {
+    pub rate: f64,
+    pub burst: u32,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, limit: &RateLimit, now: Instant) {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.rate).min(limit.burst as f64);
+        self.updated = now;
+    }
+}
+
+/// Per-key token bucket rate limiter.
+#[derive(Debug)]
+pub struct RateLimiter {
+    limit: Option<RateLimit>,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(limit: RateLimit) -> Self {
+        RateLimiter {
+            limit: Some(limit),
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// A limiter that admits every request.
+    pub fn unlimited() -> Self {
+        RateLimiter {
+            limit: None,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes a token for `key`.
+    /// Returns how long the caller should wait before retrying if the bucket is empty.
+    pub fn check(&self, key: &str) -> Result<(), Duration> {
+        let Some(limit) = &self.limit else {
+            return Ok(());
+        };
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.entry(key.to_owned()).or_insert(Bucket {
+            tokens: limit.burst as f64,
+            updated: now,
+        });
+        bucket.refill(limit, now);
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / limit.rate))
+        }
+    }
+
+    /// Forgets keys whose buckets have completely refilled, since they carry no state.
+    pub fn evict_idle(&self) {
+        let Some(limit) = &self.limit else {
+            return;
+        };
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        buckets.retain(|_, bucket| {
+            bucket.refill(limit, now);
+            bucket.tokens < limit.burst as f64
+        });
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.buckets.lock().unwrap().len()
+    }
+}
+
+/// A ResourceExhausted status saying `message`, with a `retry-after` header telling clients how
+/// many whole seconds to wait.
+pub fn throttled(message: String, retry_after: Duration) -> Status {
+    let mut status = Status::resource_exhausted(message);
+    status.metadata_mut().insert(
+        "retry-after",
+        (retry_after.as_secs_f64().ceil() as u64)
+            .to_string()
+            .parse()
+            .unwrap(),
+    );
+    status
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::rate_limit::*;
+
+    const LIMIT: RateLimit = RateLimit {
+        rate: 2.0,
+        burst: 3,
+    };
+
+    #[tokio::test(start_paused = true)]
+    async fn burst_within_limit() {
+        let limiter = RateLimiter::new(LIMIT);
+        for _ in 0..3 {
+            assert_eq!(limiter.check("alice"), Ok(()));
+        }
+        assert_eq!(limiter.check("bob"), Ok(()));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn excess_is_rejected() {
+        let limiter = RateLimiter::new(LIMIT);
+        for _ in 0..3 {
+            limiter.check("alice").unwrap();
+        }
+        assert_eq!(limiter.check("alice"), Err(Duration::from_millis(500)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn tokens_refill() {
+        let limiter = RateLimiter::new(LIMIT);
+        for _ in 0..3 {
+            limiter.check("alice").unwrap();
+        }
+        assert!(limiter.check("alice").is_err());
+
+        tokio::time::advance(Duration::from_millis(500)).await;
+        assert_eq!(limiter.check("alice"), Ok(()));
+        assert!(limiter.check("alice").is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn evict_idle_buckets() {
+        let limiter = RateLimiter::new(LIMIT);
+        limiter.check("alice").unwrap();
+        limiter.evict_idle();
+        assert_eq!(limiter.len(), 1);
+
+        tokio::time::advance(Duration::from_secs(1)).await;
+        limiter.evict_idle();
+        assert_eq!(limiter.len(), 0);
+    }
+
+    #[test]
+    fn unlimited() {
+        let limiter = RateLimiter::unlimited();
+        for _ in 0..1000 {
+            assert_eq!(limiter.check("alice"), Ok(()));
+        }
+        assert_eq!(limiter.len(), 0);
+    }
+}
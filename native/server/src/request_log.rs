@@ -0,0 +1,439 @@
+//! Logs one structured event per RPC: its method, the identity that authenticated it, how long it
+//! took, how it ended and how many bytes went each way. Each RPC also runs in a span carrying a
+//! request id, so whatever storage logs along the way can be traced back to the request.
+
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::OsRng;
+use futures::TryStreamExt;
+use hyper::Body;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use tokio::time::Instant;
+use tonic::body::BoxBody;
+use tonic::codegen::http::{HeaderMap, HeaderValue, Request, Response};
+use tonic::codegen::{Body as HttpBody, Bytes, Service};
+use tonic::{Code, Status};
+use tower::Layer;
+use tracing::Instrument;
+
+/// The header a request id arrives in from a proxy that assigned one, and is returned in.
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+/// The longest request id taken from a request; longer ones are replaced.
+const MAX_REQUEST_ID_LEN: usize = 64;
+
+tokio::task_local! {
+    /// The identity the RPC being handled authenticated as.
+    static PEER: Arc<Mutex<Option<String>>>;
+}
+
+/// Records that the RPC being handled proved it acts for `identity`, for its log event.
+pub fn authenticated(identity: &str) {
+    tracing::Span::current().record("identity", identity);
+    let _ = PEER.try_with(|peer| *peer.lock().unwrap() = Some(identity.to_owned()));
+}
+
+/// Wraps services in a `RequestLog`.
+#[derive(Clone, Copy, Debug)]
+pub struct RequestLogLayer {
+    sample_rate: f64,
+}
+
+impl RequestLogLayer {
+    /// Logs the given fraction of successful RPCs, and every failed one.
+    pub fn new(sample_rate: f64) -> Self {
+        RequestLogLayer { sample_rate }
+    }
+}
+
+impl<S> Layer<S> for RequestLogLayer {
+    type Service = RequestLog<S>;
+
+    fn layer(&self, inner: S) -> RequestLog<S> {
+        RequestLog {
+            inner,
+            sample_rate: self.sample_rate,
+        }
+    }
+}
+
+/// Logs each RPC once its response has been sent, or abandoned.
+#[derive(Clone, Debug)]
+pub struct RequestLog<S> {
+    inner: S,
+    sample_rate: f64,
+}
+
+impl<S> Service<Request<Body>> for RequestLog<S>
+where
+    S: Service<Request<Body>, Response = Response<BoxBody>>,
+    S::Future: Send + 'static,
+{
+    type Response = Response<BoxBody>;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Response<BoxBody>, S::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), S::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<Body>) -> Self::Future {
+        let id = request_id(request.headers());
+        let record = RpcRecord {
+            id: id.clone(),
+            method: request.uri().path().to_owned(),
+            peer: Arc::default(),
+            start: Instant::now(),
+            request_bytes: Arc::default(),
+            response_bytes: 0,
+            sample_rate: self.sample_rate,
+        };
+        let span = tracing::info_span!(
+            "rpc",
+            request_id = %id,
+            method = %record.method,
+            identity = tracing::field::Empty
+        );
+        let request_bytes = record.request_bytes.clone();
+        let request = request.map(|body| {
+            Body::wrap_stream(body.inspect_ok(move |chunk| {
+                request_bytes.fetch_add(chunk.len() as u64, Ordering::Relaxed);
+            }))
+        });
+        let peer = record.peer.clone();
+        let response = span.in_scope(|| PEER.sync_scope(peer.clone(), || self.inner.call(request)));
+        let response = PEER.scope(peer, response).instrument(span);
+        Box::pin(async move {
+            let mut response = response.await?;
+            if let Ok(id) = HeaderValue::from_str(&id) {
+                response.headers_mut().insert(REQUEST_ID_HEADER, id);
+            }
+            let mut record = Some(record);
+            // Failures before the body starts are reported in the headers.
+            if let Some(status) = Status::from_header_map(response.headers()) {
+                record.take().unwrap().finish(status.code());
+            }
+            Ok(response.map(|inner| BoxBody::new(Logged { inner, record })))
+        })
+    }
+}
+
+/// The request's id from `headers` if a proxy gave it one, or a fresh one.
+fn request_id(headers: &HeaderMap) -> String {
+    headers
+        .get(REQUEST_ID_HEADER)
+        .and_then(|id| id.to_str().ok())
+        .filter(|id| !id.is_empty() && id.len() <= MAX_REQUEST_ID_LEN)
+        .map(str::to_owned)
+        .unwrap_or_else(|| format!("{:016x}", OsRng.next_u64()))
+}
+
+/// What has been seen of an RPC so far.
+#[derive(Debug)]
+struct RpcRecord {
+    id: String,
+    method: String,
+    peer: Arc<Mutex<Option<String>>>,
+    start: Instant,
+    request_bytes: Arc<AtomicU64>,
+    response_bytes: u64,
+    sample_rate: f64,
+}
+
+impl RpcRecord {
+    /// Logs the RPC as having ended with `code`, unless it succeeded and isn't sampled.
+    fn finish(self, code: Code) {
+        if code == Code::Ok
+            && self.sample_rate < 1.0
+            && (OsRng.next_u64() as f64 / u64::MAX as f64) >= self.sample_rate
+        {
+            return;
+        }
+        let identity = self.peer.lock().unwrap().take();
+        tracing::info!(
+            request_id = %self.id,
+            method = %self.method,
+            identity = identity.as_deref(),
+            latency_ms = self.start.elapsed().as_millis() as u64,
+            code = ?code,
+            request_bytes = self.request_bytes.load(Ordering::Relaxed),
+            response_bytes = self.response_bytes,
+            "rpc finished"
+        );
+    }
+}
+
+/// A response body that logs its RPC once the status arrives in its trailers, it ends without
+/// one, or it's dropped before either.
+struct Logged {
+    inner: BoxBody,
+    record: Option<RpcRecord>,
+}
+
+impl Logged {
+    fn finish(&mut self, code: Code) {
+        if let Some(record) = self.record.take() {
+            record.finish(code);
+        }
+    }
+}
+
+impl HttpBody for Logged {
+    type Data = Bytes;
+    type Error = Status;
+
+    fn poll_data(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Bytes, Status>>> {
+        let data = Pin::new(&mut self.inner).poll_data(cx);
+        match &data {
+            Poll::Ready(Some(Ok(chunk))) => {
+                if let Some(record) = &mut self.record {
+                    record.response_bytes += chunk.len() as u64;
+                }
+            }
+            Poll::Ready(Some(Err(status))) => self.finish(status.code()),
+            _ => {}
+        }
+        data
+    }
+
+    fn poll_trailers(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<HeaderMap>, Status>> {
+        let trailers = Pin::new(&mut self.inner).poll_trailers(cx);
+        match &trailers {
+            Poll::Ready(Ok(trailers)) => {
+                let code = trailers
+                    .as_ref()
+                    .and_then(Status::from_header_map)
+                    .map_or(Code::Unknown, |status| status.code());
+                self.finish(code);
+            }
+            Poll::Ready(Err(status)) => self.finish(status.code()),
+            Poll::Pending => {}
+        }
+        trailers
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+}
+
+impl Drop for Logged {
+    fn drop(&mut self) {
+        // The client went away before the RPC finished.
+        self.finish(Code::Cancelled);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::convert::Infallible;
+    use std::fmt;
+    use tracing::field::{Field, Visit};
+    use tracing_subscriber::layer::SubscriberExt;
+
+    const METHOD: &str = "/service.Brongnal/RequestPreKeys";
+
+    /// The fields of every event logged while it's the default subscriber.
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<HashMap<String, String>>>>);
+
+    impl<S: tracing::Subscriber> tracing_subscriber::Layer<S> for Captured {
+        fn on_event(
+            &self,
+            event: &tracing::Event<'_>,
+            _ctx: tracing_subscriber::layer::Context<'_, S>,
+        ) {
+            let mut fields = Fields::default();
+            event.record(&mut fields);
+            self.0.lock().unwrap().push(fields.0);
+        }
+    }
+
+    impl Captured {
+        fn events(&self) -> Vec<HashMap<String, String>> {
+            self.0.lock().unwrap().clone()
+        }
+    }
+
+    #[derive(Default)]
+    struct Fields(HashMap<String, String>);
+
+    impl Visit for Fields {
+        fn record_str(&mut self, field: &Field, value: &str) {
+            self.0.insert(field.name().to_owned(), value.to_owned());
+        }
+
+        fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+            self.0.insert(field.name().to_owned(), format!("{value:?}"));
+        }
+    }
+
+    /// A unary response: one chunk of data, then the status in the trailers.
+    struct Reply {
+        data: Option<Bytes>,
+        trailers: Option<HeaderMap>,
+    }
+
+    impl HttpBody for Reply {
+        type Data = Bytes;
+        type Error = Status;
+
+        fn poll_data(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+        ) -> Poll<Option<Result<Bytes, Status>>> {
+            Poll::Ready(self.data.take().map(Ok))
+        }
+
+        fn poll_trailers(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+        ) -> Poll<Result<Option<HeaderMap>, Status>> {
+            Poll::Ready(Ok(self.trailers.take()))
+        }
+    }
+
+    /// Reads the request, then either authenticates it as alice and answers, or refuses it with
+    /// NotFound.
+    #[derive(Clone)]
+    struct Handler {
+        succeed: bool,
+    }
+
+    impl Service<Request<Body>> for Handler {
+        type Response = Response<BoxBody>;
+        type Error = Infallible;
+        type Future = Pin<Box<dyn Future<Output = Result<Response<BoxBody>, Infallible>> + Send>>;
+
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, request: Request<Body>) -> Self::Future {
+            let succeed = self.succeed;
+            Box::pin(async move {
+                hyper::body::to_bytes(request.into_body()).await.unwrap();
+                if !succeed {
+                    return Ok(Status::not_found("user not found").to_http());
+                }
+                authenticated("alice");
+                let mut trailers = HeaderMap::new();
+                Status::ok("").add_header(&mut trailers).unwrap();
+                Ok(Response::new(BoxBody::new(Reply {
+                    data: Some(Bytes::from_static(b"hello")),
+                    trailers: Some(trailers),
+                })))
+            })
+        }
+    }
+
+    /// Makes a request with `body` and `headers`, reading the whole response, and returns its
+    /// request id header.
+    async fn call(
+        service: &mut RequestLog<Handler>,
+        body: &'static str,
+        headers: &[(&str, &str)],
+    ) -> String {
+        let mut request = Request::builder().uri(METHOD);
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        let response = service
+            .call(request.body(Body::from(body)).unwrap())
+            .await
+            .unwrap();
+        let id = response.headers()[REQUEST_ID_HEADER]
+            .to_str()
+            .unwrap()
+            .to_owned();
+        let mut body = response.into_body();
+        while body.data().await.is_some() {}
+        body.trailers().await.unwrap();
+        id
+    }
+
+    fn capture() -> (Captured, tracing::subscriber::DefaultGuard) {
+        let captured = Captured::default();
+        let guard =
+            tracing::subscriber::set_default(tracing_subscriber::registry().with(captured.clone()));
+        (captured, guard)
+    }
+
+    #[tokio::test]
+    async fn logs_successful_rpc() {
+        let (captured, _guard) = capture();
+        let mut service = RequestLogLayer::new(1.0).layer(Handler { succeed: true });
+        let id = call(&mut service, "request", &[]).await;
+
+        let events = captured.events();
+        assert_eq!(events.len(), 1);
+        let event = &events[0];
+        assert_eq!(event["request_id"], id);
+        assert_eq!(event["method"], METHOD);
+        assert_eq!(event["identity"], "alice");
+        assert_eq!(event["code"], "Ok");
+        assert_eq!(event["request_bytes"], "7");
+        assert_eq!(event["response_bytes"], "5");
+        assert!(event.contains_key("latency_ms"));
+        assert_eq!(event["message"], "rpc finished");
+    }
+
+    #[tokio::test]
+    async fn logs_failed_rpc() {
+        let (captured, _guard) = capture();
+        let mut service = RequestLogLayer::new(1.0).layer(Handler { succeed: false });
+        // A proxy's request id is kept.
+        let id = call(&mut service, "", &[(REQUEST_ID_HEADER, "from-proxy")]).await;
+        assert_eq!(id, "from-proxy");
+
+        let events = captured.events();
+        assert_eq!(events.len(), 1);
+        let event = &events[0];
+        assert_eq!(event["request_id"], "from-proxy");
+        assert_eq!(event["method"], METHOD);
+        assert_eq!(event["code"], "NotFound");
+        assert!(!event.contains_key("identity"));
+        assert_eq!(event["request_bytes"], "0");
+        assert_eq!(event["response_bytes"], "0");
+    }
+
+    #[tokio::test]
+    async fn samples_successful_rpcs() {
+        let (captured, _guard) = capture();
+        let mut succeeding = RequestLogLayer::new(0.0).layer(Handler { succeed: true });
+        let mut failing = RequestLogLayer::new(0.0).layer(Handler { succeed: false });
+        for _ in 0..10 {
+            call(&mut succeeding, "request", &[]).await;
+        }
+        assert!(captured.events().is_empty());
+
+        // Failures are always logged.
+        call(&mut failing, "request", &[]).await;
+        let events = captured.events();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0]["code"], "NotFound");
+    }
+
+    #[test]
+    fn request_ids() {
+        let mut headers = HeaderMap::new();
+        let generated = request_id(&headers);
+        assert_eq!(generated.len(), 16);
+        assert_ne!(request_id(&headers), generated);
+
+        headers.insert(REQUEST_ID_HEADER, HeaderValue::from_static("abc"));
+        assert_eq!(request_id(&headers), "abc");
+        headers.insert(REQUEST_ID_HEADER, "a".repeat(65).parse().unwrap());
+        assert_eq!(request_id(&headers).len(), 16);
+    }
+}
@@ -1,260 +1,2492 @@
-use crate::brongnal::Storage;
-use anyhow::{Context, Result};
+use crate::at_rest::AtRestKey;
+use crate::brongnal::{
+    AuditEntry, BackupProgress, Checkpoint, CurrentKeys, DeviceStats, ExpiredMessage, Invite,
+    InviteRejection, MailboxQuota, MessageClaim, OpkQuota, PushToken, QueuedMessage, QuotaPolicy,
+    Storage, StorageStats,
+};
+use anyhow::{bail, Context, Result};
 use ed25519_dalek::VerifyingKey;
 use prost::Message;
-use proto::parse_verifying_key;
 use proto::service::Message as MessageProto;
+use proto::service::PushPlatform as PushPlatformProto;
 use proto::service::SignedPreKey as SignedPreKeyProto;
-use rusqlite::{params, Connection};
-use std::sync::MutexGuard;
-use std::time::{SystemTime, UNIX_EPOCH};
-use std::{sync::Arc, sync::Mutex};
-use tonic::Status;
+use proto::{parse_verifying_key, Identity};
+use rusqlite::backup::{Backup, Progress, StepResult};
+use rusqlite::{params, ErrorCode, OpenFlags, Transaction, TransactionBehavior};
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio_rusqlite::Connection;
+use tonic::{Code, Status};
+use tracing::{debug, info};
 use x25519_dalek::PublicKey as X25519PublicKey;
 
 #[derive(Debug)]
-pub struct SqliteStorage(Arc<Mutex<Connection>>);
+pub struct SqliteStorage {
+    /// Makes every change, one at a time.
+    connection: Connection,
+    /// Read only connections to the same database, which write-ahead logging lets read alongside
+    /// the writer. Without any, reads wait their turn on the writer.
+    readers: Vec<Connection>,
+    next_reader: AtomicUsize,
+    mailbox_quota: Option<MailboxQuota>,
+    /// Encrypts queued messages, if they are kept encrypted.
+    at_rest_key: Option<AtRestKey>,
+}
 
-impl SqliteStorage {
-    fn connection(&self) -> tonic::Result<MutexGuard<Connection>> {
-        self.0
-            .lock()
-            .map_err(|_e| Status::internal("failed to access sqlite connection"))
-    }
-    pub fn new(connection: Connection) -> Result<Self> {
-        connection.pragma_update(None, "journal_mode", "WAL")?;
-        connection.pragma_update(None, "synchronous", "normal")?;
-        connection.pragma_update(None, "foreign_keys", "on")?;
+/// How long a statement waits for another connection to release the database before failing.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+/// How many more times writes are tried when the database stays busy past `BUSY_TIMEOUT`.
+const BUSY_RETRIES: u32 = 3;
+/// How long to wait before the first retry, doubling before each one after.
+const BUSY_BACKOFF: Duration = Duration::from_millis(50);
+/// Pages copied between progress reports while backing up.
+const BACKUP_STEP_PAGES: i32 = 256;
 
-        connection
-            .execute(
-                "CREATE TABLE IF NOT EXISTS user (
+type Migration = fn(&Transaction) -> Result<()>;
+
+/// Schema migrations in the order they are applied. `PRAGMA user_version` records how many have
+/// run, so existing entries must never be edited or reordered; append new ones instead.
+const MIGRATIONS: &[Migration] = &[
+    create_tables,
+    index_message_user_identity,
+    index_pre_key_user_identity,
+    message_id,
+    cascade_user_deletion,
+    pre_key_ids,
+    signed_pre_key_upload_time,
+    device_ids,
+    last_resort_keys,
+    kem_pre_keys,
+    cipher_suites,
+    push_tokens,
+    message_uuids,
+    pre_key_reservations,
+    audit_log,
+    invites,
+    identity_skeletons,
+    at_rest_encryption,
+];
+
+/// The schema of databases created before migrations were introduced.
+fn create_tables(transaction: &Transaction) -> Result<()> {
+    transaction
+        .execute(
+            "CREATE TABLE IF NOT EXISTS user (
              identity STRING PRIMARY KEY,
              key BLOB NOT NULL,
              current_pre_key BLOB NOT NULL,
              creation_time INTEGER NOT NULL
          )",
-                (),
-            )
-            .context("Creating user table failed.")?;
-        connection
-            .execute(
-                "CREATE TABLE IF NOT EXISTS pre_key (
+            (),
+        )
+        .context("Creating user table failed.")?;
+    transaction
+        .execute(
+            "CREATE TABLE IF NOT EXISTS pre_key (
              key BLOB PRIMARY KEY,
              user_identity STRING NOT NULL,
              creation_time integer NOT NULL,
              FOREIGN KEY(user_identity) REFERENCES user(identity)
          )",
-                (),
-            )
-            .context("Creating pre_key table failed.")?;
-        connection
-            .execute(
-                "CREATE TABLE IF NOT EXISTS message (
+            (),
+        )
+        .context("Creating pre_key table failed.")?;
+    transaction
+        .execute(
+            "CREATE TABLE IF NOT EXISTS message (
              message BLOB PRIMARY KEY,
              user_identity STRING NOT NULL,
              creation_time integer NOT NULL,
              FOREIGN KEY(user_identity) REFERENCES user(identity)
          )",
-                (),
+            (),
+        )
+        .context("Creating message table failed.")?;
+    Ok(())
+}
+
+/// SQLite appends the rowid to every index, so this also yields a user's messages in the order
+/// they were enqueued.
+fn index_message_user_identity(transaction: &Transaction) -> Result<()> {
+    transaction
+        .execute(
+            "CREATE INDEX IF NOT EXISTS message_user_identity ON message(user_identity)",
+            (),
+        )
+        .context("Creating message index failed.")?;
+    Ok(())
+}
+
+/// Lets reserve_opk find a user's oldest key without scanning or sorting the whole table.
+fn index_pre_key_user_identity(transaction: &Transaction) -> Result<()> {
+    transaction
+        .execute(
+            "CREATE INDEX IF NOT EXISTS pre_key_user_identity ON pre_key(user_identity, creation_time)",
+            (),
+        )
+        .context("Creating pre_key index failed.")?;
+    Ok(())
+}
+
+/// Keys messages by an autoincrementing id instead of their contents, so identical messages can
+/// be queued more than once.
+fn message_id(transaction: &Transaction) -> Result<()> {
+    transaction
+        .execute_batch(
+            "CREATE TABLE message_by_id (
+             id INTEGER PRIMARY KEY AUTOINCREMENT,
+             message BLOB NOT NULL,
+             user_identity STRING NOT NULL,
+             creation_time integer NOT NULL,
+             FOREIGN KEY(user_identity) REFERENCES user(identity)
+         );
+         INSERT INTO message_by_id (message, user_identity, creation_time)
+             SELECT message, user_identity, creation_time FROM message ORDER BY rowid;
+         DROP TABLE message;
+         ALTER TABLE message_by_id RENAME TO message;
+         CREATE INDEX message_user_identity ON message(user_identity, id);",
+        )
+        .context("Adding message id failed.")?;
+    Ok(())
+}
+
+/// Rebuilds the tables referencing `user` so deleting a user also deletes their keys and messages.
+fn cascade_user_deletion(transaction: &Transaction) -> Result<()> {
+    transaction
+        .execute_batch(
+            "CREATE TABLE pre_key_cascade (
+             key BLOB PRIMARY KEY,
+             user_identity STRING NOT NULL,
+             creation_time integer NOT NULL,
+             FOREIGN KEY(user_identity) REFERENCES user(identity) ON DELETE CASCADE
+         );
+         INSERT INTO pre_key_cascade (key, user_identity, creation_time)
+             SELECT key, user_identity, creation_time FROM pre_key ORDER BY rowid;
+         DROP TABLE pre_key;
+         ALTER TABLE pre_key_cascade RENAME TO pre_key;
+         CREATE INDEX pre_key_user_identity ON pre_key(user_identity, creation_time);",
+        )
+        .context("Cascading pre_key deletion failed.")?;
+    transaction
+        .execute_batch(
+            "CREATE TABLE message_cascade (
+             id INTEGER PRIMARY KEY AUTOINCREMENT,
+             message BLOB NOT NULL,
+             user_identity STRING NOT NULL,
+             creation_time integer NOT NULL,
+             FOREIGN KEY(user_identity) REFERENCES user(identity) ON DELETE CASCADE
+         );
+         INSERT INTO sqlite_sequence (name, seq)
+             SELECT 'message_cascade', seq FROM sqlite_sequence WHERE name = 'message';
+         INSERT INTO message_cascade (id, message, user_identity, creation_time)
+             SELECT id, message, user_identity, creation_time FROM message;
+         DROP TABLE message;
+         ALTER TABLE message_cascade RENAME TO message;
+         CREATE INDEX message_user_identity ON message(user_identity, id);",
+        )
+        .context("Cascading message deletion failed.")?;
+    Ok(())
+}
+
+/// Assigns ids to one time keys and signed pre keys so messages can refer to them without
+/// repeating the keys themselves.
+fn pre_key_ids(transaction: &Transaction) -> Result<()> {
+    transaction
+        .execute_batch(
+            "CREATE TABLE pre_key_by_id (
+             id INTEGER PRIMARY KEY AUTOINCREMENT,
+             key BLOB NOT NULL UNIQUE,
+             user_identity STRING NOT NULL,
+             creation_time integer NOT NULL,
+             FOREIGN KEY(user_identity) REFERENCES user(identity) ON DELETE CASCADE
+         );
+         INSERT INTO pre_key_by_id (key, user_identity, creation_time)
+             SELECT key, user_identity, creation_time FROM pre_key ORDER BY rowid;
+         DROP TABLE pre_key;
+         ALTER TABLE pre_key_by_id RENAME TO pre_key;
+         CREATE INDEX pre_key_user_identity ON pre_key(user_identity, creation_time);",
+        )
+        .context("Adding pre_key id failed.")?;
+    transaction
+        .execute(
+            "ALTER TABLE user ADD COLUMN current_pre_key_id INTEGER NOT NULL DEFAULT 1",
+            (),
+        )
+        .context("Adding signed pre key id failed.")?;
+    Ok(())
+}
+
+/// Records when each user's signed pre key was uploaded so stale keys can be noticed. Existing
+/// keys are assumed to date from the user's last registration.
+fn signed_pre_key_upload_time(transaction: &Transaction) -> Result<()> {
+    transaction
+        .execute_batch(
+            "ALTER TABLE user ADD COLUMN current_pre_key_upload_time INTEGER NOT NULL DEFAULT 0;
+         UPDATE user SET current_pre_key_upload_time = creation_time;",
+        )
+        .context("Adding signed pre key upload time failed.")?;
+    Ok(())
+}
+
+/// Keys users, their one time keys and their messages by device. Existing registrations become
+/// device 1.
+fn device_ids(transaction: &Transaction) -> Result<()> {
+    transaction
+        .execute_batch(
+            "CREATE TABLE user_by_device (
+             identity STRING NOT NULL,
+             device_id INTEGER NOT NULL,
+             key BLOB NOT NULL,
+             current_pre_key BLOB NOT NULL,
+             creation_time INTEGER NOT NULL,
+             current_pre_key_id INTEGER NOT NULL DEFAULT 1,
+             current_pre_key_upload_time INTEGER NOT NULL DEFAULT 0,
+             PRIMARY KEY(identity, device_id)
+         );
+         INSERT INTO user_by_device (identity, device_id, key, current_pre_key, creation_time, current_pre_key_id, current_pre_key_upload_time)
+             SELECT identity, 1, key, current_pre_key, creation_time, current_pre_key_id, current_pre_key_upload_time FROM user;",
+        )
+        .context("Adding user device id failed.")?;
+    transaction
+        .execute_batch(
+            "CREATE TABLE pre_key_by_device (
+             id INTEGER PRIMARY KEY AUTOINCREMENT,
+             key BLOB NOT NULL UNIQUE,
+             user_identity STRING NOT NULL,
+             device_id INTEGER NOT NULL,
+             creation_time integer NOT NULL,
+             FOREIGN KEY(user_identity, device_id) REFERENCES user_by_device(identity, device_id) ON DELETE CASCADE
+         );
+         INSERT INTO sqlite_sequence (name, seq)
+             SELECT 'pre_key_by_device', seq FROM sqlite_sequence WHERE name = 'pre_key';
+         INSERT INTO pre_key_by_device (id, key, user_identity, device_id, creation_time)
+             SELECT id, key, user_identity, 1, creation_time FROM pre_key;
+         DROP TABLE pre_key;",
+        )
+        .context("Adding pre_key device id failed.")?;
+    transaction
+        .execute_batch(
+            "CREATE TABLE message_by_device (
+             id INTEGER PRIMARY KEY AUTOINCREMENT,
+             message BLOB NOT NULL,
+             user_identity STRING NOT NULL,
+             device_id INTEGER NOT NULL,
+             creation_time integer NOT NULL,
+             FOREIGN KEY(user_identity, device_id) REFERENCES user_by_device(identity, device_id) ON DELETE CASCADE
+         );
+         INSERT INTO sqlite_sequence (name, seq)
+             SELECT 'message_by_device', seq FROM sqlite_sequence WHERE name = 'message';
+         INSERT INTO message_by_device (id, message, user_identity, device_id, creation_time)
+             SELECT id, message, user_identity, 1, creation_time FROM message;
+         DROP TABLE message;",
+        )
+        .context("Adding message device id failed.")?;
+    // Renaming the tables also updates the foreign keys that refer to them.
+    transaction
+        .execute_batch(
+            "DROP TABLE user;
+         ALTER TABLE user_by_device RENAME TO user;
+         ALTER TABLE pre_key_by_device RENAME TO pre_key;
+         ALTER TABLE message_by_device RENAME TO message;
+         CREATE INDEX pre_key_user_identity ON pre_key(user_identity, device_id, creation_time);
+         CREATE INDEX message_user_identity ON message(user_identity, device_id, id);",
+        )
+        .context("Renaming device tables failed.")?;
+    Ok(())
+}
+
+/// Stores the key each device hands out once it runs out of one time keys.
+fn last_resort_keys(transaction: &Transaction) -> Result<()> {
+    transaction
+        .execute_batch(
+            "ALTER TABLE user ADD COLUMN last_resort_key BLOB;
+         ALTER TABLE user ADD COLUMN last_resort_key_id INTEGER NOT NULL DEFAULT 0;",
+        )
+        .context("Adding last resort key failed.")?;
+    Ok(())
+}
+
+/// Stores each device's ML-KEM pre keys for PQXDH. Keys are kept as encoded `SignedPreKey`s.
+fn kem_pre_keys(transaction: &Transaction) -> Result<()> {
+    transaction
+        .execute_batch(
+            "CREATE TABLE kem_pre_key (
+             id INTEGER PRIMARY KEY AUTOINCREMENT,
+             key BLOB NOT NULL,
+             user_identity STRING NOT NULL,
+             device_id INTEGER NOT NULL,
+             FOREIGN KEY(user_identity, device_id) REFERENCES user(identity, device_id) ON DELETE CASCADE
+         );
+         CREATE INDEX kem_pre_key_user_identity ON kem_pre_key(user_identity, device_id, id);
+         ALTER TABLE user ADD COLUMN last_resort_kem_key BLOB;
+         ALTER TABLE user ADD COLUMN last_resort_kem_key_id INTEGER NOT NULL DEFAULT 0;",
+        )
+        .context("Adding kem pre keys failed.")?;
+    Ok(())
+}
+
+/// Stores the cipher suite each device registered with.
+fn cipher_suites(transaction: &Transaction) -> Result<()> {
+    transaction
+        .execute("ALTER TABLE user ADD COLUMN cipher_suite INTEGER", ())
+        .context("Adding cipher suite failed.")?;
+    Ok(())
+}
+
+/// Stores the token each device is woken with while it has no open message stream. Platforms are
+/// stored as their `PushPlatform` proto values. Tokens and versions are `TEXT` rather than
+/// `STRING`, whose numeric affinity would turn a version like "1.0" into a number.
+fn push_tokens(transaction: &Transaction) -> Result<()> {
+    transaction
+        .execute_batch(
+            "CREATE TABLE push_token (
+             user_identity STRING NOT NULL,
+             device_id INTEGER NOT NULL,
+             platform INTEGER NOT NULL,
+             token TEXT NOT NULL,
+             app_version TEXT,
+             PRIMARY KEY(user_identity, device_id),
+             FOREIGN KEY(user_identity, device_id) REFERENCES user(identity, device_id) ON DELETE CASCADE
+         );",
+        )
+        .context("Adding push tokens failed.")?;
+    Ok(())
+}
+
+/// Records the uuid senders picked for each message, so a retried send is recognised even after
+/// the message was delivered. The id is the one the server reports for the message.
+fn message_uuids(transaction: &Transaction) -> Result<()> {
+    transaction
+        .execute_batch(
+            "CREATE TABLE message_uuid (
+             id INTEGER PRIMARY KEY AUTOINCREMENT,
+             user_identity STRING NOT NULL,
+             uuid BLOB NOT NULL,
+             creation_time INTEGER NOT NULL,
+             UNIQUE(user_identity, uuid)
+         );",
+        )
+        .context("Adding message uuids failed.")?;
+    Ok(())
+}
+
+/// Reserves one time keys for the sender they were handed out to rather than deleting them.
+/// `reserved_until` is when the reservation expires, in seconds since the epoch, and is NULL while
+/// the key is available.
+fn pre_key_reservations(transaction: &Transaction) -> Result<()> {
+    transaction
+        .execute("ALTER TABLE pre_key ADD COLUMN reserved_until INTEGER", ())
+        .context("Adding pre key reservations failed.")?;
+    Ok(())
+}
+
+/// Records changes operators make through the Admin service. Rows outlive the users they name.
+fn audit_log(transaction: &Transaction) -> Result<()> {
+    transaction
+        .execute_batch(
+            "CREATE TABLE audit_log (
+             id INTEGER PRIMARY KEY AUTOINCREMENT,
+             actor TEXT NOT NULL,
+             action TEXT NOT NULL,
+             target TEXT NOT NULL,
+             creation_time INTEGER NOT NULL
+         );",
+        )
+        .context("Adding audit log failed.")?;
+    Ok(())
+}
+
+/// Invite codes for registering new identities, by a hash of the code. `expires_at` is in seconds
+/// since the epoch, or NULL for codes that never expire.
+fn invites(transaction: &Transaction) -> Result<()> {
+    transaction
+        .execute_batch(
+            "CREATE TABLE invite (
+             code_hash BLOB PRIMARY KEY,
+             creator TEXT NOT NULL,
+             max_uses INTEGER NOT NULL,
+             uses INTEGER NOT NULL DEFAULT 0,
+             creation_time INTEGER NOT NULL,
+             expires_at INTEGER,
+             revoked INTEGER NOT NULL DEFAULT 0
+         );",
+        )
+        .context("Adding invites failed.")?;
+    Ok(())
+}
+
+/// Stores the form each device registered its identity in, and the identity's skeleton so ones
+/// easily mistaken for it can be refused. Identities registered before they were canonicalized
+/// stay under the key they were registered with; any that no longer parse are their own skeleton.
+fn identity_skeletons(transaction: &Transaction) -> Result<()> {
+    transaction
+        .execute_batch(
+            "ALTER TABLE user ADD COLUMN display_identity TEXT;
+         ALTER TABLE user ADD COLUMN skeleton TEXT;
+         CREATE INDEX user_skeleton ON user(skeleton);",
+        )
+        .context("Adding identity skeletons failed.")?;
+    let identities: Vec<String> = transaction
+        .prepare("SELECT DISTINCT CAST(identity AS TEXT) FROM user")?
+        .query_map((), |row| row.get(0))?
+        .collect::<rusqlite::Result<_>>()?;
+    for identity in identities {
+        let skeleton = Identity::parse("identity", identity.as_str())
+            .map_or_else(|_| identity.clone(), |parsed| parsed.skeleton());
+        transaction
+            .execute(
+                "UPDATE user SET display_identity = ?1, skeleton = ?2 WHERE identity = ?1",
+                params![identity, skeleton],
+            )
+            .with_context(|| format!("Storing the skeleton of {identity} failed."))?;
+    }
+    Ok(())
+}
+
+/// Records whether queued messages are encrypted at rest: no row until the database is first
+/// opened after this migration, then one whose `key_check` is NULL if they aren't, or the sealed
+/// `AT_REST_CHECK` if they are, which only the right key opens.
+fn at_rest_encryption(transaction: &Transaction) -> Result<()> {
+    transaction
+        .execute_batch(
+            "CREATE TABLE at_rest_encryption (
+             id INTEGER PRIMARY KEY CHECK (id = 0),
+             key_check BLOB
+         );",
+        )
+        .context("Adding at rest encryption mode failed.")?;
+    Ok(())
+}
+
+/// Holds the at-rest encryption mode, a setting of the database rather than data in it.
+const AT_REST_TABLE: &str = "at_rest_encryption";
+
+/// Sealed under the at-rest key, if any, so opening the database with another can be refused.
+const AT_REST_CHECK: &[u8] = b"brongnal at rest";
+
+/// Records whether the database is encrypted with `key` the first time it is opened, and refuses
+/// to open it in another mode or with another key after that, rather than mixing the two.
+fn check_at_rest_mode(connection: &rusqlite::Connection, key: Option<&AtRestKey>) -> Result<()> {
+    let recorded: Option<Option<Vec<u8>>> = match connection.query_row(
+        "SELECT key_check FROM at_rest_encryption WHERE id = 0",
+        [],
+        |row| row.get(0),
+    ) {
+        Ok(key_check) => Some(key_check),
+        Err(rusqlite::Error::QueryReturnedNoRows) => None,
+        Err(e) => return Err(e.into()),
+    };
+    match (recorded, key) {
+        (None, key) => {
+            let queued: bool =
+                connection
+                    .query_row("SELECT EXISTS(SELECT 1 FROM message)", [], |row| row.get(0))?;
+            if queued && key.is_some() {
+                bail!(
+                    "the database already holds unencrypted messages; deliver or purge them \
+                     before encrypting it"
+                );
+            }
+            connection.execute(
+                "INSERT INTO at_rest_encryption (id, key_check) VALUES (0, ?1)",
+                [key.map(|key| key.seal(AT_REST_CHECK, b""))],
+            )?;
+        }
+        (Some(None), None) => {}
+        (Some(None), Some(_)) => bail!("the database isn't encrypted, but a key was given"),
+        (Some(Some(_)), None) => bail!("the database is encrypted, but no key was given"),
+        (Some(Some(key_check)), Some(key)) => {
+            if key.open(&key_check, b"").as_deref() != Some(AT_REST_CHECK) {
+                bail!("the database is encrypted with another key");
+            }
+        }
+    }
+    Ok(())
+}
+
+/// What queued messages are bound to when sealed, so they can't be moved to another mailbox.
+fn message_context(identity: &str, device_id: u32) -> Vec<u8> {
+    [identity.as_bytes(), &[0], &device_id.to_be_bytes()].concat()
+}
+
+/// Applies any migrations the database hasn't seen yet, each in its own transaction.
+fn migrate(connection: &mut rusqlite::Connection) -> Result<()> {
+    connection.pragma_update(None, "journal_mode", "WAL")?;
+    connection.pragma_update(None, "synchronous", "normal")?;
+    connection.pragma_update(None, "foreign_keys", "on")?;
+    // Zeroes deleted messages and keys instead of leaving them in free pages.
+    connection.pragma_update(None, "secure_delete", "on")?;
+
+    let version: usize = connection.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    if version > MIGRATIONS.len() {
+        bail!(
+            "database schema version {version} is newer than the latest known version {}",
+            MIGRATIONS.len()
+        );
+    }
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let transaction = connection.transaction()?;
+        migration(&transaction)
+            .with_context(|| format!("Migrating to schema version {} failed.", i + 1))?;
+        transaction.pragma_update(None, "user_version", i + 1)?;
+        transaction.commit()?;
+        info!("Migrated database to schema version {}.", i + 1);
+    }
+    Ok(())
+}
+
+/// Inserts one time keys for `identity`'s device within `transaction`, enforcing `quota`.
+/// Returns the id assigned to each key.
+fn insert_opks(
+    transaction: &Transaction,
+    identity: &str,
+    device_id: u32,
+    opks: &[X25519PublicKey],
+    quota: OpkQuota,
+) -> tonic::Result<Vec<u32>> {
+    let stored: usize = transaction
+        .query_row(
+            "SELECT COUNT(*) FROM pre_key WHERE user_identity = ?1 AND device_id = ?2 AND reserved_until IS NULL",
+            params![identity, device_id],
+            |row| row.get(0),
+        )
+        .map_err(|e| sql_error("failed to count one time keys", e))?;
+    if stored + opks.len() > quota.max_keys {
+        if quota.policy == QuotaPolicy::Reject || opks.len() > quota.max_keys {
+            return Err(Status::resource_exhausted(format!(
+                "{identity} may store at most {} one time keys",
+                quota.max_keys
+            )));
+        }
+        transaction
+            .execute(
+                "DELETE FROM pre_key WHERE rowid IN (SELECT rowid FROM pre_key WHERE user_identity = ?1 AND device_id = ?2 AND reserved_until IS NULL ORDER BY creation_time, rowid LIMIT ?3)",
+                params![identity, device_id, stored + opks.len() - quota.max_keys],
             )
-            .context("Creating message table failed.")?;
+            .map_err(|e| sql_error("failed to evict one time keys", e))?;
+    }
+
+    let mut stmt = transaction
+        .prepare(
+            "INSERT INTO pre_key (user_identity, device_id, key, creation_time) VALUES (?1, ?2, ?3, ?4) RETURNING id",
+        )
+        .map_err(|e| sql_error("failed to insert one time keys", e))?;
+    opks.iter()
+        .map(|opk| {
+            let id: i64 = stmt
+                .query_row(
+                    params![
+                        identity,
+                        device_id,
+                        opk.to_bytes(),
+                        SystemTime::now()
+                            .duration_since(UNIX_EPOCH)
+                            .unwrap()
+                            .as_secs(),
+                    ],
+                    |row| row.get(0),
+                )
+                .map_err(|e| match e.sqlite_error_code() {
+                    Some(ErrorCode::ConstraintViolation) => {
+                        Status::already_exists("one time key already uploaded")
+                    }
+                    _ => sql_error("failed to insert one time key", e),
+                })?;
+            to_pre_key_id(id)
+        })
+        .collect()
+}
 
-        Ok(SqliteStorage(Arc::new(Mutex::new(connection))))
+/// A status for `e`, met while `context`: Unavailable if another connection held the database
+/// for longer than the busy timeout, which is worth retrying, or Internal otherwise.
+fn sql_error(context: &str, e: rusqlite::Error) -> Status {
+    match e.sqlite_error_code() {
+        Some(ErrorCode::DatabaseBusy | ErrorCode::DatabaseLocked) => {
+            Status::unavailable(format!("{context}: database is busy"))
+        }
+        _ => Status::internal(format!("{context}: {e}")),
     }
 }
 
+async fn call_on<F, R>(connection: &Connection, function: F) -> tonic::Result<R>
+where
+    F: FnOnce(&mut rusqlite::Connection) -> tonic::Result<R> + Send + 'static,
+    R: Send + 'static,
+{
+    connection
+        .call(move |connection| Ok(function(connection)))
+        .await
+        .map_err(|e| Status::internal(format!("failed to access sqlite connection: {e}")))?
+}
+
+/// Pre key ids are sent as `uint32`.
+fn to_pre_key_id(id: i64) -> tonic::Result<u32> {
+    u32::try_from(id).map_err(|_| Status::internal(format!("pre key id {id} is out of range")))
+}
+
+impl SqliteStorage {
+    pub async fn new(connection: Connection) -> Result<Self> {
+        SqliteStorage::open(connection, None).await
+    }
+
+    /// Like `new`, but keeps queued messages encrypted under `key`. Fails if the database was
+    /// created without encryption or with another key.
+    pub async fn new_encrypted(connection: Connection, key: AtRestKey) -> Result<Self> {
+        SqliteStorage::open(connection, Some(key)).await
+    }
+
+    async fn open(connection: Connection, at_rest_key: Option<AtRestKey>) -> Result<Self> {
+        let key = at_rest_key.clone();
+        connection
+            .call(move |connection| {
+                connection.busy_timeout(BUSY_TIMEOUT)?;
+                Ok(migrate(connection).and_then(|()| check_at_rest_mode(connection, key.as_ref())))
+            })
+            .await??;
+
+        Ok(SqliteStorage {
+            connection,
+            readers: Vec::new(),
+            next_reader: AtomicUsize::new(0),
+            mailbox_quota: None,
+            at_rest_key,
+        })
+    }
+
+    /// Opens `count` read only connections to the database for queries that don't change it.
+    /// In-memory databases can't be shared between connections, so keep reading on the writer.
+    pub async fn with_readers(mut self, count: usize) -> Result<Self> {
+        let path = self
+            .connection
+            .call(|connection| Ok(connection.path().map(str::to_owned)))
+            .await?;
+        let Some(path) = path.filter(|path| !path.is_empty()) else {
+            return Ok(self);
+        };
+        for _ in 0..count {
+            let reader = Connection::open_with_flags(
+                &path,
+                OpenFlags::SQLITE_OPEN_READ_ONLY
+                    | OpenFlags::SQLITE_OPEN_NO_MUTEX
+                    | OpenFlags::SQLITE_OPEN_URI,
+            )
+            .await?;
+            reader
+                .call(|connection| Ok(connection.busy_timeout(BUSY_TIMEOUT)?))
+                .await?;
+            self.readers.push(reader);
+        }
+        Ok(self)
+    }
+
+    pub fn with_mailbox_quota(mut self, quota: MailboxQuota) -> Self {
+        self.mailbox_quota = Some(quota);
+        self
+    }
+
+    /// Encodes `message` for storing in `identity`'s device's queue, sealed if messages are kept
+    /// encrypted.
+    fn seal_message(&self, identity: &str, device_id: u32, message: &MessageProto) -> Vec<u8> {
+        let encoded = message.encode_to_vec();
+        match &self.at_rest_key {
+            Some(key) => key.seal(&encoded, &message_context(identity, device_id)),
+            None => encoded,
+        }
+    }
+
+    /// Decodes a message `seal_message` stored in `identity`'s device's queue.
+    fn open_message(
+        &self,
+        identity: &str,
+        device_id: u32,
+        stored: &[u8],
+    ) -> tonic::Result<MessageProto> {
+        let encoded = match &self.at_rest_key {
+            Some(key) => Cow::Owned(
+                key.open(stored, &message_context(identity, device_id))
+                    .ok_or_else(|| Status::internal("failed to decrypt stored message"))?,
+            ),
+            None => Cow::Borrowed(stored),
+        };
+        MessageProto::decode(&*encoded)
+            .map_err(|_| Status::internal("Failed to deserialize Message proto"))
+    }
+
+    /// Runs `function` on the connection's background thread so queries don't block the runtime.
+    /// `function` runs to completion even if the returned future is dropped, e.g. when its RPC
+    /// times out, so each transaction must begin and end within a single call.
+    async fn call<F, R>(&self, function: F) -> tonic::Result<R>
+    where
+        F: FnOnce(&mut rusqlite::Connection) -> tonic::Result<R> + Send + 'static,
+        R: Send + 'static,
+    {
+        call_on(&self.connection, function).await
+    }
+
+    /// Runs `function`, which must not change the database, like `call` but on the next reader
+    /// in turn, if there are any.
+    async fn read<F, R>(&self, function: F) -> tonic::Result<R>
+    where
+        F: FnOnce(&mut rusqlite::Connection) -> tonic::Result<R> + Send + 'static,
+        R: Send + 'static,
+    {
+        if self.readers.is_empty() {
+            return self.call(function).await;
+        }
+        let next = self.next_reader.fetch_add(1, Ordering::Relaxed);
+        call_on(&self.readers[next % self.readers.len()], function).await
+    }
+
+    /// Runs `function` like `call`, trying it again with backoff while it fails because another
+    /// connection holds the database. Errors other than Unavailable, e.g. constraint violations,
+    /// are returned at once.
+    async fn write<F, R>(&self, mut function: F) -> tonic::Result<R>
+    where
+        F: FnMut(&mut rusqlite::Connection) -> tonic::Result<R> + Send + 'static,
+        R: Send + 'static,
+    {
+        let mut backoff = BUSY_BACKOFF;
+        for _ in 0..BUSY_RETRIES {
+            let (returned, result) = self
+                .call(move |connection| {
+                    let result = function(connection);
+                    Ok((function, result))
+                })
+                .await?;
+            match result {
+                Err(status) if status.code() == Code::Unavailable => {
+                    debug!(
+                        "Database is busy, retrying in {backoff:?}: {}",
+                        status.message()
+                    );
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                    function = returned;
+                }
+                result => return result,
+            }
+        }
+        self.call(function).await
+    }
+}
+
+#[tonic::async_trait]
 impl Storage for SqliteStorage {
-    fn register_user(
+    async fn register_user(
         &self,
-        identity: String,
+        identity: Identity,
+        device_id: u32,
         ik: VerifyingKey,
         spk: SignedPreKeyProto,
+    ) -> tonic::Result<u32> {
+        debug!("Adding user \"{identity}\" device {device_id} to the database.");
+
+        let display = identity.display_name().to_owned();
+        let skeleton = identity.skeleton();
+        let identity = String::from(identity);
+        self.write(move |connection| {
+            // Take the write lock up front so a lookalike can't register between the check and
+            // the insert.
+            let transaction = connection
+                .transaction_with_behavior(TransactionBehavior::Immediate)
+                .map_err(|e| sql_error("failed to start transaction", e))?;
+            match transaction.query_row(
+                "SELECT display_identity FROM user WHERE skeleton = ?1 AND (identity != ?2 OR display_identity != ?3) AND NOT (identity = ?2 AND device_id = ?4) LIMIT 1",
+                params![skeleton, identity, display, device_id],
+                |row| row.get::<_, String>(0),
+            ) {
+                Ok(taken) => {
+                    return Err(Status::already_exists(format!(
+                        "{display} is too similar to the registered identity {taken}"
+                    )))
+                }
+                Err(rusqlite::Error::QueryReturnedNoRows) => {}
+                Err(e) => return Err(sql_error("failed to query for user", e)),
+            }
+            // The signed pre key id and upload time only advance when the key changes.
+            let id: i64 = transaction.query_row(
+                "INSERT INTO user (identity, device_id, key, current_pre_key, creation_time, current_pre_key_upload_time, display_identity, skeleton) VALUES (?1, ?2, ?3, ?4, ?5, ?5, ?6, ?7)
+                 ON CONFLICT(identity, device_id) DO UPDATE SET key = excluded.key, current_pre_key = excluded.current_pre_key, creation_time = excluded.creation_time,
+                     current_pre_key_id = current_pre_key_id + (current_pre_key != excluded.current_pre_key),
+                     current_pre_key_upload_time = CASE WHEN current_pre_key != excluded.current_pre_key THEN excluded.current_pre_key_upload_time ELSE current_pre_key_upload_time END,
+                     display_identity = excluded.display_identity, skeleton = excluded.skeleton
+                 RETURNING current_pre_key_id",
+                params![
+                    identity, device_id, ik.to_bytes(), spk.encode_to_vec(),
+                    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
+                    display, skeleton,
+                ],
+                |row| row.get(0),
+            ).map_err(|e| sql_error("failed to insert user", e))?;
+            transaction
+                .commit()
+                .map_err(|e| sql_error("failed to commit user", e))?;
+            to_pre_key_id(id)
+        })
+        .await
+    }
+
+    async fn update_spk(
+        &self,
+        identity: &str,
+        device_id: u32,
+        spk: SignedPreKeyProto,
+    ) -> tonic::Result<u32> {
+        debug!("Updating pre key for user \"{identity}\" to the database.");
+
+        let identity = identity.to_owned();
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        self.write(move |connection| {
+            let id: i64 = connection
+                .query_row(
+                    "UPDATE user SET current_pre_key = ?3, current_pre_key_id = current_pre_key_id + (current_pre_key != ?3),
+                         current_pre_key_upload_time = CASE WHEN current_pre_key != ?3 THEN ?4 ELSE current_pre_key_upload_time END
+                     WHERE identity = ?1 AND device_id = ?2 RETURNING current_pre_key_id",
+                    params![identity, device_id, spk.encode_to_vec(), now],
+                    |row| row.get(0),
+                )
+                .map_err(|e| match e {
+                    rusqlite::Error::QueryReturnedNoRows => Status::not_found("user not found"),
+                    e => sql_error("failed to update signed pre key", e),
+                })?;
+            to_pre_key_id(id)
+        })
+        .await
+    }
+
+    async fn add_opks(
+        &self,
+        identity: &str,
+        device_id: u32,
+        opks: Vec<X25519PublicKey>,
+        quota: OpkQuota,
+    ) -> tonic::Result<Vec<u32>> {
+        debug!(
+            "Adding {} one time keys for user \"{identity}\" to the database.",
+            opks.len()
+        );
+
+        let identity = identity.to_owned();
+        self.write(move |connection| {
+            let transaction = connection
+                .transaction()
+                .map_err(|e| sql_error("failed to start transaction", e))?;
+            let ids = insert_opks(&transaction, &identity, device_id, &opks, quota)?;
+            transaction
+                .commit()
+                .map_err(|e| sql_error("failed to commit one time keys", e))?;
+            Ok(ids)
+        })
+        .await
+    }
+
+    async fn replace_opks(
+        &self,
+        identity: &str,
+        device_id: u32,
+        opks: Vec<X25519PublicKey>,
+        quota: OpkQuota,
+    ) -> tonic::Result<Vec<u32>> {
+        debug!(
+            "Replacing one time keys for user \"{identity}\" with {} new keys in the database.",
+            opks.len()
+        );
+
+        let identity = identity.to_owned();
+        self.write(move |connection| {
+            let transaction = connection
+                .transaction()
+                .map_err(|e| sql_error("failed to start transaction", e))?;
+            transaction
+                .execute(
+                    "DELETE FROM pre_key WHERE user_identity = ?1 AND device_id = ?2",
+                    params![identity, device_id],
+                )
+                .map_err(|e| sql_error("failed to delete one time keys", e))?;
+            let ids = insert_opks(&transaction, &identity, device_id, &opks, quota)?;
+            transaction
+                .commit()
+                .map_err(|e| sql_error("failed to commit one time keys", e))?;
+            Ok(ids)
+        })
+        .await
+    }
+
+    async fn count_opks(&self, identity: &str, device_id: u32) -> tonic::Result<usize> {
+        let identity = identity.to_owned();
+        self.read(move |connection| {
+            connection
+                .query_row(
+                    "SELECT COUNT(*) FROM pre_key WHERE user_identity = ?1 AND device_id = ?2 AND reserved_until IS NULL",
+                    params![identity, device_id],
+                    |row| row.get(0),
+                )
+                .map_err(|e| sql_error("failed to count one time keys", e))
+        })
+        .await
+    }
+
+    async fn get_opks(
+        &self,
+        identity: &str,
+        device_id: u32,
+    ) -> tonic::Result<Vec<(u32, X25519PublicKey)>> {
+        let identity = identity.to_owned();
+        let rows: Vec<(i64, [u8; 32])> = self
+            .read(move |connection| {
+                let mut stmt = connection
+                    .prepare("SELECT id, key FROM pre_key WHERE user_identity = ?1 AND device_id = ?2 AND reserved_until IS NULL ORDER BY id")
+                    .map_err(|e| sql_error("failed to query for one time keys", e))?;
+                let rows = stmt
+                    .query_map(params![identity, device_id], |row| Ok((row.get(0)?, row.get(1)?)))
+                    .and_then(|rows| rows.collect())
+                    .map_err(|e| sql_error("failed to query for one time keys", e));
+                rows
+            })
+            .await?;
+        rows.into_iter()
+            .map(|(id, key)| Ok((to_pre_key_id(id)?, X25519PublicKey::from(key))))
+            .collect()
+    }
+
+    async fn user_exists(&self, identity: &str) -> tonic::Result<bool> {
+        let identity = identity.to_owned();
+        self.read(move |connection| {
+            connection
+                .query_row(
+                    "SELECT EXISTS(SELECT 1 FROM user WHERE identity = ?1)",
+                    [identity],
+                    |row| row.get(0),
+                )
+                .map_err(|e| sql_error("failed to query for user", e))
+        })
+        .await
+    }
+
+    async fn get_devices(&self, identity: &str) -> tonic::Result<Vec<u32>> {
+        let identity = identity.to_owned();
+        self.read(move |connection| {
+            let mut stmt = connection
+                .prepare("SELECT device_id FROM user WHERE identity = ?1 ORDER BY device_id")
+                .map_err(|e| sql_error("failed to query for devices", e))?;
+            let devices = stmt
+                .query_map([identity], |row| row.get(0))
+                .and_then(|rows| rows.collect())
+                .map_err(|e| sql_error("failed to query for devices", e));
+            devices
+        })
+        .await
+    }
+
+    async fn get_current_keys(&self, identity: &str, device_id: u32) -> tonic::Result<CurrentKeys> {
+        debug!("Retrieving pre keys for user \"{identity}\" device {device_id} from the database.");
+
+        let identity = identity.to_owned();
+        let (ik, spk, spk_id, spk_uploaded_at, cipher_suite): (
+            Vec<u8>,
+            Vec<u8>,
+            i64,
+            u64,
+            Option<u32>,
+        ) = self
+            .read(move |connection| {
+                connection
+                    .query_row(
+                        "SELECT key, current_pre_key, current_pre_key_id, current_pre_key_upload_time, cipher_suite FROM user WHERE identity = ?1 AND device_id = ?2",
+                        params![identity, device_id],
+                        |row| Ok((row.get(0).unwrap(), row.get(1).unwrap(), row.get(2)?, row.get(3)?, row.get(4)?)),
+                    )
+                    .map_err(|e| match e {
+                        rusqlite::Error::QueryReturnedNoRows => Status::not_found("user not found"),
+                        e => sql_error("failed to query for keys", e),
+                    })
+            })
+            .await?;
+        let ik = parse_verifying_key("identity_key", &ik)
+            .map_err(|e| Status::internal(format!("stored key is corrupt: {e}")))?;
+        let spk = SignedPreKeyProto::decode(&*spk).unwrap();
+        Ok(CurrentKeys {
+            ik,
+            spk,
+            spk_id: to_pre_key_id(spk_id)?,
+            spk_uploaded_at: UNIX_EPOCH + Duration::from_secs(spk_uploaded_at),
+            cipher_suite,
+        })
+    }
+
+    async fn set_cipher_suite(
+        &self,
+        identity: &str,
+        device_id: u32,
+        cipher_suite: Option<u32>,
     ) -> tonic::Result<()> {
-        println!("Adding user \"{identity}\" to the database.");
+        debug!("Setting cipher suite for user \"{identity}\" device {device_id} in the database.");
 
-        let _ = self.connection()?.execute(
-            "INSERT INTO user (identity, key, current_pre_key, creation_time) VALUES (?1, ?2, ?3, ?4)",
-            (
-                identity, ik.to_bytes(), spk.encode_to_vec(),
-                SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
-            ),
-        ).context("failed to insert key.");
-        Ok(())
+        let identity = identity.to_owned();
+        self.write(move |connection| {
+            let updated = connection
+                .execute(
+                    "UPDATE user SET cipher_suite = ?3 WHERE identity = ?1 AND device_id = ?2",
+                    params![identity, device_id, cipher_suite],
+                )
+                .map_err(|e| sql_error("failed to set cipher suite", e))?;
+            if updated == 0 {
+                return Err(Status::not_found("user not found"));
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    async fn reserve_opk(
+        &self,
+        identity: &str,
+        device_id: u32,
+        until: SystemTime,
+    ) -> tonic::Result<Option<(u32, X25519PublicKey)>> {
+        debug!("Reserving one time key for user \"{identity}\" in the database.");
+
+        let identity = identity.to_owned();
+        let until = until.duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let opk: Option<(i64, [u8; 32])> = self
+            .write(move |connection| {
+                // Take the write lock up front so concurrent reservations can't select the same key.
+                let transaction = connection
+                    .transaction_with_behavior(TransactionBehavior::Immediate)
+                    .map_err(|e| sql_error("failed to start transaction", e))?;
+                let opk = match transaction.query_row(
+                    "UPDATE pre_key SET reserved_until = ?3 WHERE rowid = (SELECT rowid FROM pre_key WHERE user_identity = ?1 AND device_id = ?2 AND reserved_until IS NULL ORDER BY creation_time, rowid LIMIT 1) RETURNING id, key",
+                    params![identity, device_id, until],
+                    |row| Ok((row.get(0)?, row.get(1)?)),
+                ) {
+                    Ok(value) => Some(value),
+                    Err(rusqlite::Error::QueryReturnedNoRows) => None,
+                    Err(e) => {
+                        return Err(sql_error("failed to query for pre_key", e))
+                    }
+                };
+                transaction.commit().map_err(|e| {
+                    sql_error("failed to commit pre_key reservation", e)
+                })?;
+                Ok(opk)
+            })
+            .await?;
+
+        opk.map(|(id, key)| Ok((to_pre_key_id(id)?, X25519PublicKey::from(key))))
+            .transpose()
+    }
+
+    async fn consume_opk(&self, identity: &str, device_id: u32, id: u32) -> tonic::Result<bool> {
+        let identity = identity.to_owned();
+        self.write(move |connection| {
+            let consumed = connection
+                .execute(
+                    "DELETE FROM pre_key WHERE user_identity = ?1 AND device_id = ?2 AND id = ?3 AND reserved_until IS NOT NULL",
+                    params![identity, device_id, id],
+                )
+                .map_err(|e| sql_error("failed to consume one time key", e))?;
+            Ok(consumed > 0)
+        })
+        .await
+    }
+
+    async fn release_expired_opk_reservations(&self, before: SystemTime) -> tonic::Result<usize> {
+        let before = before.duration_since(UNIX_EPOCH).unwrap().as_secs();
+        self.write(move |connection| {
+            connection
+                .execute(
+                    "UPDATE pre_key SET reserved_until = NULL WHERE reserved_until < ?1",
+                    [before],
+                )
+                .map_err(|e| sql_error("failed to release one time key reservations", e))
+        })
+        .await
+    }
+
+    async fn set_last_resort_key(
+        &self,
+        identity: &str,
+        device_id: u32,
+        key: Option<X25519PublicKey>,
+    ) -> tonic::Result<u32> {
+        debug!(
+            "Setting last resort key for user \"{identity}\" device {device_id} in the database."
+        );
+
+        let identity = identity.to_owned();
+        self.write(move |connection| {
+            let id: i64 = connection
+                .query_row(
+                    "UPDATE user SET last_resort_key = ?3, last_resort_key_id = last_resort_key_id + (last_resort_key IS NOT ?3)
+                     WHERE identity = ?1 AND device_id = ?2 RETURNING last_resort_key_id",
+                    params![identity, device_id, key.map(|key| key.to_bytes())],
+                    |row| row.get(0),
+                )
+                .map_err(|e| match e {
+                    rusqlite::Error::QueryReturnedNoRows => Status::not_found("user not found"),
+                    e => sql_error("failed to set last resort key", e),
+                })?;
+            to_pre_key_id(id)
+        })
+        .await
+    }
+
+    async fn get_last_resort_key(
+        &self,
+        identity: &str,
+        device_id: u32,
+    ) -> tonic::Result<Option<(u32, X25519PublicKey)>> {
+        let identity = identity.to_owned();
+        let key: Option<(i64, Option<[u8; 32]>)> = self
+            .read(move |connection| {
+                match connection.query_row(
+                    "SELECT last_resort_key_id, last_resort_key FROM user WHERE identity = ?1 AND device_id = ?2",
+                    params![identity, device_id],
+                    |row| Ok((row.get(0)?, row.get(1)?)),
+                ) {
+                    Ok(value) => Ok(Some(value)),
+                    Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+                    Err(e) => Err(sql_error("failed to query for last resort key", e)),
+                }
+            })
+            .await?;
+        match key {
+            Some((id, Some(key))) => Ok(Some((to_pre_key_id(id)?, X25519PublicKey::from(key)))),
+            _ => Ok(None),
+        }
+    }
+
+    async fn replace_kem_opks(
+        &self,
+        identity: &str,
+        device_id: u32,
+        pre_keys: Vec<SignedPreKeyProto>,
+    ) -> tonic::Result<Vec<u32>> {
+        debug!(
+            "Replacing one time kem keys for user \"{identity}\" with {} new keys in the database.",
+            pre_keys.len()
+        );
+
+        let identity = identity.to_owned();
+        self.write(move |connection| {
+            let transaction = connection
+                .transaction()
+                .map_err(|e| sql_error("failed to start transaction", e))?;
+            let registered: bool = transaction
+                .query_row(
+                    "SELECT EXISTS(SELECT 1 FROM user WHERE identity = ?1 AND device_id = ?2)",
+                    params![identity, device_id],
+                    |row| row.get(0),
+                )
+                .map_err(|e| sql_error("failed to query for user", e))?;
+            if !registered {
+                return Err(Status::not_found("user not found"));
+            }
+            transaction
+                .execute(
+                    "DELETE FROM kem_pre_key WHERE user_identity = ?1 AND device_id = ?2",
+                    params![identity, device_id],
+                )
+                .map_err(|e| {
+                    sql_error("failed to delete one time kem keys", e)
+                })?;
+            let ids = {
+                let mut stmt = transaction
+                    .prepare(
+                        "INSERT INTO kem_pre_key (user_identity, device_id, key) VALUES (?1, ?2, ?3) RETURNING id",
+                    )
+                    .map_err(|e| sql_error("failed to insert one time kem keys", e))?;
+                pre_keys
+                    .iter()
+                    .map(|key| {
+                        let id: i64 = stmt
+                            .query_row(
+                                params![identity, device_id, key.encode_to_vec()],
+                                |row| row.get(0),
+                            )
+                            .map_err(|e| sql_error("failed to insert one time kem key", e))?;
+                        to_pre_key_id(id)
+                    })
+                    .collect::<tonic::Result<Vec<u32>>>()?
+            };
+            transaction.commit().map_err(|e| {
+                sql_error("failed to commit one time kem keys", e)
+            })?;
+            Ok(ids)
+        })
+        .await
+    }
+
+    async fn pop_kem_opk(
+        &self,
+        identity: &str,
+        device_id: u32,
+    ) -> tonic::Result<Option<(u32, SignedPreKeyProto)>> {
+        debug!("Popping one time kem key for user \"{identity}\" from the database.");
+
+        let identity = identity.to_owned();
+        let key: Option<(i64, Vec<u8>)> = self
+            .write(move |connection| {
+                // A single statement, so concurrent pops can't select the same key.
+                match connection.query_row(
+                    "DELETE FROM kem_pre_key WHERE id = (SELECT id FROM kem_pre_key WHERE user_identity = ?1 AND device_id = ?2 ORDER BY id LIMIT 1) RETURNING id, key",
+                    params![identity, device_id],
+                    |row| Ok((row.get(0)?, row.get(1)?)),
+                ) {
+                    Ok(value) => Ok(Some(value)),
+                    Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+                    Err(e) => Err(sql_error("failed to query for kem_pre_key", e)),
+                }
+            })
+            .await?;
+        key.map(|(id, key)| {
+            Ok((
+                to_pre_key_id(id)?,
+                SignedPreKeyProto::decode(&*key)
+                    .map_err(|_| Status::internal("stored kem key is malformed"))?,
+            ))
+        })
+        .transpose()
+    }
+
+    async fn set_last_resort_kem_key(
+        &self,
+        identity: &str,
+        device_id: u32,
+        key: Option<SignedPreKeyProto>,
+    ) -> tonic::Result<u32> {
+        debug!(
+            "Setting last resort kem key for user \"{identity}\" device {device_id} in the database."
+        );
+
+        let identity = identity.to_owned();
+        self.write(move |connection| {
+            let id: i64 = connection
+                .query_row(
+                    "UPDATE user SET last_resort_kem_key = ?3, last_resort_kem_key_id = last_resort_kem_key_id + (last_resort_kem_key IS NOT ?3)
+                     WHERE identity = ?1 AND device_id = ?2 RETURNING last_resort_kem_key_id",
+                    params![identity, device_id, key.as_ref().map(|key| key.encode_to_vec())],
+                    |row| row.get(0),
+                )
+                .map_err(|e| match e {
+                    rusqlite::Error::QueryReturnedNoRows => Status::not_found("user not found"),
+                    e => sql_error("failed to set last resort kem key", e),
+                })?;
+            to_pre_key_id(id)
+        })
+        .await
+    }
+
+    async fn get_last_resort_kem_key(
+        &self,
+        identity: &str,
+        device_id: u32,
+    ) -> tonic::Result<Option<(u32, SignedPreKeyProto)>> {
+        let identity = identity.to_owned();
+        let key: Option<(i64, Option<Vec<u8>>)> = self
+            .read(move |connection| {
+                match connection.query_row(
+                    "SELECT last_resort_kem_key_id, last_resort_kem_key FROM user WHERE identity = ?1 AND device_id = ?2",
+                    params![identity, device_id],
+                    |row| Ok((row.get(0)?, row.get(1)?)),
+                ) {
+                    Ok(value) => Ok(Some(value)),
+                    Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+                    Err(e) => Err(sql_error("failed to query for last resort kem key", e)),
+                }
+            })
+            .await?;
+        match key {
+            Some((id, Some(key))) => Ok(Some((
+                to_pre_key_id(id)?,
+                SignedPreKeyProto::decode(&*key)
+                    .map_err(|_| Status::internal("stored kem key is malformed"))?,
+            ))),
+            _ => Ok(None),
+        }
+    }
+
+    async fn get_kem_opks(
+        &self,
+        identity: &str,
+        device_id: u32,
+    ) -> tonic::Result<Vec<(u32, SignedPreKeyProto)>> {
+        let identity = identity.to_owned();
+        let rows: Vec<(i64, Vec<u8>)> = self
+            .read(move |connection| {
+                let mut stmt = connection
+                    .prepare("SELECT id, key FROM kem_pre_key WHERE user_identity = ?1 AND device_id = ?2 ORDER BY id")
+                    .map_err(|e| sql_error("failed to query for kem_pre_key", e))?;
+                let rows = stmt
+                    .query_map(params![identity, device_id], |row| Ok((row.get(0)?, row.get(1)?)))
+                    .and_then(|rows| rows.collect())
+                    .map_err(|e| sql_error("failed to query for kem_pre_key", e));
+                rows
+            })
+            .await?;
+        rows.into_iter()
+            .map(|(id, key)| {
+                Ok((
+                    to_pre_key_id(id)?,
+                    SignedPreKeyProto::decode(&*key)
+                        .map_err(|_| Status::internal("stored kem key is malformed"))?,
+                ))
+            })
+            .collect()
+    }
+
+    async fn add_message(
+        &self,
+        recipient: &str,
+        device_id: u32,
+        message: MessageProto,
+    ) -> tonic::Result<()> {
+        debug!("Enqueueing message for user {recipient} device {device_id} in database.");
+
+        let stored = self.seal_message(recipient, device_id, &message);
+        let recipient = recipient.to_owned();
+        let mailbox_quota = self.mailbox_quota;
+        self.write(move |connection| {
+            let transaction = connection
+                .transaction()
+                .map_err(|e| sql_error("failed to start transaction", e))?;
+
+            if let Some(quota) = &mailbox_quota {
+                let queued: usize = transaction
+                    .query_row(
+                        "SELECT COUNT(*) FROM message WHERE user_identity = ?1 AND device_id = ?2",
+                        params![recipient, device_id],
+                        |row| row.get(0),
+                    )
+                    .map_err(|e| sql_error("failed to count messages", e))?;
+                if queued >= quota.max_messages {
+                    match quota.policy {
+                        QuotaPolicy::Reject => {
+                            return Err(Status::resource_exhausted(format!(
+                                "mailbox for {recipient} is full"
+                            )))
+                        }
+                        QuotaPolicy::EvictOldest => {
+                            transaction
+                                .execute(
+                                    "DELETE FROM message WHERE id IN (SELECT id FROM message WHERE user_identity = ?1 AND device_id = ?2 ORDER BY id LIMIT ?3)",
+                                    params![recipient, device_id, queued + 1 - quota.max_messages],
+                                )
+                                .map_err(|e| {
+                                    sql_error("failed to evict messages", e)
+                                })?;
+                        }
+                    }
+                }
+            }
+
+            let _: u64 = transaction
+                .query_row(
+                    "INSERT INTO message (message, user_identity, device_id, creation_time) VALUES (?1, ?2, ?3, ?4) RETURNING creation_time",
+                    params![
+                        stored,
+                        &recipient,
+                        device_id,
+                        SystemTime::now()
+                            .duration_since(UNIX_EPOCH)
+                            .unwrap()
+                            .as_secs(),
+                    ],|row| Ok(row.get(0)?),
+                )
+                .map_err(|e| sql_error("failed to insert message", e))?;
+            transaction
+                .commit()
+                .map_err(|e| sql_error("failed to commit message", e))?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn get_messages(
+        &self,
+        identity: &str,
+        device_id: u32,
+    ) -> tonic::Result<Vec<MessageProto>> {
+        debug!("Retrieving messages for \"{identity}\" device {device_id} from the database.");
+
+        let recipient = identity.to_owned();
+        let mut rows: Vec<(i64, Vec<u8>)> = self
+            .write(move |connection| {
+                let mut stmt = connection
+                    .prepare("DELETE from message WHERE user_identity = ?1 AND device_id = ?2 RETURNING id, message")
+                    .map_err(|e| sql_error("failed to take messages", e))?;
+                stmt.query_map(params![recipient, device_id], |row| Ok((row.get(0)?, row.get(1)?)))
+                    .and_then(|rows| rows.collect())
+                    .map_err(|e| sql_error("failed to take messages", e))
+            })
+            .await?;
+        // RETURNING yields rows in an arbitrary order, so restore the order they were enqueued in.
+        rows.sort_by_key(|(id, _)| *id);
+        rows.iter()
+            .map(|(_, message)| self.open_message(identity, device_id, message))
+            .collect()
+    }
+
+    async fn peek_messages(
+        &self,
+        identity: &str,
+        device_id: u32,
+    ) -> tonic::Result<Vec<QueuedMessage>> {
+        let recipient = identity.to_owned();
+        let rows: Vec<(u64, Vec<u8>)> = self
+            .read(move |connection| {
+                let mut stmt = connection
+                    .prepare("SELECT creation_time, message FROM message WHERE user_identity = ?1 AND device_id = ?2 ORDER BY id")
+                    .map_err(|e| sql_error("failed to query for messages", e))?;
+                let rows = stmt
+                    .query_map(params![recipient, device_id], |row| Ok((row.get(0)?, row.get(1)?)))
+                    .and_then(|rows| rows.collect())
+                    .map_err(|e| sql_error("failed to query for messages", e));
+                rows
+            })
+            .await?;
+        rows.into_iter()
+            .map(|(queued_at, message)| {
+                Ok(QueuedMessage {
+                    queued_at: UNIX_EPOCH + Duration::from_secs(queued_at),
+                    message: self.open_message(identity, device_id, &message)?,
+                })
+            })
+            .collect()
+    }
+
+    async fn claim_message_uuid(
+        &self,
+        recipient: &str,
+        uuid: &[u8],
+    ) -> tonic::Result<MessageClaim> {
+        let recipient = recipient.to_owned();
+        let uuid = uuid.to_vec();
+        let (id, new): (i64, bool) = self
+            .write(move |connection| {
+                let transaction = connection
+                    .transaction()
+                    .map_err(|e| sql_error("failed to start transaction", e))?;
+                let inserted = transaction.query_row(
+                    "INSERT INTO message_uuid (user_identity, uuid, creation_time) VALUES (?1, ?2, ?3)
+                     ON CONFLICT(user_identity, uuid) DO NOTHING RETURNING id",
+                    params![
+                        recipient,
+                        uuid,
+                        SystemTime::now()
+                            .duration_since(UNIX_EPOCH)
+                            .unwrap()
+                            .as_secs()
+                    ],
+                    |row| row.get(0),
+                );
+                let claim = match inserted {
+                    Ok(id) => (id, true),
+                    Err(rusqlite::Error::QueryReturnedNoRows) => {
+                        let id = transaction
+                            .query_row(
+                                "SELECT id FROM message_uuid WHERE user_identity = ?1 AND uuid = ?2",
+                                params![recipient, uuid],
+                                |row| row.get(0),
+                            )
+                            .map_err(|e| {
+                                sql_error("failed to get message uuid", e)
+                            })?;
+                        (id, false)
+                    }
+                    Err(e) => {
+                        return Err(sql_error("failed to claim message uuid", e))
+                    }
+                };
+                transaction
+                    .commit()
+                    .map_err(|e| sql_error("failed to commit message uuid", e))?;
+                Ok(claim)
+            })
+            .await?;
+        let id = u64::try_from(id)
+            .map_err(|_| Status::internal(format!("message id {id} is out of range")))?;
+        Ok(if new {
+            MessageClaim::New(id)
+        } else {
+            MessageClaim::Duplicate(id)
+        })
+    }
+
+    async fn release_message_uuid(&self, recipient: &str, uuid: &[u8]) -> tonic::Result<()> {
+        let recipient = recipient.to_owned();
+        let uuid = uuid.to_vec();
+        self.write(move |connection| {
+            connection
+                .execute(
+                    "DELETE FROM message_uuid WHERE user_identity = ?1 AND uuid = ?2",
+                    params![recipient, uuid],
+                )
+                .map_err(|e| sql_error("failed to release message uuid", e))?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn set_push_token(
+        &self,
+        identity: &str,
+        device_id: u32,
+        token: PushToken,
+    ) -> tonic::Result<()> {
+        debug!("Setting push token for user \"{identity}\" device {device_id} in the database.");
+
+        let identity = identity.to_owned();
+        self.write(move |connection| {
+            connection
+                .execute(
+                    "INSERT INTO push_token (user_identity, device_id, platform, token, app_version) VALUES (?1, ?2, ?3, ?4, ?5)
+                     ON CONFLICT(user_identity, device_id) DO UPDATE SET platform = excluded.platform, token = excluded.token, app_version = excluded.app_version",
+                    params![
+                        identity,
+                        device_id,
+                        PushPlatformProto::from(token.platform) as i32,
+                        token.token,
+                        token.app_version
+                    ],
+                )
+                .map_err(|e| match e.sqlite_error_code() {
+                    Some(rusqlite::ErrorCode::ConstraintViolation) => {
+                        Status::not_found("user not found")
+                    }
+                    _ => sql_error("failed to set push token", e),
+                })?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn get_push_token(
+        &self,
+        identity: &str,
+        device_id: u32,
+    ) -> tonic::Result<Option<PushToken>> {
+        let identity = identity.to_owned();
+        let row: Option<(i32, String, Option<String>)> = self
+            .read(move |connection| {
+                match connection.query_row(
+                    "SELECT platform, token, app_version FROM push_token WHERE user_identity = ?1 AND device_id = ?2",
+                    params![identity, device_id],
+                    |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+                ) {
+                    Ok(value) => Ok(Some(value)),
+                    Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+                    Err(e) => Err(sql_error("failed to get push token", e)),
+                }
+            })
+            .await?;
+        row.map(|(platform, token, app_version)| {
+            let platform = PushPlatformProto::try_from(platform)
+                .map_err(|_| Status::internal("stored push platform is invalid"))?;
+            Ok(PushToken {
+                platform: platform.try_into()?,
+                token,
+                app_version,
+            })
+        })
+        .transpose()
+    }
+
+    async fn delete_push_token(
+        &self,
+        identity: &str,
+        device_id: u32,
+        token: &str,
+    ) -> tonic::Result<()> {
+        debug!("Deleting push token for user \"{identity}\" device {device_id} from the database.");
+
+        let identity = identity.to_owned();
+        let token = token.to_owned();
+        self.write(move |connection| {
+            connection
+                .execute(
+                    "DELETE FROM push_token WHERE user_identity = ?1 AND device_id = ?2 AND token = ?3",
+                    params![identity, device_id, token],
+                )
+                .map_err(|e| sql_error("failed to delete push token", e))?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn delete_device(&self, identity: &str, device_id: u32) -> tonic::Result<()> {
+        debug!("Deleting user \"{identity}\" device {device_id} from the database.");
+
+        let identity = identity.to_owned();
+        self.write(move |connection| {
+            let deleted = connection
+                .execute(
+                    "DELETE FROM user WHERE identity = ?1 AND device_id = ?2",
+                    params![identity, device_id],
+                )
+                .map_err(|e| sql_error("failed to delete device", e))?;
+            if deleted == 0 {
+                return Err(Status::not_found("user not found"));
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    async fn delete_user(&self, identity: &str) -> tonic::Result<()> {
+        debug!("Deleting user \"{identity}\" from the database.");
+
+        let identity = identity.to_owned();
+        self.write(move |connection| {
+            let transaction = connection
+                .transaction()
+                .map_err(|e| sql_error("failed to start transaction", e))?;
+            let deleted = transaction
+                .execute("DELETE FROM user WHERE identity = ?1", [&identity])
+                .map_err(|e| sql_error("failed to delete user", e))?;
+            if deleted == 0 {
+                return Err(Status::not_found("user not found"));
+            }
+            // Not keyed to the user row, so the cascade leaves them behind.
+            transaction
+                .execute(
+                    "DELETE FROM message_uuid WHERE user_identity = ?1",
+                    [&identity],
+                )
+                .map_err(|e| sql_error("failed to delete message uuids", e))?;
+            transaction
+                .commit()
+                .map_err(|e| sql_error("failed to commit user deletion", e))
+        })
+        .await
+    }
+
+    async fn purge_messages(&self, identity: &str) -> tonic::Result<usize> {
+        debug!("Purging messages for \"{identity}\" from the database.");
+
+        let identity = identity.to_owned();
+        self.write(move |connection| {
+            let exists: bool = connection
+                .query_row(
+                    "SELECT EXISTS(SELECT 1 FROM user WHERE identity = ?1)",
+                    [&identity],
+                    |row| row.get(0),
+                )
+                .map_err(|e| sql_error("failed to query for user", e))?;
+            if !exists {
+                return Err(Status::not_found("user not found"));
+            }
+            connection
+                .execute("DELETE FROM message WHERE user_identity = ?1", [&identity])
+                .map_err(|e| sql_error("failed to purge messages", e))
+        })
+        .await
+    }
+
+    async fn purge_expired_messages(
+        &self,
+        before: SystemTime,
+    ) -> tonic::Result<Vec<ExpiredMessage>> {
+        let before = before.duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let mut rows: Vec<(i64, String, u32, Vec<u8>)> = self
+            .write(move |connection| {
+                connection
+                    .execute(
+                        "DELETE FROM message_uuid WHERE creation_time < ?1",
+                        [before],
+                    )
+                    .map_err(|e| sql_error("failed to purge message uuids", e))?;
+                let mut stmt = connection
+                    .prepare("DELETE FROM message WHERE creation_time < ?1 RETURNING id, user_identity, device_id, message")
+                    .map_err(|e| sql_error("failed to purge messages", e))?;
+                let rows = stmt
+                    .query_map([before], |row| {
+                        Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+                    })
+                    .and_then(|rows| rows.collect())
+                    .map_err(|e| sql_error("failed to purge messages", e));
+                rows
+            })
+            .await?;
+        // RETURNING yields rows in an arbitrary order, so restore the order they were enqueued in.
+        rows.sort_by_key(|(id, ..)| *id);
+        rows.into_iter()
+            .map(|(_, recipient, device_id, message)| {
+                Ok(ExpiredMessage {
+                    message: self.open_message(&recipient, device_id, &message)?,
+                    recipient,
+                    device_id,
+                })
+            })
+            .collect()
+    }
+
+    async fn purge_expired_opks(&self, before: SystemTime) -> tonic::Result<usize> {
+        let before = before.duration_since(UNIX_EPOCH).unwrap().as_secs();
+        self.write(move |connection| {
+            connection
+                .execute(
+                    "DELETE FROM pre_key WHERE creation_time < ?1 AND creation_time < (SELECT creation_time FROM user WHERE identity = pre_key.user_identity AND device_id = pre_key.device_id)",
+                    [before],
+                )
+                .map_err(|e| sql_error("failed to purge one time keys", e))
+        })
+        .await
+    }
+
+    async fn count_stale_spks(&self, before: SystemTime) -> tonic::Result<usize> {
+        let before = before.duration_since(UNIX_EPOCH).unwrap().as_secs();
+        self.read(move |connection| {
+            connection
+                .query_row(
+                    "SELECT COUNT(*) FROM user WHERE current_pre_key_upload_time < ?1",
+                    [before],
+                    |row| row.get(0),
+                )
+                .map_err(|e| sql_error("failed to count signed pre keys", e))
+        })
+        .await
+    }
+
+    async fn device_stats(&self, identity: Option<&str>) -> tonic::Result<Vec<DeviceStats>> {
+        let identity = identity.map(str::to_owned);
+        let rows: Vec<(String, String, u32, u64, u64, usize, usize, bool)> = self
+            .read(move |connection| {
+                let mut stmt = connection
+                    .prepare(
+                        "SELECT identity, display_identity, device_id, creation_time, current_pre_key_upload_time,
+                             (SELECT COUNT(*) FROM pre_key WHERE user_identity = identity AND pre_key.device_id = user.device_id AND reserved_until IS NULL),
+                             (SELECT COUNT(*) FROM message WHERE user_identity = identity AND message.device_id = user.device_id),
+                             EXISTS(SELECT 1 FROM push_token WHERE user_identity = identity AND push_token.device_id = user.device_id)
+                         FROM user WHERE ?1 IS NULL OR identity = ?1 ORDER BY identity, device_id",
+                    )
+                    .map_err(|e| sql_error("failed to query for devices", e))?;
+                let rows = stmt
+                    .query_map([identity], |row| {
+                        Ok((
+                            row.get(0)?,
+                            row.get(1)?,
+                            row.get(2)?,
+                            row.get(3)?,
+                            row.get(4)?,
+                            row.get(5)?,
+                            row.get(6)?,
+                            row.get(7)?,
+                        ))
+                    })
+                    .and_then(|rows| rows.collect())
+                    .map_err(|e| sql_error("failed to query for devices", e));
+                rows
+            })
+            .await?;
+        Ok(rows
+            .into_iter()
+            .map(
+                |(
+                    identity,
+                    display_identity,
+                    device_id,
+                    registered_at,
+                    spk_uploaded_at,
+                    opks,
+                    messages,
+                    push,
+                )| {
+                    DeviceStats {
+                        identity,
+                        display_identity,
+                        device_id,
+                        registered_at: UNIX_EPOCH + Duration::from_secs(registered_at),
+                        spk_uploaded_at: UNIX_EPOCH + Duration::from_secs(spk_uploaded_at),
+                        opk_count: opks,
+                        queued_messages: messages,
+                        has_push_token: push,
+                    }
+                },
+            )
+            .collect())
+    }
+
+    async fn stats(&self, deepest_queues: usize) -> tonic::Result<StorageStats> {
+        self.read(move |connection| {
+            let internal = |e: rusqlite::Error| {
+                sql_error("failed to gather database stats", e)
+            };
+            let (identities, devices, opks, queued_messages, oldest_message_at) = connection
+                .query_row(
+                    "SELECT (SELECT COUNT(DISTINCT identity) FROM user), (SELECT COUNT(*) FROM user),
+                         (SELECT COUNT(*) FROM pre_key WHERE reserved_until IS NULL), (SELECT COUNT(*) FROM message),
+                         (SELECT MIN(creation_time) FROM message)",
+                    [],
+                    |row| {
+                        Ok((
+                            row.get(0)?,
+                            row.get(1)?,
+                            row.get(2)?,
+                            row.get(3)?,
+                            row.get::<_, Option<u64>>(4)?,
+                        ))
+                    },
+                )
+                .map_err(internal)?;
+            let tables: Vec<String> = connection
+                .prepare(
+                    "SELECT name FROM sqlite_master WHERE type = 'table' AND name NOT LIKE 'sqlite_%' AND name != ?1",
+                )
+                .and_then(|mut stmt| stmt.query_map([AT_REST_TABLE], |row| row.get(0))?.collect())
+                .map_err(internal)?;
+            let mut table_rows = BTreeMap::new();
+            for table in tables {
+                let rows = connection
+                    .query_row(&format!("SELECT COUNT(*) FROM \"{table}\""), [], |row| {
+                        row.get(0)
+                    })
+                    .map_err(internal)?;
+                table_rows.insert(table, rows);
+            }
+            let deepest_queues = connection
+                .prepare(
+                    "SELECT user_identity, COUNT(*) AS depth FROM message GROUP BY user_identity
+                     ORDER BY depth DESC, user_identity LIMIT ?1",
+                )
+                .and_then(|mut stmt| {
+                    stmt.query_map([deepest_queues], |row| Ok((row.get(0)?, row.get(1)?)))?
+                        .collect()
+                })
+                .map_err(internal)?;
+
+            // In-memory databases have no path.
+            let (size_bytes, wal_size_bytes) = match connection.path() {
+                Some(path) if !path.is_empty() => {
+                    let size = connection
+                        .query_row(
+                            "SELECT page_count * page_size FROM pragma_page_count(), pragma_page_size()",
+                            [],
+                            |row| row.get(0),
+                        )
+                        .map_err(internal)?;
+                    // The log is removed when the last connection closes.
+                    let wal_size = std::fs::metadata(format!("{path}-wal")).map_or(0, |wal| wal.len());
+                    (Some(size), Some(wal_size))
+                }
+                _ => (None, None),
+            };
+            Ok(StorageStats {
+                identities,
+                devices,
+                opks,
+                queued_messages,
+                table_rows,
+                size_bytes,
+                wal_size_bytes,
+                oldest_message_at: oldest_message_at
+                    .map(|queued_at| UNIX_EPOCH + Duration::from_secs(queued_at)),
+                deepest_queues,
+            })
+        })
+        .await
+    }
+
+    async fn add_audit_entry(&self, entry: AuditEntry) -> tonic::Result<()> {
+        let time = entry.time.duration_since(UNIX_EPOCH).unwrap().as_secs();
+        self.write(move |connection| {
+            connection
+                .execute(
+                    "INSERT INTO audit_log (actor, action, target, creation_time) VALUES (?1, ?2, ?3, ?4)",
+                    params![entry.actor, entry.action, entry.target, time],
+                )
+                .map_err(|e| sql_error("failed to add audit entry", e))?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn audit_log(&self) -> tonic::Result<Vec<AuditEntry>> {
+        self.read(move |connection| {
+            let mut stmt = connection
+                .prepare("SELECT actor, action, target, creation_time FROM audit_log ORDER BY id")
+                .map_err(|e| sql_error("failed to query audit log", e))?;
+            let entries = stmt
+                .query_map([], |row| {
+                    Ok(AuditEntry {
+                        actor: row.get(0)?,
+                        action: row.get(1)?,
+                        target: row.get(2)?,
+                        time: UNIX_EPOCH + Duration::from_secs(row.get(3)?),
+                    })
+                })
+                .and_then(|rows| rows.collect())
+                .map_err(|e| sql_error("failed to query audit log", e));
+            entries
+        })
+        .await
+    }
+
+    async fn add_invite(&self, invite: Invite) -> tonic::Result<()> {
+        let seconds = |time: SystemTime| time.duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let created_at = seconds(invite.created_at);
+        let expires_at = invite.expires_at.map(seconds);
+        self.write(move |connection| {
+            connection
+                .execute(
+                    "INSERT INTO invite (code_hash, creator, max_uses, uses, creation_time, expires_at, revoked)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                    params![
+                        invite.code_hash,
+                        invite.creator,
+                        invite.max_uses,
+                        invite.uses,
+                        created_at,
+                        expires_at,
+                        invite.revoked
+                    ],
+                )
+                .map_err(|e| match e.sqlite_error_code() {
+                    Some(rusqlite::ErrorCode::ConstraintViolation) => {
+                        Status::already_exists("invite already exists")
+                    }
+                    _ => sql_error("failed to add invite", e),
+                })?;
+            Ok(())
+        })
+        .await
     }
 
-    fn update_spk(&self, identity: &str, spk: SignedPreKeyProto) -> tonic::Result<()> {
-        println!("Updating pre key for user \"{identity}\" to the database.");
+    async fn redeem_invite(
+        &self,
+        code_hash: &[u8],
+        now: SystemTime,
+    ) -> tonic::Result<std::result::Result<(), InviteRejection>> {
+        let code_hash = code_hash.to_vec();
+        let now = now.duration_since(UNIX_EPOCH).unwrap().as_secs();
+        self.write(move |connection| {
+            let internal = |e| sql_error("failed to redeem invite", e);
+            // Checking and counting the use in one statement keeps concurrent registrations from
+            // using the code more often than it allows.
+            let redeemed = connection
+                .execute(
+                    "UPDATE invite SET uses = uses + 1
+                     WHERE code_hash = ?1 AND NOT revoked AND uses < max_uses
+                     AND (expires_at IS NULL OR expires_at > ?2)",
+                    params![code_hash, now],
+                )
+                .map_err(internal)?;
+            if redeemed > 0 {
+                return Ok(Ok(()));
+            }
+            let invite: Option<(bool, Option<u64>)> = match connection.query_row(
+                "SELECT revoked, expires_at FROM invite WHERE code_hash = ?1",
+                [&code_hash],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            ) {
+                Ok(invite) => Some(invite),
+                Err(rusqlite::Error::QueryReturnedNoRows) => None,
+                Err(e) => return Err(internal(e)),
+            };
+            Ok(Err(match invite {
+                None => InviteRejection::Unknown,
+                Some((true, _)) => InviteRejection::Revoked,
+                Some((false, Some(expires_at))) if expires_at <= now => InviteRejection::Expired,
+                Some(_) => InviteRejection::UsedUp,
+            }))
+        })
+        .await
+    }
+
+    async fn revoke_invite(&self, code_hash: &[u8]) -> tonic::Result<()> {
+        let code_hash = code_hash.to_vec();
+        self.write(move |connection| {
+            let revoked = connection
+                .execute(
+                    "UPDATE invite SET revoked = 1 WHERE code_hash = ?1",
+                    [&code_hash],
+                )
+                .map_err(|e| sql_error("failed to revoke invite", e))?;
+            if revoked == 0 {
+                return Err(Status::not_found("invite not found"));
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    async fn invites(&self) -> tonic::Result<Vec<Invite>> {
+        self.read(move |connection| {
+            let mut stmt = connection
+                .prepare(
+                    "SELECT code_hash, creator, max_uses, uses, creation_time, expires_at, revoked
+                     FROM invite ORDER BY creation_time, rowid",
+                )
+                .map_err(|e| sql_error("failed to query invites", e))?;
+            let invites = stmt
+                .query_map([], |row| {
+                    Ok(Invite {
+                        code_hash: row.get(0)?,
+                        creator: row.get(1)?,
+                        max_uses: row.get(2)?,
+                        uses: row.get(3)?,
+                        created_at: UNIX_EPOCH + Duration::from_secs(row.get(4)?),
+                        expires_at: row
+                            .get::<_, Option<u64>>(5)?
+                            .map(|expires_at| UNIX_EPOCH + Duration::from_secs(expires_at)),
+                        revoked: row.get(6)?,
+                    })
+                })
+                .and_then(|rows| rows.collect())
+                .map_err(|e| sql_error("failed to query invites", e));
+            invites
+        })
+        .await
+    }
+
+    async fn checkpoint(&self, min_wal_bytes: u64) -> tonic::Result<Option<Checkpoint>> {
+        // Checkpointing on the writer keeps writes from appending to the log while it's emptied.
+        self.call(move |connection| {
+            let path = match connection.path() {
+                Some(path) if !path.is_empty() => path.to_owned(),
+                _ => return Ok(None),
+            };
+            let wal_size_bytes =
+                std::fs::metadata(format!("{path}-wal")).map_or(0, |wal| wal.len());
+            if wal_size_bytes < min_wal_bytes {
+                return Ok(None);
+            }
+            let (busy, log_pages, checkpointed_pages): (bool, i64, i64) = connection
+                .query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |row| {
+                    Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+                })
+                .map_err(|e| sql_error("failed to checkpoint the write-ahead log", e))?;
+            // Both page counts are -1 when the database isn't in write-ahead logging mode.
+            Ok(Some(Checkpoint {
+                wal_size_bytes,
+                log_pages: log_pages.max(0) as u64,
+                checkpointed_pages: checkpointed_pages.max(0) as u64,
+                truncated: !busy,
+            }))
+        })
+        .await
+    }
+
+    async fn optimize(&self) -> tonic::Result<()> {
+        debug!("Optimizing the database.");
+        self.call(move |connection| {
+            connection
+                .execute_batch("PRAGMA optimize")
+                .map_err(|e| sql_error("failed to optimize the database", e))
+        })
+        .await
+    }
+
+    async fn close(&self) -> tonic::Result<()> {
+        let close = |connection: &Connection| {
+            let connection = connection.clone();
+            async move {
+                connection
+                    .close()
+                    .await
+                    .map_err(|e| Status::internal(format!("failed to close the database: {e}")))
+            }
+        };
+        // Readers would keep the checkpoint from emptying the log.
+        for reader in &self.readers {
+            close(reader).await?;
+        }
+        self.call(|connection| {
+            connection
+                .query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |_| Ok(()))
+                .map_err(|e| sql_error("failed to checkpoint the write-ahead log", e))
+        })
+        .await?;
+        close(&self.connection).await
+    }
+
+    async fn backup(&self, destination: &Path, mut progress: BackupProgress) -> tonic::Result<()> {
+        info!("Backing up the database to {}.", destination.display());
+
+        let path = self
+            .call(|connection| Ok(connection.path().map(str::to_owned)))
+            .await?;
+        let Some(path) = path.filter(|path| !path.is_empty()) else {
+            return Err(Status::unimplemented(
+                "in-memory databases can't be backed up",
+            ));
+        };
+        let destination = destination.to_owned();
+        // A connection of its own, so the copy holds up neither the writer nor the readers.
+        tokio::task::spawn_blocking(move || {
+            let internal = |e| sql_error("failed to back up the database", e);
+            let source = rusqlite::Connection::open_with_flags(
+                &path,
+                OpenFlags::SQLITE_OPEN_READ_ONLY
+                    | OpenFlags::SQLITE_OPEN_NO_MUTEX
+                    | OpenFlags::SQLITE_OPEN_URI,
+            )
+            .map_err(internal)?;
+            source.busy_timeout(BUSY_TIMEOUT).map_err(internal)?;
+            // Copying within one read transaction copies one snapshot, however long it takes,
+            // while writes carry on in the write-ahead log.
+            let snapshot = source.unchecked_transaction().map_err(internal)?;
+            snapshot
+                .query_row("SELECT COUNT(*) FROM sqlite_master", [], |_| Ok(()))
+                .map_err(internal)?;
+            let mut copy = rusqlite::Connection::open(&destination).map_err(internal)?;
+            let backup = Backup::new(&source, &mut copy).map_err(internal)?;
+            loop {
+                let step = backup.step(BACKUP_STEP_PAGES).map_err(internal)?;
+                let Progress {
+                    remaining,
+                    pagecount,
+                } = backup.progress();
+                progress((pagecount - remaining) as u64, pagecount as u64);
+                match step {
+                    StepResult::Done => return Ok(()),
+                    StepResult::More => {}
+                    _ => std::thread::sleep(BUSY_BACKOFF),
+                }
+            }
+        })
+        .await
+        .map_err(|e| Status::internal(format!("failed to back up the database: {e}")))?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::brongnal::{MailboxQuota, OPK_RESERVATION_TTL};
+    use crate::sqlite_brongnal::*;
+    use crate::storage_tests::storage_test_suite;
+    use anyhow::Result;
+    use client::{memory_client::MemoryClient, X3DHClient};
+    use proto::{SessionMessage, DEFAULT_DEVICE_ID};
+    use tonic::Code;
+
+    storage_test_suite!(SqliteStorage::new(Connection::open_in_memory().await?).await?);
+
+    mod encrypted {
+        use super::*;
+
+        storage_test_suite!(
+            SqliteStorage::new_encrypted(
+                Connection::open_in_memory().await?,
+                AtRestKey::new([7; 32])
+            )
+            .await?
+        );
+    }
+
+    #[tokio::test]
+    async fn encrypted_database() -> Result<()> {
+        let path =
+            std::env::temp_dir().join(format!("brongnal-encrypted-{}.db3", std::process::id()));
+        let remove_database = || {
+            for suffix in ["", "-wal", "-shm"] {
+                let _ = std::fs::remove_file(format!("{}{suffix}", path.display()));
+            }
+        };
+        remove_database();
+        let key = AtRestKey::new([7; 32]);
+        let message: MessageProto = SessionMessage {
+            sender_identity: String::from("alice"),
+            ciphertext: b"meet at noon".to_vec(),
+        }
+        .into();
+        let storage =
+            SqliteStorage::new_encrypted(Connection::open(&path).await?, key.clone()).await?;
+        register_bob(&storage).await?;
+        storage
+            .add_message("bob", DEFAULT_DEVICE_ID, message.clone())
+            .await?;
+        drop(storage);
+
+        // Plain sqlite sees neither the message nor its ciphertext.
+        let stored: Vec<u8> = rusqlite::Connection::open(&path)?.query_row(
+            "SELECT message FROM message",
+            [],
+            |row| row.get(0),
+        )?;
+        assert!(!stored
+            .windows(b"meet at noon".len())
+            .any(|window| window == b"meet at noon"));
+        assert_ne!(MessageProto::decode(&*stored).ok(), Some(message.clone()));
+
+        assert!(SqliteStorage::new(Connection::open(&path).await?)
+            .await
+            .is_err());
+        assert!(SqliteStorage::new_encrypted(
+            Connection::open(&path).await?,
+            AtRestKey::new([8; 32])
+        )
+        .await
+        .is_err());
+        let storage = SqliteStorage::new_encrypted(Connection::open(&path).await?, key.clone())
+            .await?
+            .with_readers(1)
+            .await?;
+        assert_eq!(
+            storage.peek_messages("bob", DEFAULT_DEVICE_ID).await?[0].message,
+            message
+        );
+        assert_eq!(
+            storage.get_messages("bob", DEFAULT_DEVICE_ID).await?,
+            vec![message.clone()]
+        );
+        drop(storage);
+        remove_database();
 
-        let _: String = self
-            .connection()?
-            .query_row(
-                "UPDATE user SET current_pre_key = ?2 WHERE identity = ?1 RETURNING identity",
-                params![identity, spk.encode_to_vec()],
-                |row| Ok(row.get(0)?),
-            )
-            .map_err(|_| Status::not_found("user not found"))?;
+        // Nor can a database that already holds plaintext messages be encrypted.
+        let storage = SqliteStorage::new(Connection::open(&path).await?).await?;
+        register_bob(&storage).await?;
+        storage
+            .add_message("bob", DEFAULT_DEVICE_ID, message)
+            .await?;
+        drop(storage);
+        assert!(
+            SqliteStorage::new_encrypted(Connection::open(&path).await?, key)
+                .await
+                .is_err()
+        );
+        remove_database();
         Ok(())
     }
 
-    fn add_opks(&self, identity: &str, opks: Vec<X25519PublicKey>) -> tonic::Result<()> {
-        println!(
-            "Adding {} one time keys for user \"{identity}\" to the database.",
-            opks.len()
-        );
+    #[tokio::test]
+    async fn database_size() -> Result<()> {
+        let in_memory = SqliteStorage::new(Connection::open_in_memory().await?).await?;
+        let stats = in_memory.stats(0).await?;
+        assert_eq!((stats.size_bytes, stats.wal_size_bytes), (None, None));
 
-        let connection = self.connection()?;
-        let mut stmt = connection
-            .prepare("INSERT INTO pre_key (user_identity, key, creation_time) VALUES (?1, ?2, ?3)")
-            .unwrap();
-        for opk in opks {
-            stmt.execute((
-                identity,
-                opk.to_bytes(),
-                SystemTime::now()
-                    .duration_since(UNIX_EPOCH)
-                    .unwrap()
-                    .as_secs(),
-            ))
-            .map_err(|_| Status::internal("failed to insert one time key"))?;
+        let path = std::env::temp_dir().join(format!("brongnal-size-{}.db3", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let storage = SqliteStorage::new(Connection::open(&path).await?).await?;
+        // With write-ahead logging, pages may not have reached the file itself yet.
+        let stats = storage.stats(0).await?;
+        assert!(stats.size_bytes > Some(0));
+        assert!(stats.wal_size_bytes > Some(0));
+        drop(storage);
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{suffix}", path.display()));
         }
         Ok(())
     }
 
-    fn get_current_keys(&self, identity: &str) -> tonic::Result<(VerifyingKey, SignedPreKeyProto)> {
-        println!("Retrieving pre keys for user \"{identity}\" from the database.");
+    #[tokio::test]
+    async fn readers_share_file_databases() -> Result<()> {
+        let in_memory = SqliteStorage::new(Connection::open_in_memory().await?)
+            .await?
+            .with_readers(2)
+            .await?;
+        assert!(in_memory.readers.is_empty());
 
-        let (ik, spk): (Vec<u8>, Vec<u8>) = self
-            .connection()?
-            .query_row(
-                "SELECT key, current_pre_key FROM user WHERE identity = ?1",
-                [identity],
-                |row| Ok((row.get(0).unwrap(), row.get(1).unwrap())),
-            )
-            .map_err(|_| Status::not_found("user not found"))?;
-        let ik = parse_verifying_key(&ik).unwrap();
-        let spk = SignedPreKeyProto::decode(&*spk).unwrap();
-        Ok((ik, spk))
+        let path =
+            std::env::temp_dir().join(format!("brongnal-readers-{}.db3", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let storage = SqliteStorage::new(Connection::open(&path).await?)
+            .await?
+            .with_readers(2)
+            .await?;
+        assert_eq!(storage.readers.len(), 2);
+        register_bob(&storage).await?;
+        // Each reader in turn sees what the writer committed.
+        for _ in 0..2 {
+            assert!(storage.user_exists("bob").await?);
+        }
+        assert_eq!(
+            storage
+                .read(|connection| connection
+                    .execute("DELETE FROM user", [])
+                    .map_err(|e| sql_error("failed to delete users", e)))
+                .await
+                .err()
+                .map(|e| e.code()),
+            Some(Code::Internal)
+        );
+        assert!(storage.user_exists("bob").await?);
+        drop(storage);
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{suffix}", path.display()));
+        }
+        Ok(())
     }
 
-    fn pop_opk(&self, identity: &str) -> tonic::Result<Option<X25519PublicKey>> {
-        println!("Popping one time key for user \"{identity}\" from the database.");
-
-        let key: Option<[u8;32]> = match self.connection()?.query_row(
-            "DELETE from pre_key WHERE key = ( SELECT key FROM pre_key WHERE user_identity = ?1 ORDER BY creation_time LIMIT 1) RETURNING key", 
-            [identity.to_owned()],
-            |row| row.get(0)) {
-            Ok(value) => Ok(Some(value)),
-            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
-            Err(e) => Err(Status::not_found(format!("failed to query for pre_key: {e}"))),
-        }?;
-
-        Ok(key.map(|key| X25519PublicKey::from(key)))
-    }
+    #[tokio::test]
+    async fn checkpoint_empties_grown_wal() -> Result<()> {
+        let in_memory = SqliteStorage::new(Connection::open_in_memory().await?).await?;
+        assert_eq!(in_memory.checkpoint(0).await?, None);
 
-    fn add_message(&self, recipient: &str, message: MessageProto) -> tonic::Result<()> {
-        println!("Enqueueing message for user {recipient} in database.");
+        let path =
+            std::env::temp_dir().join(format!("brongnal-checkpoint-{}.db3", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let storage = SqliteStorage::new(Connection::open(&path).await?)
+            .await?
+            .with_readers(1)
+            .await?;
+        for i in 0..200 {
+            storage
+                .add_audit_entry(AuditEntry {
+                    actor: "a".repeat(4096),
+                    action: String::from("grow"),
+                    target: i.to_string(),
+                    time: SystemTime::now(),
+                })
+                .await?;
+        }
+        // Readers stay open between queries, but only hold on to the log while reading.
+        assert_eq!(storage.audit_log().await?.len(), 200);
+        let wal_size = storage.stats(0).await?.wal_size_bytes.unwrap();
+        assert!(wal_size > 1 << 19);
+        assert_eq!(storage.checkpoint(wal_size + 1).await?, None);
 
-        let _: u64 = self
-            .connection()?
-            .query_row(
-                "INSERT INTO message (message, user_identity, creation_time) VALUES (?1, ?2, ?3) RETURNING creation_time",
-                (
-                    message.encode_to_vec(),
-                    recipient,
-                    SystemTime::now()
-                        .duration_since(UNIX_EPOCH)
-                        .unwrap()
-                        .as_secs(),
-                ),|row| Ok(row.get(0)?),
-            )
-            .map_err(|_| Status::not_found("user not found"))?;
+        let checkpoint = storage.checkpoint(1 << 19).await?.unwrap();
+        assert_eq!(checkpoint.wal_size_bytes, wal_size);
+        assert!(checkpoint.truncated);
+        assert_eq!(checkpoint.checkpointed_pages, checkpoint.log_pages);
+        assert_eq!(storage.stats(0).await?.wal_size_bytes, Some(0));
+        assert_eq!(storage.audit_log().await?.len(), 200);
+        storage.optimize().await?;
+        drop(storage);
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{suffix}", path.display()));
+        }
         Ok(())
     }
 
-    fn get_messages(&self, identity: &str) -> tonic::Result<Vec<MessageProto>> {
-        println!("Retrieving messages for \"{identity}\" from the database.");
+    #[tokio::test]
+    async fn close_checkpoints_the_wal() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("brongnal-close-{}.db3", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let storage = SqliteStorage::new(Connection::open(&path).await?)
+            .await?
+            .with_readers(1)
+            .await?;
+        storage
+            .add_audit_entry(AuditEntry {
+                actor: String::from("admin"),
+                action: String::from("close"),
+                target: String::new(),
+                time: SystemTime::now(),
+            })
+            .await?;
+        assert_eq!(storage.audit_log().await?.len(), 1);
+        assert!(storage.stats(0).await?.wal_size_bytes.unwrap() > 0);
 
-        let connection = self.connection()?;
-        let mut stmt = connection
-            .prepare("DELETE from message WHERE user_identity = ?1 RETURNING message")
-            .map_err(|e| {
-                Status::internal(format!("Failed to query message table for {identity}: {e}"))
-            })?;
-        let message_iter = stmt.query_map([identity], |row| Ok(row.get(0)?)).unwrap();
-        let mut ret = Vec::new();
-        for message in message_iter {
-            // TODO wtf is happening here?
-            let message: Vec<u8> = message.unwrap();
-            ret.push(
-                MessageProto::decode(&*message)
-                    .map_err(|_| Status::internal("Failed to deserialize Message proto"))?,
-            );
+        storage.close().await?;
+        let wal = std::fs::metadata(format!("{}-wal", path.display())).map_or(0, |wal| wal.len());
+        assert_eq!(wal, 0);
+        assert!(storage.audit_log().await.is_err());
+        let storage = SqliteStorage::new(Connection::open(&path).await?).await?;
+        assert_eq!(storage.audit_log().await?.len(), 1);
+        drop(storage);
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{suffix}", path.display()));
         }
-        Ok(ret)
+        Ok(())
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use crate::sqlite_brongnal::*;
-    use anyhow::Result;
-    use client::{memory_client::MemoryClient, X3DHClient};
-    use tonic::Code;
 
-    #[test]
-    fn register_user_get_keys_success() -> Result<()> {
-        let storage = SqliteStorage::new(Connection::open_in_memory()?)?;
+    #[tokio::test]
+    async fn register_user_get_keys_success() -> Result<()> {
+        let storage = SqliteStorage::new(Connection::open_in_memory().await?).await?;
         let alice = MemoryClient::new();
         let alice_ik = VerifyingKey::from(&alice.get_ik().unwrap());
         let alice_spk: SignedPreKeyProto = alice.get_spk().unwrap().into();
         assert_eq!(
-            storage.register_user(String::from("alice"), alice_ik, alice_spk.clone())?,
-            ()
+            storage
+                .register_user(
+                    "alice".parse()?,
+                    DEFAULT_DEVICE_ID,
+                    alice_ik,
+                    alice_spk.clone()
+                )
+                .await?,
+            1
         );
-        assert_eq!(storage.get_current_keys("alice")?, (alice_ik, alice_spk));
+        let keys = storage.get_current_keys("alice", DEFAULT_DEVICE_ID).await?;
+        assert_eq!((keys.ik, keys.spk, keys.spk_id), (alice_ik, alice_spk, 1));
         Ok(())
     }
 
-    #[test]
-    fn get_keys_not_found() -> Result<()> {
-        let storage = SqliteStorage::new(Connection::open_in_memory()?)?;
+    #[tokio::test]
+    async fn get_keys_not_found() -> Result<()> {
+        let storage = SqliteStorage::new(Connection::open_in_memory().await?).await?;
         assert_eq!(
-            storage.get_current_keys("alice").err().map(|e| e.code()),
+            storage
+                .get_current_keys("alice", DEFAULT_DEVICE_ID)
+                .await
+                .err()
+                .map(|e| e.code()),
             Some(Code::NotFound)
         );
         Ok(())
     }
 
-    #[test]
-    fn pop_empty_opks_none() -> Result<()> {
-        let storage = SqliteStorage::new(Connection::open_in_memory()?)?;
-        assert_eq!(storage.pop_opk("bob")?, None);
+    #[tokio::test]
+    async fn pop_empty_opks_none() -> Result<()> {
+        let storage = SqliteStorage::new(Connection::open_in_memory().await?).await?;
+        assert_eq!(
+            storage
+                .reserve_opk(
+                    "bob",
+                    DEFAULT_DEVICE_ID,
+                    SystemTime::now() + OPK_RESERVATION_TTL
+                )
+                .await?,
+            None
+        );
         Ok(())
     }
 
-    #[test]
-    fn retrieve_opk() -> Result<()> {
-        let storage = SqliteStorage::new(Connection::open_in_memory()?)?;
+    #[tokio::test]
+    async fn retrieve_opk() -> Result<()> {
+        let storage = SqliteStorage::new(Connection::open_in_memory().await?).await?;
         let mut bob = MemoryClient::new();
         let keys = bob.create_opks(1)?.pre_keys;
-        storage.register_user(
-            String::from("bob"),
-            (&bob.get_ik()?).into(),
-            bob.get_spk()?.into(),
-        )?;
-        storage.add_opks("bob", keys.clone())?;
-        assert_eq!(storage.pop_opk("bob")?, Some(keys[0]));
-        assert_eq!(storage.pop_opk("bob")?, None);
+        storage
+            .register_user(
+                "bob".parse()?,
+                DEFAULT_DEVICE_ID,
+                (&bob.get_ik()?).into(),
+                bob.get_spk()?.into(),
+            )
+            .await?;
+        storage
+            .add_opks("bob", DEFAULT_DEVICE_ID, keys.clone(), OpkQuota::default())
+            .await?;
+        assert_eq!(
+            storage
+                .reserve_opk(
+                    "bob",
+                    DEFAULT_DEVICE_ID,
+                    SystemTime::now() + OPK_RESERVATION_TTL
+                )
+                .await?
+                .map(|(_, key)| key),
+            Some(keys[0])
+        );
+        assert_eq!(
+            storage
+                .reserve_opk(
+                    "bob",
+                    DEFAULT_DEVICE_ID,
+                    SystemTime::now() + OPK_RESERVATION_TTL
+                )
+                .await?,
+            None
+        );
         Ok(())
     }
 
-    #[test]
-    fn updating_spk_user_not_found() -> Result<()> {
-        let storage = SqliteStorage::new(Connection::open_in_memory()?)?;
+    #[tokio::test]
+    async fn updating_spk_user_not_found() -> Result<()> {
+        let storage = SqliteStorage::new(Connection::open_in_memory().await?).await?;
         assert_eq!(
             storage
-                .update_spk("bob", SignedPreKeyProto::default())
+                .update_spk("bob", DEFAULT_DEVICE_ID, SignedPreKeyProto::default())
+                .await
                 .err()
                 .map(|e| e.code()),
             Some(Code::NotFound)
@@ -262,41 +2494,84 @@ mod tests {
         Ok(())
     }
 
-    #[test]
-    fn update_spk_success() -> Result<()> {
-        let storage = SqliteStorage::new(Connection::open_in_memory()?)?;
+    #[tokio::test]
+    async fn update_spk_success() -> Result<()> {
+        let storage = SqliteStorage::new(Connection::open_in_memory().await?).await?;
         let mut bob = MemoryClient::new();
         let bob_ik = VerifyingKey::from(&bob.get_ik().unwrap());
         let mut bob_spk: SignedPreKeyProto = bob.get_spk().unwrap().into();
-        storage.register_user(String::from("bob"), bob_ik, bob_spk.clone())?;
+        storage
+            .register_user("bob".parse()?, DEFAULT_DEVICE_ID, bob_ik, bob_spk.clone())
+            .await?;
 
         bob_spk.pre_key = Some(bob.create_opks(1)?.pre_keys[0].to_bytes().to_vec());
-        storage.update_spk("bob", bob_spk.clone())?;
+        storage
+            .update_spk("bob", DEFAULT_DEVICE_ID, bob_spk.clone())
+            .await?;
 
-        assert_eq!(storage.get_current_keys("bob")?, (bob_ik, bob_spk));
+        let keys = storage.get_current_keys("bob", DEFAULT_DEVICE_ID).await?;
+        assert_eq!((keys.ik, keys.spk, keys.spk_id), (bob_ik, bob_spk, 2));
         Ok(())
     }
 
-    #[test]
-    fn add_message_unknown_user() -> Result<()> {
-        let storage = SqliteStorage::new(Connection::open_in_memory()?)?;
+    #[tokio::test]
+    async fn add_message_unknown_user() -> Result<()> {
+        let storage = SqliteStorage::new(Connection::open_in_memory().await?).await?;
+        assert!(!storage.user_exists("bob").await?);
         assert_eq!(
             storage
-                .add_message("bob", MessageProto::default())
+                .add_message("bob", DEFAULT_DEVICE_ID, MessageProto::default())
+                .await
                 .err()
                 .map(|e| e.code()),
-            Some(Code::NotFound)
+            Some(Code::Internal)
         );
         Ok(())
     }
 
-    #[test]
-    fn add_get_message() -> Result<()> {
-        let storage = SqliteStorage::new(Connection::open_in_memory()?)?;
+    #[tokio::test]
+    async fn add_duplicate_message() -> Result<()> {
+        let storage = SqliteStorage::new(Connection::open_in_memory().await?).await?;
+        register_bob(&storage).await?;
+        storage
+            .add_message("bob", DEFAULT_DEVICE_ID, message_with_ciphertext(0))
+            .await?;
+        storage
+            .add_message("bob", DEFAULT_DEVICE_ID, message_with_ciphertext(0))
+            .await?;
+        assert_eq!(
+            storage.get_messages("bob", DEFAULT_DEVICE_ID).await?,
+            vec![message_with_ciphertext(0), message_with_ciphertext(0)]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn user_exists() -> Result<()> {
+        let storage = SqliteStorage::new(Connection::open_in_memory().await?).await?;
+        assert!(!storage.user_exists("bob").await?);
+        register_bob(&storage).await?;
+        assert!(storage.user_exists("bob").await?);
+        assert!(!storage.user_exists("alice").await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    // Queues kept from before payloads hold messages in the deprecated flat form.
+    #[allow(deprecated)]
+    async fn add_get_message() -> Result<()> {
+        let storage = SqliteStorage::new(Connection::open_in_memory().await?).await?;
         let bob = MemoryClient::new();
         let bob_ik = VerifyingKey::from(&bob.get_ik().unwrap());
         let bob_spk: protocol::x3dh::SignedPreKey = bob.get_spk().unwrap();
-        storage.register_user(String::from("bob"), bob_ik, bob_spk.clone().into())?;
+        storage
+            .register_user(
+                "bob".parse()?,
+                DEFAULT_DEVICE_ID,
+                bob_ik,
+                bob_spk.clone().into(),
+            )
+            .await?;
 
         let message_proto = MessageProto {
             sender_identity: Some(String::from("alice")),
@@ -304,10 +2579,776 @@ mod tests {
             ephemeral_key: Some(b"alice ephemeral key".to_vec()),
             one_time_key: Some(b"bob one time key".to_vec()),
             ciphertext: Some(b"ciphertext".to_vec()),
+            signed_pre_key_id: Some(1),
+            one_time_key_id: Some(2),
+            last_resort: None,
+            kem_ciphertext: None,
+            kem_pre_key_id: None,
+            kem_last_resort: None,
+            cipher_suite: None,
+            protocol_version: Some(1),
+            heartbeat: None,
+            pre_key_status: None,
+            message_uuid: None,
+            payload: None,
+        };
+        storage
+            .add_message("bob", DEFAULT_DEVICE_ID, message_proto.clone())
+            .await?;
+        assert_eq!(
+            storage.get_messages("bob", DEFAULT_DEVICE_ID).await?,
+            vec![message_proto]
+        );
+
+        Ok(())
+    }
+
+    async fn register_bob(storage: &SqliteStorage) -> Result<()> {
+        let bob = MemoryClient::new();
+        storage
+            .register_user(
+                "bob".parse()?,
+                DEFAULT_DEVICE_ID,
+                (&bob.get_ik()?).into(),
+                bob.get_spk()?.into(),
+            )
+            .await?;
+        Ok(())
+    }
+
+    fn message_with_ciphertext(ciphertext: u8) -> MessageProto {
+        SessionMessage {
+            sender_identity: String::from("alice"),
+            ciphertext: vec![ciphertext],
+        }
+        .into()
+    }
+
+    #[tokio::test]
+    async fn mailbox_quota_reject() -> Result<()> {
+        let storage = SqliteStorage::new(Connection::open_in_memory().await?)
+            .await?
+            .with_mailbox_quota(MailboxQuota {
+                max_messages: 2,
+                policy: QuotaPolicy::Reject,
+            });
+        register_bob(&storage).await?;
+
+        storage
+            .add_message("bob", DEFAULT_DEVICE_ID, message_with_ciphertext(0))
+            .await?;
+        storage
+            .add_message("bob", DEFAULT_DEVICE_ID, message_with_ciphertext(1))
+            .await?;
+        assert_eq!(
+            storage
+                .add_message("bob", DEFAULT_DEVICE_ID, message_with_ciphertext(2))
+                .await
+                .err()
+                .map(|e| e.code()),
+            Some(Code::ResourceExhausted)
+        );
+        assert_eq!(
+            storage.get_messages("bob", DEFAULT_DEVICE_ID).await?,
+            vec![message_with_ciphertext(0), message_with_ciphertext(1)]
+        );
+
+        storage
+            .add_message("bob", DEFAULT_DEVICE_ID, message_with_ciphertext(2))
+            .await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn mailbox_quota_evict_oldest() -> Result<()> {
+        let storage = SqliteStorage::new(Connection::open_in_memory().await?)
+            .await?
+            .with_mailbox_quota(MailboxQuota {
+                max_messages: 3,
+                policy: QuotaPolicy::EvictOldest,
+            });
+        register_bob(&storage).await?;
+
+        for i in 0..5 {
+            storage
+                .add_message("bob", DEFAULT_DEVICE_ID, message_with_ciphertext(i))
+                .await?;
+        }
+        assert_eq!(
+            storage.get_messages("bob", DEFAULT_DEVICE_ID).await?,
+            vec![
+                message_with_ciphertext(2),
+                message_with_ciphertext(3),
+                message_with_ciphertext(4)
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn purge_expired_messages() -> Result<()> {
+        let storage = SqliteStorage::new(Connection::open_in_memory().await?).await?;
+        register_bob(&storage).await?;
+        storage
+            .add_message("bob", DEFAULT_DEVICE_ID, message_with_ciphertext(0))
+            .await?;
+        storage
+            .connection
+            .call(|connection| Ok(connection.execute("UPDATE message SET creation_time = 0", ())?))
+            .await?;
+        storage
+            .add_message("bob", DEFAULT_DEVICE_ID, message_with_ciphertext(1))
+            .await?;
+
+        let day_ago = SystemTime::now() - std::time::Duration::from_secs(24 * 60 * 60);
+        assert_eq!(
+            storage.purge_expired_messages(day_ago).await?,
+            vec![ExpiredMessage {
+                recipient: String::from("bob"),
+                device_id: DEFAULT_DEVICE_ID,
+                message: message_with_ciphertext(0),
+            }]
+        );
+        assert_eq!(
+            storage.get_messages("bob", DEFAULT_DEVICE_ID).await?,
+            vec![message_with_ciphertext(1)]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn purge_expired_opks() -> Result<()> {
+        let storage = SqliteStorage::new(Connection::open_in_memory().await?).await?;
+        let mut bob = MemoryClient::new();
+        storage
+            .register_user(
+                "bob".parse()?,
+                DEFAULT_DEVICE_ID,
+                (&bob.get_ik()?).into(),
+                bob.get_spk()?.into(),
+            )
+            .await?;
+        let stale_keys = bob.create_opks(2)?.pre_keys;
+        storage
+            .add_opks("bob", DEFAULT_DEVICE_ID, stale_keys, OpkQuota::default())
+            .await?;
+        storage
+            .connection
+            .call(|connection| Ok(connection.execute("UPDATE pre_key SET creation_time = 0", ())?))
+            .await?;
+        let fresh_keys = bob.create_opks(1)?.pre_keys;
+        storage
+            .add_opks(
+                "bob",
+                DEFAULT_DEVICE_ID,
+                fresh_keys.clone(),
+                OpkQuota::default(),
+            )
+            .await?;
+
+        let day_ago = SystemTime::now() - std::time::Duration::from_secs(24 * 60 * 60);
+        assert_eq!(storage.purge_expired_opks(day_ago).await?, 2);
+        assert_eq!(
+            storage
+                .reserve_opk(
+                    "bob",
+                    DEFAULT_DEVICE_ID,
+                    SystemTime::now() + OPK_RESERVATION_TTL
+                )
+                .await?
+                .map(|(_, key)| key),
+            Some(fresh_keys[0])
+        );
+        assert_eq!(
+            storage
+                .reserve_opk(
+                    "bob",
+                    DEFAULT_DEVICE_ID,
+                    SystemTime::now() + OPK_RESERVATION_TTL
+                )
+                .await?,
+            None
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn stale_spk_refreshed_by_new_key() -> Result<()> {
+        let storage = SqliteStorage::new(Connection::open_in_memory().await?).await?;
+        let mut bob = MemoryClient::new();
+        let bob_ik = VerifyingKey::from(&bob.get_ik()?);
+        let mut bob_spk: SignedPreKeyProto = bob.get_spk()?.into();
+        storage
+            .register_user("bob".parse()?, DEFAULT_DEVICE_ID, bob_ik, bob_spk.clone())
+            .await?;
+        storage
+            .connection
+            .call(|connection| {
+                Ok(connection.execute("UPDATE user SET current_pre_key_upload_time = 0", ())?)
+            })
+            .await?;
+
+        // Re-uploading the same key doesn't make it any fresher.
+        storage
+            .register_user("bob".parse()?, DEFAULT_DEVICE_ID, bob_ik, bob_spk.clone())
+            .await?;
+        storage
+            .update_spk("bob", DEFAULT_DEVICE_ID, bob_spk.clone())
+            .await?;
+        let day_ago = SystemTime::now() - std::time::Duration::from_secs(24 * 60 * 60);
+        assert_eq!(storage.count_stale_spks(day_ago).await?, 1);
+        assert_eq!(
+            storage
+                .get_current_keys("bob", DEFAULT_DEVICE_ID)
+                .await?
+                .spk_uploaded_at,
+            UNIX_EPOCH
+        );
+
+        bob_spk.pre_key = Some(bob.create_opks(1)?.pre_keys[0].to_bytes().to_vec());
+        storage
+            .update_spk("bob", DEFAULT_DEVICE_ID, bob_spk)
+            .await?;
+        assert_eq!(storage.count_stale_spks(day_ago).await?, 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn opk_quota_reject() -> Result<()> {
+        let storage = SqliteStorage::new(Connection::open_in_memory().await?).await?;
+        let mut bob = MemoryClient::new();
+        register_bob(&storage).await?;
+        let quota = OpkQuota {
+            max_keys: 3,
+            policy: QuotaPolicy::Reject,
+        };
+
+        storage
+            .add_opks(
+                "bob",
+                DEFAULT_DEVICE_ID,
+                bob.create_opks(3)?.pre_keys,
+                quota,
+            )
+            .await?;
+        assert_eq!(storage.count_opks("bob", DEFAULT_DEVICE_ID).await?, 3);
+        assert_eq!(
+            storage
+                .add_opks(
+                    "bob",
+                    DEFAULT_DEVICE_ID,
+                    bob.create_opks(1)?.pre_keys,
+                    quota
+                )
+                .await
+                .err()
+                .map(|e| e.code()),
+            Some(Code::ResourceExhausted)
+        );
+        assert_eq!(storage.count_opks("bob", DEFAULT_DEVICE_ID).await?, 3);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn opk_quota_replace_oldest() -> Result<()> {
+        let storage = SqliteStorage::new(Connection::open_in_memory().await?).await?;
+        let mut bob = MemoryClient::new();
+        register_bob(&storage).await?;
+        let quota = OpkQuota {
+            max_keys: 3,
+            policy: QuotaPolicy::EvictOldest,
         };
-        storage.add_message("bob", message_proto.clone())?;
-        assert_eq!(storage.get_messages("bob")?, vec![message_proto]);
 
+        let keys = bob.create_opks(3)?.pre_keys;
+        storage
+            .add_opks("bob", DEFAULT_DEVICE_ID, keys.clone(), quota)
+            .await?;
+        let new_keys = bob.create_opks(2)?.pre_keys;
+        storage
+            .add_opks("bob", DEFAULT_DEVICE_ID, new_keys.clone(), quota)
+            .await?;
+        assert_eq!(storage.count_opks("bob", DEFAULT_DEVICE_ID).await?, 3);
+
+        assert_eq!(
+            storage
+                .reserve_opk(
+                    "bob",
+                    DEFAULT_DEVICE_ID,
+                    SystemTime::now() + OPK_RESERVATION_TTL
+                )
+                .await?
+                .map(|(_, key)| key),
+            Some(keys[2])
+        );
+        assert_eq!(
+            storage
+                .reserve_opk(
+                    "bob",
+                    DEFAULT_DEVICE_ID,
+                    SystemTime::now() + OPK_RESERVATION_TTL
+                )
+                .await?
+                .map(|(_, key)| key),
+            Some(new_keys[0])
+        );
+        assert_eq!(
+            storage
+                .reserve_opk(
+                    "bob",
+                    DEFAULT_DEVICE_ID,
+                    SystemTime::now() + OPK_RESERVATION_TTL
+                )
+                .await?
+                .map(|(_, key)| key),
+            Some(new_keys[1])
+        );
+        assert_eq!(storage.count_opks("bob", DEFAULT_DEVICE_ID).await?, 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn add_opks_rolls_back_partial_batch() -> Result<()> {
+        let storage = SqliteStorage::new(Connection::open_in_memory().await?).await?;
+        let mut bob = MemoryClient::new();
+        register_bob(&storage).await?;
+
+        let keys = bob.create_opks(2)?.pre_keys;
+        let batch = vec![keys[0], keys[1], keys[0]];
+        assert_eq!(
+            storage
+                .add_opks("bob", DEFAULT_DEVICE_ID, batch, OpkQuota::default())
+                .await
+                .err()
+                .map(|e| e.code()),
+            Some(Code::AlreadyExists)
+        );
+        assert_eq!(storage.count_opks("bob", DEFAULT_DEVICE_ID).await?, 0);
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_add_and_get_messages() -> Result<()> {
+        let storage =
+            std::sync::Arc::new(SqliteStorage::new(Connection::open_in_memory().await?).await?);
+        register_bob(&storage).await?;
+
+        let mut tasks = Vec::new();
+        for i in 0..100 {
+            let storage = storage.clone();
+            tasks.push(tokio::spawn(async move {
+                storage
+                    .add_message("bob", DEFAULT_DEVICE_ID, message_with_ciphertext(i))
+                    .await?;
+                storage.get_messages("bob", DEFAULT_DEVICE_ID).await
+            }));
+        }
+        let mut delivered = 0;
+        for task in tasks {
+            delivered += tokio::time::timeout(std::time::Duration::from_secs(10), task)
+                .await???
+                .len();
+        }
+        delivered += storage.get_messages("bob", DEFAULT_DEVICE_ID).await?.len();
+        assert_eq!(delivered, 100);
+        Ok(())
+    }
+
+    /// Schema of databases created before migrations were introduced.
+    const V0_SCHEMA: &str = "
+        CREATE TABLE user (
+            identity STRING PRIMARY KEY,
+            key BLOB NOT NULL,
+            current_pre_key BLOB NOT NULL,
+            creation_time INTEGER NOT NULL
+        );
+        CREATE TABLE pre_key (
+            key BLOB PRIMARY KEY,
+            user_identity STRING NOT NULL,
+            creation_time integer NOT NULL,
+            FOREIGN KEY(user_identity) REFERENCES user(identity)
+        );
+        CREATE TABLE message (
+            message BLOB PRIMARY KEY,
+            user_identity STRING NOT NULL,
+            creation_time integer NOT NULL,
+            FOREIGN KEY(user_identity) REFERENCES user(identity)
+        );";
+
+    async fn schema_version(connection: &Connection) -> Result<usize> {
+        Ok(connection
+            .call(|connection| {
+                Ok(connection.pragma_query_value(None, "user_version", |row| row.get(0))?)
+            })
+            .await?)
+    }
+
+    #[tokio::test]
+    async fn migrate_v0_database() -> Result<()> {
+        let connection = Connection::open_in_memory().await?;
+        let bob = MemoryClient::new();
+        let bob_ik = VerifyingKey::from(&bob.get_ik()?);
+        let bob_spk: SignedPreKeyProto = bob.get_spk()?.into();
+        let opk = MemoryClient::new().create_opks(1)?.pre_keys[0];
+        let message = message_with_ciphertext(0);
+        {
+            let (ik, spk, message) = (
+                bob_ik.to_bytes(),
+                bob_spk.encode_to_vec(),
+                message.encode_to_vec(),
+            );
+            connection
+                .call(move |connection| {
+                    connection.execute_batch(V0_SCHEMA)?;
+                    connection.execute(
+                        "INSERT INTO user VALUES ('bob', ?1, ?2, 0)",
+                        params![ik, spk],
+                    )?;
+                    connection.execute(
+                        "INSERT INTO pre_key VALUES (?1, 'bob', 0)",
+                        [opk.to_bytes()],
+                    )?;
+                    connection.execute("INSERT INTO message VALUES (?1, 'bob', 0)", [message])?;
+                    Ok(())
+                })
+                .await?;
+        }
+        assert_eq!(schema_version(&connection).await?, 0);
+
+        let storage = SqliteStorage::new(connection).await?;
+        assert_eq!(schema_version(&storage.connection).await?, MIGRATIONS.len());
+        let keys = storage.get_current_keys("bob", DEFAULT_DEVICE_ID).await?;
+        assert_eq!((keys.ik, keys.spk, keys.spk_id), (bob_ik, bob_spk, 1));
+        assert_eq!(
+            storage
+                .reserve_opk(
+                    "bob",
+                    DEFAULT_DEVICE_ID,
+                    SystemTime::now() + OPK_RESERVATION_TTL
+                )
+                .await?,
+            Some((1, opk))
+        );
+        let ids: Vec<i64> = storage
+            .connection
+            .call(|connection| {
+                let mut stmt = connection.prepare("SELECT id FROM message")?;
+                let ids = stmt
+                    .query_map((), |row| row.get(0))?
+                    .collect::<rusqlite::Result<_>>()?;
+                Ok(ids)
+            })
+            .await?;
+        assert_eq!(ids, vec![1]);
+        assert_eq!(
+            storage.get_messages("bob", DEFAULT_DEVICE_ID).await?,
+            vec![message]
+        );
+        let indexed: bool = storage
+            .connection
+            .call(|connection| {
+                Ok(connection.query_row(
+                    "SELECT EXISTS(SELECT 1 FROM sqlite_master WHERE type = 'index' AND name = 'message_user_identity')",
+                    (),
+                    |row| row.get(0),
+                )?)
+            })
+            .await?;
+        assert!(indexed);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn migrations_are_idempotent() -> Result<()> {
+        let storage = SqliteStorage::new(Connection::open_in_memory().await?).await?;
+        register_bob(&storage).await?;
+        let storage = SqliteStorage::new(storage.connection).await?;
+        assert_eq!(schema_version(&storage.connection).await?, MIGRATIONS.len());
+        assert!(storage.user_exists("bob").await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reject_newer_schema() -> Result<()> {
+        let connection = Connection::open_in_memory().await?;
+        connection
+            .call(|connection| Ok(connection.pragma_update(None, "user_version", 1000)?))
+            .await?;
+        assert!(SqliteStorage::new(connection).await.is_err());
+        Ok(())
+    }
+
+    async fn query_plan(storage: &SqliteStorage, sql: &'static str) -> Result<Vec<String>> {
+        Ok(storage
+            .connection
+            .call(move |connection| {
+                let mut stmt = connection.prepare(&format!("EXPLAIN QUERY PLAN {sql}"))?;
+                let details = stmt
+                    .query_map(params!["bob", DEFAULT_DEVICE_ID], |row| row.get(3))?
+                    .collect::<rusqlite::Result<_>>()?;
+                Ok(details)
+            })
+            .await?)
+    }
+
+    #[tokio::test]
+    async fn per_user_queries_use_indexes() -> Result<()> {
+        let storage = SqliteStorage::new(Connection::open_in_memory().await?).await?;
+        for sql in [
+            "SELECT key FROM pre_key WHERE user_identity = ?1 AND device_id = ?2 ORDER BY creation_time, rowid LIMIT 1",
+            "SELECT COUNT(*) FROM pre_key WHERE user_identity = ?1 AND device_id = ?2",
+            "SELECT id, message FROM message WHERE user_identity = ?1 AND device_id = ?2 ORDER BY id",
+            "SELECT COUNT(*) FROM message WHERE user_identity = ?1 AND device_id = ?2",
+            "SELECT id FROM kem_pre_key WHERE user_identity = ?1 AND device_id = ?2 ORDER BY id LIMIT 1",
+        ] {
+            let plan = query_plan(&storage, sql).await?;
+            assert!(
+                plan.iter().all(|step| step.contains("USING")
+                    && step.contains("INDEX")
+                    && !step.contains("TEMP B-TREE")),
+                "{sql}: {plan:?}"
+            );
+        }
+        Ok(())
+    }
+
+    async fn row_count(storage: &SqliteStorage, table: &'static str) -> Result<usize> {
+        Ok(storage
+            .connection
+            .call(move |connection| {
+                Ok(
+                    connection.query_row(&format!("SELECT COUNT(*) FROM {table}"), (), |row| {
+                        row.get(0)
+                    })?,
+                )
+            })
+            .await?)
+    }
+
+    #[tokio::test]
+    async fn deleting_user_row_cascades() -> Result<()> {
+        let storage = SqliteStorage::new(Connection::open_in_memory().await?).await?;
+        register_bob(&storage).await?;
+        storage
+            .add_opks(
+                "bob",
+                DEFAULT_DEVICE_ID,
+                MemoryClient::new().create_opks(3)?.pre_keys,
+                OpkQuota::default(),
+            )
+            .await?;
+        storage
+            .add_message("bob", DEFAULT_DEVICE_ID, message_with_ciphertext(0))
+            .await?;
+
+        storage
+            .connection
+            .call(|connection| {
+                Ok(connection.execute("DELETE FROM user WHERE identity = 'bob'", ())?)
+            })
+            .await?;
+        assert_eq!(row_count(&storage, "pre_key").await?, 0);
+        assert_eq!(row_count(&storage, "message").await?, 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn deleting_user_forgets_message_uuids() -> Result<()> {
+        let storage = SqliteStorage::new(Connection::open_in_memory().await?).await?;
+        register_bob(&storage).await?;
+        storage.claim_message_uuid("bob", &[1; 16]).await?;
+        storage.claim_message_uuid("bob", &[2; 16]).await?;
+        assert_eq!(row_count(&storage, "message_uuid").await?, 2);
+
+        storage.delete_user("bob").await?;
+        assert_eq!(row_count(&storage, "message_uuid").await?, 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn cascade_migration_keeps_message_ids() -> Result<()> {
+        let connection = Connection::open_in_memory().await?;
+        connection
+            .call(|connection| {
+                let transaction = connection.transaction()?;
+                for migration in &MIGRATIONS[..4] {
+                    migration(&transaction).unwrap();
+                }
+                transaction.pragma_update(None, "user_version", 4)?;
+                Ok(transaction.commit()?)
+            })
+            .await?;
+        let bob = MemoryClient::new();
+        let (ik, spk) = (bob.get_ik()?.verifying_key().to_bytes(), {
+            let spk: SignedPreKeyProto = bob.get_spk()?.into();
+            spk.encode_to_vec()
+        });
+        connection
+            .call(move |connection| {
+                connection.execute(
+                    "INSERT INTO user VALUES ('bob', ?1, ?2, 0)",
+                    params![ik, spk],
+                )?;
+                connection.execute_batch(
+                    "INSERT INTO message (message, user_identity, creation_time) VALUES (x'00', 'bob', 0);
+                     INSERT INTO message (message, user_identity, creation_time) VALUES (x'01', 'bob', 0);
+                     DELETE FROM message WHERE message = x'01';",
+                )?;
+                Ok(())
+            })
+            .await?;
+
+        let storage = SqliteStorage::new(connection).await?;
+        storage
+            .add_message("bob", DEFAULT_DEVICE_ID, message_with_ciphertext(2))
+            .await?;
+        let ids: Vec<i64> = storage
+            .connection
+            .call(|connection| {
+                let mut stmt = connection.prepare("SELECT id FROM message ORDER BY id")?;
+                let ids = stmt
+                    .query_map((), |row| row.get(0))?
+                    .collect::<rusqlite::Result<_>>()?;
+                Ok(ids)
+            })
+            .await?;
+        assert_eq!(ids, vec![1, 3]);
+        Ok(())
+    }
+    #[tokio::test]
+    async fn skeleton_migration_keeps_identities() -> Result<()> {
+        let connection = Connection::open_in_memory().await?;
+        let bob = MemoryClient::new();
+        let (ik, spk) = (bob.get_ik()?.verifying_key().to_bytes(), {
+            let spk: SignedPreKeyProto = bob.get_spk()?.into();
+            spk.encode_to_vec()
+        });
+        connection
+            .call(move |connection| {
+                let transaction = connection.transaction()?;
+                // Every migration before identity skeletons.
+                let before = MIGRATIONS
+                    .iter()
+                    .position(|migration| *migration as usize == identity_skeletons as Migration as usize)
+                    .unwrap();
+                for migration in &MIGRATIONS[..before] {
+                    migration(&transaction).unwrap();
+                }
+                transaction.pragma_update(None, "user_version", before)?;
+                // Registered before identities were canonicalized, or validated at all.
+                for identity in ["Bob", "bob smith"] {
+                    transaction.execute(
+                        "INSERT INTO user (identity, device_id, key, current_pre_key, creation_time) VALUES (?1, 1, ?2, ?3, 0)",
+                        params![identity, ik, spk],
+                    )?;
+                }
+                Ok(transaction.commit()?)
+            })
+            .await?;
+
+        let storage = SqliteStorage::new(connection).await?;
+        assert!(storage.user_exists("Bob").await?);
+        assert_eq!(
+            storage.device_stats(Some("Bob")).await?[0].display_identity,
+            "Bob"
+        );
+        assert!(storage.user_exists("bob smith").await?);
+        let bob = MemoryClient::new();
+        assert_eq!(
+            storage
+                .register_user(
+                    "bob".parse()?,
+                    1,
+                    (&bob.get_ik()?).into(),
+                    bob.get_spk()?.into()
+                )
+                .await
+                .err()
+                .map(|e| e.code()),
+            Some(Code::AlreadyExists)
+        );
+        Ok(())
+    }
+
+    fn audit_entry(action: &str) -> AuditEntry {
+        AuditEntry {
+            actor: String::from("admin"),
+            action: action.to_owned(),
+            target: String::from("bob"),
+            time: SystemTime::now(),
+        }
+    }
+
+    /// Storage on a file at `path`, with another connection to it holding an exclusive
+    /// transaction. Statements wait only briefly for the lock so retries are quick to reach.
+    async fn locked_storage(
+        path: &std::path::Path,
+    ) -> Result<(SqliteStorage, rusqlite::Connection)> {
+        let _ = std::fs::remove_file(path);
+        let storage = SqliteStorage::new(Connection::open(path).await?).await?;
+        storage
+            .connection
+            .call(|connection| Ok(connection.busy_timeout(Duration::from_millis(10))?))
+            .await?;
+        let blocker = rusqlite::Connection::open(path)?;
+        blocker.execute_batch("BEGIN EXCLUSIVE")?;
+        Ok((storage, blocker))
+    }
+
+    fn remove_database(path: &std::path::Path) {
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{suffix}", path.display()));
+        }
+    }
+
+    #[tokio::test]
+    async fn busy_write_retried_once_released() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("brongnal-busy-{}.db3", std::process::id()));
+        let (storage, blocker) = locked_storage(&path).await?;
+        let release = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(100));
+            blocker.execute_batch("COMMIT")
+        });
+
+        storage.add_audit_entry(audit_entry("purge")).await?;
+        release.join().unwrap()?;
+        assert_eq!(storage.audit_log().await?.len(), 1);
+        drop(storage);
+        remove_database(&path);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn persistently_busy_write_unavailable() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("brongnal-locked-{}.db3", std::process::id()));
+        let (storage, blocker) = locked_storage(&path).await?;
+
+        assert_eq!(
+            storage
+                .add_audit_entry(audit_entry("purge"))
+                .await
+                .err()
+                .map(|e| e.code()),
+            Some(Code::Unavailable)
+        );
+        blocker.execute_batch("COMMIT")?;
+        drop(blocker);
+        let invite = || Invite {
+            code_hash: vec![1],
+            creator: String::from("admin"),
+            max_uses: 1,
+            uses: 0,
+            created_at: SystemTime::now(),
+            expires_at: None,
+            revoked: false,
+        };
+        // Constraint violations are not retried, nor mistaken for a busy database.
+        storage.add_invite(invite()).await?;
+        assert_eq!(
+            storage.add_invite(invite()).await.err().map(|e| e.code()),
+            Some(Code::AlreadyExists)
+        );
+        drop(storage);
+        remove_database(&path);
         Ok(())
     }
 }
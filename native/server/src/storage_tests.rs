@@ -0,0 +1,1596 @@
+//! Assertions shared by every `Storage` implementation so their behavior can't diverge.
+//! Invoke `storage_test_suite!` from an implementation's test module with an expression that
+//! constructs an empty storage.
+
+use crate::brongnal::{
+    AuditEntry, ExpiredMessage, Invite, InviteRejection, MailboxQuota, MessageClaim, OpkQuota,
+    PushToken, QuotaPolicy, Storage, StorageStats, OPK_RESERVATION_TTL,
+};
+use crate::push::PushPlatform;
+use anyhow::Result;
+use chacha20poly1305::aead::OsRng;
+use client::{memory_client::MemoryClient, X3DHClient};
+use ed25519_dalek::VerifyingKey;
+use proto::service::Message as MessageProto;
+use proto::service::SignedPreKey as SignedPreKeyProto;
+use proto::{SessionMessage, DEFAULT_DEVICE_ID};
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tonic::Code;
+use x25519_dalek::{PublicKey as X25519PublicKey, StaticSecret as X25519StaticSecret};
+
+pub const MAILBOX_REJECT: MailboxQuota = MailboxQuota {
+    max_messages: 2,
+    policy: QuotaPolicy::Reject,
+};
+
+pub const MAILBOX_EVICT: MailboxQuota = MailboxQuota {
+    max_messages: 2,
+    policy: QuotaPolicy::EvictOldest,
+};
+
+async fn register(storage: &impl Storage, identity: &str) -> Result<MemoryClient> {
+    Ok(register_spk(storage, identity).await?.0)
+}
+
+/// Like `register`, but also returns the signed pre key that was registered. Signatures are
+/// randomized, so signing the same key again doesn't reproduce it.
+async fn register_spk(
+    storage: &impl Storage,
+    identity: &str,
+) -> Result<(MemoryClient, SignedPreKeyProto)> {
+    let client = MemoryClient::new();
+    let spk: SignedPreKeyProto = client.get_spk()?.into();
+    storage
+        .register_user(
+            identity.parse()?,
+            DEFAULT_DEVICE_ID,
+            (&client.get_ik()?).into(),
+            spk.clone(),
+        )
+        .await?;
+    Ok((client, spk))
+}
+
+fn message(ciphertext: u8) -> MessageProto {
+    SessionMessage {
+        sender_identity: String::from("alice"),
+        ciphertext: vec![ciphertext],
+    }
+    .into()
+}
+
+pub async fn register_and_get_keys(storage: impl Storage) -> Result<()> {
+    let (bob, bob_spk) = register_spk(&storage, "bob").await?;
+    assert!(storage.user_exists("bob").await?);
+    let keys = storage.get_current_keys("bob", DEFAULT_DEVICE_ID).await?;
+    assert_eq!(
+        (keys.ik, keys.spk, keys.spk_id),
+        (VerifyingKey::from(&bob.get_ik()?), bob_spk, 1)
+    );
+    Ok(())
+}
+
+pub async fn unknown_user(storage: impl Storage) -> Result<()> {
+    assert!(!storage.user_exists("bob").await?);
+    assert_eq!(
+        storage
+            .get_current_keys("bob", DEFAULT_DEVICE_ID)
+            .await
+            .err()
+            .map(|e| e.code()),
+        Some(Code::NotFound)
+    );
+    assert_eq!(
+        storage
+            .update_spk("bob", DEFAULT_DEVICE_ID, SignedPreKeyProto::default())
+            .await
+            .err()
+            .map(|e| e.code()),
+        Some(Code::NotFound)
+    );
+    assert_eq!(
+        storage
+            .reserve_opk(
+                "bob",
+                DEFAULT_DEVICE_ID,
+                SystemTime::now() + OPK_RESERVATION_TTL
+            )
+            .await?,
+        None
+    );
+    assert_eq!(storage.count_opks("bob", DEFAULT_DEVICE_ID).await?, 0);
+    assert_eq!(
+        storage.get_messages("bob", DEFAULT_DEVICE_ID).await?,
+        vec![]
+    );
+    Ok(())
+}
+
+pub async fn update_spk(storage: impl Storage) -> Result<()> {
+    let mut bob = register(&storage, "bob").await?;
+    let mut bob_spk: SignedPreKeyProto = bob.get_spk()?.into();
+    bob_spk.pre_key = Some(bob.create_opks(1)?.pre_keys[0].to_bytes().to_vec());
+    storage
+        .update_spk("bob", DEFAULT_DEVICE_ID, bob_spk.clone())
+        .await?;
+    assert_eq!(
+        storage
+            .get_current_keys("bob", DEFAULT_DEVICE_ID)
+            .await?
+            .spk,
+        bob_spk
+    );
+    Ok(())
+}
+
+pub async fn opks_reserved_oldest_first(storage: impl Storage) -> Result<()> {
+    let mut bob = register(&storage, "bob").await?;
+    let first = bob.create_opks(2)?.pre_keys;
+    let second = bob.create_opks(1)?.pre_keys;
+    storage
+        .add_opks("bob", DEFAULT_DEVICE_ID, first.clone(), OpkQuota::default())
+        .await?;
+    storage
+        .add_opks(
+            "bob",
+            DEFAULT_DEVICE_ID,
+            second.clone(),
+            OpkQuota::default(),
+        )
+        .await?;
+    assert_eq!(storage.count_opks("bob", DEFAULT_DEVICE_ID).await?, 3);
+
+    assert_eq!(
+        storage
+            .reserve_opk(
+                "bob",
+                DEFAULT_DEVICE_ID,
+                SystemTime::now() + OPK_RESERVATION_TTL
+            )
+            .await?
+            .map(|(_, key)| key),
+        Some(first[0])
+    );
+    assert_eq!(
+        storage
+            .reserve_opk(
+                "bob",
+                DEFAULT_DEVICE_ID,
+                SystemTime::now() + OPK_RESERVATION_TTL
+            )
+            .await?
+            .map(|(_, key)| key),
+        Some(first[1])
+    );
+    assert_eq!(
+        storage
+            .reserve_opk(
+                "bob",
+                DEFAULT_DEVICE_ID,
+                SystemTime::now() + OPK_RESERVATION_TTL
+            )
+            .await?
+            .map(|(_, key)| key),
+        Some(second[0])
+    );
+    assert_eq!(
+        storage
+            .reserve_opk(
+                "bob",
+                DEFAULT_DEVICE_ID,
+                SystemTime::now() + OPK_RESERVATION_TTL
+            )
+            .await?,
+        None
+    );
+    Ok(())
+}
+
+pub async fn concurrent_reserve_opk(storage: impl Storage + Send + Sync + 'static) -> Result<()> {
+    let mut bob = register(&storage, "bob").await?;
+    let keys = bob.create_opks(20)?.pre_keys;
+    storage
+        .add_opks("bob", DEFAULT_DEVICE_ID, keys.clone(), OpkQuota::default())
+        .await?;
+
+    let storage = Arc::new(storage);
+    let mut pops = Vec::new();
+    for _ in 0..50 {
+        let storage = storage.clone();
+        pops.push(tokio::spawn(async move {
+            storage
+                .reserve_opk(
+                    "bob",
+                    DEFAULT_DEVICE_ID,
+                    SystemTime::now() + OPK_RESERVATION_TTL,
+                )
+                .await
+        }));
+    }
+    let mut popped = Vec::new();
+    for pop in pops {
+        popped.extend(pop.await??.map(|(_, key)| key));
+    }
+    assert_eq!(popped.len(), 20);
+    let unique: HashSet<[u8; 32]> = popped.iter().map(|key| key.to_bytes()).collect();
+    assert_eq!(unique.len(), 20);
+    assert!(popped.iter().all(|key| keys.contains(key)));
+    Ok(())
+}
+
+pub async fn opk_reservations(storage: impl Storage) -> Result<()> {
+    let mut bob = register(&storage, "bob").await?;
+    let keys = bob.create_opks(2)?.pre_keys;
+    let ids = storage
+        .add_opks("bob", DEFAULT_DEVICE_ID, keys.clone(), OpkQuota::default())
+        .await?;
+    let now = SystemTime::now();
+    let minute = Duration::from_secs(60);
+
+    // A reserved key is no longer available, and a message can consume it only once.
+    assert_eq!(
+        storage
+            .reserve_opk("bob", DEFAULT_DEVICE_ID, now + minute)
+            .await?,
+        Some((ids[0], keys[0]))
+    );
+    assert_eq!(storage.count_opks("bob", DEFAULT_DEVICE_ID).await?, 1);
+    assert!(
+        !storage
+            .consume_opk("bob", DEFAULT_DEVICE_ID, ids[1])
+            .await?
+    );
+    assert!(!storage.consume_opk("bob", 2, ids[0]).await?);
+    assert!(
+        storage
+            .consume_opk("bob", DEFAULT_DEVICE_ID, ids[0])
+            .await?
+    );
+    assert!(
+        !storage
+            .consume_opk("bob", DEFAULT_DEVICE_ID, ids[0])
+            .await?
+    );
+
+    // An expired reservation returns the key to the pool.
+    assert_eq!(
+        storage
+            .reserve_opk("bob", DEFAULT_DEVICE_ID, now + minute)
+            .await?,
+        Some((ids[1], keys[1]))
+    );
+    assert_eq!(
+        storage
+            .reserve_opk("bob", DEFAULT_DEVICE_ID, now + minute)
+            .await?,
+        None
+    );
+    assert_eq!(storage.release_expired_opk_reservations(now).await?, 0);
+    assert_eq!(
+        storage
+            .release_expired_opk_reservations(now + 2 * minute)
+            .await?,
+        1
+    );
+    assert_eq!(storage.count_opks("bob", DEFAULT_DEVICE_ID).await?, 1);
+    assert!(
+        !storage
+            .consume_opk("bob", DEFAULT_DEVICE_ID, ids[1])
+            .await?
+    );
+    assert_eq!(
+        storage
+            .reserve_opk("bob", DEFAULT_DEVICE_ID, now + minute)
+            .await?,
+        Some((ids[1], keys[1]))
+    );
+    Ok(())
+}
+
+pub async fn opk_quota(storage: impl Storage) -> Result<()> {
+    let mut bob = register(&storage, "bob").await?;
+    let reject = OpkQuota {
+        max_keys: 2,
+        policy: QuotaPolicy::Reject,
+    };
+    let evict = OpkQuota {
+        max_keys: 2,
+        policy: QuotaPolicy::EvictOldest,
+    };
+    let keys = bob.create_opks(3)?.pre_keys;
+
+    storage
+        .add_opks("bob", DEFAULT_DEVICE_ID, keys[..2].to_vec(), reject)
+        .await?;
+    assert_eq!(
+        storage
+            .add_opks("bob", DEFAULT_DEVICE_ID, keys[2..].to_vec(), reject)
+            .await
+            .err()
+            .map(|e| e.code()),
+        Some(Code::ResourceExhausted)
+    );
+    assert_eq!(storage.count_opks("bob", DEFAULT_DEVICE_ID).await?, 2);
+
+    storage
+        .add_opks("bob", DEFAULT_DEVICE_ID, keys[2..].to_vec(), evict)
+        .await?;
+    assert_eq!(
+        storage
+            .reserve_opk(
+                "bob",
+                DEFAULT_DEVICE_ID,
+                SystemTime::now() + OPK_RESERVATION_TTL
+            )
+            .await?
+            .map(|(_, key)| key),
+        Some(keys[1])
+    );
+    assert_eq!(
+        storage
+            .reserve_opk(
+                "bob",
+                DEFAULT_DEVICE_ID,
+                SystemTime::now() + OPK_RESERVATION_TTL
+            )
+            .await?
+            .map(|(_, key)| key),
+        Some(keys[2])
+    );
+    Ok(())
+}
+
+pub async fn replace_opks(storage: impl Storage) -> Result<()> {
+    let mut bob = register(&storage, "bob").await?;
+    storage
+        .add_opks(
+            "bob",
+            DEFAULT_DEVICE_ID,
+            bob.create_opks(3)?.pre_keys,
+            OpkQuota::default(),
+        )
+        .await?;
+    let keys = bob.create_opks(2)?.pre_keys;
+    storage
+        .replace_opks("bob", DEFAULT_DEVICE_ID, keys.clone(), OpkQuota::default())
+        .await?;
+
+    assert_eq!(storage.count_opks("bob", DEFAULT_DEVICE_ID).await?, 2);
+    assert_eq!(
+        storage
+            .reserve_opk(
+                "bob",
+                DEFAULT_DEVICE_ID,
+                SystemTime::now() + OPK_RESERVATION_TTL
+            )
+            .await?
+            .map(|(_, key)| key),
+        Some(keys[0])
+    );
+    assert_eq!(
+        storage
+            .reserve_opk(
+                "bob",
+                DEFAULT_DEVICE_ID,
+                SystemTime::now() + OPK_RESERVATION_TTL
+            )
+            .await?
+            .map(|(_, key)| key),
+        Some(keys[1])
+    );
+    assert_eq!(
+        storage
+            .reserve_opk(
+                "bob",
+                DEFAULT_DEVICE_ID,
+                SystemTime::now() + OPK_RESERVATION_TTL
+            )
+            .await?,
+        None
+    );
+    Ok(())
+}
+
+pub async fn duplicate_opks(storage: impl Storage) -> Result<()> {
+    let mut bob = register(&storage, "bob").await?;
+    register(&storage, "alice").await?;
+    let keys = bob.create_opks(2)?.pre_keys;
+    storage
+        .add_opks("bob", DEFAULT_DEVICE_ID, keys.clone(), OpkQuota::default())
+        .await?;
+    let reserved = storage
+        .reserve_opk(
+            "bob",
+            DEFAULT_DEVICE_ID,
+            SystemTime::now() + OPK_RESERVATION_TTL,
+        )
+        .await?
+        .map(|(_, key)| key);
+    assert_eq!(reserved, Some(keys[0]));
+
+    // No key is stored twice, so none can be handed out twice, even once reserved.
+    for result in [
+        storage
+            .add_opks("bob", DEFAULT_DEVICE_ID, vec![keys[1]], OpkQuota::default())
+            .await,
+        storage
+            .add_opks(
+                "alice",
+                DEFAULT_DEVICE_ID,
+                vec![keys[0]],
+                OpkQuota::default(),
+            )
+            .await,
+        storage
+            .replace_opks(
+                "alice",
+                DEFAULT_DEVICE_ID,
+                vec![keys[1]],
+                OpkQuota::default(),
+            )
+            .await,
+    ] {
+        assert_eq!(result.err().map(|e| e.code()), Some(Code::AlreadyExists));
+    }
+    assert_eq!(storage.count_opks("bob", DEFAULT_DEVICE_ID).await?, 1);
+    assert_eq!(storage.count_opks("alice", DEFAULT_DEVICE_ID).await?, 0);
+
+    // The keys a device replaces are discarded, so it may upload them again.
+    storage
+        .replace_opks("bob", DEFAULT_DEVICE_ID, keys, OpkQuota::default())
+        .await?;
+    assert_eq!(storage.count_opks("bob", DEFAULT_DEVICE_ID).await?, 2);
+    Ok(())
+}
+
+pub async fn reregister_replaces_keys(storage: impl Storage) -> Result<()> {
+    register(&storage, "bob").await?;
+    let (bob, bob_spk) = register_spk(&storage, "bob").await?;
+    let keys = storage.get_current_keys("bob", DEFAULT_DEVICE_ID).await?;
+    assert_eq!(
+        (keys.ik, keys.spk, keys.spk_id),
+        (VerifyingKey::from(&bob.get_ik()?), bob_spk, 2)
+    );
+    Ok(())
+}
+
+pub async fn pre_key_ids(storage: impl Storage) -> Result<()> {
+    let mut bob = MemoryClient::new();
+    let bob_ik = VerifyingKey::from(&bob.get_ik()?);
+    let mut bob_spk: SignedPreKeyProto = bob.get_spk()?.into();
+    let spk_id = storage
+        .register_user("bob".parse()?, DEFAULT_DEVICE_ID, bob_ik, bob_spk.clone())
+        .await?;
+    assert_eq!(
+        storage
+            .register_user("bob".parse()?, DEFAULT_DEVICE_ID, bob_ik, bob_spk.clone())
+            .await?,
+        spk_id
+    );
+    assert_eq!(
+        storage
+            .update_spk("bob", DEFAULT_DEVICE_ID, bob_spk.clone())
+            .await?,
+        spk_id
+    );
+    bob_spk.pre_key = Some(bob.create_opks(1)?.pre_keys[0].to_bytes().to_vec());
+    let new_spk_id = storage
+        .update_spk("bob", DEFAULT_DEVICE_ID, bob_spk)
+        .await?;
+    assert_ne!(new_spk_id, spk_id);
+    assert_eq!(
+        storage
+            .get_current_keys("bob", DEFAULT_DEVICE_ID)
+            .await?
+            .spk_id,
+        new_spk_id
+    );
+
+    let keys = bob.create_opks(2)?.pre_keys;
+    let ids = storage
+        .add_opks("bob", DEFAULT_DEVICE_ID, keys.clone(), OpkQuota::default())
+        .await?;
+    assert_eq!(ids.len(), 2);
+    assert_ne!(ids[0], ids[1]);
+    assert_eq!(
+        storage
+            .reserve_opk(
+                "bob",
+                DEFAULT_DEVICE_ID,
+                SystemTime::now() + OPK_RESERVATION_TTL
+            )
+            .await?,
+        Some((ids[0], keys[0]))
+    );
+    let replaced = storage
+        .replace_opks(
+            "bob",
+            DEFAULT_DEVICE_ID,
+            bob.create_opks(1)?.pre_keys,
+            OpkQuota::default(),
+        )
+        .await?;
+    assert!(!ids.contains(&replaced[0]));
+    Ok(())
+}
+
+pub async fn stale_spks(storage: impl Storage) -> Result<()> {
+    let before = SystemTime::now() - Duration::from_secs(1);
+    register(&storage, "bob").await?;
+
+    let day = Duration::from_secs(24 * 60 * 60);
+    assert!(
+        storage
+            .get_current_keys("bob", DEFAULT_DEVICE_ID)
+            .await?
+            .spk_uploaded_at
+            >= before
+    );
+    assert_eq!(storage.count_stale_spks(SystemTime::now() - day).await?, 0);
+    assert_eq!(storage.count_stale_spks(SystemTime::now() + day).await?, 1);
+    Ok(())
+}
+
+pub async fn messages_delivered_in_order(storage: impl Storage) -> Result<()> {
+    register(&storage, "bob").await?;
+    register(&storage, "carol").await?;
+    for i in 0..3 {
+        storage
+            .add_message("bob", DEFAULT_DEVICE_ID, message(i))
+            .await?;
+    }
+    storage
+        .add_message("carol", DEFAULT_DEVICE_ID, message(3))
+        .await?;
+
+    assert_eq!(
+        storage.get_messages("bob", DEFAULT_DEVICE_ID).await?,
+        vec![message(0), message(1), message(2)]
+    );
+    assert_eq!(
+        storage.get_messages("bob", DEFAULT_DEVICE_ID).await?,
+        vec![]
+    );
+    assert_eq!(
+        storage.get_messages("carol", DEFAULT_DEVICE_ID).await?,
+        vec![message(3)]
+    );
+    Ok(())
+}
+
+/// Expects a storage configured with `MAILBOX_REJECT`.
+pub async fn mailbox_quota_reject(storage: impl Storage) -> Result<()> {
+    register(&storage, "bob").await?;
+    for i in 0..2 {
+        storage
+            .add_message("bob", DEFAULT_DEVICE_ID, message(i))
+            .await?;
+    }
+    assert_eq!(
+        storage
+            .add_message("bob", DEFAULT_DEVICE_ID, message(2))
+            .await
+            .err()
+            .map(|e| e.code()),
+        Some(Code::ResourceExhausted)
+    );
+    assert_eq!(
+        storage.get_messages("bob", DEFAULT_DEVICE_ID).await?,
+        vec![message(0), message(1)]
+    );
+    Ok(())
+}
+
+/// Expects a storage configured with `MAILBOX_EVICT`.
+pub async fn mailbox_quota_evict_oldest(storage: impl Storage) -> Result<()> {
+    register(&storage, "bob").await?;
+    for i in 0..4 {
+        storage
+            .add_message("bob", DEFAULT_DEVICE_ID, message(i))
+            .await?;
+    }
+    assert_eq!(
+        storage.get_messages("bob", DEFAULT_DEVICE_ID).await?,
+        vec![message(2), message(3)]
+    );
+    Ok(())
+}
+
+pub async fn purge_expired_messages(storage: impl Storage) -> Result<()> {
+    register(&storage, "bob").await?;
+    storage
+        .add_message("bob", DEFAULT_DEVICE_ID, message(0))
+        .await?;
+
+    let day = Duration::from_secs(24 * 60 * 60);
+    assert_eq!(
+        storage
+            .purge_expired_messages(SystemTime::now() - day)
+            .await?,
+        vec![]
+    );
+    assert_eq!(
+        storage
+            .purge_expired_messages(SystemTime::now() + day)
+            .await?,
+        vec![ExpiredMessage {
+            recipient: String::from("bob"),
+            device_id: DEFAULT_DEVICE_ID,
+            message: message(0),
+        }]
+    );
+    assert_eq!(
+        storage.get_messages("bob", DEFAULT_DEVICE_ID).await?,
+        vec![]
+    );
+    Ok(())
+}
+
+pub async fn delete_user(storage: impl Storage) -> Result<()> {
+    let mut bob = register(&storage, "bob").await?;
+    register(&storage, "carol").await?;
+    storage
+        .add_opks(
+            "bob",
+            DEFAULT_DEVICE_ID,
+            bob.create_opks(2)?.pre_keys,
+            OpkQuota::default(),
+        )
+        .await?;
+    storage
+        .add_message("bob", DEFAULT_DEVICE_ID, message(0))
+        .await?;
+    storage
+        .add_message("carol", DEFAULT_DEVICE_ID, message(1))
+        .await?;
+    storage.claim_message_uuid("bob", &[1; 16]).await?;
+    let MessageClaim::New(carol_id) = storage.claim_message_uuid("carol", &[1; 16]).await? else {
+        panic!("The first claim of a uuid is new.");
+    };
+
+    storage.delete_user("bob").await?;
+    assert!(!storage.user_exists("bob").await?);
+    assert_eq!(storage.count_opks("bob", DEFAULT_DEVICE_ID).await?, 0);
+    assert_eq!(
+        storage.get_messages("bob", DEFAULT_DEVICE_ID).await?,
+        vec![]
+    );
+    assert_eq!(
+        storage.delete_user("bob").await.err().map(|e| e.code()),
+        Some(Code::NotFound)
+    );
+    assert_eq!(
+        storage.get_messages("carol", DEFAULT_DEVICE_ID).await?,
+        vec![message(1)]
+    );
+    // Nothing records which uuids bob was sent, but carol's are kept.
+    assert!(matches!(
+        storage.claim_message_uuid("bob", &[1; 16]).await?,
+        MessageClaim::New(_)
+    ));
+    assert_eq!(
+        storage.claim_message_uuid("carol", &[1; 16]).await?,
+        MessageClaim::Duplicate(carol_id)
+    );
+    Ok(())
+}
+
+pub async fn last_resort_key(storage: impl Storage) -> Result<()> {
+    register(&storage, "bob").await?;
+    let first = X25519PublicKey::from(&X25519StaticSecret::random_from_rng(OsRng));
+    let second = X25519PublicKey::from(&X25519StaticSecret::random_from_rng(OsRng));
+    assert_eq!(
+        storage
+            .get_last_resort_key("bob", DEFAULT_DEVICE_ID)
+            .await?,
+        None
+    );
+    assert_eq!(
+        storage
+            .set_last_resort_key("bob", DEFAULT_DEVICE_ID, Some(first))
+            .await?,
+        1
+    );
+    assert_eq!(
+        storage
+            .set_last_resort_key("bob", DEFAULT_DEVICE_ID, Some(first))
+            .await?,
+        1
+    );
+    // Handing out the last-resort key doesn't use it up.
+    for _ in 0..2 {
+        assert_eq!(
+            storage
+                .get_last_resort_key("bob", DEFAULT_DEVICE_ID)
+                .await?,
+            Some((1, first))
+        );
+    }
+    assert_eq!(
+        storage
+            .set_last_resort_key("bob", DEFAULT_DEVICE_ID, Some(second))
+            .await?,
+        2
+    );
+    assert_eq!(
+        storage
+            .get_last_resort_key("bob", DEFAULT_DEVICE_ID)
+            .await?,
+        Some((2, second))
+    );
+    storage
+        .set_last_resort_key("bob", DEFAULT_DEVICE_ID, None)
+        .await?;
+    assert_eq!(
+        storage
+            .get_last_resort_key("bob", DEFAULT_DEVICE_ID)
+            .await?,
+        None
+    );
+    assert_eq!(
+        storage
+            .set_last_resort_key("carol", DEFAULT_DEVICE_ID, Some(first))
+            .await
+            .err()
+            .map(|e| e.code()),
+        Some(Code::NotFound)
+    );
+
+    storage
+        .set_last_resort_key("bob", DEFAULT_DEVICE_ID, Some(first))
+        .await?;
+    storage.delete_device("bob", DEFAULT_DEVICE_ID).await?;
+    register(&storage, "bob").await?;
+    assert_eq!(
+        storage
+            .get_last_resort_key("bob", DEFAULT_DEVICE_ID)
+            .await?,
+        None
+    );
+    Ok(())
+}
+
+fn kem_key(byte: u8) -> SignedPreKeyProto {
+    SignedPreKeyProto {
+        pre_key: Some(vec![byte; 4]),
+        signature: Some(vec![byte; 4]),
+    }
+}
+
+pub async fn kem_pre_keys(storage: impl Storage) -> Result<()> {
+    assert_eq!(
+        storage
+            .replace_kem_opks("bob", DEFAULT_DEVICE_ID, vec![kem_key(1)])
+            .await
+            .unwrap_err()
+            .code(),
+        Code::NotFound
+    );
+    assert_eq!(
+        storage
+            .set_last_resort_kem_key("bob", DEFAULT_DEVICE_ID, Some(kem_key(1)))
+            .await
+            .unwrap_err()
+            .code(),
+        Code::NotFound
+    );
+    register(&storage, "bob").await?;
+    assert_eq!(storage.pop_kem_opk("bob", DEFAULT_DEVICE_ID).await?, None);
+
+    storage
+        .replace_kem_opks("bob", DEFAULT_DEVICE_ID, vec![kem_key(1)])
+        .await?;
+    // Replacing discards the keys uploaded before.
+    let ids = storage
+        .replace_kem_opks("bob", DEFAULT_DEVICE_ID, vec![kem_key(2), kem_key(3)])
+        .await?;
+    assert_eq!(ids.len(), 2);
+    assert_eq!(
+        storage.pop_kem_opk("bob", DEFAULT_DEVICE_ID).await?,
+        Some((ids[0], kem_key(2)))
+    );
+    assert_eq!(
+        storage.pop_kem_opk("bob", DEFAULT_DEVICE_ID).await?,
+        Some((ids[1], kem_key(3)))
+    );
+    assert_eq!(storage.pop_kem_opk("bob", DEFAULT_DEVICE_ID).await?, None);
+
+    assert_eq!(
+        storage
+            .get_last_resort_kem_key("bob", DEFAULT_DEVICE_ID)
+            .await?,
+        None
+    );
+    for _ in 0..2 {
+        assert_eq!(
+            storage
+                .set_last_resort_kem_key("bob", DEFAULT_DEVICE_ID, Some(kem_key(4)))
+                .await?,
+            1
+        );
+    }
+    assert_eq!(
+        storage
+            .set_last_resort_kem_key("bob", DEFAULT_DEVICE_ID, Some(kem_key(5)))
+            .await?,
+        2
+    );
+    assert_eq!(
+        storage
+            .get_last_resort_kem_key("bob", DEFAULT_DEVICE_ID)
+            .await?,
+        Some((2, kem_key(5)))
+    );
+    storage
+        .set_last_resort_kem_key("bob", DEFAULT_DEVICE_ID, None)
+        .await?;
+    assert_eq!(
+        storage
+            .get_last_resort_kem_key("bob", DEFAULT_DEVICE_ID)
+            .await?,
+        None
+    );
+
+    storage
+        .replace_kem_opks("bob", DEFAULT_DEVICE_ID, vec![kem_key(6)])
+        .await?;
+    storage
+        .set_last_resort_kem_key("bob", DEFAULT_DEVICE_ID, Some(kem_key(7)))
+        .await?;
+    storage.delete_device("bob", DEFAULT_DEVICE_ID).await?;
+    assert_eq!(storage.pop_kem_opk("bob", DEFAULT_DEVICE_ID).await?, None);
+    assert_eq!(
+        storage
+            .get_last_resort_kem_key("bob", DEFAULT_DEVICE_ID)
+            .await?,
+        None
+    );
+    Ok(())
+}
+
+/// The read-only queries an account export is built from leave everything where it was.
+pub async fn export_queries(storage: impl Storage) -> Result<()> {
+    let mut bob = register(&storage, "bob").await?;
+    let pre_keys = bob.create_opks(3)?.pre_keys;
+    let ids = storage
+        .add_opks(
+            "bob",
+            DEFAULT_DEVICE_ID,
+            pre_keys.clone(),
+            OpkQuota::default(),
+        )
+        .await?;
+    storage
+        .reserve_opk(
+            "bob",
+            DEFAULT_DEVICE_ID,
+            SystemTime::now() + OPK_RESERVATION_TTL,
+        )
+        .await?;
+    let kem_ids = storage
+        .replace_kem_opks("bob", DEFAULT_DEVICE_ID, vec![kem_key(1), kem_key(2)])
+        .await?;
+    let before = SystemTime::now() - Duration::from_secs(1);
+    storage
+        .add_message("bob", DEFAULT_DEVICE_ID, message(0))
+        .await?;
+    storage
+        .add_message("bob", DEFAULT_DEVICE_ID, message(1))
+        .await?;
+
+    // The reserved key is no longer available to senders.
+    let expected: Vec<_> = ids.into_iter().zip(pre_keys).skip(1).collect();
+    for _ in 0..2 {
+        assert_eq!(storage.get_opks("bob", DEFAULT_DEVICE_ID).await?, expected);
+        assert_eq!(
+            storage.get_kem_opks("bob", DEFAULT_DEVICE_ID).await?,
+            vec![(kem_ids[0], kem_key(1)), (kem_ids[1], kem_key(2))]
+        );
+        let queued = storage.peek_messages("bob", DEFAULT_DEVICE_ID).await?;
+        assert_eq!(
+            queued
+                .iter()
+                .map(|queued| &queued.message)
+                .collect::<Vec<_>>(),
+            vec![&message(0), &message(1)]
+        );
+        assert!(queued.iter().all(|queued| queued.queued_at >= before));
+    }
+    assert_eq!(
+        storage.get_messages("bob", DEFAULT_DEVICE_ID).await?,
+        vec![message(0), message(1)]
+    );
+
+    assert_eq!(storage.get_opks("carol", DEFAULT_DEVICE_ID).await?, vec![]);
+    assert_eq!(
+        storage.get_kem_opks("carol", DEFAULT_DEVICE_ID).await?,
+        vec![]
+    );
+    assert_eq!(
+        storage.peek_messages("carol", DEFAULT_DEVICE_ID).await?,
+        vec![]
+    );
+    Ok(())
+}
+
+pub async fn cipher_suite(storage: impl Storage) -> Result<()> {
+    assert_eq!(
+        storage
+            .set_cipher_suite("bob", DEFAULT_DEVICE_ID, Some(1))
+            .await
+            .unwrap_err()
+            .code(),
+        Code::NotFound
+    );
+    register(&storage, "bob").await?;
+    let cipher_suite = || async {
+        storage
+            .get_current_keys("bob", DEFAULT_DEVICE_ID)
+            .await
+            .map(|keys| keys.cipher_suite)
+    };
+    assert_eq!(cipher_suite().await?, None);
+    storage
+        .set_cipher_suite("bob", DEFAULT_DEVICE_ID, Some(1))
+        .await?;
+    assert_eq!(cipher_suite().await?, Some(1));
+    // Suites the server doesn't know are relayed all the same.
+    storage
+        .set_cipher_suite("bob", DEFAULT_DEVICE_ID, Some(99))
+        .await?;
+    assert_eq!(cipher_suite().await?, Some(99));
+    storage
+        .set_cipher_suite("bob", DEFAULT_DEVICE_ID, None)
+        .await?;
+    assert_eq!(cipher_suite().await?, None);
+    Ok(())
+}
+
+fn push_token(platform: PushPlatform, token: &str, app_version: &str) -> PushToken {
+    PushToken {
+        platform,
+        token: token.to_owned(),
+        app_version: Some(app_version.to_owned()),
+    }
+}
+
+pub async fn push_tokens(storage: impl Storage) -> Result<()> {
+    let old = push_token(PushPlatform::Fcm, "old", "1.0");
+    assert_eq!(
+        storage
+            .set_push_token("bob", DEFAULT_DEVICE_ID, old.clone())
+            .await
+            .unwrap_err()
+            .code(),
+        Code::NotFound
+    );
+    register(&storage, "bob").await?;
+    assert_eq!(
+        storage.get_push_token("bob", DEFAULT_DEVICE_ID).await?,
+        None
+    );
+    storage
+        .set_push_token("bob", DEFAULT_DEVICE_ID, old.clone())
+        .await?;
+    assert_eq!(
+        storage.get_push_token("bob", DEFAULT_DEVICE_ID).await?,
+        Some(old)
+    );
+
+    // Registering again replaces the old token, even on another platform.
+    let new = PushToken {
+        app_version: None,
+        ..push_token(PushPlatform::Apns, "new", "")
+    };
+    storage
+        .set_push_token("bob", DEFAULT_DEVICE_ID, new.clone())
+        .await?;
+    assert_eq!(
+        storage.get_push_token("bob", DEFAULT_DEVICE_ID).await?,
+        Some(new.clone())
+    );
+
+    // A late rejection of the old token leaves the new one alone.
+    storage
+        .delete_push_token("bob", DEFAULT_DEVICE_ID, "old")
+        .await?;
+    assert_eq!(
+        storage.get_push_token("bob", DEFAULT_DEVICE_ID).await?,
+        Some(new)
+    );
+    storage
+        .delete_push_token("bob", DEFAULT_DEVICE_ID, "new")
+        .await?;
+    assert_eq!(
+        storage.get_push_token("bob", DEFAULT_DEVICE_ID).await?,
+        None
+    );
+
+    storage
+        .set_push_token(
+            "bob",
+            DEFAULT_DEVICE_ID,
+            push_token(PushPlatform::Fcm, "token", "1.0"),
+        )
+        .await?;
+    storage.delete_device("bob", DEFAULT_DEVICE_ID).await?;
+    assert_eq!(
+        storage.get_push_token("bob", DEFAULT_DEVICE_ID).await?,
+        None
+    );
+    Ok(())
+}
+
+pub async fn message_uuids(storage: impl Storage) -> Result<()> {
+    let MessageClaim::New(id) = storage.claim_message_uuid("bob", &[1; 16]).await? else {
+        panic!("The first claim of a uuid is new.");
+    };
+    assert_eq!(
+        storage.claim_message_uuid("bob", &[1; 16]).await?,
+        MessageClaim::Duplicate(id)
+    );
+
+    // Uuids are only unique per recipient.
+    let MessageClaim::New(other_id) = storage.claim_message_uuid("carol", &[1; 16]).await? else {
+        panic!("Another recipient's uuids are separate.");
+    };
+    assert_ne!(other_id, id);
+    assert!(matches!(
+        storage.claim_message_uuid("bob", &[2; 16]).await?,
+        MessageClaim::New(_)
+    ));
+
+    storage.release_message_uuid("bob", &[1; 16]).await?;
+    assert!(matches!(
+        storage.claim_message_uuid("bob", &[1; 16]).await?,
+        MessageClaim::New(_)
+    ));
+
+    // Claims expire along with the messages they were made for.
+    storage
+        .purge_expired_messages(SystemTime::now() + Duration::from_secs(1))
+        .await?;
+    assert!(matches!(
+        storage.claim_message_uuid("carol", &[1; 16]).await?,
+        MessageClaim::New(_)
+    ));
+    Ok(())
+}
+
+pub async fn multiple_devices(storage: impl Storage) -> Result<()> {
+    let mut phone = register(&storage, "bob").await?;
+    let mut laptop = MemoryClient::new();
+    storage
+        .register_user(
+            "bob".parse()?,
+            2,
+            (&laptop.get_ik()?).into(),
+            laptop.get_spk()?.into(),
+        )
+        .await?;
+    assert_eq!(
+        storage.get_devices("bob").await?,
+        vec![DEFAULT_DEVICE_ID, 2]
+    );
+    assert_eq!(
+        storage.get_current_keys("bob", 2).await?.ik,
+        VerifyingKey::from(&laptop.get_ik()?)
+    );
+
+    let phone_opk = phone.create_opks(1)?.pre_keys[0];
+    let laptop_opk = laptop.create_opks(1)?.pre_keys[0];
+    storage
+        .add_opks(
+            "bob",
+            DEFAULT_DEVICE_ID,
+            vec![phone_opk],
+            OpkQuota::default(),
+        )
+        .await?;
+    storage
+        .add_opks("bob", 2, vec![laptop_opk], OpkQuota::default())
+        .await?;
+    storage
+        .add_message("bob", DEFAULT_DEVICE_ID, message(0))
+        .await?;
+    storage.add_message("bob", 2, message(1)).await?;
+    assert_eq!(
+        storage
+            .reserve_opk("bob", 2, SystemTime::now() + OPK_RESERVATION_TTL)
+            .await?
+            .map(|(_, opk)| opk),
+        Some(laptop_opk)
+    );
+    assert_eq!(storage.count_opks("bob", DEFAULT_DEVICE_ID).await?, 1);
+    assert_eq!(storage.get_messages("bob", 2).await?, vec![message(1)]);
+
+    storage.delete_device("bob", 2).await?;
+    assert_eq!(storage.get_devices("bob").await?, vec![DEFAULT_DEVICE_ID]);
+    assert!(storage.user_exists("bob").await?);
+    assert_eq!(
+        storage
+            .delete_device("bob", 2)
+            .await
+            .err()
+            .map(|e| e.code()),
+        Some(Code::NotFound)
+    );
+    assert_eq!(
+        storage.get_messages("bob", DEFAULT_DEVICE_ID).await?,
+        vec![message(0)]
+    );
+
+    storage.delete_device("bob", DEFAULT_DEVICE_ID).await?;
+    assert!(!storage.user_exists("bob").await?);
+    Ok(())
+}
+
+/// Registers a new device for `identity`, which is given in the form the device registers it in.
+async fn register_device(storage: &impl Storage, identity: &str, device_id: u32) -> Option<Code> {
+    let client = MemoryClient::new();
+    storage
+        .register_user(
+            identity.parse().unwrap(),
+            device_id,
+            (&client.get_ik().unwrap()).into(),
+            client.get_spk().unwrap().into(),
+        )
+        .await
+        .err()
+        .map(|e| e.code())
+}
+
+pub async fn lookalike_identities(storage: impl Storage) -> Result<()> {
+    assert_eq!(register_device(&storage, "Alice", 1).await, None);
+    assert_eq!(register_device(&storage, "Alice", 2).await, None);
+    assert!(storage.user_exists("alice").await?);
+    assert!(!storage.user_exists("Alice").await?);
+    let devices = storage.device_stats(Some("alice")).await?;
+    assert_eq!(devices.len(), 2);
+    assert!(devices
+        .iter()
+        .all(|device| device.identity == "alice" && device.display_identity == "Alice"));
+
+    // The same identity in another form, including normalized to the same canonical form, and a
+    // basic confusable pair: the first letter is Cyrillic.
+    for identity in ["alice", "ALICE", "ａｌｉｃｅ", "аlice"] {
+        assert_eq!(
+            register_device(&storage, identity, 3).await,
+            Some(Code::AlreadyExists),
+            "{identity:?}"
+        );
+    }
+    assert_eq!(storage.get_devices("alice").await?, vec![1, 2]);
+    assert!(!storage.user_exists("аlice").await?);
+
+    // A device that is the identity's only one may register it anew in another form.
+    assert_eq!(register_device(&storage, "раураl", 1).await, None);
+    assert_eq!(
+        register_device(&storage, "paypal", 1).await,
+        Some(Code::AlreadyExists)
+    );
+    assert_eq!(register_device(&storage, "РАУРАl", 1).await, None);
+    assert_eq!(
+        storage.device_stats(Some("раураl")).await?[0].display_identity,
+        "РАУРАl"
+    );
+
+    // Lookalikes are free again once the identity is gone.
+    storage.delete_user("alice").await?;
+    assert_eq!(register_device(&storage, "аlice", 1).await, None);
+    Ok(())
+}
+
+pub async fn stats(storage: impl Storage) -> Result<()> {
+    assert_eq!(storage.device_stats(None).await?, Vec::new());
+    assert_eq!(storage.stats(10).await?.devices, 0);
+
+    let before = SystemTime::now() - Duration::from_secs(1);
+    let mut bob = register(&storage, "bob").await?;
+    register(&storage, "alice").await?;
+    let laptop = MemoryClient::new();
+    storage
+        .register_user(
+            "bob".parse()?,
+            2,
+            (&laptop.get_ik()?).into(),
+            laptop.get_spk()?.into(),
+        )
+        .await?;
+    storage
+        .add_opks(
+            "bob",
+            DEFAULT_DEVICE_ID,
+            bob.create_opks(3)?.pre_keys,
+            OpkQuota::default(),
+        )
+        .await?;
+    storage
+        .reserve_opk(
+            "bob",
+            DEFAULT_DEVICE_ID,
+            SystemTime::now() + OPK_RESERVATION_TTL,
+        )
+        .await?;
+    storage.add_message("bob", 2, message(0)).await?;
+    storage.add_message("bob", 2, message(1)).await?;
+    storage
+        .set_push_token("bob", 2, push_token(PushPlatform::Fcm, "token", "1.0"))
+        .await?;
+
+    let stats = storage.device_stats(None).await?;
+    assert_eq!(
+        stats
+            .iter()
+            .map(|device| (device.identity.as_str(), device.device_id))
+            .collect::<Vec<_>>(),
+        vec![
+            ("alice", DEFAULT_DEVICE_ID),
+            ("bob", DEFAULT_DEVICE_ID),
+            ("bob", 2)
+        ]
+    );
+    for device in &stats {
+        assert!(device.registered_at >= before);
+        assert!(device.spk_uploaded_at >= before);
+    }
+    // Reserved keys aren't available to hand out.
+    assert_eq!(
+        stats
+            .iter()
+            .map(|device| (
+                device.opk_count,
+                device.queued_messages,
+                device.has_push_token
+            ))
+            .collect::<Vec<_>>(),
+        vec![(0, 0, false), (2, 0, false), (0, 2, true)]
+    );
+    assert_eq!(storage.device_stats(Some("bob")).await?, stats[1..]);
+    assert_eq!(storage.device_stats(Some("carol")).await?, Vec::new());
+
+    storage
+        .add_message("alice", DEFAULT_DEVICE_ID, message(2))
+        .await?;
+    let totals = storage.stats(10).await?;
+    let oldest_message_at = totals.oldest_message_at.unwrap();
+    assert!(oldest_message_at >= before && oldest_message_at <= SystemTime::now());
+    assert_eq!(
+        totals,
+        StorageStats {
+            identities: 2,
+            devices: 3,
+            opks: 2,
+            queued_messages: 3,
+            table_rows: totals.table_rows.clone(),
+            size_bytes: totals.size_bytes,
+            wal_size_bytes: totals.wal_size_bytes,
+            oldest_message_at: totals.oldest_message_at,
+            deepest_queues: vec![(String::from("bob"), 2), (String::from("alice"), 1)],
+        }
+    );
+    assert_eq!(
+        totals.table_rows.keys().collect::<Vec<_>>(),
+        [
+            "audit_log",
+            "invite",
+            "kem_pre_key",
+            "message",
+            "message_uuid",
+            "pre_key",
+            "push_token",
+            "user"
+        ]
+    );
+    // Reserved keys still take up rows.
+    for (table, rows) in [
+        ("user", 3),
+        ("pre_key", 3),
+        ("message", 3),
+        ("push_token", 1),
+    ] {
+        assert_eq!(totals.table_rows.get(table), Some(&rows), "{table}");
+    }
+    assert_eq!(
+        storage.stats(1).await?.deepest_queues,
+        vec![(String::from("bob"), 2)]
+    );
+    Ok(())
+}
+
+pub async fn purge_messages(storage: impl Storage) -> Result<()> {
+    assert_eq!(
+        storage.purge_messages("bob").await.unwrap_err().code(),
+        Code::NotFound
+    );
+    register(&storage, "bob").await?;
+    register(&storage, "alice").await?;
+    let laptop = MemoryClient::new();
+    storage
+        .register_user(
+            "bob".parse()?,
+            2,
+            (&laptop.get_ik()?).into(),
+            laptop.get_spk()?.into(),
+        )
+        .await?;
+    storage
+        .add_message("bob", DEFAULT_DEVICE_ID, message(0))
+        .await?;
+    storage.add_message("bob", 2, message(1)).await?;
+    storage
+        .add_message("alice", DEFAULT_DEVICE_ID, message(2))
+        .await?;
+
+    assert_eq!(storage.purge_messages("bob").await?, 2);
+    assert!(storage.user_exists("bob").await?);
+    assert_eq!(
+        storage.get_devices("bob").await?,
+        vec![DEFAULT_DEVICE_ID, 2]
+    );
+    assert_eq!(
+        storage.get_messages("bob", DEFAULT_DEVICE_ID).await?,
+        Vec::new()
+    );
+    assert_eq!(storage.get_messages("bob", 2).await?, Vec::new());
+    assert_eq!(
+        storage.get_messages("alice", DEFAULT_DEVICE_ID).await?,
+        vec![message(2)]
+    );
+
+    // Messages queued after the purge are delivered.
+    storage.add_message("bob", 2, message(3)).await?;
+    assert_eq!(storage.purge_messages("alice").await?, 0);
+    assert_eq!(storage.get_messages("bob", 2).await?, vec![message(3)]);
+    Ok(())
+}
+
+pub async fn audit_log(storage: impl Storage) -> Result<()> {
+    assert_eq!(storage.audit_log().await?, Vec::new());
+    let entry = |action: &str, seconds: u64| AuditEntry {
+        actor: String::from("oncall"),
+        action: action.to_owned(),
+        target: String::from("bob"),
+        time: UNIX_EPOCH + Duration::from_secs(seconds),
+    };
+    storage
+        .add_audit_entry(entry("purge_messages", 100))
+        .await?;
+    storage.add_audit_entry(entry("delete_user", 200)).await?;
+    assert_eq!(
+        storage.audit_log().await?,
+        vec![entry("purge_messages", 100), entry("delete_user", 200)]
+    );
+    Ok(())
+}
+
+pub async fn invites(storage: impl Storage) -> Result<()> {
+    let invite = |code_hash: &[u8], max_uses: u32, expires_at: Option<u64>| Invite {
+        code_hash: code_hash.to_vec(),
+        creator: String::from("oncall"),
+        max_uses,
+        uses: 0,
+        created_at: UNIX_EPOCH + Duration::from_secs(100),
+        expires_at: expires_at.map(|seconds| UNIX_EPOCH + Duration::from_secs(seconds)),
+        revoked: false,
+    };
+    let now = UNIX_EPOCH + Duration::from_secs(200);
+    storage.add_invite(invite(b"once", 1, None)).await?;
+    storage.add_invite(invite(b"twice", 2, Some(300))).await?;
+    storage.add_invite(invite(b"expired", 1, Some(200))).await?;
+    assert_eq!(
+        storage
+            .add_invite(invite(b"once", 5, None))
+            .await
+            .unwrap_err()
+            .code(),
+        Code::AlreadyExists
+    );
+
+    assert_eq!(storage.redeem_invite(b"once", now).await?, Ok(()));
+    assert_eq!(
+        storage.redeem_invite(b"once", now).await?,
+        Err(InviteRejection::UsedUp)
+    );
+    assert_eq!(storage.redeem_invite(b"twice", now).await?, Ok(()));
+    assert_eq!(
+        storage.redeem_invite(b"expired", now).await?,
+        Err(InviteRejection::Expired)
+    );
+    assert_eq!(
+        storage.redeem_invite(b"unknown", now).await?,
+        Err(InviteRejection::Unknown)
+    );
+
+    storage.revoke_invite(b"twice").await?;
+    assert_eq!(
+        storage.redeem_invite(b"twice", now).await?,
+        Err(InviteRejection::Revoked)
+    );
+    assert_eq!(
+        storage.revoke_invite(b"unknown").await.unwrap_err().code(),
+        Code::NotFound
+    );
+
+    assert_eq!(
+        storage.invites().await?,
+        vec![
+            Invite {
+                uses: 1,
+                ..invite(b"once", 1, None)
+            },
+            Invite {
+                uses: 1,
+                revoked: true,
+                ..invite(b"twice", 2, Some(300))
+            },
+            invite(b"expired", 1, Some(200)),
+        ]
+    );
+    Ok(())
+}
+
+/// Generates a `#[test]` per shared assertion for the storage built by `$storage`.
+macro_rules! storage_test_suite {
+    ($storage:expr) => {
+        mod storage_suite {
+            use super::*;
+            use crate::storage_tests;
+
+            #[tokio::test]
+            async fn register_and_get_keys() -> anyhow::Result<()> {
+                storage_tests::register_and_get_keys($storage).await
+            }
+
+            #[tokio::test]
+            async fn unknown_user() -> anyhow::Result<()> {
+                storage_tests::unknown_user($storage).await
+            }
+
+            #[tokio::test]
+            async fn update_spk() -> anyhow::Result<()> {
+                storage_tests::update_spk($storage).await
+            }
+
+            #[tokio::test]
+            async fn opks_reserved_oldest_first() -> anyhow::Result<()> {
+                storage_tests::opks_reserved_oldest_first($storage).await
+            }
+
+            #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+            async fn concurrent_reserve_opk() -> anyhow::Result<()> {
+                storage_tests::concurrent_reserve_opk($storage).await
+            }
+
+            #[tokio::test]
+            async fn opk_reservations() -> anyhow::Result<()> {
+                storage_tests::opk_reservations($storage).await
+            }
+
+            #[tokio::test]
+            async fn opk_quota() -> anyhow::Result<()> {
+                storage_tests::opk_quota($storage).await
+            }
+
+            #[tokio::test]
+            async fn replace_opks() -> anyhow::Result<()> {
+                storage_tests::replace_opks($storage).await
+            }
+
+            #[tokio::test]
+            async fn duplicate_opks() -> anyhow::Result<()> {
+                storage_tests::duplicate_opks($storage).await
+            }
+
+            #[tokio::test]
+            async fn reregister_replaces_keys() -> anyhow::Result<()> {
+                storage_tests::reregister_replaces_keys($storage).await
+            }
+
+            #[tokio::test]
+            async fn pre_key_ids() -> anyhow::Result<()> {
+                storage_tests::pre_key_ids($storage).await
+            }
+
+            #[tokio::test]
+            async fn stale_spks() -> anyhow::Result<()> {
+                storage_tests::stale_spks($storage).await
+            }
+
+            #[tokio::test]
+            async fn messages_delivered_in_order() -> anyhow::Result<()> {
+                storage_tests::messages_delivered_in_order($storage).await
+            }
+
+            #[tokio::test]
+            async fn mailbox_quota_reject() -> anyhow::Result<()> {
+                storage_tests::mailbox_quota_reject(
+                    $storage.with_mailbox_quota(storage_tests::MAILBOX_REJECT),
+                )
+                .await
+            }
+
+            #[tokio::test]
+            async fn mailbox_quota_evict_oldest() -> anyhow::Result<()> {
+                storage_tests::mailbox_quota_evict_oldest(
+                    $storage.with_mailbox_quota(storage_tests::MAILBOX_EVICT),
+                )
+                .await
+            }
+
+            #[tokio::test]
+            async fn purge_expired_messages() -> anyhow::Result<()> {
+                storage_tests::purge_expired_messages($storage).await
+            }
+
+            #[tokio::test]
+            async fn delete_user() -> anyhow::Result<()> {
+                storage_tests::delete_user($storage).await
+            }
+
+            #[tokio::test]
+            async fn last_resort_key() -> anyhow::Result<()> {
+                storage_tests::last_resort_key($storage).await
+            }
+
+            #[tokio::test]
+            async fn multiple_devices() -> anyhow::Result<()> {
+                storage_tests::multiple_devices($storage).await
+            }
+
+            #[tokio::test]
+            async fn kem_pre_keys() -> anyhow::Result<()> {
+                storage_tests::kem_pre_keys($storage).await
+            }
+
+            #[tokio::test]
+            async fn export_queries() -> anyhow::Result<()> {
+                storage_tests::export_queries($storage).await
+            }
+
+            #[tokio::test]
+            async fn cipher_suite() -> anyhow::Result<()> {
+                storage_tests::cipher_suite($storage).await
+            }
+
+            #[tokio::test]
+            async fn push_tokens() -> anyhow::Result<()> {
+                storage_tests::push_tokens($storage).await
+            }
+
+            #[tokio::test]
+            async fn message_uuids() -> anyhow::Result<()> {
+                storage_tests::message_uuids($storage).await
+            }
+
+            #[tokio::test]
+            async fn lookalike_identities() -> anyhow::Result<()> {
+                storage_tests::lookalike_identities($storage).await
+            }
+
+            #[tokio::test]
+            async fn stats() -> anyhow::Result<()> {
+                storage_tests::stats($storage).await
+            }
+
+            #[tokio::test]
+            async fn purge_messages() -> anyhow::Result<()> {
+                storage_tests::purge_messages($storage).await
+            }
+
+            #[tokio::test]
+            async fn audit_log() -> anyhow::Result<()> {
+                storage_tests::audit_log($storage).await
+            }
+
+            #[tokio::test]
+            async fn invites() -> anyhow::Result<()> {
+                storage_tests::invites($storage).await
+            }
+        }
+    };
+}
+
+pub(crate) use storage_test_suite;
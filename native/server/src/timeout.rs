@@ -0,0 +1,225 @@
+//! Bounds how long the server works on each RPC before answering, so one stuck on storage, e.g.
+//! waiting on a database lock, fails with DeadlineExceeded instead of tying up its connection.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tonic::body::BoxBody;
+use tonic::codegen::http::{Request, Response};
+use tonic::codegen::Service;
+use tonic::Status;
+use tower::Layer;
+
+/// How long unary RPCs may take by default.
+pub const RPC_TIMEOUT: Duration = Duration::from_secs(10);
+/// Methods whose responses stream for as long as the client listens, which have no budget unless
+/// one is given.
+const STREAMING_METHODS: [&str; 2] = ["RetrieveMessages", "MessageStatusStream"];
+
+/// How long the server may take to answer each method.
+#[derive(Clone, Debug)]
+pub struct RpcTimeouts {
+    default: Duration,
+    methods: HashMap<String, Option<Duration>>,
+}
+
+impl RpcTimeouts {
+    /// Gives every method but the streaming ones `default` to answer.
+    pub fn new(default: Duration) -> Self {
+        RpcTimeouts {
+            default,
+            methods: STREAMING_METHODS
+                .iter()
+                .map(|method| (method.to_string(), None))
+                .collect(),
+        }
+    }
+
+    /// Gives `method`, e.g. "SendMessage", `budget` to answer, or as long as it takes if `None`.
+    pub fn with_method(mut self, method: &str, budget: Option<Duration>) -> Self {
+        self.methods.insert(method.to_owned(), budget);
+        self
+    }
+
+    /// How long the method at gRPC `path` may take to answer.
+    pub fn budget(&self, path: &str) -> Option<Duration> {
+        let method = path.rsplit('/').next().unwrap_or_default();
+        *self.methods.get(method).unwrap_or(&Some(self.default))
+    }
+}
+
+impl Default for RpcTimeouts {
+    fn default() -> Self {
+        RpcTimeouts::new(RPC_TIMEOUT)
+    }
+}
+
+/// Wraps services in `RpcTimeouts`.
+#[derive(Clone, Debug)]
+pub struct RpcTimeoutLayer {
+    timeouts: RpcTimeouts,
+}
+
+impl RpcTimeoutLayer {
+    pub fn new(timeouts: RpcTimeouts) -> Self {
+        RpcTimeoutLayer { timeouts }
+    }
+}
+
+impl<S> Layer<S> for RpcTimeoutLayer {
+    type Service = RpcTimeout<S>;
+
+    fn layer(&self, inner: S) -> RpcTimeout<S> {
+        RpcTimeout {
+            inner,
+            timeouts: self.timeouts.clone(),
+        }
+    }
+}
+
+/// Answers requests the inner service takes too long to answer with DeadlineExceeded, dropping
+/// its work on them. Streaming responses are only timed until they start.
+#[derive(Clone, Debug)]
+pub struct RpcTimeout<S> {
+    inner: S,
+    timeouts: RpcTimeouts,
+}
+
+impl<S, B> Service<Request<B>> for RpcTimeout<S>
+where
+    S: Service<Request<B>, Response = Response<BoxBody>>,
+    S::Future: Send + 'static,
+{
+    type Response = Response<BoxBody>;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Response<BoxBody>, S::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), S::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<B>) -> Self::Future {
+        let budget = self.timeouts.budget(request.uri().path());
+        let response = self.inner.call(request);
+        Box::pin(async move {
+            let Some(budget) = budget else {
+                return response.await;
+            };
+            match tokio::time::timeout(budget, response).await {
+                Ok(response) => response,
+                Err(_) => Ok(Status::deadline_exceeded(format!(
+                    "server didn't answer within {budget:?}"
+                ))
+                .to_http()),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::brongnal::BrongnalController;
+    use crate::memory_brongnal::MemoryStorage;
+    use anyhow::Result;
+    use client::memory_client::MemoryClient;
+    use client::X3DHClient;
+    use proto::service::brongnal_client::BrongnalClient;
+    use proto::service::brongnal_server::BrongnalServer;
+    use proto::service::{
+        RegisterPreKeyBundleRequest, RequestPreKeysRequest, RetrieveMessagesRequest,
+    };
+    use tokio::net::TcpListener;
+    use tokio::sync::oneshot;
+    use tokio_stream::wrappers::TcpListenerStream;
+    use tonic::transport::Server;
+    use tonic::Code;
+
+    #[test]
+    fn budgets() {
+        let timeouts = RpcTimeouts::new(Duration::from_secs(5))
+            .with_method("ExportAccountData", Some(Duration::from_secs(60)))
+            .with_method("SendMessage", None);
+        assert_eq!(
+            timeouts.budget("/service.Brongnal/RequestPreKeys"),
+            Some(Duration::from_secs(5))
+        );
+        assert_eq!(
+            timeouts.budget("/service.Brongnal/ExportAccountData"),
+            Some(Duration::from_secs(60))
+        );
+        assert_eq!(timeouts.budget("/service.Brongnal/SendMessage"), None);
+        assert_eq!(timeouts.budget("/service.Brongnal/RetrieveMessages"), None);
+        assert_eq!(
+            timeouts.budget("/service.Brongnal/MessageStatusStream"),
+            None
+        );
+        assert_eq!(
+            RpcTimeouts::default().budget("/service.Brongnal/Ping"),
+            Some(RPC_TIMEOUT)
+        );
+    }
+
+    #[tokio::test]
+    async fn slow_unary_calls_time_out() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let storage = MemoryStorage::default().with_latency(Duration::from_millis(200));
+        let controller = BrongnalController::new(Box::new(storage));
+        let timeouts =
+            RpcTimeouts::new(Duration::from_millis(50)).with_method("RegisterPreKeyBundle", None);
+        let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+        let server = tokio::spawn(
+            Server::builder()
+                .layer(RpcTimeoutLayer::new(timeouts))
+                .add_service(BrongnalServer::new(controller))
+                .serve_with_incoming_shutdown(TcpListenerStream::new(listener), async {
+                    let _ = shutdown_rx.await;
+                }),
+        );
+        let mut stub = BrongnalClient::connect(format!("http://{addr}")).await?;
+
+        // Methods without a budget take as long as storage does.
+        let mut bob = MemoryClient::new();
+        stub.register_pre_key_bundle(RegisterPreKeyBundleRequest {
+            identity: Some(String::from("bob")),
+            identity_key: Some(bob.get_ik()?.verifying_key().to_bytes().to_vec()),
+            signed_pre_key: Some(bob.get_spk()?.into()),
+            one_time_key_bundle: Some(bob.create_opks(1)?.into()),
+            device_id: None,
+            last_resort_key: None,
+            last_resort_kem_key: None,
+            one_time_kem_keys: Vec::new(),
+            cipher_suite: None,
+            challenge: None,
+            challenge_solution: None,
+            invite_code: None,
+        })
+        .await?;
+
+        let status = stub
+            .request_pre_keys(RequestPreKeysRequest {
+                identity: Some(String::from("bob")),
+            })
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::DeadlineExceeded);
+
+        // The message stream isn't limited, however long storage takes to open it.
+        let messages = stub
+            .retrieve_messages(RetrieveMessagesRequest {
+                identity: Some(String::from("bob")),
+                device_id: None,
+            })
+            .await;
+        assert!(messages.is_ok());
+        drop(messages);
+
+        drop(stub);
+        shutdown_tx.send(()).unwrap();
+        server.await??;
+        Ok(())
+    }
+}
@@ -0,0 +1,194 @@
+use std::fs::{DirBuilder, Permissions};
+use std::io::ErrorKind;
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use tokio::net::UnixListener;
+use tokio_stream::wrappers::UnixListenerStream;
+
+/// Removes the socket file once the server stops listening.
+#[derive(Debug)]
+pub struct SocketCleanup(PathBuf);
+
+impl Drop for SocketCleanup {
+    fn drop(&mut self) {
+        println!("Removing unix socket {}.", self.0.display());
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+/// Binds a unix domain socket at `path`, replacing a stale socket left behind by a previous run.
+/// The socket is only accessible to the owning user and group: it is bound inside a private
+/// staging directory and given its mode before being moved to `path`, so no one else can connect
+/// in between.
+pub fn bind(path: &Path) -> std::io::Result<(UnixListenerStream, SocketCleanup)> {
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path)?,
+        Ok(_) => {
+            return Err(std::io::Error::new(
+                ErrorKind::AlreadyExists,
+                format!("{} exists and is not a socket", path.display()),
+            ))
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let staging = parent.join(format!(".{name}.tmp"));
+    DirBuilder::new().mode(0o700).create(&staging)?;
+    let bound = (|| -> std::io::Result<UnixListener> {
+        // Kept short: socket paths are limited to about a hundred bytes.
+        let staged = staging.join("s");
+        let listener = UnixListener::bind(&staged)?;
+        std::fs::set_permissions(&staged, Permissions::from_mode(0o660))?;
+        std::fs::rename(&staged, path)?;
+        Ok(listener)
+    })();
+    let _ = std::fs::remove_dir_all(&staging);
+    let listener = bound?;
+    Ok((
+        UnixListenerStream::new(listener),
+        SocketCleanup(path.to_owned()),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::brongnal::BrongnalController;
+    use crate::gossamer::InMemoryGossamer;
+    use crate::memory_brongnal::MemoryStorage;
+    use crate::uds::*;
+    use anyhow::Result;
+    use client::memory_client::MemoryClient;
+    use client::{
+        connect_uds, listen, message, register, ClientEvent, DecryptedMessage, SendPolicy,
+    };
+    use proto::gossamer::gossamer_client::GossamerClient;
+    use proto::gossamer::gossamer_server::GossamerServer;
+    use proto::service::brongnal_client::BrongnalClient;
+    use proto::service::brongnal_server::BrongnalServer;
+    use proto::DEFAULT_DEVICE_ID;
+    use std::sync::Arc;
+    use tokio::sync::broadcast::error::RecvError;
+    use tokio::sync::{broadcast, oneshot, Mutex};
+    use tonic::transport::Server;
+
+    /// Somewhere to report the events of calls whose events a test doesn't look at.
+    fn ignored_events() -> broadcast::Sender<ClientEvent> {
+        broadcast::channel(1).0
+    }
+
+    /// The next message `events` reports, skipping every other event, or `None` once nothing can
+    /// report any more.
+    async fn next_message(
+        events: &mut broadcast::Receiver<ClientEvent>,
+    ) -> Option<DecryptedMessage> {
+        loop {
+            match events.recv().await {
+                Ok(ClientEvent::MessageReceived(message)) => return Some(message),
+                Ok(_) => {}
+                Err(RecvError::Lagged(missed)) => panic!("Missed {missed} events."),
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn register_and_message_over_uds() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("brongnal-{}.sock", std::process::id()));
+        let (incoming, cleanup) = bind(&path)?;
+        assert_eq!(
+            std::fs::metadata(&path)?.permissions().mode() & 0o777,
+            0o660
+        );
+
+        let controller = BrongnalController::new(Box::new(MemoryStorage::default()));
+        let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+        let server = tokio::spawn(async move {
+            let _cleanup = cleanup;
+            Server::builder()
+                .add_service(BrongnalServer::new(controller))
+                .add_service(GossamerServer::new(InMemoryGossamer::default()))
+                .serve_with_incoming_shutdown(incoming, async {
+                    let _ = shutdown_rx.await;
+                })
+                .await
+        });
+
+        let channel = connect_uds(&path).await?;
+        let mut stub = BrongnalClient::new(channel.clone());
+        let mut gossamer = GossamerClient::new(channel);
+        let alice = Arc::new(Mutex::new(MemoryClient::new()));
+        let bob = Arc::new(Mutex::new(MemoryClient::new()));
+        register(
+            &mut stub,
+            alice.clone(),
+            String::from("alice"),
+            DEFAULT_DEVICE_ID,
+            &ignored_events(),
+        )
+        .await?;
+        register(
+            &mut stub,
+            bob.clone(),
+            String::from("bob"),
+            DEFAULT_DEVICE_ID,
+            &ignored_events(),
+        )
+        .await?;
+
+        let (tx, mut rx) = broadcast::channel(16);
+        let listener = tokio::spawn(listen(
+            stub.clone(),
+            gossamer.clone(),
+            bob,
+            String::from("bob"),
+            DEFAULT_DEVICE_ID,
+            tx,
+        ));
+        message(
+            &mut stub,
+            &mut gossamer,
+            alice.clone(),
+            String::from("alice"),
+            DEFAULT_DEVICE_ID,
+            "bob",
+            b"Hello Bob!",
+            SendPolicy::default(),
+            &ignored_events(),
+        )
+        .await?;
+
+        let received = next_message(&mut rx).await.unwrap();
+        assert_eq!(received.sender_identity, "alice");
+        assert_eq!(received.message, b"Hello Bob!");
+
+        // Plaintexts are bytes end to end, so ones that aren't UTF-8 arrive intact.
+        let binary = b"\0\xff\xfe\0binary\x80";
+        message(
+            &mut stub,
+            &mut gossamer,
+            alice,
+            String::from("alice"),
+            DEFAULT_DEVICE_ID,
+            "bob",
+            binary,
+            SendPolicy::default(),
+            &ignored_events(),
+        )
+        .await?;
+        assert_eq!(next_message(&mut rx).await.unwrap().message, binary);
+
+        listener.abort();
+        drop(stub);
+        drop(gossamer);
+        shutdown_tx.send(()).unwrap();
+        server.await??;
+        assert!(!path.exists());
+        Ok(())
+    }
+}
@@ -0,0 +1,206 @@
+//! Lets browsers reach the server with gRPC-Web, which they can speak over HTTP/1.1 and fetch
+//! where HTTP/2 gRPC is out of reach.
+
+use std::time::Duration;
+use tonic::codegen::http::header::{HeaderName, HeaderValue, InvalidHeaderValue};
+use tower_http::cors::{AllowOrigin, CorsLayer};
+
+/// Request headers browsers may send cross-origin: gRPC-Web's own and the deadline.
+const ALLOW_HEADERS: [&str; 4] = ["x-grpc-web", "content-type", "x-user-agent", "grpc-timeout"];
+/// Response headers scripts may read: the status, and the metadata clients act on.
+const EXPOSE_HEADERS: [&str; 5] = [
+    "grpc-status",
+    "grpc-message",
+    "grpc-status-details-bin",
+    "retry-after",
+    "max-ciphertext-len",
+];
+/// How long browsers may cache a preflight.
+const MAX_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// CORS for gRPC-Web from pages served by `allowed_origins`, or from any page if one of them is
+/// `*`. With none, only same-origin pages and non-browser clients can connect.
+pub fn cors(allowed_origins: &[String]) -> Result<CorsLayer, InvalidHeaderValue> {
+    let allow_origin = if allowed_origins.iter().any(|origin| origin == "*") {
+        AllowOrigin::mirror_request()
+    } else {
+        AllowOrigin::list(
+            allowed_origins
+                .iter()
+                .map(|origin| HeaderValue::from_str(origin))
+                .collect::<Result<Vec<_>, _>>()?,
+        )
+    };
+    Ok(CorsLayer::new()
+        .allow_origin(allow_origin)
+        .max_age(MAX_AGE)
+        .allow_headers(ALLOW_HEADERS.map(HeaderName::from_static))
+        .expose_headers(EXPOSE_HEADERS.map(HeaderName::from_static)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::brongnal::BrongnalController;
+    use crate::memory_brongnal::MemoryStorage;
+    use anyhow::Result;
+    use client::memory_client::MemoryClient;
+    use client::X3DHClient;
+    use hyper::{Body, Method, Request};
+    use proto::service::brongnal_client::BrongnalClient;
+    use proto::service::brongnal_server::BrongnalServer;
+    use proto::service::message::Payload;
+    use proto::service::{Message as MessageProto, PreKeyBundle as PreKeyBundleProto};
+    use proto::service::{
+        RegisterPreKeyBundleRequest, RequestPreKeysRequest, RetrieveMessagesRequest,
+        SendMessageRequest,
+    };
+    use protocol::x3dh::{initiate_send, PreKeyBundle};
+    use tokio::net::TcpListener;
+    use tokio::sync::oneshot;
+    use tokio_stream::wrappers::TcpListenerStream;
+    use tonic::transport::Server;
+    use tonic_web::{GrpcWebClientService, GrpcWebLayer};
+
+    /// A message from a new identity to the device `bundle` belongs to.
+    fn message_to(bundle: PreKeyBundleProto, text: &[u8]) -> Result<MessageProto> {
+        let bundle: PreKeyBundle = bundle.try_into()?;
+        let (_sk, message) = initiate_send(
+            bundle,
+            String::from("alice"),
+            &MemoryClient::new().get_ik()?,
+            text,
+        )?;
+        Ok(message.into())
+    }
+
+    #[tokio::test]
+    async fn grpc_web() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let controller = BrongnalController::new(Box::new(MemoryStorage::default()));
+        let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+        let server = tokio::spawn(
+            Server::builder()
+                .accept_http1(true)
+                .layer(cors(&[String::from("https://app.example")])?)
+                .layer(GrpcWebLayer::new())
+                .add_service(BrongnalServer::new(controller))
+                .serve_with_incoming_shutdown(TcpListenerStream::new(listener), async {
+                    let _ = shutdown_rx.await;
+                }),
+        );
+        let mut stub = BrongnalClient::with_origin(
+            GrpcWebClientService::new(hyper::Client::builder().build_http()),
+            format!("http://{addr}").parse()?,
+        );
+
+        let mut bob = MemoryClient::new();
+        stub.register_pre_key_bundle(RegisterPreKeyBundleRequest {
+            identity: Some(String::from("bob")),
+            identity_key: Some(bob.get_ik()?.verifying_key().to_bytes().to_vec()),
+            signed_pre_key: Some(bob.get_spk()?.into()),
+            one_time_key_bundle: Some(bob.create_opks(2)?.into()),
+            device_id: None,
+            last_resort_key: None,
+            last_resort_kem_key: None,
+            one_time_kem_keys: Vec::new(),
+            cipher_suite: None,
+            challenge: None,
+            challenge_solution: None,
+            invite_code: None,
+        })
+        .await?;
+        let bundle_stub = stub.clone();
+        let request_bundle = || {
+            let mut stub = bundle_stub.clone();
+            async move {
+                let response = stub
+                    .request_pre_keys(RequestPreKeysRequest {
+                        identity: Some(String::from("bob")),
+                    })
+                    .await?;
+                anyhow::Ok(response.into_inner().bundles.remove(0))
+            }
+        };
+        let bundle = request_bundle().await?;
+        assert!(bundle.one_time_key_id.is_some());
+        let first = message_to(bundle, b"Queued")?;
+        stub.send_message(SendMessageRequest {
+            recipient_identity: Some(String::from("bob")),
+            message: Some(first.clone()),
+            device_messages: Vec::new(),
+            message_uuid: None,
+            partial: None,
+        })
+        .await?;
+
+        // Queued messages are streamed first, then ones that arrive while the stream is open.
+        let mut messages = stub
+            .retrieve_messages(RetrieveMessagesRequest {
+                identity: Some(String::from("bob")),
+                device_id: None,
+            })
+            .await?
+            .into_inner();
+        assert_eq!(messages.message().await?, Some(first));
+        let second = message_to(request_bundle().await?, b"Live")?;
+        stub.send_message(SendMessageRequest {
+            recipient_identity: Some(String::from("bob")),
+            message: Some(second.clone()),
+            device_messages: Vec::new(),
+            message_uuid: None,
+            partial: None,
+        })
+        .await?;
+        // Skipping heartbeats and the notice that bob is out of one time keys.
+        let received = loop {
+            match messages.message().await? {
+                Some(MessageProto {
+                    payload: Some(Payload::ServerControl(_)),
+                    ..
+                }) => {}
+                message => break message,
+            }
+        };
+        assert_eq!(received, Some(second));
+        drop(messages);
+
+        // Browsers only let pages from allowed origins make requests.
+        let preflight = |origin: &'static str| {
+            Request::builder()
+                .method(Method::OPTIONS)
+                .uri(format!("http://{addr}/service.Brongnal/RequestPreKeys"))
+                .header("origin", origin)
+                .header("access-control-request-method", "POST")
+                .header("access-control-request-headers", "content-type,x-grpc-web")
+                .body(Body::empty())
+        };
+        let http = hyper::Client::new();
+        let allowed = http.request(preflight("https://app.example")?).await?;
+        assert_eq!(
+            allowed
+                .headers()
+                .get("access-control-allow-origin")
+                .unwrap(),
+            "https://app.example"
+        );
+        let denied = http.request(preflight("https://evil.example")?).await?;
+        assert!(denied
+            .headers()
+            .get("access-control-allow-origin")
+            .is_none());
+
+        drop(bundle_stub);
+        drop(stub);
+        shutdown_tx.send(()).unwrap();
+        server.await??;
+        Ok(())
+    }
+
+    #[test]
+    fn origins() {
+        assert!(cors(&[String::from("*")]).is_ok());
+        assert!(cors(&[String::from("bad\norigin")]).is_err());
+    }
+}
@@ -0,0 +1,703 @@
+//! End-to-end tests of registering, linking, backing up and deleting accounts, with clients
+//! talking to a server over a unix domain socket.
+
+mod common;
+
+use anyhow::Result;
+use client::memory_client::MemoryClient;
+use client::sqlite_client::SqliteClient;
+use client::{
+    approve_link, count_one_time_keys, delete_account, delete_device, export_account_data,
+    export_backup, finish_linking, import_backup, is_revoked, listen, message,
+    publish_identity_key, register, register_with_suite, start_linking, ClientEvent, SendPolicy,
+    Timeouts, X3DHClient,
+};
+use common::{ignored_events, next_message, registered_pair, serve, spawn_server};
+use proto::admin::admin_client::AdminClient;
+use proto::admin::admin_server::AdminServer;
+use proto::admin::{DeleteUserRequest, GetUserDetailRequest, ListUsersRequest, ServerStatsRequest};
+use proto::gossamer::gossamer_server::GossamerServer;
+use proto::service::account_data_record::Record;
+use proto::service::brongnal_server::BrongnalServer;
+use proto::service::{AccountDataRecord, PreKeyKind, RequestPreKeysRequest};
+use proto::DEFAULT_DEVICE_ID;
+use protocol::backup::{BackupError, KdfParams};
+use protocol::x3dh::CipherSuite;
+use server::admin::{AdminAuth, AdminService};
+use server::brongnal::{hash_invite_code, BrongnalController, Invite, Storage};
+use server::gossamer::InMemoryGossamer;
+use server::memory_brongnal::MemoryStorage;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::sync::{broadcast, Mutex};
+use tonic::transport::Server;
+
+#[tokio::test]
+async fn register_with_challenge() -> Result<()> {
+    let controller =
+        BrongnalController::new(Box::new(MemoryStorage::default())).with_registration_difficulty(8);
+    let server = spawn_server("challenge", controller).await?;
+
+    // The client solves the challenge on its own.
+    let mut stub = server.stub();
+    register(
+        &mut stub,
+        Arc::new(Mutex::new(MemoryClient::new())),
+        String::from("bob"),
+        DEFAULT_DEVICE_ID,
+        &ignored_events(),
+    )
+    .await?;
+    let bundles = stub
+        .request_pre_keys(RequestPreKeysRequest {
+            identity: Some(String::from("bob")),
+        })
+        .await?
+        .into_inner()
+        .bundles;
+    assert_eq!(bundles.len(), 1);
+
+    drop(stub);
+    server.shutdown().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn register_with_invite() -> Result<()> {
+    let storage = MemoryStorage::default();
+    storage
+        .add_invite(Invite {
+            code_hash: hash_invite_code("welcome"),
+            creator: String::from("oncall"),
+            max_uses: 1,
+            uses: 0,
+            created_at: SystemTime::now(),
+            expires_at: None,
+            revoked: false,
+        })
+        .await?;
+    let controller = BrongnalController::new(Box::new(storage.clone())).with_invites_required(true);
+    let server = spawn_server("invite", controller).await?;
+    let mut stub = server.stub();
+    let register_invited = |name: &str| {
+        let mut stub = stub.clone();
+        let name = name.to_owned();
+        async move {
+            register_with_suite(
+                &mut stub,
+                Arc::new(Mutex::new(MemoryClient::new())),
+                name,
+                DEFAULT_DEVICE_ID,
+                CipherSuite::CURRENT,
+                Timeouts::default(),
+                Some(String::from("welcome")),
+                &ignored_events(),
+            )
+            .await
+        }
+    };
+    register_invited("bob").await?;
+    let err = register_invited("carol").await.unwrap_err();
+    let status = err.downcast_ref::<tonic::Status>().unwrap();
+    assert_eq!(status.code(), tonic::Code::PermissionDenied);
+    assert_eq!(status.metadata().get("invite-rejected").unwrap(), "used-up");
+    assert!(register(
+        &mut stub,
+        Arc::new(Mutex::new(MemoryClient::new())),
+        String::from("dave"),
+        DEFAULT_DEVICE_ID,
+        &ignored_events(),
+    )
+    .await
+    .is_err());
+    assert!(storage.user_exists("bob").await?);
+    assert!(!storage.user_exists("carol").await?);
+
+    drop(stub);
+    server.shutdown().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn delete_account_tombstones_ledger() -> Result<()> {
+    let gossamer = InMemoryGossamer::default();
+    let controller =
+        BrongnalController::new(Box::new(MemoryStorage::default())).with_gossamer(gossamer.clone());
+    let server = serve(
+        "delete",
+        Server::builder()
+            .add_service(BrongnalServer::new(controller))
+            .add_service(GossamerServer::new(gossamer)),
+    )
+    .await?;
+
+    let mut stub = server.stub();
+    let mut gossamer = server.gossamer();
+    let bob = Arc::new(Mutex::new(MemoryClient::new()));
+    register(
+        &mut stub,
+        bob.clone(),
+        String::from("bob"),
+        DEFAULT_DEVICE_ID,
+        &ignored_events(),
+    )
+    .await?;
+    publish_identity_key(
+        &mut gossamer,
+        bob.clone(),
+        String::from("bob"),
+        Timeouts::default(),
+    )
+    .await?;
+    let ik = bob.lock().await.get_ik()?.verifying_key();
+
+    delete_account(
+        &mut stub,
+        &mut gossamer,
+        bob.clone(),
+        String::from("bob"),
+        Timeouts::default(),
+    )
+    .await?;
+    let status = stub
+        .request_pre_keys(RequestPreKeysRequest {
+            identity: Some(String::from("bob")),
+        })
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), tonic::Code::NotFound);
+    assert!(is_revoked(&mut gossamer, "bob", &ik, Timeouts::default()).await?);
+    // The keys that signed for bob are gone from the device too.
+    assert_ne!(bob.lock().await.get_ik()?.verifying_key(), ik);
+
+    drop(stub);
+    drop(gossamer);
+    server.shutdown().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn export_account_data_to_file() -> Result<()> {
+    let controller = BrongnalController::new(Box::new(MemoryStorage::default()));
+    let server = spawn_server("export", controller).await?;
+    let output = server.path.with_extension("export");
+    let mut stub = server.stub();
+    let bob = Arc::new(Mutex::new(MemoryClient::new()));
+    register(
+        &mut stub,
+        bob.clone(),
+        String::from("bob"),
+        DEFAULT_DEVICE_ID,
+        &ignored_events(),
+    )
+    .await?;
+    let count = export_account_data(
+        &mut stub,
+        bob.clone(),
+        String::from("bob"),
+        false,
+        &output,
+        Timeouts::default(),
+    )
+    .await?;
+
+    let export = std::fs::read(&output)?;
+    std::fs::remove_file(&output)?;
+    let mut remaining = export.as_slice();
+    let mut records: Vec<AccountDataRecord> = Vec::new();
+    while !remaining.is_empty() {
+        records.push(prost::Message::decode_length_delimited(&mut remaining)?);
+    }
+    assert_eq!(records.len(), count);
+    let Some(Record::Registration(registration)) = &records[0].record else {
+        panic!("export doesn't start with a registration: {:?}", records[0]);
+    };
+    assert_eq!(
+        registration.identity_key(),
+        bob.lock().await.get_ik()?.verifying_key().as_bytes()
+    );
+    assert!(records
+        .iter()
+        .all(|record| record.device_id() == DEFAULT_DEVICE_ID));
+    assert!(records.iter().any(|record| matches!(
+        &record.record,
+        Some(Record::PreKey(pre_key)) if pre_key.kind() == PreKeyKind::OneTime
+    )));
+
+    drop(stub);
+    server.shutdown().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn message_recipient_registered_with_aes() -> Result<()> {
+    let controller = BrongnalController::new(Box::new(MemoryStorage::default()));
+    let server = spawn_server("aes", controller).await?;
+    let mut stub = server.stub();
+    let mut gossamer = server.gossamer();
+    let alice = Arc::new(Mutex::new(MemoryClient::new()));
+    let bob = Arc::new(Mutex::new(MemoryClient::new()));
+    register(
+        &mut stub,
+        alice.clone(),
+        String::from("alice"),
+        DEFAULT_DEVICE_ID,
+        &ignored_events(),
+    )
+    .await?;
+    register_with_suite(
+        &mut stub,
+        bob.clone(),
+        String::from("bob"),
+        DEFAULT_DEVICE_ID,
+        CipherSuite::V2,
+        Timeouts::default(),
+        None,
+        &ignored_events(),
+    )
+    .await?;
+
+    let (tx, mut rx) = broadcast::channel(16);
+    let listener = tokio::spawn(listen(
+        stub.clone(),
+        gossamer.clone(),
+        bob,
+        String::from("bob"),
+        DEFAULT_DEVICE_ID,
+        tx,
+    ));
+    message(
+        &mut stub,
+        &mut gossamer,
+        alice,
+        String::from("alice"),
+        DEFAULT_DEVICE_ID,
+        "bob",
+        b"Hello Bob!",
+        SendPolicy::default(),
+        &ignored_events(),
+    )
+    .await?;
+    assert_eq!(next_message(&mut rx).await.unwrap().message, b"Hello Bob!");
+
+    listener.abort();
+    drop(stub);
+    drop(gossamer);
+    server.shutdown().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn multiple_devices() -> Result<()> {
+    let controller = BrongnalController::new(Box::new(MemoryStorage::default()));
+    let server = spawn_server("devices", controller).await?;
+    let mut stub = server.stub();
+    let mut gossamer = server.gossamer();
+    let alice = Arc::new(Mutex::new(MemoryClient::new()));
+    let phone = Arc::new(Mutex::new(MemoryClient::new()));
+    let laptop = Arc::new(Mutex::new(MemoryClient::new()));
+    register(
+        &mut stub,
+        alice.clone(),
+        String::from("alice"),
+        DEFAULT_DEVICE_ID,
+        &ignored_events(),
+    )
+    .await?;
+    register(
+        &mut stub,
+        phone.clone(),
+        String::from("bob"),
+        DEFAULT_DEVICE_ID,
+        &ignored_events(),
+    )
+    .await?;
+    register(
+        &mut stub,
+        laptop.clone(),
+        String::from("bob"),
+        2,
+        &ignored_events(),
+    )
+    .await?;
+
+    let (phone_tx, mut phone_rx) = broadcast::channel(16);
+    let phone_listener = tokio::spawn(listen(
+        stub.clone(),
+        gossamer.clone(),
+        phone.clone(),
+        String::from("bob"),
+        DEFAULT_DEVICE_ID,
+        phone_tx,
+    ));
+    let (laptop_tx, mut laptop_rx) = broadcast::channel(16);
+    let laptop_listener = tokio::spawn(listen(
+        stub.clone(),
+        gossamer.clone(),
+        laptop.clone(),
+        String::from("bob"),
+        2,
+        laptop_tx,
+    ));
+    let alice_listener = tokio::spawn(listen(
+        stub.clone(),
+        gossamer.clone(),
+        alice.clone(),
+        String::from("alice"),
+        DEFAULT_DEVICE_ID,
+        ignored_events(),
+    ));
+
+    // Alice confirms the key of a message from Bob's phone to the phone alone, under the session
+    // the message set up, which takes none of the laptop's one time keys.
+    let laptop_opks =
+        count_one_time_keys(&mut stub, String::from("bob"), 2, Timeouts::default()).await?;
+    message(
+        &mut stub,
+        &mut gossamer,
+        phone.clone(),
+        String::from("bob"),
+        DEFAULT_DEVICE_ID,
+        "alice",
+        b"Hello Alice!",
+        SendPolicy::default(),
+        &ignored_events(),
+    )
+    .await?;
+    loop {
+        if let ClientEvent::SessionConfirmed { peer, device_id } = phone_rx.recv().await? {
+            assert_eq!(peer, "alice");
+            assert_eq!(device_id, DEFAULT_DEVICE_ID);
+            break;
+        }
+    }
+    assert_eq!(
+        count_one_time_keys(&mut stub, String::from("bob"), 2, Timeouts::default()).await?,
+        laptop_opks
+    );
+
+    message(
+        &mut stub,
+        &mut gossamer,
+        alice.clone(),
+        String::from("alice"),
+        DEFAULT_DEVICE_ID,
+        "bob",
+        b"Hello Bob!",
+        SendPolicy::default(),
+        &ignored_events(),
+    )
+    .await?;
+    assert_eq!(
+        next_message(&mut phone_rx).await.unwrap().message,
+        b"Hello Bob!"
+    );
+    assert_eq!(
+        next_message(&mut laptop_rx).await.unwrap().message,
+        b"Hello Bob!"
+    );
+
+    // Removing the phone closes its stream and stops it from receiving new messages.
+    delete_device(
+        &mut stub,
+        laptop,
+        String::from("bob"),
+        DEFAULT_DEVICE_ID,
+        Timeouts::default(),
+    )
+    .await?;
+    phone_listener.await??;
+    message(
+        &mut stub,
+        &mut gossamer,
+        alice,
+        String::from("alice"),
+        DEFAULT_DEVICE_ID,
+        "bob",
+        b"Still there?",
+        SendPolicy::default(),
+        &ignored_events(),
+    )
+    .await?;
+    assert_eq!(
+        next_message(&mut laptop_rx).await.unwrap().message,
+        b"Still there?"
+    );
+    assert!(next_message(&mut phone_rx).await.is_none());
+    let (tx, _rx) = broadcast::channel(16);
+    assert!(listen(
+        stub.clone(),
+        gossamer.clone(),
+        phone,
+        String::from("bob"),
+        DEFAULT_DEVICE_ID,
+        tx
+    )
+    .await
+    .is_err());
+    laptop_listener.abort();
+    alice_listener.abort();
+
+    drop(stub);
+    drop(gossamer);
+    server.shutdown().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn link_device() -> Result<()> {
+    let controller = BrongnalController::new(Box::new(MemoryStorage::default()));
+    let server = spawn_server("link", controller).await?;
+    let mut stub = server.stub();
+    let mut gossamer = server.gossamer();
+    let alice = Arc::new(Mutex::new(MemoryClient::new()));
+    let phone = Arc::new(Mutex::new(MemoryClient::new()));
+    register(
+        &mut stub,
+        alice.clone(),
+        String::from("alice"),
+        DEFAULT_DEVICE_ID,
+        &ignored_events(),
+    )
+    .await?;
+    register(
+        &mut stub,
+        phone.clone(),
+        String::from("bob"),
+        DEFAULT_DEVICE_ID,
+        &ignored_events(),
+    )
+    .await?;
+
+    let link = start_linking();
+    approve_link(
+        &mut stub,
+        phone.clone(),
+        &link.payload(),
+        Timeouts::default(),
+    )
+    .await?;
+    let ik = finish_linking(&mut stub, link, Timeouts::default()).await?;
+    assert_eq!(ik, phone.lock().await.get_ik()?);
+
+    let laptop = Arc::new(Mutex::new(MemoryClient::with_ik(ik)));
+    register(
+        &mut stub,
+        laptop.clone(),
+        String::from("bob"),
+        2,
+        &ignored_events(),
+    )
+    .await?;
+    let (tx, mut rx) = broadcast::channel(16);
+    let listener = tokio::spawn(listen(
+        stub.clone(),
+        gossamer.clone(),
+        laptop,
+        String::from("bob"),
+        2,
+        tx,
+    ));
+    message(
+        &mut stub,
+        &mut gossamer,
+        alice,
+        String::from("alice"),
+        DEFAULT_DEVICE_ID,
+        "bob",
+        b"Hello Bob!",
+        SendPolicy::default(),
+        &ignored_events(),
+    )
+    .await?;
+    assert_eq!(next_message(&mut rx).await.unwrap().message, b"Hello Bob!");
+    listener.abort();
+
+    drop(stub);
+    drop(gossamer);
+    server.shutdown().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn restore_from_backup() -> Result<()> {
+    let ik_path = std::env::temp_dir().join(format!("brongnal-backup-{}.ik", std::process::id()));
+    let db_path =
+        std::env::temp_dir().join(format!("brongnal-backup-{}.sqlite", std::process::id()));
+    let controller = BrongnalController::new(Box::new(MemoryStorage::default()));
+    let server = spawn_server("backup", controller).await?;
+    let mut stub = server.stub();
+    let mut gossamer = server.gossamer();
+    let (alice, bob) = registered_pair(&mut stub).await?;
+    message(
+        &mut stub,
+        &mut gossamer,
+        alice.clone(),
+        String::from("alice"),
+        DEFAULT_DEVICE_ID,
+        "bob",
+        b"Hello Bob!",
+        SendPolicy::default(),
+        &ignored_events(),
+    )
+    .await?;
+
+    let params = KdfParams {
+        m_cost: 8,
+        t_cost: 1,
+        p_cost: 1,
+    };
+    let backup = export_backup(bob.clone(), "hunter2", params).await?;
+    let restored = Arc::new(Mutex::new(SqliteClient::new(&ik_path, &db_path)?));
+    let err = import_backup(restored.clone(), &backup, "hunter3", false)
+        .await
+        .unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<BackupError>(),
+        Some(&BackupError::WrongPassphrase)
+    );
+    // Alice is registered under her own identity, so restoring Bob's needs confirmation.
+    let err = import_backup(alice.clone(), &backup, "hunter2", false)
+        .await
+        .unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<BackupError>(),
+        Some(&BackupError::DifferentIdentity)
+    );
+    import_backup(restored.clone(), &backup, "hunter2", false).await?;
+    assert_eq!(restored.lock().await.get_ik()?, bob.lock().await.get_ik()?);
+
+    // The restored client holds the one time key the queued message was sent to.
+    let (tx, mut rx) = broadcast::channel(16);
+    let listener = tokio::spawn(listen(
+        stub.clone(),
+        gossamer.clone(),
+        restored,
+        String::from("bob"),
+        DEFAULT_DEVICE_ID,
+        tx,
+    ));
+    assert_eq!(next_message(&mut rx).await.unwrap().message, b"Hello Bob!");
+    listener.abort();
+
+    drop(stub);
+    drop(gossamer);
+    server.shutdown().await?;
+    let _ = std::fs::remove_file(ik_path);
+    let _ = std::fs::remove_file(db_path);
+    Ok(())
+}
+
+#[tokio::test]
+async fn admin_requires_token() -> Result<()> {
+    let controller = Arc::new(BrongnalController::new(Box::new(MemoryStorage::default())));
+    let server = serve(
+        "admin",
+        Server::builder()
+            .add_service(BrongnalServer::from_arc(controller.clone()))
+            .add_service(GossamerServer::new(InMemoryGossamer::default()))
+            .add_service(AdminServer::with_interceptor(
+                AdminService::new(controller),
+                AdminAuth::new(Some(String::from("s3cret"))),
+            )),
+    )
+    .await?;
+
+    let channel = server.channel.clone();
+    let mut stub = server.stub();
+    let gossamer = server.gossamer();
+    let bob = Arc::new(Mutex::new(MemoryClient::new()));
+    register(
+        &mut stub,
+        bob.clone(),
+        String::from("bob"),
+        DEFAULT_DEVICE_ID,
+        &ignored_events(),
+    )
+    .await?;
+    let listener = tokio::spawn(listen(
+        stub.clone(),
+        gossamer.clone(),
+        bob,
+        String::from("bob"),
+        DEFAULT_DEVICE_ID,
+        ignored_events(),
+    ));
+
+    let with_token = |token: &'static str| {
+        AdminClient::with_interceptor(channel.clone(), move |mut request: tonic::Request<()>| {
+            request.metadata_mut().insert(
+                "authorization",
+                tonic::metadata::MetadataValue::from_static(token),
+            );
+            Ok(request)
+        })
+    };
+    assert_eq!(
+        AdminClient::new(channel.clone())
+            .list_users(ListUsersRequest {})
+            .await
+            .unwrap_err()
+            .code(),
+        tonic::Code::Unauthenticated
+    );
+    assert_eq!(
+        with_token("Bearer guess")
+            .server_stats(ServerStatsRequest::default())
+            .await
+            .unwrap_err()
+            .code(),
+        tonic::Code::Unauthenticated
+    );
+    assert_eq!(
+        AdminClient::new(channel.clone())
+            .delete_user(DeleteUserRequest {
+                identity: Some(String::from("bob")),
+            })
+            .await
+            .unwrap_err()
+            .code(),
+        tonic::Code::Unauthenticated
+    );
+
+    let mut admin = with_token("Bearer s3cret");
+    let users = admin
+        .list_users(ListUsersRequest {})
+        .await?
+        .into_inner()
+        .users;
+    assert_eq!(users.len(), 1);
+    assert_eq!(users[0].identity.as_deref(), Some("bob"));
+    assert!(users[0].one_time_key_count > Some(0));
+    // Bob's listener opens his message stream in the background.
+    let stats = tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            let stats = admin
+                .server_stats(ServerStatsRequest::default())
+                .await?
+                .into_inner();
+            if stats.open_stream_count == Some(1) {
+                return anyhow::Ok(stats);
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await??;
+    assert_eq!(stats.user_count, Some(1));
+    let detail = admin
+        .get_user_detail(GetUserDetailRequest {
+            identity: Some(String::from("bob")),
+        })
+        .await?
+        .into_inner();
+    assert_eq!(detail.devices.len(), 1);
+    assert_eq!(detail.devices[0].connected, Some(true));
+
+    listener.abort();
+    drop(stub);
+    drop(gossamer);
+    drop(admin);
+    drop(channel);
+    server.shutdown().await?;
+    Ok(())
+}
@@ -0,0 +1,134 @@
+//! Helpers shared by the end-to-end tests. Every test binary compiles all of them, whether it
+//! uses them or not.
+#![allow(dead_code)]
+
+use anyhow::Result;
+use client::memory_client::MemoryClient;
+use client::{connect_uds, register, ClientEvent, DecryptedMessage};
+use proto::gossamer::gossamer_client::GossamerClient;
+use proto::gossamer::gossamer_server::GossamerServer;
+use proto::service::brongnal_client::BrongnalClient;
+use proto::service::brongnal_server::BrongnalServer;
+use proto::DEFAULT_DEVICE_ID;
+use server::brongnal::BrongnalController;
+use server::gossamer::InMemoryGossamer;
+use server::uds::bind;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{broadcast, oneshot, Mutex};
+use tokio::task::JoinHandle;
+use tonic::transport::server::Router;
+use tonic::transport::{Channel, Server};
+
+/// Somewhere to report the events of calls whose events a test doesn't look at.
+pub fn ignored_events() -> broadcast::Sender<ClientEvent> {
+    broadcast::channel(1).0
+}
+
+/// The next message `events` reports, skipping every other event, or `None` once nothing can
+/// report any more.
+pub async fn next_message(
+    events: &mut broadcast::Receiver<ClientEvent>,
+) -> Option<DecryptedMessage> {
+    loop {
+        match events.recv().await {
+            Ok(ClientEvent::MessageReceived(message)) => return Some(message),
+            Ok(_) => {}
+            Err(RecvError::Lagged(missed)) => panic!("Missed {missed} events."),
+            Err(RecvError::Closed) => return None,
+        }
+    }
+}
+
+/// A server listening on a unix domain socket in the temporary directory, and a channel to it.
+pub struct TestServer {
+    pub path: PathBuf,
+    pub channel: Channel,
+    shutdown: oneshot::Sender<()>,
+    controller: Option<Arc<BrongnalController>>,
+    server: JoinHandle<Result<(), tonic::transport::Error>>,
+}
+
+impl TestServer {
+    pub fn stub(&self) -> BrongnalClient<Channel> {
+        BrongnalClient::new(self.channel.clone())
+    }
+
+    pub fn gossamer(&self) -> GossamerClient<Channel> {
+        GossamerClient::new(self.channel.clone())
+    }
+
+    /// Stops accepting requests, ends the streams of listening clients and waits for the open
+    /// requests to finish.
+    pub async fn shutdown(self) -> Result<()> {
+        let _ = self.shutdown.send(());
+        if let Some(controller) = self.controller {
+            controller.drain();
+        }
+        self.server.await??;
+        Ok(())
+    }
+
+    /// Stops the server without waiting for open streams, which a client may still be listening
+    /// on.
+    pub fn abort(self) {
+        self.server.abort();
+    }
+}
+
+/// Serves `controller` and an in-memory Gossamer on a socket named after `name`.
+pub async fn spawn_server(
+    name: &str,
+    controller: impl Into<Arc<BrongnalController>>,
+) -> Result<TestServer> {
+    let controller = controller.into();
+    let router = Server::builder()
+        .add_service(BrongnalServer::from_arc(controller.clone()))
+        .add_service(GossamerServer::new(InMemoryGossamer::default()));
+    let mut server = serve(name, router).await?;
+    server.controller = Some(controller);
+    Ok(server)
+}
+
+/// Serves `router` on a socket named after `name`, for tests that need other services.
+pub async fn serve(name: &str, router: Router) -> Result<TestServer> {
+    let path = std::env::temp_dir().join(format!("brongnal-{name}-{}.sock", std::process::id()));
+    let (incoming, cleanup) = bind(&path)?;
+    let (shutdown, shutdown_rx) = oneshot::channel::<()>();
+    let server = tokio::spawn(async move {
+        let _cleanup = cleanup;
+        router
+            .serve_with_incoming_shutdown(incoming, async {
+                let _ = shutdown_rx.await;
+            })
+            .await
+    });
+    let channel = connect_uds(&path).await?;
+    Ok(TestServer {
+        path,
+        channel,
+        shutdown,
+        controller: None,
+        server,
+    })
+}
+
+/// Registers alice and bob on their default devices.
+pub async fn registered_pair(
+    stub: &mut BrongnalClient<Channel>,
+) -> Result<(Arc<Mutex<MemoryClient>>, Arc<Mutex<MemoryClient>>)> {
+    let alice = Arc::new(Mutex::new(MemoryClient::new()));
+    let bob = Arc::new(Mutex::new(MemoryClient::new()));
+    for (client, name) in [(&alice, "alice"), (&bob, "bob")] {
+        register(
+            stub,
+            client.clone(),
+            String::from(name),
+            DEFAULT_DEVICE_ID,
+            &ignored_events(),
+        )
+        .await?;
+    }
+    Ok((alice, bob))
+}
@@ -0,0 +1,332 @@
+//! End-to-end tests of how clients notice and handle servers that stop answering, over a unix
+//! domain socket.
+
+mod common;
+
+use anyhow::Result;
+use client::memory_client::MemoryClient;
+use client::{
+    connect_uds, listen_with_timeouts, message_with_uuid, new_message_uuid, ping, register,
+    ClientEvent, ConnectionState, SendPolicy, TimedOut, Timeouts, RPC_TIMEOUT,
+};
+use common::{ignored_events, registered_pair, serve, spawn_server};
+use proto::gossamer::gossamer_client::GossamerClient;
+use proto::gossamer::gossamer_server::{Gossamer, GossamerServer};
+use proto::gossamer::{
+    ActionRequest, ActionResponse, GetLedgerRequest, GetLedgerResponse, RevokeKeyRequest,
+    RevokeKeyResponse,
+};
+use proto::service::brongnal_client::BrongnalClient;
+use proto::service::brongnal_server::BrongnalServer;
+use proto::DEFAULT_DEVICE_ID;
+use server::brongnal::BrongnalController;
+use server::gossamer::InMemoryGossamer;
+use server::memory_brongnal::MemoryStorage;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::unix::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::UnixListener;
+use tokio::net::UnixStream;
+use tokio::sync::{broadcast, Mutex};
+use tokio::task::JoinHandle;
+use tonic::transport::Server;
+
+/// Forwards connections on `path` to the server at `target` until `blackhole` is set, after
+/// which it silently discards traffic in both directions, like a NAT that has forgotten the
+/// connection.
+fn blackholing_proxy(
+    path: &Path,
+    target: PathBuf,
+    blackhole: Arc<AtomicBool>,
+) -> Result<JoinHandle<()>> {
+    async fn pump(mut from: OwnedReadHalf, mut to: OwnedWriteHalf, blackhole: Arc<AtomicBool>) {
+        let mut buf = [0; 4096];
+        while let Ok(len) = from.read(&mut buf).await {
+            if len == 0 {
+                return;
+            }
+            if !blackhole.load(Ordering::SeqCst) && to.write_all(&buf[..len]).await.is_err() {
+                return;
+            }
+        }
+    }
+
+    let _ = std::fs::remove_file(path);
+    let listener = UnixListener::bind(path)?;
+    Ok(tokio::spawn(async move {
+        while let Ok((client, _)) = listener.accept().await {
+            let Ok(server) = UnixStream::connect(&target).await else {
+                return;
+            };
+            let (client_read, client_write) = client.into_split();
+            let (server_read, server_write) = server.into_split();
+            tokio::spawn(pump(client_read, server_write, blackhole.clone()));
+            tokio::spawn(pump(server_read, client_write, blackhole.clone()));
+        }
+    }))
+}
+
+#[tokio::test]
+async fn listen_detects_blackholed_connection() -> Result<()> {
+    let controller = BrongnalController::new(Box::new(MemoryStorage::default()))
+        .with_heartbeat_interval(Duration::from_millis(100));
+    let server = spawn_server("blackhole", controller).await?;
+    let proxy_path = std::env::temp_dir().join(format!(
+        "brongnal-blackhole-proxy-{}.sock",
+        std::process::id()
+    ));
+    let blackhole = Arc::new(AtomicBool::new(false));
+    let proxy = blackholing_proxy(&proxy_path, server.path.clone(), blackhole.clone())?;
+
+    let channel = connect_uds(&proxy_path).await?;
+    let mut stub = BrongnalClient::new(channel.clone());
+    let bob = Arc::new(Mutex::new(MemoryClient::new()));
+    register(
+        &mut stub,
+        bob.clone(),
+        String::from("bob"),
+        DEFAULT_DEVICE_ID,
+        &ignored_events(),
+    )
+    .await?;
+    let (tx, mut rx) = broadcast::channel(16);
+    let listener = tokio::spawn(listen_with_timeouts(
+        stub,
+        GossamerClient::new(channel.clone()),
+        bob.clone(),
+        String::from("bob"),
+        DEFAULT_DEVICE_ID,
+        tx,
+        Timeouts {
+            heartbeat: Duration::from_millis(500),
+            ..Default::default()
+        },
+    ));
+    while rx.recv().await? != ClientEvent::ConnectionState(ConnectionState::Connected) {}
+
+    // Heartbeats keep a quiet but healthy stream open well past the timeout.
+    tokio::time::sleep(Duration::from_millis(1500)).await;
+    assert!(!listener.is_finished());
+
+    // Nothing tells the client the connection is gone, but the missing heartbeats do.
+    blackhole.store(true, Ordering::SeqCst);
+    let result = tokio::time::timeout(Duration::from_secs(2), listener).await??;
+    assert!(result
+        .unwrap_err()
+        .to_string()
+        .starts_with("No heartbeat from the server"));
+
+    // Nor does the server answer a new stream, which gives up before any heartbeat is due.
+    let result = listen_with_timeouts(
+        BrongnalClient::new(channel.clone()),
+        GossamerClient::new(channel),
+        bob,
+        String::from("bob"),
+        DEFAULT_DEVICE_ID,
+        ignored_events(),
+        Timeouts {
+            stream_connect: Duration::from_millis(200),
+            ..Default::default()
+        },
+    )
+    .await;
+    assert_eq!(
+        result.unwrap_err().downcast_ref::<TimedOut>(),
+        Some(&TimedOut {
+            operation: "retrieve_messages",
+            after: Duration::from_millis(200),
+        })
+    );
+
+    proxy.abort();
+    server.abort();
+    let _ = std::fs::remove_file(proxy_path);
+    Ok(())
+}
+
+#[tokio::test]
+async fn ping_probes_quiet_stream() -> Result<()> {
+    let server = spawn_server(
+        "ping",
+        BrongnalController::new(Box::new(MemoryStorage::default())),
+    )
+    .await?;
+    let proxy_path =
+        std::env::temp_dir().join(format!("brongnal-ping-proxy-{}.sock", std::process::id()));
+    let blackhole = Arc::new(AtomicBool::new(false));
+    let proxy = blackholing_proxy(&proxy_path, server.path.clone(), blackhole.clone())?;
+
+    let channel = connect_uds(&proxy_path).await?;
+    let mut stub = BrongnalClient::new(channel.clone());
+    let rtt = ping(&mut stub, Timeouts::default()).await?;
+    assert!(rtt < RPC_TIMEOUT);
+    let bob = Arc::new(Mutex::new(MemoryClient::new()));
+    register(
+        &mut stub,
+        bob.clone(),
+        String::from("bob"),
+        DEFAULT_DEVICE_ID,
+        &ignored_events(),
+    )
+    .await?;
+    let (tx, mut rx) = broadcast::channel(16);
+    let listener = tokio::spawn(listen_with_timeouts(
+        stub,
+        GossamerClient::new(channel),
+        bob,
+        String::from("bob"),
+        DEFAULT_DEVICE_ID,
+        tx,
+        Timeouts {
+            rpc: Duration::from_millis(200),
+            probe: Duration::from_millis(200),
+            ..Default::default()
+        },
+    ));
+
+    // The server's heartbeats are far apart, so the quiet stream is probed in between.
+    let rtt = tokio::time::timeout(Duration::from_secs(2), async {
+        loop {
+            if let ClientEvent::RoundTripTime(rtt) = rx.recv().await? {
+                return anyhow::Ok(rtt);
+            }
+        }
+    })
+    .await??;
+    assert!(rtt < Duration::from_millis(200));
+
+    // Once the server stops answering, the probe gives up on the stream well before the
+    // missing heartbeats would.
+    blackhole.store(true, Ordering::SeqCst);
+    let result = tokio::time::timeout(Duration::from_secs(2), listener).await??;
+    let err = result.unwrap_err();
+    assert!(err
+        .to_string()
+        .starts_with("The server stopped answering pings"));
+    assert_eq!(
+        err.downcast_ref::<TimedOut>(),
+        Some(&TimedOut {
+            operation: "ping",
+            after: Duration::from_millis(200),
+        })
+    );
+
+    proxy.abort();
+    server.abort();
+    Ok(())
+}
+
+/// Answers like `InMemoryGossamer`, but only after `delay`.
+struct SlowGossamer {
+    inner: InMemoryGossamer,
+    delay: Duration,
+}
+
+#[tonic::async_trait]
+impl Gossamer for SlowGossamer {
+    async fn perform(
+        &self,
+        request: tonic::Request<ActionRequest>,
+    ) -> Result<tonic::Response<ActionResponse>, tonic::Status> {
+        tokio::time::sleep(self.delay).await;
+        self.inner.perform(request).await
+    }
+
+    async fn revoke_key(
+        &self,
+        request: tonic::Request<RevokeKeyRequest>,
+    ) -> Result<tonic::Response<RevokeKeyResponse>, tonic::Status> {
+        tokio::time::sleep(self.delay).await;
+        self.inner.revoke_key(request).await
+    }
+
+    async fn get_ledger(
+        &self,
+        request: tonic::Request<GetLedgerRequest>,
+    ) -> Result<tonic::Response<GetLedgerResponse>, tonic::Status> {
+        tokio::time::sleep(self.delay).await;
+        self.inner.get_ledger(request).await
+    }
+}
+
+#[tokio::test]
+async fn message_times_out_on_slow_server() -> Result<()> {
+    let server = serve(
+        "slow",
+        Server::builder()
+            .add_service(BrongnalServer::new(BrongnalController::new(Box::new(
+                MemoryStorage::default(),
+            ))))
+            .add_service(GossamerServer::new(SlowGossamer {
+                inner: InMemoryGossamer::default(),
+                delay: Duration::from_secs(5),
+            })),
+    )
+    .await?;
+
+    let mut stub = server.stub();
+    let mut gossamer = server.gossamer();
+    let (alice, _bob) = registered_pair(&mut stub).await?;
+
+    // Checking bob's key in Gossamer outlasts the deadline for a single request.
+    let rpc = Duration::from_millis(200);
+    let result = message_with_uuid(
+        &mut stub,
+        &mut gossamer,
+        alice.clone(),
+        String::from("alice"),
+        DEFAULT_DEVICE_ID,
+        "bob",
+        b"Hello Bob!",
+        SendPolicy::default(),
+        new_message_uuid(),
+        Timeouts {
+            rpc,
+            ..Default::default()
+        },
+        &ignored_events(),
+    )
+    .await;
+    assert_eq!(
+        result.unwrap_err().downcast_ref::<TimedOut>(),
+        Some(&TimedOut {
+            operation: "get_ledger",
+            after: rpc,
+        })
+    );
+
+    // And the message as a whole gives up even while each request is within its own.
+    let message = Duration::from_millis(300);
+    let (tx, mut rx) = broadcast::channel(16);
+    let result = message_with_uuid(
+        &mut stub,
+        &mut gossamer,
+        alice,
+        String::from("alice"),
+        DEFAULT_DEVICE_ID,
+        "bob",
+        b"Hello Bob!",
+        SendPolicy::default(),
+        new_message_uuid(),
+        Timeouts {
+            message,
+            ..Default::default()
+        },
+        &tx,
+    )
+    .await;
+    assert_eq!(
+        result.unwrap_err().downcast_ref::<TimedOut>(),
+        Some(&TimedOut {
+            operation: "message",
+            after: message,
+        })
+    );
+    assert!(matches!(rx.recv().await?, ClientEvent::Error(_)));
+
+    server.abort();
+    Ok(())
+}
@@ -0,0 +1,2204 @@
+//! End-to-end tests of sending and receiving messages, with clients talking to a server over a
+//! unix domain socket.
+
+mod common;
+
+use anyhow::Result;
+use chacha20poly1305::aead::Payload;
+use clap::Parser;
+use client::api::BrongnalApp;
+use client::cli::{self, Cli};
+use client::error::{ClientError, UserAction};
+use client::memory_client::MemoryClient;
+use client::session::{Brongnal, MIN_RECONNECT_DELAY};
+use client::{
+    check_key_confirmations, connect_uds, content_type_ad, count_one_time_keys, listen, message,
+    message_with_uuid, new_message_uuid, register, session_ad, session_encrypt,
+    watch_message_status, ClientEvent, ConnectionState, DecryptedMessage, DeliveryState, KeyBackup,
+    MessageTooLarge, PeerSession, PendingConfirmation, SendPolicy, SenderVerification, SentMessage,
+    SessionState, Timeouts, X3DHClient, MAX_MESSAGE_LEN, MAX_RESEND_ATTEMPTS, SESSION_RESET_AFTER,
+};
+use common::{ignored_events, next_message, registered_pair, spawn_server, TestServer};
+use ed25519_dalek::SigningKey;
+use futures::StreamExt;
+use proto::gossamer::gossamer_client::GossamerClient;
+use proto::gossamer::gossamer_server::GossamerServer;
+use proto::service::brongnal_client::BrongnalClient;
+use proto::service::brongnal_server::BrongnalServer;
+use proto::service::message::Payload as PayloadProto;
+use proto::service::Message as MessageProto;
+use proto::service::SignedPreKey as SignedPreKeyProto;
+use proto::service::{
+    ContentType, DecryptionFailure, DeviceMessage, KeyConfirmation, RegisterPreKeyBundleRequest,
+    RequestPreKeysRequest, SendMessageRequest,
+};
+use proto::{Identity, SessionMessage, DEFAULT_DEVICE_ID, MESSAGE_UUID_LEN};
+use protocol::aead::MIN_CIPHERTEXT_LEN;
+use protocol::kem::{KemPublicKey, KemSecretKey, SignedKemPreKey};
+use protocol::x3dh::{initiate_send, initiate_send_with_context, SignedPreKey, SignedPreKeys};
+use server::brongnal::{BrongnalController, QueuedMessage, Storage, MAX_OPKS_PER_REQUEST};
+use server::federation::{Federation, FederationPeer};
+use server::gossamer::InMemoryGossamer;
+use server::memory_brongnal::MemoryStorage;
+use server::uds::bind;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::sync::{broadcast, Mutex};
+use tonic::transport::Server;
+use x25519_dalek::{PublicKey as X25519PublicKey, StaticSecret as X25519StaticSecret};
+
+#[tokio::test]
+async fn retried_message_is_queued_once() -> Result<()> {
+    let storage = MemoryStorage::default();
+    let controller = BrongnalController::new(Box::new(storage.clone()));
+    let server = spawn_server("retry", controller).await?;
+    let mut stub = server.stub();
+    let mut gossamer = server.gossamer();
+    let (alice, _bob) = registered_pair(&mut stub).await?;
+
+    // Alice never saw the first response, say because it timed out, so she sends again.
+    let uuid = new_message_uuid();
+    let mut ids = Vec::new();
+    for _ in 0..2 {
+        ids.push(
+            message_with_uuid(
+                &mut stub,
+                &mut gossamer,
+                alice.clone(),
+                String::from("alice"),
+                DEFAULT_DEVICE_ID,
+                "bob",
+                b"Hello Bob!",
+                SendPolicy::default(),
+                uuid,
+                Timeouts::default(),
+                &ignored_events(),
+            )
+            .await?,
+        );
+    }
+    assert_eq!(ids[0], ids[1]);
+    assert_eq!(
+        storage.get_messages("bob", DEFAULT_DEVICE_ID).await?.len(),
+        1
+    );
+
+    server.abort();
+    Ok(())
+}
+
+#[tokio::test]
+async fn undecryptable_message_is_resent() -> Result<()> {
+    let storage = MemoryStorage::default();
+    let controller = BrongnalController::new(Box::new(storage.clone()));
+    let server = spawn_server("resend", controller).await?;
+    let mut stub = server.stub();
+    let mut gossamer = server.gossamer();
+    let (alice, bob) = registered_pair(&mut stub).await?;
+
+    // Bob loses the one time key Alice's message is encrypted to before collecting it.
+    let (alice_tx, mut alice_rx) = broadcast::channel(16);
+    let id = message(
+        &mut stub,
+        &mut gossamer,
+        alice.clone(),
+        String::from("alice"),
+        DEFAULT_DEVICE_ID,
+        "bob",
+        b"Hello Bob!",
+        SendPolicy::default(),
+        &alice_tx,
+    )
+    .await?;
+    let queued = storage.peek_messages("bob", DEFAULT_DEVICE_ID).await?;
+    bob.lock().await.fetch_wipe_opk_by_id(
+        protocol::x3dh::Message::try_from(queued[0].message.clone())?
+            .opk_id
+            .unwrap(),
+    )?;
+
+    let alice_listener = tokio::spawn(listen(
+        stub.clone(),
+        gossamer.clone(),
+        alice,
+        String::from("alice"),
+        DEFAULT_DEVICE_ID,
+        alice_tx,
+    ));
+    let (bob_tx, mut bob_rx) = broadcast::channel(16);
+    let bob_listener = tokio::spawn(listen(
+        stub.clone(),
+        gossamer.clone(),
+        bob,
+        String::from("bob"),
+        DEFAULT_DEVICE_ID,
+        bob_tx,
+    ));
+
+    // Bob tells Alice he couldn't decrypt it, so she resends it to a fresh prekey bundle.
+    assert_eq!(
+        next_message(&mut bob_rx).await.unwrap().message,
+        b"Hello Bob!"
+    );
+    loop {
+        if let ClientEvent::MessageResent {
+            id: resent,
+            recipient,
+            device_id,
+            ..
+        } = alice_rx.recv().await?
+        {
+            assert_eq!(resent, id);
+            assert_eq!(recipient, "bob");
+            assert_eq!(device_id, DEFAULT_DEVICE_ID);
+            break;
+        }
+    }
+
+    alice_listener.abort();
+    bob_listener.abort();
+    server.abort();
+    Ok(())
+}
+
+/// Sends Alice a notice from Bob that the message she sent as `uuid` didn't decrypt.
+async fn send_decryption_failure(
+    stub: &mut BrongnalClient<tonic::transport::Channel>,
+    bob: &Mutex<MemoryClient>,
+    uuid: [u8; MESSAGE_UUID_LEN],
+) -> Result<()> {
+    let failure = prost::Message::encode_to_vec(&DecryptionFailure {
+        message_uuid: Some(uuid.to_vec()),
+        device_id: Some(DEFAULT_DEVICE_ID),
+    });
+    let bundle = stub
+        .request_pre_keys(RequestPreKeysRequest {
+            identity: Some(String::from("alice")),
+        })
+        .await?
+        .into_inner()
+        .bundles
+        .remove(0);
+    let (_, notice) = initiate_send_with_context(
+        bundle.try_into()?,
+        String::from("bob"),
+        &bob.lock().await.get_ik()?,
+        &failure,
+        &content_type_ad(ContentType::DecryptionFailure),
+    )?;
+    let mut notice: MessageProto = notice.into();
+    notice.set_content_type(ContentType::DecryptionFailure);
+    stub.send_message(SendMessageRequest {
+        recipient_identity: Some(String::from("alice")),
+        message: None,
+        device_messages: vec![DeviceMessage {
+            device_id: Some(DEFAULT_DEVICE_ID),
+            message: Some(notice),
+        }],
+        message_uuid: None,
+        partial: None,
+    })
+    .await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn resends_are_limited() -> Result<()> {
+    let storage = MemoryStorage::default();
+    let controller = BrongnalController::new(Box::new(storage.clone()));
+    let server = spawn_server("resend-limit", controller).await?;
+    let mut stub = server.stub();
+    let mut gossamer = server.gossamer();
+    let (alice, bob) = registered_pair(&mut stub).await?;
+    let uuid = new_message_uuid();
+    message_with_uuid(
+        &mut stub,
+        &mut gossamer,
+        alice.clone(),
+        String::from("alice"),
+        DEFAULT_DEVICE_ID,
+        "bob",
+        b"Hello Bob!",
+        SendPolicy::default(),
+        uuid,
+        Timeouts::default(),
+        &ignored_events(),
+    )
+    .await?;
+    let (alice_tx, mut alice_rx) = broadcast::channel(16);
+    let alice_listener = tokio::spawn(listen(
+        stub.clone(),
+        gossamer.clone(),
+        alice,
+        String::from("alice"),
+        DEFAULT_DEVICE_ID,
+        alice_tx,
+    ));
+
+    // Each copy fails to decrypt in turn, then the message as first sent again, but they are
+    // resent no more than `MAX_RESEND_ATTEMPTS` times in all.
+    let mut failed = uuid;
+    for _ in 0..MAX_RESEND_ATTEMPTS {
+        send_decryption_failure(&mut stub, &bob, failed).await?;
+        loop {
+            match alice_rx.recv().await? {
+                ClientEvent::MessageResent { .. } => break,
+                ClientEvent::Error(error) => panic!("{error}"),
+                _ => {}
+            }
+        }
+        let queued = storage.peek_messages("bob", DEFAULT_DEVICE_ID).await?;
+        failed = queued.last().unwrap().message.message_uuid().try_into()?;
+    }
+    for failed in [failed, uuid] {
+        send_decryption_failure(&mut stub, &bob, failed).await?;
+        loop {
+            match alice_rx.recv().await? {
+                ClientEvent::Error(error) => {
+                    assert!(error.contains("still can't decrypt"), "{error}");
+                    break;
+                }
+                ClientEvent::MessageResent { .. } => panic!("Resent the message once too often."),
+                _ => {}
+            }
+        }
+    }
+    assert_eq!(
+        storage.peek_messages("bob", DEFAULT_DEVICE_ID).await?.len(),
+        1 + MAX_RESEND_ATTEMPTS as usize
+    );
+
+    alice_listener.abort();
+    server.abort();
+    Ok(())
+}
+
+#[tokio::test]
+async fn key_confirmation_over_uds() -> Result<()> {
+    let controller = BrongnalController::new(Box::new(MemoryStorage::default()));
+    let server = spawn_server("confirm", controller).await?;
+    let mut stub = server.stub();
+    let mut gossamer = server.gossamer();
+    let (alice, bob) = registered_pair(&mut stub).await?;
+    let (alice_tx, mut alice_rx) = broadcast::channel(16);
+
+    // Bob never collects the first message, so its key is reported unconfirmed.
+    let unconfirmed = message(
+        &mut stub,
+        &mut gossamer,
+        alice.clone(),
+        String::from("alice"),
+        DEFAULT_DEVICE_ID,
+        "bob",
+        b"Anyone there?",
+        SendPolicy::default(),
+        &alice_tx,
+    )
+    .await?;
+    check_key_confirmations(alice.clone(), Duration::ZERO, &alice_tx).await?;
+    loop {
+        if let ClientEvent::KeyNotConfirmed {
+            id,
+            recipient,
+            device_id,
+        } = alice_rx.recv().await?
+        {
+            assert_eq!(id, unconfirmed);
+            assert_eq!(recipient, "bob");
+            assert_eq!(device_id, DEFAULT_DEVICE_ID);
+            break;
+        }
+    }
+    assert_eq!(
+        alice.lock().await.session_state("bob")?,
+        SessionState::Unconfirmed
+    );
+    // Nor is the session it set up used any more, so the next message agrees on a new key.
+    assert!(alice
+        .lock()
+        .await
+        .get_session("bob", DEFAULT_DEVICE_ID)?
+        .is_none());
+
+    // A confirmation with the wrong MAC is rejected, and the real one still accepted.
+    let uuid = new_message_uuid();
+    message_with_uuid(
+        &mut stub,
+        &mut gossamer,
+        alice.clone(),
+        String::from("alice"),
+        DEFAULT_DEVICE_ID,
+        "bob",
+        b"Hello Bob!",
+        SendPolicy::default(),
+        uuid,
+        Timeouts::default(),
+        &alice_tx,
+    )
+    .await?;
+    let bundle = stub
+        .request_pre_keys(RequestPreKeysRequest {
+            identity: Some(String::from("alice")),
+        })
+        .await?
+        .into_inner()
+        .bundles
+        .remove(0);
+    let forged = KeyConfirmation {
+        message_uuid: Some(uuid.to_vec()),
+        device_id: Some(DEFAULT_DEVICE_ID),
+        mac: Some(vec![0; 32]),
+    };
+    // From a key Bob hasn't published, which can't replace the session Alice keeps with him.
+    let ik = MemoryClient::new().get_ik()?;
+    let (_, forged) = initiate_send_with_context(
+        bundle.try_into()?,
+        String::from("bob"),
+        &ik,
+        &prost::Message::encode_to_vec(&forged),
+        &content_type_ad(ContentType::KeyConfirmation),
+    )?;
+    let mut forged: MessageProto = forged.into();
+    forged.set_content_type(ContentType::KeyConfirmation);
+    stub.send_message(SendMessageRequest {
+        recipient_identity: Some(String::from("alice")),
+        message: None,
+        device_messages: vec![DeviceMessage {
+            device_id: Some(DEFAULT_DEVICE_ID),
+            message: Some(forged),
+        }],
+        message_uuid: None,
+        partial: None,
+    })
+    .await?;
+    let alice_listener = tokio::spawn(listen(
+        stub.clone(),
+        gossamer.clone(),
+        alice.clone(),
+        String::from("alice"),
+        DEFAULT_DEVICE_ID,
+        alice_tx,
+    ));
+    loop {
+        match alice_rx.recv().await? {
+            ClientEvent::Error(error) => {
+                assert!(error.starts_with("Rejected a key confirmation from bob"));
+                break;
+            }
+            ClientEvent::SessionConfirmed { .. } => panic!("Accepted a forged confirmation."),
+            _ => {}
+        }
+    }
+
+    let (bob_tx, mut bob_rx) = broadcast::channel(16);
+    let bob_listener = tokio::spawn(listen(
+        stub.clone(),
+        gossamer.clone(),
+        bob.clone(),
+        String::from("bob"),
+        DEFAULT_DEVICE_ID,
+        bob_tx,
+    ));
+    // The first message's confirmation comes too late to count, if Alice can even read it under
+    // the session she dropped, but the second's does.
+    assert_eq!(
+        next_message(&mut bob_rx).await.unwrap().message,
+        b"Anyone there?"
+    );
+    assert_eq!(
+        next_message(&mut bob_rx).await.unwrap().message,
+        b"Hello Bob!"
+    );
+    loop {
+        match alice_rx.recv().await? {
+            ClientEvent::SessionConfirmed { peer, device_id } => {
+                assert_eq!(peer, "bob");
+                assert_eq!(device_id, DEFAULT_DEVICE_ID);
+                break;
+            }
+            ClientEvent::Error(error) => panic!("{error}"),
+            _ => {}
+        }
+    }
+    assert_eq!(
+        alice.lock().await.session_state("bob")?,
+        SessionState::Confirmed
+    );
+    // Alice's replies to confirmations aren't confirmed, so Bob has nothing to wait for.
+    assert_eq!(
+        bob.lock().await.session_state("alice")?,
+        SessionState::Unconfirmed
+    );
+
+    alice_listener.abort();
+    bob_listener.abort();
+    server.abort();
+    Ok(())
+}
+
+#[tokio::test]
+async fn relabelled_content_types_are_rejected() -> Result<()> {
+    let controller = BrongnalController::new(Box::new(MemoryStorage::default()));
+    let server = spawn_server("relabel", controller).await?;
+    let mut stub = server.stub();
+    let mut gossamer = server.gossamer();
+    let (alice, bob) = registered_pair(&mut stub).await?;
+    // A message Alice would resend if told Bob couldn't decrypt it.
+    let uuid = new_message_uuid();
+    message_with_uuid(
+        &mut stub,
+        &mut gossamer,
+        alice.clone(),
+        String::from("alice"),
+        DEFAULT_DEVICE_ID,
+        "bob",
+        b"Hello Bob!",
+        SendPolicy::default(),
+        uuid,
+        Timeouts::default(),
+        &ignored_events(),
+    )
+    .await?;
+
+    // Bob's messages to Alice, each sealed as one content type and relabelled as another in
+    // transit.
+    let confirmation = prost::Message::encode_to_vec(&KeyConfirmation {
+        message_uuid: Some(uuid.to_vec()),
+        device_id: Some(DEFAULT_DEVICE_ID),
+        mac: Some(vec![0; 32]),
+    });
+    let failure = prost::Message::encode_to_vec(&DecryptionFailure {
+        message_uuid: Some(uuid.to_vec()),
+        device_id: Some(DEFAULT_DEVICE_ID),
+    });
+    let ik = bob.lock().await.get_ik()?;
+    for (plaintext, sealed_as, labelled_as) in [
+        (
+            &confirmation,
+            ContentType::KeyConfirmation,
+            ContentType::Text,
+        ),
+        (&failure, ContentType::Text, ContentType::DecryptionFailure),
+        (
+            &confirmation,
+            ContentType::Text,
+            ContentType::KeyConfirmation,
+        ),
+    ] {
+        let bundle = stub
+            .request_pre_keys(RequestPreKeysRequest {
+                identity: Some(String::from("alice")),
+            })
+            .await?
+            .into_inner()
+            .bundles
+            .remove(0);
+        let (_, sealed) = initiate_send_with_context(
+            bundle.try_into()?,
+            String::from("bob"),
+            &ik,
+            plaintext,
+            &content_type_ad(sealed_as),
+        )?;
+        let mut sealed: MessageProto = sealed.into();
+        sealed.set_content_type(labelled_as);
+        stub.send_message(SendMessageRequest {
+            recipient_identity: Some(String::from("alice")),
+            message: None,
+            device_messages: vec![DeviceMessage {
+                device_id: Some(DEFAULT_DEVICE_ID),
+                message: Some(sealed),
+            }],
+            message_uuid: None,
+            partial: None,
+        })
+        .await?;
+    }
+
+    let (alice_tx, mut alice_rx) = broadcast::channel(16);
+    let alice_listener = tokio::spawn(listen(
+        stub.clone(),
+        gossamer.clone(),
+        alice.clone(),
+        String::from("alice"),
+        DEFAULT_DEVICE_ID,
+        alice_tx,
+    ));
+    for _ in 0..3 {
+        loop {
+            match alice_rx.recv().await? {
+                ClientEvent::Error(error) => {
+                    assert!(
+                        error.starts_with("Failed to decrypt a message from \"bob\""),
+                        "{error}"
+                    );
+                    break;
+                }
+                ClientEvent::MessageReceived(message) => {
+                    panic!("Showed a relabelled {:?}.", message.message)
+                }
+                ClientEvent::SessionConfirmed { .. } => {
+                    panic!("Accepted a relabelled confirmation.")
+                }
+                _ => {}
+            }
+        }
+    }
+    assert_eq!(
+        alice
+            .lock()
+            .await
+            .get_sent_message(&uuid)?
+            .unwrap()
+            .attempts,
+        0
+    );
+
+    alice_listener.abort();
+    server.abort();
+    Ok(())
+}
+
+#[tokio::test]
+async fn message_payloads_over_uds() -> Result<()> {
+    let storage = MemoryStorage::default();
+    let controller = BrongnalController::new(Box::new(storage.clone()));
+    let server = spawn_server("payloads", controller).await?;
+    let mut stub = server.stub();
+    let mut gossamer = server.gossamer();
+    let (alice, bob) = registered_pair(&mut stub).await?;
+
+    // Once the first message sets up a session, later ones go under it.
+    set_up_sessions(&server, &alice, &bob).await?;
+    message(
+        &mut stub,
+        &mut gossamer,
+        alice.clone(),
+        String::from("alice"),
+        DEFAULT_DEVICE_ID,
+        "bob",
+        b"Over the session",
+        SendPolicy::default(),
+        &ignored_events(),
+    )
+    .await?;
+    assert!(matches!(
+        storage.peek_messages("bob", DEFAULT_DEVICE_ID).await?[..],
+        [QueuedMessage {
+            message: MessageProto {
+                payload: Some(PayloadProto::SessionCiphertext(_)),
+                ..
+            },
+            ..
+        }]
+    ));
+    // A payload from a later release, which Bob can't read but hears about.
+    storage
+        .add_message(
+            "bob",
+            DEFAULT_DEVICE_ID,
+            MessageProto {
+                message_uuid: Some(vec![7; MESSAGE_UUID_LEN]),
+                ..Default::default()
+            },
+        )
+        .await?;
+    message(
+        &mut stub,
+        &mut gossamer,
+        alice,
+        String::from("alice"),
+        DEFAULT_DEVICE_ID,
+        "bob",
+        b"Still there?",
+        SendPolicy::default(),
+        &ignored_events(),
+    )
+    .await?;
+
+    let (bob_tx, mut bob_rx) = broadcast::channel(16);
+    let listener = tokio::spawn(listen(
+        stub.clone(),
+        gossamer.clone(),
+        bob,
+        String::from("bob"),
+        DEFAULT_DEVICE_ID,
+        bob_tx,
+    ));
+    let mut events = Vec::new();
+    while events.len() < 3 {
+        match bob_rx.recv().await? {
+            ClientEvent::MessageReceived(message) => events
+                .push(String::from_utf8(message.message)? + " from " + &message.sender_identity),
+            ClientEvent::Warning(warning) => events.push(warning),
+            ClientEvent::Error(error) => panic!("{error}"),
+            _ => {}
+        }
+    }
+    assert_eq!(
+        events,
+        [
+            "Over the session from alice",
+            "Ignoring a message in a form this client doesn't know how to read.",
+            "Still there? from alice",
+        ]
+    );
+
+    listener.abort();
+    server.abort();
+    Ok(())
+}
+
+/// Has Alice send Bob a first message, with X3DH, so each keeps a session with the other, and
+/// waits for Bob to read it and for Alice to read his confirmation of it, which goes under the
+/// session.
+async fn set_up_sessions(
+    server: &TestServer,
+    alice: &Arc<Mutex<MemoryClient>>,
+    bob: &Arc<Mutex<MemoryClient>>,
+) -> Result<()> {
+    message(
+        &mut server.stub(),
+        &mut server.gossamer(),
+        alice.clone(),
+        String::from("alice"),
+        DEFAULT_DEVICE_ID,
+        "bob",
+        b"Hello Bob!",
+        SendPolicy::default(),
+        &ignored_events(),
+    )
+    .await?;
+    let (alice_tx, mut alice_rx) = broadcast::channel(16);
+    let alice_listener = tokio::spawn(listen(
+        server.stub(),
+        server.gossamer(),
+        alice.clone(),
+        String::from("alice"),
+        DEFAULT_DEVICE_ID,
+        alice_tx,
+    ));
+    let (bob_tx, mut bob_rx) = broadcast::channel(16);
+    let bob_listener = tokio::spawn(listen(
+        server.stub(),
+        server.gossamer(),
+        bob.clone(),
+        String::from("bob"),
+        DEFAULT_DEVICE_ID,
+        bob_tx,
+    ));
+    assert_eq!(
+        next_message(&mut bob_rx).await.unwrap().message,
+        b"Hello Bob!"
+    );
+    loop {
+        match alice_rx.recv().await? {
+            ClientEvent::SessionConfirmed { .. } => break,
+            ClientEvent::Error(error) => panic!("{error}"),
+            _ => {}
+        }
+    }
+    alice_listener.abort();
+    bob_listener.abort();
+    Ok(())
+}
+
+/// Sends `message` from Alice to Bob under the session she keeps with him.
+async fn send_over_session(
+    stub: &mut BrongnalClient<tonic::transport::Channel>,
+    alice: &Mutex<MemoryClient>,
+    message: &[u8],
+) -> Result<()> {
+    let ciphertext = session_encrypt(
+        &mut *alice.lock().await,
+        "bob",
+        DEFAULT_DEVICE_ID,
+        Payload {
+            msg: message,
+            aad: &session_ad("alice", DEFAULT_DEVICE_ID, ContentType::Text),
+        },
+    )?;
+    send_session_ciphertext(stub, ciphertext).await
+}
+
+/// Sends a `ciphertext` Alice encrypted under the session she keeps with Bob, as many times as it
+/// is called with it.
+async fn send_session_ciphertext(
+    stub: &mut BrongnalClient<tonic::transport::Channel>,
+    ciphertext: Vec<u8>,
+) -> Result<()> {
+    let mut message: MessageProto = SessionMessage {
+        sender_identity: String::from("alice"),
+        ciphertext,
+    }
+    .into();
+    message.set_sender_device_id(DEFAULT_DEVICE_ID);
+    stub.send_message(SendMessageRequest {
+        recipient_identity: Some(String::from("bob")),
+        message: None,
+        device_messages: vec![DeviceMessage {
+            device_id: Some(DEFAULT_DEVICE_ID),
+            message: Some(message),
+        }],
+        message_uuid: None,
+        partial: None,
+    })
+    .await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn lost_session_is_reset() -> Result<()> {
+    let controller = BrongnalController::new(Box::new(MemoryStorage::default()));
+    let server = spawn_server("reset", controller).await?;
+    let mut stub = server.stub();
+    let mut gossamer = server.gossamer();
+    let (alice, bob) = registered_pair(&mut stub).await?;
+    // Bob loses the session he shared with Alice, who keeps sending under it.
+    set_up_sessions(&server, &alice, &bob).await?;
+    bob.lock()
+        .await
+        .delete_session("alice", DEFAULT_DEVICE_ID)?;
+    for _ in 0..SESSION_RESET_AFTER {
+        send_over_session(&mut stub, &alice, b"Lost").await?;
+    }
+
+    let (alice_tx, mut alice_rx) = broadcast::channel(16);
+    let alice_listener = tokio::spawn(listen(
+        stub.clone(),
+        gossamer.clone(),
+        alice.clone(),
+        String::from("alice"),
+        DEFAULT_DEVICE_ID,
+        alice_tx,
+    ));
+    let (bob_tx, mut bob_rx) = broadcast::channel(16);
+    let bob_listener = tokio::spawn(listen(
+        stub.clone(),
+        gossamer.clone(),
+        bob.clone(),
+        String::from("bob"),
+        DEFAULT_DEVICE_ID,
+        bob_tx,
+    ));
+    let mut failures = 0;
+    loop {
+        match bob_rx.recv().await? {
+            ClientEvent::Error(_) => failures += 1,
+            ClientEvent::SessionReset { peer } => {
+                assert_eq!(peer, "alice");
+                break;
+            }
+            _ => {}
+        }
+    }
+    assert_eq!(failures, SESSION_RESET_AFTER);
+    // The notice sets up a new session with Alice, which her next message goes under.
+    loop {
+        match alice_rx.recv().await? {
+            ClientEvent::SessionReset { peer } => {
+                assert_eq!(peer, "bob");
+                break;
+            }
+            ClientEvent::Error(error) => panic!("{error}"),
+            _ => {}
+        }
+    }
+    message(
+        &mut stub,
+        &mut gossamer,
+        alice.clone(),
+        String::from("alice"),
+        DEFAULT_DEVICE_ID,
+        "bob",
+        b"Back again",
+        SendPolicy::default(),
+        &ignored_events(),
+    )
+    .await?;
+    assert_eq!(
+        next_message(&mut bob_rx).await.unwrap().message,
+        b"Back again"
+    );
+
+    // Within the cooldown, failures don't reset the session again. The message Bob can't read is
+    // resent with X3DH instead.
+    bob.lock()
+        .await
+        .delete_session("alice", DEFAULT_DEVICE_ID)?;
+    for _ in 0..SESSION_RESET_AFTER {
+        send_over_session(&mut stub, &alice, b"Lost again").await?;
+    }
+    message(
+        &mut stub,
+        &mut gossamer,
+        alice,
+        String::from("alice"),
+        DEFAULT_DEVICE_ID,
+        "bob",
+        b"Still here",
+        SendPolicy::default(),
+        &ignored_events(),
+    )
+    .await?;
+    loop {
+        match bob_rx.recv().await? {
+            ClientEvent::MessageReceived(message) if message.message == b"Still here" => break,
+            ClientEvent::SessionReset { .. } => panic!("Reset the session within the cooldown."),
+            _ => {}
+        }
+    }
+
+    alice_listener.abort();
+    bob_listener.abort();
+    server.abort();
+    Ok(())
+}
+
+#[tokio::test]
+async fn forged_session_messages_dont_reset() -> Result<()> {
+    let controller = BrongnalController::new(Box::new(MemoryStorage::default()));
+    let server = spawn_server("forged-reset", controller).await?;
+    let mut stub = server.stub();
+    let gossamer = server.gossamer();
+    let (alice, bob) = registered_pair(&mut stub).await?;
+    set_up_sessions(&server, &alice, &bob).await?;
+    // Anyone can send messages that claim to be from Alice but don't decrypt under the session.
+    for _ in 0..SESSION_RESET_AFTER {
+        let mut ciphertext = session_encrypt(
+            &mut *alice.lock().await,
+            "bob",
+            DEFAULT_DEVICE_ID,
+            Payload {
+                msg: b"Forged",
+                aad: &session_ad("alice", DEFAULT_DEVICE_ID, ContentType::Text),
+            },
+        )?;
+        *ciphertext.last_mut().unwrap() ^= 1;
+        send_session_ciphertext(&mut stub, ciphertext).await?;
+    }
+    send_over_session(&mut stub, &alice, b"Still mine").await?;
+
+    let (bob_tx, mut bob_rx) = broadcast::channel(16);
+    let bob_listener = tokio::spawn(listen(
+        stub.clone(),
+        gossamer,
+        bob.clone(),
+        String::from("bob"),
+        DEFAULT_DEVICE_ID,
+        bob_tx,
+    ));
+    let mut failures = 0;
+    loop {
+        match bob_rx.recv().await? {
+            ClientEvent::Error(_) => failures += 1,
+            ClientEvent::MessageReceived(message) => {
+                assert_eq!(message.message, b"Still mine");
+                break;
+            }
+            ClientEvent::SessionReset { .. } => panic!("Forged messages reset the session."),
+            _ => {}
+        }
+    }
+    assert_eq!(failures, SESSION_RESET_AFTER);
+
+    bob_listener.abort();
+    server.abort();
+    Ok(())
+}
+
+#[tokio::test]
+async fn replayed_session_messages_are_dropped() -> Result<()> {
+    let controller = BrongnalController::new(Box::new(MemoryStorage::default()));
+    let server = spawn_server("replay", controller).await?;
+    let mut stub = server.stub();
+    let gossamer = server.gossamer();
+    let (alice, bob) = registered_pair(&mut stub).await?;
+    set_up_sessions(&server, &alice, &bob).await?;
+    let mut ciphertexts = Vec::new();
+    for message in [b"One", b"Two"] {
+        ciphertexts.push(session_encrypt(
+            &mut *alice.lock().await,
+            "bob",
+            DEFAULT_DEVICE_ID,
+            Payload {
+                msg: message,
+                aad: &session_ad("alice", DEFAULT_DEVICE_ID, ContentType::Text),
+            },
+        )?);
+    }
+    // Slightly out of order, with each message replayed.
+    for i in [1, 0, 1, 0] {
+        send_session_ciphertext(&mut stub, ciphertexts[i].clone()).await?;
+    }
+
+    let (bob_tx, mut bob_rx) = broadcast::channel(16);
+    let listener = tokio::spawn(listen(
+        stub.clone(),
+        gossamer.clone(),
+        bob.clone(),
+        String::from("bob"),
+        DEFAULT_DEVICE_ID,
+        bob_tx,
+    ));
+    let mut events = Vec::new();
+    while events.len() < 4 {
+        match bob_rx.recv().await? {
+            ClientEvent::MessageReceived(message) => {
+                events.push(String::from_utf8(message.message)?)
+            }
+            ClientEvent::ReplayDetected { peer } => events.push(format!("Replayed from {peer}")),
+            ClientEvent::Error(error) => panic!("{error}"),
+            _ => {}
+        }
+    }
+    assert_eq!(
+        events,
+        ["Two", "One", "Replayed from alice", "Replayed from alice"]
+    );
+    listener.abort();
+
+    // Bob remembers what he received across reconnecting.
+    send_session_ciphertext(&mut stub, ciphertexts[1].clone()).await?;
+    let (bob_tx, mut bob_rx) = broadcast::channel(16);
+    let listener = tokio::spawn(listen(
+        stub.clone(),
+        gossamer,
+        bob,
+        String::from("bob"),
+        DEFAULT_DEVICE_ID,
+        bob_tx,
+    ));
+    loop {
+        match bob_rx.recv().await? {
+            ClientEvent::ReplayDetected { peer } => {
+                assert_eq!(peer, "alice");
+                break;
+            }
+            ClientEvent::MessageReceived(_) => panic!("Showed a replayed message."),
+            ClientEvent::Error(error) => panic!("{error}"),
+            _ => {}
+        }
+    }
+
+    // Only exact copies are replays. A forgery behind the chain fails to decrypt like any other.
+    let mut forged = ciphertexts[0].clone();
+    *forged.last_mut().unwrap() ^= 1;
+    send_session_ciphertext(&mut stub, forged).await?;
+    loop {
+        match bob_rx.recv().await? {
+            ClientEvent::Error(_) => break,
+            ClientEvent::ReplayDetected { .. } => panic!("Took a forgery for a replay."),
+            _ => {}
+        }
+    }
+
+    listener.abort();
+    server.abort();
+    Ok(())
+}
+
+#[tokio::test]
+async fn delivery_states_over_uds() -> Result<()> {
+    let controller = BrongnalController::new(Box::new(MemoryStorage::default()));
+    let server = spawn_server("status", controller).await?;
+    let mut stub = server.stub();
+    let mut gossamer = server.gossamer();
+    let (alice, bob) = registered_pair(&mut stub).await?;
+    let (alice_events, mut alice_rx) = broadcast::channel(16);
+    let watcher = {
+        let mut stub = stub.clone();
+        let alice = alice.clone();
+        let alice_events = alice_events.clone();
+        tokio::spawn(async move {
+            watch_message_status(
+                &mut stub,
+                alice,
+                String::from("alice"),
+                DEFAULT_DEVICE_ID,
+                &alice_events,
+                Timeouts::default(),
+            )
+            .await
+        })
+    };
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let id = message(
+        &mut stub,
+        &mut gossamer,
+        alice.clone(),
+        String::from("alice"),
+        DEFAULT_DEVICE_ID,
+        "bob",
+        b"Hello Bob!",
+        SendPolicy::default(),
+        &alice_events,
+    )
+    .await?;
+    let listener = tokio::spawn(listen(
+        stub.clone(),
+        gossamer.clone(),
+        bob.clone(),
+        String::from("bob"),
+        DEFAULT_DEVICE_ID,
+        ignored_events(),
+    ));
+    let mut states = Vec::new();
+    while states.len() < 2 {
+        if let ClientEvent::DeliveryStateChanged {
+            id,
+            recipient,
+            device_id,
+            state,
+        } = alice_rx.recv().await?
+        {
+            assert_eq!(recipient, "bob");
+            assert_eq!(device_id, DEFAULT_DEVICE_ID);
+            states.push((id, state));
+        }
+    }
+    assert_eq!(
+        states,
+        vec![(id, DeliveryState::Queued), (id, DeliveryState::Delivered)]
+    );
+
+    listener.abort();
+    watcher.abort();
+    server.abort();
+    Ok(())
+}
+
+#[tokio::test]
+async fn client_events_for_a_conversation() -> Result<()> {
+    let controller = BrongnalController::new(Box::new(MemoryStorage::default()));
+    let server = spawn_server("events", controller).await?;
+    let mut stub = server.stub();
+    let mut gossamer = server.gossamer();
+    let alice = Arc::new(Mutex::new(MemoryClient::new()));
+    let bob = Arc::new(Mutex::new(MemoryClient::new()));
+    let (alice_events, mut alice_rx) = broadcast::channel(16);
+    let (bob_events, mut bob_rx) = broadcast::channel(16);
+    register(
+        &mut stub,
+        alice.clone(),
+        String::from("alice"),
+        DEFAULT_DEVICE_ID,
+        &alice_events,
+    )
+    .await?;
+    assert_eq!(
+        alice_rx.recv().await?,
+        ClientEvent::Registered {
+            name: String::from("alice"),
+            device_id: DEFAULT_DEVICE_ID
+        }
+    );
+    register(
+        &mut stub,
+        bob.clone(),
+        String::from("bob"),
+        DEFAULT_DEVICE_ID,
+        &bob_events,
+    )
+    .await?;
+    assert_eq!(
+        bob_rx.recv().await?,
+        ClientEvent::Registered {
+            name: String::from("bob"),
+            device_id: DEFAULT_DEVICE_ID
+        }
+    );
+
+    let listener = tokio::spawn(listen(
+        stub.clone(),
+        gossamer.clone(),
+        bob.clone(),
+        String::from("bob"),
+        DEFAULT_DEVICE_ID,
+        bob_events,
+    ));
+    assert_eq!(
+        bob_rx.recv().await?,
+        ClientEvent::ConnectionState(ConnectionState::Connecting)
+    );
+    assert_eq!(
+        bob_rx.recv().await?,
+        ClientEvent::ConnectionState(ConnectionState::Connected)
+    );
+
+    let id = message(
+        &mut stub,
+        &mut gossamer,
+        alice.clone(),
+        String::from("alice"),
+        DEFAULT_DEVICE_ID,
+        "bob",
+        b"Hello Bob!",
+        SendPolicy::default(),
+        &alice_events,
+    )
+    .await?;
+    assert_eq!(
+        alice_rx.recv().await?,
+        ClientEvent::MessageSent {
+            id,
+            recipient: String::from("bob"),
+            no_one_time_key: false,
+        }
+    );
+    assert_eq!(
+        bob_rx.recv().await?,
+        ClientEvent::MessageReceived(DecryptedMessage {
+            sender_identity: String::from("alice"),
+            message: b"Hello Bob!".to_vec(),
+            sender_revoked: false,
+            sender_verification: SenderVerification::Unverified,
+            session_state: SessionState::Unconfirmed,
+        })
+    );
+
+    let err = message(
+        &mut stub,
+        &mut gossamer,
+        alice.clone(),
+        String::from("alice"),
+        DEFAULT_DEVICE_ID,
+        "carol",
+        b"Hello Carol!",
+        SendPolicy::default(),
+        &alice_events,
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(
+        ClientError::classify(&err),
+        ClientError::NeedsUserAction(UserAction::RecipientNotFound {
+            identity: String::from("carol")
+        })
+    );
+    assert!(matches!(
+        alice_rx.recv().await?,
+        ClientEvent::Error(error) if error.starts_with("Failed to message carol")
+    ));
+
+    // A message Bob can't decrypt is reported, and the ones after it still arrive.
+    let bundle = stub
+        .request_pre_keys(RequestPreKeysRequest {
+            identity: Some(String::from("bob")),
+        })
+        .await?
+        .into_inner()
+        .bundles
+        .remove(0);
+    let ik = alice.lock().await.get_ik()?;
+    let (_, mut corrupt) =
+        initiate_send(bundle.try_into()?, String::from("alice"), &ik, b"Garbled")?;
+    let bundle = stub
+        .request_pre_keys(RequestPreKeysRequest {
+            identity: Some(String::from("bob")),
+        })
+        .await?
+        .into_inner()
+        .bundles
+        .remove(0);
+    let (_, forgotten_opk) = initiate_send(
+        bundle.try_into()?,
+        String::from("mallory"),
+        &ik,
+        b"Forgotten",
+    )?;
+    bob.lock()
+        .await
+        .fetch_wipe_opk_by_id(forgotten_opk.opk_id.unwrap())?;
+    corrupt.ciphertext[0] ^= 1;
+    stub.send_message(SendMessageRequest {
+        recipient_identity: Some(String::from("bob")),
+        message: None,
+        device_messages: vec![DeviceMessage {
+            device_id: Some(DEFAULT_DEVICE_ID),
+            message: Some(corrupt.into()),
+        }],
+        message_uuid: None,
+        partial: None,
+    })
+    .await?;
+    // Nor does one encrypted to a one time key Bob no longer has.
+    stub.send_message(SendMessageRequest {
+        recipient_identity: Some(String::from("bob")),
+        message: None,
+        device_messages: vec![DeviceMessage {
+            device_id: Some(DEFAULT_DEVICE_ID),
+            message: Some(forgotten_opk.into()),
+        }],
+        message_uuid: None,
+        partial: None,
+    })
+    .await?;
+    message(
+        &mut stub,
+        &mut gossamer,
+        alice,
+        String::from("alice"),
+        DEFAULT_DEVICE_ID,
+        "bob",
+        b"Still there?",
+        SendPolicy::default(),
+        &alice_events,
+    )
+    .await?;
+    assert!(matches!(
+        bob_rx.recv().await?,
+        ClientEvent::Error(error) if error.starts_with("Failed to decrypt a message from \"alice\"")
+    ));
+    assert!(matches!(
+        bob_rx.recv().await?,
+        ClientEvent::Error(error) if error.starts_with("Failed to decrypt a message from \"mallory\"")
+    ));
+    assert_eq!(
+        next_message(&mut bob_rx).await.unwrap().message,
+        b"Still there?"
+    );
+
+    drop(stub);
+    drop(gossamer);
+    server.shutdown().await?;
+    assert_eq!(
+        bob_rx.recv().await?,
+        ClientEvent::ConnectionState(ConnectionState::Disconnected)
+    );
+    listener.await??;
+    Ok(())
+}
+
+#[tokio::test]
+async fn message_size_limit() -> Result<()> {
+    let controller =
+        BrongnalController::new(Box::new(MemoryStorage::default())).with_max_ciphertext_len(1000);
+    let server = spawn_server("size-limit", controller).await?;
+    let mut stub = server.stub();
+    let gossamer = server.gossamer();
+    let (alice, bob) = registered_pair(&mut stub).await?;
+    let alice_stub = stub.clone();
+    let send = |text: Vec<u8>| {
+        let mut stub = alice_stub.clone();
+        let mut gossamer = gossamer.clone();
+        let alice = alice.clone();
+        async move {
+            message(
+                &mut stub,
+                &mut gossamer,
+                alice,
+                String::from("alice"),
+                DEFAULT_DEVICE_ID,
+                "bob",
+                &text,
+                SendPolicy::default(),
+                &ignored_events(),
+            )
+            .await
+        }
+    };
+
+    // Too large for any server, so it isn't even encrypted.
+    let err = send(vec![0; MAX_MESSAGE_LEN + 1]).await.unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<MessageTooLarge>(),
+        Some(&MessageTooLarge {
+            len: MAX_MESSAGE_LEN + 1,
+            max: MAX_MESSAGE_LEN,
+        })
+    );
+    assert_eq!(
+        count_one_time_keys(
+            &mut stub,
+            String::from("bob"),
+            DEFAULT_DEVICE_ID,
+            Timeouts::default()
+        )
+        .await?,
+        100
+    );
+
+    // This server's limit is lower, which it tells the client.
+    let max = 1000 - MIN_CIPHERTEXT_LEN;
+    let err = send(vec![1; max + 1]).await.unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<MessageTooLarge>(),
+        Some(&MessageTooLarge { len: max + 1, max })
+    );
+    send(vec![2; max]).await?;
+
+    let (tx, mut rx) = broadcast::channel(16);
+    let listener = tokio::spawn(listen(
+        stub.clone(),
+        gossamer.clone(),
+        bob,
+        String::from("bob"),
+        DEFAULT_DEVICE_ID,
+        tx,
+    ));
+    assert_eq!(next_message(&mut rx).await.unwrap().message, vec![2; max]);
+    listener.abort();
+
+    drop(alice_stub);
+    drop(stub);
+    drop(gossamer);
+    server.shutdown().await?;
+    Ok(())
+}
+
+type Malform<T> = fn(&mut T);
+
+/// The message of the first device message in `request`.
+fn first_message(request: &mut SendMessageRequest) -> &mut MessageProto {
+    request.device_messages[0].message.as_mut().unwrap()
+}
+
+#[tokio::test]
+// Messages in the deprecated flat form are still checked, naming fields outside any payload.
+#[allow(deprecated)]
+async fn malformed_requests_name_the_field() -> Result<()> {
+    let controller =
+        BrongnalController::new(Box::new(MemoryStorage::default())).with_max_ciphertext_len(1000);
+    let server = spawn_server("malformed", controller).await?;
+    let mut stub = server.stub();
+    register(
+        &mut stub,
+        Arc::new(Mutex::new(MemoryClient::new())),
+        String::from("bob"),
+        DEFAULT_DEVICE_ID,
+        &ignored_events(),
+    )
+    .await?;
+
+    let mut carol = MemoryClient::new();
+    let registration = RegisterPreKeyBundleRequest {
+        identity: Some(String::from("carol")),
+        identity_key: Some(carol.get_ik()?.verifying_key().to_bytes().to_vec()),
+        signed_pre_key: Some(carol.get_spk()?.into()),
+        one_time_key_bundle: Some(carol.create_opks(2)?.into()),
+        ..Default::default()
+    };
+    let too_many = MAX_OPKS_PER_REQUEST + 1;
+    let register_cases: [(Malform<RegisterPreKeyBundleRequest>, String); 15] = [
+        (|r| r.identity = None, "request missing identity".into()),
+        (
+            |r| r.identity = Some(String::new()),
+            "Invalid identity: empty".into(),
+        ),
+        (
+            |r| r.identity = Some("c".repeat(100)),
+            "Invalid identity: 100 bytes exceeds the limit of 64".into(),
+        ),
+        (
+            |r| r.identity = Some(String::from("carol\u{7}")),
+            "Invalid identity: contains the disallowed character '\\u{7}'".into(),
+        ),
+        (|r| r.identity_key = None, "Invalid identity_key: missing".into()),
+        (
+            |r| r.identity_key.as_mut().unwrap().truncate(16),
+            "Invalid identity_key: expected 32 bytes, got 16".into(),
+        ),
+        (
+            |r| r.signed_pre_key = None,
+            "Invalid signed_pre_key: missing".into(),
+        ),
+        (
+            |r| {
+                let spk = r.signed_pre_key.as_mut().unwrap();
+                spk.pre_key.as_mut().unwrap().truncate(31);
+            },
+            "Invalid signed_pre_key.pre_key: expected 32 bytes, got 31".into(),
+        ),
+        (
+            |r| r.signed_pre_key.as_mut().unwrap().signature = None,
+            "Invalid signed_pre_key.signature: missing".into(),
+        ),
+        (
+            |r| r.one_time_key_bundle = None,
+            "Invalid one_time_key_bundle: missing".into(),
+        ),
+        (
+            |r| {
+                let bundle = r.one_time_key_bundle.as_mut().unwrap();
+                bundle.pre_keys = vec![bundle.pre_keys[0].clone(); MAX_OPKS_PER_REQUEST + 1];
+            },
+            format!(
+                "Invalid one_time_key_bundle.pre_keys: {too_many} entries exceeds the limit of {MAX_OPKS_PER_REQUEST}"
+            ),
+        ),
+        (
+            |r| r.one_time_key_bundle.as_mut().unwrap().pre_keys[1].push(0),
+            "Invalid one_time_key_bundle.pre_keys: expected 32 bytes, got 33".into(),
+        ),
+        (
+            |r| {
+                let bundle = r.one_time_key_bundle.as_mut().unwrap();
+                bundle.signature.as_mut().unwrap().pop();
+            },
+            "Invalid one_time_key_bundle.signature: expected 64 bytes, got 63".into(),
+        ),
+        (
+            |r| r.last_resort_key = Some(SignedPreKeyProto::default()),
+            "Invalid last_resort_key.pre_key: missing".into(),
+        ),
+        (
+            |r| {
+                r.one_time_kem_keys =
+                    vec![SignedPreKeyProto::default(); MAX_OPKS_PER_REQUEST + 1]
+            },
+            format!(
+                "Invalid one_time_kem_keys: {too_many} entries exceeds the limit of {MAX_OPKS_PER_REQUEST}"
+            ),
+        ),
+    ];
+    for (malform, message) in register_cases {
+        let mut request = registration.clone();
+        malform(&mut request);
+        let status = stub.register_pre_key_bundle(request).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument, "{message}");
+        assert_eq!(status.message(), message);
+    }
+    // None of them got as far as registering carol.
+    let carol_keys = stub
+        .request_pre_keys(RequestPreKeysRequest {
+            identity: Some(String::from("carol")),
+        })
+        .await;
+    assert_eq!(carol_keys.unwrap_err().code(), tonic::Code::NotFound);
+    stub.register_pre_key_bundle(registration).await?;
+
+    for (identity, message) in [
+        (None, "request missing identity"),
+        (Some(String::new()), "Invalid identity: empty"),
+        (
+            Some(String::from("bob/..")),
+            "Invalid identity: contains the disallowed character '/'",
+        ),
+    ] {
+        let status = stub
+            .request_pre_keys(RequestPreKeysRequest { identity })
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument, "{message}");
+        assert_eq!(status.message(), message);
+    }
+
+    let send = SendMessageRequest {
+        recipient_identity: Some(String::from("bob")),
+        device_messages: vec![DeviceMessage {
+            device_id: Some(DEFAULT_DEVICE_ID),
+            message: Some(MessageProto {
+                sender_identity: Some(String::from("carol")),
+                sender_identity_key: Some(carol.get_ik()?.verifying_key().to_bytes().to_vec()),
+                ephemeral_key: Some(
+                    X25519PublicKey::from(&X25519StaticSecret::from([2; 32]))
+                        .to_bytes()
+                        .to_vec(),
+                ),
+                ciphertext: Some(vec![0; 100]),
+                ..Default::default()
+            }),
+        }],
+        message_uuid: Some(vec![7; MESSAGE_UUID_LEN]),
+        ..Default::default()
+    };
+    let send_cases: [(Malform<SendMessageRequest>, &str); 12] = [
+        (
+            |r| r.recipient_identity = None,
+            "request missing recipient_identity",
+        ),
+        (
+            |r| r.recipient_identity = Some(String::from(" bob")),
+            "Invalid recipient_identity: starts or ends with whitespace",
+        ),
+        (
+            |r| r.message_uuid = Some(vec![7; MESSAGE_UUID_LEN - 1]),
+            "Invalid message_uuid: expected 16 bytes, got 15",
+        ),
+        (|r| r.device_messages.clear(), "request missing message"),
+        (
+            |r| r.device_messages[0].message = None,
+            "Invalid device_messages.message: missing",
+        ),
+        (
+            |r| first_message(r).sender_identity = None,
+            "Invalid message.sender_identity: missing",
+        ),
+        (
+            |r| first_message(r).sender_identity = Some("c".repeat(65)),
+            "Invalid message.sender_identity: 65 bytes exceeds the limit of 64",
+        ),
+        (
+            |r| {
+                let message = first_message(r);
+                message.sender_identity_key.as_mut().unwrap().truncate(16);
+            },
+            "Invalid message.sender_identity_key: expected 32 bytes, got 16",
+        ),
+        (
+            |r| first_message(r).ephemeral_key = None,
+            "Invalid message.ephemeral_key: missing",
+        ),
+        (
+            |r| first_message(r).one_time_key = Some(vec![0; 16]),
+            "Invalid message.one_time_key: expected 32 bytes, got 16",
+        ),
+        (
+            |r| first_message(r).ciphertext = None,
+            "Invalid message.ciphertext: missing",
+        ),
+        (
+            |r| first_message(r).ciphertext = Some(vec![0; 1001]),
+            "Invalid message.ciphertext: 1001 bytes exceeds the limit of 1000",
+        ),
+    ];
+    for (malform, message) in send_cases {
+        let mut request = send.clone();
+        malform(&mut request);
+        let status = stub.send_message(request).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument, "{message}");
+        assert_eq!(status.message(), message);
+    }
+    stub.send_message(send).await?;
+
+    drop(stub);
+    server.shutdown().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn federated_conversation() -> Result<()> {
+    let paths = ["a", "b"].map(|server| {
+        std::env::temp_dir().join(format!(
+            "brongnal-federation-{server}-{}.sock",
+            std::process::id()
+        ))
+    });
+    let (incoming_a, cleanup_a) = bind(&paths[0])?;
+    let (incoming_b, cleanup_b) = bind(&paths[1])?;
+    // Both servers are listening, so each can be connected to before the other serves.
+    let channel_a = connect_uds(&paths[0]).await?;
+    let channel_b = connect_uds(&paths[1]).await?;
+    let controller_a = BrongnalController::new(Box::new(MemoryStorage::default())).with_federation(
+        Federation::new("a.example").with_peer(
+            "b.example",
+            FederationPeer::new(channel_b.clone(), "s3cret"),
+        ),
+    );
+    let controller_b = BrongnalController::new(Box::new(MemoryStorage::default())).with_federation(
+        Federation::new("b.example").with_peer(
+            "a.example",
+            FederationPeer::new(channel_a.clone(), "s3cret"),
+        ),
+    );
+    let (shutdown_tx, shutdown_rx) = broadcast::channel::<()>(1);
+    let mut servers = Vec::new();
+    for (controller, incoming, cleanup) in [
+        (controller_a, incoming_a, cleanup_a),
+        (controller_b, incoming_b, cleanup_b),
+    ] {
+        let mut shutdown_rx = shutdown_rx.resubscribe();
+        servers.push(tokio::spawn(async move {
+            let _cleanup = cleanup;
+            Server::builder()
+                .add_service(BrongnalServer::new(controller))
+                .add_service(GossamerServer::new(InMemoryGossamer::default()))
+                .serve_with_incoming_shutdown(incoming, async move {
+                    let _ = shutdown_rx.recv().await;
+                })
+                .await
+        }));
+    }
+
+    let mut stub_a = BrongnalClient::new(channel_a.clone());
+    let mut gossamer_a = GossamerClient::new(channel_a);
+    let mut stub_b = BrongnalClient::new(channel_b.clone());
+    let mut gossamer_b = GossamerClient::new(channel_b);
+    let alice = Arc::new(Mutex::new(MemoryClient::new()));
+    let bob = Arc::new(Mutex::new(MemoryClient::new()));
+    register(
+        &mut stub_a,
+        alice.clone(),
+        String::from("alice"),
+        DEFAULT_DEVICE_ID,
+        &ignored_events(),
+    )
+    .await?;
+    register(
+        &mut stub_b,
+        bob.clone(),
+        String::from("bob"),
+        DEFAULT_DEVICE_ID,
+        &ignored_events(),
+    )
+    .await?;
+
+    let (alice_tx, mut alice_rx) = broadcast::channel(16);
+    let alice_listener = tokio::spawn(listen(
+        stub_a.clone(),
+        gossamer_a.clone(),
+        alice.clone(),
+        String::from("alice"),
+        DEFAULT_DEVICE_ID,
+        alice_tx,
+    ));
+    let (bob_tx, mut bob_rx) = broadcast::channel(16);
+    let bob_listener = tokio::spawn(listen(
+        stub_b.clone(),
+        gossamer_b.clone(),
+        bob.clone(),
+        String::from("bob"),
+        DEFAULT_DEVICE_ID,
+        bob_tx,
+    ));
+
+    message(
+        &mut stub_a,
+        &mut gossamer_a,
+        alice.clone(),
+        String::from("alice"),
+        DEFAULT_DEVICE_ID,
+        "bob@b.example",
+        b"Hello Bob!",
+        SendPolicy::default(),
+        &ignored_events(),
+    )
+    .await?;
+    let received = next_message(&mut bob_rx).await.unwrap();
+    assert_eq!(received.sender_identity, "alice@a.example");
+    assert_eq!(received.message, b"Hello Bob!");
+
+    // Bob replies to the identity the message came from.
+    message(
+        &mut stub_b,
+        &mut gossamer_b,
+        bob,
+        String::from("bob"),
+        DEFAULT_DEVICE_ID,
+        &received.sender_identity,
+        b"Hello Alice!",
+        SendPolicy::default(),
+        &ignored_events(),
+    )
+    .await?;
+    let received = next_message(&mut alice_rx).await.unwrap();
+    assert_eq!(received.sender_identity, "bob@b.example");
+    assert_eq!(received.message, b"Hello Alice!");
+
+    let status = stub_a
+        .request_pre_keys(RequestPreKeysRequest {
+            identity: Some(String::from("carol@c.example")),
+        })
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), tonic::Code::NotFound);
+
+    alice_listener.abort();
+    bob_listener.abort();
+    drop(stub_a);
+    drop(gossamer_a);
+    drop(stub_b);
+    drop(gossamer_b);
+    shutdown_tx.send(())?;
+    for server in servers {
+        server.await??;
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn cli_one_shot_send() -> Result<()> {
+    let data_dir = std::env::temp_dir().join(format!("brongnal-cli-{}", std::process::id()));
+    let controller = BrongnalController::new(Box::new(MemoryStorage::default()));
+    let server = spawn_server("cli", controller).await?;
+    let mut stub = server.stub();
+    let gossamer = server.gossamer();
+    let bob = Arc::new(Mutex::new(MemoryClient::new()));
+    register(
+        &mut stub,
+        bob.clone(),
+        String::from("bob"),
+        DEFAULT_DEVICE_ID,
+        &ignored_events(),
+    )
+    .await?;
+    let (tx, mut rx) = broadcast::channel(16);
+    let listener = tokio::spawn(listen(
+        stub.clone(),
+        gossamer.clone(),
+        bob,
+        String::from("bob"),
+        DEFAULT_DEVICE_ID,
+        tx,
+    ));
+
+    let server_url = format!("unix:{}", server.path.display());
+    let alice = |args: &[&str]| -> Result<Cli> {
+        let flags = [
+            "client",
+            "--name",
+            "alice",
+            "--server",
+            &server_url,
+            "--data-dir",
+            data_dir.to_str().unwrap(),
+        ];
+        Ok(Cli::try_parse_from(flags.iter().chain(args))?)
+    };
+    cli::run(alice(&["register"])?).await?;
+    cli::run(alice(&[
+        "send",
+        "--to",
+        "bob",
+        "--message",
+        "Hello from a script",
+    ])?)
+    .await?;
+
+    let received = next_message(&mut rx).await.unwrap();
+    assert_eq!(received.sender_identity, "alice");
+    assert_eq!(received.message, b"Hello from a script");
+    // The one-shot commands use the keys the first one created.
+    let alice_keys = Cli::try_parse_from([
+        "client",
+        "--name",
+        "alice",
+        "--data-dir",
+        data_dir.to_str().unwrap(),
+    ])?
+    .settings()?
+    .open_client()?;
+    let registered = stub
+        .request_pre_keys(RequestPreKeysRequest {
+            identity: Some(String::from("alice")),
+        })
+        .await?
+        .into_inner()
+        .bundles
+        .remove(0);
+    assert_eq!(
+        registered.identity_key(),
+        alice_keys.get_ik()?.verifying_key().as_bytes()
+    );
+
+    listener.abort();
+    drop(stub);
+    drop(gossamer);
+    server.shutdown().await?;
+    std::fs::remove_dir_all(&data_dir)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn app_api_over_uds() -> Result<()> {
+    let data_dir = std::env::temp_dir().join(format!("brongnal-app-{}", std::process::id()));
+    let storage = MemoryStorage::default();
+    let serve =
+        |storage: MemoryStorage| spawn_server("app", BrongnalController::new(Box::new(storage)));
+    let server = serve(storage.clone()).await?;
+
+    let url = format!("unix:{}", server.path.display());
+    let app = |name: &str| {
+        BrongnalApp::new(
+            data_dir.join(name).to_string_lossy().into_owned(),
+            url.clone(),
+        )
+    };
+    let alice = app("alice").await?;
+    let bob = app("bob").await?;
+    let err = alice
+        .send_message(String::from("bob"), String::from("Hello Bob!"))
+        .await
+        .unwrap_err();
+    assert_eq!(err.to_string(), "Register before sending messages.");
+    alice.register(String::from("alice")).await?;
+    bob.register(String::from("bob")).await?;
+    assert!(bob.register(String::from("bob")).await.is_err());
+    let messages = bob.message_stream();
+
+    alice
+        .send_message(String::from("bob"), String::from("Hello Bob!"))
+        .await?;
+    let received = messages.next().await.unwrap();
+    assert_eq!(received.sender, "alice");
+    assert_eq!(received.text.as_deref(), Some("Hello Bob!"));
+    assert!(!received.sender_revoked);
+    assert_eq!(bob.contacts().await?, vec![String::from("alice")]);
+    assert_eq!(alice.contacts().await?, vec![String::from("bob")]);
+
+    // Restarting the server ends Bob's stream; his listener reconnects once it is back.
+    server.shutdown().await?;
+    let server = serve(storage).await?;
+    alice
+        .send_message(String::from("bob"), String::from("Still there?"))
+        .await?;
+    let received = tokio::time::timeout(Duration::from_secs(10), messages.next())
+        .await?
+        .unwrap();
+    assert_eq!(received.text.as_deref(), Some("Still there?"));
+
+    drop(alice);
+    drop(bob);
+    server.shutdown().await?;
+    let _ = std::fs::remove_dir_all(data_dir);
+    Ok(())
+}
+
+#[tokio::test]
+async fn sessions_over_uds() -> Result<()> {
+    let storage = MemoryStorage::default();
+    let serve = |storage: MemoryStorage| {
+        spawn_server("session", BrongnalController::new(Box::new(storage)))
+    };
+    let server = serve(storage.clone()).await?;
+
+    let url = format!("unix:{}", server.path.display());
+    let alice = Brongnal::connect(&url, MemoryClient::new()).await?;
+    let bob = Brongnal::connect(&url, MemoryClient::new()).await?;
+    let mut alice_events = Box::pin(alice.events());
+    let mut bob_events = Box::pin(bob.events());
+    let mut alice_state = alice.connection_state();
+    assert_eq!(*alice_state.borrow(), ConnectionState::Disconnected);
+    let alice_name: Identity = "alice".parse()?;
+    let bob_name: Identity = "bob".parse()?;
+    let err = alice.send(&bob_name, b"Hello Bob!").await.unwrap_err();
+    assert_eq!(err.to_string(), "Register before sending messages.");
+    alice.register(&alice_name).await?;
+    bob.register(&bob_name).await?;
+    assert!(bob.register(&bob_name).await.is_err());
+    alice_state
+        .wait_for(|state| *state == ConnectionState::Connected)
+        .await?;
+
+    let id = alice.send(&bob_name, b"Hello Bob!").await?;
+    loop {
+        match alice_events.next().await.unwrap() {
+            ClientEvent::MessageSent {
+                id: sent,
+                recipient,
+                ..
+            } => {
+                assert_eq!(sent, id);
+                assert_eq!(recipient, "bob");
+                break;
+            }
+            ClientEvent::Error(e) => panic!("{e}"),
+            _ => {}
+        }
+    }
+    let received = loop {
+        if let ClientEvent::MessageReceived(message) = bob_events.next().await.unwrap() {
+            break message;
+        }
+    };
+    assert_eq!(received.sender_identity, "alice");
+    assert_eq!(received.message, b"Hello Bob!");
+
+    // Messages queued while the server is down wait in the outbox until the session has
+    // reconnected, rather than failing.
+    server.shutdown().await?;
+    let backoff = *alice_state
+        .wait_for(|state| matches!(state, ConnectionState::Backoff { .. }))
+        .await?;
+    let ConnectionState::Backoff { until } = backoff else {
+        unreachable!();
+    };
+    assert!(until <= tokio::time::Instant::now() + MIN_RECONNECT_DELAY);
+    let id = alice.send(&bob_name, b"Still there?").await?;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let server = serve(storage).await?;
+    tokio::time::timeout(
+        Duration::from_secs(10),
+        alice_state.wait_for(|state| *state == ConnectionState::Connected),
+    )
+    .await??;
+    loop {
+        match alice_events.next().await.unwrap() {
+            ClientEvent::MessageSent { id: sent, .. } if sent == id => break,
+            ClientEvent::Error(e) if e.starts_with("Failed to message") => panic!("{e}"),
+            _ => {}
+        }
+    }
+    let received = tokio::time::timeout(Duration::from_secs(10), async {
+        loop {
+            if let ClientEvent::MessageReceived(message) = bob_events.next().await.unwrap() {
+                return message;
+            }
+        }
+    })
+    .await?;
+    assert_eq!(received.message, b"Still there?");
+
+    // Dropping a session stops its background tasks, ending its event streams.
+    drop(alice);
+    assert!(tokio::time::timeout(Duration::from_secs(1), async {
+        while alice_events.next().await.is_some() {}
+    })
+    .await
+    .is_ok());
+    drop(bob);
+    server.shutdown().await?;
+    Ok(())
+}
+
+/// A `MemoryClient` that records which of its methods were called.
+struct RecordingClient {
+    inner: MemoryClient,
+    calls: Arc<std::sync::Mutex<Vec<&'static str>>>,
+}
+
+impl RecordingClient {
+    fn record(&self, call: &'static str) {
+        self.calls.lock().unwrap().push(call);
+    }
+}
+
+impl X3DHClient for RecordingClient {
+    fn fetch_wipe_opk(&mut self, opk: &X25519PublicKey) -> Result<X25519StaticSecret> {
+        self.record("fetch_wipe_opk");
+        self.inner.fetch_wipe_opk(opk)
+    }
+    fn fetch_wipe_opk_by_id(&mut self, id: u32) -> Result<X25519StaticSecret> {
+        self.record("fetch_wipe_opk_by_id");
+        self.inner.fetch_wipe_opk_by_id(id)
+    }
+    fn get_ik(&self) -> Result<SigningKey> {
+        self.record("get_ik");
+        self.inner.get_ik()
+    }
+    fn get_pre_key(&self) -> Result<X25519StaticSecret> {
+        self.record("get_pre_key");
+        self.inner.get_pre_key()
+    }
+    fn get_spk_secret(&self, id: u32) -> Result<X25519StaticSecret> {
+        self.record("get_spk_secret");
+        self.inner.get_spk_secret(id)
+    }
+    fn set_pre_key_id(&mut self, key: &X25519PublicKey, id: u32) -> Result<()> {
+        self.record("set_pre_key_id");
+        self.inner.set_pre_key_id(key, id)
+    }
+    fn get_spk(&self) -> Result<SignedPreKey> {
+        self.record("get_spk");
+        self.inner.get_spk()
+    }
+    fn create_opks(&mut self, num_keys: u32) -> Result<SignedPreKeys> {
+        self.record("create_opks");
+        self.inner.create_opks(num_keys)
+    }
+    fn rotate_spk(&mut self) -> Result<SignedPreKey> {
+        self.record("rotate_spk");
+        self.inner.rotate_spk()
+    }
+    fn get_last_resort_key(&self) -> Result<SignedPreKey> {
+        self.record("get_last_resort_key");
+        self.inner.get_last_resort_key()
+    }
+    fn get_last_resort_secret(&self, id: u32) -> Result<X25519StaticSecret> {
+        self.record("get_last_resort_secret");
+        self.inner.get_last_resort_secret(id)
+    }
+    fn rotate_last_resort_key(&mut self) -> Result<SignedPreKey> {
+        self.record("rotate_last_resort_key");
+        self.inner.rotate_last_resort_key()
+    }
+    fn create_kem_opks(&mut self, num_keys: u32) -> Result<Vec<SignedKemPreKey>> {
+        self.record("create_kem_opks");
+        self.inner.create_kem_opks(num_keys)
+    }
+    fn set_kem_pre_key_id(&mut self, key: &KemPublicKey, id: u32) -> Result<()> {
+        self.record("set_kem_pre_key_id");
+        self.inner.set_kem_pre_key_id(key, id)
+    }
+    fn fetch_wipe_kem_opk_by_id(&mut self, id: u32) -> Result<KemSecretKey> {
+        self.record("fetch_wipe_kem_opk_by_id");
+        self.inner.fetch_wipe_kem_opk_by_id(id)
+    }
+    fn get_last_resort_kem_key(&self) -> Result<Option<SignedKemPreKey>> {
+        self.record("get_last_resort_kem_key");
+        self.inner.get_last_resort_kem_key()
+    }
+    fn get_last_resort_kem_secret(&self, id: u32) -> Result<KemSecretKey> {
+        self.record("get_last_resort_kem_secret");
+        self.inner.get_last_resort_kem_secret(id)
+    }
+    fn rotate_last_resort_kem_key(&mut self) -> Result<SignedKemPreKey> {
+        self.record("rotate_last_resort_kem_key");
+        self.inner.rotate_last_resort_kem_key()
+    }
+    fn save_sent_message(
+        &mut self,
+        uuid: &[u8; MESSAGE_UUID_LEN],
+        message: SentMessage,
+    ) -> Result<()> {
+        self.record("save_sent_message");
+        self.inner.save_sent_message(uuid, message)
+    }
+    fn get_sent_message(&self, uuid: &[u8; MESSAGE_UUID_LEN]) -> Result<Option<SentMessage>> {
+        self.record("get_sent_message");
+        self.inner.get_sent_message(uuid)
+    }
+    fn forget_sent_messages(&mut self, before: SystemTime) -> Result<()> {
+        self.record("forget_sent_messages");
+        self.inner.forget_sent_messages(before)
+    }
+    fn export_keys(&self) -> Result<KeyBackup> {
+        self.record("export_keys");
+        self.inner.export_keys()
+    }
+    fn import_keys(&mut self, keys: KeyBackup) -> Result<()> {
+        self.record("import_keys");
+        self.inner.import_keys(keys)
+    }
+    fn get_session(&mut self, peer: &str, device_id: u32) -> Result<Option<PeerSession>> {
+        self.record("get_session");
+        self.inner.get_session(peer, device_id)
+    }
+    fn save_session(&mut self, peer: &str, device_id: u32, session: &PeerSession) -> Result<()> {
+        self.record("save_session");
+        self.inner.save_session(peer, device_id, session)
+    }
+    fn delete_session(&mut self, peer: &str, device_id: u32) -> Result<()> {
+        self.record("delete_session");
+        self.inner.delete_session(peer, device_id)
+    }
+    fn session_devices(&self, peer: &str) -> Result<Vec<u32>> {
+        self.record("session_devices");
+        self.inner.session_devices(peer)
+    }
+    fn expect_key_confirmation(
+        &mut self,
+        uuid: &[u8; MESSAGE_UUID_LEN],
+        confirmation: PendingConfirmation,
+    ) -> Result<()> {
+        self.record("expect_key_confirmation");
+        self.inner.expect_key_confirmation(uuid, confirmation)
+    }
+    fn take_key_confirmation(
+        &mut self,
+        uuid: &[u8; MESSAGE_UUID_LEN],
+        device_id: u32,
+    ) -> Result<Option<PendingConfirmation>> {
+        self.record("take_key_confirmation");
+        self.inner.take_key_confirmation(uuid, device_id)
+    }
+    fn expire_key_confirmations(
+        &mut self,
+        before: SystemTime,
+    ) -> Result<Vec<([u8; MESSAGE_UUID_LEN], PendingConfirmation)>> {
+        self.record("expire_key_confirmations");
+        self.inner.expire_key_confirmations(before)
+    }
+    fn session_state(&self, peer: &str) -> Result<SessionState> {
+        self.record("session_state");
+        self.inner.session_state(peer)
+    }
+    fn confirm_session(&mut self, peer: &str) -> Result<()> {
+        self.record("confirm_session");
+        self.inner.confirm_session(peer)
+    }
+    fn wipe(&mut self) -> Result<()> {
+        self.record("wipe");
+        self.inner.wipe()
+    }
+}
+
+#[tokio::test]
+async fn helpers_accept_any_x3dh_client() -> Result<()> {
+    let server = spawn_server(
+        "mock",
+        BrongnalController::new(Box::new(MemoryStorage::default())),
+    )
+    .await?;
+    let mut stub = server.stub();
+    let mut gossamer = server.gossamer();
+    let alice_calls = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let alice = Arc::new(Mutex::new(RecordingClient {
+        inner: MemoryClient::new(),
+        calls: alice_calls.clone(),
+    }));
+    let bob_calls = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let bob = Arc::new(Mutex::new(RecordingClient {
+        inner: MemoryClient::new(),
+        calls: bob_calls.clone(),
+    }));
+    register(
+        &mut stub,
+        alice.clone(),
+        String::from("alice"),
+        DEFAULT_DEVICE_ID,
+        &ignored_events(),
+    )
+    .await?;
+    register(
+        &mut stub,
+        bob.clone(),
+        String::from("bob"),
+        DEFAULT_DEVICE_ID,
+        &ignored_events(),
+    )
+    .await?;
+    let registration = bob_calls.lock().unwrap().clone();
+    for call in [
+        "get_ik",
+        "get_spk",
+        "create_opks",
+        "get_last_resort_key",
+        "set_pre_key_id",
+    ] {
+        assert!(registration.contains(&call), "register didn't call {call}");
+    }
+    alice_calls.lock().unwrap().clear();
+    bob_calls.lock().unwrap().clear();
+
+    let (tx, mut rx) = broadcast::channel(16);
+    let listener = tokio::spawn(listen(
+        stub.clone(),
+        gossamer.clone(),
+        bob,
+        String::from("bob"),
+        DEFAULT_DEVICE_ID,
+        tx,
+    ));
+    message(
+        &mut stub,
+        &mut gossamer,
+        alice,
+        String::from("alice"),
+        DEFAULT_DEVICE_ID,
+        "bob",
+        b"Hello Bob!",
+        SendPolicy::default(),
+        &ignored_events(),
+    )
+    .await?;
+    assert_eq!(next_message(&mut rx).await.unwrap().message, b"Hello Bob!");
+
+    // Sending looks for sessions with bob's devices, needs the sender's identity key, keeps the
+    // session the key agreement sets up, waits for the recipient to confirm it, and keeps the
+    // message in case the recipient can't decrypt it; receiving consumes the one time key the
+    // sender picked.
+    assert_eq!(
+        *alice_calls.lock().unwrap(),
+        vec![
+            "session_devices",
+            "session_devices",
+            "get_ik",
+            "get_session",
+            "save_session",
+            "expect_key_confirmation",
+            "save_sent_message",
+            "forget_sent_messages"
+        ]
+    );
+    let receipt = bob_calls.lock().unwrap().clone();
+    for call in ["get_spk_secret", "fetch_wipe_opk_by_id", "get_ik"] {
+        assert!(receipt.contains(&call), "receiving didn't call {call}");
+    }
+
+    listener.abort();
+    server.abort();
+    Ok(())
+}
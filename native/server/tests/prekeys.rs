@@ -0,0 +1,704 @@
+//! End-to-end tests of signed, one time and last-resort prekeys, with clients talking to a server
+//! over a unix domain socket.
+
+mod common;
+
+use anyhow::Result;
+use client::memory_client::MemoryClient;
+use client::{
+    count_one_time_keys, listen, message, publish_identity_key, register, revoke_identity_key,
+    rotate_spk, top_up_opks_periodically, upload_one_time_keys, ClientEvent, ConnectionState,
+    NoOneTimeKey, OpkTopUp, SendPolicy, SenderVerification, SpkAgePolicy, StaleSpkAction, Timeouts,
+    X3DHClient, RETAINED_SPKS,
+};
+use common::{ignored_events, next_message, registered_pair, spawn_server};
+use proto::service::brongnal_client::BrongnalClient;
+use proto::service::RequestPreKeysRequest;
+use proto::DEFAULT_DEVICE_ID;
+use server::brongnal::BrongnalController;
+use server::memory_brongnal::MemoryStorage;
+use server::sqlite_brongnal::SqliteStorage;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{broadcast, Mutex};
+use tokio_rusqlite::Connection;
+
+#[tokio::test]
+async fn rotated_spk_retained_for_in_flight_messages() -> Result<()> {
+    let controller = BrongnalController::new(Box::new(MemoryStorage::default()));
+    let server = spawn_server("spk", controller).await?;
+    let mut stub = server.stub();
+    let mut gossamer = server.gossamer();
+    let (alice, bob) = registered_pair(&mut stub).await?;
+
+    // Sent against the old signed pre key before bob rotated it.
+    message(
+        &mut stub,
+        &mut gossamer,
+        alice.clone(),
+        String::from("alice"),
+        DEFAULT_DEVICE_ID,
+        "bob",
+        b"Old",
+        SendPolicy::default(),
+        &ignored_events(),
+    )
+    .await?;
+    rotate_spk(
+        &mut stub,
+        bob.clone(),
+        String::from("bob"),
+        DEFAULT_DEVICE_ID,
+        Timeouts::default(),
+    )
+    .await?;
+    message(
+        &mut stub,
+        &mut gossamer,
+        alice.clone(),
+        String::from("alice"),
+        DEFAULT_DEVICE_ID,
+        "bob",
+        b"New",
+        SendPolicy::default(),
+        &ignored_events(),
+    )
+    .await?;
+
+    let (tx, mut rx) = broadcast::channel(16);
+    let listener = tokio::spawn(listen(
+        stub.clone(),
+        gossamer.clone(),
+        bob.clone(),
+        String::from("bob"),
+        DEFAULT_DEVICE_ID,
+        tx,
+    ));
+    assert_eq!(next_message(&mut rx).await.unwrap().message, b"Old");
+    assert_eq!(next_message(&mut rx).await.unwrap().message, b"New");
+    listener.abort();
+
+    let carol = Arc::new(Mutex::new(MemoryClient::new()));
+    register(
+        &mut stub,
+        carol.clone(),
+        String::from("carol"),
+        DEFAULT_DEVICE_ID,
+        &ignored_events(),
+    )
+    .await?;
+    message(
+        &mut stub,
+        &mut gossamer,
+        alice,
+        String::from("alice"),
+        DEFAULT_DEVICE_ID,
+        "carol",
+        b"Expired",
+        SendPolicy::default(),
+        &ignored_events(),
+    )
+    .await?;
+    for _ in 0..=RETAINED_SPKS {
+        rotate_spk(
+            &mut stub,
+            carol.clone(),
+            String::from("carol"),
+            DEFAULT_DEVICE_ID,
+            Timeouts::default(),
+        )
+        .await?;
+    }
+    // The message is reported as undecryptable rather than ending the stream.
+    let (tx, mut rx) = broadcast::channel(16);
+    let listener = tokio::spawn(listen(
+        stub.clone(),
+        gossamer.clone(),
+        carol,
+        String::from("carol"),
+        DEFAULT_DEVICE_ID,
+        tx,
+    ));
+    loop {
+        match rx.recv().await? {
+            ClientEvent::ConnectionState(_) => {}
+            event => {
+                assert!(matches!(
+                    event,
+                    ClientEvent::Error(error) if error.starts_with("Failed to decrypt a message from \"alice\"")
+                ));
+                break;
+            }
+        }
+    }
+    assert!(!listener.is_finished());
+    listener.abort();
+
+    drop(stub);
+    drop(gossamer);
+    server.shutdown().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn stale_spk_policy() -> Result<()> {
+    let connection = Connection::open_in_memory().await?;
+    let storage = SqliteStorage::new(connection.clone()).await?;
+    let controller = BrongnalController::new(Box::new(storage));
+    let server = spawn_server("stale", controller).await?;
+    let mut stub = server.stub();
+    let mut gossamer = server.gossamer();
+    let (alice, _bob) = registered_pair(&mut stub).await?;
+
+    let refuse = SendPolicy {
+        spk_age: SpkAgePolicy {
+            max_age: Duration::from_secs(24 * 60 * 60),
+            action: StaleSpkAction::Refuse,
+        },
+        ..Default::default()
+    };
+    message(
+        &mut stub,
+        &mut gossamer,
+        alice.clone(),
+        String::from("alice"),
+        DEFAULT_DEVICE_ID,
+        "bob",
+        b"Fresh",
+        refuse,
+        &ignored_events(),
+    )
+    .await?;
+
+    connection
+        .call(|connection| {
+            Ok(connection.execute("UPDATE user SET current_pre_key_upload_time = 0", ())?)
+        })
+        .await?;
+    // Only messages that set up a session check the signed pre key, as Alice's must once she
+    // loses the one the first set up.
+    alice
+        .lock()
+        .await
+        .delete_session("bob", DEFAULT_DEVICE_ID)?;
+    assert!(message(
+        &mut stub,
+        &mut gossamer,
+        alice.clone(),
+        String::from("alice"),
+        DEFAULT_DEVICE_ID,
+        "bob",
+        b"Stale",
+        refuse,
+        &ignored_events()
+    )
+    .await
+    .is_err());
+    let warn = SendPolicy {
+        spk_age: SpkAgePolicy {
+            action: StaleSpkAction::Warn,
+            ..refuse.spk_age
+        },
+        ..refuse
+    };
+    message(
+        &mut stub,
+        &mut gossamer,
+        alice,
+        String::from("alice"),
+        DEFAULT_DEVICE_ID,
+        "bob",
+        b"Stale",
+        warn,
+        &ignored_events(),
+    )
+    .await?;
+
+    drop(stub);
+    drop(gossamer);
+    server.shutdown().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn revoked_identity_key() -> Result<()> {
+    let controller = BrongnalController::new(Box::new(MemoryStorage::default()));
+    let server = spawn_server("revoke", controller).await?;
+    let mut stub = server.stub();
+    let mut gossamer = server.gossamer();
+    let alice = Arc::new(Mutex::new(MemoryClient::new()));
+    let bob = Arc::new(Mutex::new(MemoryClient::new()));
+    for (name, client) in [("alice", &alice), ("bob", &bob)] {
+        register(
+            &mut stub,
+            client.clone(),
+            String::from(name),
+            DEFAULT_DEVICE_ID,
+            &ignored_events(),
+        )
+        .await?;
+        publish_identity_key(
+            &mut gossamer,
+            client.clone(),
+            String::from(name),
+            Timeouts::default(),
+        )
+        .await?;
+    }
+
+    message(
+        &mut stub,
+        &mut gossamer,
+        alice.clone(),
+        String::from("alice"),
+        DEFAULT_DEVICE_ID,
+        "bob",
+        b"Hello Bob!",
+        SendPolicy::default(),
+        &ignored_events(),
+    )
+    .await?;
+    revoke_identity_key(
+        &mut gossamer,
+        alice.clone(),
+        String::from("alice"),
+        Timeouts::default(),
+    )
+    .await?;
+    let (tx, mut rx) = broadcast::channel(16);
+    let listener = tokio::spawn(listen(
+        stub.clone(),
+        gossamer.clone(),
+        bob.clone(),
+        String::from("bob"),
+        DEFAULT_DEVICE_ID,
+        tx,
+    ));
+    let received = next_message(&mut rx).await.unwrap();
+    assert_eq!(received.message, b"Hello Bob!");
+    assert!(received.sender_revoked);
+    assert_eq!(received.sender_verification, SenderVerification::Verified);
+    listener.abort();
+
+    revoke_identity_key(&mut gossamer, bob, String::from("bob"), Timeouts::default()).await?;
+    assert!(message(
+        &mut stub,
+        &mut gossamer,
+        alice,
+        String::from("alice"),
+        DEFAULT_DEVICE_ID,
+        "bob",
+        b"Are you there?",
+        SendPolicy::default(),
+        &ignored_events()
+    )
+    .await
+    .is_err());
+
+    drop(stub);
+    drop(gossamer);
+    server.shutdown().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn sender_verification() -> Result<()> {
+    let controller = BrongnalController::new(Box::new(MemoryStorage::default()));
+    let server = spawn_server("verify", controller).await?;
+    let mut stub = server.stub();
+    let mut gossamer = server.gossamer();
+    let bob = Arc::new(Mutex::new(MemoryClient::new()));
+    register(
+        &mut stub,
+        bob.clone(),
+        String::from("bob"),
+        DEFAULT_DEVICE_ID,
+        &ignored_events(),
+    )
+    .await?;
+    // Carol never publishes her identity key.
+    let alice = Arc::new(Mutex::new(MemoryClient::new()));
+    let carol = Arc::new(Mutex::new(MemoryClient::new()));
+    let mallory = Arc::new(Mutex::new(MemoryClient::new()));
+    for (name, client) in [("alice", &alice), ("mallory", &mallory)] {
+        publish_identity_key(
+            &mut gossamer,
+            client.clone(),
+            String::from(name),
+            Timeouts::default(),
+        )
+        .await?;
+    }
+
+    let (tx, mut rx) = broadcast::channel(16);
+    let listener = tokio::spawn(listen(
+        stub.clone(),
+        gossamer.clone(),
+        bob,
+        String::from("bob"),
+        DEFAULT_DEVICE_ID,
+        tx,
+    ));
+    for (claimed, client, expected) in [
+        ("alice", &alice, SenderVerification::Verified),
+        ("carol", &carol, SenderVerification::Unverified),
+        ("alice", &mallory, SenderVerification::Mismatch),
+    ] {
+        message(
+            &mut stub,
+            &mut gossamer,
+            client.clone(),
+            String::from(claimed),
+            DEFAULT_DEVICE_ID,
+            "bob",
+            b"Hello Bob!",
+            SendPolicy::default(),
+            &ignored_events(),
+        )
+        .await?;
+        let mut warned = false;
+        let received = loop {
+            match rx.recv().await? {
+                ClientEvent::Warning(warning) => {
+                    assert!(warning.contains("claiming to be from alice"), "{warning}");
+                    warned = true;
+                }
+                ClientEvent::MessageReceived(message) => break message,
+                _ => {}
+            }
+        };
+        assert_eq!(received.sender_identity, claimed);
+        assert_eq!(received.sender_verification, expected);
+        assert_eq!(warned, expected == SenderVerification::Mismatch);
+    }
+    listener.abort();
+
+    drop(stub);
+    drop(gossamer);
+    server.shutdown().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn last_resort_key() -> Result<()> {
+    let controller = BrongnalController::new(Box::new(MemoryStorage::default()));
+    let server = spawn_server("last-resort", controller).await?;
+    let mut stub = server.stub();
+    let mut gossamer = server.gossamer();
+    let (alice, bob) = registered_pair(&mut stub).await?;
+    // Use up every one time key bob uploaded.
+    loop {
+        let bundle = stub
+            .request_pre_keys(RequestPreKeysRequest {
+                identity: Some(String::from("bob")),
+            })
+            .await?
+            .into_inner()
+            .bundles
+            .remove(0);
+        if bundle.last_resort() {
+            break;
+        }
+    }
+
+    // Senders that require a one time key refuse to fall back to the last-resort key.
+    let err = message(
+        &mut stub,
+        &mut gossamer,
+        alice.clone(),
+        String::from("alice"),
+        DEFAULT_DEVICE_ID,
+        "bob",
+        b"Secret",
+        SendPolicy {
+            require_otk: true,
+            ..Default::default()
+        },
+        &ignored_events(),
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<NoOneTimeKey>(),
+        Some(&NoOneTimeKey {
+            peer: String::from("bob"),
+            device_id: DEFAULT_DEVICE_ID,
+        })
+    );
+
+    // Both messages use the last-resort key, which bob keeps after the first, as each sets up a
+    // session in place of one Alice lost.
+    for text in ["Hello Bob!", "Hello again Bob!"] {
+        alice
+            .lock()
+            .await
+            .delete_session("bob", DEFAULT_DEVICE_ID)?;
+        let (events, mut events_rx) = broadcast::channel(16);
+        let id = message(
+            &mut stub,
+            &mut gossamer,
+            alice.clone(),
+            String::from("alice"),
+            DEFAULT_DEVICE_ID,
+            "bob",
+            text.as_bytes(),
+            SendPolicy::default(),
+            &events,
+        )
+        .await?;
+        assert_eq!(
+            events_rx.recv().await?,
+            ClientEvent::NoOneTimeKey {
+                peer: String::from("bob")
+            }
+        );
+        assert_eq!(
+            events_rx.recv().await?,
+            ClientEvent::MessageSent {
+                id,
+                recipient: String::from("bob"),
+                no_one_time_key: true,
+            }
+        );
+    }
+    // Uploading more one time keys, without re-registering, ends the fallback.
+    let count = upload_one_time_keys(
+        &mut stub,
+        bob.clone(),
+        String::from("bob"),
+        DEFAULT_DEVICE_ID,
+        1,
+        Timeouts::default(),
+    )
+    .await?;
+    assert_eq!(count, 1);
+    alice
+        .lock()
+        .await
+        .delete_session("bob", DEFAULT_DEVICE_ID)?;
+    message(
+        &mut stub,
+        &mut gossamer,
+        alice.clone(),
+        String::from("alice"),
+        DEFAULT_DEVICE_ID,
+        "bob",
+        b"Secret",
+        SendPolicy {
+            require_otk: true,
+            ..Default::default()
+        },
+        &ignored_events(),
+    )
+    .await?;
+    let (tx, mut rx) = broadcast::channel(16);
+    let listener = tokio::spawn(listen(
+        stub.clone(),
+        gossamer.clone(),
+        bob,
+        String::from("bob"),
+        DEFAULT_DEVICE_ID,
+        tx,
+    ));
+    assert_eq!(next_message(&mut rx).await.unwrap().message, b"Hello Bob!");
+    assert_eq!(
+        next_message(&mut rx).await.unwrap().message,
+        b"Hello again Bob!"
+    );
+    assert_eq!(next_message(&mut rx).await.unwrap().message, b"Secret");
+    listener.abort();
+
+    drop(stub);
+    drop(gossamer);
+    server.shutdown().await?;
+    Ok(())
+}
+
+/// Waits for `name`'s one time key count on the server to reach `expected`.
+async fn wait_for_opk_count(
+    stub: &mut BrongnalClient<tonic::transport::Channel>,
+    name: &str,
+    expected: u32,
+) -> Result<()> {
+    tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            let count = count_one_time_keys(
+                stub,
+                name.to_owned(),
+                DEFAULT_DEVICE_ID,
+                Timeouts::default(),
+            )
+            .await?;
+            if count == expected {
+                return Ok(());
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await?
+}
+
+#[tokio::test]
+async fn opk_top_up() -> Result<()> {
+    let controller = BrongnalController::new(Box::new(MemoryStorage::default()));
+    let server = spawn_server("opk-top-up", controller).await?;
+    let mut stub = server.stub();
+    let mut gossamer = server.gossamer();
+    let (alice, bob) = registered_pair(&mut stub).await?;
+    wait_for_opk_count(&mut stub, "bob", 100).await?;
+
+    // Bob only checks after receiving messages, and uploads at most once.
+    let (bob_events, mut bob_rx) = broadcast::channel(16);
+    let top_up = OpkTopUp {
+        low_water_mark: 100,
+        batch_size: 10,
+        check_period: Duration::from_secs(60 * 60),
+        settle_delay: Duration::from_millis(10),
+        min_upload_interval: Duration::from_secs(60 * 60),
+    };
+    let bob_top_up = tokio::spawn(top_up_opks_periodically(
+        stub.clone(),
+        bob.clone(),
+        String::from("bob"),
+        DEFAULT_DEVICE_ID,
+        top_up,
+        Timeouts::default(),
+        bob_events.subscribe(),
+    ));
+    let listener = tokio::spawn(listen(
+        stub.clone(),
+        gossamer.clone(),
+        bob.clone(),
+        String::from("bob"),
+        DEFAULT_DEVICE_ID,
+        bob_events,
+    ));
+    // Each message sets up a session, the second in place of one Alice lost.
+    for (text, count) in [("Hello Bob!", 109), ("Hello again Bob!", 108)] {
+        alice
+            .lock()
+            .await
+            .delete_session("bob", DEFAULT_DEVICE_ID)?;
+        message(
+            &mut stub,
+            &mut gossamer,
+            alice.clone(),
+            String::from("alice"),
+            DEFAULT_DEVICE_ID,
+            "bob",
+            text.as_bytes(),
+            SendPolicy::default(),
+            &ignored_events(),
+        )
+        .await?;
+        assert_eq!(
+            next_message(&mut bob_rx).await.unwrap().message,
+            text.as_bytes()
+        );
+        wait_for_opk_count(&mut stub, "bob", count).await?;
+    }
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    wait_for_opk_count(&mut stub, "bob", 108).await?;
+    // Bob confirms the keys of both messages under the sessions they set up, which takes none of
+    // Alice's.
+    wait_for_opk_count(&mut stub, "alice", 100).await?;
+
+    // Alice checks periodically, uploading until she reaches the low-water mark.
+    let (alice_events, alice_rx) = broadcast::channel(16);
+    let alice_top_up = tokio::spawn(top_up_opks_periodically(
+        stub.clone(),
+        alice.clone(),
+        String::from("alice"),
+        DEFAULT_DEVICE_ID,
+        OpkTopUp {
+            low_water_mark: 120,
+            check_period: Duration::from_millis(10),
+            min_upload_interval: Duration::ZERO,
+            ..top_up
+        },
+        Timeouts::default(),
+        alice_rx,
+    ));
+    wait_for_opk_count(&mut stub, "alice", 120).await?;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    wait_for_opk_count(&mut stub, "alice", 120).await?;
+    drop(alice_events);
+    alice_top_up.await?;
+
+    listener.abort();
+    bob_top_up.abort();
+    drop(stub);
+    drop(gossamer);
+    server.shutdown().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn one_time_keys_low_notification() -> Result<()> {
+    let controller = BrongnalController::new(Box::new(MemoryStorage::default()))
+        .with_opk_low_thresholds(vec![98]);
+    let server = spawn_server("opks-low", controller).await?;
+    let mut stub = server.stub();
+    let gossamer = server.gossamer();
+    let bob = Arc::new(Mutex::new(MemoryClient::new()));
+    register(
+        &mut stub,
+        bob.clone(),
+        String::from("bob"),
+        DEFAULT_DEVICE_ID,
+        &ignored_events(),
+    )
+    .await?;
+    let (bob_events, mut bob_rx) = broadcast::channel(16);
+    let bob_top_up = tokio::spawn(top_up_opks_periodically(
+        stub.clone(),
+        bob.clone(),
+        String::from("bob"),
+        DEFAULT_DEVICE_ID,
+        OpkTopUp {
+            low_water_mark: 100,
+            batch_size: 10,
+            check_period: Duration::from_secs(60 * 60),
+            ..Default::default()
+        },
+        Timeouts::default(),
+        bob_events.subscribe(),
+    ));
+    let listener = tokio::spawn(listen(
+        stub.clone(),
+        gossamer.clone(),
+        bob,
+        String::from("bob"),
+        DEFAULT_DEVICE_ID,
+        bob_events,
+    ));
+    assert_eq!(
+        bob_rx.recv().await?,
+        ClientEvent::ConnectionState(ConnectionState::Connecting)
+    );
+    assert_eq!(
+        bob_rx.recv().await?,
+        ClientEvent::ConnectionState(ConnectionState::Connected)
+    );
+
+    // Someone else takes Bob's keys until only the threshold is left.
+    for _ in 0..2 {
+        stub.request_pre_keys(RequestPreKeysRequest {
+            identity: Some(String::from("bob")),
+        })
+        .await?;
+    }
+    assert_eq!(
+        bob_rx.recv().await?,
+        ClientEvent::OneTimeKeysLow { remaining: 98 }
+    );
+    // Which the top-up task acts on without waiting for its next check.
+    wait_for_opk_count(&mut stub, "bob", 108).await?;
+
+    listener.abort();
+    bob_top_up.abort();
+    drop(stub);
+    drop(gossamer);
+    server.shutdown().await?;
+    Ok(())
+}